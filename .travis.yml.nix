@@ -17,6 +17,12 @@ let
       language = "nix";
       nix = "2.0";
     };
+
+    # there is no nix on FreeBSD, so only the watcher is tested there
+    freebsd = {
+      os = "freebsd";
+      language = "rust";
+    };
   };
 
   scripts = {
@@ -64,6 +70,13 @@ let
       # based on https://gist.github.com/jkcclemens/000456ca646bd502cac0dbddcb8fa307
     };
 
+    watch-tests = {
+      name = "watch tests";
+      script = [
+        ''BUILD_REV_COUNT=1 RUN_TIME_CLOSURE=/dev/null cargo test --lib watch::''
+      ];
+    };
+
     # cache rust dependency building
     cache = name: {
       before_cache =
@@ -147,6 +160,9 @@ let
         # cachix 3 on macOS is broken on travis, see
         # https://github.com/cachix/cachix/issues/228#issuecomment-533634704
         [ hosts.macos /*scripts.macos-cachix-fix scripts.setup-cachix*/ scripts.builds ]
+
+        # kqueue (on macOS, the watcher is tested with the lints)
+        [ hosts.freebsd scripts.watch-tests ]
       ];
     };
 in pkgs.runCommand "travis.yml" {
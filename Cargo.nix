@@ -31,6 +31,7 @@ rec {
         (cratesIO.crates."serde_json"."${deps."lorri"."0.1.0"."serde_json"}" deps)
         (cratesIO.crates."structopt"."${deps."lorri"."0.1.0"."structopt"}" deps)
         (cratesIO.crates."tempfile"."${deps."lorri"."0.1.0"."tempfile"}" deps)
+        (cratesIO.crates."toml"."${deps."lorri"."0.1.0"."toml"}" deps)
         (cratesIO.crates."vec1"."${deps."lorri"."0.1.0"."vec1"}" deps)
       ]);
     };
@@ -53,6 +54,7 @@ rec {
       serde_json."${deps.lorri."0.1.0".serde_json}".default = true;
       structopt."${deps.lorri."0.1.0".structopt}".default = true;
       tempfile."${deps.lorri."0.1.0".tempfile}".default = true;
+      toml."${deps.lorri."0.1.0".toml}".default = true;
       vec1."${deps.lorri."0.1.0".vec1}".default = true;
     }) [
       (cratesIO.features_.atomicwrites."${deps."lorri"."0.1.0"."atomicwrites"}" deps)
@@ -72,6 +74,7 @@ rec {
       (cratesIO.features_.serde_json."${deps."lorri"."0.1.0"."serde_json"}" deps)
       (cratesIO.features_.structopt."${deps."lorri"."0.1.0"."structopt"}" deps)
       (cratesIO.features_.tempfile."${deps."lorri"."0.1.0"."tempfile"}" deps)
+      (cratesIO.features_.toml."${deps."lorri"."0.1.0"."toml"}" deps)
       (cratesIO.features_.vec1."${deps."lorri"."0.1.0"."vec1"}" deps)
     ];
 
@@ -206,6 +209,7 @@ rec {
     serde_json = "1.0.38";
     structopt = "0.2.14";
     tempfile = "3.0.7";
+    toml = "0.4.10";
     vec1 = "1.1.0";
   };
   deps.md5."0.6.1" = {};
@@ -427,6 +431,9 @@ rec {
   deps.thread_local."0.3.6" = {
     lazy_static = "1.2.0";
   };
+  deps.toml."0.4.10" = {
+    serde = "1.0.88";
+  };
   deps.ucd_util."0.1.3" = {};
   deps.unicode_segmentation."1.2.1" = {};
   deps.unicode_width."0.1.5" = {};
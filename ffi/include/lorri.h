@@ -0,0 +1,90 @@
+/*
+ * A C interface to the lorri daemon, for editor plugins.
+ *
+ * Build it with `cargo build --release -p lorri-ffi`, which writes
+ * `target/release/liblorri_ffi.so` (`.dylib` on macOS), and link
+ * with `-llorri_ffi`. From Lua, load it with LuaJIT's `ffi.load`.
+ *
+ * The functions find the daemon like the `lorri` command does, and
+ * may be called from any thread. Existing functions and codes never
+ * change; new ones may be added.
+ */
+
+#ifndef LORRI_H
+#define LORRI_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Success. */
+#define LORRI_OK 0
+/* An argument is NULL, or not a path to an existing file. */
+#define LORRI_ERR_INVALID_ARGUMENT 1
+/* The daemon is not running. */
+#define LORRI_ERR_NOT_RUNNING 2
+/* The daemon cannot watch the nix file (it is not readable, ...). */
+#define LORRI_ERR_REFUSED 3
+/* No environment has been built for the project yet. */
+#define LORRI_ERR_NOT_BUILT 4
+/* Anything else: the daemon did not answer, reading the
+ * environment failed, a bug in lorri, ... (functions returning a
+ * pointer return NULL instead; none of them crash the caller). */
+#define LORRI_ERR_FAILED 5
+
+/*
+ * Ask the daemon to watch and build the project of `nix_file` (like
+ * `lorri direnv` does), for example when a file of the project is
+ * opened. Returns once the daemon registered it, not once it is
+ * built.
+ */
+int lorri_ping(const char *nix_file);
+
+/*
+ * Read the environment of the last successful build of the project
+ * of `nix_file`. On success, `*env_json` is a JSON object of
+ * variable names to values, to be freed with `lorri_string_free`;
+ * otherwise it is NULL.
+ *
+ * This runs bash to evaluate the environment; read it again after
+ * a `completed` event rather than on every prompt.
+ */
+int lorri_get_env(const char *nix_file, char **env_json);
+
+/* Free a string returned by this library. NULL is ignored. */
+void lorri_string_free(char *string);
+
+/*
+ * Called with each event of a subscription, as a line of JSON
+ * without the newline (like `lorri internal stream-events` prints
+ * them), which is only valid during the call. Called once more with
+ * NULL when the stream ends: after `lorri_unsubscribe`, or when the
+ * daemon closes it (when it stops, or if the callback is so slow
+ * that it falls behind).
+ */
+typedef void (*lorri_event_callback)(const char *event, void *userdata);
+
+typedef struct LorriSubscription lorri_subscription;
+
+/*
+ * Call `callback` with the build events of the project of
+ * `nix_file`, or of all projects if it is NULL, passing `userdata`
+ * along. The callback runs on a thread of its own. Returns NULL if
+ * the daemon is not running or an argument is invalid.
+ */
+lorri_subscription *lorri_subscribe(const char *nix_file,
+                                    lorri_event_callback callback,
+                                    void *userdata);
+
+/*
+ * End a subscription and free it. Returns once the callback is no
+ * longer running, so it must not be called from the callback.
+ * NULL is ignored.
+ */
+void lorri_unsubscribe(lorri_subscription *subscription);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* LORRI_H */
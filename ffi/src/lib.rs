@@ -0,0 +1,359 @@
+//! A C interface to the lorri daemon, for editor plugins which
+//! can’t spawn `lorri` for every prompt. See `include/lorri.h` for
+//! the documentation of the functions; the two have to be kept in
+//! sync, and existing functions and codes must never change.
+//!
+//! A panic must not unwind into C, so every function runs its body
+//! in `catch_unwind` and reports a panic as `LORRI_ERR_FAILED` (or
+//! NULL).
+
+#![warn(missing_docs)]
+
+extern crate lorri;
+extern crate serde_json;
+#[cfg(test)]
+extern crate tempfile;
+
+use lorri::cas::ContentAddressable;
+use lorri::constants::Paths;
+use lorri::locate_file;
+use lorri::ops::ping;
+use lorri::project::env;
+use lorri::project::roots::Roots;
+use lorri::project::Project;
+use lorri::socket::communicate::{
+    client, EventMessage, PingResult, StreamEvents, DEFAULT_READ_TIMEOUT,
+};
+use lorri::socket::path::SocketPath;
+use lorri::socket::Timeout;
+use lorri::NixFile;
+use std::ffi::{CStr, CString};
+use std::net::Shutdown;
+use std::os::raw::{c_char, c_int, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread::JoinHandle;
+
+/// Success.
+pub const LORRI_OK: c_int = 0;
+/// An argument is NULL, or not a path to an existing file.
+pub const LORRI_ERR_INVALID_ARGUMENT: c_int = 1;
+/// The daemon is not running.
+pub const LORRI_ERR_NOT_RUNNING: c_int = 2;
+/// The daemon cannot watch the nix file.
+pub const LORRI_ERR_REFUSED: c_int = 3;
+/// No environment has been built for the project yet.
+pub const LORRI_ERR_NOT_BUILT: c_int = 4;
+/// Anything else: the daemon did not answer, reading the
+/// environment failed, a bug in lorri (a panic), ….
+pub const LORRI_ERR_FAILED: c_int = 5;
+
+/// Called with every event of a subscription, see `lorri_subscribe`.
+pub type LorriEventCallback = extern "C" fn(event: *const c_char, userdata: *mut c_void);
+
+/// A running subscription, see `lorri_subscribe`.
+pub struct LorriSubscription {
+    /// The connection to the daemon, to close it.
+    connection: UnixStream,
+    /// Reads the events and calls the callback.
+    thread: JoinHandle<()>,
+}
+
+/// The callback’s user data, which the caller promised to be usable
+/// from another thread.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// The nix file at `nix_file`, named like `lorri direnv` pings the
+/// daemon with it (see `locate_file::as_pinged`).
+unsafe fn nix_file(nix_file: *const c_char) -> Result<NixFile, c_int> {
+    if nix_file.is_null() {
+        return Err(LORRI_ERR_INVALID_ARGUMENT);
+    }
+    let path = Path::new(std::ffi::OsStr::from_bytes(
+        CStr::from_ptr(nix_file).to_bytes(),
+    ));
+    locate_file::as_pinged(path)
+        .map(NixFile::from)
+        .map_err(|_| LORRI_ERR_INVALID_ARGUMENT)
+}
+
+/// Run `f`, returning `on_panic` if it panics instead of unwinding
+/// into the caller. Nothing `f` shares with the caller outlives a
+/// panic, so it is treated as unwind safe.
+fn no_unwind<T, F: FnOnce() -> T>(on_panic: T, f: F) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(on_panic)
+}
+
+fn paths() -> Result<Paths, c_int> {
+    Paths::initialize().map_err(|_| LORRI_ERR_FAILED)
+}
+
+/// Ask the daemon to watch and build `nix_file`.
+///
+/// # Safety
+///
+/// `nix_file_path` is NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lorri_ping(nix_file_path: *const c_char) -> c_int {
+    no_unwind(LORRI_ERR_FAILED, || {
+        let result = nix_file(nix_file_path)
+            .and_then(|nix_file| ping_daemon(paths()?.daemon_socket_file(), nix_file));
+        result.err().unwrap_or(LORRI_OK)
+    })
+}
+
+fn ping_daemon(socket_path: &Path, nix_file: NixFile) -> Result<(), c_int> {
+    let client = client::ping(DEFAULT_READ_TIMEOUT)
+        .connect(&SocketPath::from(socket_path))
+        .map_err(|_| LORRI_ERR_NOT_RUNNING)?;
+    match ping::send(client, nix_file) {
+        Ok(PingResult::Registered) => Ok(()),
+        Ok(PingResult::Refused(_)) => Err(LORRI_ERR_REFUSED),
+        Err(_) => Err(LORRI_ERR_FAILED),
+    }
+}
+
+/// Write the environment of the last build of `nix_file` to `env`,
+/// as a JSON object of variable names to values.
+///
+/// # Safety
+///
+/// `nix_file_path` is NULL or a NUL-terminated string, `env_json`
+/// NULL or writable.
+#[no_mangle]
+pub unsafe extern "C" fn lorri_get_env(
+    nix_file_path: *const c_char,
+    env_json: *mut *mut c_char,
+) -> c_int {
+    if env_json.is_null() {
+        return LORRI_ERR_INVALID_ARGUMENT;
+    }
+    *env_json = ptr::null_mut();
+    let result = no_unwind(Err(LORRI_ERR_FAILED), || {
+        nix_file(nix_file_path).and_then(|nix_file| {
+            let paths = paths()?;
+            env_json_of(paths.gc_root_dir(), paths.cas_store().clone(), nix_file)
+        })
+    });
+    match result {
+        Ok(json) => {
+            *env_json = json.into_raw();
+            LORRI_OK
+        }
+        Err(code) => code,
+    }
+}
+
+/// The environment of the last build of `nix_file`, with its roots
+/// in `gc_root_dir`. Only looks, so a project which was never built
+/// leaves no traces.
+fn env_json_of(
+    gc_root_dir: &Path,
+    cas: ContentAddressable,
+    nix_file: NixFile,
+) -> Result<CString, c_int> {
+    let project = Project::lookup(nix_file, gc_root_dir, cas);
+    let root_paths = Roots::from_project(&project).paths();
+    if !root_paths.all_exist() {
+        return Err(LORRI_ERR_NOT_BUILT);
+    }
+    let env = env::read(&root_paths.bash_export()).map_err(|_| LORRI_ERR_FAILED)?;
+    let json = serde_json::to_string(&env).map_err(|_| LORRI_ERR_FAILED)?;
+    CString::new(json).map_err(|_| LORRI_ERR_FAILED)
+}
+
+/// Free a string returned by this library.
+///
+/// # Safety
+///
+/// `string` is NULL or was returned by this library, and is not
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lorri_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        no_unwind((), || drop(CString::from_raw(string)));
+    }
+}
+
+/// Call `callback` with the build events of `nix_file` (or of all
+/// projects, if it is NULL) on a background thread.
+///
+/// # Safety
+///
+/// `nix_file_path` is NULL or a NUL-terminated string, and
+/// `userdata` can be used from another thread.
+#[no_mangle]
+pub unsafe extern "C" fn lorri_subscribe(
+    nix_file_path: *const c_char,
+    callback: Option<LorriEventCallback>,
+    userdata: *mut c_void,
+) -> *mut LorriSubscription {
+    no_unwind(ptr::null_mut(), || {
+        let callback = match callback {
+            Some(callback) => callback,
+            None => return ptr::null_mut(),
+        };
+        let nix_file = if nix_file_path.is_null() {
+            None
+        } else {
+            match nix_file(nix_file_path) {
+                Ok(nix_file) => Some(nix_file),
+                Err(_) => return ptr::null_mut(),
+            }
+        };
+        let socket_path = match paths() {
+            Ok(paths) => PathBuf::from(paths.daemon_socket_file()),
+            Err(_) => return ptr::null_mut(),
+        };
+        subscribe(&socket_path, nix_file, callback, UserData(userdata))
+    })
+}
+
+fn subscribe(
+    socket_path: &Path,
+    nix_file: Option<NixFile>,
+    callback: LorriEventCallback,
+    userdata: UserData,
+) -> *mut LorriSubscription {
+    let answers = match client::stream_events(Timeout::Infinite)
+        .connect(&SocketPath::from(socket_path))
+        .ok()
+        .and_then(|client| client.request_stream(&StreamEvents { nix_file }).ok())
+    {
+        Some(answers) => answers,
+        None => return ptr::null_mut(),
+    };
+    let connection = match answers.closer() {
+        Ok(connection) => connection,
+        Err(_) => return ptr::null_mut(),
+    };
+    let thread = std::thread::spawn(move || {
+        // the stream ends with NULL, even if reading it panicked
+        no_unwind((), || {
+            for answer in answers {
+                let line = match answer {
+                    Ok(EventMessage::Event(line)) => line,
+                    Ok(EventMessage::Gap { dropped }) => {
+                        format!("{{\"event\":\"gap\",\"dropped\":{}}}", dropped)
+                    }
+                    Err(_) => break,
+                };
+                // JSON escapes all control characters
+                if let Ok(line) = CString::new(line.trim_end()) {
+                    callback(line.as_ptr(), userdata.0);
+                }
+            }
+        });
+        callback(ptr::null(), userdata.0);
+    });
+    Box::into_raw(Box::new(LorriSubscription { connection, thread }))
+}
+
+/// End `subscription` and free it. Returns once the callback is no
+/// longer called.
+///
+/// # Safety
+///
+/// `subscription` is NULL or was returned by `lorri_subscribe`, and
+/// is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn lorri_unsubscribe(subscription: *mut LorriSubscription) {
+    if subscription.is_null() {
+        return;
+    }
+    let subscription = Box::from_raw(subscription);
+    no_unwind((), || {
+        // the connection may be closed already
+        let _ = subscription.connection.shutdown(Shutdown::Both);
+        // a panic of the thread is returned, not resumed
+        let _ = subscription.thread.join();
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_arguments() {
+        let missing = CString::new("/does/not/exist/shell.nix").unwrap();
+        let mut env_json = ptr::null_mut();
+        unsafe {
+            assert_eq!(lorri_ping(ptr::null()), LORRI_ERR_INVALID_ARGUMENT);
+            assert_eq!(lorri_ping(missing.as_ptr()), LORRI_ERR_INVALID_ARGUMENT);
+            assert_eq!(
+                lorri_get_env(missing.as_ptr(), &mut env_json),
+                LORRI_ERR_INVALID_ARGUMENT
+            );
+            assert!(env_json.is_null());
+            assert_eq!(
+                lorri_get_env(missing.as_ptr(), ptr::null_mut()),
+                LORRI_ERR_INVALID_ARGUMENT
+            );
+            assert!(lorri_subscribe(ptr::null(), None, ptr::null_mut()).is_null());
+            lorri_string_free(ptr::null_mut());
+            lorri_unsubscribe(ptr::null_mut());
+        }
+    }
+
+    #[test]
+    fn nix_files_like_direnv() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().canonicalize().unwrap();
+        std::fs::create_dir(dir.join("project")).unwrap();
+        std::fs::write(dir.join("project/default.nix"), "").unwrap();
+        std::os::unix::fs::symlink("default.nix", dir.join("project/shell.nix")).unwrap();
+        std::os::unix::fs::symlink("project", dir.join("link")).unwrap();
+        let path = CString::new(dir.join("link/shell.nix").as_os_str().as_bytes()).unwrap();
+        assert_eq!(
+            unsafe { nix_file(path.as_ptr()) },
+            Ok(NixFile::from(dir.join("project/shell.nix")))
+        );
+    }
+
+    #[test]
+    fn no_daemon() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().canonicalize().unwrap();
+        let socket_path = dir.join("daemon.socket");
+        let gc_root_dir = dir.join("gc_roots");
+        std::fs::create_dir(&gc_root_dir).unwrap();
+        let cas = ContentAddressable::new(dir.join("cas")).unwrap();
+        std::fs::write(dir.join("shell.nix"), "").unwrap();
+        let nix_file = NixFile::from(dir.join("shell.nix"));
+        extern "C" fn never(_event: *const c_char, _userdata: *mut c_void) {
+            panic!("no events without a daemon")
+        }
+        assert_eq!(
+            ping_daemon(&socket_path, nix_file.clone()),
+            Err(LORRI_ERR_NOT_RUNNING)
+        );
+        assert_eq!(
+            env_json_of(&gc_root_dir, cas, nix_file.clone()),
+            Err(LORRI_ERR_NOT_BUILT)
+        );
+        // looking for the environment doesn’t set up the project
+        assert_eq!(gc_root_dir.read_dir().unwrap().count(), 0);
+        let subscription = subscribe(
+            &socket_path,
+            Some(nix_file),
+            never,
+            UserData(ptr::null_mut()),
+        );
+        assert!(subscription.is_null());
+    }
+
+    #[test]
+    fn panics_are_errors() {
+        assert_eq!(
+            no_unwind(LORRI_ERR_FAILED, || -> c_int { panic!("a bug") }),
+            LORRI_ERR_FAILED
+        );
+        assert_eq!(no_unwind(LORRI_ERR_FAILED, || LORRI_OK), LORRI_OK);
+    }
+}
@@ -0,0 +1,194 @@
+//! Guess the inputs of a nix file before its first build.
+//!
+//! lorri learns about the inputs of a build from the build itself,
+//! so a project’s first build (which may take a long time) runs
+//! before anything is watched. A shallow scan of the nix file for
+//! relative path literals (like `import ./nix/pkgs.nix` or
+//! `callPackage ../tool {}`) finds the obvious inputs, which are
+//! watched right away, so that editing them during the first build
+//! still triggers a rebuild. The build then finds all inputs.
+//! Only the paths in the project are guessed: a directory outside
+//! of it (like `../tool`) may be too large to watch, or to hash.
+
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Read at most this many nix files.
+const MAX_FILES: usize = 100;
+
+/// `nix_file` and the existing paths in `root` (canonical) it refers
+/// to with relative path literals, following the references of the
+/// nix files among them (or the `default.nix` of directories).
+pub fn references(nix_file: &Path, root: &Path) -> Vec<PathBuf> {
+    let mut found = vec![nix_file.to_path_buf()];
+    let mut seen: HashSet<PathBuf> = found.iter().cloned().collect();
+    let mut next = 0;
+    let mut files_read = 0;
+    while next < found.len() && files_read < MAX_FILES {
+        let file = if found[next].is_dir() {
+            found[next].join("default.nix")
+        } else {
+            found[next].clone()
+        };
+        next += 1;
+        if file.extension() != Some("nix".as_ref()) {
+            continue;
+        }
+        let source = match fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(_) => continue,
+        };
+        files_read += 1;
+        let dir = file.parent().unwrap_or_else(|| Path::new("/"));
+        for literal in path_literals(&source) {
+            // only existing paths can be watched
+            if let Ok(path) = dir.join(literal).canonicalize() {
+                if path.starts_with(root) && seen.insert(path.clone()) {
+                    found.push(path);
+                }
+            }
+        }
+    }
+    found
+}
+
+/// The relative path literals in the nix `source`, outside of
+/// comments and strings.
+fn path_literals(source: &str) -> Vec<String> {
+    lazy_static! {
+        static ref PATH_LITERAL: Regex =
+            Regex::new(r"(?:^|[^A-Za-z0-9._+\-/])(\.\.?(?:/[A-Za-z0-9._+\-]+)+)")
+                .expect("invalid regex!");
+    }
+    PATH_LITERAL
+        .captures_iter(&code_only(source))
+        .map(|captures| captures[1].to_string())
+        .collect()
+}
+
+/// `source` with its comments and strings replaced by spaces, so
+/// that the paths they mention aren’t taken for path literals.
+fn code_only(source: &str) -> String {
+    let mut code = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('#', _) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        code.push('\n');
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut star = false;
+                for c in chars.by_ref() {
+                    if star && c == '/' {
+                        break;
+                    }
+                    star = c == '*';
+                }
+            }
+            ('"', _) => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => (),
+                    }
+                }
+            }
+            ('\'', Some('\'')) => {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.peek() == Some(&'\'') {
+                        chars.next();
+                        // `''$`, `'''` and `''\` are escapes
+                        match chars.peek() {
+                            Some('$') | Some('\'') | Some('\\') => {
+                                chars.next();
+                            }
+                            _ => break,
+                        }
+                    }
+                }
+            }
+            (c, _) => {
+                code.push(c);
+                continue;
+            }
+        }
+        code.push(' ');
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{path_literals, references};
+    use std::fs;
+
+    #[test]
+    fn literals_outside_of_comments_and_strings() {
+        let source = r#"
+            # import ./commented.nix
+            /* callPackage ./block-commented.nix {} */
+            let pkgs = import ./nix/pkgs.nix {};
+            in pkgs.mkShell {
+              src = ./.;
+              tool = pkgs.callPackage ../tool {};
+              name = "./string";
+              shellHook = ''
+                ./configure ''${./escaped}
+              '';
+              other = builtins.readFile ./other.txt;
+            }
+        "#;
+        assert_eq!(
+            path_literals(source),
+            vec!["./nix/pkgs.nix", "./.", "../tool", "./other.txt"]
+        );
+    }
+
+    #[test]
+    fn follow_nix_files() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path().canonicalize()?;
+        let project = root.join("project");
+        fs::create_dir_all(project.join("nix"))?;
+        fs::create_dir_all(root.join("tool"))?;
+        fs::write(
+            project.join("shell.nix"),
+            "import ./nix/pkgs.nix { tool = import ../tool; missing = ./missing.nix; }",
+        )?;
+        fs::write(project.join("nix/pkgs.nix"), "{ tool }: ./overlay.txt")?;
+        fs::write(project.join("nix/overlay.txt"), "")?;
+        fs::write(root.join("tool/default.nix"), "./. # itself")?;
+
+        assert_eq!(
+            references(&project.join("shell.nix"), &root),
+            vec![
+                project.join("shell.nix"),
+                project.join("nix/pkgs.nix"),
+                root.join("tool"),
+                project.join("nix/overlay.txt"),
+            ]
+        );
+        // but not outside of the project
+        assert_eq!(
+            references(&project.join("shell.nix"), &project),
+            vec![
+                project.join("shell.nix"),
+                project.join("nix/pkgs.nix"),
+                project.join("nix/overlay.txt"),
+            ]
+        );
+        Ok(())
+    }
+}
@@ -42,9 +42,14 @@ where
     }
 }
 
+/// Quote `s` for use as a single shell word.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::expect_bash;
+    use super::{expect_bash, quote};
 
     #[test]
     #[should_panic]
@@ -56,4 +61,9 @@ mod tests {
     fn expect_bash_can_pass() {
         expect_bash(r#"exit "$1""#, &["0"]);
     }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote("/it's/shell.nix"), r"'/it'\''s/shell.nix'");
+    }
 }
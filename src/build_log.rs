@@ -0,0 +1,285 @@
+//! The output of a build loop’s current (or most recent) build,
+//! kept in memory so that clients can follow it like `tail -f`
+//! (see `socket::communicate::CommunicationType::FollowLog`).
+//!
+//! The build loop writes to the log through a `LogWriter`; every
+//! reader gets its own `LogCursor`, an iterator over the log which
+//! blocks until the next entry is written. Readers don’t slow
+//! down the build: a slow reader only falls behind, and once a new
+//! build starts, the rest of the previous build’s log is skipped.
+//! Only the last `MAX_LINES` lines of a build are kept.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many lines of a build the log keeps; once there are more,
+/// the oldest are dropped (and skipped by readers behind them).
+pub const MAX_LINES: usize = 10_000;
+
+/// The log of the current (or most recent) build. Clones share it.
+#[derive(Clone, Default)]
+pub struct BuildLog(Arc<(Mutex<State>, Condvar)>);
+
+#[derive(Default)]
+struct State {
+    /// Number of builds started, the current one included.
+    builds: u64,
+    /// The last `MAX_LINES` lines of the current build.
+    lines: VecDeque<String>,
+    /// How many lines of the current build were dropped from
+    /// `lines`.
+    dropped: usize,
+    /// Whether the current build is still running.
+    running: bool,
+}
+
+/// An entry of the log, see `LogCursor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogEntry {
+    /// A build started.
+    Started,
+    /// A line of output.
+    Line(String),
+    /// The build finished.
+    Finished,
+}
+
+impl BuildLog {
+    /// An empty log, before any build.
+    pub fn new() -> BuildLog {
+        BuildLog::default()
+    }
+
+    /// Start the log of a new build, forgetting the previous one.
+    pub fn start(&self) {
+        self.update(|state| {
+            state.builds += 1;
+            state.lines.clear();
+            state.dropped = 0;
+            state.running = true;
+        })
+    }
+
+    /// Add a line to the log of the current build.
+    pub fn push(&self, line: String) {
+        self.update(|state| {
+            if state.lines.len() == MAX_LINES {
+                state.lines.pop_front();
+                state.dropped += 1;
+            }
+            state.lines.push_back(line)
+        })
+    }
+
+    /// Mark the current build as finished.
+    pub fn finish(&self) {
+        self.update(|state| state.running = false)
+    }
+
+    /// A writer which adds each line written to it to the log.
+    pub fn writer(&self) -> LogWriter {
+        LogWriter {
+            log: self.clone(),
+            partial: vec![],
+        }
+    }
+
+    /// Read the log from the start of the current (or most
+    /// recent) build, and then the logs of all following builds.
+    pub fn cursor(&self) -> LogCursor {
+        let builds = (self.0).0.lock().expect("log lock poisoned").builds;
+        LogCursor {
+            log: self.clone(),
+            build: builds.max(1),
+            line: 0,
+            started: false,
+            finished: false,
+        }
+    }
+
+    fn update<F: FnOnce(&mut State)>(&self, f: F) {
+        let (ref state, ref changed) = *self.0;
+        f(&mut state.lock().expect("log lock poisoned"));
+        changed.notify_all();
+    }
+}
+
+/// Writes lines to a `BuildLog`, see `BuildLog::writer`.
+pub struct LogWriter {
+    log: BuildLog,
+    /// The start of a line whose end wasn’t written yet.
+    partial: Vec<u8>,
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            self.log
+                .push(String::from_utf8_lossy(&line[..end]).into_owned());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads a `BuildLog`, see `BuildLog::cursor`.
+///
+/// As an iterator, it never ends, and blocks until the next entry
+/// of the log is written.
+pub struct LogCursor {
+    log: BuildLog,
+    /// The build we read.
+    build: u64,
+    /// The next line to read, counting the dropped ones.
+    line: usize,
+    /// Whether we returned `Started` for `build`.
+    started: bool,
+    /// Whether we returned `Finished` for `build`.
+    finished: bool,
+}
+
+impl LogCursor {
+    /// Like `next`, but give up after waiting for `timeout`.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<LogEntry> {
+        let deadline = Instant::now() + timeout;
+        let log = self.log.0.clone();
+        let (ref state, ref changed) = *log;
+        let mut state = state.lock().expect("log lock poisoned");
+        loop {
+            if let Some(entry) = self.advance(&state) {
+                return Some(entry);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = changed
+                .wait_timeout(state, deadline - now)
+                .expect("log lock poisoned")
+                .0;
+        }
+    }
+
+    /// The next entry, if it was already written.
+    fn advance(&mut self, state: &State) -> Option<LogEntry> {
+        if self.build < state.builds {
+            // a newer build started, the rest of ours is gone
+            if self.started && !self.finished {
+                self.finished = true;
+                return Some(LogEntry::Finished);
+            }
+            self.build = state.builds;
+            self.line = 0;
+            self.started = false;
+            self.finished = false;
+        }
+        if self.build > state.builds || self.finished {
+            // no build (or no new one) yet
+            return None;
+        }
+        if !self.started {
+            self.started = true;
+            return Some(LogEntry::Started);
+        }
+        // skip the lines dropped before we read them
+        self.line = self.line.max(state.dropped);
+        if let Some(line) = state.lines.get(self.line - state.dropped) {
+            self.line += 1;
+            return Some(LogEntry::Line(line.clone()));
+        }
+        if !state.running {
+            self.finished = true;
+            return Some(LogEntry::Finished);
+        }
+        None
+    }
+}
+
+impl Iterator for LogCursor {
+    type Item = LogEntry;
+
+    fn next(&mut self) -> Option<LogEntry> {
+        loop {
+            if let Some(entry) = self.next_timeout(Duration::from_secs(3600)) {
+                return Some(entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildLog, LogEntry, MAX_LINES};
+    use std::io::Write;
+    use std::time::Duration;
+
+    fn line(s: &str) -> LogEntry {
+        LogEntry::Line(String::from(s))
+    }
+
+    #[test]
+    fn follow_builds() {
+        let log = BuildLog::new();
+        let mut cursor = log.cursor();
+        // nothing to read before the first build
+        assert_eq!(cursor.next_timeout(Duration::from_millis(10)), None);
+
+        log.start();
+        let mut writer = log.writer();
+        writer.write_all(b"building").unwrap();
+        writer.write_all(b"\nerror: oops\n").unwrap();
+        log.finish();
+        assert_eq!(
+            cursor.by_ref().take(4).collect::<Vec<_>>(),
+            vec![
+                LogEntry::Started,
+                line("building"),
+                line("error: oops"),
+                LogEntry::Finished
+            ]
+        );
+        assert_eq!(cursor.next_timeout(Duration::from_millis(10)), None);
+
+        // a new cursor reads the most recent build
+        assert_eq!(log.cursor().nth(1), Some(line("building")));
+
+        // the rest of a build is skipped once the next one starts
+        log.start();
+        log.push(String::from("first"));
+        assert_eq!(cursor.nth(1), Some(line("first")));
+        log.start();
+        log.push(String::from("second"));
+        assert_eq!(
+            cursor.by_ref().take(3).collect::<Vec<_>>(),
+            vec![LogEntry::Finished, LogEntry::Started, line("second")]
+        );
+    }
+
+    #[test]
+    fn drop_the_oldest_lines() {
+        let log = BuildLog::new();
+        let mut behind = log.cursor();
+        log.start();
+        assert_eq!(behind.next(), Some(LogEntry::Started));
+        log.push(String::from("first"));
+        assert_eq!(behind.next(), Some(line("first")));
+        for i in 0..=MAX_LINES {
+            log.push(format!("line {}", i));
+        }
+        log.finish();
+
+        // readers behind skip the dropped lines
+        assert_eq!(behind.next(), Some(line("line 1")));
+        let lines: Vec<LogEntry> = log.cursor().take(MAX_LINES + 2).collect();
+        assert_eq!(lines[1], line("line 1"));
+        assert_eq!(lines[MAX_LINES], line(&format!("line {}", MAX_LINES)));
+        assert_eq!(lines[MAX_LINES + 1], LogEntry::Finished);
+    }
+}
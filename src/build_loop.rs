@@ -1,24 +1,195 @@
 //! Uses `builder` and filesystem watch code to repeatedly
 //! evaluate and build a given Nix file.
 
+use crate::backfill;
+use crate::build_log::{BuildLog, LogWriter};
 use crate::builder;
+use crate::cachix;
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+use crate::derivation;
+use crate::flake;
+use crate::nix::StorePath;
 use crate::notify;
-use crate::pathreduction::reduce_paths;
+use crate::pathreduction::{reduce_paths, Skipped};
+use crate::project::bin_dir;
+use crate::project::config::{LogConfig, ProjectConfig, CONFIG_FILE_NAME};
+use crate::project::env;
+use crate::project::failures;
+use crate::project::ide_env;
 use crate::project::roots;
 use crate::project::roots::Roots;
 use crate::project::Project;
-use crate::watch::Watch;
+use crate::push;
+use crate::read_trace;
+use crate::skew;
+use crate::watch::{ExtendError, Watch};
+use regex::Regex;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 /// Builder events sent back over `BuildLoop.tx`.
+///
+/// New kinds of events are added over time, so consumers
+/// should ignore the ones they don’t know.
+///
+/// The events of a build carry its `BuildId`; the ones which start
+/// and end it also carry when that happened.
 #[derive(Clone, Debug)]
 pub enum Event {
-    /// The build has started
-    Started,
+    /// The build has started; if a change of its inputs started
+    /// it, the build also carries how long after the change was
+    /// noticed it started (see `Watch::take_changed_at`), which
+    /// includes waiting for events to settle and for the previous
+    /// build to finish
+    Started(BuildId, SystemTime, Option<Duration>),
     /// The build completed successfully
-    Completed(BuildResults),
+    Completed(BuildId, SystemTime, BuildResults),
     /// The build command returned a failing exit status
-    Failure(BuildExitFailure),
+    Failure(BuildId, SystemTime, BuildExitFailure),
+    /// Nix reported progress of the running build
+    Progress(BuildId, builder::Progress),
+    /// Nix printed a line while running the build, as it was
+    /// printed; a failing build also has its lines in its
+    /// `BuildExitFailure`
+    LogLine(BuildId, String),
+    /// The result of a build was pushed to cachix
+    CachixPush(cachix::PushOutcome),
+    /// The push command of the project (see
+    /// `project::config::PushConfig`) ran after a build
+    Pushed(push::PushOutcome),
+    /// Store paths of the project’s GC roots disappeared from the
+    /// store; a rebuild follows immediately
+    RootsLost(Vec<StorePath>),
+    /// The running build was cancelled (see `BuildLoop::canceller`);
+    /// the next build starts once an input changes
+    Cancelled(BuildId),
+    /// The build read files which lorri doesn’t watch, so changing
+    /// them won’t rebuild (only with strict input tracking, see
+    /// `project::config::WatchConfig::strict`)
+    UntrackedReads(Vec<PathBuf>),
+    /// The mtimes of inputs of the build are further in the future
+    /// than `project::config::WatchConfig::max_clock_skew_secs`
+    /// (see `skew`); they are tracked by content hash
+    ClockSkew {
+        /// The inputs with mtimes in the future
+        paths: Vec<PathBuf>,
+        /// How far the furthest is ahead
+        ahead: Duration,
+    },
+    /// The build failed because of a network error, and is retried
+    Retrying {
+        /// The build which is retried
+        build: BuildId,
+        /// The number of this retry, starting at 1
+        attempt: u32,
+        /// How often the build is retried at most
+        max: u32,
+    },
+    /// The project’s GC roots now point to a new environment;
+    /// sent before the `Completed` event of the build which
+    /// produced it. The roots are switched atomically, and only
+    /// once a build succeeded, so they always point to a complete
+    /// environment.
+    EnvironmentSwitched(BuildId, builder::OutputPaths<StorePath>),
+}
+
+impl Event {
+    /// The build the event belongs to, if any.
+    pub fn build(&self) -> Option<BuildId> {
+        match self {
+            Event::Started(build, _, _)
+            | Event::Completed(build, _, _)
+            | Event::Failure(build, _, _)
+            | Event::Progress(build, _)
+            | Event::LogLine(build, _)
+            | Event::Cancelled(build)
+            | Event::Retrying { build, .. }
+            | Event::EnvironmentSwitched(build, _) => Some(*build),
+            Event::CachixPush(_)
+            | Event::Pushed(_)
+            | Event::RootsLost(_)
+            | Event::UntrackedReads(_)
+            | Event::ClockSkew { .. } => None,
+        }
+    }
+
+    /// When the build the event belongs to started or ended, for
+    /// the events which start and end builds.
+    pub fn time(&self) -> Option<SystemTime> {
+        match self {
+            Event::Started(_, time, _)
+            | Event::Completed(_, time, _)
+            | Event::Failure(_, time, _) => Some(*time),
+            _ => None,
+        }
+    }
+}
+
+/// Identifies a build, from its `Event::Started` to its
+/// `Event::Completed`, `Event::Failure` or `Event::Cancelled`
+/// (retries included), so the events of concurrent builds can be
+/// told apart. Unique while the process runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BuildId(u64);
+
+impl BuildId {
+    /// A new, unique id.
+    pub fn next() -> BuildId {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        BuildId(NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    /// The id as a number.
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// The id with the number `id`, as read back from an event.
+impl From<u64> for BuildId {
+    fn from(id: u64) -> BuildId {
+        BuildId(id)
+    }
+}
+
+impl std::fmt::Display for BuildId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How often builds failing with network errors are retried, unless
+/// configured otherwise (see `BuildLoop::configure`).
+pub const NETWORK_RETRIES: u32 = 3;
+
+/// Wait this long before the first retry, and twice as long before
+/// each following one (unless configured otherwise).
+pub const NETWORK_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Never wait longer than this before a retry (unless configured
+/// otherwise).
+pub const NETWORK_RETRY_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait before retry number `attempt` (starting at 1):
+/// `first`, doubled for every retry before it, but at most `max`.
+pub fn retry_delay(first: Duration, max: Duration, attempt: u32) -> Duration {
+    2u32.checked_pow(attempt.saturating_sub(1))
+        .and_then(|factor| first.checked_mul(factor))
+        .map_or(max, |delay| delay.min(max))
+}
+
+/// Tell the user about the inputs `reduce_paths` left out, since
+/// changes to them will not trigger a rebuild.
+fn warn_skipped(skipped: &[Skipped]) {
+    for skipped in skipped {
+        warn!("not watching input {}", skipped);
+    }
 }
 
 /// Results of a single, successful build.
@@ -26,6 +197,35 @@ pub enum Event {
 pub struct BuildResults {
     /// See `build::Info.outputPaths
     pub output_paths: builder::OutputPaths<roots::RootPath>,
+    /// Where the store paths of the build came from
+    pub cache_stats: builder::CacheStats,
+    /// The derivation of the environment (see `builder::Info`)
+    pub drv_path: Option<PathBuf>,
+    /// How long evaluating and realising took
+    pub timings: builder::Timings,
+    /// Whether the build needed anything but evaluation
+    pub rebuild: Rebuild,
+    /// The hash of the environment (see `project::env::hash`), if
+    /// it could be read
+    pub env_hash: Option<String>,
+    /// How many files the build loop watches after the build (see
+    /// `Watch::watched_files`)
+    pub watched_files: usize,
+}
+
+/// How much a successful build had to do, compared to the one
+/// before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rebuild {
+    /// The environment may depend on anything new (or this is the
+    /// first build).
+    Full,
+    /// The environment depends on the same derivations and sources
+    /// as before, only strings like its `shellHook` changed: nix
+    /// evaluated the nix file and only built the environment itself
+    /// (locally, it is never substituted), its dependencies were
+    /// already there.
+    EvalOnly,
 }
 
 /// Results of a single, failing build.
@@ -33,6 +233,9 @@ pub struct BuildResults {
 pub struct BuildExitFailure {
     /// stderr log output
     pub log_lines: Vec<std::ffi::OsString>,
+    /// Directory with a snapshot of the inputs, log and nix command
+    /// of the build (see `project::failures`), if nix ran
+    pub artifacts: Option<PathBuf>,
 }
 
 /// The BuildLoop repeatedly builds the Nix expression in
@@ -45,78 +248,1043 @@ pub struct BuildLoop<'a> {
     /// Watches all input files for changes.
     /// As new input files are discovered, they are added to the watchlist.
     watch: Watch,
+    /// Lost roots which were already reported.
+    lost_roots: Vec<StorePath>,
+    /// Cancels the running build.
+    canceller: builder::Canceller,
+    /// Runs the project’s push command, one push at a time.
+    pushes: push::Queue,
+    /// Cancel the running build when an input changes, and start
+    /// over right away.
+    cancel_on_change: bool,
+    /// How long changes are batched, unless the project configures
+    /// it (see `set_debounce`).
+    debounce: Duration,
+    /// How often builds failing with network errors are retried.
+    network_retries: u32,
+    /// How long to wait before the first retry (see `retry_delay`).
+    network_retry_delay: Duration,
+    /// How long to wait before a retry at most.
+    network_retry_max_delay: Duration,
+    /// Whether the last build was cancelled because an input changed.
+    changed_during_build: bool,
+    /// Run nix-build in a session of its own (see `set_detach_builds`).
+    detach_builds: bool,
+    /// Tells other threads whether a build is pending or running.
+    activity: Activity,
+    /// The output of the current build, for other threads to follow.
+    build_log: BuildLog,
+    /// Time, as far as waiting before retries is concerned.
+    clock: Arc<dyn Clock>,
+    /// Files the last build read without lorri watching them
+    /// (only with strict input tracking).
+    untracked_reads: Vec<PathBuf>,
+    /// The environment the last build switched the roots to,
+    /// if it changed them.
+    switched: Option<builder::OutputPaths<StorePath>>,
+    /// The `Event::ClockSkew` of the last build, if its inputs
+    /// were skewed too much.
+    clock_skew: Option<Event>,
+    /// The inputs of the environment of the last successful build
+    /// (see `Rebuild`).
+    last_inputs: Option<derivation::Inputs>,
+    /// The paths the last build read, reduced (see `reduce_paths`).
+    input_paths: Vec<PathBuf>,
+}
+
+/// Wait this long before restarting a build loop which failed
+/// unrecoverably, and twice as long after each following failure
+/// (see `supervise`).
+pub const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Wait at most this long before restarting a build loop.
+pub const RESTART_MAX_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// A build loop which ran this long before failing failed on its
+/// own, so it is restarted after `RESTART_DELAY` again.
+pub const RESTART_RESET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Run `BuildLoop::forever` for `project`, stopped by `canceller`.
+/// When the loop fails unrecoverably (it reports the error as a
+/// failed build), start over with a fresh loop, waiting longer after
+/// every failure in a row (on `clock`, which the loops use, too).
+/// `setup` configures each loop. Returns once the loop is stopped.
+pub fn supervise<F>(
+    project: &Project,
+    canceller: builder::Canceller,
+    clock: Arc<dyn Clock>,
+    tx: Sender<Event>,
+    setup: F,
+) where
+    F: Fn(&mut BuildLoop),
+{
+    let pushes = push::Queue::default();
+    restart_failed(&*clock, &canceller, || {
+        let mut build_loop = BuildLoop::new(project);
+        build_loop.set_canceller(canceller.clone());
+        build_loop.set_pushes(pushes.clone());
+        build_loop.set_clock(clock.clone());
+        setup(&mut build_loop);
+        build_loop.forever(tx.clone()).map_err(|e| {
+            error!("the build loop of {} failed: {:?}", project.source, e);
+            e
+        })
+    })
+}
+
+/// Call `run` until it succeeds or `canceller` is stopped, waiting
+/// before each restart (see `supervise`).
+fn restart_failed<F>(clock: &dyn Clock, canceller: &builder::Canceller, mut run: F)
+where
+    F: FnMut() -> Result<(), UnrecoverableErrors>,
+{
+    let mut failures = 0;
+    loop {
+        let started = clock.now();
+        if run().is_ok() {
+            return;
+        }
+        // a loop which ran for a while failed on its own
+        if clock.now() >= started + RESTART_RESET_AFTER {
+            failures = 0;
+        }
+        failures += 1;
+        let delay = retry_delay(RESTART_DELAY, RESTART_MAX_DELAY, failures);
+        info!("restarting the build loop in {}s", delay.as_secs());
+        let restart = clock.now() + delay;
+        loop {
+            if canceller.is_stopped() {
+                return;
+            }
+            let now = clock.now();
+            if now >= restart {
+                break;
+            }
+            clock.sleep((restart - now).min(Duration::from_millis(100)));
+        }
+    }
+}
+
+/// Whether a `BuildLoop` has a build pending or running, shared
+/// with other threads (see `BuildLoop::set_activity`).
+#[derive(Clone)]
+pub struct Activity(Arc<(Mutex<ActivityState>, Condvar)>);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ActivityState {
+    Idle,
+    Busy,
+}
+
+impl Activity {
+    /// A new, busy activity; build loops start with a build.
+    pub fn new() -> Activity {
+        Activity(Arc::new((Mutex::new(ActivityState::Busy), Condvar::new())))
+    }
+
+    /// Whether no build is pending or running.
+    pub fn is_idle(&self) -> bool {
+        *(self.0).0.lock().expect("activity lock poisoned") == ActivityState::Idle
+    }
+
+    /// Block until no build is pending or running.
+    pub fn wait_idle(&self) {
+        let (ref state, ref changed) = *self.0;
+        let mut state = state.lock().expect("activity lock poisoned");
+        while *state == ActivityState::Busy {
+            state = changed.wait(state).expect("activity lock poisoned");
+        }
+    }
+
+    /// Block until no build is pending or running, for at most
+    /// `timeout`. Returns whether it is idle.
+    pub fn wait_idle_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (ref state, ref changed) = *self.0;
+        let mut state = state.lock().expect("activity lock poisoned");
+        while *state == ActivityState::Busy {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = changed
+                .wait_timeout(state, deadline - now)
+                .expect("activity lock poisoned")
+                .0;
+        }
+        true
+    }
+
+    /// Mark the activity idle, for a build loop which never
+    /// started.
+    pub fn set_idle(&self) {
+        self.set_busy(false)
+    }
+
+    fn set_busy(&self, busy: bool) {
+        let (ref state, ref changed) = *self.0;
+        *state.lock().expect("activity lock poisoned") = if busy {
+            ActivityState::Busy
+        } else {
+            ActivityState::Idle
+        };
+        changed.notify_all();
+    }
+}
+
+/// Writes the output of a build to the `BuildLog` and (if
+/// configured, see `LogConfig`) a file. Failing to write to the
+/// file only stops logging to the file.
+struct Tee {
+    file: Option<fs::File>,
+    build_log: LogWriter,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = match self.file {
+            Some(ref mut file) => file.write_all(buf),
+            None => Ok(()),
+        };
+        if let Err(e) = written {
+            warn!("could not write the build log, not logging any more: {}", e);
+            self.file = None;
+        }
+        self.build_log.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(ref mut file) = self.file {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Marks an `Activity` idle when dropped, so that nobody waits
+/// forever for a build loop which exited (or panicked).
+struct IdleOnExit(Activity);
+
+impl Drop for IdleOnExit {
+    fn drop(&mut self) {
+        self.0.set_busy(false)
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Activity {
+        Activity::new()
+    }
 }
 
 impl<'a> BuildLoop<'a> {
     /// Instatiate a new BuildLoop. Uses an internal filesystem
     /// watching implementation.
     pub fn new(project: &'a Project) -> BuildLoop<'a> {
+        let mut watch = Watch::init().expect("Failed to initialize watch");
+        watch.set_project_dir(project.config_root());
+        watch.set_store_dir(&project.store.store_dir());
         BuildLoop {
             project,
-            watch: Watch::init().expect("Failed to initialize watch"),
+            watch,
+            lost_roots: vec![],
+            canceller: builder::Canceller::new(),
+            pushes: push::Queue::default(),
+            cancel_on_change: false,
+            debounce: Duration::from_millis(0),
+            network_retries: NETWORK_RETRIES,
+            network_retry_delay: NETWORK_RETRY_DELAY,
+            network_retry_max_delay: NETWORK_RETRY_MAX_DELAY,
+            changed_during_build: false,
+            detach_builds: false,
+            activity: Activity::new(),
+            build_log: BuildLog::new(),
+            clock: Arc::new(SystemClock),
+            untracked_reads: vec![],
+            switched: None,
+            clock_skew: None,
+            last_inputs: None,
+            input_paths: vec![],
         }
     }
 
+    /// A handle to cancel the running build from another thread.
+    pub fn canceller(&self) -> builder::Canceller {
+        self.canceller.clone()
+    }
+
+    /// Cancel builds and stop the loop with `canceller`, instead of
+    /// with the loop’s own one (see `supervise`).
+    pub fn set_canceller(&mut self, canceller: builder::Canceller) {
+        self.canceller = canceller;
+    }
+
+    /// Run pushes on `pushes`, instead of on the loop’s own queue,
+    /// so that they don’t overlap with those of an earlier loop of
+    /// the same project (see `supervise`).
+    pub fn set_pushes(&mut self, pushes: push::Queue) {
+        self.pushes = pushes;
+    }
+
+    /// Apply the global configuration: see `set_cancel_on_change`
+    /// and `set_debounce`, how often (and after how long) builds
+    /// failing with network errors are retried and how often inputs tracked by content
+    /// hash are checked (see `Watch::set_poll_interval`).
+    pub fn configure(&mut self, config: &Config) {
+        self.set_cancel_on_change(config.build.cancel_on_change);
+        self.set_debounce(Duration::from_millis(config.watch.debounce_ms));
+        self.network_retries = config.build.network_retries;
+        self.network_retry_delay = Duration::from_secs(config.build.network_retry_delay_secs);
+        self.network_retry_max_delay =
+            Duration::from_secs(config.build.network_retry_max_delay_secs);
+        self.watch
+            .set_poll_interval(Duration::from_secs(config.watch.poll_interval_secs));
+        self.watch.set_backend(config.watch.backend);
+    }
+
+    /// Cancel the running build as soon as one of its watched
+    /// inputs changes, and start a new one, instead of finishing
+    /// the stale build first.
+    pub fn set_cancel_on_change(&mut self, cancel_on_change: bool) {
+        self.cancel_on_change = cancel_on_change;
+    }
+
+    /// Run nix-build without a controlling terminal, in a process
+    /// group of its own (see `nix::detach`), as the daemon does. In
+    /// the foreground, ctrl-c has to reach it instead.
+    pub fn set_detach_builds(&mut self, detach_builds: bool) {
+        self.detach_builds = detach_builds;
+    }
+
+    /// Treat changes as one batch until none arrive for `debounce`,
+    /// see `Watch::set_debounce`.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+        self.watch.set_debounce(debounce);
+    }
+
+    /// Wait on `clock` instead of the system clock, for retries
+    /// and for more file changes (see `Watch::set_latency`).
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.watch.set_clock(clock.clone());
+        self.clock = clock;
+    }
+
+    /// Report whether a build is pending or running to `activity`,
+    /// instead of to the loop’s own one.
+    pub fn set_activity(&mut self, activity: Activity) {
+        self.activity = activity;
+    }
+
+    /// Write the output of builds to `build_log`,
+    /// instead of to the loop’s own one.
+    pub fn set_build_log(&mut self, build_log: BuildLog) {
+        self.build_log = build_log;
+    }
+
     /// Loop forever, watching the filesystem for changes. Blocks.
     /// Sends `Event`s over `Self.tx` once they happen.
     /// When new filesystem changes are detected while a build is
-    /// still running, it is finished first before starting a new build.
-    pub fn forever(&mut self, tx: Sender<Event>) {
+    /// still running, it is finished first before starting a new build,
+    /// unless the loop cancels builds on changes
+    /// (see `set_cancel_on_change`). Builds requested with
+    /// `Canceller::request_build` start like after a change, but
+    /// only within `watch::POLL_INTERVAL` of the request.
+    ///
+    /// Returns once the loop is stopped with `Canceller::stop`,
+    /// which it notices within `watch::POLL_INTERVAL`, or after
+    /// reporting an unrecoverable error as a failure of the build
+    /// (see `supervise` to restart it).
+    pub fn forever(&mut self, tx: Sender<Event>) -> Result<(), UnrecoverableErrors> {
+        let _idle_on_exit = IdleOnExit(self.activity.clone());
+        self.backfill();
         loop {
+            if self.canceller.is_stopped() {
+                return Ok(());
+            }
+
             // TODO: Make err use Display instead of Debug.
             // Otherwise user errors (especially for IO errors)
             // are pretty hard to debug. Might need to review
             // whether we can handle some errors earlier than here.
-            tx.send(Event::Started)
+            self.activity.set_busy(true);
+            // this build satisfies requests made until now
+            let build = self
+                .canceller
+                .take_build_request()
+                .unwrap_or_else(BuildId::next);
+            let latency = self.watch.take_changed_at().map(|changed_at| {
+                let now = self.clock.now();
+                if now > changed_at {
+                    now - changed_at
+                } else {
+                    Duration::from_secs(0)
+                }
+            });
+            debug!("build {} of {} started", build, self.project.source);
+            tx.send(Event::Started(build, SystemTime::now(), latency))
                 .expect("Failed to notify a started evaluation");
 
-            match self.once() {
+            let mut attempt = 0;
+            let result = loop {
+                let report_tx = tx.clone();
+                match self.build(|report| {
+                    let event = match report {
+                        builder::Report::Progress(progress) => Event::Progress(build, progress),
+                        builder::Report::LogLine(line) => Event::LogLine(build, line),
+                    };
+                    report_tx
+                        .send(event)
+                        .expect("Failed to notify the progress of an evaluation")
+                }) {
+                    Err(BuildError::Network(_)) if attempt < self.network_retries => {
+                        attempt += 1;
+                        tx.send(Event::Retrying {
+                            build,
+                            attempt,
+                            max: self.network_retries,
+                        })
+                        .expect("Failed to notify a retried evaluation");
+                        self.clock.sleep(retry_delay(
+                            self.network_retry_delay,
+                            self.network_retry_max_delay,
+                            attempt,
+                        ));
+                    }
+                    result => break result,
+                }
+            };
+            match result {
                 Ok(result) => {
-                    tx.send(Event::Completed(result))
+                    if let Some(paths) = self.switched.take() {
+                        tx.send(Event::EnvironmentSwitched(build, paths))
+                            .expect("Failed to notify about a switched environment");
+                    }
+                    self.push_to_cachix(&result, tx.clone());
+                    self.run_push_command(tx.clone());
+                    tx.send(Event::Completed(build, SystemTime::now(), result))
                         .expect("Failed to notify the results of a completed evaluation");
                 }
-                Err(BuildError::Recoverable(failure)) => {
-                    tx.send(Event::Failure(failure))
+                Err(BuildError::Cancelled) => {
+                    tx.send(Event::Cancelled(build))
+                        .expect("Failed to notify a cancelled evaluation");
+                }
+                Err(BuildError::Recoverable(failure))
+                | Err(BuildError::Network(failure))
+                | Err(BuildError::Interactive(failure)) => {
+                    tx.send(Event::Failure(build, SystemTime::now(), failure))
+                        .expect("Failed to notify the results of a failed evaluation");
+                }
+                Err(BuildError::Unrecoverable(e)) => {
+                    let failure = BuildExitFailure {
+                        log_lines: vec![format!("lorri: the build loop failed: {:?}", e).into()],
+                        artifacts: None,
+                    };
+                    tx.send(Event::Failure(build, SystemTime::now(), failure))
                         .expect("Failed to notify the results of a failed evaluation");
+                    return Err(e);
                 }
-                otherwise => {
-                    otherwise.unwrap();
+            }
+
+            if self.canceller.is_stopped() {
+                return Ok(());
+            }
+
+            let untracked = std::mem::replace(&mut self.untracked_reads, Vec::new());
+            if !untracked.is_empty() {
+                tx.send(Event::UntrackedReads(untracked))
+                    .expect("Failed to notify about untracked reads");
+            }
+            if let Some(clock_skew) = self.clock_skew.take() {
+                tx.send(clock_skew)
+                    .expect("Failed to notify about skewed mtimes");
+            }
+
+            if self.changed_during_build {
+                // the build was cancelled because of the change,
+                // so there is nothing to wait for
+                continue;
+            }
+
+            self.activity.set_busy(false);
+
+            // poll for roots which are lost from the store, but
+            // report (and rebuild) each loss only once
+            let roots = Roots::from_project(self.project);
+            let reported = std::mem::replace(&mut self.lost_roots, vec![]);
+            let mut lost = vec![];
+            let canceller = &self.canceller;
+            self.watch.wait_for_change_or(|| {
+                if canceller.is_stopped() || canceller.build_requested() {
+                    return true;
+                }
+                lost = roots.lost();
+                lost.retain(|path| !reported.contains(path));
+                !lost.is_empty()
+            });
+            if !lost.is_empty() {
+                self.lost_roots = lost.clone();
+                tx.send(Event::RootsLost(lost))
+                    .expect("Failed to notify about lost roots");
+            }
+        }
+    }
+
+    /// Watch the paths the nix file obviously refers to (see
+    /// `backfill`) before the first build, so that changing them
+    /// while it runs triggers a rebuild.
+    fn backfill(&mut self) {
+        let config = match self.project.config() {
+            Ok(config) => config,
+            // reported by the build
+            Err(_) => return,
+        };
+        let nix_file = match self.project.source.nix_file() {
+            Some(nix_file) => nix_file,
+            // expressions and remote flakes are found by their first build
+            None => return,
+        };
+        let root = self.project.config_root();
+        let paths = backfill::references(
+            Path::new(nix_file.as_os_str()),
+            &root.canonicalize().unwrap_or_else(|_| root.to_path_buf()),
+        );
+        let reduced = reduce_paths(&paths);
+        warn_skipped(&reduced.skipped);
+        let (watched, hashed) = config
+            .watch
+            .partition(self.project.config_root(), reduced.paths);
+        debug!(
+            "backfilled {} watched, {} hashed paths",
+            watched.len(),
+            hashed.len()
+        );
+        if let Err(e) = self.watch.extend(&watched) {
+            warn!("could not watch the paths the nix file refers to: {}", e);
+        }
+        self.watch.extend_hashed(&hashed);
+    }
+
+    /// Push the build result to the project’s cachix cache in the
+    /// background, if configured, and report the outcome on `tx`.
+    fn push_to_cachix(&self, result: &BuildResults, tx: Sender<Event>) {
+        let config = match self.project.config() {
+            Ok(config) => config.cachix,
+            // reported by the build already
+            Err(_) => return,
+        };
+        let path = result.output_paths.shell_gc_root.clone();
+        std::thread::spawn(move || {
+            if let Some(outcome) = cachix::push(&config, path) {
+                // the receiver is gone if the loop stopped while
+                // pushing, nobody is left to tell then
+                let _ = tx.send(Event::CachixPush(outcome));
+            }
+        });
+    }
+
+    /// Run the project’s push command on the environment of the
+    /// last build in the background (after the running push, see
+    /// `push::Queue`), if configured, and report the outcome on `tx`.
+    fn run_push_command(&self, tx: Sender<Event>) {
+        let config = match self.project.config() {
+            Ok(config) => config.push,
+            // reported by the build already
+            Err(_) => return,
+        };
+        if config.command.is_none() {
+            return;
+        }
+        let output_paths = match Roots::from_project(self.project).current() {
+            Some(output_paths) => output_paths,
+            None => {
+                warn!("cannot push the environment, its GC roots are gone");
+                return;
+            }
+        };
+        let paths = std::iter::once(output_paths.shell_gc_root)
+            .chain(output_paths.shells.values().cloned())
+            .collect();
+        self.pushes.push(config, paths, move |outcome| {
+            tx.send(Event::Pushed(outcome))
+                .expect("Failed to notify the outcome of a push");
+        });
+    }
+
+    /// Whether the environment `drv_path` of a successful build has
+    /// the same inputs as the one of the build before it.
+    fn compare_inputs(&mut self, drv_path: Option<&PathBuf>) -> Rebuild {
+        let inputs = match drv_path.map(|drv| derivation::read_inputs(drv)) {
+            Some(Ok(inputs)) => Some(inputs),
+            Some(Err(e)) => {
+                debug!("cannot read the inputs of the environment: {}", e);
+                None
+            }
+            None => None,
+        };
+        let rebuild = match (&self.last_inputs, &inputs) {
+            (Some(last), Some(inputs)) if last == inputs => Rebuild::EvalOnly,
+            _ => Rebuild::Full,
+        };
+        self.last_inputs = inputs;
+        rebuild
+    }
+
+    /// Hash the environment in `output_paths` (see `env::hash`), and
+    /// record it for `lorri direnv` and `lorri info`.
+    fn record_env_hash(
+        &self,
+        output_paths: &builder::OutputPaths<roots::RootPath>,
+    ) -> Option<String> {
+        let file = self.project.env_hash_file();
+        match env::read(&output_paths.bash_export()) {
+            Ok(environment) => {
+                let hash = env::hash(&environment);
+                if let Err(e) = fs::write(&file, &hash) {
+                    warn!("could not write {}: {}", file.display(), e);
                 }
+                Some(hash)
+            }
+            Err(e) => {
+                warn!("could not hash the environment: {}", e);
+                // don’t leave the hash of the previous one behind
+                let _ = fs::remove_file(&file);
+                None
+            }
+        }
+    }
+
+    /// Open the file the nix output of the next build is appended to,
+    /// if one is configured.
+    fn open_log(&self, config: &LogConfig) -> Option<fs::File> {
+        let path = config.nix_output_path(self.project.config_root(), SystemTime::now())?;
+        let file = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| fs::OpenOptions::new().create(true).append(true).open(&path));
+        match file {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!("could not open the build log {}: {}", path.display(), e);
+                None
             }
+        }
+    }
 
-            self.watch.wait_for_change().expect("Waiter exited");
+    /// Snapshot a failed build in the project’s failures directory,
+    /// see `project::failures`. Errors are logged.
+    fn save_failure(
+        &self,
+        inputs: &[PathBuf],
+        log_lines: &[std::ffi::OsString],
+        command: &str,
+    ) -> Option<PathBuf> {
+        let mut inputs = inputs.to_vec();
+        if let Some(nix_file) = self.project.source.nix_file() {
+            inputs.push(PathBuf::from(nix_file.as_os_str()));
+        }
+        let snapshot = failures::Snapshot {
+            project_dir: self.project.config_root(),
+            inputs: &inputs,
+            log_lines,
+            command,
+        };
+        match snapshot.save(&self.project.failures_dir(), SystemTime::now()) {
+            Ok(dir) => {
+                info!("saved the failed build to {}", dir.display());
+                Some(dir)
+            }
+            Err(e) => {
+                warn!("could not save the failed build: {}", e);
+                None
+            }
         }
     }
 
     /// Execute a single build of the environment.
     ///
     /// This will create GC roots and expand the file watch list for
-    /// the evaluation.
+    /// the evaluation, and push the result to cachix if configured
+    /// (waiting for the push to finish).
     pub fn once(&mut self) -> Result<BuildResults, BuildError> {
-        let build = builder::run(&self.project.nix_file, &self.project.cas)?;
+        let result = self.build(|_| ())?;
+        if let Ok(config) = self.project.config() {
+            let path = result.output_paths.shell_gc_root.clone();
+            match cachix::push(&config.cachix, path) {
+                Some(cachix::PushOutcome {
+                    cache,
+                    result: Err(e),
+                    ..
+                }) => warn!("could not push to cachix cache {}: {}", cache, e),
+                Some(cachix::PushOutcome { cache, path, .. }) => {
+                    info!("pushed {} to cachix cache {}", path, cache)
+                }
+                None => {}
+            }
+        }
+        Ok(result)
+    }
+
+    /// The paths the last build read, reduced to the files and
+    /// directories which are watched for them (see `reduce_paths`),
+    /// sorted.
+    pub fn input_paths(&self) -> &[PathBuf] {
+        &self.input_paths
+    }
+
+    /// Like `once`, but report the progress and output of the
+    /// build to `on_report`.
+    fn build<F>(&mut self, on_report: F) -> Result<BuildResults, BuildError>
+    where
+        F: FnMut(builder::Report),
+    {
+        self.build_log.start();
+        self.changed_during_build = false;
+        let result = self.run_build(on_report);
+        self.build_log.finish();
+        result
+    }
+
+    /// Set up `watch` as the project configuration `config` says.
+    fn configure_watch(&mut self, config: &ProjectConfig) -> Result<(), BuildError> {
+        let config_root = self.project.config_root();
+        let ignore = match config.watch.ignore_rules(config_root) {
+            Ok(ignore) => ignore,
+            Err(e) => {
+                return Err(BuildError::Recoverable(BuildExitFailure {
+                    log_lines: vec![format!("invalid {}: {}", CONFIG_FILE_NAME, e).into()],
+                    artifacts: None,
+                }))
+            }
+        };
+        // nix reports the inputs by their canonical paths
+        self.watch.set_ignore(
+            &config_root
+                .canonicalize()
+                .unwrap_or_else(|_| config_root.to_path_buf()),
+            ignore,
+        );
+        self.watch.set_debounce(
+            config
+                .watch
+                .debounce_ms
+                .map(Duration::from_millis)
+                .unwrap_or(self.debounce),
+        );
+
+        if cfg!(target_os = "macos") {
+            let macos = &config.watch.macos;
+            self.watch
+                .set_latency(Duration::from_millis(macos.latency_ms));
+            self.watch
+                .set_directory_granularity(macos.directory_granularity);
+        }
+        Ok(())
+    }
+
+    /// The files of `reads` which are neither `tracked` nor always
+    /// watched (the nix file, configuration and flake lock), with a
+    /// warning for each.
+    fn untracked(&self, reads: &[PathBuf], tracked: &[PathBuf]) -> Vec<PathBuf> {
+        let mut untracked = read_trace::untracked(reads, tracked, &self.project.state_dirs());
+        let nix_file = self.project.source.nix_file();
+        untracked.retain(|path| {
+            nix_file
+                .as_ref()
+                .map_or(true, |nix_file| path.as_os_str() != nix_file.as_os_str())
+                && path.file_name() != Some(CONFIG_FILE_NAME.as_ref())
+                && path.file_name() != Some(flake::FLAKE_LOCK_FILE_NAME.as_ref())
+        });
+        for path in &untracked {
+            warn!("nix read {}, but lorri doesn’t watch it", path.display());
+        }
+        untracked
+    }
+
+    /// See `build`.
+    fn run_build<F>(&mut self, on_report: F) -> Result<BuildResults, BuildError>
+    where
+        F: FnMut(builder::Report),
+    {
+        let config = match self.project.config() {
+            Ok(config) => config,
+            Err(e) => {
+                return Err(BuildError::Recoverable(BuildExitFailure {
+                    log_lines: vec![e.to_string().into()],
+                    artifacts: None,
+                }))
+            }
+        };
+
+        self.configure_watch(&config)?;
+
+        let mut options = match self.project.nix_options(&config) {
+            Ok(options) => options,
+            Err(e) => {
+                return Err(BuildError::Recoverable(BuildExitFailure {
+                    log_lines: vec![e.into()],
+                    artifacts: None,
+                }))
+            }
+        };
+
+        if config.watch.strict {
+            options.trace_reads();
+        }
+        if self.detach_builds {
+            options.detach();
+        }
+
+        let mut log = Tee {
+            file: self.open_log(&config.log),
+            build_log: self.build_log.writer(),
+        };
+        let cancel_on_change = self.cancel_on_change;
+        let mut changed = false;
+        let build = {
+            let watch = &self.watch;
+            builder::run(
+                &self.project.source,
+                &self.project.cas,
+                &self.project.store,
+                &options,
+                Some(&mut log),
+                &self.canceller,
+                on_report,
+                || {
+                    if cancel_on_change {
+                        changed = watch.poll_change();
+                    } else {
+                        // the changes are built next, but when they
+                        // arrived counts for their latency
+                        watch.note_arrivals();
+                    }
+                    changed
+                },
+            )
+        };
+        self.changed_during_build = changed;
+        let build = match build {
+            Err(builder::Error::Cancelled) => return Err(BuildError::Cancelled),
+            result => result?,
+        };
         let roots = Roots::from_project(&self.project);
 
-        let paths = build.paths;
+        let mut paths = build.paths;
         debug!("original paths: {:?}", paths.len());
+        if let Some(dir) = self.project.source.local_flake_dir() {
+            // nix evaluates a copy of the flake in the store
+            let inputs = flake::local_inputs(&paths, &dir, &self.project.store);
+            debug!("  -> {} in the local flake", inputs.len());
+            paths.extend(inputs);
+        }
 
-        let paths = reduce_paths(&paths);
+        let artifacts = if build.exec_result.success() {
+            None
+        } else {
+            self.save_failure(&paths, &build.log_lines, &build.command)
+        };
+
+        let reduced = reduce_paths(&paths);
+        warn_skipped(&reduced.skipped);
+        let paths = reduced.paths;
         debug!("  -> reduced to: {:?}", paths.len());
+        self.input_paths = paths.iter().cloned().collect();
+        self.input_paths.sort();
 
         debug!("named drvs: {:#?}", build.output_paths);
 
-        let event = BuildResults {
-            output_paths: roots.create_roots(build.output_paths)?,
-        };
+        // add all new (reduced) nix sources to the input source watchlist,
+        // or track them by hash if they are out of the watch scope
+        let (mut watched, mut hashed) = config.watch.partition(self.project.config_root(), paths);
+        // inputs with mtimes in the future are probably on a network
+        // filesystem with a skewed clock, where notifications are
+        // unreliable, too
+        let skewed = skew::ahead_of(&watched, SystemTime::now());
+        watched.retain(|path| !skewed.iter().any(|skewed| &skewed.path == path));
+        hashed.extend(skewed.iter().map(|skewed| skewed.path.clone()));
+        if let Some(ahead) = skew::max_ahead(&skewed) {
+            if ahead > Duration::from_secs(config.watch.max_clock_skew_secs) {
+                warn!(
+                    "{} inputs have mtimes up to {}s in the future, is the clock of their filesystem skewed?",
+                    skewed.len(),
+                    ahead.as_secs()
+                );
+                self.clock_skew = Some(Event::ClockSkew {
+                    paths: skewed.into_iter().map(|skewed| skewed.path).collect(),
+                    ahead,
+                });
+            }
+        }
+        debug!("  -> {} watched, {} hashed", watched.len(), hashed.len());
+        self.watch.extend(&watched)?;
+        self.watch.extend_hashed(&hashed);
+
+        if let Some(ref reads) = build.reads {
+            let tracked: Vec<PathBuf> = watched.iter().chain(&hashed).cloned().collect();
+            self.untracked_reads = self.untracked(reads, &tracked);
+        }
 
-        // add all new (reduced) nix sources to the input source watchlist
-        self.watch.extend(&paths.into_iter().collect::<Vec<_>>())?;
+        // changing the configuration might change the build, too
+        let config_file = self.project.config_root().join(CONFIG_FILE_NAME);
+        if config_file.exists() {
+            self.watch.extend(&[config_file])?;
+        }
+        // and so does updating the inputs of a flake
+        if let Some(dir) = self.project.source.local_flake_dir() {
+            let lock_file = dir.join(flake::FLAKE_LOCK_FILE_NAME);
+            if lock_file.exists() {
+                self.watch.extend(&[lock_file])?;
+            }
+        }
 
-        if build.exec_result.success() {
+        // the roots keep pointing to the previous environment
+        // until a build succeeds
+        if let Some(output_paths) = build.output_paths {
+            if roots.current().as_ref() != Some(&output_paths) {
+                self.switched = Some(output_paths.clone());
+            }
+            let rebuild = self.compare_inputs(build.drv_path.as_ref());
+            let output_paths = roots.create_roots(output_paths)?;
+            let env_hash = self.record_env_hash(&output_paths);
+            let event = BuildResults {
+                output_paths,
+                cache_stats: build.cache_stats,
+                drv_path: build.drv_path,
+                timings: build.timings,
+                rebuild,
+                env_hash,
+                watched_files: self.watch.watched_files(),
+            };
+            if let Err(e) = bin_dir::update(
+                &self.project.bin_dir(),
+                &self.project.store.store_dir(),
+                &event.output_paths,
+            ) {
+                warn!(
+                    "could not update {}: {}",
+                    self.project.bin_dir().display(),
+                    e
+                );
+            }
+            for format in config.ide_env.formats {
+                if let Err(e) =
+                    ide_env::write(self.project.config_root(), &event.output_paths, format)
+                {
+                    warn!("could not write the {:?} IDE environment: {}", format, e);
+                }
+            }
             Ok(event)
         } else {
-            Err(BuildError::Recoverable(BuildExitFailure {
-                log_lines: build.log_lines,
-            }))
+            let failure = BuildExitFailure {
+                log_lines: config.log.cap_failure_log(build.log_lines),
+                artifacts,
+            };
+            Err(failure.into_error(config.nix.allow_ifd))
+        }
+    }
+}
+
+impl BuildExitFailure {
+    /// The line in which nix (or a tool it ran, like git or ssh)
+    /// asked for input, if the build failed because of that.
+    /// Builds can’t answer prompts, see `nix::non_interactive`.
+    pub fn interactive_prompt(&self) -> Option<String> {
+        self.log_lines
+            .iter()
+            .map(|line| line.to_string_lossy())
+            .find(|line| builder::asks_for_input(line))
+            .map(|line| line.into_owned())
+    }
+
+    /// The derivation which evaluation had to build, and where the
+    /// nix expression imports from it (if nix said), if the build
+    /// failed because import from derivation is disabled.
+    pub fn import_from_derivation(&self) -> Option<(String, Option<String>)> {
+        lazy_static! {
+            static ref DISABLED: Regex = Regex::new(
+                "cannot build '(?P<drv>[^']*)' during evaluation because the option \
+                 'allow-import-from-derivation' is disabled"
+            )
+            .expect("invalid regex!");
+            static ref POSITION: Regex =
+                Regex::new(r"^\s*at (?P<position>[^:\s]+:\d+:\d+):?$").expect("invalid regex!");
+        }
+        let lines: Vec<_> = self
+            .log_lines
+            .iter()
+            .map(|line| line.to_string_lossy())
+            .collect();
+        let (index, drv) = lines.iter().enumerate().find_map(|(index, line)| {
+            DISABLED
+                .captures(line)
+                .map(|matches| (index, matches["drv"].to_owned()))
+        })?;
+        // nix prints the position right after the error
+        let position = lines[index..]
+            .iter()
+            .find_map(|line| POSITION.captures(line))
+            .map(|matches| matches["position"].to_owned());
+        Some((drv, position))
+    }
+
+    /// Whether the build failed because of a (probably transient)
+    /// network error, like a substituter timing out. Only nix’s
+    /// `error:` lines count, not warnings (nix retries those
+    /// downloads itself, and reports an error if that fails, too)
+    /// or what builders print.
+    pub fn is_network_error(&self) -> bool {
+        lazy_static! {
+            static ref NETWORK_ERROR: Regex = Regex::new(
+                "^\\s*error:.*(?:HTTP error (?:5[0-9][0-9]|429)|Timeout was reached\
+                 |Could(?:n't| not) resolve host|Couldn't connect to server\
+                 |Connection timed out|Connection reset by peer|SSL connect error)"
+            )
+            .expect("invalid regex!");
+        }
+        self.log_lines
+            .iter()
+            .any(|line| NETWORK_ERROR.is_match(&line.to_string_lossy()))
+    }
+
+    /// The error this failure is: `Interactive` if nix asked for
+    /// input, `Network` if it couldn’t download something, and
+    /// `Recoverable` otherwise, with a hint if the build needed
+    /// import from derivation, which `allow_ifd` forbids.
+    fn into_error(mut self, allow_ifd: Option<bool>) -> BuildError {
+        let import_from_derivation = match allow_ifd {
+            Some(false) => self.import_from_derivation(),
+            _ => None,
+        };
+        if let Some((drv, position)) = import_from_derivation {
+            self.log_lines.push(
+                format!(
+                    "lorri: evaluation needs {} built{}, to import from it, which \
+                     `allow-ifd = false` in the `[nix]` section of {} forbids; \
+                     build it in a separate step, or allow import from derivation",
+                    drv,
+                    position
+                        .map(|position| format!(" (at {})", position))
+                        .unwrap_or_default(),
+                    CONFIG_FILE_NAME
+                )
+                .into(),
+            );
+            BuildError::Recoverable(self)
+        } else if let Some(prompt) = self.interactive_prompt() {
+            self.log_lines.push(
+                format!(
+                    "lorri: nix asked for input (“{}”), but builds run without a terminal; \
+                     set up credentials or known ssh hosts so they work non-interactively",
+                    prompt.trim()
+                )
+                .into(),
+            );
+            BuildError::Interactive(self)
+        } else if self.is_network_error() {
+            BuildError::Network(self)
+        } else {
+            BuildError::Recoverable(self)
         }
     }
 }
@@ -131,6 +1299,18 @@ pub enum BuildError {
     /// the Nix expression itself.
     Recoverable(BuildExitFailure),
 
+    /// The build was cancelled.
+    Cancelled,
+
+    /// Like `Recoverable`, but caused by a network error (e.g. while
+    /// downloading from a substituter), so retrying might help.
+    Network(BuildExitFailure),
+
+    /// Like `Recoverable`, but caused by nix (or a fetcher) asking
+    /// for input, like a password or to accept an ssh host key.
+    /// The last log line explains what to do about it.
+    Interactive(BuildExitFailure),
+
     /// Unrecoverable errors are anything else: a broken Nix,
     /// permission problems, etc.
     Unrecoverable(UnrecoverableErrors),
@@ -159,8 +1339,229 @@ impl From<roots::AddRootError> for BuildError {
         BuildError::Unrecoverable(UnrecoverableErrors::AddRoot(e))
     }
 }
+impl From<ExtendError> for BuildError {
+    fn from(e: ExtendError) -> BuildError {
+        match e {
+            ExtendError::Notify(e) => e.into(),
+            // a path lorri refuses to watch, which needs configuring
+            ExtendError::TooBroad { .. } => BuildError::Recoverable(BuildExitFailure {
+                log_lines: vec![e.to_string().into()],
+                artifacts: None,
+            }),
+        }
+    }
+}
 impl From<notify::Error> for BuildError {
     fn from(e: notify::Error) -> BuildError {
         BuildError::Unrecoverable(UnrecoverableErrors::Notify(e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        restart_failed, retry_delay, Activity, BuildExitFailure, UnrecoverableErrors,
+        RESTART_DELAY, RESTART_RESET_AFTER,
+    };
+    use crate::builder::Canceller;
+    use crate::clock::{Clock, FakeClock};
+    use crate::notify;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    /// Run `restart_failed` in a thread, with a loop which fails
+    /// after running for as long as it is sent; it sends back when
+    /// it was started.
+    fn restarting(
+        clock: &FakeClock,
+        canceller: &Canceller,
+    ) -> (mpsc::Sender<Duration>, mpsc::Receiver<Duration>) {
+        let (run_tx, run_rx) = mpsc::channel::<Duration>();
+        let (started_tx, started_rx) = mpsc::channel();
+        let clock = clock.clone();
+        let canceller = canceller.clone();
+        std::thread::spawn(move || {
+            let start = clock.now();
+            restart_failed(&clock, &canceller, || {
+                started_tx.send(clock.now() - start).unwrap();
+                // the test is over
+                let ran = match run_rx.recv() {
+                    Ok(ran) => ran,
+                    Err(_) => return Ok(()),
+                };
+                clock.advance(ran);
+                Err(UnrecoverableErrors::Notify(notify::Error::Generic(
+                    "broken".to_string(),
+                )))
+            })
+        });
+        (run_tx, started_rx)
+    }
+
+    #[test]
+    fn restart_with_backoff() {
+        let clock = FakeClock::new();
+        let canceller = Canceller::new();
+        let (run, started) = restarting(&clock, &canceller);
+        let mut at = started.recv().unwrap();
+        assert_eq!(at, Duration::from_secs(0));
+
+        let mut delay = RESTART_DELAY;
+        for _ in 0..3 {
+            run.send(Duration::from_secs(1)).unwrap();
+            clock.wait_for_sleepers(1);
+            clock.advance(delay - Duration::from_millis(1));
+            clock.wait_for_sleepers(1);
+            assert!(started.try_recv().is_err());
+            clock.advance(Duration::from_millis(1));
+            let restarted = started.recv().unwrap();
+            assert_eq!(restarted - at, Duration::from_secs(1) + delay);
+            at = restarted;
+            delay *= 2;
+        }
+
+        // a loop which ran long enough starts the backoff over
+        run.send(RESTART_RESET_AFTER).unwrap();
+        clock.wait_for_sleepers(1);
+        clock.advance(RESTART_DELAY);
+        assert_eq!(
+            started.recv().unwrap() - at,
+            RESTART_RESET_AFTER + RESTART_DELAY
+        );
+
+        canceller.stop();
+    }
+
+    #[test]
+    fn no_restart_when_stopped() {
+        let clock = FakeClock::new();
+        let canceller = Canceller::new();
+        let (run, started) = restarting(&clock, &canceller);
+        started.recv().unwrap();
+
+        run.send(Duration::from_secs(1)).unwrap();
+        clock.wait_for_sleepers(1);
+        canceller.stop();
+        clock.advance(RESTART_DELAY);
+        assert!(started.recv().is_err());
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let delays: Vec<u64> = (1..=6)
+            .map(|attempt| {
+                retry_delay(Duration::from_secs(5), Duration::from_secs(60), attempt).as_secs()
+            })
+            .collect();
+        assert_eq!(delays, vec![5, 10, 20, 40, 60, 60]);
+        assert_eq!(
+            retry_delay(Duration::from_secs(5), Duration::from_secs(60), 100),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn network_errors() {
+        let failure = |line: &str| BuildExitFailure {
+            log_lines: vec![line.into()],
+            artifacts: None,
+        };
+        assert!(!failure(
+            "warning: unable to download 'https://cache.nixos.org/abc.narinfo': HTTP error 503"
+        )
+        .is_network_error());
+        assert!(failure(
+            "error: unable to download 'https://example.com/src.tar.gz': Timeout was reached (28)"
+        )
+        .is_network_error());
+        assert!(failure(
+            "error: unable to download 'https://example.com/src.tar.gz': \
+             Couldn't resolve host name (6)"
+        )
+        .is_network_error());
+        // a build’s own output
+        assert!(!failure("curl: (6) Couldn't resolve host 'example.com'").is_network_error());
+        assert!(!failure("Timeout was reached, error: retrying").is_network_error());
+        // nix retries by itself, a later failure is another one
+        assert!(!failure(
+            "warning: error: unable to download 'https://cache.example.com/nix-cache-info': \
+             Couldn't connect to server (7); retrying in 281 ms"
+        )
+        .is_network_error());
+        assert!(failure(
+            "error: unable to download 'https://api.github.com/repos/o/r/tarball/abc': HTTP error 429"
+        )
+        .is_network_error());
+        assert!(!failure(
+            "error: unable to download 'https://example.com/src.tar.gz': HTTP error 404"
+        )
+        .is_network_error());
+        assert!(!failure("error: undefined variable 'foo' at /shell.nix:1:1").is_network_error());
+    }
+
+    #[test]
+    fn import_from_derivation() {
+        let failure = BuildExitFailure {
+            log_lines: vec![
+                "error: cannot build '/nix/store/abc-gen.drv^out' during evaluation \
+                 because the option 'allow-import-from-derivation' is disabled"
+                    .into(),
+                "".into(),
+                "       at /home/user/project/shell.nix:4:9:".into(),
+                "            3| let".into(),
+            ],
+            artifacts: None,
+        };
+        assert_eq!(
+            failure.import_from_derivation(),
+            Some((
+                String::from("/nix/store/abc-gen.drv^out"),
+                Some(String::from("/home/user/project/shell.nix:4:9"))
+            ))
+        );
+        let other = BuildExitFailure {
+            log_lines: vec!["error: undefined variable 'foo' at /shell.nix:1:1".into()],
+            artifacts: None,
+        };
+        assert_eq!(other.import_from_derivation(), None);
+    }
+
+    #[test]
+    fn interactive_prompts() {
+        let failure = |line: &str| BuildExitFailure {
+            log_lines: vec!["building...".into(), line.into()],
+            artifacts: None,
+        };
+        assert_eq!(
+            failure("Host key verification failed.").interactive_prompt(),
+            Some(String::from("Host key verification failed."))
+        );
+        assert!(failure(
+            "fatal: could not read Username for 'https://github.com': terminal prompts disabled"
+        )
+        .interactive_prompt()
+        .is_some());
+        assert!(
+            failure("Enter passphrase for key '/home/user/.ssh/id_ed25519':")
+                .interactive_prompt()
+                .is_some()
+        );
+        assert!(
+            failure("error: undefined variable 'password' at /shell.nix:1:1")
+                .interactive_prompt()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn wait_idle_timeout() {
+        let activity = Activity::new();
+        assert!(!activity.wait_idle_timeout(Duration::from_millis(10)));
+
+        let busy = activity.clone();
+        let idle = std::thread::spawn(move || busy.wait_idle_timeout(Duration::from_secs(10)));
+        activity.set_idle();
+        assert!(idle.join().unwrap());
+        assert!(activity.wait_idle_timeout(Duration::from_millis(0)));
+    }
+}
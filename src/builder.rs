@@ -7,54 +7,85 @@
 //! can parse additional information from the `nix-build`
 //! `stderr`, like which source files are used by the evaluator.
 
+use build_loop::BuildId;
 use cas::ContentAddressable;
-use nix::StorePath;
+use nix::{self, Options, Store, StorePath};
 use osstrlines;
+use read_trace;
 use regex::Regex;
+use serde_json;
 use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::{OsStr, OsString};
-use std::io::BufReader;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::fmt;
+use std::io::{BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use NixFile;
+use std::time::{Duration, Instant};
+use NixSource;
+
+/// How often a running build checks whether it should be cancelled
+/// (see `run`).
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A build which printed something like a prompt (see
+/// `asks_for_input`) and then nothing for this long waits for input
+/// it will never get, and is interrupted.
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(10);
 
 // TODO: when moving to CallOpts, you have to change the names of the roots CallOpts generates!
-fn instrumented_build(
-    root_nix_file: &NixFile,
+#[allow(clippy::too_many_arguments)]
+fn instrumented_build<F, C>(
+    source: &NixSource,
     cas: &ContentAddressable,
-) -> Result<Info<StorePath>, Error> {
-    // We're looking for log lines matching:
-    //
-    //     copied source '...' -> '/nix/store/...'
-    //     evaluating file '...'
-    //
-    // to determine which files we should setup watches on.
-    // Increasing verbosity by two levels via `-vv` satisfies that.
+    store: &Store,
+    options: &Options,
+    mut log: Option<&mut dyn Write>,
+    canceller: &Canceller,
+    mut on_report: F,
+    mut cancel_when: C,
+) -> Result<Info<StorePath>, Error>
+where
+    F: FnMut(Report),
+    C: FnMut() -> bool,
+{
+    let internal_json = *SUPPORTS_INTERNAL_JSON;
+    let trace_file = if options.traces_reads() {
+        Some(tempfile::NamedTempFile::new()?)
+    } else {
+        None
+    };
+    let traced = trace_file
+        .as_ref()
+        .and_then(|file| read_trace::command("nix-build", file.path()));
+    let tracing = traced.is_some();
+    if trace_file.is_some() && !tracing {
+        warn!("strict input tracking needs `strace`, which is not installed");
+    }
+    let mut cmd = traced.unwrap_or_else(|| Command::new("nix-build"));
+    cmd.args(nix_build_args(source, cas, store, options)?)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    nix::non_interactive(&mut cmd);
+    // a session of its own also makes it the leader of a new process
+    // group: cancelling the build interrupts everything nix-build
+    // started, too
+    let detached = options.detaches();
+    if detached {
+        nix::detach(&mut cmd);
+    } else if tracing {
+        // strace doesn’t pass on signals to nix-build, so it needs a
+        // process group to cancel as well
+        signal::new_group(&mut cmd);
+    }
 
-    let mut cmd = Command::new("nix-build");
-
-    let logged_evaluation_nix = cas.file_from_string(include_str!("./logged-evaluation.nix"))?;
-
-    cmd.args(&[
-        OsStr::new("-vv"),
-        // TODO: this must create a GcRootTempDir and pass it out
-        OsStr::new("--no-out-link"),
-        OsStr::new("--argstr"),
-        OsStr::new("runTimeClosure"),
-        OsStr::new(crate::RUN_TIME_CLOSURE),
-        OsStr::new("--argstr"),
-        OsStr::new("src"),
-        root_nix_file.as_os_str(),
-        OsStr::new("--"),
-        &logged_evaluation_nix.as_os_str(),
-    ])
-    .stdin(Stdio::null())
-    .stdout(Stdio::piped())
-    .stderr(Stdio::piped());
-
-    debug!("$ {:?}", cmd);
+    let command = format!("{:?}", cmd);
+    debug!("$ {}", command);
 
+    let started = Instant::now();
     let mut child = cmd.spawn()?;
 
     let stdout = child
@@ -65,13 +96,33 @@ fn instrumented_build(
         .stderr
         .take()
         .expect("we must be able to access the stderr of nix-build");
+    canceller.start(child, detached || tracing);
 
-    let stderr_results: thread::JoinHandle<std::io::Result<Vec<LogDatum>>> =
-        thread::spawn(move || {
-            osstrlines::Lines::from(BufReader::new(stderr))
-                .map(|line| line.map(parse_evaluation_line))
-                .collect::<Result<Vec<LogDatum>, _>>()
-        });
+    // stderr is parsed in a separate thread; the parsed lines are
+    // passed back as they arrive, so that progress can be reported
+    // while the build is still running.
+    let (stderr_tx, stderr_rx) = mpsc::channel();
+    let unterminated = Arc::new(Mutex::new(vec![]));
+    let stderr = Unterminated {
+        inner: stderr,
+        line: unterminated.clone(),
+    };
+    let stderr_results: thread::JoinHandle<std::io::Result<()>> = thread::spawn(move || {
+        let mut parser = InternalJsonParser::new();
+        for line in osstrlines::Lines::from(BufReader::new(stderr)) {
+            let line = line?;
+            let datum = if internal_json {
+                parser.parse(&line)
+            } else {
+                Some(parse_evaluation_line(&line))
+            };
+            if let Some(datum) = datum {
+                // the receiving end only hangs up if the build is abandoned
+                let _ = stderr_tx.send(datum);
+            }
+        }
+        Ok(())
+    });
 
     let build_products: thread::JoinHandle<std::io::Result<Vec<StorePath>>> =
         thread::spawn(move || {
@@ -80,63 +131,843 @@ fn instrumented_build(
                 .collect::<Result<Vec<StorePath>, _>>()
         });
 
-    let (exec_result, mut build_products, results) = (
-        child.wait()?,
+    // iterate over all lines, parsing out the ones we are interested in
+    let mut paths: Vec<PathBuf> = vec![];
+    let mut log_lines: Vec<OsString> = vec![];
+    let mut cache_stats = CacheStats::default();
+    let mut instantiated: Option<(PathBuf, Instant)> = None;
+    let mut polling = true;
+    let mut polled = Instant::now();
+    let mut last_output = Instant::now();
+    loop {
+        let received = stderr_rx.recv_timeout(CANCEL_POLL_INTERVAL);
+        // also while nix keeps printing, which it may never stop
+        if polling && polled.elapsed() >= CANCEL_POLL_INTERVAL {
+            polled = Instant::now();
+            if cancel_when() {
+                debug!("cancelling the build of {}", source);
+                canceller.cancel();
+                polling = false;
+            }
+        }
+        let result = match received {
+            Ok(result) => {
+                last_output = Instant::now();
+                result
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                if polling && last_output.elapsed() >= PROMPT_TIMEOUT {
+                    let pending = unterminated.lock().expect("stderr lock poisoned").clone();
+                    if let Some(prompt) = waiting_for_input(&pending, log_lines.last()) {
+                        warn!("the build of {} waits for input: {}", source, prompt);
+                        // nothing follows the prompt, so keep it for
+                        // the failure (see `asks_for_input`)
+                        if !pending.is_empty() {
+                            log_lines.push(OsString::from(prompt));
+                        }
+                        canceller.interrupt();
+                        polling = false;
+                    }
+                }
+                continue;
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        match result {
+            LogDatum::CopiedSource(src) | LogDatum::ReadFileOrDir(src) => {
+                paths.push(src);
+            }
+            LogDatum::NixSourceFile(mut src) => {
+                // We need to emulate nix’s `default.nix` mechanism here.
+                // That is, if the user uses something like
+                // `import ./foo`
+                // and `foo` is a directory, nix will actually import
+                // `./foo/default.nix`
+                // but still print `./foo`.
+                // Since this is the only time directories are printed,
+                // we can just manually re-implement that behavior.
+                if src.is_dir() {
+                    src.push("default.nix");
+                }
+                paths.push(src);
+            }
+            LogDatum::Progress(progress) => on_report(Report::Progress(progress)),
+            LogDatum::Substituted(substituter) => cache_stats.substituted_from(substituter),
+            LogDatum::Built => cache_stats.built += 1,
+            LogDatum::Text(line) => {
+                cache_stats.count_text(&line);
+                if let Some(drv_path) = instantiated_environment(&line) {
+                    instantiated = Some((drv_path, Instant::now()));
+                }
+                write_log(&mut log, line.as_bytes());
+                on_report(Report::LogLine(line.clone()));
+                log_lines.push(OsString::from(line))
+            }
+            LogDatum::NonUtf(line) => {
+                write_log(&mut log, line.as_bytes());
+                on_report(Report::LogLine(line.to_string_lossy().into_owned()));
+                log_lines.push(line)
+            }
+        };
+    }
+
+    let ((exec_result, cancelled), build_products, ()) = (
+        canceller.wait()?,
         build_products.join()??,
         stderr_results.join()??,
     );
+    if cancelled {
+        return Err(Error::Cancelled);
+    }
+    let finished = Instant::now();
+    let (drv_path, timings) = match instantiated {
+        Some((drv_path, evaluated)) => (
+            Some(drv_path),
+            Timings {
+                evaluate: evaluated - started,
+                realise: finished - evaluated,
+            },
+        ),
+        // evaluation failed, or nix didn’t say
+        None => (
+            None,
+            Timings {
+                evaluate: finished - started,
+                realise: Duration::from_secs(0),
+            },
+        ),
+    };
+
+    // failed builds have no outputs
+    let output_paths = if exec_result.success() {
+        Some(output_paths(options.shell_names(), build_products))
+    } else {
+        None
+    };
+
+    let reads = match trace_file {
+        Some(ref file) if tracing => Some(read_trace::read(file.path())?),
+        _ => None,
+    };
+
+    Ok(Info {
+        exec_result,
+        command,
+        output_paths,
+        paths,
+        log_lines,
+        cache_stats,
+        drv_path,
+        timings,
+        reads,
+    })
+}
+
+/// The derivation of the environment, if `line` is the message of
+/// nix (at `-vv`) that it instantiated it. For projects with named
+/// shells, it is the one of every shell in turn.
+fn instantiated_environment(line: &str) -> Option<PathBuf> {
+    lazy_static! {
+        static ref INSTANTIATED: Regex =
+            Regex::new("^instantiated 'lorri-keep-env-hack-[^']*' -> '(?P<drv>[^']*)'$")
+                .expect("invalid regex!");
+    }
+    INSTANTIATED
+        .captures(line)
+        .map(|matches| PathBuf::from(&matches["drv"]))
+}
 
+/// Assign the store paths `nix-build` printed for `logged-evaluation.nix`
+/// to the `OutputPaths`: one path for the evaluated shell, or one per
+/// named shell (see `nix::Options::shells`), sorted by name.
+fn output_paths(
+    shell_names: &[String],
+    mut build_products: Vec<StorePath>,
+) -> OutputPaths<StorePath> {
+    let expected = shell_names.len().max(1);
     assert!(
-        build_products.len() == 1,
-        "got more or less than one build product from logged_evaluation.nix: {:#?}",
+        build_products.len() == expected,
+        "expected {} build products from logged_evaluation.nix, got: {:#?}",
+        expected,
         build_products
     );
-    let shell_gc_root = build_products.pop().unwrap();
+    if shell_names.is_empty() {
+        return OutputPaths {
+            shell_gc_root: build_products.pop().unwrap(),
+            shells: BTreeMap::new(),
+        };
+    }
+    let mut sorted_names = shell_names.to_vec();
+    sorted_names.sort();
+    let shells: BTreeMap<String, StorePath> =
+        sorted_names.into_iter().zip(build_products).collect();
+    OutputPaths {
+        // the first shell is the default one
+        shell_gc_root: shells[&shell_names[0]].clone(),
+        shells,
+    }
+}
 
-    // iterate over all lines, parsing out the ones we are interested in
-    let (paths, log_lines): (Vec<PathBuf>, Vec<OsString>) =
-        results
-            .into_iter()
-            .fold((vec![], vec![]), |(mut paths, mut log_lines), result| {
-                match result {
-                    LogDatum::CopiedSource(src) | LogDatum::ReadFileOrDir(src) => {
-                        paths.push(src);
-                    }
-                    LogDatum::NixSourceFile(mut src) => {
-                        // We need to emulate nix’s `default.nix` mechanism here.
-                        // That is, if the user uses something like
-                        // `import ./foo`
-                        // and `foo` is a directory, nix will actually import
-                        // `./foo/default.nix`
-                        // but still print `./foo`.
-                        // Since this is the only time directories are printed,
-                        // we can just manually re-implement that behavior.
-                        if src.is_dir() {
-                            src.push("default.nix");
-                        }
-                        paths.push(src);
-                    }
-                    LogDatum::Text(line) => log_lines.push(OsString::from(line)),
-                    LogDatum::NonUtf(line) => log_lines.push(line),
+/// Cancels the running build of a `BuildLoop` from another thread.
+/// Clones cancel the same builds.
+#[derive(Clone, Default)]
+pub struct Canceller(Arc<Mutex<CancelState>>);
+
+#[derive(Default)]
+struct CancelState {
+    /// The running `nix-build`, if any.
+    child: Option<Child>,
+    /// Whether it leads a process group of its own (see
+    /// `nix::detach`).
+    group: bool,
+    /// Whether the running build was cancelled.
+    cancelled: bool,
+    /// Whether the build loop is stopped for good.
+    stopped: bool,
+    /// The id of the build requested since the last one started.
+    build_requested: Option<BuildId>,
+}
+
+impl Canceller {
+    /// A canceller without a running build.
+    pub fn new() -> Canceller {
+        Canceller::default()
+    }
+
+    /// Interrupt the running build and the processes it started,
+    /// like ctrl-c would.
+    /// Returns whether there was a build to cancel.
+    pub fn cancel(&self) -> bool {
+        let mut state = self.0.lock().expect("canceller lock poisoned");
+        if !state.interrupt() {
+            return false;
+        }
+        state.cancelled = true;
+        true
+    }
+
+    /// Interrupt the running build like `cancel`, but let it fail
+    /// instead of counting as cancelled.
+    fn interrupt(&self) -> bool {
+        self.0.lock().expect("canceller lock poisoned").interrupt()
+    }
+
+    /// Cancel the running build, and all builds started later.
+    /// The build loop exits once it notices (see `is_stopped`).
+    pub fn stop(&self) {
+        self.0.lock().expect("canceller lock poisoned").stopped = true;
+        self.cancel();
+    }
+
+    /// Whether `stop` was called.
+    pub fn is_stopped(&self) -> bool {
+        self.0.lock().expect("canceller lock poisoned").stopped
+    }
+
+    /// Ask the build loop for a build, even if no input changed. It
+    /// starts once the running build is done, or once the loop
+    /// notices it (see `is_stopped`). Returns the id the build will
+    /// have; requests until it starts get the same one.
+    pub fn request_build(&self) -> BuildId {
+        *self
+            .0
+            .lock()
+            .expect("canceller lock poisoned")
+            .build_requested
+            .get_or_insert_with(BuildId::next)
+    }
+
+    /// Whether a build was requested since the last one started.
+    pub fn build_requested(&self) -> bool {
+        self.0
+            .lock()
+            .expect("canceller lock poisoned")
+            .build_requested
+            .is_some()
+    }
+
+    /// The id of the build requested since the last call, which
+    /// forgets the request.
+    pub fn take_build_request(&self) -> Option<BuildId> {
+        self.0
+            .lock()
+            .expect("canceller lock poisoned")
+            .build_requested
+            .take()
+    }
+
+    /// Track `child` as the running build, which leads a process
+    /// group of its own if `group` is set.
+    fn start(&self, child: Child, group: bool) {
+        let mut state = self.0.lock().expect("canceller lock poisoned");
+        state.cancelled = state.stopped;
+        if state.stopped {
+            signal::interrupt(child.id(), group);
+        }
+        state.child = Some(child);
+        state.group = group;
+    }
+
+    /// Wait for the running build to exit, and return its exit status
+    /// and whether it was cancelled.
+    fn wait(&self) -> Result<(ExitStatus, bool), std::io::Error> {
+        loop {
+            {
+                let mut state = self.0.lock().expect("canceller lock poisoned");
+                let status = match state.child {
+                    Some(ref mut child) => child.try_wait()?,
+                    None => panic!("waiting for a build which was never started"),
                 };
+                if let Some(status) = status {
+                    state.child = None;
+                    return Ok((status, state.cancelled));
+                }
+            }
+            // don’t hold the lock, so the build can be cancelled meanwhile
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+impl CancelState {
+    /// Interrupt the running build, if any.
+    fn interrupt(&mut self) -> bool {
+        let pid = match self.child.as_mut() {
+            // not reaped yet, so the pid can’t have been reused
+            Some(child) => match child.try_wait() {
+                Ok(None) => child.id(),
+                _ => return false,
+            },
+            None => return false,
+        };
+        signal::interrupt(pid, self.group);
+        true
+    }
+}
+
+/// Sending signals to processes.
+mod signal {
+    extern crate nix;
 
-                (paths, log_lines)
+    use self::nix::sys::signal::{kill, killpg, Signal};
+    use self::nix::unistd::{setpgid, Pid};
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    /// Run `cmd` as the leader of a new process group.
+    pub fn new_group(cmd: &mut Command) {
+        // setpgid() is async-signal-safe, so it can run between
+        // fork() and exec()
+        unsafe {
+            cmd.pre_exec(|| match setpgid(Pid::from_raw(0), Pid::from_raw(0)) {
+                Ok(()) => Ok(()),
+                Err(_) => Err(std::io::Error::last_os_error()),
             });
+        }
+    }
 
-    Ok(Info {
-        exec_result,
-        output_paths: OutputPaths { shell_gc_root },
-        paths,
-        log_lines,
-    })
+    /// Interrupt `pid`, like ctrl-c does: the whole process group it
+    /// leads if `group` is set.
+    pub fn interrupt(pid: u32, group: bool) {
+        let pid = Pid::from_raw(pid as i32);
+        let sent = if group {
+            killpg(pid, Signal::SIGINT)
+        } else {
+            kill(pid, Signal::SIGINT)
+        };
+        if let Err(e) = sent {
+            warn!("could not interrupt process {}: {}", pid, e);
+        }
+    }
+}
+
+/// A reader of nix’s output which remembers the last line as long
+/// as it isn’t terminated, since prompts wait for input at its end.
+struct Unterminated<R> {
+    inner: R,
+    line: Arc<Mutex<Vec<u8>>>,
+}
+
+impl<R: Read> Read for Unterminated<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let mut line = self.line.lock().expect("stderr lock poisoned");
+        match buf[..read].iter().rposition(|byte| *byte == b'\n') {
+            Some(end) => {
+                line.clear();
+                line.extend_from_slice(&buf[end + 1..read]);
+            }
+            None => line.extend_from_slice(&buf[..read]),
+        }
+        Ok(read)
+    }
+}
+
+/// Whether `line` of nix’s output (or of a tool it ran, like git or
+/// ssh) asks for input, or says that it couldn’t.
+pub fn asks_for_input(line: &str) -> bool {
+    lazy_static! {
+        static ref PROMPT: Regex = Regex::new(
+            "(?i)password(?: for [^:]*)?:|Enter passphrase|could not read (?:Username|Password)\
+             |terminal prompts disabled|Host key verification failed\
+             |continue connecting \\(yes/no|do you want to allow configuration setting"
+        )
+        .expect("invalid regex!");
+    }
+    PROMPT.is_match(line)
 }
 
-/// Builds the Nix expression in `root_nix_file`.
+/// The prompt a build which went silent waits at: the `unterminated`
+/// last line of its output, or else its last complete line.
+fn waiting_for_input(unterminated: &[u8], last_line: Option<&OsString>) -> Option<String> {
+    let line = if unterminated.is_empty() {
+        last_line?.to_string_lossy().into_owned()
+    } else {
+        String::from_utf8_lossy(unterminated).into_owned()
+    };
+    if asks_for_input(&line) {
+        Some(line.trim().to_owned())
+    } else {
+        None
+    }
+}
+
+/// The expression lorri evaluates instead of the user’s nix file,
+/// which it gets passed as `src`. See `nix_build_args`.
+pub const LOGGED_EVALUATION_NIX: &str = include_str!("./logged-evaluation.nix");
+
+/// The arguments `nix-build` is called with to build `source`.
+/// Expressions and flakes (see `flake_expression`) are saved to
+/// `cas` and built like nix files.
+///
+/// The last argument is the path of `LOGGED_EVALUATION_NIX` in `cas`.
+pub fn nix_build_args(
+    source: &NixSource,
+    cas: &ContentAddressable,
+    store: &Store,
+    options: &Options,
+) -> Result<Vec<OsString>, std::io::Error> {
+    // We're looking for log lines matching:
+    //
+    //     copied source '...' -> '/nix/store/...'
+    //     evaluating file '...'
+    //
+    // to determine which files we should setup watches on.
+    // Increasing verbosity by two levels via `-vv` satisfies that.
+
+    let logged_evaluation_nix = cas.file_from_string(LOGGED_EVALUATION_NIX)?;
+    let src = match source {
+        NixSource::File(nix_file) => PathBuf::from(nix_file.as_os_str()),
+        NixSource::Expression { expression, .. } => cas.file_from_string(expression)?,
+        NixSource::Flake { reference, dir } => {
+            cas.file_from_string(&flake_expression(reference, dir))?
+        }
+    };
+
+    let mut args: Vec<OsString> = vec![];
+    if *SUPPORTS_INTERNAL_JSON {
+        // structured logs, which carry progress information
+        args.extend(vec!["--log-format".into(), "internal-json".into()]);
+    }
+    args.extend(store.args().into_iter().map(OsString::from));
+    args.extend(options.args());
+    if let NixSource::Flake { .. } = source {
+        args.extend(
+            [
+                "--option",
+                "extra-experimental-features",
+                "nix-command flakes",
+            ]
+            .iter()
+            .map(OsString::from),
+        );
+    }
+    args.extend(
+        vec![
+            OsStr::new("-vv"),
+            // TODO: this must create a GcRootTempDir and pass it out
+            OsStr::new("--no-out-link"),
+            OsStr::new("--argstr"),
+            OsStr::new("runTimeClosure"),
+            OsStr::new(crate::RUN_TIME_CLOSURE),
+            OsStr::new("--argstr"),
+            OsStr::new("src"),
+            src.as_os_str(),
+            OsStr::new("--"),
+            logged_evaluation_nix.as_os_str(),
+        ]
+        .into_iter()
+        .map(OsStr::to_owned),
+    );
+    Ok(args)
+}
+
+/// A nix expression for the development shell of the flake
+/// `reference` (see `NixSource::Flake`): `<flake>#<attribute>` is
+/// `devShells.<system>.<attribute>` (or the attribute path itself,
+/// like `nix develop` does), and a bare `<flake>` is
+/// `devShells.<system>.default` (or the older `devShell.<system>`).
+/// Relative paths are relative to `dir`.
+pub fn flake_expression(reference: &str, dir: &Path) -> String {
+    let (flake, attribute) = match reference.find('#') {
+        Some(i) => (&reference[..i], &reference[i + 1..]),
+        None => (reference, ""),
+    };
+    let flake = if flake.is_empty() || flake == "." {
+        dir.display().to_string()
+    } else if flake.starts_with('.') {
+        dir.join(flake).display().to_string()
+    } else {
+        flake.to_string()
+    };
+    format!(
+        r#"let
+  flake = builtins.getFlake {flake};
+  system = builtins.currentSystem;
+  attribute = {attribute};
+  byPath = builtins.foldl' (set: name: set.${{name}}) flake
+    (builtins.filter builtins.isString (builtins.split "\." attribute));
+in
+if attribute == ""
+then flake.devShells.${{system}}.default or flake.devShell.${{system}}
+else flake.devShells.${{system}}.${{attribute}} or byPath
+"#,
+        flake = nix_string(&flake),
+        attribute = nix_string(attribute),
+    )
+}
+
+/// `s` as a nix string literal.
+fn nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('$', "\\$")
+    )
+}
+
+/// Append `line` to `log`, and stop logging if that fails.
+fn write_log(log: &mut Option<&mut dyn Write>, line: &[u8]) {
+    let written = match log {
+        Some(w) => w.write_all(line).and_then(|()| w.write_all(b"\n")),
+        None => Ok(()),
+    };
+    if let Err(e) = written {
+        warn!("could not write the build log, not logging any more: {}", e);
+        *log = None;
+    }
+}
+
+/// Builds the Nix expression of `source` into `store`,
+/// with the nix settings `options`.
 ///
 /// Instruments the nix file to gain extra information,
 /// which is valuable even if the build fails.
-pub fn run(root_nix_file: &NixFile, cas: &ContentAddressable) -> Result<Info<StorePath>, Error> {
-    instrumented_build(root_nix_file, cas)
+///
+/// The output of nix is copied to `log`, if given.
+///
+/// The build can be interrupted with `canceller`, which makes it
+/// return `Error::Cancelled`. It is also cancelled as soon as
+/// `cancel_when` returns true, which is checked every 100ms while
+/// the build runs.
+///
+/// `on_report` is called with every line of nix’s output as it
+/// is read, and whenever nix reports progress (only on nix versions
+/// supporting `--log-format internal-json`).
+#[allow(clippy::too_many_arguments)]
+pub fn run<F, C>(
+    source: &NixSource,
+    cas: &ContentAddressable,
+    store: &Store,
+    options: &Options,
+    log: Option<&mut dyn Write>,
+    canceller: &Canceller,
+    on_report: F,
+    cancel_when: C,
+) -> Result<Info<StorePath>, Error>
+where
+    F: FnMut(Report),
+    C: FnMut() -> bool,
+{
+    instrumented_build(
+        source,
+        cas,
+        store,
+        options,
+        log,
+        canceller,
+        on_report,
+        cancel_when,
+    )
+}
+
+/// What a running build reports as it goes (see `run`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Report {
+    /// Nix reported progress.
+    Progress(Progress),
+    /// Nix printed a line (of the evaluation or a build).
+    LogLine(String),
+}
+
+lazy_static! {
+    /// Whether the installed nix understands `--log-format internal-json`
+    /// (added in nix 2.3).
+    static ref SUPPORTS_INTERNAL_JSON: bool = Command::new("nix-build")
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|out| nix_version(&String::from_utf8_lossy(&out.stdout)))
+        .map(|version| version >= (2, 3))
+        .unwrap_or(false);
+}
+
+/// Parse major and minor version from `nix-build --version` output,
+/// like `nix-build (Nix) 2.3.1`.
+fn nix_version(output: &str) -> Option<(u32, u32)> {
+    let version = output.split_whitespace().last()?;
+    let mut parts = version.split('.').map(|p| p.parse::<u32>().ok());
+    Some((parts.next()??, parts.next()??))
+}
+
+/// Progress of a running build, as reported by nix.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Progress {
+    /// What is being counted.
+    pub kind: ProgressKind,
+    /// Number of items finished.
+    pub done: u64,
+    /// Number of items nix expects to process in total.
+    pub expected: u64,
+    /// The store path nix started working on last: the derivation
+    /// it builds, or the path it downloads.
+    pub current: Option<String>,
+}
+
+/// The kinds of work nix reports progress for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ProgressKind {
+    /// Derivations built locally.
+    Builds,
+    /// Store paths copied from a substituter (binary cache).
+    Downloads,
+    /// Bytes downloaded from substituters.
+    Bytes,
+}
+
+impl Progress {
+    /// Percentage of finished items, if nix knows how many to expect.
+    pub fn percent(&self) -> Option<u8> {
+        (self.done.min(self.expected) * 100)
+            .checked_div(self.expected)
+            .map(|percent| percent as u8)
+    }
+}
+
+impl fmt::Display for Progress {
+    /// Like `34/120 derivations, building hello-2.12` or
+    /// `1.5/12.0 MiB downloaded`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ProgressKind::Builds => write!(f, "{}/{} derivations", self.done, self.expected)?,
+            ProgressKind::Downloads => {
+                write!(f, "{}/{} paths downloaded", self.done, self.expected)?
+            }
+            ProgressKind::Bytes => {
+                let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+                write!(
+                    f,
+                    "{:.1}/{:.1} MiB downloaded",
+                    mib(self.done),
+                    mib(self.expected)
+                )?
+            }
+        }
+        match (self.kind, &self.current) {
+            (ProgressKind::Builds, Some(drv)) => write!(f, ", building {}", store_path_name(drv)),
+            (ProgressKind::Downloads, Some(path)) => {
+                write!(f, ", fetching {}", store_path_name(path))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The name of the store path `path`, without the store directory,
+/// hash and `.drv` extension: `hello-2.12` for
+/// `/nix/store/<hash>-hello-2.12.drv`.
+fn store_path_name(path: &str) -> &str {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    let name = base.splitn(2, '-').nth(1).unwrap_or(base);
+    if name.ends_with(".drv") {
+        &name[..name.len() - ".drv".len()]
+    } else {
+        name
+    }
+}
+
+/// Parser for nix’s `--log-format internal-json` output.
+///
+/// Each line is either plain text or `@nix ` followed by a JSON
+/// record. Messages are passed on to `parse_evaluation_line`,
+/// progress records are tracked per activity.
+struct InternalJsonParser {
+    /// Activity types of the activities started so far, by id.
+    activities: HashMap<u64, u64>,
+    /// Last reported percentage and current path by kind, to only
+    /// report changes.
+    reported: HashMap<ProgressKind, (Option<u8>, Option<String>)>,
+    /// The store path nix started working on last, by kind (see
+    /// `Progress::current`).
+    current: HashMap<ProgressKind, String>,
+    /// Bytes done and expected of each file transfer so far, by
+    /// activity id; finished transfers still count.
+    transfers: HashMap<u64, (u64, u64)>,
+}
+
+/// A record in nix’s internal-json log format.
+/// Fields we don’t need are ignored.
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum InternalJson {
+    Msg {
+        msg: String,
+    },
+    Start {
+        id: u64,
+        #[serde(rename = "type")]
+        activity_type: u64,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+    Stop {
+        id: u64,
+    },
+    Result {
+        id: u64,
+        #[serde(rename = "type")]
+        result_type: u64,
+        #[serde(default)]
+        fields: Vec<serde_json::Value>,
+    },
+}
+
+// Activity and result types, see `src/libutil/logging.hh` in nix.
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_COPY_PATHS: u64 = 103;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+const ACT_SUBSTITUTE: u64 = 108;
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_PROGRESS: u64 = 105;
+
+impl InternalJsonParser {
+    fn new() -> InternalJsonParser {
+        InternalJsonParser {
+            activities: HashMap::new(),
+            reported: HashMap::new(),
+            current: HashMap::new(),
+            transfers: HashMap::new(),
+        }
+    }
+
+    /// Parse a line; `None` if the line carries nothing of interest.
+    fn parse(&mut self, line: &OsStr) -> Option<LogDatum> {
+        let json = line
+            .to_str()
+            .filter(|l| l.starts_with("@nix "))
+            .map(|l| &l["@nix ".len()..]);
+        let record = match json {
+            None => return Some(parse_evaluation_line(line)),
+            Some(json) => match serde_json::from_str::<InternalJson>(json) {
+                Err(_) => return Some(parse_evaluation_line(line)),
+                Ok(record) => record,
+            },
+        };
+        match record {
+            InternalJson::Msg { msg } => Some(parse_evaluation_line(msg)),
+            InternalJson::Start {
+                id,
+                activity_type,
+                fields,
+            } => {
+                self.activities.insert(id, activity_type);
+                let field = |i: usize| fields.get(i).and_then(|f| f.as_str());
+                match activity_type {
+                    // the first field is the derivation
+                    ACT_BUILD => {
+                        if let Some(drv) = field(0) {
+                            self.current.insert(ProgressKind::Builds, drv.to_owned());
+                        }
+                        Some(LogDatum::Built)
+                    }
+                    // the fields are the store path and the substituter
+                    ACT_SUBSTITUTE => {
+                        if let Some(path) = field(0) {
+                            for kind in &[ProgressKind::Downloads, ProgressKind::Bytes] {
+                                self.current.insert(*kind, path.to_owned());
+                            }
+                        }
+                        Some(LogDatum::Substituted(field(1)?.to_owned()))
+                    }
+                    _ => None,
+                }
+            }
+            InternalJson::Stop { id } => {
+                self.activities.remove(&id);
+                None
+            }
+            InternalJson::Result {
+                result_type: RES_BUILD_LOG_LINE,
+                fields,
+                ..
+            } => fields
+                .first()
+                .and_then(|f| f.as_str())
+                .map(|line| LogDatum::Text(line.to_owned())),
+            InternalJson::Result {
+                id,
+                result_type: RES_PROGRESS,
+                fields,
+            } => {
+                let field = |i: usize| fields.get(i).and_then(|f| f.as_u64());
+                let kind = match self.activities.get(&id) {
+                    Some(&ACT_BUILDS) => ProgressKind::Builds,
+                    Some(&ACT_COPY_PATHS) => ProgressKind::Downloads,
+                    Some(&ACT_FILE_TRANSFER) => {
+                        self.transfers.insert(id, (field(0)?, field(1)?));
+                        ProgressKind::Bytes
+                    }
+                    _ => return None,
+                };
+                let current = self.current.get(&kind).cloned();
+                let progress = match kind {
+                    // the sum of all transfers
+                    ProgressKind::Bytes => Progress {
+                        kind,
+                        done: self.transfers.values().map(|t| t.0).sum(),
+                        expected: self.transfers.values().map(|t| t.1).sum(),
+                        current,
+                    },
+                    _ => Progress {
+                        kind,
+                        done: field(0)?,
+                        expected: field(1)?,
+                        current,
+                    },
+                };
+                let reported = (progress.percent(), progress.current.clone());
+                if self.reported.get(&kind) == Some(&reported) {
+                    None
+                } else {
+                    self.reported.insert(kind, reported);
+                    Some(LogDatum::Progress(progress))
+                }
+            }
+            InternalJson::Result { .. } => None,
+        }
+    }
 }
 
 /// Classifies the output of nix-instantiate -vv.
@@ -152,6 +983,52 @@ enum LogDatum {
     Text(String),
     /// Text which we coudn’t decode from UTF-8
     NonUtf(OsString),
+    /// Build progress (from internal-json logs)
+    Progress(Progress),
+    /// A store path was fetched from this substituter
+    /// (from internal-json logs, see `CacheStats::count_text`)
+    Substituted(String),
+    /// A derivation was built locally (from internal-json logs)
+    Built,
+}
+
+/// Where the store paths a build needed came from: fetched from
+/// substituters (binary caches), or built locally. Paths which were
+/// in the store already are not counted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// The number of paths fetched, by substituter.
+    pub substituted: BTreeMap<String, u64>,
+    /// The number of derivations built locally.
+    pub built: u64,
+}
+
+impl CacheStats {
+    /// The number of paths fetched from all substituters.
+    pub fn substituted_total(&self) -> u64 {
+        self.substituted.values().sum()
+    }
+
+    fn substituted_from(&mut self, substituter: String) {
+        *self.substituted.entry(substituter).or_insert(0) += 1;
+    }
+
+    /// Count a line of nix’s plain text log, which has the
+    /// messages internal-json reports as activities.
+    fn count_text(&mut self, line: &str) {
+        lazy_static! {
+            static ref SUBSTITUTED: Regex =
+                Regex::new("^copying path '[^']*' from '(?P<substituter>[^']*)'")
+                    .expect("invalid regex!");
+            static ref BUILT: Regex =
+                Regex::new(r"^building '[^']*\.drv'").expect("invalid regex!");
+        }
+        if let Some(matches) = SUBSTITUTED.captures(line) {
+            self.substituted_from(matches["substituter"].to_owned());
+        } else if BUILT.is_match(line) {
+            self.built += 1;
+        }
+    }
 }
 
 /// Examine a line of output and extract interesting log items in to
@@ -197,6 +1074,18 @@ where
     }
 }
 
+/// How long the phases of a build took (by wall clock): evaluating
+/// the nix file until the environment’s derivation is instantiated,
+/// and realising it (fetching and building its dependencies)
+/// afterwards. If evaluation fails, it is all `evaluate`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings {
+    /// Evaluating the nix file.
+    pub evaluate: Duration,
+    /// Realising the derivation.
+    pub realise: Duration,
+}
+
 /// The results of an individual build.
 /// Even if the exit code is not 0, there is still
 /// valuable information in the output, like new paths
@@ -206,8 +1095,11 @@ pub struct Info<T> {
     /// The result of executing Nix
     pub exec_result: std::process::ExitStatus,
 
-    /// See `OutputPaths`
-    pub output_paths: OutputPaths<T>,
+    /// The command line nix was run with
+    pub command: String,
+
+    /// See `OutputPaths`; `None` if the build failed
+    pub output_paths: Option<OutputPaths<T>>,
 
     // TODO: rename to `sources` (it’s the input sources we have to watch)
     /// A list of paths examined during the evaluation
@@ -215,13 +1107,31 @@ pub struct Info<T> {
 
     /// A list of stderr log lines
     pub log_lines: Vec<OsString>,
+
+    /// Where the store paths of the build came from
+    pub cache_stats: CacheStats,
+
+    /// The derivation of the environment, once nix instantiated it
+    /// (see `instantiated_environment`)
+    pub drv_path: Option<PathBuf>,
+
+    /// How long evaluating and realising took
+    pub timings: Timings,
+
+    /// The files nix accessed, if it was traced
+    /// (see `nix::Options::trace_reads`)
+    pub reads: Option<Vec<PathBuf>>,
 }
 
 /// Output paths generated by `logged-evaluation.nix`
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct OutputPaths<T> {
     /// Shell path modified to work as a gc root
+    /// (of the default shell, if the project has named shells)
     pub shell_gc_root: T,
+    /// The same for each named shell of the project, by name
+    /// (see `project::config::ShellConfig`)
+    pub shells: BTreeMap<String, T>,
 }
 
 /// Possible errors from an individual evaluation
@@ -232,6 +1142,9 @@ pub enum Error {
 
     /// Failed to spawn a log processing thread
     ThreadFailure(std::boxed::Box<(dyn std::any::Any + std::marker::Send + 'static)>),
+
+    /// The build was cancelled with a `Canceller`
+    Cancelled,
 }
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Error {
@@ -284,6 +1197,65 @@ mod tests {
         );
     }
 
+    /// A long-running command in its own process group, like nix-build.
+    fn sleep() -> Command {
+        let mut cmd = Command::new("sleep");
+        ::nix::detach(cmd.arg("10"));
+        cmd
+    }
+
+    #[test]
+    fn cancel_running_build() {
+        let canceller = Canceller::new();
+        assert!(!canceller.cancel());
+
+        let child = sleep().spawn().unwrap();
+        canceller.start(child, true);
+        let other = canceller.clone();
+        let cancel = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            other.cancel()
+        });
+        let (status, cancelled) = canceller.wait().unwrap();
+        assert!(cancel.join().unwrap());
+        assert!(cancelled);
+        assert!(!status.success());
+        // nothing is running any more
+        assert!(!canceller.cancel());
+
+        // builds started after stopping are cancelled right away
+        canceller.stop();
+        assert!(canceller.is_stopped());
+        canceller.start(sleep().spawn().unwrap(), true);
+        let (status, cancelled) = canceller.wait().unwrap();
+        assert!(cancelled);
+        assert!(!status.success());
+    }
+
+    #[test]
+    fn builds_waiting_for_input() -> std::io::Result<()> {
+        let line = Arc::new(Mutex::new(vec![]));
+        let mut stderr = Unterminated {
+            inner: &b"cloning\nuser@host's password: "[..],
+            line: line.clone(),
+        };
+        std::io::copy(&mut stderr, &mut std::io::sink())?;
+        let pending = line.lock().unwrap().clone();
+        assert_eq!(pending, b"user@host's password: ");
+        assert_eq!(
+            waiting_for_input(&pending, Some(&OsString::from("cloning"))),
+            Some(String::from("user@host's password:"))
+        );
+
+        // builds which are just slow
+        assert_eq!(
+            waiting_for_input(b"", Some(&OsString::from("building '/nix/store/a.drv'"))),
+            None
+        );
+        assert_eq!(waiting_for_input(b"[1/2 built] ", None), None);
+        Ok(())
+    }
+
     #[test]
     fn non_utf8_nix_output() -> std::io::Result<()> {
         let tmp = tempfile::tempdir()?;
@@ -327,11 +1299,323 @@ in {}
 
         print!("{}", nix_drv);
 
-        let info = run(&::NixFile::from(cas.file_from_string(&nix_drv)?), &cas).unwrap();
+        let mut log: Vec<u8> = vec![];
+        let mut reported: Vec<String> = vec![];
+        let info = run(
+            &::NixFile::from(cas.file_from_string(&nix_drv)?).into(),
+            &cas,
+            &Store::from_env(),
+            &Options::new(),
+            Some(&mut log),
+            &Canceller::new(),
+            |report| {
+                if let Report::LogLine(line) = report {
+                    reported.push(line)
+                }
+            },
+            || false,
+        )
+        .unwrap();
         assert!(info.exec_result.success());
 
         let expect: OsString = OsStr::from_bytes(b"\"\xAB\xBC\xCD\xDE\xDE\xEF\"").to_owned();
         assert!(info.log_lines.contains(&expect));
+        assert!(log
+            .split(|b| *b == b'\n')
+            .any(|line| line == expect.as_bytes()));
+        // lines which aren’t UTF-8 are reported lossily
+        assert!(reported.contains(&expect.to_string_lossy().into_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn internal_json_lines() {
+        let mut parser = InternalJsonParser::new();
+        let mut parse = |line: &str| parser.parse(OsStr::new(line));
+
+        assert_eq!(
+            parse(r#"@nix {"action":"msg","level":4,"msg":"evaluating file '/foo/shell.nix'"}"#),
+            Some(LogDatum::NixSourceFile(PathBuf::from("/foo/shell.nix")))
+        );
+        assert_eq!(
+            parse("plain text"),
+            Some(LogDatum::Text(String::from("plain text")))
+        );
+        assert_eq!(
+            parse(
+                r#"@nix {"action":"start","id":7,"level":0,"type":104,"text":"","parent":0,"fields":[]}"#
+            ),
+            None
+        );
+        assert_eq!(
+            parse(r#"@nix {"action":"result","id":7,"type":105,"fields":[1,4,1,0]}"#),
+            Some(LogDatum::Progress(Progress {
+                kind: ProgressKind::Builds,
+                done: 1,
+                expected: 4,
+                current: None,
+            }))
+        );
+        // unchanged percentage is not reported again
+        assert_eq!(
+            parse(r#"@nix {"action":"result","id":7,"type":105,"fields":[1,4,2,0]}"#),
+            None
+        );
+        assert_eq!(
+            parse(r#"@nix {"action":"result","id":8,"type":101,"fields":["building"]}"#),
+            Some(LogDatum::Text(String::from("building")))
+        );
+        // progress of unknown activities is ignored
+        assert_eq!(
+            parse(r#"@nix {"action":"result","id":9,"type":105,"fields":[1,2,0,0]}"#),
+            None
+        );
+        assert_eq!(
+            parse(
+                r#"@nix {"action":"start","id":10,"level":4,"type":108,"text":"copying path","parent":0,"fields":["/nix/store/abc-hello","https://cache.nixos.org"]}"#
+            ),
+            Some(LogDatum::Substituted(String::from(
+                "https://cache.nixos.org"
+            )))
+        );
+        assert_eq!(
+            parse(
+                r#"@nix {"action":"start","id":11,"level":3,"type":105,"text":"building","parent":0,"fields":["/nix/store/def-shell.drv","",1,1]}"#
+            ),
+            Some(LogDatum::Built)
+        );
+        // progress names what nix works on
+        let progress =
+            match parse(r#"@nix {"action":"result","id":7,"type":105,"fields":[1,4,2,0]}"#) {
+                Some(LogDatum::Progress(progress)) => progress,
+                other => panic!("expected progress, got {:?}", other),
+            };
+        assert_eq!(
+            progress.current.as_ref().map(String::as_str),
+            Some("/nix/store/def-shell.drv")
+        );
+        assert_eq!(progress.to_string(), "1/4 derivations, building shell");
+        // the bytes of all file transfers add up
+        for id in &[12, 13] {
+            parse(&format!(
+                r#"@nix {{"action":"start","id":{},"level":4,"type":101,"text":"downloading","parent":10,"fields":["https://cache.nixos.org/nar/abc.nar.xz"]}}"#,
+                id
+            ));
+        }
+        assert_eq!(
+            parse(r#"@nix {"action":"result","id":12,"type":105,"fields":[1048576,2097152,0,0]}"#),
+            Some(LogDatum::Progress(Progress {
+                kind: ProgressKind::Bytes,
+                done: 1_048_576,
+                expected: 2_097_152,
+                current: Some(String::from("/nix/store/abc-hello")),
+            }))
+        );
+        let progress = match parse(
+            r#"@nix {"action":"result","id":13,"type":105,"fields":[0,2097152,0,0]}"#,
+        ) {
+            Some(LogDatum::Progress(progress)) => progress,
+            other => panic!("expected progress, got {:?}", other),
+        };
+        assert_eq!(progress.to_string(), "1.0/4.0 MiB downloaded");
+    }
+
+    #[test]
+    fn progress_summary() {
+        let progress = Progress {
+            kind: ProgressKind::Builds,
+            done: 34,
+            expected: 120,
+            current: None,
+        };
+        assert_eq!(progress.to_string(), "34/120 derivations");
+        assert_eq!(progress.percent(), Some(28));
+    }
+
+    #[test]
+    fn cache_stats_from_text() {
+        let mut stats = CacheStats::default();
+        for line in &[
+            "copying path '/nix/store/abc-hello' from 'https://cache.nixos.org'...",
+            "copying path '/nix/store/def-git' from 'https://cache.nixos.org'...",
+            "copying path '/nix/store/ghi-tool' from 'https://team.cachix.org'...",
+            "building '/nix/store/jkl-shell.drv'...",
+            "evaluating file '/home/user/project/shell.nix'",
+        ] {
+            stats.count_text(line);
+        }
+        assert_eq!(stats.built, 1);
+        assert_eq!(stats.substituted_total(), 3);
+        assert_eq!(stats.substituted["https://cache.nixos.org"], 2);
+    }
+
+    #[test]
+    fn instantiated_derivation() {
+        assert_eq!(
+            instantiated_environment(
+                "instantiated 'lorri-keep-env-hack-nix-shell' -> '/nix/store/abc-lorri-keep-env-hack-nix-shell.drv'"
+            ),
+            Some(PathBuf::from("/nix/store/abc-lorri-keep-env-hack-nix-shell.drv"))
+        );
+        // derivations the environment depends on
+        assert_eq!(
+            instantiated_environment(
+                "instantiated 'hello-2.12' -> '/nix/store/def-hello-2.12.drv'"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_nix_version() {
+        assert_eq!(nix_version("nix-build (Nix) 2.3.1\n"), Some((2, 3)));
+        assert_eq!(nix_version("nix-build (Nix) 2.2"), Some((2, 2)));
+        assert_eq!(nix_version("garbage"), None);
+    }
+
+    #[test]
+    fn named_output_paths() {
+        let store_path =
+            |name: &str| StorePath::from(OsString::from(format!("/nix/store/{}", name)));
+        let single = output_paths(&[], vec![store_path("shell")]);
+        assert_eq!(single.shell_gc_root, store_path("shell"));
+        assert!(single.shells.is_empty());
+
+        // nix-build prints the shells sorted by name
+        let names = vec![String::from("docs"), String::from("ci")];
+        let named = output_paths(&names, vec![store_path("ci"), store_path("docs")]);
+        assert_eq!(named.shell_gc_root, store_path("docs"));
+        assert_eq!(named.shells["ci"], store_path("ci"));
+        assert_eq!(named.shells["docs"], store_path("docs"));
+    }
+
+    #[test]
+    fn flake_expressions() {
+        let dir = Path::new("/home/user/project");
+        let expr = flake_expression(".#docs", dir);
+        assert!(expr.contains(r#"builtins.getFlake "/home/user/project";"#));
+        assert!(expr.contains(r#"attribute = "docs";"#));
+        let expr = flake_expression("github:owner/repo", dir);
+        assert!(expr.contains(r#"builtins.getFlake "github:owner/repo";"#));
+        assert!(expr.contains(r#"attribute = "";"#));
+        assert_eq!(nix_string(r#"a"b\c${d}"#), r#""a\"b\\c\${d}""#);
+    }
+
+    #[test]
+    fn src_of_sources() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let cas = ContentAddressable::new(tmp.path().join("cas"))?;
+        let src = |source: &NixSource| -> std::io::Result<OsString> {
+            let args = nix_build_args(source, &cas, &Store::Default, &Options::new())?;
+            let i = args.iter().position(|arg| arg == "src").unwrap();
+            Ok(args[i + 1].clone())
+        };
+
+        let nix_file = NixSource::from(::NixFile::from(PathBuf::from("/project/shell.nix")));
+        assert_eq!(src(&nix_file)?, "/project/shell.nix");
+
+        let expression = String::from("with import <nixpkgs> {}; mkShell {}");
+        let saved = src(&NixSource::Expression {
+            expression: expression.clone(),
+            dir: PathBuf::from("/project"),
+        })?;
+        assert_eq!(std::fs::read_to_string(saved)?, expression);
+        Ok(())
+    }
+
+    /// The `sandboxed` wrapper of `logged-evaluation.nix`, run by bash
+    /// around a hook: the hook sees a throwaway `$HOME` and the dead
+    /// proxies, the caller's variables come back afterwards, and the
+    /// ones the hook exports stay.
+    #[test]
+    fn shell_hook_sandbox() -> std::io::Result<()> {
+        let start = "sandboxed = hook: ''";
+        let wrapper =
+            &LOGGED_EVALUATION_NIX[LOGGED_EVALUATION_NIX.find(start).unwrap() + start.len()..];
+        let wrapper = &wrapper[..wrapper.find("'';").unwrap()];
+        let hook = r#"
+            echo dotfile > "$HOME/.hookrc"
+            echo "$HOME" > "$OUT/hook-home"
+            echo "$https_proxy $XDG_CACHE_HOME" > "$OUT/hook-env"
+            export HOOK_VAR=kept"#;
+        let script = format!(
+            r#"{}
+echo "$HOME $HOOK_VAR ${{https_proxy-unset}} ${{lorri_sandbox-unset}}""#,
+            wrapper.replace("${hook}", hook)
+        );
+
+        let tmp = tempfile::tempdir()?;
+        let home = tmp.path().join("home");
+        std::fs::create_dir(&home)?;
+        let output = Command::new("bash")
+            .args(&["-e", "-c", &script])
+            .env("HOME", &home)
+            .env("OUT", tmp.path())
+            .env_remove("https_proxy")
+            .output()?;
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{} kept unset unset\n", home.display())
+        );
+
+        let sandbox = std::fs::read_to_string(tmp.path().join("hook-home"))?;
+        let sandbox = Path::new(sandbox.trim_end());
+        assert_ne!(sandbox, home);
+        assert!(!sandbox.exists(), "{} was not removed", sandbox.display());
+        assert!(!home.join(".hookrc").exists());
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("hook-env"))?,
+            format!("http://127.0.0.1:9 {}/.cache\n", sandbox.display())
+        );
+        Ok(())
+    }
+
+    /// `logged-evaluation.nix` builds the attribute path it is given,
+    /// with names containing dots, and looks the named shells up
+    /// below it.
+    #[test]
+    fn evaluate_attribute_paths() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let src = tmp.path().join("default.nix");
+        std::fs::write(
+            &src,
+            r#"
+let shell = name: derivation { inherit name; builder = "/bin/sh"; system = "x"; };
+in { shells."python3.11" = { default = shell "py"; docs = shell "py-docs"; }; }
+"#,
+        )?;
+        let closure = tmp.path().join("closure.nix");
+        std::fs::write(
+            &closure,
+            r#"{ closure = []; builder = "/bin/sh"; path = ""; }"#,
+        )?;
+        let evaluation = tmp.path().join("logged-evaluation.nix");
+        std::fs::write(&evaluation, LOGGED_EVALUATION_NIX)?;
+
+        let name = |options: &Options, attribute: &str| -> std::io::Result<String> {
+            let output = Command::new("nix-instantiate")
+                .args(&["--eval", "--json", "-A", attribute])
+                .args(options.args())
+                .arg("--arg")
+                .arg("src")
+                .arg(&src)
+                .arg("--arg")
+                .arg("runTimeClosure")
+                .arg(&closure)
+                .arg(&evaluation)
+                .output()?;
+            assert!(output.status.success(), "{:?}", output);
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        };
+        let mut options = Options::new();
+        options.attribute(&nix::attribute_path(r#"shells."python3.11""#).unwrap());
+        assert_eq!(name(&options, "name")?, r#""lorri-keep-env-hack-py""#);
+        options.shells(&[(String::from("docs"), vec![String::from("docs")])]);
+        assert_eq!(
+            name(&options, "docs.name")?,
+            r#""lorri-keep-env-hack-py-docs""#
+        );
         Ok(())
     }
 }
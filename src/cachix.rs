@@ -0,0 +1,75 @@
+//! Push build results to a cachix binary cache.
+//!
+//! Using the cache for builds is configured like any other
+//! substituter (see `project::config::CachixConfig`); pushing
+//! calls out to the `cachix` command line tool.
+
+use crate::nix;
+use crate::project::config::CachixConfig;
+use crate::project::roots::RootPath;
+use std::process::{Command, Stdio};
+
+/// Environment variable the `cachix` tool reads its auth token from.
+const DEFAULT_AUTH_TOKEN_ENV: &str = "CACHIX_AUTH_TOKEN";
+
+/// The outcome of pushing a build result to cachix.
+#[derive(Clone, Debug)]
+pub struct PushOutcome {
+    /// Name of the cache pushed to.
+    pub cache: String,
+    /// Path whose closure was pushed.
+    pub path: RootPath,
+    /// `Err` with an explanation if the push failed.
+    pub result: Result<(), String>,
+}
+
+/// Push the closure of `path` to the cache configured in `config`.
+///
+/// Returns `None` if pushing is not configured.
+pub fn push(config: &CachixConfig, path: RootPath) -> Option<PushOutcome> {
+    let cache = match config.name {
+        Some(ref name) if config.push => name.clone(),
+        _ => return None,
+    };
+
+    let mut cmd = Command::new("cachix");
+    cmd.arg("push")
+        .arg(&cache)
+        .arg(path.as_os_str())
+        .stdout(Stdio::null());
+    nix::non_interactive(&mut cmd);
+    // the token is configured by name, so it doesn’t end up in
+    // the (usually checked-in) project configuration
+    if let Some(ref var) = config.auth_token_env {
+        if var != DEFAULT_AUTH_TOKEN_ENV {
+            match std::env::var_os(var) {
+                Some(token) => {
+                    cmd.env(DEFAULT_AUTH_TOKEN_ENV, token);
+                }
+                None => {
+                    return Some(PushOutcome {
+                        cache,
+                        path,
+                        result: Err(format!("the auth token variable `{}` is not set", var)),
+                    })
+                }
+            }
+        }
+    }
+
+    debug!("$ {:?}", cmd);
+    let result = match cmd.output() {
+        Err(e) => Err(format!("could not run cachix: {}", e)),
+        Ok(ref output) if !output.status.success() => Err(format!(
+            "cachix exited with {}:\n{}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )),
+        Ok(_) => Ok(()),
+    };
+    Some(PushOutcome {
+        cache,
+        path,
+        result,
+    })
+}
@@ -30,6 +30,11 @@ impl ContentAddressable {
         Ok(ContentAddressable { store_dir })
     }
 
+    /// The directory of the store.
+    pub fn dir(&self) -> &std::path::Path {
+        &self.store_dir
+    }
+
     /// Adds the contents to a file in the content-addressable store
     /// and returns the `PathBuf` pointing to the file.
     ///
@@ -1,6 +1,14 @@
 //! Defines the CLI interface using structopt.
 
+use client_gen::Lang;
+use event_sink::EventKind;
+use event_stream::SlowListeners;
+use logging::Rotation;
+use nix;
+use project::ide_env::IdeFormat;
 use std::path::PathBuf;
+use std::time::Duration;
+use watch::WatchBackend;
 use NixFile;
 
 #[derive(StructOpt, Debug)]
@@ -14,6 +22,13 @@ pub struct Arguments {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     pub verbosity: u8,
 
+    /// Print only single-line JSON records to stdout, one per line,
+    /// and all other messages to stderr. The last record is the
+    /// result: `{"result":"ok","message":...}` or
+    /// `{"result":"error","exit_code":...,"message":...}`
+    #[structopt(long = "porcelain")]
+    pub porcelain: bool,
+
     /// Sub-command to execute
     #[structopt(subcommand)]
     pub command: Command,
@@ -31,13 +46,32 @@ pub enum Command {
     #[structopt(name = "info", alias = "information")]
     Info(InfoOptions),
 
+    /// Summarize the project's last build in the daemon: its result,
+    /// duration and output paths, how many files are watched, and
+    /// whether the environment is stale
+    #[structopt(name = "status")]
+    Status(StatusOptions),
+
+    /// Ask the daemon to build the project now, even if no input
+    /// changed, and wait for the build. Exits non-zero if it fails
+    /// or times out
+    #[structopt(name = "ping")]
+    Ping(PingOptions),
+
+    /// Build the project once, without the daemon, create its GC
+    /// roots and print a summary: the output paths, how long the
+    /// build took and its inputs. Exits non-zero if it fails, for CI
+    /// pipelines which warm caches
+    #[structopt(name = "build")]
+    Build(BuildOptions),
+
     /// Build `shell.nix` whenever an input file changes
     #[structopt(name = "watch")]
     Watch(WatchOptions),
 
     /// Start the multi-project daemon. Replaces `lorri watch`
     #[structopt(name = "daemon")]
-    Daemon,
+    Daemon(DaemonOptions),
 
     /// (plumbing) Tell the lorri daemon to care about the current directory's project
     #[structopt(name = "ping_")]
@@ -49,7 +83,455 @@ pub enum Command {
 
     /// Bootstrap files for a new setup
     #[structopt(name = "init")]
-    Init,
+    Init(InitOptions),
+
+    /// Install git hooks which check the shell file before commits and
+    /// notify the daemon after checkouts. Existing hooks are kept.
+    #[structopt(name = "install-git-hooks")]
+    InstallGitHooks(InstallGitHooksOptions),
+
+    /// Install the daemon as a systemd user service, started on the
+    /// first connection to its socket, and enable it
+    #[structopt(name = "install-service")]
+    InstallService(InstallServiceOptions),
+
+    /// Remove the GC roots of projects whose directories no longer
+    /// exist, so that `nix-collect-garbage` can free their
+    /// environments. Roots of projects lorri didn't record the nix
+    /// file of are kept
+    #[structopt(name = "gc")]
+    Gc(GcOptions),
+
+    /// (plumbing) Commands for debugging and inspecting lorri
+    #[structopt(name = "internal")]
+    Internal {
+        /// Sub-command to execute
+        #[structopt(subcommand)]
+        command: Internal_,
+    },
+}
+
+/// Sub-commands of `lorri internal`. These are meant for
+/// debugging and scripting, their interface may change.
+#[derive(StructOpt, Debug)]
+pub enum Internal_ {
+    /// Check the direnv hook chain for the current project, from
+    /// `.envrc` to the exported `PATH`, and report the first broken link
+    #[structopt(name = "direnv-hook-check")]
+    DirenvHookCheck(DirenvHookCheckOptions),
+
+    /// Parse and evaluate a shell file without building it, printing
+    /// any errors as `file:line:column: error: message`.
+    /// Exits non-zero if evaluation fails, so it can be used as a
+    /// pre-commit hook.
+    #[structopt(name = "check")]
+    Check(CheckOptions),
+
+    /// Write the project's environment into the configuration of an
+    /// IDE: `.vscode/settings.json` (vscode) or `.idea/lorri.env`
+    /// (idea, for the EnvFile plugin)
+    #[structopt(name = "ide-env")]
+    IdeEnv(IdeEnvOptions),
+
+    /// Print the expression lorri evaluates for a shell file (which
+    /// wraps the shell file to trace its inputs) and the exact
+    /// `nix-build` command it runs
+    #[structopt(name = "show-eval-expr")]
+    ShowEvalExpr(ShowEvalExprOptions),
+
+    /// Cancel the running build of a project in the daemon.
+    /// Exits non-zero if no build was running
+    #[structopt(name = "cancel")]
+    Cancel(CancelOptions),
+
+    /// Wait until the daemon has no pending or running builds,
+    /// for example to wait for all environments after pinging
+    /// a few projects.
+    #[structopt(name = "wait-idle")]
+    WaitIdle(WaitIdleOptions),
+
+    /// Build a trivial project in the running daemon (or in an
+    /// ephemeral one, if none is running) and check that its
+    /// environment is rooted, then clean up.
+    #[structopt(name = "self-test")]
+    SelfTest(SelfTestOptions),
+
+    /// Print the log of the current (or most recent) build of a
+    /// project in the daemon, as it is written
+    #[structopt(name = "logs")]
+    Logs(LogsOptions),
+
+    /// Print the events of the daemon's builds as JSON lines, as
+    /// they happen. Missed events (if this reads too slowly) are
+    /// reported as `{"event":"gap","dropped":<n>}`
+    #[structopt(name = "stream-events")]
+    StreamEvents(StreamEventsOptions),
+
+    /// List the projects the daemon watches, with the state of their
+    /// last build, when it finished and their GC root
+    #[structopt(name = "list-projects")]
+    ListProjects(ListProjectsOptions),
+
+    /// Stop the daemon: it stops listening right away (removing its
+    /// socket), and exits once its running builds are done
+    #[structopt(name = "stop-daemon")]
+    StopDaemon(StopDaemonOptions),
+
+    /// Print how many projects the daemon watches and how many file
+    /// descriptors it has open, out of how many it may
+    #[structopt(name = "stats")]
+    Stats(StatsOptions),
+
+    /// Tell the daemon to watch and build the given nix files, like
+    /// `lorri ping_` for many projects. `-` reads newline-separated
+    /// paths from stdin, as in `fd shell.nix | lorri internal register -`
+    #[structopt(name = "register")]
+    Register(RegisterOptions),
+
+    /// Tell the daemon to watch and build a project, like
+    /// `lorri ping_`, optionally waiting for the daemon to start
+    #[structopt(name = "ping")]
+    Ping(InternalPingOptions),
+
+    /// Check that the GC roots of all projects point to paths which
+    /// are still in the nix store (for example after
+    /// `nix-collect-garbage -d`), and print a summary.
+    /// Exits non-zero if dangling roots remain
+    #[structopt(name = "root-check")]
+    RootCheck(RootCheckOptions),
+
+    /// Print a typed client for the events of `lorri internal
+    /// stream-events`, in Python or TypeScript, to save tools from
+    /// parsing them by hand
+    #[structopt(name = "gen-client")]
+    GenClient(GenClientOptions),
+
+    /// Print the projects lorri knows, with their `.lorri.toml`, as
+    /// JSON for `lorri internal import-projects` on another machine
+    #[structopt(name = "export-projects")]
+    ExportProjects,
+
+    /// Import the projects of `lorri internal export-projects`: write
+    /// their `.lorri.toml` where there is none yet, and register them
+    /// with the daemon. Projects which don't exist here are skipped
+    #[structopt(name = "import-projects")]
+    ImportProjects(ImportProjectsOptions),
+
+    /// Ask the shells in the current project (registered by the hook
+    /// `--hook bash` or `--hook zsh` prints) to reload their direnv
+    /// environment, for example after a rebuild
+    #[structopt(name = "refresh-shells")]
+    RefreshShells(RefreshShellsOptions),
+}
+
+/// Options for the `refresh-shells` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct RefreshShellsOptions {
+    /// The .nix file of the project whose shells to refresh; shells
+    /// in its directory (or below) are refreshed
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// Refresh the shells of all projects
+    #[structopt(long = "all")]
+    pub all: bool,
+    /// Print the hook for ~/.bashrc (bash) or ~/.zshrc (zsh) which
+    /// registers shells, instead of refreshing them, as in
+    /// `eval "$(lorri internal refresh-shells --hook bash)"`
+    #[structopt(long = "hook")]
+    pub hook: Option<::ops::refresh_shells::HookShell>,
+}
+
+/// Options for the `daemon` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct DaemonOptions {
+    /// How many events are buffered for each `lorri internal
+    /// stream-events` client which doesn't keep up [default: 1024,
+    /// or `event-buffer` in `[daemon]` of config.toml]
+    #[structopt(long = "event-buffer")]
+    pub event_buffer: Option<usize>,
+    /// What happens once the buffer of such a client is full:
+    /// `drop-oldest` (the client is told how many events it missed)
+    /// or `disconnect` [default: drop-oldest, or `slow-listeners` in
+    /// `[daemon]` of config.toml]
+    #[structopt(long = "slow-listeners")]
+    pub slow_listeners: Option<SlowListeners>,
+    /// Cancel a running build as soon as one of its inputs changes,
+    /// and start a new one, instead of finishing the stale build
+    /// [default: `cancel-on-change` in `[build]` of config.toml]
+    #[structopt(long = "cancel-on-change")]
+    pub cancel_on_change: bool,
+    /// Finish running builds when their inputs change, even if
+    /// `cancel-on-change` is set in config.toml
+    #[structopt(long = "no-cancel-on-change", conflicts_with = "cancel_on_change")]
+    pub no_cancel_on_change: bool,
+    /// After a change, wait until no more changes arrive for this
+    /// many milliseconds before rebuilding, so that a burst of
+    /// writes triggers only one build [default: 0, or `debounce-ms`
+    /// in `[watch]` of config.toml]
+    #[structopt(long = "debounce-ms")]
+    pub debounce_ms: Option<u64>,
+    /// How to watch for changes: `inotify` (FSEvents on macOS),
+    /// `poll`, or `auto`, which polls paths on network filesystems
+    /// and once the system runs out of inotify watches [default:
+    /// auto, or `backend` in `[watch]` of config.toml]
+    #[structopt(long = "watch-backend")]
+    pub watch_backend: Option<WatchBackend>,
+    /// Show a desktop notification when a build fails, or completes
+    /// after a long time (see --notify-long-build-secs) [default:
+    /// `enabled` in `[notify]` of config.toml]
+    #[structopt(long = "notify")]
+    pub notify: bool,
+    /// Notify about completed builds which took at least this many
+    /// seconds [default: 30, or `long-build-secs` in `[notify]` of
+    /// config.toml]
+    #[structopt(long = "notify-long-build-secs")]
+    pub notify_long_build_secs: Option<u64>,
+    /// Also write the log to this file (see --log-rotate and
+    /// --log-keep)
+    #[structopt(long = "log-file", parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
+    /// When to rotate the log file: `daily`, or before it grows
+    /// beyond a size like `10M`
+    #[structopt(long = "log-rotate", default_value = "10M")]
+    pub log_rotate: Rotation,
+    /// How many rotated log files to keep (`<log-file>.1` is the
+    /// newest)
+    #[structopt(long = "log-keep", default_value = "5")]
+    pub log_keep: usize,
+    /// Don't log to stderr (only to --log-file)
+    #[structopt(long = "no-stderr-log")]
+    pub no_stderr_log: bool,
+}
+
+/// Options for the `init` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct InitOptions {
+    /// Directory with templates for the generated files (`shell.nix`
+    /// and `envrc`), which may contain placeholders like `{{project}}`,
+    /// `{{channel}}`, `{{user}}` and `{{env.NAME}}`
+    #[structopt(long = "template-dir", parse(from_os_str))]
+    pub template_dir: Option<PathBuf>,
+}
+
+/// Options for the `internal self-test` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct SelfTestOptions {
+    /// Always start an ephemeral daemon, even if one is running
+    #[structopt(long = "ephemeral")]
+    pub ephemeral: bool,
+}
+
+/// Options for the `internal root-check` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct RootCheckOptions {
+    /// Remove the dangling roots
+    #[structopt(long = "repair")]
+    pub repair: bool,
+    /// Ask the daemon to rebuild the projects with dangling roots
+    #[structopt(long = "rebuild")]
+    pub rebuild: bool,
+}
+
+/// Options for the `internal stream-events` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct StreamEventsOptions {
+    /// Only print the events of this .nix file in the current directory
+    /// (also `--nix-file`)
+    #[structopt(long = "shell-file", raw(alias = r#""nix-file""#), parse(from_os_str))]
+    pub nix_file: Option<PathBuf>,
+    /// Only print these kinds of events: failures, completions or
+    /// started (comma-separated, or given repeatedly)
+    #[structopt(long = "only", raw(use_delimiter = "true"))]
+    pub only: Vec<EventKind>,
+    /// Start with the events after the one with this `sequence`
+    /// number (as far as the daemon still keeps them), to resume
+    /// after reconnecting
+    #[structopt(long = "since")]
+    pub since: Option<u64>,
+    /// The `epoch` of the event passed to `--since`: if the daemon
+    /// restarted since, print a gap and all the events it keeps
+    #[structopt(long = "epoch", raw(requires = r#""since""#))]
+    pub epoch: Option<u64>,
+    /// Keep reconnecting when the connection to the daemon is lost,
+    /// like when it restarts, print `{"event":"reconnected"}` after
+    /// reconnecting, and resume after the last event
+    #[structopt(long = "follow", short = "f")]
+    pub follow: bool,
+    /// Also print the lines nix prints while building, as `log-line`
+    /// events (they have no `sequence`, and are not resumed)
+    #[structopt(long = "log-lines")]
+    pub log_lines: bool,
+}
+
+/// Options for the `internal logs` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct LogsOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// Keep following the logs of the next builds, until interrupted
+    #[structopt(long = "follow", short = "f")]
+    pub follow: bool,
+}
+
+/// Options for the `internal list-projects` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct ListProjectsOptions {
+    /// Print the projects as a JSON array
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `internal stats` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct StatsOptions {
+    /// Print the statistics as a JSON object
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `ping` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct PingOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// Give up waiting for the build after this many seconds
+    #[structopt(long = "timeout")]
+    pub timeout: Option<u64>,
+}
+
+/// Options for the `internal ping` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct InternalPingOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// If the daemon isn't listening yet, keep trying to connect
+    /// (for login scripts and direnv hooks racing its start)
+    #[structopt(long = "wait-for-daemon")]
+    pub wait_for_daemon: bool,
+    /// How long to wait for the daemon, in seconds
+    #[structopt(long = "timeout", default_value = "30")]
+    pub timeout: u64,
+}
+
+/// Options for the `internal register` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct RegisterOptions {
+    /// The nix files to register, or `-` to read them from stdin
+    #[structopt(parse(from_os_str), required = true)]
+    pub paths: Vec<PathBuf>,
+}
+
+/// Options for the `internal import-projects` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct ImportProjectsOptions {
+    /// The file written by `lorri internal export-projects`, or `-`
+    /// to read it from stdin
+    #[structopt(parse(from_os_str))]
+    pub file: PathBuf,
+    /// Write configurations which change nix settings or run
+    /// commands without asking
+    #[structopt(long = "yes", short = "y")]
+    pub yes: bool,
+}
+
+/// Options for the `internal stop-daemon` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct StopDaemonOptions {
+    /// Cancel the running builds instead of waiting for them
+    #[structopt(long = "cancel-builds")]
+    pub cancel_builds: bool,
+}
+
+/// Options for the `internal wait-idle` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct WaitIdleOptions {
+    /// Only wait for the builds of this .nix file in the current
+    /// directory; exits non-zero if the daemon doesn’t watch it
+    #[structopt(long = "shell-file", parse(from_os_str))]
+    pub nix_file: Option<PathBuf>,
+}
+
+/// Options for the `internal cancel` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct CancelOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+}
+
+/// Options for the `internal show-eval-expr` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct ShowEvalExprOptions {
+    /// The .nix file to show the evaluation of
+    #[structopt(parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+}
+
+/// Options for the `internal direnv-hook-check` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct DirenvHookCheckOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+}
+
+/// Options for the `internal check` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct CheckOptions {
+    /// The .nix file to check
+    #[structopt(parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+}
+
+/// Options for the `install-git-hooks` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct InstallGitHooksOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+}
+
+/// Options for the `install-service` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct InstallServiceOptions {
+    /// Install systemd user units (the only supported service manager)
+    #[structopt(long = "systemd")]
+    pub systemd: bool,
+    /// Put this directory in front of the daemon's PATH, so that
+    /// the tools in it (like a nix outside of the usual profiles)
+    /// are discovered. Can be given more than once
+    #[structopt(long = "discover", parse(from_os_str))]
+    pub discover: Vec<PathBuf>,
+    /// The CPU time the daemon and its builds may use, like `CPUQuota=`
+    /// in systemd.resource-control(5)
+    #[structopt(long = "cpu-quota", default_value = "100%")]
+    pub cpu_quota: String,
+    /// The memory use above which the daemon and its builds are
+    /// throttled, like `MemoryHigh=` in systemd.resource-control(5)
+    #[structopt(long = "memory-high", default_value = "4G")]
+    pub memory_high: String,
+}
+
+/// Options for the `internal gen-client` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct GenClientOptions {
+    /// The language of the client: python or typescript
+    #[structopt(long = "lang")]
+    pub lang: Lang,
+}
+
+/// Options for the `internal ide-env` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct IdeEnvOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// The IDE configuration format: vscode or idea
+    #[structopt(long = "format")]
+    pub format: IdeFormat,
 }
 
 /// Options for `watch` subcommand.
@@ -58,6 +540,44 @@ pub struct DirenvOptions {
     /// The .nix file in the current directory to use
     #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
     pub nix_file: PathBuf,
+    /// Load this named shell of the project (see `[[shell]]` in
+    /// `.lorri.toml`) instead of the default one
+    #[structopt(long = "shell")]
+    pub shell: Option<String>,
+    /// Load the attribute path ATTR of the nix file, which the
+    /// daemon builds as a project of its own (see `lorri watch
+    /// --attr`)
+    #[structopt(
+        short = "A",
+        long = "attr",
+        value_name = "ATTR",
+        parse(try_from_str = "::nix::valid_attribute_path")
+    )]
+    pub attr: Option<String>,
+    /// Print the changes to the environment and the files to watch
+    /// as JSON, for tools other than direnv
+    #[structopt(long = "json")]
+    pub json: bool,
+    /// Wait this long (like `200ms` or `2s`) for a build the daemon
+    /// is running, and load the previous environment, marked stale
+    /// with `LORRI_STALE=1`, if it takes longer
+    #[structopt(
+        long = "max-wait",
+        parse(try_from_str = "::ops::direnv::parse_max_wait")
+    )]
+    pub max_wait: Option<Duration>,
+}
+
+/// Options for the `gc` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct GcOptions {
+    /// Only print the roots which would be removed
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+    /// Only remove roots of projects last built longer ago than
+    /// this, like `30d` (units: s, m, h, d, w)
+    #[structopt(long = "older-than", parse(try_from_str = "::ops::gc::parse_age"))]
+    pub older_than: Option<Duration>,
 }
 
 /// Options for `watch` subcommand.
@@ -66,6 +586,34 @@ pub struct InfoOptions {
     /// The .nix file in the current directory to use
     #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
     pub nix_file: PathBuf,
+    /// Report this watch backend instead of the configured one (see
+    /// `lorri daemon --watch-backend`)
+    #[structopt(long = "watch-backend")]
+    pub watch_backend: Option<WatchBackend>,
+}
+
+/// Options for the `status` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct StatusOptions {
+    /// The project directory, or its .nix file
+    #[structopt(parse(from_os_str), default_value = "shell.nix")]
+    pub path: PathBuf,
+}
+
+/// Options for the `build` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct BuildOptions {
+    /// The .nix file in the current directory to use
+    #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
+    pub nix_file: PathBuf,
+    /// Print the summary as a single line of JSON
+    #[structopt(long = "json")]
+    pub json: bool,
+    // structopt takes doc comments as help, which flattened
+    // options can't have (see `NixArgs` instead)
+    #[allow(missing_docs)]
+    #[structopt(flatten)]
+    pub nix_args: NixArgs,
 }
 
 /// Options for `watch` subcommand.
@@ -74,9 +622,116 @@ pub struct WatchOptions {
     /// The .nix file in the current directory to use
     #[structopt(long = "shell-file", parse(from_os_str), default_value = "shell.nix")]
     pub nix_file: PathBuf,
+    /// Build this nix expression instead of the .nix file, like
+    /// `with import <nixpkgs> {}; mkShell { buildInputs = [ hello ]; }`
+    /// (relative paths in it don't refer to the current directory,
+    /// use absolute ones)
+    #[structopt(long = "expr")]
+    pub expr: Option<String>,
     /// Exit after a the first build
     #[structopt(long = "once")]
     pub once: bool,
+    /// Cancel a running build as soon as one of its inputs changes,
+    /// and start a new one, instead of finishing the stale build
+    /// [default: `cancel-on-change` in `[build]` of config.toml]
+    #[structopt(long = "cancel-on-change")]
+    pub cancel_on_change: bool,
+    /// Finish running builds when their inputs change, even if
+    /// `cancel-on-change` is set in config.toml
+    #[structopt(long = "no-cancel-on-change", conflicts_with = "cancel_on_change")]
+    pub no_cancel_on_change: bool,
+    /// After a change, wait until no more changes arrive for this
+    /// many milliseconds before rebuilding, so that a burst of
+    /// writes triggers only one build [default: 0, or `debounce-ms`
+    /// in `[watch]` of config.toml]
+    #[structopt(long = "debounce-ms")]
+    pub debounce_ms: Option<u64>,
+    /// How to watch for changes: `inotify` (FSEvents on macOS),
+    /// `poll`, or `auto`, which polls paths on network filesystems
+    /// and once the system runs out of inotify watches [default:
+    /// auto, or `backend` in `[watch]` of config.toml]
+    #[structopt(long = "watch-backend")]
+    pub watch_backend: Option<WatchBackend>,
+    /// Show a desktop notification when a build fails, or completes
+    /// after a long time (see --notify-long-build-secs) [default:
+    /// `enabled` in `[notify]` of config.toml]
+    #[structopt(long = "notify")]
+    pub notify: bool,
+    /// Notify about completed builds which took at least this many
+    /// seconds [default: 30, or `long-build-secs` in `[notify]` of
+    /// config.toml]
+    #[structopt(long = "notify-long-build-secs")]
+    pub notify_long_build_secs: Option<u64>,
+    // structopt takes doc comments as help, which flattened
+    // options can't have (see `NixArgs` instead)
+    #[allow(missing_docs)]
+    #[structopt(flatten)]
+    pub nix_args: NixArgs,
+}
+
+/// Arguments and settings passed to nix, like the ones of
+/// `nix-build`. They take precedence over the ones in the
+/// project's `.lorri.toml`.
+#[derive(StructOpt, Debug, Default)]
+pub struct NixArgs {
+    /// Build the attribute path ATTR of the nix file, like
+    /// `devShells.backend`, instead of all of it (quote names with
+    /// dots: `devShells."python3.11"`). Every attribute is a project
+    /// of its own, with its own GC roots
+    #[structopt(
+        short = "A",
+        long = "attr",
+        value_name = "ATTR",
+        parse(try_from_str = "::nix::valid_attribute_path")
+    )]
+    pub attr: Option<String>,
+    /// Pass the value of the nix expression EXPR as argument NAME
+    /// to the function in the nix file
+    #[structopt(
+        long = "arg",
+        number_of_values = 2,
+        raw(value_names = "&[\"NAME\", \"EXPR\"]")
+    )]
+    pub arg: Vec<String>,
+    /// Pass the string VALUE as argument NAME to the function in
+    /// the nix file
+    #[structopt(
+        long = "argstr",
+        number_of_values = 2,
+        raw(value_names = "&[\"NAME\", \"VALUE\"]")
+    )]
+    pub argstr: Vec<String>,
+    /// Set the nix setting NAME to VALUE
+    #[structopt(
+        long = "option",
+        number_of_values = 2,
+        raw(value_names = "&[\"NAME\", \"VALUE\"]")
+    )]
+    pub option: Vec<String>,
+    /// Print the stack trace of evaluation errors
+    #[structopt(long = "show-trace")]
+    pub show_trace: bool,
+}
+
+impl NixArgs {
+    /// The nix options for these arguments, except for `attr`,
+    /// which selects the project (see `Project::with_attribute`).
+    pub fn options(&self) -> nix::Options {
+        let mut options = nix::Options::new();
+        for pair in self.option.chunks(2) {
+            options.set(&pair[0], &pair[1]);
+        }
+        for pair in self.arg.chunks(2) {
+            options.shell_arg(&pair[0], &pair[1]);
+        }
+        for pair in self.argstr.chunks(2) {
+            options.shell_argstr(&pair[0], &pair[1]);
+        }
+        if self.show_trace {
+            options.show_trace();
+        }
+        options
+    }
 }
 
 /// Send a message with a lorri project.
@@ -95,6 +750,12 @@ pub struct Ping_ {
 #[derive(StructOpt, Debug)]
 #[structopt(name = "basic")]
 pub struct UpgradeTo {
+    /// Print the source lorri would be built from, the nix-build
+    /// invocation and the profile it would be installed into,
+    /// without building or installing anything
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+
     /// the path to a local check out of Lorri.
     #[structopt(subcommand)]
     pub source: Option<UpgradeSource>,
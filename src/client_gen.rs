@@ -0,0 +1,520 @@
+//! Generate typed clients for the JSON lines of `lorri internal
+//! stream-events` (see `event_sink`), so that tools don’t have to
+//! parse them by hand. The clients are generated from
+//! `event_sink::EVENT_SCHEMA`, and parse every event into a class
+//! (Python) or an interface (TypeScript) with its fields. Events
+//! which the client doesn’t know (from newer daemons) are skipped.
+
+use crate::event_sink::{EventSchema, Field, FieldType, COMMON_FIELDS, EVENT_SCHEMA};
+use crate::VERSION_BUILD_REV;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// The languages clients can be generated in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    /// Python 3.8 or later, without dependencies.
+    Python,
+    /// TypeScript, for node.js.
+    Typescript,
+}
+
+impl FromStr for Lang {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Lang, String> {
+        match s {
+            "python" => Ok(Lang::Python),
+            "typescript" => Ok(Lang::Typescript),
+            _ => Err(format!(
+                "unknown language `{}`, use python or typescript",
+                s
+            )),
+        }
+    }
+}
+
+/// The event `stream-events` prints when it missed events, which
+/// has none of the `COMMON_FIELDS`.
+const GAP: EventSchema = EventSchema {
+    name: "gap",
+    doc: "Events were dropped because this client read too slowly",
+    fields: &[Field {
+        name: "dropped",
+        ty: FieldType::Integer,
+        always: true,
+        doc: "How many",
+    }],
+};
+
+/// The event `stream-events --follow` prints after reconnecting to
+/// the daemon, which has no fields.
+const RECONNECTED: EventSchema = EventSchema {
+    name: "reconnected",
+    doc: "The connection to the daemon was lost (it restarted, or this client read too slowly) \
+          and re-established; the events it still kept follow, a gap stands for the others",
+    fields: &[],
+};
+
+/// `fields` with the required ones first, since optional fields of
+/// Python dataclasses can't come before required ones.
+fn required_first(fields: impl Iterator<Item = &'static Field>) -> Vec<&'static Field> {
+    let mut fields: Vec<&Field> = fields.collect();
+    // stable, so the fields stay in order otherwise
+    fields.sort_by_key(|field| !field.always);
+    fields
+}
+
+/// The events with all their fields, required ones first.
+fn events() -> Vec<(&'static EventSchema, Vec<&'static Field>)> {
+    EVENT_SCHEMA
+        .iter()
+        .map(|schema| {
+            (
+                schema,
+                required_first(schema.fields.iter().chain(COMMON_FIELDS)),
+            )
+        })
+        .chain(std::iter::once((&GAP, GAP.fields.iter().collect())))
+        .chain(std::iter::once((&RECONNECTED, vec![])))
+        .collect()
+}
+
+/// An object nested in events: its name, the documentation of the
+/// first field it appears in, and its fields, required ones first.
+type Object = (&'static str, &'static str, Vec<&'static Field>);
+
+/// The objects nested in events, in the order they first appear.
+fn objects() -> Vec<Object> {
+    fn collect(ty: &FieldType, doc: &'static str, objects: &mut Vec<Object>) {
+        match *ty {
+            FieldType::List(ty) | FieldType::Map(ty) | FieldType::Nullable(ty) => {
+                collect(ty, doc, objects)
+            }
+            FieldType::Object(name, fields) => {
+                for field in fields {
+                    collect(&field.ty, field.doc, objects);
+                }
+                if !objects.iter().any(|(known, _, _)| *known == name) {
+                    objects.push((name, doc, required_first(fields.iter())));
+                }
+            }
+            FieldType::String | FieldType::Integer | FieldType::Time | FieldType::OneOf(_) => {}
+        }
+    }
+    let mut objects = vec![];
+    for (_, fields) in events() {
+        for field in fields {
+            collect(&field.ty, field.doc, &mut objects);
+        }
+    }
+    objects
+}
+
+/// `cachix-push` as `CachixPush`.
+fn class_name(event: &str) -> String {
+    event
+        .split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Generate the client in `lang`.
+pub fn generate(lang: Lang) -> String {
+    let mut out = String::new();
+    match lang {
+        Lang::Python => python(&mut out),
+        Lang::Typescript => typescript(&mut out),
+    }
+    .expect("writing to a string never fails");
+    out
+}
+
+fn python_type(ty: &FieldType) -> String {
+    match *ty {
+        FieldType::String | FieldType::Time => String::from("str"),
+        FieldType::Integer => String::from("int"),
+        FieldType::OneOf(values) => format!(
+            "Literal[{}]",
+            values
+                .iter()
+                .map(|value| format!("{:?}", value))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        FieldType::List(ty) => format!("List[{}]", python_type(ty)),
+        FieldType::Map(ty) => format!("Dict[str, {}]", python_type(ty)),
+        FieldType::Nullable(ty) => format!("Optional[{}]", python_type(ty)),
+        FieldType::Object(name, _) => String::from(name),
+    }
+}
+
+/// An expression converting the JSON `value` to `ty`, at nesting
+/// `depth` (to name the variables of comprehensions).
+fn python_convert(ty: &FieldType, value: &str, depth: usize) -> String {
+    match *ty {
+        FieldType::String | FieldType::Integer | FieldType::Time | FieldType::OneOf(_) => {
+            String::from(value)
+        }
+        FieldType::List(ty) => {
+            let item = format!("v{}", depth);
+            let converted = python_convert(ty, &item, depth + 1);
+            if converted == item {
+                String::from(value)
+            } else {
+                format!("[{} for {} in {}]", converted, item, value)
+            }
+        }
+        FieldType::Map(ty) => {
+            let item = format!("v{}", depth);
+            let converted = python_convert(ty, &item, depth + 1);
+            if converted == item {
+                String::from(value)
+            } else {
+                format!(
+                    "{{k{d}: {} for k{d}, {} in {}.items()}}",
+                    converted,
+                    item,
+                    value,
+                    d = depth
+                )
+            }
+        }
+        FieldType::Nullable(ty) => {
+            let converted = python_convert(ty, value, depth);
+            if converted == value {
+                converted
+            } else {
+                format!("None if {} is None else {}", value, converted)
+            }
+        }
+        FieldType::Object(name, _) => format!("{}._from_json({})", name, value),
+    }
+}
+
+fn python_class(
+    out: &mut String,
+    name: &str,
+    doc: &str,
+    fields: &[&'static Field],
+) -> std::fmt::Result {
+    writeln!(
+        out,
+        "\n\n@dataclass\nclass {}:\n    \"\"\"{}.\"\"\"\n",
+        name, doc
+    )?;
+    for field in fields {
+        writeln!(out, "    #: {}", field.doc)?;
+        if field.always {
+            writeln!(out, "    {}: {}", field.name, python_type(&field.ty))?;
+        } else {
+            writeln!(
+                out,
+                "    {}: Optional[{}] = None",
+                field.name,
+                python_type(&field.ty)
+            )?;
+        }
+    }
+    writeln!(
+        out,
+        "\n    @classmethod\n    def _from_json(cls, data: Dict[str, Any]) -> \"{}\":\n        return cls(",
+        name
+    )?;
+    for field in fields {
+        let value = if field.always {
+            python_convert(&field.ty, &format!("data[{:?}]", field.name), 0)
+        } else {
+            python_convert(
+                &FieldType::Nullable(&field.ty),
+                &format!("data.get({:?})", field.name),
+                0,
+            )
+        };
+        writeln!(out, "            {}={},", field.name, value)?;
+    }
+    writeln!(out, "        )")
+}
+
+fn python(out: &mut String) -> std::fmt::Result {
+    writeln!(
+        out,
+        "# Generated by `lorri internal gen-client --lang python` (lorri {}).\n\
+         # Do not edit; regenerate it for newer versions of lorri.\n\
+         \"\"\"Typed events of `lorri internal stream-events`.\"\"\"\n\n\
+         import json\n\
+         import subprocess\n\
+         from dataclasses import dataclass\n\
+         from typing import Any, Dict, Iterator, List, Literal, Optional, Union",
+        VERSION_BUILD_REV
+    )?;
+    for (name, doc, fields) in objects() {
+        python_class(out, name, doc, &fields)?;
+    }
+    let events = events();
+    for (schema, fields) in &events {
+        python_class(out, &class_name(schema.name), schema.doc, fields)?;
+    }
+    let names: Vec<String> = events
+        .iter()
+        .map(|(schema, _)| class_name(schema.name))
+        .collect();
+    writeln!(out, "\n\nEvent = Union[{}]\n", names.join(", "))?;
+    writeln!(out, "_EVENTS = {{")?;
+    for (schema, _) in &events {
+        writeln!(out, "    {:?}: {},", schema.name, class_name(schema.name))?;
+    }
+    writeln!(
+        out,
+        "}}\n\n\n\
+         def parse_event(line: str) -> Optional[Event]:\n    \
+         \"\"\"Parse a line of `lorri internal stream-events`; None for unknown events.\"\"\"\n    \
+         data = json.loads(line)\n    \
+         cls = _EVENTS.get(data.get(\"event\"))\n    \
+         return None if cls is None else cls._from_json(data)\n\n\n\
+         def stream_events(nix_file: Optional[str] = None, lorri: str = \"lorri\") -> Iterator[Event]:\n    \
+         \"\"\"The events of the running daemon (of the project of `nix_file`, a\n    \
+         file in the current directory), until it closes the stream.\"\"\"\n    \
+         command = [lorri, \"internal\", \"stream-events\"]\n    \
+         if nix_file is not None:\n        \
+         command += [\"--shell-file\", nix_file]\n    \
+         with subprocess.Popen(command, stdout=subprocess.PIPE, text=True) as process:\n        \
+         for line in process.stdout:\n            \
+         event = parse_event(line)\n            \
+         if event is not None:\n                \
+         yield event"
+    )
+}
+
+fn typescript_type(ty: &FieldType) -> String {
+    match *ty {
+        FieldType::String | FieldType::Time => String::from("string"),
+        FieldType::Integer => String::from("number"),
+        FieldType::OneOf(values) => values
+            .iter()
+            .map(|value| format!("{:?}", value))
+            .collect::<Vec<_>>()
+            .join(" | "),
+        FieldType::List(ty) => format!("Array<{}>", typescript_type(ty)),
+        FieldType::Map(ty) => format!("Record<string, {}>", typescript_type(ty)),
+        FieldType::Nullable(ty) => format!("{} | null", typescript_type(ty)),
+        FieldType::Object(name, _) => String::from(name),
+    }
+}
+
+fn typescript_interface(
+    out: &mut String,
+    name: &str,
+    doc: &str,
+    event: Option<&str>,
+    fields: &[&Field],
+) -> std::fmt::Result {
+    writeln!(out, "\n/** {}. */\nexport interface {} {{", doc, name)?;
+    if let Some(event) = event {
+        writeln!(out, "  event: {:?};", event)?;
+    }
+    for field in fields {
+        writeln!(
+            out,
+            "  /** {} */\n  {}{}: {};",
+            field.doc,
+            field.name,
+            if field.always { "" } else { "?" },
+            typescript_type(&field.ty)
+        )?;
+    }
+    writeln!(out, "}}")
+}
+
+fn typescript(out: &mut String) -> std::fmt::Result {
+    writeln!(
+        out,
+        "// Generated by `lorri internal gen-client --lang typescript` (lorri {}).\n\
+         // Do not edit; regenerate it for newer versions of lorri.\n\
+         // Typed events of `lorri internal stream-events`.\n\n\
+         import {{ spawn }} from \"child_process\";\n\
+         import {{ createInterface }} from \"readline\";",
+        VERSION_BUILD_REV
+    )?;
+    for (name, doc, fields) in objects() {
+        typescript_interface(out, name, doc, None, &fields)?;
+    }
+    let events = events();
+    for (schema, fields) in &events {
+        typescript_interface(
+            out,
+            &class_name(schema.name),
+            schema.doc,
+            Some(schema.name),
+            fields,
+        )?;
+    }
+    let names: Vec<String> = events
+        .iter()
+        .map(|(schema, _)| class_name(schema.name))
+        .collect();
+    writeln!(
+        out,
+        "\nexport type Event =\n  | {};\n",
+        names.join("\n  | ")
+    )?;
+    writeln!(
+        out,
+        "const EVENTS = new Set([{}]);",
+        events
+            .iter()
+            .map(|(schema, _)| format!("{:?}", schema.name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )?;
+    writeln!(
+        out,
+        "\n/** Parse a line of `lorri internal stream-events`; null for unknown events. */\n\
+         export function parseEvent(line: string): Event | null {{\n  \
+         const data = JSON.parse(line);\n  \
+         return EVENTS.has(data.event) ? (data as Event) : null;\n\
+         }}\n\n\
+         /**\n \
+         * The events of the running daemon (of the project of `nixFile`, a\n \
+         * file in the current directory), until it closes the stream.\n \
+         */\n\
+         export async function* streamEvents(\n  \
+         options: {{ nixFile?: string; lorri?: string }} = {{}},\n\
+         ): AsyncGenerator<Event> {{\n  \
+         const args = [\"internal\", \"stream-events\"];\n  \
+         if (options.nixFile !== undefined) {{\n    \
+         args.push(\"--shell-file\", options.nixFile);\n  \
+         }}\n  \
+         const child = spawn(options.lorri ?? \"lorri\", args, {{\n    \
+         stdio: [\"ignore\", \"pipe\", \"inherit\"],\n  \
+         }});\n  \
+         try {{\n    \
+         for await (const line of createInterface({{ input: child.stdout }})) {{\n      \
+         const event = parseEvent(line);\n      \
+         if (event !== null) {{\n        \
+         yield event;\n      \
+         }}\n    \
+         }}\n  \
+         }} finally {{\n    \
+         child.kill();\n  \
+         }}\n\
+         }}"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{class_name, generate, required_first, Lang};
+    use crate::event_sink::{Field, FieldType};
+    use std::process::Command;
+
+    #[test]
+    fn clients() {
+        assert_eq!(class_name("environment-switched"), "EnvironmentSwitched");
+        assert_eq!(class_name("gap"), "Gap");
+
+        let python = generate(Lang::Python);
+        assert!(python.contains(
+            "\n@dataclass\nclass Completed:\n    \"\"\"The build completed successfully.\"\"\"\n"
+        ));
+        assert!(python.contains("            cache=Cache._from_json(data[\"cache\"]),\n"));
+        assert!(python.contains("    artifacts: Optional[str]\n"));
+        assert!(python.contains("    build_id: Optional[int] = None\n"));
+        assert!(python.contains("    \"cachix-push\": CachixPush,\n"));
+
+        let typescript = generate(Lang::Typescript);
+        assert!(typescript.contains("export interface Progress {\n  event: \"progress\";\n"));
+        assert!(typescript.contains("  kind: \"Builds\" | \"Downloads\" | \"Bytes\";\n"));
+        assert!(typescript.contains("  shells?: Record<string, string>;\n"));
+        assert!(typescript.contains("  | Gap\n  | Reconnected;\n"));
+    }
+
+    #[test]
+    fn required_fields_first() {
+        const FIELDS: &[Field] = &[
+            Field {
+                name: "a",
+                ty: FieldType::Integer,
+                always: false,
+                doc: "",
+            },
+            Field {
+                name: "b",
+                ty: FieldType::Integer,
+                always: true,
+                doc: "",
+            },
+            Field {
+                name: "c",
+                ty: FieldType::Integer,
+                always: false,
+                doc: "",
+            },
+        ];
+        assert_eq!(
+            required_first(FIELDS.iter())
+                .iter()
+                .map(|field| field.name)
+                .collect::<Vec<_>>(),
+            ["b", "a", "c"]
+        );
+    }
+
+    /// The generated Python client can be imported, and parses events.
+    #[test]
+    fn python_client_runs() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(tmp.path().join("lorri_events.py"), generate(Lang::Python))?;
+        let script = r#"
+import lorri_events
+event = lorri_events.parse_event('{"nix_file":"/p/shell.nix","event":"completed","build_id":2,"time":"2020-01-01T00:00:42.000Z","shell_gc_root":"/r","cache":{"substituted":12,"built":1,"substituters":{"https://cache.nixos.org":12}},"drv_path":null,"timings":{"evaluate_ms":1500,"realise_ms":20000},"rebuild":"Full","env_hash":null}')
+assert isinstance(event, lorri_events.Completed), event
+assert event.cache.substituters == {"https://cache.nixos.org": 12}, event
+assert event.timings.realise_ms == 20000, event
+assert event.shells is None, event
+assert lorri_events.parse_event('{"event":"from-the-future"}') is None
+"#;
+        let output = match Command::new("python3")
+            .arg("-c")
+            .arg(script)
+            .current_dir(tmp.path())
+            .output()
+        {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("skipping python_client_runs: python3 is not installed");
+                return Ok(());
+            }
+            output => output?,
+        };
+        assert!(output.status.success(), "{:?}", output);
+        Ok(())
+    }
+
+    /// The generated TypeScript client type-checks.
+    #[test]
+    fn typescript_client_checks() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let client = tmp.path().join("lorri_events.ts");
+        std::fs::write(&client, generate(Lang::Typescript))?;
+        let output = match Command::new("tsc")
+            .args(&["--strict", "--noEmit", "--target", "es2020"])
+            .args(&["--module", "commonjs", "--types", "node"])
+            .arg(&client)
+            .output()
+        {
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("skipping typescript_client_checks: tsc is not installed");
+                return Ok(());
+            }
+            output => output?,
+        };
+        assert!(output.status.success(), "{:?}", output);
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! Time as seen by the build loop.
+//!
+//! Waiting (for more file events, before retrying a build) goes
+//! through a `Clock`, so that tests can replace the system clock
+//! with a `FakeClock` and decide when time passes. `FakeClock` is
+//! available to integration tests with the `testing` feature.
+
+use std::time::{Duration, Instant};
+
+/// A source of time which can be waited on.
+pub trait Clock: Send + Sync {
+    /// The current time.
+    fn now(&self) -> Instant;
+    /// Block for `duration`.
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock of the system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration)
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub use self::fake::FakeClock;
+
+#[cfg(any(test, feature = "testing"))]
+mod fake {
+    use super::Clock;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::{Duration, Instant};
+
+    struct Time {
+        now: Instant,
+        /// When the threads blocked in `sleep` wake up.
+        sleepers: Vec<Instant>,
+    }
+
+    /// A clock which only moves when `advance`d.
+    /// Clones share the same time.
+    #[derive(Clone)]
+    pub struct FakeClock(Arc<(Mutex<Time>, Condvar)>);
+
+    impl FakeClock {
+        /// A clock standing at the current time.
+        pub fn new() -> FakeClock {
+            FakeClock(Arc::new((
+                Mutex::new(Time {
+                    now: Instant::now(),
+                    sleepers: vec![],
+                }),
+                Condvar::new(),
+            )))
+        }
+
+        /// Move the clock forward by `duration`, waking up the
+        /// sleepers whose time has come.
+        pub fn advance(&self, duration: Duration) {
+            let (ref time, ref changed) = *self.0;
+            time.lock().expect("clock lock poisoned").now += duration;
+            changed.notify_all();
+        }
+
+        /// Block until `n` threads are sleeping on this clock and
+        /// not yet due to wake up, so that advancing it afterwards
+        /// wakes them up.
+        pub fn wait_for_sleepers(&self, n: usize) {
+            let (ref time, ref changed) = *self.0;
+            let mut time = time.lock().expect("clock lock poisoned");
+            while time
+                .sleepers
+                .iter()
+                .filter(|&&wake| wake > time.now)
+                .count()
+                < n
+            {
+                time = changed.wait(time).expect("clock lock poisoned");
+            }
+        }
+    }
+
+    impl Default for FakeClock {
+        fn default() -> FakeClock {
+            FakeClock::new()
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            (self.0).0.lock().expect("clock lock poisoned").now
+        }
+
+        fn sleep(&self, duration: Duration) {
+            let (ref time, ref changed) = *self.0;
+            let mut time = time.lock().expect("clock lock poisoned");
+            let deadline = time.now + duration;
+            time.sleepers.push(deadline);
+            changed.notify_all();
+            while time.now < deadline {
+                time = changed.wait(time).expect("clock lock poisoned");
+            }
+            let me = time
+                .sleepers
+                .iter()
+                .position(|&wake| wake == deadline)
+                .expect("sleeper is registered");
+            time.sleepers.swap_remove(me);
+            changed.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, FakeClock};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn fake_clock_sleeps_until_advanced() {
+        let clock = FakeClock::new();
+        let start = clock.now();
+        let (tx, rx) = mpsc::channel();
+        let sleeper = clock.clone();
+        let handle = std::thread::spawn(move || {
+            sleeper.sleep(Duration::from_secs(5));
+            tx.send(sleeper.now()).unwrap();
+        });
+
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(4));
+        clock.wait_for_sleepers(1);
+        assert!(rx.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(1));
+        handle.join().unwrap();
+        assert_eq!(rx.recv().unwrap() - start, Duration::from_secs(5));
+    }
+}
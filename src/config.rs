@@ -0,0 +1,446 @@
+//! The global configuration of lorri, for all projects, in
+//! `config.toml` in lorri’s configuration directory
+//! (`$XDG_CONFIG_HOME/lorri/config.toml` on Linux):
+//!
+//! ```toml
+//! [daemon]
+//! # see `lorri daemon --event-buffer` and `--slow-listeners`
+//! event-buffer = 1024
+//! slow-listeners = "drop-oldest"
+//!
+//! [build]
+//! # see `lorri daemon --cancel-on-change` (and
+//! # `--no-cancel-on-change`)
+//! cancel-on-change = false
+//! # how often builds failing with network errors are retried,
+//! # waiting this long before the first retry (and twice as long
+//! # before each following one, up to the maximum)
+//! network-retries = 3
+//! network-retry-delay-secs = 5
+//! network-retry-max-delay-secs = 300
+//!
+//! [watch]
+//! # see `lorri daemon --debounce-ms`; the project’s `.lorri.toml`
+//! # can override it (see `project::config`)
+//! debounce-ms = 0
+//! # how often inputs tracked by content hash are checked (at
+//! # least every second)
+//! poll-interval-secs = 10
+//! # see `lorri daemon --watch-backend`
+//! backend = "auto"
+//!
+//! [notify]
+//! # see `lorri daemon --notify` and `--notify-long-build-secs`
+//! enabled = false
+//! long-build-secs = 30
+//!
+//! [hooks]
+//! # shell commands run when builds of any project start, complete
+//! # or fail, unless the project’s `.lorri.toml` has its own (see
+//! # `hooks`)
+//! completed = ""
+//! failure = 'notify-send lorri "$LORRI_PROJECT failed"'
+//! ```
+//!
+//! Every setting can also be set with an environment variable named
+//! after its section and key, like `LORRI_WATCH_DEBOUNCE_MS`. They
+//! take precedence over the file; command line flags take precedence
+//! over both. A missing file is the same as an empty one.
+//!
+//! Unknown settings in the file are errors. Variables in the
+//! namespace of a section which don’t name one of its settings (like
+//! `LORRI_WATCH_DEBOUNCE`) are only warned about, since the
+//! environment is shared with other programs; with
+//! `LORRI_STRICT_CONFIG=1` they are errors, too.
+
+use crate::build_loop::{NETWORK_RETRIES, NETWORK_RETRY_DELAY, NETWORK_RETRY_MAX_DELAY};
+use crate::config_error::{closest, InvalidSetting};
+use crate::event_stream::{SlowListeners, DEFAULT_CAPACITY};
+use crate::project::config::HooksConfig;
+use crate::watch::{WatchBackend, POLL_INTERVAL};
+use std::io;
+use std::path::{Path, PathBuf};
+use toml;
+
+/// The name of the configuration file in lorri’s configuration
+/// directory.
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// The prefix of the environment variables which override settings.
+const ENV_PREFIX: &str = "LORRI_";
+
+/// The environment variable which makes unknown settings in the
+/// environment errors, see the module documentation.
+const STRICT_ENV_VAR: &str = "LORRI_STRICT_CONFIG";
+
+/// The global configuration, see the module documentation.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Settings of the daemon itself.
+    pub daemon: DaemonConfig,
+    /// How projects are built.
+    pub build: BuildConfig,
+    /// How the inputs of projects are watched.
+    pub watch: WatchConfig,
+    /// Desktop notifications about builds.
+    pub notify: NotifyConfig,
+    /// Commands run on the build events of all projects.
+    pub hooks: HooksConfig,
+}
+
+/// The `[daemon]` section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DaemonConfig {
+    /// How many events are buffered for each client which doesn’t
+    /// keep up (see `event_stream`).
+    pub event_buffer: usize,
+    /// What happens once the buffer of such a client is full.
+    pub slow_listeners: SlowListeners,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> DaemonConfig {
+        DaemonConfig {
+            event_buffer: DEFAULT_CAPACITY,
+            slow_listeners: SlowListeners::default(),
+        }
+    }
+}
+
+/// The `[build]` section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BuildConfig {
+    /// See `BuildLoop::set_cancel_on_change`.
+    pub cancel_on_change: bool,
+    /// How often builds failing with network errors are retried.
+    pub network_retries: u32,
+    /// How long to wait before the first retry, in seconds.
+    pub network_retry_delay_secs: u64,
+    /// How long to wait before a retry at most, in seconds.
+    pub network_retry_max_delay_secs: u64,
+}
+
+impl Default for BuildConfig {
+    fn default() -> BuildConfig {
+        BuildConfig {
+            cancel_on_change: false,
+            network_retries: NETWORK_RETRIES,
+            network_retry_delay_secs: NETWORK_RETRY_DELAY.as_secs(),
+            network_retry_max_delay_secs: NETWORK_RETRY_MAX_DELAY.as_secs(),
+        }
+    }
+}
+
+/// The `[watch]` section.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WatchConfig {
+    /// See `BuildLoop::set_debounce`, in milliseconds.
+    pub debounce_ms: u64,
+    /// See `Watch::set_poll_interval`, in seconds (at least one).
+    #[serde(deserialize_with = "at_least_one")]
+    pub poll_interval_secs: u64,
+    /// See `Watch::set_backend`.
+    pub backend: WatchBackend,
+}
+
+impl Default for WatchConfig {
+    fn default() -> WatchConfig {
+        WatchConfig {
+            debounce_ms: 0,
+            poll_interval_secs: POLL_INTERVAL.as_secs(),
+            backend: WatchBackend::default(),
+        }
+    }
+}
+
+/// Deserialize a number which has to be at least one, like an
+/// interval the build loop waits for.
+fn at_least_one<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::{Error, Unexpected};
+    use serde::Deserialize;
+
+    match u64::deserialize(deserializer)? {
+        0 => Err(D::Error::invalid_value(
+            Unexpected::Unsigned(0),
+            &"a number of at least 1",
+        )),
+        n => Ok(n),
+    }
+}
+
+/// The `[notify]` section, see `notification`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct NotifyConfig {
+    /// Show notifications at all.
+    pub enabled: bool,
+    /// Notify about completed builds which took at least this many
+    /// seconds (failed builds are always notified about).
+    pub long_build_secs: u64,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> NotifyConfig {
+        NotifyConfig {
+            enabled: false,
+            long_build_secs: 30,
+        }
+    }
+}
+
+/// Loading the configuration failed.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file exists, but cannot be read.
+    Io(PathBuf, io::Error),
+    /// The file is not valid TOML, or has unknown or invalid settings.
+    Parse(InvalidSetting),
+    /// The environment variable has an invalid value.
+    Env(String, toml::de::Error),
+    /// The environment variable names no setting of its section
+    /// (only with `LORRI_STRICT_CONFIG=1`). Has the variable which
+    /// was probably meant, if any.
+    UnknownEnv(String, Option<String>),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(invalid) => write!(f, "{}", invalid),
+            ConfigError::Env(name, e) => write!(f, "invalid ${}: {}", name, e),
+            ConfigError::UnknownEnv(name, suggestion) => {
+                write!(f, "${} is no setting", name)?;
+                match suggestion {
+                    Some(suggestion) => write!(f, "; did you mean ${}?", suggestion),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from `file`, overridden by the
+    /// environment variables of this process.
+    pub fn load(file: &Path) -> Result<Config, ConfigError> {
+        Config::from_sources(
+            file,
+            std::env::vars_os().filter_map(|(name, value)| {
+                Some((name.into_string().ok()?, value.into_string().ok()?))
+            }),
+        )
+    }
+
+    /// Read the configuration from `file`, overridden by the
+    /// settings in `env` (environment variables, by name). Variables
+    /// which don’t name a setting are ignored, or warned about if
+    /// they look like they should (see the module documentation).
+    pub fn from_sources<I>(file: &Path, env: I) -> Result<Config, ConfigError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let contents = match std::fs::read_to_string(file) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(ConfigError::Io(file.to_owned(), e)),
+            Ok(contents) => contents,
+        };
+        let parse_error = |e| ConfigError::Parse(InvalidSetting::new(file, &contents, &e));
+        let mut table: toml::value::Table = toml::from_str(&contents).map_err(parse_error)?;
+        let mut config: Config = toml::Value::Table(table.clone())
+            .try_into()
+            .map_err(parse_error)?;
+
+        let defaults = match toml::Value::try_from(Config::default()) {
+            Ok(toml::Value::Table(defaults)) => defaults,
+            _ => unreachable!("the configuration is a table"),
+        };
+        let mut env: Vec<(String, String)> = env
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        // apply (and report) them in a stable order
+        env.sort();
+        let strict = env
+            .iter()
+            .any(|(name, value)| name == STRICT_ENV_VAR && value == "1");
+        for (name, raw) in env {
+            let setting = defaults.iter().find_map(|(section, keys)| {
+                keys.as_table()?
+                    .iter()
+                    .find(|(key, _)| env_var(section, key) == name)
+                    .map(|(key, default)| (section, key, default))
+            });
+            let (section, key, default) = match setting {
+                Some(setting) => setting,
+                None => {
+                    unknown_env(&defaults, &name, strict)?;
+                    continue;
+                }
+            };
+            // strings are taken as they are, other values are parsed
+            let value = match default {
+                toml::Value::String(_) => toml::Value::String(raw),
+                _ => toml::from_str::<toml::value::Table>(&format!("value = {}", raw))
+                    .ok()
+                    .and_then(|mut parsed| parsed.remove("value"))
+                    .unwrap_or_else(|| toml::Value::String(raw)),
+            };
+            if let toml::Value::Table(ref mut keys) = *table
+                .entry(section.clone())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+            {
+                keys.insert(key.clone(), value);
+            }
+            config = toml::Value::Table(table.clone())
+                .try_into()
+                .map_err(|e| ConfigError::Env(name.clone(), e))?;
+        }
+        Ok(config)
+    }
+}
+
+/// Report the variable `name`, which names no setting, if it is in
+/// the namespace of one of the sections of `defaults`: as an error
+/// if `strict`, otherwise as a warning.
+fn unknown_env(defaults: &toml::value::Table, name: &str, strict: bool) -> Result<(), ConfigError> {
+    let section = defaults
+        .iter()
+        .find(|(section, _)| name.starts_with(&env_var(section, "")));
+    let variables: Vec<String> = match section {
+        Some((section, keys)) => keys
+            .as_table()
+            .into_iter()
+            .flat_map(|keys| keys.keys())
+            .map(|key| env_var(section, key))
+            .collect(),
+        None => return Ok(()),
+    };
+    let suggestion = closest(name, variables.iter().map(String::as_str));
+    let error = ConfigError::UnknownEnv(name.to_owned(), suggestion);
+    if strict {
+        return Err(error);
+    }
+    warn!("ignoring {}", error);
+    Ok(())
+}
+
+/// The environment variable for `key` in `section`, like
+/// `LORRI_WATCH_DEBOUNCE_MS`.
+fn env_var(section: &str, key: &str) -> String {
+    format!("{}{}_{}", ENV_PREFIX, section, key)
+        .to_uppercase()
+        .replace('-', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError};
+    use event_stream::SlowListeners;
+    use std::io::Write;
+    use watch::WatchBackend;
+
+    #[test]
+    fn layered_config() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let missing = tmp.path().join("missing.toml");
+        let env = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            Config::from_sources(&missing, env(&[("HOME", "/home/user")])).unwrap(),
+            Config::default()
+        );
+
+        let file = tmp.path().join("config.toml");
+        write!(
+            std::fs::File::create(&file)?,
+            "[watch]\ndebounce-ms = 100\npoll-interval-secs = 30\n\
+             [daemon]\nslow-listeners = \"disconnect\"\n"
+        )?;
+        let config = Config::from_sources(
+            &file,
+            env(&[
+                ("LORRI_WATCH_DEBOUNCE_MS", "200"),
+                ("LORRI_BUILD_CANCEL_ON_CHANGE", "true"),
+                ("LORRI_WATCH_BACKEND", "poll"),
+                ("LORRI_HOOKS_FAILURE", "notify-send \"build failed\""),
+                ("LORRI_UNRELATED", "x"),
+            ]),
+        )
+        .unwrap();
+        // the environment wins over the file
+        assert_eq!(config.watch.debounce_ms, 200);
+        assert_eq!(config.watch.poll_interval_secs, 30);
+        assert!(config.build.cancel_on_change);
+        assert_eq!(config.watch.backend, WatchBackend::Poll);
+        assert_eq!(config.daemon.slow_listeners, SlowListeners::Disconnect);
+        assert_eq!(config.build.network_retries, 3);
+        assert_eq!(config.hooks.failure, "notify-send \"build failed\"");
+
+        match Config::from_sources(&file, env(&[("LORRI_WATCH_DEBOUNCE_MS", "soon")])) {
+            Err(ConfigError::Env(name, _)) => assert_eq!(name, "LORRI_WATCH_DEBOUNCE_MS"),
+            other => panic!("{:?}", other),
+        }
+        // the loop would never wait
+        match Config::from_sources(&file, env(&[("LORRI_WATCH_POLL_INTERVAL_SECS", "0")])) {
+            Err(ConfigError::Env(name, _)) => assert_eq!(name, "LORRI_WATCH_POLL_INTERVAL_SECS"),
+            other => panic!("{:?}", other),
+        }
+        write!(
+            std::fs::File::create(&file)?,
+            "[watch]\npoll-interval-secs = 0\n"
+        )?;
+        match Config::from_sources(&file, vec![]) {
+            Err(ConfigError::Parse(invalid)) => assert_eq!(invalid.line, Some(2)),
+            other => panic!("{:?}", other),
+        }
+        write!(std::fs::File::create(&file)?, "[watch]\ndebounce = 1\n")?;
+        match Config::from_sources(&file, vec![]) {
+            Err(ConfigError::Parse(invalid)) => {
+                assert_eq!(invalid.file, file);
+                assert_eq!(invalid.line, Some(2));
+                assert_eq!(invalid.suggestion, Some(String::from("debounce-ms")));
+            }
+            other => panic!("{:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_env_vars() {
+        let env = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        let missing = std::path::Path::new("/nonexistent/config.toml");
+        let typo = ("LORRI_WATCH_DEBOUNCE", "200");
+        assert_eq!(
+            Config::from_sources(missing, env(&[typo])).unwrap(),
+            Config::default()
+        );
+        match Config::from_sources(missing, env(&[typo, ("LORRI_STRICT_CONFIG", "1")])) {
+            Err(ConfigError::UnknownEnv(name, suggestion)) => {
+                assert_eq!(name, "LORRI_WATCH_DEBOUNCE");
+                assert_eq!(suggestion, Some(String::from("LORRI_WATCH_DEBOUNCE_MS")));
+            }
+            other => panic!("{:?}", other),
+        }
+        // other programs’ variables are not settings
+        assert!(Config::from_sources(
+            missing,
+            env(&[("LORRI_STALE", "1"), ("LORRI_STRICT_CONFIG", "1")])
+        )
+        .is_ok());
+    }
+}
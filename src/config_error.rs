@@ -0,0 +1,222 @@
+//! Explain invalid settings in TOML configuration files: which file,
+//! line and key, and what was probably meant.
+//!
+//! The `toml` crate only knows the line of syntax errors; settings
+//! of the wrong type or with unknown names are found by their key.
+
+use std::path::{Path, PathBuf};
+
+/// An invalid setting in a configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvalidSetting {
+    /// The configuration file.
+    pub file: PathBuf,
+    /// The line of the setting (starting at 1), if it could be found.
+    pub line: Option<usize>,
+    /// The (dotted) key of the setting, like `watch.debounce-ms`.
+    pub key: Option<String>,
+    /// What is wrong with it.
+    pub message: String,
+    /// The name or value which was probably meant.
+    pub suggestion: Option<String>,
+}
+
+impl InvalidSetting {
+    /// Explain `error`, from deserializing `contents` of `file`.
+    pub fn new(file: &Path, contents: &str, error: &toml::de::Error) -> InvalidSetting {
+        let text = error.to_string();
+        // the `toml` crate appends these to its messages
+        let (text, line) = match (text.rfind(" at line "), error.line_col()) {
+            (Some(at), Some((line, _))) => (&text[..at], Some(line + 1)),
+            _ => (&text[..], None),
+        };
+        let (message, context) = match text.rfind(" for key `") {
+            Some(at) if text.ends_with('`') => (
+                &text[..at],
+                Some(&text[at + " for key `".len()..text.len() - 1]),
+            ),
+            _ => (text, None),
+        };
+
+        let unknown = quoted(message, "unknown field `");
+        // an unknown field is a key of its own, below the context
+        let key = match (context, unknown) {
+            (Some(context), Some(field)) => Some(format!("{}.{}", context, field)),
+            (None, Some(field)) => Some(field.to_owned()),
+            (context, None) => context.map(String::from),
+        };
+        let wrong = unknown.or_else(|| quoted(message, "unknown variant `"));
+        let suggestion = wrong.and_then(|wrong| {
+            let expected = message.find(", expected ").map(|at| &message[at..])?;
+            closest(wrong, expected.split('`').skip(1).step_by(2))
+        });
+
+        InvalidSetting {
+            file: file.to_owned(),
+            line: line.or_else(|| key.as_ref().and_then(|key| find_line(contents, key))),
+            key,
+            message: message.to_owned(),
+            suggestion,
+        }
+    }
+}
+
+impl std::fmt::Display for InvalidSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.file.display())?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        match self.key {
+            Some(ref key) => write!(f, ": invalid setting `{}`: {}", key, self.message)?,
+            None => write!(f, ": {}", self.message)?,
+        }
+        if let Some(ref suggestion) = self.suggestion {
+            write!(f, "; did you mean `{}`?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// The text between `prefix` and the next backtick in `message`.
+fn quoted<'a>(message: &'a str, prefix: &str) -> Option<&'a str> {
+    let start = message.find(prefix)? + prefix.len();
+    let len = message[start..].find('`')?;
+    Some(&message[start..start + len])
+}
+
+/// The line (starting at 1) where the setting `key` (like
+/// `watch.debounce-ms`, or a table like `watch`) is set in the TOML
+/// `contents`. Only understands the usual `[table]` and `key = value`
+/// lines, which is where typos are.
+fn find_line(contents: &str, key: &str) -> Option<usize> {
+    let (table, name) = match key.rfind('.') {
+        Some(at) => (&key[..at], &key[at + 1..]),
+        None => ("", key),
+    };
+    let mut current = String::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            current = line
+                .trim_start_matches('[')
+                .split(']')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_owned();
+            if current == key {
+                return Some(number + 1);
+            }
+        } else if current == table
+            && line.starts_with(name)
+            && line[name.len()..].trim_start().starts_with('=')
+        {
+            return Some(number + 1);
+        }
+    }
+    None
+}
+
+/// The candidate closest to `wrong`, if it is close enough to be a
+/// typo of it.
+pub fn closest<'a, I>(wrong: &str, candidates: I) -> Option<String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(wrong, candidate), candidate))
+        .filter(|(distance, candidate)| {
+            *distance <= std::cmp::max(2, candidate.len() / 3) || candidate.starts_with(wrong)
+        })
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate.to_owned())
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, b) in b.iter().enumerate() {
+            let substituted = diagonal + if a == *b { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closest, InvalidSetting};
+    use std::path::Path;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    #[allow(dead_code)]
+    struct Config {
+        #[serde(default)]
+        watch: Watch,
+    }
+
+    #[derive(Debug, Default, Deserialize)]
+    #[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+    #[allow(dead_code)]
+    struct Watch {
+        debounce_ms: u64,
+        poll_interval_secs: u64,
+    }
+
+    fn explain(contents: &str) -> InvalidSetting {
+        let error = toml::from_str::<Config>(contents).unwrap_err();
+        InvalidSetting::new(Path::new("/config.toml"), contents, &error)
+    }
+
+    #[test]
+    fn unknown_key() {
+        let invalid = explain("[watch]\npoll-interval-secs = 1\ndebounce = 1\n");
+        assert_eq!(invalid.line, Some(3));
+        assert_eq!(invalid.key, Some(String::from("watch.debounce")));
+        assert_eq!(invalid.suggestion, Some(String::from("debounce-ms")));
+        assert_eq!(
+            invalid.to_string(),
+            "/config.toml:3: invalid setting `watch.debounce`: unknown field `debounce`, \
+             expected `debounce-ms` or `poll-interval-secs`; did you mean `debounce-ms`?"
+        );
+
+        let invalid = explain("[wach]\ndebounce-ms = 1\n");
+        assert_eq!(invalid.line, Some(1));
+        assert_eq!(invalid.suggestion, Some(String::from("watch")));
+    }
+
+    #[test]
+    fn wrong_type() {
+        let invalid = explain("\n[watch]\ndebounce-ms = \"soon\"\n");
+        assert_eq!(
+            invalid.to_string(),
+            "/config.toml:3: invalid setting `watch.debounce-ms`: \
+             invalid type: string \"soon\", expected u64"
+        );
+    }
+
+    #[test]
+    fn syntax_error() {
+        let invalid = explain("[watch\n");
+        assert_eq!(invalid.line, Some(1));
+        assert_eq!(invalid.key, None);
+    }
+
+    #[test]
+    fn closest_candidate() {
+        let candidates = ["auto", "inotify", "poll"];
+        assert_eq!(
+            closest("inotfy", candidates.iter().cloned()),
+            Some(String::from("inotify"))
+        );
+        assert_eq!(closest("kqueue", candidates.iter().cloned()), None);
+    }
+}
@@ -4,13 +4,16 @@ extern crate directories;
 
 use self::directories::ProjectDirs;
 use cas::ContentAddressable;
+use config;
 use std::path::{Path, PathBuf};
 
 /// Path constants like the GC root directory.
 pub struct Paths {
     gc_root_dir: PathBuf,
     daemon_socket_file: PathBuf,
+    shells_dir: PathBuf,
     cas_store: ContentAddressable,
+    config_file: PathBuf,
 }
 
 impl Paths {
@@ -21,16 +24,18 @@ impl Paths {
         let create_dir = |dir: PathBuf| -> std::io::Result<PathBuf> {
             std::fs::create_dir_all(&dir).and(Ok(dir))
         };
+        // fall back to the cache dir on non-linux
+        let runtime_dir = create_dir(
+            pd.runtime_dir()
+                .unwrap_or_else(|| pd.cache_dir())
+                .to_owned(),
+        )?;
         Ok(Paths {
             gc_root_dir: create_dir(pd.cache_dir().join("gc_roots"))?,
-            daemon_socket_file: create_dir(
-                pd.runtime_dir()
-                    // fall back to the cache dir on non-linux
-                    .unwrap_or_else(|| pd.cache_dir())
-                    .to_owned(),
-            )?
-            .join("daemon.socket"),
+            daemon_socket_file: runtime_dir.join("daemon.socket"),
+            shells_dir: runtime_dir.join("shells"),
             cas_store: ContentAddressable::new(pd.cache_dir().join("cas"))?,
+            config_file: pd.config_dir().join(config::CONFIG_FILE_NAME),
         })
     }
 
@@ -48,6 +53,12 @@ impl Paths {
         &self.daemon_socket_file
     }
 
+    /// Directory in which shells register to be refreshed after
+    /// rebuilds (see `::ops::refresh_shells`). Created by the shells.
+    pub fn shells_dir(&self) -> &Path {
+        &self.shells_dir
+    }
+
     /// content-addressable store.
     ///
     /// It should be used to reify strings that are needed as files,
@@ -55,4 +66,10 @@ impl Paths {
     pub fn cas_store(&self) -> &ContentAddressable {
         &self.cas_store
     }
+
+    /// The global configuration file (see `::config`), which might
+    /// not exist.
+    pub fn config_file(&self) -> &Path {
+        &self.config_file
+    }
 }
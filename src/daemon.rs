@@ -1,12 +1,39 @@
 //! The lorri daemon, watches multiple projects in the background.
 
-use crate::build_loop::BuildLoop;
+use crate::build_log::{BuildLog, LogEntry};
+use crate::build_loop::{self, Activity, BuildId};
+use crate::builder::Canceller;
+use crate::cas::ContentAddressable;
+use crate::clock::SystemClock;
+use crate::config::Config;
+use crate::event_sink;
+use crate::event_stream::{BufferConfig, EventStream, Since, Streamed};
+use crate::fds;
+use crate::hooks;
+use crate::notification::Notifier;
+use crate::ops::refresh_shells;
+use crate::project::config::{HooksConfig, ProjectConfig};
+use crate::project::roots::Roots;
 use crate::project::Project;
-use crate::socket::communicate::{NoMessage, Ping, DEFAULT_READ_TIMEOUT};
+use crate::socket::communicate::{
+    client, listener, BuildState, CancelBuild, CancelBuildResult, CommunicationType, EventMessage,
+    FollowLog, Latency, LatencyResult, ListProjects, ListProjectsResult, LogMessage, Monitor, Ping,
+    PingAttribute, PingResult, ProjectStatus, Rebuild, RegistrationError, RequestBuild,
+    RequestBuildResult, Resume, Shutdown, ShutdownResult, Stats, StatsResult, Status, StatusResult,
+    StreamEvents, Subscribe, WaitIdle, WaitIdleFor, WaitIdleForResult, WaitIdleResult,
+    WatchedProject, DEFAULT_READ_TIMEOUT,
+};
+use crate::socket::path::{BindError, SocketPath};
 use crate::socket::{ReadError, ReadWriter, Timeout};
-use crate::NixFile;
+use crate::thread::Pool;
+use crate::{nix, NixFile, NixSource};
 use std::collections::HashMap;
-use std::sync::mpsc;
+use std::net;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// Indicate that the user is interested in a specific nix file.
 /// Usually a nix file describes the environment of a project,
@@ -23,20 +50,93 @@ use std::sync::mpsc;
 pub struct IndicateActivity {
     /// This nix file should be build/watched by the daemon.
     pub nix_file: NixFile,
+    /// Only this attribute path of it (see `PingAttribute`).
+    pub attribute: Option<String>,
+    /// Answered once the project is set up, or with why it could
+    /// not be, so the client learns about it.
+    pub registered: mpsc::Sender<Result<(), RegistrationError>>,
 }
 
+/// A project the daemon builds: a nix file, and the attribute path
+/// of it a client asked for with `PingAttribute`, if any (see
+/// `Project::with_attribute`). Requests which only name the nix
+/// file are about its project without an attribute.
+type ProjectKey = (NixFile, Option<String>);
+
 /// Keeps all state of the running `lorri daemon` service, watches nix files and runs builds.
+///
+/// `start` listens on a socket and builds the projects clients ask
+/// for, until `stop` is called (or until it is asked to shut down,
+/// see `run_until_shutdown`). Alternatively, the daemon can be
+/// driven manually with `handlers` and `add`.
 pub struct Daemon {
-    /// A thread for each `BuildLoop`, keyed by the nix files listened on.
-    handler_threads: HashMap<NixFile, std::thread::JoinHandle<()>>,
+    /// The build loops, shared with the threads of the running daemon.
+    builds: Arc<Mutex<Builds>>,
+    /// The handlers functions for incoming requests
+    handler_fns: HandlerFns,
+    /// The threads of the running daemon (see `start`).
+    running: Option<Running>,
+    /// Requests to shut down, see `run_until_shutdown`.
+    shutdown_rx: mpsc::Receiver<ShutdownRequest>,
+}
+
+/// A request to shut the daemon down.
+struct ShutdownRequest {
+    /// Cancel the running builds instead of waiting for them.
+    cancel_builds: bool,
+}
+
+/// Asks a daemon in `Daemon::run_until_shutdown` to shut down, for
+/// example from a signal handler.
+#[derive(Clone)]
+pub struct ShutdownHandle(mpsc::Sender<ShutdownRequest>);
+
+impl ShutdownHandle {
+    /// Ask the daemon to shut down. The first request waits for the
+    /// running builds, later ones (or `cancel_builds`) cancel them.
+    pub fn request(&self, cancel_builds: bool) {
+        // the daemon is gone already otherwise
+        let _ = self.0.send(ShutdownRequest { cancel_builds });
+    }
+}
+
+/// How often a shutting down daemon checks whether its builds are done.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often a running daemon counts its open file descriptors.
+const FD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The `BuildLoop`s a daemon controls.
+struct Builds {
+    /// A thread for each `BuildLoop`, keyed by the projects listened on.
+    handler_threads: HashMap<ProjectKey, std::thread::JoinHandle<()>>,
     /// Sending end that we pass to every `BuildLoop` the daemon controls.
     // TODO: this needs to transmit information to identify the builder with
     build_events_tx: mpsc::Sender<::build_loop::Event>,
-    /// The handlers functions for incoming requests
-    handler_fns: HandlerFns,
 }
 
-// TODO: set a `Listener` up in the daemon instead of manually outside
+/// A daemon which was `start`ed.
+struct Running {
+    /// The accept loop and the build instruction handler.
+    pool: Pool,
+    /// The socket the accept loop listens on.
+    socket_path: PathBuf,
+    /// Tells the accept loop to exit.
+    stopping: Arc<AtomicBool>,
+    /// Dropped to stop the file descriptor monitor.
+    stop_fd_monitor: mpsc::Sender<()>,
+}
+
+/// Starting the daemon failed.
+#[derive(Debug)]
+pub enum StartError {
+    /// The daemon is already running.
+    AlreadyRunning,
+    /// Binding the socket failed, maybe another daemon listens on it.
+    Bind(BindError),
+    /// Spawning a thread failed.
+    Spawn(std::io::Error),
+}
 
 impl Daemon {
     /// Create a new daemon. Also return an `mpsc::Receiver` that
@@ -44,13 +144,28 @@ impl Daemon {
     /// supervises.
     pub fn new() -> (Daemon, mpsc::Receiver<::build_loop::Event>) {
         let (tx, rx) = mpsc::channel();
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
         (
             Daemon {
-                handler_threads: HashMap::new(),
-                build_events_tx: tx,
+                builds: Arc::new(Mutex::new(Builds {
+                    handler_threads: HashMap::new(),
+                    build_events_tx: tx,
+                })),
                 handler_fns: HandlerFns {
                     read_timeout: DEFAULT_READ_TIMEOUT,
+                    cancellers: Arc::new(Mutex::new(HashMap::new())),
+                    activities: Arc::new(Mutex::new(HashMap::new())),
+                    build_logs: Arc::new(Mutex::new(HashMap::new())),
+                    events: EventStream::default(),
+                    projects: Arc::new(Mutex::new(HashMap::new())),
+                    fd_monitor: Arc::new(Mutex::new(fds::Monitor::default())),
+                    latency: Arc::new(Mutex::new(LatencyResult::default())),
+                    config: Config::default(),
+                    shells_dir: None,
+                    shutdown: ShutdownHandle(shutdown_tx),
                 },
+                running: None,
+                shutdown_rx,
             },
             rx,
         )
@@ -61,23 +176,479 @@ impl Daemon {
         self.handler_fns.clone()
     }
 
+    /// Set the buffers of the clients listening to build events
+    /// (see `event_stream`). Has to be called before `start` and `add`.
+    pub fn set_event_buffer(&mut self, config: BufferConfig) {
+        self.handler_fns.events = EventStream::new(config);
+    }
+
+    /// Cancel running builds when their inputs change, and start
+    /// over (see `BuildLoop::set_cancel_on_change`). Has to be
+    /// called before `start` and `add`.
+    pub fn set_cancel_on_change(&mut self, cancel_on_change: bool) {
+        self.handler_fns.config.build.cancel_on_change = cancel_on_change;
+    }
+
+    /// Wait for bursts of changes to settle before rebuilding (see
+    /// `BuildLoop::set_debounce`). Has to be called before `start`
+    /// and `add`.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.handler_fns.config.watch.debounce_ms = debounce.as_millis() as u64;
+    }
+
+    /// Apply the global configuration: the event buffers (see
+    /// `set_event_buffer`) and the settings of the build loops (see
+    /// `BuildLoop::configure`). Has to be called before `start` and
+    /// `add`.
+    pub fn set_config(&mut self, config: Config) {
+        self.set_event_buffer(BufferConfig {
+            capacity: config.daemon.event_buffer,
+            slow_listeners: config.daemon.slow_listeners,
+        });
+        self.handler_fns.config = config;
+    }
+
+    /// After a build changed the environment of a project, refresh
+    /// the shells in it which registered in `shells_dir` (see
+    /// `ops::refresh_shells`). Has to be called before `start` and
+    /// `add`.
+    pub fn set_shells_dir(&mut self, shells_dir: PathBuf) {
+        self.handler_fns.shells_dir = Some(shells_dir);
+    }
+
     /// Add nix file to the set of files this daemon watches
     /// & build if they change.
     pub fn add(&mut self, project: Project) {
-        let tx = self.build_events_tx.clone();
+        add(&self.builds, &self.handler_fns, project)
+    }
+
+    /// Listen on `socket_path` and build the projects clients ping,
+    /// with GC roots in `gc_root_dir` and `cas` for lorri’s files.
+    /// Returns once the daemon is ready; it runs in the background
+    /// until `stop` is called.
+    pub fn start(
+        &mut self,
+        socket_path: &Path,
+        gc_root_dir: &Path,
+        cas: ContentAddressable,
+    ) -> Result<(), StartError> {
+        if self.running.is_some() {
+            return Err(StartError::AlreadyRunning);
+        }
+        let listener =
+            listener::Listener::new(&SocketPath::from(socket_path)).map_err(StartError::Bind)?;
+        let stopping = Arc::new(AtomicBool::new(false));
 
-        self.handler_threads
-            .entry(project.nix_file.clone())
-            .or_insert_with(|| {
-                std::thread::spawn(move || {
-                    let mut build_loop = BuildLoop::new(&project);
+        // messages sent from accept handlers
+        let (accept_messages_tx, accept_messages_rx) = mpsc::channel();
 
+        let mut pool = Pool::new();
+        let handlers = self.handlers();
+        let accept_stopping = stopping.clone();
+        pool.spawn("accept-loop", move || {
+            while !accept_stopping.load(Ordering::SeqCst) {
+                let accept_messages_tx = accept_messages_tx.clone();
+                // has to clone handlers once per accept loop,
+                // because accept spawns a thread each time.
+                let handlers = handlers.clone();
+                let handle = listener.accept(move |unix_stream, comm_type| match comm_type {
+                    CommunicationType::Ping => {
+                        handlers.ping(ReadWriter::new(&unix_stream), accept_messages_tx)
+                    }
+                    CommunicationType::PingAttribute => {
+                        handlers.ping_attribute(ReadWriter::new(&unix_stream), accept_messages_tx)
+                    }
+                    CommunicationType::CancelBuild => {
+                        handlers.cancel_build(ReadWriter::new(&unix_stream))
+                    }
+                    CommunicationType::WaitIdle => {
+                        handlers.wait_idle(ReadWriter::new(&unix_stream))
+                    }
+                    CommunicationType::WaitIdleFor => {
+                        handlers.wait_idle_for(ReadWriter::new(&unix_stream))
+                    }
+                    CommunicationType::FollowLog => {
+                        handlers.follow_log(ReadWriter::new(&unix_stream))
+                    }
+                    CommunicationType::StreamEvents => match unix_stream.try_clone() {
+                        Ok(socket) => handlers.stream_events(ReadWriter::new(&unix_stream), socket),
+                        Err(e) => warn!("could not listen to a `StreamEvents` client: {}", e),
+                    },
+                    CommunicationType::Monitor => match unix_stream.try_clone() {
+                        Ok(socket) => handlers.monitor(ReadWriter::new(&unix_stream), socket),
+                        Err(e) => warn!("could not listen to a `Monitor` client: {}", e),
+                    },
+                    CommunicationType::Subscribe => match unix_stream.try_clone() {
+                        Ok(socket) => handlers.subscribe(ReadWriter::new(&unix_stream), socket),
+                        Err(e) => warn!("could not listen to a `Subscribe` client: {}", e),
+                    },
+                    CommunicationType::Resume => match unix_stream.try_clone() {
+                        Ok(socket) => handlers.resume(ReadWriter::new(&unix_stream), socket),
+                        Err(e) => warn!("could not listen to a `Resume` client: {}", e),
+                    },
+                    CommunicationType::ListProjects => {
+                        handlers.list_projects(ReadWriter::new(&unix_stream))
+                    }
+                    CommunicationType::Shutdown => handlers.shutdown(ReadWriter::new(&unix_stream)),
+                    CommunicationType::Status => handlers.status(ReadWriter::new(&unix_stream)),
+                    CommunicationType::Stats => handlers.stats(ReadWriter::new(&unix_stream)),
+                    CommunicationType::Rebuild => {
+                        handlers.rebuild(ReadWriter::new(&unix_stream), accept_messages_tx)
+                    }
+                    CommunicationType::Latency => handlers.latency(ReadWriter::new(&unix_stream)),
+                    CommunicationType::RequestBuild => {
+                        handlers.request_build(ReadWriter::new(&unix_stream), accept_messages_tx)
+                    }
+                    // rejected by `accept()` already
+                    CommunicationType::Unknown => {
+                        warn!("a newer lorri client connected, ignoring its request")
+                    }
+                });
+                match handle {
+                    Err(listener::AcceptError::UnknownCommunicationType) => {
+                        warn!("a newer lorri client connected, ignoring its request")
+                    }
+                    // TODO
+                    other => {
+                        other.unwrap();
+                    }
+                }
+            }
+        })
+        .map_err(StartError::Spawn)?;
+
+        let builds = self.builds.clone();
+        let handler_fns = self.handlers();
+        let gc_root_dir = gc_root_dir.to_owned();
+        pool.spawn("build-instruction-handler", move || {
+            // For each build instruction, add the corresponding file
+            // to the watch list. Ends once the accept loop (and all
+            // the handlers it started) are done.
+            for start_build in accept_messages_rx {
+                let key = (start_build.nix_file.clone(), start_build.attribute.clone());
+                let registered = match Project::with_attribute(
+                    NixSource::File(start_build.nix_file),
+                    start_build.attribute,
+                    &gc_root_dir,
+                    cas.clone(),
+                ) {
+                    Ok(project) => {
+                        add(&builds, &handler_fns, project);
+                        Ok(())
+                    }
+                    Err(e) => {
+                        warn!("could not set up the project of {}: {}", key.0, e);
+                        handler_fns.forget_activity(&key);
+                        Err(RegistrationError::Setup(e.to_string()))
+                    }
+                };
+                // the client may have given up waiting already
+                let _ = start_build.registered.send(registered);
+            }
+        })
+        .map_err(StartError::Spawn)?;
+
+        let (stop_fd_monitor, stop_fd_monitor_rx) = mpsc::channel();
+        let handler_fns = self.handlers();
+        pool.spawn("fd-monitor", move || {
+            // until `stop_listening` drops the sender
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stop_fd_monitor_rx.recv_timeout(FD_CHECK_INTERVAL)
+            {
+                handler_fns.check_fds();
+            }
+        })
+        .map_err(StartError::Spawn)?;
+
+        self.running = Some(Running {
+            pool,
+            socket_path: socket_path.to_owned(),
+            stopping,
+            stop_fd_monitor,
+        });
+        Ok(())
+    }
+
+    /// A handle to ask the daemon to shut down, see `run_until_shutdown`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.handler_fns.shutdown.clone()
+    }
+
+    /// Block until the daemon is asked to shut down, with a
+    /// `ShutdownHandle` or a `Shutdown` message. Then stop listening
+    /// (which removes the socket), wait until no builds are pending
+    /// or running, and `stop`. Another request while waiting (or
+    /// one asking for it) cancels the builds instead.
+    pub fn run_until_shutdown(&mut self) {
+        let mut cancel_builds = match self.shutdown_rx.recv() {
+            Ok(request) => request.cancel_builds,
+            // we keep a handle ourselves
+            Err(mpsc::RecvError) => unreachable!("shutdown handles dropped"),
+        };
+        self.stop_listening();
+        let activities: Vec<Activity> = self
+            .handler_fns
+            .activities
+            .lock()
+            .expect("activities lock poisoned")
+            .values()
+            .cloned()
+            .collect();
+        if !cancel_builds && !activities.iter().all(Activity::is_idle) {
+            info!("shutting down once the running builds are done; ask again to cancel them");
+        }
+        while !cancel_builds && !activities.iter().all(Activity::is_idle) {
+            if self
+                .shutdown_rx
+                .recv_timeout(SHUTDOWN_POLL_INTERVAL)
+                .is_ok()
+            {
+                cancel_builds = true;
+            }
+        }
+        if cancel_builds {
+            info!("shutting down, cancelling the running builds");
+        }
+        self.stop();
+    }
+
+    /// Stop the accept loop of a `start`ed daemon, and with it
+    /// listening on the socket.
+    fn stop_listening(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.stopping.store(true, Ordering::SeqCst);
+            // wake up the accept loop with a connection
+            // which doesn’t ask for anything
+            if let Err(e) =
+                client::ping(DEFAULT_READ_TIMEOUT).connect(&SocketPath::from(&running.socket_path))
+            {
+                warn!("could not wake up the accept loop: {:?}", e);
+            }
+            drop(running.stop_fd_monitor);
+            running.pool.join_all_or_panic();
+        }
+    }
+
+    /// Stop a `start`ed daemon: stop listening on the socket, cancel
+    /// the running builds and stop all build loops. Build loops exit
+    /// in the background, once they notice (see `BuildLoop::forever`).
+    /// Started again, the daemon starts new build loops.
+    pub fn stop(&mut self) {
+        self.stop_listening();
+        let mut cancellers = self
+            .handler_fns
+            .cancellers
+            .lock()
+            .expect("cancellers lock poisoned");
+        for (_, canceller) in cancellers.drain() {
+            canceller.stop();
+        }
+        self.builds
+            .lock()
+            .expect("builds lock poisoned")
+            .handler_threads
+            .clear();
+    }
+}
+
+/// Start a `BuildLoop` for `project`, unless `builds` has one already.
+fn add(builds: &Mutex<Builds>, handler_fns: &HandlerFns, project: Project) {
+    // clients name projects by their nix file
+    let nix_file = match project.source.nix_file() {
+        Some(nix_file) => nix_file,
+        None => {
+            warn!(
+                "the daemon only builds nix files and local flakes, not {}",
+                project.source
+            );
+            return;
+        }
+    };
+    let key = (nix_file.clone(), project.attribute().map(String::from));
+    let mut builds = builds.lock().expect("builds lock poisoned");
+    let tx = builds.build_events_tx.clone();
+    let canceller = handler_fns.canceller(&key);
+    let activity = handler_fns.activity(&key);
+    let build_log = handler_fns.build_log(&key);
+    let events = handler_fns.events.clone();
+    let projects = handler_fns.projects.clone();
+    let latency = handler_fns.latency.clone();
+    let config = handler_fns.config.clone();
+    let shells_dir = handler_fns.shells_dir.clone();
+
+    builds
+        .handler_threads
+        .entry(key.clone())
+        .or_insert_with(|| {
+            let source = project.source.clone();
+            let config_root = project.config_root().to_owned();
+            let (loop_tx, loop_rx) = mpsc::channel();
+            let global_hooks = config.hooks.clone();
+            let mut notifier = if config.notify.enabled {
+                Some(Notifier::new(&config.notify))
+            } else {
+                None
+            };
+            projects.lock().expect("projects lock poisoned").insert(
+                key.clone(),
+                ProjectStatus {
+                    project: WatchedProject {
+                        nix_file: nix_file.clone(),
+                        state: BuildState::Pending,
+                        last_build: None,
+                        gc_root: PathBuf::from(
+                            Roots::from_project(&project)
+                                .paths()
+                                .shell_gc_root
+                                .as_os_str(),
+                        ),
+                    },
+                    build_started: None,
+                    build_duration: None,
+                    output_paths: vec![],
+                    watched_files: 0,
+                },
+            );
+            let handle = std::thread::spawn(move || {
+                // one broken project mustn’t stop the others
+                let clock = Arc::new(SystemClock);
+                build_loop::supervise(&project, canceller, clock, loop_tx, |build_loop| {
+                    build_loop.set_activity(activity.clone());
+                    build_loop.set_build_log(build_log.clone());
+                    build_loop.configure(&config);
+                    build_loop.set_detach_builds(true);
+                });
+            });
+            let sink_nix_file = nix_file.clone();
+            let sink_key = key.clone();
+            std::thread::spawn(move || {
+                // the sinks and hooks are reloaded with every build,
+                // like the rest of the project configuration
+                let mut sinks = vec![];
+                let mut hooks = HooksConfig::default();
+                for event in loop_rx {
+                    if let build_loop::Event::Started(_, _, change_latency) = event {
+                        match ProjectConfig::load(&config_root) {
+                            Ok(project_config) => {
+                                sinks = project_config.event_sinks;
+                                hooks = project_config.hooks;
+                            }
+                            // the build reports the error, too
+                            Err(e) => warn!(
+                                "keeping the previous event sinks and hooks of {}: {}",
+                                config_root.display(),
+                                e
+                            ),
+                        }
+                        if let Some(change_latency) = change_latency {
+                            latency
+                                .lock()
+                                .expect("latency lock poisoned")
+                                .record(change_latency);
+                        }
+                    }
+                    event_sink::mirror(&sinks, &config_root, &source, &event);
+                    hooks::run(&hooks, &global_hooks, &config_root, &source, &event);
+                    if let Some(ref mut notifier) = notifier {
+                        notifier.observe(&source, &event);
+                    }
+                    if let (Some(shells_dir), build_loop::Event::EnvironmentSwitched(..)) =
+                        (&shells_dir, &event)
+                    {
+                        refresh_shells(shells_dir, &config_root);
+                    }
+                    events.publish(&sink_nix_file, &event);
+                    if let Some(project) = projects
+                        .lock()
+                        .expect("projects lock poisoned")
+                        .get_mut(&sink_key)
+                    {
+                        update_state(project, &event);
+                    }
                     // cloning the tx means the daemon’s rx gets all
                     // messages from all builders.
-                    build_loop.forever(tx);
-                })
+                    if tx.send(event).is_err() {
+                        break;
+                    }
+                }
             });
+            handle
+        });
+}
+
+/// Ask the shells registered in `shells_dir` which are in the
+/// project at `config_root` to reload their environment.
+fn refresh_shells(shells_dir: &Path, config_root: &Path) {
+    let refreshed = config_root
+        .canonicalize()
+        .and_then(|dir| refresh_shells::refresh(shells_dir, Some(&dir)));
+    match refreshed {
+        Ok(ref shells) if shells.is_empty() => {}
+        Ok(shells) => info!(
+            "refreshed {} shells in {}",
+            shells.len(),
+            config_root.display()
+        ),
+        Err(e) => warn!(
+            "could not refresh the shells in {}: {}",
+            config_root.display(),
+            e
+        ),
+    }
+}
+
+/// Record the outcome of `event` in the state of `project`.
+fn update_state(status: &mut ProjectStatus, event: &build_loop::Event) {
+    let state = match event {
+        build_loop::Event::Started(..) => BuildState::Building,
+        build_loop::Event::Completed(..) => BuildState::Success,
+        build_loop::Event::Failure(..) => BuildState::Failure,
+        build_loop::Event::Cancelled(_) => BuildState::Cancelled,
+        _ => return,
+    };
+    let now = event.time().unwrap_or_else(SystemTime::now);
+    let millis = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_millis() as u64)
+    };
+    if state == BuildState::Building {
+        status.build_started = millis(now);
+        status.build_duration = None;
+    } else {
+        status.project.last_build = millis(now).map(|millis| millis / 1000);
+        status.build_duration = match (status.build_started, millis(now)) {
+            (Some(started), Some(ended)) => Some(ended.saturating_sub(started)),
+            _ => None,
+        };
+    }
+    if let build_loop::Event::Completed(_, _, results) = event {
+        let roots = &results.output_paths;
+        status.output_paths = std::iter::once(&roots.shell_gc_root)
+            .chain(roots.shells.values())
+            .map(|root| PathBuf::from(root.as_os_str()))
+            .collect();
+        status.watched_files = results.watched_files as u64;
+    }
+    status.project.state = state;
+}
+
+/// Check that the daemon can watch and build `nix_file`.
+fn check_nix_file(nix_file: &NixFile) -> Result<(), RegistrationError> {
+    let path = Path::new(nix_file.as_os_str());
+    if !path.is_absolute() {
+        return Err(RegistrationError::RelativePath);
     }
+    let io_error = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::NotFound => RegistrationError::NotFound,
+        std::io::ErrorKind::PermissionDenied => RegistrationError::PermissionDenied,
+        _ => RegistrationError::Io(e.to_string()),
+    };
+    if !std::fs::metadata(path).map_err(io_error)?.is_file() {
+        return Err(RegistrationError::NotAFile);
+    }
+    std::fs::File::open(path).map_err(io_error)?;
+    Ok(())
 }
 
 /// Holds handler functions the daemon uses to react to messages.
@@ -85,9 +656,131 @@ impl Daemon {
 pub struct HandlerFns {
     /// How long the daemon waits for messages to arrive after accept()
     read_timeout: Timeout,
+    /// Cancel the build of a watched project, or request one.
+    /// Registered like `activities`, so that a build can be
+    /// requested before the build loop starts.
+    cancellers: Arc<Mutex<HashMap<ProjectKey, Canceller>>>,
+    /// Whether builds of a project are pending or running.
+    /// Registered as soon as the project is pinged, so waiting
+    /// for idleness right after a ping includes its build.
+    activities: Arc<Mutex<HashMap<ProjectKey, Activity>>>,
+    /// The output of the builds of a project, registered like
+    /// `activities`.
+    build_logs: Arc<Mutex<HashMap<ProjectKey, BuildLog>>>,
+    /// The clients listening to the events of all build loops.
+    events: EventStream,
+    /// The watched projects and the state of their builds.
+    projects: Arc<Mutex<HashMap<ProjectKey, ProjectStatus>>>,
+    /// Warns when the daemon runs low on file descriptors.
+    fd_monitor: Arc<Mutex<fds::Monitor>>,
+    /// How long builds took to start after a change, over all
+    /// projects.
+    latency: Arc<Mutex<LatencyResult>>,
+    /// The settings of the build loops (see `BuildLoop::configure`).
+    config: Config,
+    /// Where shells register to be refreshed, see `set_shells_dir`.
+    shells_dir: Option<PathBuf>,
+    /// Asks the daemon to shut down.
+    shutdown: ShutdownHandle,
 }
 
+/// How often a `FollowLog` or `StreamEvents` handler waiting for
+/// the next message
+/// checks whether its client hung up.
+const HANG_UP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 impl HandlerFns {
+    /// The activity of `key`, registered as busy if it is new.
+    fn activity(&self, key: &ProjectKey) -> Activity {
+        self.activities
+            .lock()
+            .expect("activities lock poisoned")
+            .entry(key.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// The canceller of the build loop of `key`, registered if it
+    /// is new.
+    fn canceller(&self, key: &ProjectKey) -> Canceller {
+        self.cancellers
+            .lock()
+            .expect("cancellers lock poisoned")
+            .entry(key.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Ask the build loop of the project of `nix_file` (without an
+    /// attribute) for a build, or start one (which builds it) like a
+    /// ping. Returns the id of the build.
+    fn ask_for_build(
+        &self,
+        nix_file: &NixFile,
+        build_chan: &mpsc::Sender<IndicateActivity>,
+    ) -> Result<BuildId, RegistrationError> {
+        let key = (nix_file.clone(), None);
+        let watched = self
+            .projects
+            .lock()
+            .expect("projects lock poisoned")
+            .contains_key(&key);
+        // the first build of a new loop takes the request, too
+        let build = self.canceller(&key).request_build();
+        if !watched {
+            self.indicate_activity(key, build_chan)?;
+        }
+        Ok(build)
+    }
+
+    /// Register the project `key` and ask for its build loop to be
+    /// started (which builds it), unless it runs already. Returns
+    /// once the project is set up.
+    fn indicate_activity(
+        &self,
+        key: ProjectKey,
+        build_chan: &mpsc::Sender<IndicateActivity>,
+    ) -> Result<(), RegistrationError> {
+        self.activity(&key);
+        self.build_log(&key);
+        let (nix_file, attribute) = key;
+        let (registered_tx, registered_rx) = mpsc::channel();
+        let activity = IndicateActivity {
+            nix_file: nix_file.clone(),
+            attribute,
+            registered: registered_tx,
+        };
+        if build_chan.send(activity).is_err() {
+            info!("not building {}, the daemon is stopping", nix_file);
+            return Ok(());
+        }
+        // no answer if the daemon stopped before setting it up
+        registered_rx.recv().unwrap_or(Ok(()))
+    }
+
+    /// Unregister the activity of the project `key`, whose build
+    /// loop never started, and release those waiting for it.
+    fn forget_activity(&self, key: &ProjectKey) {
+        let activity = self
+            .activities
+            .lock()
+            .expect("activities lock poisoned")
+            .remove(key);
+        if let Some(activity) = activity {
+            activity.set_idle();
+        }
+    }
+
+    /// The build log of `key`, registered if it is new.
+    fn build_log(&self, key: &ProjectKey) -> BuildLog {
+        self.build_logs
+            .lock()
+            .expect("build logs lock poisoned")
+            .entry(key.clone())
+            .or_default()
+            .clone()
+    }
+
     /// Accept handler for `socket::communicate::Ping` messages.
     /// For a valid ping message, it sends an instruction to start
     /// the build to `build_chan`.
@@ -95,25 +788,468 @@ impl HandlerFns {
     // the ReadWriter here has to be the inverse of the `Client.ping()`, which is `ReadWriter<!, Ping>`
     pub fn ping(
         &self,
-        rw: ReadWriter<Ping, NoMessage>,
+        mut rw: ReadWriter<Ping, PingResult>,
         build_chan: mpsc::Sender<IndicateActivity>,
     ) {
         let ping: Result<Ping, ReadError> = rw.read(&self.read_timeout);
-        match ping {
-            Err(ReadError::Timeout) => debug!(
-                "Client didn’t send a `Ping` message after waiting for {}",
-                &self.read_timeout
-            ),
+        let p = match ping {
+            Err(ReadError::Timeout) => {
+                debug!(
+                    "Client didn’t send a `Ping` message after waiting for {}",
+                    &self.read_timeout
+                );
+                return;
+            }
             Err(ReadError::Deserialize(e)) => {
-                debug!("Client `Ping` message could not be decoded: {}", e)
-            }
-            Ok(p) => {
-                info!("pinged with {}", p.nix_file);
-                build_chan
-                    .send(IndicateActivity {
-                        nix_file: p.nix_file,
-                    })
-                    .expect("StartBuild channel closed")
+                debug!("Client `Ping` message could not be decoded: {}", e);
+                return;
+            }
+            Ok(p) => p,
+        };
+        let registered = check_nix_file(&p.nix_file).and_then(|()| {
+            info!("pinged with {}", p.nix_file);
+            self.indicate_activity((p.nix_file.clone(), None), &build_chan)
+        });
+        let result = match registered {
+            Ok(()) => PingResult::Registered,
+            Err(e) => {
+                warn!("pinged with {}, but {}", p.nix_file, e);
+                PingResult::Refused(e)
+            }
+        };
+        // clients which only send the ping have hung up already
+        if let Err(e) = rw.write(&self.read_timeout, &result) {
+            debug!("Could not answer a `Ping` message: {:?}", e)
+        }
+    }
+
+    /// Accept handler for `socket::communicate::PingAttribute`
+    /// messages. Like `ping`, for the project of the attribute.
+    pub fn ping_attribute(
+        &self,
+        mut rw: ReadWriter<PingAttribute, PingResult>,
+        build_chan: mpsc::Sender<IndicateActivity>,
+    ) {
+        let request = rw.react(self.read_timeout.clone(), |p| {
+            let checked = check_nix_file(&p.nix_file).and_then(|()| {
+                nix::attribute_path(&p.attribute).map_err(RegistrationError::InvalidAttribute)
+            });
+            let registered = checked.and_then(|_| {
+                info!("pinged with {} (attribute {})", p.nix_file, p.attribute);
+                let key = (p.nix_file.clone(), Some(p.attribute.clone()));
+                self.indicate_activity(key, &build_chan)
+            });
+            match registered {
+                Ok(()) => PingResult::Registered,
+                Err(e) => {
+                    warn!(
+                        "pinged with {} (attribute {}), but {}",
+                        p.nix_file, p.attribute, e
+                    );
+                    PingResult::Refused(e)
+                }
+            }
+        });
+        if let Err(e) = request {
+            debug!("Could not answer a `PingAttribute` message: {:?}", e)
+        }
+    }
+
+    /// Accept handler for `socket::communicate::Rebuild` messages,
+    /// from clients before `RequestBuild`.
+    /// Asks the build loop of the nix file for a build, or starts
+    /// one (which builds it) like a ping.
+    pub fn rebuild(
+        &self,
+        mut rw: ReadWriter<Rebuild, PingResult>,
+        build_chan: mpsc::Sender<IndicateActivity>,
+    ) {
+        let request = rw.react(self.read_timeout.clone(), |request| {
+            if let Err(e) = check_nix_file(&request.nix_file) {
+                warn!("asked to build {}, but {}", request.nix_file, e);
+                return PingResult::Refused(e);
+            }
+            info!("asked to build {}", request.nix_file);
+            match self.ask_for_build(&request.nix_file, &build_chan) {
+                Ok(_) => PingResult::Registered,
+                Err(e) => {
+                    warn!("asked to build {}, but {}", request.nix_file, e);
+                    PingResult::Refused(e)
+                }
+            }
+        });
+        if let Err(e) = request {
+            debug!("Could not answer a `Rebuild` message: {:?}", e)
+        }
+    }
+
+    /// Accept handler for `socket::communicate::RequestBuild`
+    /// messages. Like `rebuild`, and answers with the id of the
+    /// build.
+    pub fn request_build(
+        &self,
+        mut rw: ReadWriter<RequestBuild, RequestBuildResult>,
+        build_chan: mpsc::Sender<IndicateActivity>,
+    ) {
+        let request = rw.react(self.read_timeout.clone(), |request| {
+            if let Err(e) = check_nix_file(&request.nix_file) {
+                warn!("asked to build {}, but {}", request.nix_file, e);
+                return RequestBuildResult::Refused(e);
+            }
+            info!("asked to build {}", request.nix_file);
+            match self.ask_for_build(&request.nix_file, &build_chan) {
+                Ok(build) => RequestBuildResult::Requested {
+                    build_id: build.as_u64(),
+                },
+                Err(e) => {
+                    warn!("asked to build {}, but {}", request.nix_file, e);
+                    RequestBuildResult::Refused(e)
+                }
+            }
+        });
+        if let Err(e) = request {
+            debug!("Could not answer a `RequestBuild` message: {:?}", e)
+        }
+    }
+
+    /// Accept handler for `socket::communicate::CancelBuild` messages.
+    /// Cancels the running build of the nix file, if any, and answers
+    /// whether there was one.
+    pub fn cancel_build(&self, mut rw: ReadWriter<CancelBuild, CancelBuildResult>) {
+        let cancellers = self.cancellers.clone();
+        let request = rw.react(self.read_timeout.clone(), |request| {
+            let cancelled = cancellers
+                .lock()
+                .expect("cancellers lock poisoned")
+                .get(&(request.nix_file.clone(), None))
+                .map(Canceller::cancel)
+                .unwrap_or(false);
+            CancelBuildResult { cancelled }
+        });
+        match request {
+            Err(e) => debug!("Could not answer a `CancelBuild` message: {:?}", e),
+            Ok(request) => info!("asked to cancel the build of {}", request.nix_file),
+        }
+    }
+
+    /// Accept handler for `socket::communicate::WaitIdle` messages.
+    /// Answers once no builds (of the projects of the requested nix
+    /// file, with any attribute, or of all projects) are pending or
+    /// running.
+    pub fn wait_idle(&self, mut rw: ReadWriter<WaitIdle, WaitIdleResult>) {
+        let request = rw.react(self.read_timeout.clone(), |request| {
+            let watched = self.activities_of(&request.nix_file);
+            if request.nix_file.is_some() && watched.is_empty() {
+                return WaitIdleResult { watched: false };
+            }
+            // a build loop may start building again while we wait
+            // for another one, so wait until all are idle at once
+            while !watched.iter().all(Activity::is_idle) {
+                watched.iter().for_each(Activity::wait_idle);
+            }
+            WaitIdleResult { watched: true }
+        });
+        if let Err(e) = request {
+            debug!("Could not answer a `WaitIdle` message: {:?}", e)
+        }
+    }
+
+    /// Accept handler for `socket::communicate::WaitIdleFor`
+    /// messages. Like `wait_idle`, answering after `max_wait_ms` at
+    /// the latest, so that clients which gave up don’t leave the
+    /// handler waiting.
+    pub fn wait_idle_for(&self, mut rw: ReadWriter<WaitIdleFor, WaitIdleForResult>) {
+        let request = rw.react(self.read_timeout.clone(), |request| {
+            let deadline = Instant::now() + Duration::from_millis(request.max_wait_ms);
+            let watched = self.activities_of(&request.nix_file);
+            if request.nix_file.is_some() && watched.is_empty() {
+                return WaitIdleForResult {
+                    watched: false,
+                    idle: true,
+                };
+            }
+            // like `wait_idle`, until all are idle at once
+            while !watched.iter().all(Activity::is_idle) {
+                for activity in &watched {
+                    let now = Instant::now();
+                    if now >= deadline || !activity.wait_idle_timeout(deadline - now) {
+                        return WaitIdleForResult {
+                            watched: true,
+                            idle: false,
+                        };
+                    }
+                }
+            }
+            WaitIdleForResult {
+                watched: true,
+                idle: true,
+            }
+        });
+        if let Err(e) = request {
+            debug!("Could not answer a `WaitIdleFor` message: {:?}", e)
+        }
+    }
+
+    /// The activities of the projects of `nix_file` (with any
+    /// attribute), or of all projects.
+    fn activities_of(&self, nix_file: &Option<NixFile>) -> Vec<Activity> {
+        self.activities
+            .lock()
+            .expect("activities lock poisoned")
+            .iter()
+            .filter(|((watched, _), _)| match nix_file {
+                None => true,
+                Some(ref requested) => watched == requested,
+            })
+            .map(|(_, activity)| activity.clone())
+            .collect()
+    }
+
+    /// Accept handler for `socket::communicate::Shutdown` messages.
+    /// Answers with the number of running builds, then asks the
+    /// daemon to shut down.
+    pub fn shutdown(&self, mut rw: ReadWriter<Shutdown, ShutdownResult>) {
+        let activities = self.activities.clone();
+        let request = rw.react(self.read_timeout.clone(), |_| {
+            let running_builds = activities
+                .lock()
+                .expect("activities lock poisoned")
+                .values()
+                .filter(|activity| !activity.is_idle())
+                .count() as u64;
+            ShutdownResult { running_builds }
+        });
+        match request {
+            Ok(request) => self.shutdown.request(request.cancel_builds),
+            Err(e) => debug!("Could not answer a `Shutdown` message: {:?}", e),
+        }
+    }
+
+    /// Accept handler for `socket::communicate::ListProjects` messages.
+    /// Answers with the watched projects and the state of their builds.
+    pub fn list_projects(&self, mut rw: ReadWriter<ListProjects, ListProjectsResult>) {
+        let projects = self.projects.clone();
+        let request = rw.react(self.read_timeout.clone(), |_| {
+            let mut projects: Vec<WatchedProject> = projects
+                .lock()
+                .expect("projects lock poisoned")
+                .values()
+                .map(|status| status.project.clone())
+                .collect();
+            // the projects of the attributes of a nix file differ by root
+            projects.sort_by(|a, b| {
+                (a.nix_file.as_os_str(), &a.gc_root).cmp(&(b.nix_file.as_os_str(), &b.gc_root))
+            });
+            ListProjectsResult { projects }
+        });
+        if let Err(e) = request {
+            debug!("Could not answer a `ListProjects` message: {:?}", e)
+        }
+    }
+
+    /// Accept handler for `socket::communicate::Status` messages.
+    /// Answers with the state of the project and its last build.
+    pub fn status(&self, mut rw: ReadWriter<Status, StatusResult>) {
+        let projects = self.projects.clone();
+        let request = rw.react(self.read_timeout.clone(), |request| StatusResult {
+            project: projects
+                .lock()
+                .expect("projects lock poisoned")
+                .get(&(request.nix_file.clone(), None))
+                .cloned(),
+        });
+        if let Err(e) = request {
+            debug!("Could not answer a `Status` message: {:?}", e)
+        }
+    }
+
+    /// Count the open file descriptors, warning if they are about
+    /// to run out (see `fds::Monitor`).
+    fn check_fds(&self) -> Option<fds::Usage> {
+        match fds::usage() {
+            Ok(usage) => {
+                self.fd_monitor
+                    .lock()
+                    .expect("fd monitor lock poisoned")
+                    .observe(usage);
+                Some(usage)
+            }
+            Err(e) => {
+                debug!("could not count the open file descriptors: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Accept handler for `socket::communicate::Stats` messages.
+    /// Answers with the number of projects and file descriptors.
+    pub fn stats(&self, mut rw: ReadWriter<Stats, StatsResult>) {
+        let request = rw.react(self.read_timeout.clone(), |_| {
+            let open_fds = self.check_fds().map_or(0, |usage| usage.open);
+            let limits = fds::limits().unwrap_or(fds::Limits {
+                soft: None,
+                hard: None,
+            });
+            StatsResult {
+                projects: self.projects.lock().expect("projects lock poisoned").len() as u64,
+                open_fds,
+                peak_open_fds: self
+                    .fd_monitor
+                    .lock()
+                    .expect("fd monitor lock poisoned")
+                    .peak(),
+                fd_soft_limit: limits.soft,
+                fd_hard_limit: limits.hard,
+            }
+        });
+        if let Err(e) = request {
+            debug!("Could not answer a `Stats` message: {:?}", e)
+        }
+    }
+
+    /// Accept handler for `socket::communicate::Latency` messages.
+    pub fn latency(&self, mut rw: ReadWriter<Latency, LatencyResult>) {
+        let request = rw.react(self.read_timeout.clone(), |_| {
+            self.latency.lock().expect("latency lock poisoned").clone()
+        });
+        if let Err(e) = request {
+            debug!("Could not answer a `Latency` message: {:?}", e)
+        }
+    }
+
+    /// Accept handler for `socket::communicate::FollowLog` messages.
+    /// Sends the log of the current (or most recent) build of the
+    /// nix file as it is written. A client which doesn’t read
+    /// blocks its handler, but not the build.
+    pub fn follow_log(&self, mut rw: ReadWriter<FollowLog, LogMessage>) {
+        let request = match rw.read(&self.read_timeout) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Client `FollowLog` message could not be read: {:?}", e);
+                return;
+            }
+        };
+        let build_log = self
+            .build_logs
+            .lock()
+            .expect("build logs lock poisoned")
+            .get(&(request.nix_file.clone(), None))
+            .cloned();
+        let mut cursor = match build_log {
+            Some(build_log) => build_log.cursor(),
+            None => {
+                if let Err(e) = rw.write(&self.read_timeout, &LogMessage::NotWatched) {
+                    debug!("Could not answer a `FollowLog` message: {:?}", e)
+                }
+                return;
+            }
+        };
+        loop {
+            let entry = match cursor.next_timeout(HANG_UP_CHECK_INTERVAL) {
+                Some(entry) => entry,
+                None if rw.hung_up() => return,
+                None => continue,
+            };
+            let last = entry == LogEntry::Finished && !request.follow;
+            let message = match entry {
+                LogEntry::Started => LogMessage::Started,
+                LogEntry::Line(line) => LogMessage::Line(line),
+                LogEntry::Finished => LogMessage::Finished,
+            };
+            if let Err(e) = rw.write(&Timeout::Infinite, &message) {
+                debug!("Stopped following a log: {:?}", e);
+                return;
+            }
+            if last {
+                return;
+            }
+        }
+    }
+
+    /// Accept handler for `socket::communicate::StreamEvents` messages.
+    /// Sends the events of the build loops until the client hangs
+    /// up. A client which doesn’t read blocks its handler, while
+    /// its events are buffered; once the buffer is full, events are
+    /// dropped or the client is disconnected (see `event_stream`).
+    pub fn stream_events(&self, rw: ReadWriter<StreamEvents, EventMessage>, socket: UnixStream) {
+        match rw.read(&self.read_timeout) {
+            Ok(request) => self.send_events(rw, request.nix_file, None, false, socket),
+            Err(e) => debug!("Client `StreamEvents` message could not be read: {:?}", e),
+        }
+    }
+
+    /// Accept handler for `socket::communicate::Monitor` messages.
+    pub fn monitor(&self, rw: ReadWriter<Monitor, EventMessage>, socket: UnixStream) {
+        match rw.read(&self.read_timeout) {
+            Ok(request) => {
+                let since = request.since.map(|sequence| Since {
+                    sequence,
+                    epoch: None,
+                });
+                self.send_events(rw, request.nix_file, since, false, socket)
+            }
+            Err(e) => debug!("Client `Monitor` message could not be read: {:?}", e),
+        }
+    }
+
+    /// Accept handler for `socket::communicate::Subscribe` messages.
+    pub fn subscribe(&self, rw: ReadWriter<Subscribe, EventMessage>, socket: UnixStream) {
+        match rw.read(&self.read_timeout) {
+            Ok(request) => {
+                let since = request.since.map(|sequence| Since {
+                    sequence,
+                    epoch: None,
+                });
+                self.send_events(rw, request.nix_file, since, request.log_lines, socket)
+            }
+            Err(e) => debug!("Client `Subscribe` message could not be read: {:?}", e),
+        }
+    }
+
+    /// Accept handler for `socket::communicate::Resume` messages.
+    pub fn resume(&self, rw: ReadWriter<Resume, EventMessage>, socket: UnixStream) {
+        match rw.read(&self.read_timeout) {
+            Ok(request) => {
+                let since = request.since.map(|sequence| Since {
+                    sequence,
+                    epoch: request.epoch,
+                });
+                self.send_events(rw, request.nix_file, since, request.log_lines, socket)
+            }
+            Err(e) => debug!("Client `Resume` message could not be read: {:?}", e),
+        }
+    }
+
+    /// Send the events of `nix_file` (or of all nix files) after
+    /// `since` (and the log lines, with `log_lines`) to a client,
+    /// until it hangs up. `socket` is shut down
+    /// if the client reads too slowly.
+    fn send_events<R>(
+        &self,
+        mut rw: ReadWriter<R, EventMessage>,
+        nix_file: Option<NixFile>,
+        since: Option<Since>,
+        log_lines: bool,
+        socket: UnixStream,
+    ) {
+        let mut subscription = self.events.subscribe(nix_file, since, log_lines, move || {
+            info!("disconnecting a slow event listener");
+            // unblocks the handler if it is stuck writing
+            if let Err(e) = socket.shutdown(net::Shutdown::Both) {
+                debug!("Could not disconnect an event listener: {}", e);
+            }
+        });
+        loop {
+            let message = match subscription.next_timeout(HANG_UP_CHECK_INTERVAL) {
+                Some(Streamed::Event(line)) => EventMessage::Event(line),
+                Some(Streamed::Gap(dropped)) => EventMessage::Gap { dropped },
+                Some(Streamed::Disconnected) => return,
+                None if rw.hung_up() => return,
+                None => continue,
+            };
+            if let Err(e) = rw.write(&Timeout::Infinite, &message) {
+                debug!("Stopped streaming events: {:?}", e);
+                return;
             }
         }
     }
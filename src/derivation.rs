@@ -0,0 +1,193 @@
+//! Read the inputs of `.drv` files, which nix writes as ATerms:
+//!
+//! ```text
+//! Derive([outputs],[(input drv,[outputs])],[input sources],"system","builder",[args],[env])
+//! ```
+//!
+//! Two derivations with the same inputs only differ in their
+//! builder, arguments or environment, so building one after the
+//! other doesn’t build or fetch any dependencies (see
+//! `build_loop::Rebuild`).
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+/// The inputs of a derivation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inputs {
+    /// The derivations it depends on, with the outputs it uses.
+    pub drvs: BTreeMap<String, Vec<String>>,
+    /// The store paths it depends on which are not built, like
+    /// sources.
+    pub srcs: Vec<String>,
+}
+
+/// Read the inputs of the derivation `drv`.
+pub fn read_inputs(drv: &Path) -> io::Result<Inputs> {
+    let contents = std::fs::read_to_string(drv)?;
+    parse_inputs(&contents).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a derivation", drv.display()),
+        )
+    })
+}
+
+/// The inputs of the derivation `aterm`, the contents of a `.drv`
+/// file.
+pub fn parse_inputs(aterm: &str) -> Option<Inputs> {
+    if !aterm.starts_with("Derive") {
+        return None;
+    }
+    let mut parser = Parser(&aterm["Derive".len()..]);
+    let fields = match parser.term()? {
+        Term::Tuple(fields) => fields,
+        _ => return None,
+    };
+    if !parser.0.trim_end().is_empty() {
+        return None;
+    }
+    let mut fields = fields.into_iter().skip(1);
+    let drvs = match fields.next()? {
+        Term::List(drvs) => drvs
+            .into_iter()
+            .map(|drv| match drv {
+                Term::Tuple(pair) => {
+                    let mut pair = pair.into_iter();
+                    match (pair.next(), pair.next(), pair.next()) {
+                        (Some(Term::Str(path)), Some(outputs), None) => {
+                            Some((path, outputs.into_strings()?))
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    let srcs = fields.next()?.into_strings()?;
+    Some(Inputs { drvs, srcs })
+}
+
+/// A value of an ATerm.
+#[derive(Debug)]
+enum Term {
+    Str(String),
+    List(Vec<Term>),
+    Tuple(Vec<Term>),
+}
+
+impl Term {
+    /// The strings of a list of strings.
+    fn into_strings(self) -> Option<Vec<String>> {
+        match self {
+            Term::List(terms) => terms
+                .into_iter()
+                .map(|term| match term {
+                    Term::Str(s) => Some(s),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    }
+}
+
+/// Parses terms off the front of the rest of the input.
+struct Parser<'a>(&'a str);
+
+impl<'a> Parser<'a> {
+    fn term(&mut self) -> Option<Term> {
+        match self.next()? {
+            '"' => self.string().map(Term::Str),
+            '[' => self.sequence(']').map(Term::List),
+            '(' => self.sequence(')').map(Term::Tuple),
+            _ => None,
+        }
+    }
+
+    /// The comma separated terms up to `end`.
+    fn sequence(&mut self, end: char) -> Option<Vec<Term>> {
+        let mut terms = vec![];
+        if self.0.starts_with(end) {
+            self.next();
+            return Some(terms);
+        }
+        loop {
+            terms.push(self.term()?);
+            match self.next()? {
+                ',' => continue,
+                c if c == end => return Some(terms),
+                _ => return None,
+            }
+        }
+    }
+
+    /// The rest of a string, after the opening quote.
+    fn string(&mut self) -> Option<String> {
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Some(s),
+                '\\' => s.push(match self.next()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    c => c,
+                }),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.0.chars().next()?;
+        self.0 = &self.0[c.len_utf8()..];
+        Some(c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_inputs, Inputs};
+
+    #[test]
+    fn derivation_inputs() {
+        let drv = |hook: &str| {
+            format!(
+                "Derive([(\"out\",\"/nix/store/abc-shell\",\"\",\"\")],\
+                 [(\"/nix/store/def-bash.drv\",[\"out\"]),(\"/nix/store/ghi-hello.drv\",[\"dev\",\"out\"])],\
+                 [\"/nix/store/jkl-builder.sh\"],\"x86_64-linux\",\"/nix/store/mno-bash/bin/bash\",\
+                 [\"-e\",\"/nix/store/jkl-builder.sh\"],\
+                 [(\"name\",\"shell\"),(\"shellHook\",\"{}\")])",
+                hook
+            )
+        };
+        let inputs = parse_inputs(&drv("echo \\\"hi\\\"\\n")).unwrap();
+        assert_eq!(
+            inputs,
+            Inputs {
+                drvs: vec![
+                    (
+                        String::from("/nix/store/def-bash.drv"),
+                        vec![String::from("out")]
+                    ),
+                    (
+                        String::from("/nix/store/ghi-hello.drv"),
+                        vec![String::from("dev"), String::from("out")]
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+                srcs: vec![String::from("/nix/store/jkl-builder.sh")],
+            }
+        );
+        // changing the environment doesn’t change the inputs
+        assert_eq!(parse_inputs(&drv("echo bye")), Some(inputs));
+
+        assert_eq!(parse_inputs("Derive([],[],[]"), None);
+        assert_eq!(parse_inputs("not a derivation"), None);
+    }
+}
@@ -0,0 +1,1111 @@
+//! Mirror the events of a project’s build loop to the sinks
+//! configured in its `.lorri.toml` (see
+//! `project::config::EventSinkConfig`), for integrations which
+//! don’t want to talk to the daemon socket.
+//!
+//! Every event is written as one line of JSON, like
+//!
+//! ```json
+//! {"nix_file":"/home/user/project/shell.nix","event":"retrying","build_id":4,"attempt":1,"max":3}
+//! ```
+//!
+//! The events of a build have the same `build_id` (see
+//! `build_loop::BuildId`). The ones which start and end a build
+//! have its RFC 3339 `time`, to compute how long it took.
+//!
+//! Sinks are best-effort: failing to write to one is logged, and
+//! never stops the build loop.
+
+use crate::build_loop::{Event, Rebuild};
+use crate::builder::ProgressKind;
+use crate::fds;
+use crate::project::config::{rfc3339, EventSinkConfig};
+use crate::NixSource;
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long sending an event to a socket sink may take, connecting
+/// and writing each: a listener which doesn’t accept or read its
+/// events must not hold up the other sinks and the build loop.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Names of the events, as used in the `events` filter of a sink.
+pub const EVENT_NAMES: &[&str] = &[
+    "started",
+    "completed",
+    "failure",
+    "progress",
+    "cachix-push",
+    "push",
+    "roots-lost",
+    "cancelled",
+    "retrying",
+    "untracked-reads",
+    "clock-skew",
+    "environment-switched",
+    "log-line",
+];
+
+/// The kinds of events `lorri internal stream-events --only` can
+/// select.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// `failure` events.
+    Failures,
+    /// `completed` events.
+    Completions,
+    /// `started` events.
+    Started,
+}
+
+impl EventKind {
+    /// The name of the events of this kind (see `EVENT_NAMES`).
+    pub fn event_name(self) -> &'static str {
+        match self {
+            EventKind::Failures => "failure",
+            EventKind::Completions => "completed",
+            EventKind::Started => "started",
+        }
+    }
+}
+
+impl FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EventKind, String> {
+        match s {
+            "failures" => Ok(EventKind::Failures),
+            "completions" => Ok(EventKind::Completions),
+            "started" => Ok(EventKind::Started),
+            _ => Err(format!(
+                "unknown kind of events `{}`, use failures, completions or started",
+                s
+            )),
+        }
+    }
+}
+
+/// The type of a field of the JSON lines, for clients generated
+/// from `EVENT_SCHEMA` (see `client_gen`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldType {
+    /// A string.
+    String,
+    /// A non-negative integer.
+    Integer,
+    /// An RFC 3339 time.
+    Time,
+    /// One of these strings.
+    OneOf(&'static [&'static str]),
+    /// An array of values.
+    List(&'static FieldType),
+    /// An object of names to values.
+    Map(&'static FieldType),
+    /// `null`, or a value.
+    Nullable(&'static FieldType),
+    /// An object with these fields, named like this in clients.
+    Object(&'static str, &'static [Field]),
+}
+
+/// A field of the JSON lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Field {
+    /// The key of the field.
+    pub name: &'static str,
+    /// The type of its value.
+    pub ty: FieldType,
+    /// Whether the field is in every line of its event (otherwise,
+    /// it may be missing).
+    pub always: bool,
+    /// What the field means.
+    pub doc: &'static str,
+}
+
+/// The fields of an event in the JSON lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventSchema {
+    /// The name of the event, its `event` field.
+    pub name: &'static str,
+    /// What the event means.
+    pub doc: &'static str,
+    /// Its fields, besides the `COMMON_FIELDS`.
+    pub fields: &'static [Field],
+}
+
+const fn field(name: &'static str, ty: FieldType, doc: &'static str) -> Field {
+    Field {
+        name,
+        ty,
+        always: true,
+        doc,
+    }
+}
+
+const fn optional(name: &'static str, ty: FieldType, doc: &'static str) -> Field {
+    Field {
+        name,
+        ty,
+        always: false,
+        doc,
+    }
+}
+
+const PATHS: FieldType = FieldType::List(&FieldType::String);
+
+const CACHE_FIELDS: &[Field] = &[
+    field(
+        "substituted",
+        FieldType::Integer,
+        "Store paths downloaded from substituters",
+    ),
+    field("built", FieldType::Integer, "Derivations built locally"),
+    field(
+        "substituters",
+        FieldType::Map(&FieldType::Integer),
+        "Store paths downloaded, by substituter",
+    ),
+];
+
+const TIMINGS_FIELDS: &[Field] = &[
+    field(
+        "evaluate_ms",
+        FieldType::Integer,
+        "Milliseconds spent evaluating the nix file",
+    ),
+    field(
+        "realise_ms",
+        FieldType::Integer,
+        "Milliseconds spent building and fetching the environment",
+    ),
+];
+
+/// The fields all events may have, see `Line`.
+pub const COMMON_FIELDS: &[Field] = &[
+    optional(
+        "nix_file",
+        FieldType::String,
+        "The project's nix file (or flake.nix)",
+    ),
+    optional(
+        "expression",
+        FieldType::String,
+        "The project's nix expression, for projects without a nix file",
+    ),
+    optional(
+        "flake",
+        FieldType::String,
+        "The project's flake reference, for remote flakes",
+    ),
+    optional(
+        "sequence",
+        FieldType::Integer,
+        "The number of the event in the daemon's event stream",
+    ),
+    optional(
+        "epoch",
+        FieldType::Integer,
+        "The daemon's epoch, which changes when it restarts; sequence numbers only compare within an epoch",
+    ),
+    optional(
+        "build_id",
+        FieldType::Integer,
+        "The build the event belongs to",
+    ),
+    optional("time", FieldType::Time, "When the build started or ended"),
+];
+
+/// The fields of every event (in the order of `EVENT_NAMES`), kept
+/// next to `Details` so that they change together.
+pub const EVENT_SCHEMA: &[EventSchema] = &[
+    EventSchema {
+        name: "started",
+        doc: "The build has started",
+        fields: &[optional(
+            "latency_ms",
+            FieldType::Integer,
+            "How long after a change of its inputs was noticed the build started, if one started it",
+        )],
+    },
+    EventSchema {
+        name: "completed",
+        doc: "The build completed successfully",
+        fields: &[
+            field(
+                "shell_gc_root",
+                FieldType::String,
+                "The GC root of the environment",
+            ),
+            optional(
+                "shells",
+                FieldType::Map(&FieldType::String),
+                "The GC roots of the named shells",
+            ),
+            field(
+                "cache",
+                FieldType::Object("Cache", CACHE_FIELDS),
+                "Where the store paths of the build came from",
+            ),
+            field(
+                "drv_path",
+                FieldType::Nullable(&FieldType::String),
+                "The derivation of the environment, if nix reported it",
+            ),
+            field(
+                "timings",
+                FieldType::Object("Timings", TIMINGS_FIELDS),
+                "How long the phases of the build took",
+            ),
+            field(
+                "rebuild",
+                FieldType::OneOf(&["Full", "EvalOnly"]),
+                "EvalOnly if the environment has the same inputs as the one of the last \
+                 build, so that nix only built the environment itself, none of its dependencies",
+            ),
+            field(
+                "env_hash",
+                FieldType::Nullable(&FieldType::String),
+                "A hash of the variables of the environment, the same for identical \
+                 environments on other machines",
+            ),
+        ],
+    },
+    EventSchema {
+        name: "failure",
+        doc: "The build failed",
+        fields: &[
+            field(
+                "log_lines",
+                FieldType::List(&FieldType::String),
+                "The lines nix printed",
+            ),
+            field(
+                "artifacts",
+                FieldType::Nullable(&FieldType::String),
+                "The directory with the outputs of failed derivations",
+            ),
+        ],
+    },
+    EventSchema {
+        name: "progress",
+        doc: "Nix reported progress of the running build",
+        fields: &[
+            field(
+                "kind",
+                FieldType::OneOf(&["Builds", "Downloads", "Bytes"]),
+                "What is counted",
+            ),
+            field("done", FieldType::Integer, "How many are done"),
+            field("expected", FieldType::Integer, "How many are expected"),
+            optional(
+                "current",
+                FieldType::String,
+                "The store path nix started working on last: the derivation it builds, \
+                 or the path it downloads",
+            ),
+        ],
+    },
+    EventSchema {
+        name: "cachix-push",
+        doc: "The result of a build was pushed to cachix",
+        fields: &[
+            field("cache", FieldType::String, "The cachix cache"),
+            field("path", FieldType::String, "The pushed store path"),
+            field(
+                "error",
+                FieldType::Nullable(&FieldType::String),
+                "Why the push failed",
+            ),
+        ],
+    },
+    EventSchema {
+        name: "push",
+        doc: "The push command of the project ran after a build",
+        fields: &[
+            field(
+                "command",
+                FieldType::List(&FieldType::String),
+                "The command, with its arguments",
+            ),
+            field("paths", PATHS, "The pushed store paths"),
+            field(
+                "error",
+                FieldType::Nullable(&FieldType::String),
+                "Why the push failed",
+            ),
+        ],
+    },
+    EventSchema {
+        name: "roots-lost",
+        doc: "Store paths of the GC roots disappeared; a rebuild follows",
+        fields: &[field("paths", PATHS, "The lost store paths")],
+    },
+    EventSchema {
+        name: "cancelled",
+        doc: "The running build was cancelled",
+        fields: &[],
+    },
+    EventSchema {
+        name: "retrying",
+        doc: "The build failed because of a network error, and is retried",
+        fields: &[
+            field("attempt", FieldType::Integer, "The number of this retry"),
+            field("max", FieldType::Integer, "How often the build is retried"),
+        ],
+    },
+    EventSchema {
+        name: "untracked-reads",
+        doc: "The build read files which lorri doesn't watch",
+        fields: &[field("paths", PATHS, "The files")],
+    },
+    EventSchema {
+        name: "clock-skew",
+        doc: "Inputs of the build have mtimes in the future",
+        fields: &[
+            field("paths", PATHS, "The inputs"),
+            field(
+                "ahead_secs",
+                FieldType::Integer,
+                "How far the furthest is ahead",
+            ),
+        ],
+    },
+    EventSchema {
+        name: "environment-switched",
+        doc: "The GC roots now point to a new environment",
+        fields: &[
+            field(
+                "shell_gc_root",
+                FieldType::String,
+                "The store path of the environment",
+            ),
+            optional(
+                "shells",
+                FieldType::Map(&FieldType::String),
+                "The store paths of the named shells",
+            ),
+        ],
+    },
+    EventSchema {
+        name: "log-line",
+        doc: "Nix printed a line while running the build",
+        fields: &[field("line", FieldType::String, "The line")],
+    },
+];
+
+/// Events which are only mirrored to sinks which ask for them by
+/// name: there is one for every line nix prints, and for every
+/// progress update.
+const OPT_IN_EVENTS: &[&str] = &["log-line", "progress"];
+
+/// Where a sink sends the events.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// Append them to a file.
+    File(PathBuf),
+    /// Run a command for each event, with the event on stdin.
+    Command(Vec<String>),
+    /// Send them to a listening Unix socket, one connection per event.
+    Socket(PathBuf),
+}
+
+impl EventSinkConfig {
+    /// The target of this sink, relative paths are relative to
+    /// `project_dir`. Exactly one target has to be configured.
+    pub fn target(&self, project_dir: &Path) -> Result<Target, String> {
+        match (&self.file, &self.command, &self.socket) {
+            (Some(file), None, None) => Ok(Target::File(project_dir.join(file))),
+            (None, Some(command), None) if !command.is_empty() => {
+                Ok(Target::Command(command.clone()))
+            }
+            (None, None, Some(socket)) => Ok(Target::Socket(project_dir.join(socket))),
+            _ => Err(String::from(
+                "an event sink needs exactly one of `file`, `command` or `socket`",
+            )),
+        }
+    }
+
+    /// Whether `event` passes the sink’s filter (no filter passes
+    /// all but the `OPT_IN_EVENTS`).
+    pub fn accepts(&self, event: &Event) -> bool {
+        let name = name_of(event);
+        if self.events.is_empty() {
+            !OPT_IN_EVENTS.contains(&name)
+        } else {
+            self.events.iter().any(|n| n == name)
+        }
+    }
+}
+
+/// The name of `event` in filters and in the JSON lines.
+pub fn name_of(event: &Event) -> &'static str {
+    match event {
+        Event::Started(..) => "started",
+        Event::Completed(..) => "completed",
+        Event::Failure(..) => "failure",
+        Event::Progress(..) => "progress",
+        Event::CachixPush(_) => "cachix-push",
+        Event::Pushed(_) => "push",
+        Event::RootsLost(_) => "roots-lost",
+        Event::Cancelled(_) => "cancelled",
+        Event::Retrying { .. } => "retrying",
+        Event::UntrackedReads(_) => "untracked-reads",
+        Event::ClockSkew { .. } => "clock-skew",
+        Event::EnvironmentSwitched(..) => "environment-switched",
+        Event::LogLine(..) => "log-line",
+    }
+}
+
+/// An event as written to the sinks. Its project is named by one of
+/// `nix_file` (also for local flakes), `expression` and `flake` (see
+/// `NixSource`).
+#[derive(Serialize)]
+struct Line<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nix_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expression: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    flake: Option<&'a str>,
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    epoch: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    build_id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<String>,
+    #[serde(flatten)]
+    details: Details<'a>,
+}
+
+/// The fields specific to an event.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Details<'a> {
+    None {},
+    Started {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        latency_ms: Option<u64>,
+    },
+    Completed {
+        shell_gc_root: String,
+        /// The roots of the named shells, if any
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        shells: BTreeMap<&'a str, String>,
+        cache: Cache<'a>,
+        drv_path: Option<String>,
+        timings: Timings,
+        rebuild: Rebuild,
+        env_hash: Option<&'a str>,
+    },
+    Failure {
+        log_lines: Vec<String>,
+        artifacts: Option<String>,
+    },
+    Progress {
+        kind: ProgressKind,
+        done: u64,
+        expected: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        current: Option<&'a str>,
+    },
+    LogLine {
+        line: &'a str,
+    },
+    CachixPush {
+        cache: &'a str,
+        path: String,
+        error: Option<&'a str>,
+    },
+    Push {
+        command: &'a [String],
+        paths: Vec<String>,
+        error: Option<&'a str>,
+    },
+    RootsLost {
+        paths: Vec<String>,
+    },
+    Retrying {
+        attempt: u32,
+        max: u32,
+    },
+    UntrackedReads {
+        paths: Vec<String>,
+    },
+    ClockSkew {
+        paths: Vec<String>,
+        ahead_secs: u64,
+    },
+    /// Like `Completed`, but with the store paths the roots point to
+    EnvironmentSwitched {
+        shell_gc_root: String,
+        #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+        shells: BTreeMap<&'a str, String>,
+    },
+}
+
+/// Where the store paths of a completed build came from (see
+/// `builder::CacheStats`).
+#[derive(Serialize)]
+struct Cache<'a> {
+    substituted: u64,
+    built: u64,
+    substituters: &'a BTreeMap<String, u64>,
+}
+
+/// How long the phases of a completed build took (see
+/// `builder::Timings`).
+#[derive(Serialize)]
+struct Timings {
+    evaluate_ms: u64,
+    realise_ms: u64,
+}
+
+/// Encode `event` of the build loop of `source` as a JSON line.
+pub fn to_json_line(source: &NixSource, event: &Event) -> String {
+    encode(source, event, None)
+}
+
+/// Like `to_json_line`, with the event’s `sequence` number in the
+/// daemon’s event stream of `epoch` (see `event_stream`).
+pub fn to_sequenced_json_line(
+    source: &NixSource,
+    event: &Event,
+    epoch: u64,
+    sequence: u64,
+) -> String {
+    encode(source, event, Some((epoch, sequence)))
+}
+
+fn encode(source: &NixSource, event: &Event, sequenced: Option<(u64, u64)>) -> String {
+    let details = match event {
+        Event::Started(_, _, latency) => Details::Started {
+            latency_ms: latency.map(|latency| latency.as_millis() as u64),
+        },
+        Event::Cancelled(_) => Details::None {},
+        Event::Completed(_, _, result) => Details::Completed {
+            shell_gc_root: result.output_paths.shell_gc_root.to_string(),
+            shells: result
+                .output_paths
+                .shells
+                .iter()
+                .map(|(name, root)| (name.as_str(), root.to_string()))
+                .collect(),
+            cache: Cache {
+                substituted: result.cache_stats.substituted_total(),
+                built: result.cache_stats.built,
+                substituters: &result.cache_stats.substituted,
+            },
+            drv_path: result
+                .drv_path
+                .as_ref()
+                .map(|drv| drv.display().to_string()),
+            timings: Timings {
+                evaluate_ms: result.timings.evaluate.as_millis() as u64,
+                realise_ms: result.timings.realise.as_millis() as u64,
+            },
+            rebuild: result.rebuild,
+            env_hash: result.env_hash.as_ref().map(String::as_str),
+        },
+        Event::Failure(_, _, failure) => Details::Failure {
+            log_lines: failure
+                .log_lines
+                .iter()
+                .map(|line| line.to_string_lossy().into_owned())
+                .collect(),
+            artifacts: failure
+                .artifacts
+                .as_ref()
+                .map(|dir| dir.display().to_string()),
+        },
+        Event::Progress(_, progress) => Details::Progress {
+            kind: progress.kind,
+            done: progress.done,
+            expected: progress.expected,
+            current: progress.current.as_ref().map(String::as_str),
+        },
+        Event::LogLine(_, line) => Details::LogLine { line },
+        Event::CachixPush(outcome) => Details::CachixPush {
+            cache: &outcome.cache,
+            path: outcome.path.to_string(),
+            error: outcome.result.as_ref().err().map(|e| e.as_str()),
+        },
+        Event::Pushed(outcome) => Details::Push {
+            command: &outcome.command,
+            paths: outcome
+                .paths
+                .iter()
+                .map(|path| path.as_path().display().to_string())
+                .collect(),
+            error: outcome.result.as_ref().err().map(|e| e.as_str()),
+        },
+        Event::UntrackedReads(paths) => Details::UntrackedReads {
+            paths: paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect(),
+        },
+        Event::ClockSkew { paths, ahead } => Details::ClockSkew {
+            paths: paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect(),
+            ahead_secs: ahead.as_secs(),
+        },
+        Event::RootsLost(paths) => Details::RootsLost {
+            paths: paths
+                .iter()
+                .map(|path| path.as_path().display().to_string())
+                .collect(),
+        },
+        Event::EnvironmentSwitched(_, paths) => Details::EnvironmentSwitched {
+            shell_gc_root: paths.shell_gc_root.as_path().display().to_string(),
+            shells: paths
+                .shells
+                .iter()
+                .map(|(name, path)| (name.as_str(), path.as_path().display().to_string()))
+                .collect(),
+        },
+        Event::Retrying { attempt, max, .. } => Details::Retrying {
+            attempt: *attempt,
+            max: *max,
+        },
+    };
+    // local flakes are named by their `flake.nix`, like nix files
+    let nix_file = source.nix_file().map(|nix_file| nix_file.to_string());
+    let (expression, flake) = match source {
+        _ if nix_file.is_some() => (None, None),
+        NixSource::Expression { expression, .. } => (Some(expression.as_str()), None),
+        NixSource::Flake { reference, .. } => (None, Some(reference.as_str())),
+        NixSource::File(_) => (None, None),
+    };
+    let mut line = serde_json::to_string(&Line {
+        nix_file,
+        expression,
+        flake,
+        event: name_of(event),
+        sequence: sequenced.map(|(_, sequence)| sequence),
+        epoch: sequenced.map(|(epoch, _)| epoch),
+        build_id: event.build().map(|build| build.as_u64()),
+        time: event.time().map(rfc3339),
+        details,
+    })
+    .expect("events always encode as JSON");
+    line.push('\n');
+    line
+}
+
+/// Send `line` to `target`.
+pub fn send(target: &Target, line: &str) -> io::Result<()> {
+    match target {
+        Target::File(path) => {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(line.as_bytes())
+        }
+        Target::Command(argv) => {
+            let mut child = fds::restore_soft_limit(&mut Command::new(&argv[0]))
+                .args(&argv[1..])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn()?;
+            let mut stdin = child.stdin.take().expect("stdin is piped");
+            let line = line.to_string();
+            // don’t hold up the build loop for slow commands
+            std::thread::spawn(move || {
+                let written = stdin.write_all(line.as_bytes());
+                drop(stdin);
+                match (written, child.wait()) {
+                    (Err(e), _) | (_, Err(e)) => warn!("event sink command failed: {}", e),
+                    (_, Ok(status)) if !status.success() => {
+                        warn!("event sink command exited with {}", status)
+                    }
+                    _ => (),
+                }
+            });
+            Ok(())
+        }
+        Target::Socket(path) => {
+            let mut stream = unix_socket::connect(path, SOCKET_TIMEOUT)?;
+            stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+            stream.write_all(line.as_bytes())
+        }
+    }
+}
+
+mod unix_socket {
+    extern crate nix;
+
+    use self::nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    use self::nix::libc;
+    use self::nix::sys::socket::{self, AddressFamily, SockFlag, SockType};
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    /// Connect to the Unix socket at `path`, or fail once `timeout`
+    /// passed (while the listener’s backlog is full, since it
+    /// doesn’t accept connections).
+    pub fn connect(path: &Path, timeout: Duration) -> io::Result<UnixStream> {
+        let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+        address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        let bytes = path.as_os_str().as_bytes();
+        // the path needs a terminating NUL
+        if bytes.len() >= address.sun_path.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("the socket path {} is too long", path.display()),
+            ));
+        }
+        for (to, from) in address.sun_path.iter_mut().zip(bytes) {
+            *to = *from as libc::c_char;
+        }
+        let to_io = |e: self::nix::Error| match e.as_errno() {
+            Some(errno) => io::Error::from_raw_os_error(errno as i32),
+            None => io::Error::new(io::ErrorKind::InvalidInput, e.to_string()),
+        };
+        let fd = socket::socket(
+            AddressFamily::Unix,
+            SockType::Stream,
+            SockFlag::empty(),
+            None,
+        )
+        .map_err(to_io)?;
+        // owns the descriptor from now on
+        let stream = unsafe { UnixStream::from_raw_fd(fd) };
+        // not for the hooks and commands the daemon runs meanwhile
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(to_io)?;
+        stream.set_nonblocking(true)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let connected = unsafe {
+                libc::connect(
+                    stream.as_raw_fd(),
+                    &address as *const libc::sockaddr_un as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+                )
+            };
+            if connected == 0 {
+                break;
+            }
+            let error = io::Error::last_os_error();
+            match error.raw_os_error() {
+                Some(libc::EISCONN) => break,
+                Some(libc::EAGAIN) | Some(libc::EINPROGRESS) | Some(libc::EALREADY) => {
+                    if Instant::now() >= deadline {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("{} does not accept connections", path.display()),
+                        ));
+                    }
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                _ => return Err(error),
+            }
+        }
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+}
+
+/// Send `event` of the build loop of `source` to the `sinks`
+/// which accept it. Failures are logged.
+pub fn mirror(sinks: &[EventSinkConfig], project_dir: &Path, source: &NixSource, event: &Event) {
+    let mut line = None;
+    for sink in sinks.iter().filter(|sink| sink.accepts(event)) {
+        let line = line.get_or_insert_with(|| to_json_line(source, event));
+        let result = sink
+            .target(project_dir)
+            .and_then(|target| send(&target, line).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("could not send an event to a sink: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        mirror, to_json_line, to_sequenced_json_line, unix_socket, Target, COMMON_FIELDS,
+        EVENT_NAMES, EVENT_SCHEMA,
+    };
+    use build_loop::{BuildExitFailure, BuildId, BuildResults, Event, Rebuild};
+    use builder::{CacheStats, OutputPaths, Progress, ProgressKind, Timings};
+    use project::config::EventSinkConfig;
+    use project::roots::RootPath;
+    use serde_json;
+    use std::ffi::OsString;
+    use std::fs;
+    use std::os::unix::net::UnixListener;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, UNIX_EPOCH};
+    use {NixFile, NixSource};
+
+    fn nix_file() -> NixSource {
+        NixFile::from(PathBuf::from("/home/user/project/shell.nix")).into()
+    }
+
+    #[test]
+    fn json_lines() {
+        assert_eq!(
+            to_json_line(
+                &nix_file(),
+                &Event::Started(
+                    BuildId::from(4),
+                    UNIX_EPOCH + Duration::from_millis(1_577_836_800_500),
+                    None
+                )
+            ),
+            "{\"nix_file\":\"/home/user/project/shell.nix\",\"event\":\"started\",\"build_id\":4,\"time\":\"2020-01-01T00:00:00.500Z\"}\n"
+        );
+        assert_eq!(
+            to_json_line(
+                &nix_file(),
+                &Event::Started(
+                    BuildId::from(4),
+                    UNIX_EPOCH + Duration::from_millis(1_577_836_800_500),
+                    Some(Duration::from_millis(350))
+                )
+            ),
+            "{\"nix_file\":\"/home/user/project/shell.nix\",\"event\":\"started\",\"build_id\":4,\"time\":\"2020-01-01T00:00:00.500Z\",\"latency_ms\":350}\n"
+        );
+        assert_eq!(
+            to_json_line(
+                &nix_file(),
+                &Event::Retrying {
+                    build: BuildId::from(4),
+                    attempt: 1,
+                    max: 3
+                }
+            ),
+            "{\"nix_file\":\"/home/user/project/shell.nix\",\"event\":\"retrying\",\"build_id\":4,\"attempt\":1,\"max\":3}\n"
+        );
+        assert_eq!(
+            to_json_line(
+                &nix_file(),
+                &Event::ClockSkew {
+                    paths: vec![PathBuf::from("/mnt/nfs/default.nix")],
+                    ahead: Duration::from_secs(90),
+                }
+            ),
+            "{\"nix_file\":\"/home/user/project/shell.nix\",\"event\":\"clock-skew\",\"paths\":[\"/mnt/nfs/default.nix\"],\"ahead_secs\":90}\n"
+        );
+        assert_eq!(
+            to_json_line(
+                &nix_file(),
+                &Event::LogLine(BuildId::from(4), String::from("building '/nix/store/abc.drv'..."))
+            ),
+            "{\"nix_file\":\"/home/user/project/shell.nix\",\"event\":\"log-line\",\"build_id\":4,\"line\":\"building '/nix/store/abc.drv'...\"}\n"
+        );
+        assert_eq!(
+            to_json_line(&nix_file(), &Event::UntrackedReads(vec![])),
+            "{\"nix_file\":\"/home/user/project/shell.nix\",\"event\":\"untracked-reads\",\"paths\":[]}\n"
+        );
+        let flake = |reference: &str| NixSource::Flake {
+            reference: String::from(reference),
+            dir: PathBuf::from("/home/user/project"),
+        };
+        assert_eq!(
+            to_json_line(&flake("."), &Event::Started(BuildId::from(5), UNIX_EPOCH, None)),
+            "{\"nix_file\":\"/home/user/project/flake.nix\",\"event\":\"started\",\"build_id\":5,\"time\":\"1970-01-01T00:00:00.000Z\"}\n"
+        );
+        assert_eq!(
+            to_json_line(
+                &flake("github:owner/repo"),
+                &Event::Started(BuildId::from(5), UNIX_EPOCH, None)
+            ),
+            "{\"flake\":\"github:owner/repo\",\"event\":\"started\",\"build_id\":5,\"time\":\"1970-01-01T00:00:00.000Z\"}\n"
+        );
+    }
+
+    #[test]
+    fn completed_with_cache_stats() {
+        let mut cache_stats = CacheStats::default();
+        cache_stats
+            .substituted
+            .insert(String::from("https://cache.nixos.org"), 12);
+        cache_stats.built = 1;
+        let results = BuildResults {
+            output_paths: OutputPaths {
+                shell_gc_root: RootPath::from(PathBuf::from("/gc_root/shell_gc_root")),
+                shells: Default::default(),
+            },
+            cache_stats,
+            drv_path: Some(PathBuf::from(
+                "/nix/store/abc-lorri-keep-env-hack-shell.drv",
+            )),
+            timings: Timings {
+                evaluate: Duration::from_millis(1500),
+                realise: Duration::from_secs(20),
+            },
+            rebuild: Rebuild::Full,
+            env_hash: Some(String::from("8f14e45fceea167a5a36dedd4bea2543")),
+            watched_files: 0,
+        };
+        assert_eq!(
+            to_json_line(
+                &nix_file(),
+                &Event::Completed(
+                    BuildId::from(2),
+                    UNIX_EPOCH + Duration::from_secs(1_577_836_842),
+                    results
+                )
+            ),
+            "{\"nix_file\":\"/home/user/project/shell.nix\",\"event\":\"completed\",\"build_id\":2,\
+             \"time\":\"2020-01-01T00:00:42.000Z\",\
+             \"shell_gc_root\":\"/gc_root/shell_gc_root\",\
+             \"cache\":{\"substituted\":12,\"built\":1,\"substituters\":{\"https://cache.nixos.org\":12}},\
+             \"drv_path\":\"/nix/store/abc-lorri-keep-env-hack-shell.drv\",\
+             \"timings\":{\"evaluate_ms\":1500,\"realise_ms\":20000},\"rebuild\":\"Full\",\
+             \"env_hash\":\"8f14e45fceea167a5a36dedd4bea2543\"}\n"
+        );
+    }
+
+    /// The schema has the fields of the JSON lines.
+    #[test]
+    fn schema() {
+        assert_eq!(
+            EVENT_SCHEMA
+                .iter()
+                .map(|schema| schema.name)
+                .collect::<Vec<_>>(),
+            EVENT_NAMES
+        );
+        let failure = BuildExitFailure {
+            log_lines: vec![OsString::from("error")],
+            artifacts: None,
+        };
+        let events = vec![
+            Event::Started(BuildId::from(1), UNIX_EPOCH, None),
+            Event::Failure(BuildId::from(1), UNIX_EPOCH, failure),
+            Event::Cancelled(BuildId::from(1)),
+            Event::RootsLost(vec![]),
+            Event::ClockSkew {
+                paths: vec![],
+                ahead: Duration::from_secs(1),
+            },
+            Event::Retrying {
+                build: BuildId::from(1),
+                attempt: 1,
+                max: 3,
+            },
+            Event::LogLine(BuildId::from(1), String::from("building")),
+        ];
+        for event in events {
+            let line: serde_json::Value =
+                serde_json::from_str(&to_sequenced_json_line(&nix_file(), &event, 7, 1)).unwrap();
+            let line = line.as_object().unwrap();
+            let schema = EVENT_SCHEMA
+                .iter()
+                .find(|schema| schema.name == line["event"])
+                .unwrap();
+            let fields = || COMMON_FIELDS.iter().chain(schema.fields);
+            for key in line.keys().filter(|key| *key != "event") {
+                assert!(fields().any(|field| field.name == key), "{}", key);
+            }
+            for field in schema.fields.iter().filter(|field| field.always) {
+                assert!(line.contains_key(field.name), "{}", field.name);
+            }
+        }
+    }
+
+    #[test]
+    fn log_lines_only_on_request() {
+        let line = Event::LogLine(BuildId::from(1), String::from("building"));
+        let mut sink = EventSinkConfig::default();
+        assert!(!sink.accepts(&line));
+        assert!(sink.accepts(&Event::Started(BuildId::from(1), UNIX_EPOCH, None)));
+        sink.events = vec![String::from("log-line")];
+        assert!(sink.accepts(&line));
+    }
+
+    #[test]
+    fn progress_only_on_request() {
+        let progress = Event::Progress(
+            BuildId::from(1),
+            Progress {
+                kind: ProgressKind::Builds,
+                done: 1,
+                expected: 2,
+                current: None,
+            },
+        );
+        let mut sink = EventSinkConfig::default();
+        assert!(!sink.accepts(&progress));
+        sink.events = vec![String::from("progress")];
+        assert!(sink.accepts(&progress));
+    }
+
+    #[test]
+    fn exactly_one_target() {
+        let dir = Path::new("/home/user/project");
+        let mut sink = EventSinkConfig::default();
+        assert!(sink.target(dir).is_err());
+        sink.file = Some(PathBuf::from("events.jsonl"));
+        assert_eq!(sink.target(dir), Ok(Target::File(dir.join("events.jsonl"))));
+        sink.socket = Some(PathBuf::from("/run/events.sock"));
+        assert!(sink.target(dir).is_err());
+    }
+
+    #[test]
+    fn filtered_file_sink() {
+        let tmp = tempfile::tempdir().unwrap();
+        let sink = EventSinkConfig {
+            file: Some(PathBuf::from("events.jsonl")),
+            events: vec![String::from("failure")],
+            ..EventSinkConfig::default()
+        };
+        let build = BuildId::from(1);
+        let failure = Event::Failure(
+            build,
+            UNIX_EPOCH,
+            BuildExitFailure {
+                log_lines: vec!["error: oops".into()],
+                artifacts: Some(PathBuf::from("/failures/2020-01-01T123000Z")),
+            },
+        );
+        for event in &[Event::Started(build, UNIX_EPOCH, None), failure] {
+            mirror(std::slice::from_ref(&sink), tmp.path(), &nix_file(), event);
+        }
+        assert_eq!(
+            fs::read_to_string(tmp.path().join("events.jsonl")).unwrap(),
+            "{\"nix_file\":\"/home/user/project/shell.nix\",\"event\":\"failure\",\"build_id\":1,\"time\":\"1970-01-01T00:00:00.000Z\",\"log_lines\":[\"error: oops\"],\"artifacts\":\"/failures/2020-01-01T123000Z\"}\n"
+        );
+    }
+
+    #[test]
+    fn socket_listener_which_never_accepts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("events.sock");
+        let _listener = UnixListener::bind(&path).unwrap();
+        // connections wait in the backlog until it is full
+        let mut waiting = vec![];
+        let error = loop {
+            match unix_socket::connect(&path, Duration::from_millis(50)) {
+                Ok(stream) => waiting.push(stream),
+                Err(e) => break e,
+            }
+            if waiting.len() > 10_000 {
+                panic!("the backlog never filled up");
+            }
+        };
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert!(!waiting.is_empty());
+    }
+}
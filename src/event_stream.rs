@@ -0,0 +1,566 @@
+//! Fan out the events of all build loops to the clients of the
+//! daemon which listen to them
+//! (see `socket::communicate::CommunicationType::StreamEvents`).
+//!
+//! Every listener gets a bounded buffer, so a listener which stops
+//! reading can’t make the daemon’s memory grow. Once its buffer is
+//! full, the daemon either drops the oldest events (the listener
+//! gets a `Streamed::Gap` in their place) or disconnects the
+//! listener, see `SlowListeners`.
+//!
+//! Every event gets a sequence number, increasing for as long as the
+//! daemon runs, and the daemon’s epoch, which changes when it
+//! restarts. The most recent events (as many as fit into a
+//! listener’s buffer) are kept, so a listener which reconnects can
+//! resume after the last event it saw (see `EventStream::subscribe`).
+//!
+//! The lines nix prints while building (`Event::LogLine`) are the
+//! exception: there are far more of them than of other events, so
+//! they get no sequence number, are not kept, and only go to the
+//! listeners which ask for them. A listener whose buffer is full
+//! misses log lines, they never push out other events.
+
+use crate::build_loop::Event;
+use crate::event_sink;
+use crate::{NixFile, NixSource};
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How many events are buffered per listener by default.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// What happens to a listener whose buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowListeners {
+    /// Drop the oldest buffered event, the listener is told how
+    /// many events it missed.
+    DropOldest,
+    /// Disconnect the listener.
+    Disconnect,
+}
+
+impl Default for SlowListeners {
+    fn default() -> SlowListeners {
+        SlowListeners::DropOldest
+    }
+}
+
+impl FromStr for SlowListeners {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SlowListeners, String> {
+        match s {
+            "drop-oldest" => Ok(SlowListeners::DropOldest),
+            "disconnect" => Ok(SlowListeners::Disconnect),
+            _ => Err(format!(
+                "unknown policy `{}`, use drop-oldest or disconnect",
+                s
+            )),
+        }
+    }
+}
+
+/// The buffers of the listeners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferConfig {
+    /// How many events are buffered per listener (at least one).
+    pub capacity: usize,
+    /// What happens once a buffer is full.
+    pub slow_listeners: SlowListeners,
+}
+
+impl Default for BufferConfig {
+    fn default() -> BufferConfig {
+        BufferConfig {
+            capacity: DEFAULT_CAPACITY,
+            slow_listeners: SlowListeners::default(),
+        }
+    }
+}
+
+/// What a listener reads, see `Subscription`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Streamed {
+    /// An event, as a JSON line (see `event_sink::to_sequenced_json_line`).
+    Event(String),
+    /// This many events were dropped because the listener was slow.
+    Gap(u64),
+    /// The listener was disconnected because it was slow.
+    Disconnected,
+}
+
+/// The last event a listener saw, to resume after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Since {
+    /// The event’s `sequence` number.
+    pub sequence: u64,
+    /// The event’s `epoch`, if the listener knows it.
+    pub epoch: Option<u64>,
+}
+
+/// The listeners of the events of all build loops. Clones share them.
+#[derive(Clone)]
+pub struct EventStream {
+    config: BufferConfig,
+    /// When the stream was created, in nanoseconds since the Unix
+    /// epoch; sequence numbers only compare within an epoch.
+    epoch: u64,
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for EventStream {
+    fn default() -> EventStream {
+        EventStream::new(BufferConfig::default())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    listeners: Vec<Arc<Listener>>,
+    /// The most recent events, oldest first.
+    history: VecDeque<Published>,
+    /// The sequence number of the last event (0 before the first).
+    sequence: u64,
+}
+
+struct Published {
+    sequence: u64,
+    nix_file: NixFile,
+    line: String,
+}
+
+struct Listener {
+    /// Only events of this nix file, if set.
+    nix_file: Option<NixFile>,
+    /// Whether the listener gets `Event::LogLine`s.
+    log_lines: bool,
+    buffer: Mutex<Buffer>,
+    changed: Condvar,
+    /// Called when the listener is disconnected.
+    on_disconnect: Box<dyn Fn() + Send + Sync>,
+}
+
+#[derive(Default)]
+struct Buffer {
+    events: VecDeque<String>,
+    /// Events dropped since the listener last read.
+    dropped: u64,
+    disconnected: bool,
+}
+
+impl EventStream {
+    /// No listeners yet, with buffers as in `config`.
+    pub fn new(config: BufferConfig) -> EventStream {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_nanos() as u64)
+            .unwrap_or(0);
+        EventStream {
+            config,
+            epoch,
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// The `epoch` of the events, different for every daemon.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Listen to the events of `nix_file` (or of all nix files).
+    /// `on_disconnect` is called if the listener falls too far
+    /// behind with `SlowListeners::Disconnect`, for example to
+    /// close its connection (a slow listener might be blocked on
+    /// writing the previous event).
+    ///
+    /// With `since`, the listener first gets the kept events after
+    /// the one with that sequence number, preceded by a
+    /// `Streamed::Gap` if some of them are no longer kept. An event
+    /// of another epoch (or, without an epoch, a sequence number the
+    /// daemon didn’t reach yet) means the daemon restarted: all kept
+    /// events are replayed, after a gap for the events the listener
+    /// missed, of which there is at least one with an epoch (those
+    /// of the previous daemon after `since` are unknown).
+    ///
+    /// With `log_lines`, the listener also gets the `Event::LogLine`s
+    /// published from now on.
+    pub fn subscribe<F>(
+        &self,
+        nix_file: Option<NixFile>,
+        since: Option<Since>,
+        log_lines: bool,
+        on_disconnect: F,
+    ) -> Subscription
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        let mut buffer = Buffer::default();
+        let mut state = self.state.lock().expect("event stream lock poisoned");
+        if let Some(since) = since {
+            let other_epoch = since.epoch.map_or(false, |epoch| epoch != self.epoch);
+            let after = if other_epoch || since.sequence > state.sequence {
+                0
+            } else {
+                since.sequence
+            };
+            let oldest = state
+                .history
+                .front()
+                .map_or(state.sequence + 1, |published| published.sequence);
+            buffer.dropped = oldest.saturating_sub(after + 1);
+            if other_epoch {
+                buffer.dropped = buffer.dropped.max(1);
+            }
+            buffer.events = state
+                .history
+                .iter()
+                .filter(|published| published.sequence > after)
+                .filter(|published| nix_file.as_ref().map_or(true, |n| *n == published.nix_file))
+                .map(|published| published.line.clone())
+                .collect();
+        }
+        let listener = Arc::new(Listener {
+            nix_file,
+            log_lines,
+            buffer: Mutex::new(buffer),
+            changed: Condvar::new(),
+            on_disconnect: Box::new(on_disconnect),
+        });
+        state.listeners.push(listener.clone());
+        Subscription {
+            stream: self.clone(),
+            listener,
+        }
+    }
+
+    /// Pass `event` of the build loop of `nix_file` to the listeners.
+    /// Never blocks on a listener.
+    pub fn publish(&self, nix_file: &NixFile, event: &Event) {
+        let capacity = self.config.capacity.max(1);
+        let mut state = self.state.lock().expect("event stream lock poisoned");
+        if let Event::LogLine(..) = event {
+            let line = event_sink::to_json_line(&NixSource::File(nix_file.clone()), event);
+            for listener in &state.listeners {
+                if !listener.log_lines
+                    || listener.nix_file.as_ref().map_or(false, |n| n != nix_file)
+                {
+                    continue;
+                }
+                let mut buffer = listener.buffer.lock().expect("buffer lock poisoned");
+                if buffer.events.len() < capacity {
+                    buffer.events.push_back(line.clone());
+                    listener.changed.notify_all();
+                }
+            }
+            return;
+        }
+        state.sequence += 1;
+        let sequence = state.sequence;
+        let line = event_sink::to_sequenced_json_line(
+            &NixSource::File(nix_file.clone()),
+            event,
+            self.epoch,
+            sequence,
+        );
+        if state.history.len() >= capacity {
+            state.history.pop_front();
+        }
+        state.history.push_back(Published {
+            sequence,
+            nix_file: nix_file.clone(),
+            line: line.clone(),
+        });
+        state.listeners.retain(|listener| {
+            if listener.nix_file.as_ref().map_or(false, |n| n != nix_file) {
+                return true;
+            }
+            let mut buffer = listener.buffer.lock().expect("buffer lock poisoned");
+            let keep = if buffer.events.len() < capacity {
+                buffer.events.push_back(line.clone());
+                true
+            } else {
+                match self.config.slow_listeners {
+                    SlowListeners::DropOldest => {
+                        buffer.events.pop_front();
+                        buffer.dropped += 1;
+                        buffer.events.push_back(line.clone());
+                        true
+                    }
+                    SlowListeners::Disconnect => {
+                        buffer.events.clear();
+                        buffer.disconnected = true;
+                        (listener.on_disconnect)();
+                        false
+                    }
+                }
+            };
+            listener.changed.notify_all();
+            keep
+        });
+    }
+}
+
+/// Reads the events for one listener, see `EventStream::subscribe`.
+/// Stops listening when dropped.
+pub struct Subscription {
+    stream: EventStream,
+    listener: Arc<Listener>,
+}
+
+impl Subscription {
+    /// The next event, or `None` if there was none within `timeout`.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<Streamed> {
+        let deadline = Instant::now() + timeout;
+        let mut buffer = self.listener.buffer.lock().expect("buffer lock poisoned");
+        while buffer.events.is_empty() && buffer.dropped == 0 && !buffer.disconnected {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            buffer = self
+                .listener
+                .changed
+                .wait_timeout(buffer, deadline - now)
+                .expect("buffer lock poisoned")
+                .0;
+        }
+        if buffer.disconnected {
+            Some(Streamed::Disconnected)
+        } else if buffer.dropped > 0 {
+            // the dropped events came before all buffered ones
+            Some(Streamed::Gap(std::mem::replace(&mut buffer.dropped, 0)))
+        } else {
+            buffer.events.pop_front().map(Streamed::Event)
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let listener = &self.listener;
+        self.stream
+            .state
+            .lock()
+            .expect("event stream lock poisoned")
+            .listeners
+            .retain(|other| !Arc::ptr_eq(other, listener));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferConfig, EventStream, Since, SlowListeners, Streamed};
+    use build_loop::{BuildId, Event};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+    use NixFile;
+
+    const NO_WAIT: Duration = Duration::from_millis(10);
+
+    fn nix_file(name: &str) -> NixFile {
+        NixFile::from(PathBuf::from(format!("/home/user/{}/shell.nix", name)))
+    }
+
+    fn stream(slow_listeners: SlowListeners) -> EventStream {
+        EventStream::new(BufferConfig {
+            capacity: 2,
+            slow_listeners,
+        })
+    }
+
+    /// Resume after `sequence`, without an epoch.
+    fn since(sequence: u64) -> Option<Since> {
+        Some(Since {
+            sequence,
+            epoch: None,
+        })
+    }
+
+    fn is_event(streamed: Option<Streamed>) -> bool {
+        match streamed {
+            Some(Streamed::Event(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// The sequence number of the streamed event.
+    fn sequence(streamed: Option<Streamed>) -> u64 {
+        match streamed {
+            Some(Streamed::Event(line)) => {
+                let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+                event["sequence"].as_u64().unwrap()
+            }
+            other => panic!("not an event: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn filter_by_nix_file() {
+        let stream = stream(SlowListeners::DropOldest);
+        let mut all = stream.subscribe(None, None, false, || ());
+        let mut one = stream.subscribe(Some(nix_file("one")), None, false, || ());
+        stream.publish(
+            &nix_file("two"),
+            &Event::Started(BuildId::from(1), UNIX_EPOCH, None),
+        );
+        assert!(is_event(all.next_timeout(NO_WAIT)));
+        assert_eq!(one.next_timeout(NO_WAIT), None);
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let stream = stream(SlowListeners::DropOldest);
+        let mut subscription = stream.subscribe(None, None, false, || ());
+        for _ in 0..5 {
+            stream.publish(
+                &nix_file("one"),
+                &Event::Started(BuildId::from(1), UNIX_EPOCH, None),
+            );
+        }
+        assert_eq!(subscription.next_timeout(NO_WAIT), Some(Streamed::Gap(3)));
+        assert!(is_event(subscription.next_timeout(NO_WAIT)));
+        assert!(is_event(subscription.next_timeout(NO_WAIT)));
+        assert_eq!(subscription.next_timeout(NO_WAIT), None);
+    }
+
+    #[test]
+    fn disconnect() {
+        let stream = stream(SlowListeners::Disconnect);
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let flag = disconnected.clone();
+        let mut subscription = stream.subscribe(None, None, false, move || {
+            flag.store(true, Ordering::SeqCst)
+        });
+        for _ in 0..3 {
+            stream.publish(
+                &nix_file("one"),
+                &Event::Started(BuildId::from(1), UNIX_EPOCH, None),
+            );
+        }
+        assert!(disconnected.load(Ordering::SeqCst));
+        assert_eq!(
+            subscription.next_timeout(NO_WAIT),
+            Some(Streamed::Disconnected)
+        );
+        assert!(stream.state.lock().unwrap().listeners.is_empty());
+    }
+
+    #[test]
+    fn resume() {
+        let stream = stream(SlowListeners::DropOldest);
+        for _ in 0..3 {
+            stream.publish(
+                &nix_file("one"),
+                &Event::Started(BuildId::from(1), UNIX_EPOCH, None),
+            );
+        }
+        // events 2 and 3 are kept
+        let mut subscription = stream.subscribe(None, since(2), false, || ());
+        assert_eq!(sequence(subscription.next_timeout(NO_WAIT)), 3);
+        assert_eq!(subscription.next_timeout(NO_WAIT), None);
+        stream.publish(
+            &nix_file("one"),
+            &Event::Started(BuildId::from(1), UNIX_EPOCH, None),
+        );
+        assert_eq!(sequence(subscription.next_timeout(NO_WAIT)), 4);
+
+        let mut missed = stream.subscribe(None, since(0), false, || ());
+        assert_eq!(missed.next_timeout(NO_WAIT), Some(Streamed::Gap(2)));
+        assert_eq!(sequence(missed.next_timeout(NO_WAIT)), 3);
+        assert_eq!(sequence(missed.next_timeout(NO_WAIT)), 4);
+
+        // the daemon restarted since the listener saw event 10
+        let mut restarted = stream.subscribe(None, since(10), false, || ());
+        assert_eq!(restarted.next_timeout(NO_WAIT), Some(Streamed::Gap(2)));
+        assert_eq!(sequence(restarted.next_timeout(NO_WAIT)), 3);
+    }
+
+    #[test]
+    fn resume_in_another_epoch() {
+        let stream = EventStream::new(BufferConfig::default());
+        for _ in 0..3 {
+            stream.publish(
+                &nix_file("one"),
+                &Event::Started(BuildId::from(1), UNIX_EPOCH, None),
+            );
+        }
+        let after = |sequence, epoch| {
+            Some(Since {
+                sequence,
+                epoch: Some(epoch),
+            })
+        };
+
+        let mut same = stream.subscribe(None, after(2, stream.epoch()), false, || ());
+        match same.next_timeout(NO_WAIT) {
+            Some(Streamed::Event(line)) => {
+                let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(event["sequence"], 3);
+                assert_eq!(event["epoch"], stream.epoch());
+            }
+            other => panic!("not an event: {:?}", other),
+        }
+        assert_eq!(same.next_timeout(NO_WAIT), None);
+
+        // the listener saw event 2 of a daemon which restarted since;
+        // all kept events are new to it, but it missed some before
+        let mut restarted = stream.subscribe(None, after(2, stream.epoch() + 1), false, || ());
+        assert_eq!(restarted.next_timeout(NO_WAIT), Some(Streamed::Gap(1)));
+        assert_eq!(sequence(restarted.next_timeout(NO_WAIT)), 1);
+        assert_eq!(sequence(restarted.next_timeout(NO_WAIT)), 2);
+        assert_eq!(sequence(restarted.next_timeout(NO_WAIT)), 3);
+    }
+
+    #[test]
+    fn log_lines_only_for_listeners_asking_for_them() {
+        let stream = stream(SlowListeners::Disconnect);
+        let mut events = stream.subscribe(None, None, false, || ());
+        let mut log_lines = stream.subscribe(None, None, true, || ());
+        stream.publish(
+            &nix_file("one"),
+            &Event::Started(BuildId::from(1), UNIX_EPOCH, None),
+        );
+        for _ in 0..3 {
+            stream.publish(
+                &nix_file("one"),
+                &Event::LogLine(BuildId::from(1), String::from("building")),
+            );
+        }
+        assert_eq!(sequence(events.next_timeout(NO_WAIT)), 1);
+        assert_eq!(events.next_timeout(NO_WAIT), None);
+
+        // the full buffer dropped a log line instead of disconnecting
+        assert_eq!(sequence(log_lines.next_timeout(NO_WAIT)), 1);
+        match log_lines.next_timeout(NO_WAIT) {
+            Some(Streamed::Event(line)) => {
+                let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+                assert_eq!(event["event"], "log-line");
+                assert!(event.get("sequence").is_none());
+            }
+            other => panic!("not a log line: {:?}", other),
+        }
+        assert_eq!(log_lines.next_timeout(NO_WAIT), None);
+
+        // log lines are not kept for listeners which resume
+        let mut resumed = stream.subscribe(None, since(0), true, || ());
+        assert_eq!(sequence(resumed.next_timeout(NO_WAIT)), 1);
+        assert_eq!(resumed.next_timeout(NO_WAIT), None);
+        stream.publish(
+            &nix_file("one"),
+            &Event::Started(BuildId::from(2), UNIX_EPOCH, None),
+        );
+        assert_eq!(sequence(resumed.next_timeout(NO_WAIT)), 2);
+    }
+
+    #[test]
+    fn unsubscribe_on_drop() {
+        let stream = stream(SlowListeners::DropOldest);
+        drop(stream.subscribe(None, None, false, || ()));
+        assert!(stream.state.lock().unwrap().listeners.is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+//! The file descriptors of this process: how many are open, and how
+//! many may be. Each watched project holds an inotify instance (or
+//! an FSEvents stream) and its build's pipes, and every client a
+//! socket, so a daemon with many projects can run out of them.
+
+extern crate nix;
+
+use self::nix::libc;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::Mutex;
+
+/// Warn once this many percent of the soft limit are in use.
+pub const WARN_PERCENT: u64 = 80;
+
+/// The `RLIMIT_NOFILE` of this process; `None` is unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The limit the process runs into.
+    pub soft: Option<u64>,
+    /// The highest value the soft limit can be raised to.
+    pub hard: Option<u64>,
+}
+
+/// How many file descriptors are open, out of how many.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    /// The open file descriptors.
+    pub open: u64,
+    /// The soft limit, `None` if there is none.
+    pub limit: Option<u64>,
+}
+
+impl Usage {
+    /// The open file descriptors in percent of the limit.
+    pub fn percent(&self) -> Option<u64> {
+        match self.limit {
+            Some(0) => Some(100),
+            Some(limit) => Some(self.open.saturating_mul(100) / limit),
+            None => None,
+        }
+    }
+
+    /// Whether at least `WARN_PERCENT` of the limit are in use.
+    pub fn near_limit(&self) -> bool {
+        self.percent()
+            .map_or(false, |percent| percent >= WARN_PERCENT)
+    }
+}
+
+/// The directory listing the open file descriptors of this process.
+#[cfg(target_os = "linux")]
+const FD_DIR: &str = "/proc/self/fd";
+#[cfg(not(target_os = "linux"))]
+const FD_DIR: &str = "/dev/fd";
+
+/// Count the open file descriptors of this process.
+pub fn open() -> io::Result<u64> {
+    let mut open = 0u64;
+    let entries = std::fs::read_dir(FD_DIR)?;
+    for entry in entries {
+        entry?;
+        open += 1;
+    }
+    // reading the directory takes a file descriptor, too
+    Ok(open.saturating_sub(1))
+}
+
+/// Read the limits of this process.
+pub fn limits() -> io::Result<Limits> {
+    let rlimit = getrlimit()?;
+    Ok(Limits {
+        soft: from_rlim(rlimit.rlim_cur),
+        hard: from_rlim(rlimit.rlim_max),
+    })
+}
+
+/// Count the open file descriptors and read the soft limit.
+pub fn usage() -> io::Result<Usage> {
+    Ok(Usage {
+        open: open()?,
+        limit: limits()?.soft,
+    })
+}
+
+/// `OPEN_MAX` of macOS, the highest soft limit it allows.
+#[cfg(target_os = "macos")]
+fn max_soft_limit() -> Option<libc::rlim_t> {
+    Some(10240)
+}
+
+/// `fs.nr_open` of Linux, the highest soft limit it allows (which
+/// matters when the hard limit is unlimited).
+#[cfg(target_os = "linux")]
+fn max_soft_limit() -> Option<libc::rlim_t> {
+    std::fs::read_to_string("/proc/sys/fs/nr_open")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn max_soft_limit() -> Option<libc::rlim_t> {
+    None
+}
+
+/// The soft limit to raise to, for the hard limit `hard`:
+/// setrlimit rejects anything higher than `max` (see
+/// `max_soft_limit`), even if the hard limit is unlimited.
+fn soft_limit_target(hard: libc::rlim_t, max: Option<libc::rlim_t>) -> libc::rlim_t {
+    match max {
+        Some(max) => std::cmp::min(hard, max),
+        None => hard,
+    }
+}
+
+lazy_static! {
+    /// The soft limit before `raise_soft_limit` raised it, which the
+    /// processes lorri starts get back (see `restore_soft_limit`).
+    static ref ORIGINAL_SOFT_LIMIT: Mutex<Option<libc::rlim_t>> = Mutex::new(None);
+}
+
+/// Raise the soft limit as far as the hard limit and the system
+/// allow (see `max_soft_limit`) and return the new limits.
+pub fn raise_soft_limit() -> io::Result<Limits> {
+    let mut rlimit = getrlimit()?;
+    let original = rlimit.rlim_cur;
+    let target = soft_limit_target(rlimit.rlim_max, max_soft_limit());
+    if original != libc::RLIM_INFINITY && original < target {
+        rlimit.rlim_cur = target;
+        // only touches this process (see `restore_soft_limit`)
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ORIGINAL_SOFT_LIMIT
+            .lock()
+            .expect("soft limit lock poisoned")
+            .get_or_insert(original);
+    }
+    limits()
+}
+
+/// Start `cmd` with the soft limit this process had before
+/// `raise_soft_limit` raised it: the builds and commands the daemon
+/// runs don’t need more, and some programs (like those using
+/// `select()`) break with more.
+pub fn restore_soft_limit(cmd: &mut Command) -> &mut Command {
+    let original = *ORIGINAL_SOFT_LIMIT
+        .lock()
+        .expect("soft limit lock poisoned");
+    match original {
+        None => cmd,
+        // getrlimit() and setrlimit() are async-signal-safe, so
+        // they can run between fork() and exec()
+        Some(soft) => unsafe {
+            cmd.pre_exec(move || {
+                let mut rlimit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                if libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // lowering the soft limit is always allowed
+                rlimit.rlim_cur = soft;
+                if libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        },
+    }
+}
+
+fn getrlimit() -> io::Result<libc::rlimit> {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // only writes to `rlimit`
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(rlimit)
+}
+
+fn from_rlim(rlim: libc::rlim_t) -> Option<u64> {
+    if rlim == libc::RLIM_INFINITY {
+        None
+    } else {
+        Some(rlim)
+    }
+}
+
+/// Watches the usage over time: warns when it gets near the limit,
+/// and tracks the highest one seen.
+#[derive(Debug, Default)]
+pub struct Monitor {
+    /// Whether the last usage was near the limit.
+    near_limit: bool,
+    /// The most file descriptors seen open at once.
+    peak: u64,
+}
+
+impl Monitor {
+    /// Record `usage`. Logs a warning when it gets near the limit,
+    /// and returns whether it did.
+    pub fn observe(&mut self, usage: Usage) -> bool {
+        self.peak = std::cmp::max(self.peak, usage.open);
+        let was_near_limit = self.near_limit;
+        self.near_limit = usage.near_limit();
+        match (was_near_limit, self.near_limit, usage.limit) {
+            (false, true, Some(limit)) => {
+                warn!(
+                    "{} of {} file descriptors are open; the daemon fails to watch \
+                     projects once they run out. Raise the limit (`ulimit -n`, or \
+                     `LimitNOFILE=` for systemd services) or watch fewer projects",
+                    usage.open, limit
+                );
+                true
+            }
+            (true, false, _) => {
+                info!("{} file descriptors are open", usage.open);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// The most file descriptors seen open at once.
+    pub fn peak(&self) -> u64 {
+        self.peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        libc, limits, raise_soft_limit, restore_soft_limit, soft_limit_target, Monitor, Usage,
+    };
+    use std::process::Command;
+
+    #[test]
+    fn raise_below_the_system_maximum() {
+        assert_eq!(
+            soft_limit_target(libc::RLIM_INFINITY, Some(1_048_576)),
+            1_048_576
+        );
+        assert_eq!(soft_limit_target(4096, Some(10240)), 4096);
+        assert_eq!(soft_limit_target(4096, None), 4096);
+    }
+
+    #[test]
+    fn children_get_the_original_limit() {
+        let original = limits().unwrap().soft;
+        raise_soft_limit().unwrap();
+        let output = restore_soft_limit(Command::new("sh").args(&["-c", "ulimit -n"]))
+            .output()
+            .unwrap();
+        let expected = match original {
+            Some(soft) => soft.to_string(),
+            None => String::from("unlimited"),
+        };
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), expected);
+    }
+
+    #[test]
+    fn warn_near_the_limit() {
+        let usage = |open| Usage {
+            open,
+            limit: Some(100),
+        };
+        assert_eq!(usage(79).percent(), Some(79));
+        assert!(!usage(79).near_limit());
+        assert!(usage(80).near_limit());
+        assert!(!Usage {
+            open: 1_000_000,
+            limit: None
+        }
+        .near_limit());
+
+        // warns once, until the usage dropped again
+        let mut monitor = Monitor::default();
+        assert!(!monitor.observe(usage(50)));
+        assert!(monitor.observe(usage(85)));
+        assert!(!monitor.observe(usage(95)));
+        assert!(!monitor.observe(usage(60)));
+        assert!(monitor.observe(usage(81)));
+        assert_eq!(monitor.peak(), 95);
+    }
+}
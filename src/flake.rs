@@ -0,0 +1,146 @@
+//! Projects defined by a flake (see `NixSource::Flake`).
+//!
+//! A project whose nix file is a `flake.nix` builds the flake’s
+//! default development shell. nix evaluates a copy of the flake in
+//! the store, so the files it reports as inputs are in that copy;
+//! `local_inputs` finds the files they were copied from.
+
+use nix::Store;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The name of a flake’s nix file.
+pub const FLAKE_FILE_NAME: &str = "flake.nix";
+
+/// The name of the file pinning a flake’s inputs.
+pub const FLAKE_LOCK_FILE_NAME: &str = "flake.lock";
+
+/// The files in `flake_dir` which the `paths` nix evaluated are
+/// copies of. The copy of the flake is the store directory whose
+/// `flake.nix` is the same as the one in `flake_dir`; other flakes
+/// (like nixpkgs) are inputs from elsewhere.
+pub fn local_inputs(paths: &[PathBuf], flake_dir: &Path, store: &Store) -> Vec<PathBuf> {
+    let root = store.chroot().unwrap_or_else(|| PathBuf::from("/"));
+    local_inputs_in(&root, paths, flake_dir)
+}
+
+/// `local_inputs`, with the store at `root` on disk.
+fn local_inputs_in(root: &Path, paths: &[PathBuf], flake_dir: &Path) -> Vec<PathBuf> {
+    let flake = match fs::read(flake_dir.join(FLAKE_FILE_NAME)) {
+        Ok(flake) => flake,
+        Err(_) => return vec![],
+    };
+    let on_disk = |path: &Path| root.join(path.strip_prefix("/").unwrap_or(path));
+    let mut copies: Vec<&Path> = vec![];
+    let mut inputs = vec![];
+    for path in paths {
+        let copy = match store_directory(path) {
+            Some(copy) => copy,
+            None => continue,
+        };
+        if !copies.contains(&copy) {
+            if fs::read(on_disk(copy).join(FLAKE_FILE_NAME)).ok().as_ref() != Some(&flake) {
+                continue;
+            }
+            copies.push(copy);
+        }
+        if let Ok(relative) = path.strip_prefix(copy) {
+            let input = flake_dir.join(relative);
+            if input.exists() && !inputs.contains(&input) {
+                inputs.push(input);
+            }
+        }
+    }
+    inputs
+}
+
+/// The top-level directory `/nix/store/<hash>-<name>` containing
+/// `path`, if it is in the store.
+fn store_directory(path: &Path) -> Option<&Path> {
+    let relative = path.strip_prefix("/nix/store").ok()?;
+    let name = relative.iter().next()?;
+    let len = "/nix/store/".len() + name.len();
+    path.to_str()
+        .filter(|path| path.len() >= len)
+        .map(|path| Path::new(&path[..len]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{local_inputs_in, store_directory};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use {NixFile, NixSource};
+
+    #[test]
+    fn flake_sources() {
+        let source = |path: &str| NixSource::from_nix_file(NixFile::from(PathBuf::from(path)));
+        let flake = source("/home/user/project/flake.nix");
+        assert_eq!(
+            flake,
+            NixSource::Flake {
+                reference: String::from("."),
+                dir: PathBuf::from("/home/user/project"),
+            }
+        );
+        assert_eq!(
+            flake.local_flake_dir(),
+            Some(PathBuf::from("/home/user/project"))
+        );
+        // the daemon knows the flake by its `flake.nix`
+        assert_eq!(
+            flake.nix_file(),
+            Some(NixFile::from(PathBuf::from("/home/user/project/flake.nix")))
+        );
+
+        let shell = source("/home/user/project/shell.nix");
+        assert_eq!(shell.local_flake_dir(), None);
+
+        let remote = NixSource::Flake {
+            reference: String::from("github:owner/repo#docs"),
+            dir: PathBuf::from("/home/user/project"),
+        };
+        assert_eq!(remote.local_flake_dir(), None);
+        assert_eq!(remote.nix_file(), None);
+    }
+
+    #[test]
+    fn store_directories() {
+        assert_eq!(
+            store_directory(Path::new("/nix/store/abc-source/nix/pkgs.nix")),
+            Some(Path::new("/nix/store/abc-source"))
+        );
+        assert_eq!(store_directory(Path::new("/home/user/flake.nix")), None);
+    }
+
+    #[test]
+    fn inputs_in_the_flake_copy() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let project = tmp.path().join("project");
+        let copy = tmp.path().join("nix/store/abc-source");
+        let nixpkgs = tmp.path().join("nix/store/def-source");
+        for dir in &[&project, &copy, &nixpkgs] {
+            fs::create_dir_all(dir.join("nix"))?;
+            fs::write(dir.join("nix/pkgs.nix"), "{}")?;
+        }
+        fs::write(project.join("flake.nix"), "{ outputs = _: {}; }")?;
+        fs::write(copy.join("flake.nix"), "{ outputs = _: {}; }")?;
+        fs::write(nixpkgs.join("flake.nix"), "{ outputs = _: { lib = {}; }; }")?;
+
+        let evaluated: Vec<PathBuf> = vec![
+            "/nix/store/abc-source/flake.nix",
+            "/nix/store/abc-source/nix/pkgs.nix",
+            "/nix/store/abc-source/nix/missing.nix",
+            "/nix/store/def-source/nix/pkgs.nix",
+            "/home/user/elsewhere.nix",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(
+            local_inputs_in(tmp.path(), &evaluated, &project),
+            vec![project.join("flake.nix"), project.join("nix/pkgs.nix")]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! Glob patterns for paths, like the ones of `.gitignore`:
+//!
+//! - `*` matches any characters but `/`, `?` any single one
+//! - `**` matches any number of directories (`**/`, `/**/`, `/**`)
+//! - a pattern without `/` (except at the end) matches the name of a
+//!   file or directory anywhere, otherwise the path relative to the
+//!   root (a leading `/` changes nothing)
+//!
+//! A pattern which matches a directory matches everything below it.
+//! `Rules` combines patterns like the lines of a `.gitignore`.
+
+use regex::Regex;
+use std::path::{Component, Path};
+
+/// A compiled glob pattern, see the module documentation.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    pattern: String,
+    regex: Regex,
+}
+
+impl PartialEq for Glob {
+    fn eq(&self, other: &Glob) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl Glob {
+    /// Compile `pattern`.
+    pub fn new(pattern: &str) -> Result<Glob, String> {
+        let trimmed = pattern.trim_end_matches('/');
+        if trimmed.is_empty() {
+            return Err(format!("invalid glob `{}`: it is empty", pattern));
+        }
+        let anchored = trimmed.contains('/');
+        let trimmed = trimmed.trim_start_matches('/');
+
+        let mut regex = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+        let mut rest = trimmed;
+        while let Some(c) = rest.chars().next() {
+            if rest.starts_with("**/") {
+                regex.push_str("(?:.*/)?");
+                rest = &rest[3..];
+            } else if rest.starts_with("**") {
+                regex.push_str(".*");
+                rest = &rest[2..];
+            } else {
+                match c {
+                    '*' => regex.push_str("[^/]*"),
+                    '?' => regex.push_str("[^/]"),
+                    c => regex.push_str(&regex::escape(&c.to_string())),
+                }
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        regex.push('$');
+
+        Ok(Glob {
+            pattern: pattern.to_string(),
+            regex: Regex::new(&regex).map_err(|e| format!("invalid glob `{}`: {}", pattern, e))?,
+        })
+    }
+
+    /// The pattern as written.
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether `path`, relative to the root of the pattern, or one of
+    /// the directories it is in matches.
+    pub fn matches(&self, path: &Path) -> bool {
+        let mut prefix = String::new();
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => name.to_string_lossy(),
+                // only relative, normalized paths can match
+                _ => return false,
+            };
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(&name);
+            if self.regex.is_match(&prefix) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Ordered ignore rules, like the lines of a `.gitignore`: the last
+/// rule matching a path decides, and rules starting with `!`
+/// re-include paths which earlier rules ignore.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rules(Vec<Rule>);
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    glob: Glob,
+    /// Whether matching paths are ignored (or re-included).
+    ignore: bool,
+    /// Whether the rule only applies to the contents of directories,
+    /// not to paths named explicitly (see `Rules::is_ignored`).
+    contents_only: bool,
+}
+
+impl Rules {
+    /// Add `rule`, a glob pattern which ignores the paths it matches
+    /// or, prefixed with `!`, re-includes them (a leading `\`
+    /// escapes a literal `!` or `#`).
+    pub fn add(&mut self, rule: &str) -> Result<(), String> {
+        self.push(rule, false)
+    }
+
+    /// Add the rules of a `.gitignore` file (invalid ones are
+    /// skipped). They only apply to the contents of directories.
+    pub fn add_gitignore(&mut self, contents: &str) {
+        for line in contents.lines().map(str::trim_end) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(e) = self.push(line, true) {
+                debug!("skipping .gitignore rule: {}", e);
+            }
+        }
+    }
+
+    /// Like `add`, for a rule which only applies to the contents of
+    /// directories.
+    pub fn add_for_contents(&mut self, rule: &str) -> Result<(), String> {
+        self.push(rule, true)
+    }
+
+    fn push(&mut self, rule: &str, contents_only: bool) -> Result<(), String> {
+        let (pattern, ignore) = if rule.starts_with('!') {
+            (&rule[1..], false)
+        } else if rule.starts_with("\\!") || rule.starts_with("\\#") {
+            (&rule[1..], true)
+        } else {
+            (rule, true)
+        };
+        self.0.push(Rule {
+            glob: Glob::new(pattern)?,
+            ignore,
+            contents_only,
+        });
+        Ok(())
+    }
+
+    /// Whether `path` (relative to the root of the rules) is ignored.
+    /// A path which is named explicitly, rather than found in a
+    /// directory (`in_directory`), is only ignored by rules which
+    /// were not added for contents.
+    pub fn is_ignored(&self, path: &Path, in_directory: bool) -> bool {
+        self.0
+            .iter()
+            .rev()
+            .filter(|rule| in_directory || !rule.contents_only)
+            .find(|rule| rule.glob.matches(path))
+            .map_or(false, |rule| rule.ignore)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Glob, Rules};
+    use std::path::Path;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Glob::new(pattern).unwrap().matches(Path::new(path))
+    }
+
+    #[test]
+    fn globs() {
+        // names match anywhere, and everything below them
+        assert!(matches("*.log", "build.log"));
+        assert!(matches("*.log", "logs/build.log"));
+        assert!(!matches("*.log", "build.log.gz"));
+        assert!(matches("target/", "target/debug/lorri"));
+        assert!(matches("result*", "sub/result-dev"));
+        assert!(matches("?.tmp", "a.tmp"));
+        assert!(!matches("?.tmp", "ab.tmp"));
+
+        // paths match relative to the root
+        assert!(matches("docs/*.md", "docs/index.md"));
+        assert!(!matches("docs/*.md", "src/docs/index.md"));
+        assert!(matches("/build", "build/out"));
+        assert!(!matches("/build", "src/build"));
+        assert!(matches("**/gen/*.rs", "src/gen/a.rs"));
+        assert!(matches("**/gen/*.rs", "gen/a.rs"));
+        assert!(matches("docs/**", "docs/a/b/c.md"));
+        assert!(matches("a/**/z", "a/b/c/z"));
+
+        // regex characters are literal
+        assert!(matches("a+b.txt", "a+b.txt"));
+        assert!(!matches("a+b.txt", "aab.txt"));
+        assert!(!matches("*.log", "../x.log"));
+        assert!(Glob::new("/").is_err());
+    }
+
+    #[test]
+    fn rules() {
+        let mut rules = Rules::default();
+        rules.add_gitignore("# build output\n\ntarget/\n*.log  \n!keep.log\n\\#notes\n");
+        rules.add("!target/doc").unwrap();
+        rules.add("secret.nix").unwrap();
+
+        assert!(rules.is_ignored(Path::new("target/debug/lorri"), true));
+        assert!(rules.is_ignored(Path::new("logs/build.log"), true));
+        assert!(!rules.is_ignored(Path::new("keep.log"), true));
+        assert!(rules.is_ignored(Path::new("#notes"), true));
+        assert!(!rules.is_ignored(Path::new("target/doc/index.html"), true));
+        assert!(!rules.is_ignored(Path::new("src/main.rs"), true));
+
+        // files named explicitly are only ignored by other rules
+        assert!(!rules.is_ignored(Path::new("build.log"), false));
+        assert!(rules.is_ignored(Path::new("secret.nix"), false));
+    }
+}
@@ -0,0 +1,206 @@
+//! Run the user’s shell commands when builds start, complete or
+//! fail (see `project::config::HooksConfig`), for desktop
+//! notifications, status bars or chat alerts without consuming the
+//! event stream.
+//!
+//! A hook runs with `sh -c` in the project directory, and learns
+//! about the event from its environment:
+//!
+//! - `LORRI_EVENT`: `started`, `completed` or `failure`
+//! - `LORRI_PROJECT`: the nix file of the project (or its expression
+//!   or flake, see `NixSource`)
+//! - `LORRI_BUILD_ID` and `LORRI_TIME` (RFC 3339), like the fields of
+//!   the events’ JSON lines (see `event_sink`)
+//! - `LORRI_LATENCY_MS` (`started`, if a change started the build)
+//! - `LORRI_SHELL_GC_ROOT` (`completed`)
+//! - `LORRI_LOG_TAIL`: the last `LOG_TAIL_LINES` lines of the
+//!   build’s log, and `LORRI_ARTIFACTS` if it has some (`failure`)
+//!
+//! Hooks are best-effort like event sinks: failures are logged, and
+//! never hold up the build loop.
+
+use crate::build_loop::Event;
+use crate::event_sink::name_of;
+use crate::fds;
+use crate::project::config::{rfc3339, HooksConfig};
+use crate::NixSource;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// How many lines of a failed build’s log are in `LORRI_LOG_TAIL`.
+pub const LOG_TAIL_LINES: usize = 10;
+
+/// The command to run for `event`: the project’s hook, or else the
+/// global one.
+fn command_for<'a>(
+    project: &'a HooksConfig,
+    global: &'a HooksConfig,
+    event: &Event,
+) -> Option<&'a str> {
+    let hook = |hooks: &'a HooksConfig| match event {
+        Event::Started(..) => Some(&hooks.started),
+        Event::Completed(..) => Some(&hooks.completed),
+        Event::Failure(..) => Some(&hooks.failure),
+        _ => None,
+    };
+    [hook(project)?, hook(global)?]
+        .iter()
+        .map(|command| command.trim())
+        .find(|command| !command.is_empty())
+}
+
+/// The environment of the hook for `event` of `source`.
+fn environment(source: &NixSource, event: &Event) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("LORRI_EVENT", name_of(event).to_owned()),
+        ("LORRI_PROJECT", source.to_string()),
+    ];
+    if let Some(build) = event.build() {
+        env.push(("LORRI_BUILD_ID", build.to_string()));
+    }
+    if let Some(time) = event.time() {
+        env.push(("LORRI_TIME", rfc3339(time)));
+    }
+    match event {
+        Event::Started(_, _, Some(latency)) => {
+            env.push(("LORRI_LATENCY_MS", latency.as_millis().to_string()))
+        }
+        Event::Completed(_, _, result) => env.push((
+            "LORRI_SHELL_GC_ROOT",
+            result.output_paths.shell_gc_root.to_string(),
+        )),
+        Event::Failure(_, _, failure) => {
+            let skip = failure.log_lines.len().saturating_sub(LOG_TAIL_LINES);
+            let tail: Vec<String> = failure.log_lines[skip..]
+                .iter()
+                .map(|line| line.to_string_lossy().into_owned())
+                .collect();
+            env.push(("LORRI_LOG_TAIL", tail.join("\n")));
+            if let Some(ref artifacts) = failure.artifacts {
+                env.push(("LORRI_ARTIFACTS", artifacts.display().to_string()));
+            }
+        }
+        _ => (),
+    }
+    env
+}
+
+/// Run the hook for `event` of the build loop of `source`, if the
+/// project (or else the global configuration) has one. Returns
+/// without waiting for it to finish.
+pub fn run(
+    project: &HooksConfig,
+    global: &HooksConfig,
+    project_dir: &Path,
+    source: &NixSource,
+    event: &Event,
+) {
+    let command = match command_for(project, global, event) {
+        Some(command) => command,
+        None => return,
+    };
+    let mut cmd = Command::new("sh");
+    cmd.args(&["-c", command])
+        .current_dir(project_dir)
+        .envs(environment(source, event))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null());
+    fds::restore_soft_limit(&mut cmd);
+    debug!("$ {:?}", cmd);
+    let name = name_of(event);
+    match cmd.spawn() {
+        Err(e) => warn!("could not run the {} hook: {}", name, e),
+        // don’t hold up the build loop for slow hooks
+        Ok(mut child) => {
+            std::thread::spawn(move || match child.wait() {
+                Err(e) => warn!("the {} hook failed: {}", name, e),
+                Ok(status) if !status.success() => {
+                    warn!("the {} hook exited with {}", name, status)
+                }
+                Ok(_) => (),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{command_for, environment};
+    use build_loop::{BuildExitFailure, BuildId, Event};
+    use project::config::HooksConfig;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+    use NixFile;
+
+    #[test]
+    fn project_hooks_take_precedence() {
+        let project = HooksConfig {
+            failure: String::from("project-failed"),
+            ..HooksConfig::default()
+        };
+        let global = HooksConfig {
+            started: String::from("global-started"),
+            failure: String::from("global-failed"),
+            ..HooksConfig::default()
+        };
+        let started = Event::Started(BuildId::from(1), UNIX_EPOCH, None);
+        let failure = Event::Failure(
+            BuildId::from(1),
+            UNIX_EPOCH,
+            BuildExitFailure {
+                log_lines: vec![],
+                artifacts: None,
+            },
+        );
+        assert_eq!(
+            command_for(&project, &global, &started),
+            Some("global-started")
+        );
+        assert_eq!(
+            command_for(&project, &global, &failure),
+            Some("project-failed")
+        );
+        assert_eq!(
+            command_for(&project, &global, &Event::Cancelled(BuildId::from(1))),
+            None
+        );
+    }
+
+    #[test]
+    fn event_details_in_the_environment() {
+        let source = NixFile::from(PathBuf::from("/project/shell.nix")).into();
+        let started = Event::Started(
+            BuildId::from(3),
+            UNIX_EPOCH + Duration::from_secs(1_577_836_800),
+            Some(Duration::from_millis(250)),
+        );
+        assert_eq!(
+            environment(&source, &started),
+            vec![
+                ("LORRI_EVENT", String::from("started")),
+                ("LORRI_PROJECT", String::from("/project/shell.nix")),
+                ("LORRI_BUILD_ID", String::from("3")),
+                ("LORRI_TIME", String::from("2020-01-01T00:00:00.000Z")),
+                ("LORRI_LATENCY_MS", String::from("250")),
+            ]
+        );
+
+        let failure = Event::Failure(
+            BuildId::from(3),
+            UNIX_EPOCH,
+            BuildExitFailure {
+                log_lines: (0..15).map(|n| format!("line {}", n).into()).collect(),
+                artifacts: Some(PathBuf::from("/tmp/artifacts")),
+            },
+        );
+        let env = environment(&source, &failure);
+        let tail = &env
+            .iter()
+            .find(|(name, _)| *name == "LORRI_LOG_TAIL")
+            .unwrap()
+            .1;
+        assert!(tail.starts_with("line 5\n"), "{}", tail);
+        assert!(tail.ends_with("line 14"), "{}", tail);
+        assert!(env.contains(&("LORRI_ARTIFACTS", String::from("/tmp/artifacts"))));
+    }
+}
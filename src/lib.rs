@@ -19,6 +19,7 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate toml;
 
 extern crate futures;
 extern crate notify;
@@ -27,27 +28,47 @@ extern crate vec1;
 
 extern crate proptest;
 
+pub mod backfill;
 pub mod bash;
+pub mod build_log;
 pub mod build_loop;
 pub mod builder;
+pub mod cachix;
 pub mod cas;
 pub mod changelog;
 pub mod cli;
+pub mod client_gen;
+pub mod clock;
+pub mod config;
+pub mod config_error;
 pub mod constants;
 pub mod daemon;
+pub mod derivation;
+pub mod event_sink;
+pub mod event_stream;
+pub mod fds;
+pub mod flake;
+pub mod glob;
+pub mod hooks;
 pub mod locate_file;
 pub mod logging;
 pub mod mpsc;
 pub mod nix;
+pub mod notification;
 pub mod ops;
 pub mod osstrlines;
 pub mod pathreduction;
 pub mod project;
+pub mod push;
+pub mod read_trace;
+pub mod skew;
 pub mod socket;
+#[cfg(feature = "test-fixtures")]
+pub mod test_fixtures;
 pub mod thread;
 pub mod watch;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 // OUT_DIR and build_rev.rs are generated by cargo, see ../build.rs
 include!(concat!(env!("OUT_DIR"), "/build_rev.rs"));
@@ -81,3 +102,127 @@ impl From<PathBuf> for NixFile {
         NixFile(p)
     }
 }
+
+/// What a project builds: a .nix file, an inline expression, or the
+/// development shell of a flake.
+#[derive(Hash, PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub enum NixSource {
+    /// A .nix file; its directory is the project directory.
+    File(NixFile),
+    /// A nix expression, like
+    /// `with import <nixpkgs> {}; mkShell {}`.
+    Expression {
+        /// The expression.
+        expression: String,
+        /// The project directory.
+        dir: PathBuf,
+    },
+    /// A flake reference, like `.#docs` or `github:owner/repo`;
+    /// relative paths are relative to `dir`. Without an attribute,
+    /// the default `devShell` of the flake.
+    Flake {
+        /// The flake reference.
+        reference: String,
+        /// The project directory.
+        dir: PathBuf,
+    },
+}
+
+impl NixSource {
+    /// `nix_file`, or the default development shell of the flake in
+    /// its directory if it is a `flake.nix`.
+    pub fn from_nix_file(nix_file: NixFile) -> NixSource {
+        let flake_dir = {
+            let path = Path::new(nix_file.as_os_str());
+            match path.parent() {
+                Some(dir) if path.file_name() == Some(flake::FLAKE_FILE_NAME.as_ref()) => {
+                    Some(dir.to_owned())
+                }
+                _ => None,
+            }
+        };
+        match flake_dir {
+            Some(dir) => NixSource::Flake {
+                reference: String::from("."),
+                dir,
+            },
+            None => NixSource::File(nix_file),
+        }
+    }
+
+    /// The directory of the flake, if this is a flake on the local
+    /// file system (like `.` or `../other#shell`).
+    pub fn local_flake_dir(&self) -> Option<PathBuf> {
+        let (reference, dir) = match self {
+            NixSource::Flake { reference, dir } => (reference, dir),
+            _ => return None,
+        };
+        let flake = reference.split('#').next().unwrap_or_default();
+        if flake.is_empty() || flake == "." {
+            Some(dir.clone())
+        } else if flake.starts_with("./") || flake.starts_with("../") || flake.starts_with('/') {
+            Some(dir.join(flake))
+        } else {
+            None
+        }
+    }
+
+    /// The project directory.
+    pub fn dir(&self) -> &Path {
+        match self {
+            NixSource::File(nix_file) => Path::new(nix_file.as_os_str())
+                .parent()
+                .unwrap_or_else(|| Path::new("/")),
+            NixSource::Expression { dir, .. } | NixSource::Flake { dir, .. } => dir,
+        }
+    }
+
+    /// The .nix file which names the project: the nix file, or the
+    /// `flake.nix` of a local flake. Clients of the daemon name
+    /// projects by it.
+    pub fn nix_file(&self) -> Option<NixFile> {
+        match self {
+            NixSource::File(nix_file) => Some(nix_file.clone()),
+            _ => self
+                .local_flake_dir()
+                .map(|dir| NixFile::from(dir.join(flake::FLAKE_FILE_NAME))),
+        }
+    }
+
+    /// Bytes which identify the source, for naming its roots: the
+    /// path of a file (as lorri always did), or the kind of source,
+    /// its directory and its text.
+    pub fn id_bytes(&self) -> Vec<u8> {
+        use std::os::unix::ffi::OsStrExt;
+        let (kind, dir, text) = match self {
+            NixSource::File(nix_file) => return nix_file.as_os_str().as_bytes().to_vec(),
+            NixSource::Expression { expression, dir } => ("expression", dir, expression),
+            NixSource::Flake { reference, dir } => ("flake", dir, reference),
+        };
+        let mut bytes = format!("{}:", kind).into_bytes();
+        bytes.extend(dir.as_os_str().as_bytes());
+        bytes.push(0);
+        bytes.extend(text.as_bytes());
+        bytes
+    }
+}
+
+impl std::fmt::Display for NixSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NixSource::File(nix_file) => nix_file.fmt(f),
+            NixSource::Expression { expression, dir } => {
+                write!(f, "expression `{}` in {}", expression, dir.display())
+            }
+            NixSource::Flake { reference, dir } => {
+                write!(f, "flake {} in {}", reference, dir.display())
+            }
+        }
+    }
+}
+
+impl From<NixFile> for NixSource {
+    fn from(nix_file: NixFile) -> NixSource {
+        NixSource::File(nix_file)
+    }
+}
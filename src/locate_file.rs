@@ -2,7 +2,7 @@
 
 use std::env;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// Error conditions encountered when hunting for a file on disk
 #[derive(Debug)]
@@ -32,9 +32,26 @@ pub fn in_cwd(name: &PathBuf) -> Result<PathBuf, FileLocationError> {
     }
 }
 
+/// The path of the nix file `path` as `lorri direnv` pings the
+/// daemon with it, from a shell in the file’s directory: the
+/// current directory (which has no symlinks) joined with the file
+/// name, which stays as it is, even if it is a symlink.
+pub fn as_pinged(path: &Path) -> io::Result<PathBuf> {
+    let path = env::current_dir()?.join(path);
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) if path.is_file() => Ok(dir.canonicalize()?.join(name)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a file", path.display()),
+        )),
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{in_cwd, FileLocationError};
+    use super::{as_pinged, in_cwd, FileLocationError};
+    use std::fs;
+    use std::os::unix::fs::symlink;
     use std::path::Path;
     use std::path::PathBuf;
 
@@ -57,4 +74,26 @@ mod tests {
             _ => panic!("unexpected result: {:?}", result),
         }
     }
+
+    #[test]
+    fn resolve_the_directory_not_the_file() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().canonicalize()?;
+        fs::create_dir(dir.join("project"))?;
+        fs::write(dir.join("project/default.nix"), "")?;
+        symlink("default.nix", dir.join("project/shell.nix"))?;
+        symlink("project", dir.join("link"))?;
+
+        assert_eq!(
+            as_pinged(&dir.join("link/shell.nix"))?,
+            dir.join("project/shell.nix")
+        );
+        assert_eq!(
+            as_pinged(&dir.join("project/../link/./shell.nix"))?,
+            dir.join("project/shell.nix")
+        );
+        assert!(as_pinged(&dir.join("project")).is_err());
+        assert!(as_pinged(&dir.join("project/missing.nix")).is_err());
+        Ok(())
+    }
 }
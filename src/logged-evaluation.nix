@@ -1,4 +1,31 @@
-{ src, runTimeClosure }:
+{ src, runTimeClosure
+# what to do with the `shellHook` while building the environment
+# (see `ShellHookConfig`): "run", "skip", or "replace" it with
+# `shellHookReplacement`. The `shellHook` variable itself is always
+# kept in the environment.
+, shellHookMode ? "run"
+, shellHookReplacement ? ""
+# "true" to run the hook with a throwaway HOME (and XDG directories)
+# and proxies which lead nowhere, so that it can't change the
+# dotfiles of the user building it; tools ignoring the proxies still
+# reach the network, unless the nix build sandbox cuts it off
+, shellHookSandbox ? "false"
+# named shells (see `ShellConfig`), as a JSON list of
+# `{ name, attribute }`: build the attribute path (a list of names)
+# of the evaluated attribute set for each of them, instead of the
+# evaluated shell.
+# The result is an attribute set by name, so nix-build prints the
+# shells sorted by name.
+, shells ? "[]"
+# arguments for the function in `src` (`--arg` and `--argstr` of
+# lorri); like nix-build, only the ones it takes are passed
+, shellArgs ? {}
+# the attribute path of the evaluated attribute set to build, as a
+# JSON list of names (like `["devShells","backend"]`, names may
+# contain dots), instead of all of it; the attributes of named
+# shells are relative to it
+, attribute ? "[]"
+}:
 let
   runtimeCfg = import runTimeClosure;
 
@@ -17,7 +44,7 @@ let
     let
       raw = overrides.scopedImport overrides src;
     in if (builtins.isFunction raw)
-    then raw {}
+    then raw (builtins.intersectAttrs (builtins.functionArgs raw) shellArgs)
     else raw;
 
   # If you add a .drv to a gc-root, the `.drv` itself is protected
@@ -31,6 +58,38 @@ let
   # gc rooting the resulting store path from this build will retain
   # references to all the store paths needed, preventing the shell's
   # actual environment from being deleted.
+  shellHook = {
+    run = "runHook shellHook";
+    skip = ":";
+    replace = ''eval "$lorriShellHookReplacement"'';
+  }.${shellHookMode};
+
+  # bash restores variables assigned for a function call once it
+  # returns, while the variables the hook exports are kept. (No
+  # trailing newline, it is followed by a `;` below.)
+  sandboxed = hook: ''
+    lorri_sandbox="$(mktemp -d)"
+    lorri_shell_hook() {
+      ${hook}
+    }
+    HOME="$lorri_sandbox" \
+      XDG_CONFIG_HOME="$lorri_sandbox/.config" \
+      XDG_CACHE_HOME="$lorri_sandbox/.cache" \
+      XDG_DATA_HOME="$lorri_sandbox/.local/share" \
+      XDG_STATE_HOME="$lorri_sandbox/.local/state" \
+      http_proxy=http://127.0.0.1:9 https_proxy=http://127.0.0.1:9 \
+      HTTP_PROXY=http://127.0.0.1:9 HTTPS_PROXY=http://127.0.0.1:9 \
+      all_proxy=http://127.0.0.1:9 ALL_PROXY=http://127.0.0.1:9 \
+      no_proxy= NO_PROXY= \
+      lorri_shell_hook
+    unset -f lorri_shell_hook
+    rm -rf "$lorri_sandbox"
+    unset lorri_sandbox'';
+
+  runShellHook = if shellHookSandbox == "true" && shellHookMode != "skip"
+    then sandboxed shellHook
+    else shellHook;
+
   keep-env-hack = drv: derivation (drv.drvAttrs // {
     name = "lorri-keep-env-hack-${drv.name}";
 
@@ -126,7 +185,7 @@ let
       # target/lorri#23
       # https://github.com/NixOS/nix/blob/bfc6bdf222d00d3cb1b0e168a5d55d1a7c9cdb72/src/nix-build/nix-build.cc#L424
       if [ "$(type -t runHook)" = function ]; then
-       runHook shellHook;
+       ${runShellHook};
       fi;
 
       export > $out/bash-export
@@ -135,8 +194,23 @@ let
     # Because it’s a trivially lightweight drv, we should never substitute.
     preferLocalBuild = true;
     allowSubstitutes = false;
-  });
+  } // (if shellHookMode == "replace"
+    then { lorriShellHookReplacement = shellHookReplacement; }
+    else {}));
+
+  attrByPathOf = root: builtins.foldl' (value: name: value.${name}) root;
+
+  selected = attrByPathOf imported (builtins.fromJSON attribute);
+
+  attrByPath = attrByPathOf selected;
+
+  namedShells = builtins.fromJSON shells;
 
-  gc-root = keep-env-hack imported;
+  gc-root = if namedShells == []
+    then keep-env-hack selected
+    else builtins.listToAttrs (map (shell: {
+      inherit (shell) name;
+      value = keep-env-hack (attrByPath shell.attribute);
+    }) namedShells);
 
 in gc-root
@@ -3,9 +3,20 @@
 //!
 //! Note this is only a default, and the environment variable
 //! RUST_LOG will override it.
+//!
+//! Besides stderr, the log can go to a file which is rotated by size
+//! or daily (see `LogFile`), for daemons not run by a service manager.
 
 use env_logger;
+use log::{Log, Metadata, Record};
+use project::config::{utc_date, utc_timestamp};
 use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
 
 /// Potentially set the RUST_LOG environment, and configure env_logger
 /// based on if RUST_LOG is set already.
@@ -14,15 +25,208 @@ use std::env;
 /// investigate something specific. However, we also want a useful
 /// `-v` option as a quick shortcut.
 pub fn init_with_default_log_level(verbosity: u8) {
-    let requested_level = level_from_verbosity(verbosity);
+    init(verbosity, None, true).expect("logging to stderr can't fail")
+}
 
-    if env::var_os("RUST_LOG").is_none() {
+/// Like `init_with_default_log_level`, but also log to `log_file`
+/// (if given), and to stderr only if `stderr` is set.
+///
+/// Fails if the log file can’t be opened.
+pub fn init(verbosity: u8, log_file: Option<&LogFile>, stderr: bool) -> io::Result<()> {
+    let requested_level = level_from_verbosity(verbosity);
+    let rust_log_set = env::var_os("RUST_LOG").is_some();
+    if !rust_log_set {
         env::set_var("RUST_LOG", requested_level);
-        env_logger::init();
-        info!("Setting RUST_LOG to {}", requested_level);
-    } else {
+    }
+
+    let file = match log_file {
+        Some(log_file) => Some(Mutex::new(RotatingFile::open(log_file.clone())?)),
+        None => None,
+    };
+    let logger = Logger {
+        filter: env_logger::Builder::from_default_env().build(),
+        stderr,
+        file,
+    };
+    log::set_max_level(logger.filter.filter());
+    log::set_boxed_logger(Box::new(logger)).expect("the logger was already set");
+
+    if rust_log_set {
         warn!("RUST_LOG is already set, ignoring -v options");
-        env_logger::init();
+    } else {
+        info!("Setting RUST_LOG to {}", requested_level);
+    }
+    Ok(())
+}
+
+/// Logs the records `filter` lets through to stderr and/or a file.
+struct Logger {
+    /// Filters by RUST_LOG, and writes to stderr.
+    filter: env_logger::Logger,
+    stderr: bool,
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        if self.stderr {
+            self.filter.log(record);
+        }
+        if let Some(ref file) = self.file {
+            let now = SystemTime::now();
+            let line = format!(
+                "{} {:<5} {}: {}\n",
+                utc_timestamp(now),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            // a poisoned lock means a panic while logging, nothing
+            // to report it to but stderr
+            if let Ok(mut file) = file.lock() {
+                if let Err(e) = file.write_at(line.as_bytes(), now) {
+                    eprintln!("lorri: cannot write the log file: {}", e);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.filter.flush();
+    }
+}
+
+/// A log file, rotated to `<path>.1`, `<path>.2`, … (the oldest).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFile {
+    /// The file currently logged to.
+    pub path: PathBuf,
+    /// When to start a new file.
+    pub rotation: Rotation,
+    /// How many rotated files to keep.
+    pub keep: usize,
+}
+
+/// When a `LogFile` is rotated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Before it grows beyond this many bytes.
+    Size(u64),
+    /// On the first line logged on a new day (UTC).
+    Daily,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    /// `daily`, or a size in bytes with an optional `K`, `M` or `G`
+    /// suffix, like `10M`.
+    fn from_str(s: &str) -> Result<Rotation, String> {
+        if s == "daily" {
+            return Ok(Rotation::Daily);
+        }
+        let (number, factor) = match s.chars().last() {
+            Some('K') => (&s[..s.len() - 1], 1 << 10),
+            Some('M') => (&s[..s.len() - 1], 1 << 20),
+            Some('G') => (&s[..s.len() - 1], 1 << 30),
+            _ => (s, 1),
+        };
+        match number.parse::<u64>() {
+            Ok(size) if size > 0 => size
+                .checked_mul(factor)
+                .map(Rotation::Size)
+                .ok_or_else(|| format!("rotation size `{}` is too large", s)),
+            _ => Err(format!(
+                "unknown rotation `{}`, use daily or a size like 10M",
+                s
+            )),
+        }
+    }
+}
+
+/// The open file of a `LogFile`.
+struct RotatingFile {
+    config: LogFile,
+    file: File,
+    /// Bytes in `file`.
+    size: u64,
+    /// The day (see `utc_date`) `file` was last written on.
+    date: String,
+}
+
+impl RotatingFile {
+    /// Open the log file, appending to it if it exists.
+    fn open(config: LogFile) -> io::Result<RotatingFile> {
+        if let Some(dir) = config.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let metadata = file.metadata()?;
+        Ok(RotatingFile {
+            size: metadata.len(),
+            date: utc_date(metadata.modified().unwrap_or_else(|_| SystemTime::now())),
+            config,
+            file,
+        })
+    }
+
+    /// Append `line` at `now`, rotating the file first if it is due.
+    fn write_at(&mut self, line: &[u8], now: SystemTime) -> io::Result<()> {
+        let today = utc_date(now);
+        let due = match self.config.rotation {
+            Rotation::Size(max) => self.size > 0 && self.size + line.len() as u64 > max,
+            Rotation::Daily => self.size > 0 && today != self.date,
+        };
+        if due {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        self.date = today;
+        Ok(())
+    }
+
+    /// Shift the rotated files by one, dropping the oldest, move the
+    /// current file to `<path>.1` and start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        let rotated = |n: usize| -> PathBuf {
+            let mut name = path.as_os_str().to_owned();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        remove_if_exists(&rotated(self.config.keep))?;
+        for n in (1..self.config.keep).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        if self.config.keep > 0 {
+            fs::rename(path, rotated(1))?;
+        } else {
+            fs::remove_file(path)?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Remove the file at `path`, unless it doesn’t exist.
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
     }
 }
 
@@ -38,7 +242,9 @@ fn level_from_verbosity(verbosity: u8) -> &'static str {
 
 #[cfg(test)]
 mod tests {
-    use super::level_from_verbosity;
+    use super::{level_from_verbosity, LogFile, RotatingFile, Rotation};
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
 
     #[test]
     fn test_level_from_verbosity() {
@@ -46,4 +252,54 @@ mod tests {
         assert_eq!(level_from_verbosity(3), "debug");
         assert_eq!(level_from_verbosity(19), "debug");
     }
+
+    #[test]
+    fn parse_rotation() {
+        assert_eq!("daily".parse(), Ok(Rotation::Daily));
+        assert_eq!("512".parse(), Ok(Rotation::Size(512)));
+        assert_eq!("10M".parse(), Ok(Rotation::Size(10 << 20)));
+        assert!("0K".parse::<Rotation>().is_err());
+        assert!("weekly".parse::<Rotation>().is_err());
+        assert_eq!(
+            "18014398509481984K".parse::<Rotation>(),
+            Err(String::from(
+                "rotation size `18014398509481984K` is too large"
+            ))
+        );
+    }
+
+    #[test]
+    fn rotate_by_size_and_day() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let path = tmp.path().join("logs/lorri.log");
+        let day = |n: u64| UNIX_EPOCH + Duration::from_secs(n * 86_400);
+        let read = |suffix: &str| fs::read_to_string(format!("{}{}", path.display(), suffix));
+
+        let mut file = RotatingFile::open(LogFile {
+            path: path.clone(),
+            rotation: Rotation::Size(8),
+            keep: 2,
+        })?;
+        for line in ["a\n", "b\n", "c\n", "d\n", "e\n", "f\n", "g\n"].iter() {
+            file.write_at(line.as_bytes(), day(0))?;
+        }
+        // four lines fit into 8 bytes, the oldest file was dropped
+        assert_eq!(read("")?, "e\nf\ng\n");
+        assert_eq!(read(".1")?, "a\nb\nc\nd\n");
+        assert!(read(".2").is_err());
+
+        let mut file = RotatingFile::open(LogFile {
+            path: path.clone(),
+            rotation: Rotation::Daily,
+            keep: 2,
+        })?;
+        file.write_at(b"h\n", day(20_000))?;
+        file.write_at(b"i\n", day(20_000))?;
+        file.write_at(b"j\n", day(20_001))?;
+        // the file was last written today, not on day 20000
+        assert_eq!(read("")?, "j\n");
+        assert_eq!(read(".1")?, "h\ni\n");
+        assert_eq!(read(".2")?, "e\nf\ng\n");
+        Ok(())
+    }
 }
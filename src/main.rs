@@ -1,16 +1,27 @@
 extern crate lorri;
+extern crate serde_json;
 extern crate structopt;
 #[macro_use]
 extern crate log;
 
 use lorri::constants;
+use lorri::event_stream::Since;
+use lorri::flake;
 use lorri::locate_file;
-use lorri::NixFile;
+use lorri::logging::LogFile;
+use lorri::{NixFile, NixSource};
 
-use lorri::cli::{Arguments, Command};
-use lorri::ops::{daemon, direnv, info, init, ping, upgrade, watch, ExitError, OpResult};
+use lorri::cli::{Arguments, Command, Internal_};
+use lorri::ops::{
+    build, cancel, check, daemon, direnv, direnv_hook_check, gc, gen_client, ide_env, info, init,
+    install_git_hooks, install_service, list_projects, logs, migrate, ping, porcelain,
+    print_record, refresh_shells, register, root_check, self_test, set_porcelain, show_eval_expr,
+    stats, status, stop_daemon, stream_events, upgrade, wait_idle, watch, ExitError, OpResult,
+};
+use lorri::project::config::ProjectConfig;
 use lorri::project::Project;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
 
 const TRIVIAL_SHELL_SRC: &str = include_str!("./trivial-shell.nix");
@@ -20,28 +31,81 @@ fn main() {
     let exit = |result: OpResult| match result {
         Err(err) => {
             eprintln!("{}", err.message());
+            if porcelain() {
+                println!(
+                    "{}",
+                    serde_json::json!({
+                        "result": "error",
+                        "exit_code": err.exitcode(),
+                        "message": err.message(),
+                    })
+                );
+            }
             std::process::exit(err.exitcode());
         }
         Ok(Some(msg)) => {
-            println!("{}", msg);
+            print_record(&msg, serde_json::json!({"result": "ok", "message": msg}));
             std::process::exit(0);
         }
         Ok(None) => {
+            if porcelain() {
+                println!("{}", serde_json::json!({"result": "ok"}));
+            }
             std::process::exit(0);
         }
     };
 
     let opts = Arguments::from_args();
+    set_porcelain(opts.porcelain);
 
-    lorri::logging::init_with_default_log_level(opts.verbosity);
+    if let Err(e) = init_logging(&opts) {
+        exit(Err(e));
+    }
     debug!("Input options: {:?}", opts);
 
     let result = run_command(opts);
     exit(result);
 }
 
+/// Log to stderr, and for the daemon to its `--log-file`.
+fn init_logging(opts: &Arguments) -> Result<(), ExitError> {
+    match opts.command {
+        Command::Daemon(ref daemon) => {
+            let log_file = daemon.log_file.as_ref().map(|path| LogFile {
+                path: path.clone(),
+                rotation: daemon.log_rotate,
+                keep: daemon.log_keep,
+            });
+            lorri::logging::init(opts.verbosity, log_file.as_ref(), !daemon.no_stderr_log)
+                .map_err(|e| ExitError::errmsg(format!("Cannot open the log file: {}", e)))
+        }
+        _ => {
+            lorri::logging::init_with_default_log_level(opts.verbosity);
+            Ok(())
+        }
+    }
+}
+
 /// Try to read `shell.nix` from the current working dir.
 fn get_shell_nix(shellfile: &PathBuf) -> Result<NixFile, ExitError> {
+    // the project configuration can name another default; an
+    // invalid one is reported by the build
+    if shellfile == Path::new("shell.nix") {
+        let configured = std::env::current_dir()
+            .ok()
+            .and_then(|dir| ProjectConfig::load(&dir).ok())
+            .and_then(|config| config.shell_file)
+            .filter(|configured| configured != shellfile);
+        if let Some(configured) = configured {
+            return get_shell_nix(&configured);
+        }
+    }
+    // without a shell.nix, a flake provides the shell
+    if shellfile == Path::new("shell.nix") && !shellfile.exists() {
+        if let Ok(flake) = locate_file::in_cwd(&PathBuf::from(flake::FLAKE_FILE_NAME)) {
+            return Ok(NixFile::from(flake));
+        }
+    }
     // use shell.nix from cwd
     Ok(NixFile::from(locate_file::in_cwd(&shellfile).map_err(
         |_| {
@@ -56,34 +120,179 @@ fn get_shell_nix(shellfile: &PathBuf) -> Result<NixFile, ExitError> {
     )?))
 }
 
+/// The nix file of the project at `path`: a nix file, or a
+/// directory with a `shell.nix` (or a flake). Its path is the one
+/// `lorri direnv` pings the daemon with (see `locate_file::as_pinged`).
+fn project_nix_file(path: &Path) -> Result<NixFile, ExitError> {
+    let nix_file = if path.is_dir() {
+        let shell_nix = path.join("shell.nix");
+        let flake = path.join(flake::FLAKE_FILE_NAME);
+        if !shell_nix.exists() && flake.exists() {
+            flake
+        } else {
+            shell_nix
+        }
+    } else {
+        path.to_owned()
+    };
+    let nix_file = get_shell_nix(&nix_file)?;
+    locate_file::as_pinged(Path::new(nix_file.as_os_str()))
+        .map(NixFile::from)
+        .map_err(|e| ExitError::errmsg(format!("Could not resolve {}: {}", nix_file, e)))
+}
+
 fn create_project(paths: &constants::Paths, shell_nix: NixFile) -> Result<Project, ExitError> {
-    Project::new(shell_nix, &paths.gc_root_dir(), paths.cas_store().clone())
-        .or_else(|_| Err(ExitError::errmsg("Could not set up project paths")))
+    create_project_from(paths, NixSource::File(shell_nix), None)
+}
+
+/// Like `create_project`, for any `NixSource`, and the attribute
+/// path `attribute` of it (see `Project::with_attribute`).
+fn create_project_from(
+    paths: &constants::Paths,
+    source: NixSource,
+    attribute: Option<String>,
+) -> Result<Project, ExitError> {
+    Project::with_attribute(
+        source,
+        attribute,
+        paths.gc_root_dir(),
+        paths.cas_store().clone(),
+    )
+    .map_err(|e| ExitError::errmsg(format!("Could not set up project paths: {}", e)))
+}
+
+/// The inline `expression`, with the current directory as its
+/// project directory. The expression is saved to the CAS for every
+/// build, see `builder::nix_build_args`.
+fn expression_source(expression: String) -> Result<NixSource, ExitError> {
+    let dir = std::env::current_dir()
+        .map_err(|e| ExitError::errmsg(format!("Could not read the current directory: {}", e)))?;
+    Ok(NixSource::Expression { expression, dir })
 }
 
 /// Run the main function of the relevant command.
 fn run_command(opts: Arguments) -> OpResult {
     let paths = lorri::ops::get_paths()?;
     match opts.command {
-        Command::Info(opts) => {
-            get_shell_nix(&opts.nix_file).and_then(|sn| info::main(create_project(&paths, sn)?))
+        Command::Info(opts) => get_shell_nix(&opts.nix_file)
+            .and_then(|sn| info::main(create_project(&paths, sn)?, opts.watch_backend)),
+
+        Command::Direnv(opts) => get_shell_nix(&opts.nix_file).and_then(|sn| {
+            direnv::main(
+                create_project_from(&paths, NixSource::File(sn), opts.attr.clone())?,
+                opts.shell.as_ref().map(String::as_str),
+                opts.json,
+                opts.max_wait,
+            )
+        }),
+
+        Command::Status(opts) => project_nix_file(&opts.path).and_then(status::main),
+
+        Command::Ping(opts) => get_shell_nix(&opts.nix_file)
+            .and_then(|sn| ping::build_main(sn, opts.timeout.map(Duration::from_secs))),
+
+        Command::Build(opts) => {
+            let source = NixSource::File(get_shell_nix(&opts.nix_file)?);
+            let mut project = create_project_from(&paths, source, opts.nix_args.attr.clone())?;
+            project.nix_args = opts.nix_args.options();
+            build::main(project, opts.json)
         }
 
-        Command::Direnv(opts) => {
-            get_shell_nix(&opts.nix_file).and_then(|sn| direnv::main(create_project(&paths, sn)?))
+        Command::Watch(opts) => {
+            let source = match opts.expr.clone() {
+                Some(expression) => expression_source(expression)?,
+                None => NixSource::File(get_shell_nix(&opts.nix_file)?),
+            };
+            let mut project = create_project_from(&paths, source, opts.nix_args.attr.clone())?;
+            project.nix_args = opts.nix_args.options();
+            watch::main(project, opts)
         }
 
-        Command::Watch(opts) => get_shell_nix(&opts.nix_file)
-            .and_then(|sn| watch::main(create_project(&paths, sn)?, opts)),
+        Command::Daemon(opts) => daemon::main(opts),
 
-        Command::Daemon => daemon::main(),
+        Command::Gc(opts) => gc::main(opts.dry_run, opts.older_than),
 
         Command::Upgrade(args) => upgrade::main(args, paths.cas_store()),
 
         // TODO: remove
-        Command::Ping_(p) => ping::main(p.nix_file),
+        Command::Ping_(p) => ping::main(p.nix_file, None),
+
+        Command::Init(opts) => init::main(
+            TRIVIAL_SHELL_SRC,
+            DEFAULT_ENVRC,
+            opts.template_dir.as_ref().map(PathBuf::as_path),
+        ),
 
-        Command::Init => init::main(TRIVIAL_SHELL_SRC, DEFAULT_ENVRC),
+        Command::InstallGitHooks(opts) => {
+            get_shell_nix(&opts.nix_file).and_then(install_git_hooks::main)
+        }
+
+        Command::InstallService(opts) => install_service::main(opts),
+
+        Command::Internal { command } => match command {
+            Internal_::DirenvHookCheck(opts) => get_shell_nix(&opts.nix_file)
+                .and_then(|sn| direnv_hook_check::main(create_project(&paths, sn)?)),
+            Internal_::Check(opts) => get_shell_nix(&opts.nix_file).and_then(check::main),
+            Internal_::IdeEnv(opts) => get_shell_nix(&opts.nix_file)
+                .and_then(|sn| ide_env::main(create_project(&paths, sn)?, opts.format)),
+            Internal_::Cancel(opts) => get_shell_nix(&opts.nix_file).and_then(cancel::main),
+            Internal_::WaitIdle(opts) => match opts.nix_file {
+                None => wait_idle::main(None),
+                Some(nix_file) => get_shell_nix(&nix_file).and_then(|sn| wait_idle::main(Some(sn))),
+            },
+            Internal_::SelfTest(opts) => self_test::main(opts.ephemeral),
+            Internal_::RootCheck(opts) => root_check::main(opts.repair, opts.rebuild),
+            Internal_::StreamEvents(opts) => {
+                let since = opts.since.map(|sequence| Since {
+                    sequence,
+                    epoch: opts.epoch,
+                });
+                let (only, follow, log_lines) = (opts.only, opts.follow, opts.log_lines);
+                match opts.nix_file {
+                    None => stream_events::main(None, since, &only, follow, log_lines),
+                    Some(nix_file) => get_shell_nix(&nix_file).and_then(|sn| {
+                        stream_events::main(Some(sn), since, &only, follow, log_lines)
+                    }),
+                }
+            }
+            Internal_::ListProjects(opts) => list_projects::main(opts.json),
+            Internal_::StopDaemon(opts) => stop_daemon::main(opts.cancel_builds),
+            Internal_::Stats(opts) => stats::main(opts.json),
+            Internal_::Register(opts) => register::main(opts.paths),
+            Internal_::Ping(opts) => get_shell_nix(&opts.nix_file).and_then(|sn| {
+                ping::main(
+                    sn,
+                    if opts.wait_for_daemon {
+                        Some(Duration::from_secs(opts.timeout))
+                    } else {
+                        None
+                    },
+                )
+            }),
+            Internal_::Logs(opts) => {
+                get_shell_nix(&opts.nix_file).and_then(|sn| logs::main(sn, opts.follow))
+            }
+            Internal_::ShowEvalExpr(opts) => get_shell_nix(&opts.nix_file)
+                .and_then(|sn| show_eval_expr::main(create_project(&paths, sn)?)),
+            Internal_::GenClient(opts) => gen_client::main(opts.lang),
+            Internal_::ExportProjects => migrate::export_main(),
+            Internal_::ImportProjects(opts) => migrate::import_main(&opts.file, opts.yes),
+            Internal_::RefreshShells(opts) => {
+                if opts.hook.is_some() || opts.all {
+                    refresh_shells::main(opts.hook, None)
+                } else {
+                    get_shell_nix(&opts.nix_file).and_then(|nix_file| {
+                        let dir = Path::new(nix_file.as_os_str())
+                            .parent()
+                            .and_then(|dir| dir.canonicalize().ok())
+                            .ok_or_else(|| {
+                                ExitError::errmsg(format!("{} has no directory", nix_file))
+                            })?;
+                        refresh_shells::main(None, Some(&dir))
+                    })
+                }
+            }
+        },
     }
 }
 
@@ -104,21 +313,20 @@ mod tests {
             std::str::from_utf8(&out.stderr).unwrap()
         );
         Ok(())
+    }
 
-        // TODO: provide .instantiate(), which does a plain nix-instantiate
-        // and returns the .drv file.
-        // let res = nix::CallOpts::expression(TRIVIAL_SHELL_SRC)
-        //     .instantiate();
-
-        // match res {
-        //     Ok(_drv) => Ok(()),
-        //     Err(nix::InstantiateError::ExecutionFailed(output)) =>
-        //         panic!(
-        //             "stdout:\n{}\nstderr:{}\n",
-        //             std::str::from_utf8(&output.stdout).unwrap(),
-        //             std::str::from_utf8(&output.stderr).unwrap()
-        //         ),
-        //     Err(nix::InstantiateError::Io(io)) => Err(io)
-        // }
+    /// Instantiate the trivial shell file with `CallOpts::instantiate`,
+    /// which `lorri internal check` uses.
+    #[test]
+    fn instantiate_trivial_shell_nix() -> std::io::Result<()> {
+        match lorri::nix::CallOpts::expression(TRIVIAL_SHELL_SRC).instantiate() {
+            Ok(_drv) => Ok(()),
+            Err(lorri::nix::InstantiateError::ExecutionFailed(output)) => panic!(
+                "stdout:\n{}\nstderr:{}\n",
+                std::str::from_utf8(&output.stdout).unwrap(),
+                std::str::from_utf8(&output.stderr).unwrap()
+            ),
+            Err(lorri::nix::InstantiateError::Io(io)) => Err(io),
+        }
     }
 }
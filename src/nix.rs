@@ -32,10 +32,11 @@
 //! }
 //! ```
 
+use fds;
 use osstrlines;
 use serde_json;
 use std::collections::HashMap;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use vec1::Vec1;
@@ -80,6 +81,434 @@ impl From<std::ffi::OsString> for StorePath {
     }
 }
 
+/// The nix store lorri’s nix invocations operate on.
+///
+/// By default nix chooses the store on its own (the daemon on
+/// multi-user installations, the local store otherwise).
+/// `NIX_REMOTE` selects a different store, for example a chroot
+/// store like `local?root=/home/user/nix` for users without
+/// a writable `/nix`. See the `store` setting in `man nix.conf`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Store {
+    /// Let nix decide which store to use.
+    Default,
+    /// An explicit store URI, passed to nix with `--store`.
+    Uri(String),
+}
+
+impl Store {
+    /// Read the store from `NIX_REMOTE`, like nix does.
+    pub fn from_env() -> Store {
+        match std::env::var("NIX_REMOTE") {
+            Ok(ref uri) if !uri.is_empty() => Store::Uri(uri.clone()),
+            _ => Store::Default,
+        }
+    }
+
+    /// Arguments selecting this store for nix commands.
+    pub fn args(&self) -> Vec<&OsStr> {
+        match self {
+            Store::Default => vec![],
+            Store::Uri(uri) => vec![OsStr::new("--store"), OsStr::new(uri)],
+        }
+    }
+
+    /// The root directory of a local chroot store, if this is one.
+    ///
+    /// Both `local?root=/some/dir` and a plain absolute path
+    /// `/some/dir` denote a chroot store.
+    pub fn chroot(&self) -> Option<PathBuf> {
+        let uri = match self {
+            Store::Default => return None,
+            Store::Uri(uri) => uri,
+        };
+        if uri.starts_with('/') {
+            return Some(PathBuf::from(uri));
+        }
+        if !uri.starts_with("local?") {
+            return None;
+        }
+        Self::param(uri, "root").map(PathBuf::from)
+    }
+
+    /// Whether this store keeps its paths and roots where the
+    /// default store does: `auto`, `local` or `daemon` without
+    /// parameters, like nix’s default.
+    pub fn is_default_layout(&self) -> bool {
+        match self {
+            Store::Default => true,
+            Store::Uri(uri) => match uri.as_str() {
+                "auto" | "local" | "daemon" => true,
+                _ => false,
+            },
+        }
+    }
+
+    /// The nix state directory of this store, where nix expects
+    /// to find the `gcroots` directory.
+    pub fn state_dir(&self) -> PathBuf {
+        if let Store::Uri(uri) = self {
+            if let Some(state) = Self::param(uri, "state") {
+                return PathBuf::from(state);
+            }
+        }
+        match self.chroot() {
+            Some(root) => root.join("nix/var/nix"),
+            None => match std::env::var("NIX_STATE_DIR") {
+                Ok(dir) => PathBuf::from(dir),
+                Err(_) => PathBuf::from("/nix/var/nix/"),
+            },
+        }
+    }
+
+    /// The directory of the store paths: the `store` parameter of
+    /// the store URI, `NIX_STORE_DIR`, or `/nix/store`, like nix.
+    pub fn store_dir(&self) -> PathBuf {
+        if let Store::Uri(uri) = self {
+            if let Some(store) = Self::param(uri, "store") {
+                return PathBuf::from(store);
+            }
+        }
+        match std::env::var("NIX_STORE_DIR") {
+            Ok(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from("/nix/store"),
+        }
+    }
+
+    /// Value of the query parameter `name` in a store URI.
+    fn param<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+        let query = uri.splitn(2, '?').nth(1)?;
+        query
+            .split('&')
+            .filter_map(|kv| {
+                let mut kv = kv.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(key), Some(value)) if key == name => Some(value),
+                    _ => None,
+                }
+            })
+            .next()
+    }
+}
+
+/// Settings passed to nix commands with `--option name value`,
+/// overriding the ones from `nix.conf`, entries prepended to
+/// the search path (`NIX_PATH`) with `-I name=path`, arguments
+/// to the evaluated function with `--argstr name value`, and
+/// `--show-trace`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    settings: Vec<(String, String)>,
+    search_path: Vec<OsString>,
+    arguments: Vec<(String, String)>,
+    /// Arguments of the function in the shell file, as nix
+    /// expressions, by name.
+    shell_args: Vec<(String, String)>,
+    /// The names of the attribute path to build (see `attribute`).
+    attribute: Option<Vec<String>>,
+    show_trace: bool,
+    trace_reads: bool,
+    detach: bool,
+    shells: Vec<String>,
+}
+
+impl Options {
+    /// No settings.
+    pub fn new() -> Options {
+        Options::default()
+    }
+
+    /// Set the nix setting `name` to `value`.
+    pub fn set(&mut self, name: &str, value: &str) -> &mut Self {
+        self.settings.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Make `<name>` refer to `path` in nix expressions.
+    pub fn include(&mut self, name: &str, path: &Path) -> &mut Self {
+        let mut entry = OsString::from(format!("{}=", name));
+        entry.push(path);
+        self.search_path.push(entry);
+        self
+    }
+
+    /// Pass the string `value` as argument `name` to the evaluated
+    /// function (nix ignores arguments the function doesn’t take).
+    pub fn argstr(&mut self, name: &str, value: &str) -> &mut Self {
+        self.arguments.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Pass the value of the nix expression `expr` as argument
+    /// `name` to the function in the shell file, like `nix-build
+    /// --arg` does (the evaluated function is the one of
+    /// `builder::LOGGED_EVALUATION_NIX`, which passes them on as
+    /// its `shellArgs` argument).
+    pub fn shell_arg(&mut self, name: &str, expr: &str) -> &mut Self {
+        self.shell_args.push((name.to_string(), expr.to_string()));
+        self
+    }
+
+    /// Like `shell_arg`, for the string `value`.
+    pub fn shell_argstr(&mut self, name: &str, value: &str) -> &mut Self {
+        self.shell_arg(name, &string_literal(value))
+    }
+
+    /// Build the attribute path `path` (see `attribute_path`) of the
+    /// evaluated attribute set, like `devShells.backend`, instead of
+    /// all of it. Only `builder::run` does this.
+    pub fn attribute(&mut self, path: &[String]) -> &mut Self {
+        self.attribute = Some(path.to_vec());
+        self
+    }
+
+    /// Print the stack trace of evaluation errors.
+    pub fn show_trace(&mut self) -> &mut Self {
+        self.show_trace = true;
+        self
+    }
+
+    /// Add the settings, search path entries and arguments of
+    /// `other` after these, so that they take precedence.
+    pub fn extend(&mut self, other: &Options) -> &mut Self {
+        self.settings.extend(other.settings.iter().cloned());
+        self.search_path.extend(other.search_path.iter().cloned());
+        self.arguments.extend(other.arguments.iter().cloned());
+        self.shell_args.extend(other.shell_args.iter().cloned());
+        if other.attribute.is_some() {
+            self.attribute = other.attribute.clone();
+        }
+        self.show_trace |= other.show_trace;
+        self
+    }
+
+    /// Trace the files nix reads during builds (see `read_trace`).
+    /// Only `builder::run` does this.
+    pub fn trace_reads(&mut self) -> &mut Self {
+        self.trace_reads = true;
+        self
+    }
+
+    /// Whether builds should trace the files nix reads.
+    pub fn traces_reads(&self) -> bool {
+        self.trace_reads
+    }
+
+    /// Run builds in a session of their own (see `detach`).
+    /// Only `builder::run` does this.
+    pub fn detach(&mut self) -> &mut Self {
+        self.detach = true;
+        self
+    }
+
+    /// Whether builds should run in a session of their own.
+    pub fn detaches(&self) -> bool {
+        self.detach
+    }
+
+    /// Build the named `shells`, given as name and attribute path in
+    /// the evaluated attribute set (see `attribute_path`), instead of
+    /// the evaluated shell (see `project::config::ShellConfig`).
+    /// Only `builder::run` does this.
+    pub fn shells(&mut self, shells: &[(String, Vec<String>)]) -> &mut Self {
+        let json: Vec<serde_json::Value> = shells
+            .iter()
+            .map(|(name, attribute)| serde_json::json!({ "name": name, "attribute": attribute }))
+            .collect();
+        self.shells = shells.iter().map(|(name, _)| name.clone()).collect();
+        self.argstr(
+            "shells",
+            &serde_json::to_string(&json).expect("shells always encode as JSON"),
+        )
+    }
+
+    /// The names of the shells builds produce (see `shells`),
+    /// empty for just the evaluated shell.
+    pub fn shell_names(&self) -> &[String] {
+        &self.shells
+    }
+
+    /// Arguments passing the settings to nix commands.
+    pub fn args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![];
+        for (name, value) in &self.settings {
+            args.extend(vec!["--option".into(), name.into(), value.into()]);
+        }
+        for entry in &self.search_path {
+            args.extend(vec!["-I".into(), entry.clone()]);
+        }
+        for (name, value) in &self.arguments {
+            args.extend(vec!["--argstr".into(), name.into(), value.into()]);
+        }
+        if let Some(ref attribute) = self.attribute {
+            args.extend(vec![
+                "--argstr".into(),
+                "attribute".into(),
+                serde_json::to_string(attribute)
+                    .expect("attribute paths always encode as JSON")
+                    .into(),
+            ]);
+        }
+        if !self.shell_args.is_empty() {
+            // later arguments of the same name win, like with nix
+            let attrs: String = self
+                .shell_args
+                .iter()
+                .map(|(name, expr)| format!(" {} = ({});", string_literal(name), expr))
+                .collect();
+            args.extend(vec![
+                "--arg".into(),
+                "shellArgs".into(),
+                format!("{{{} }}", attrs).into(),
+            ]);
+        }
+        if self.show_trace {
+            args.push("--show-trace".into());
+        }
+        args
+    }
+}
+
+/// The names of the attribute path `path`, like
+/// `devShells."backend.v2"`: separated by dots, and quoted (with `\"`
+/// and `\\` escapes) if they contain dots themselves.
+pub fn attribute_path(path: &str) -> Result<Vec<String>, String> {
+    let error = |reason: &str| format!("invalid attribute path `{}`: {}", path, reason);
+    let mut names = vec![];
+    let mut chars = path.chars().peekable();
+    loop {
+        let mut name = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => name.push(c),
+                        None => return Err(error("unterminated quote")),
+                    },
+                    Some(c) => name.push(c),
+                    None => return Err(error("unterminated quote")),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                match c {
+                    '.' => break,
+                    '"' => return Err(error("quote names as a whole")),
+                    _ => name.push(c),
+                }
+                chars.next();
+            }
+            if name.is_empty() {
+                return Err(error("empty attribute name"));
+            }
+        }
+        names.push(name);
+        match chars.next() {
+            None => return Ok(names),
+            Some('.') => {}
+            Some(_) => return Err(error("expected a `.` after a quoted name")),
+        }
+    }
+}
+
+/// `path`, if it is an attribute path (see `attribute_path`); for
+/// command line options.
+pub fn valid_attribute_path(path: &str) -> Result<String, String> {
+    attribute_path(path).map(|_| path.to_string())
+}
+
+/// `value` as a nix string literal.
+fn string_literal(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
+}
+
+/// Prepare `cmd` (a nix command) to run without any user
+/// interaction: its stdin is closed, and git and ssh are told to
+/// fail instead of asking for credentials or host keys. It also
+/// gets the file descriptor limit lorri started with (see
+/// `fds::restore_soft_limit`).
+pub fn non_interactive(cmd: &mut Command) -> &mut Command {
+    cmd.stdin(Stdio::null())
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env(
+            "NIX_SSHOPTS",
+            with_batch_mode(std::env::var("NIX_SSHOPTS").ok()),
+        );
+    if std::env::var_os("GIT_SSH_COMMAND").is_none() {
+        cmd.env("GIT_SSH_COMMAND", "ssh -o BatchMode=yes");
+    }
+    fds::restore_soft_limit(cmd)
+}
+
+/// Run `cmd` in a session of its own: it has no controlling
+/// terminal (so tools can’t prompt on `/dev/tty` either), and leads
+/// a new process group. Only for commands the daemon runs, since
+/// ctrl-c in a terminal no longer reaches them.
+pub fn detach(cmd: &mut Command) -> &mut Command {
+    session::detach(cmd)
+}
+
+/// Add `-o BatchMode=yes` to the ssh options `opts`.
+fn with_batch_mode(opts: Option<String>) -> String {
+    match opts {
+        Some(ref opts) if opts.contains("BatchMode") => opts.clone(),
+        Some(ref opts) if !opts.trim().is_empty() => format!("{} -o BatchMode=yes", opts),
+        _ => String::from("-o BatchMode=yes"),
+    }
+}
+
+mod session {
+    extern crate nix;
+
+    use self::nix::unistd::setsid;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    /// Run `cmd` in a new session, without a controlling terminal.
+    pub fn detach(cmd: &mut Command) -> &mut Command {
+        // setsid() is async-signal-safe, so it can run between
+        // fork() and exec()
+        unsafe {
+            cmd.pre_exec(|| match setsid() {
+                Ok(_) => Ok(()),
+                Err(_) => Err(std::io::Error::last_os_error()),
+            })
+        }
+    }
+}
+
+/// Download and unpack the tarball at `url` into `store`, and return
+/// its store path. The download is checked against `sha256`; if a
+/// path with that hash already exists, nothing is downloaded.
+pub fn fetch_tarball(store: &Store, url: &str, sha256: &str) -> Result<StorePath, FetchError> {
+    let mut cmd = Command::new("nix-prefetch-url");
+    cmd.args(store.args())
+        .args(&["--unpack", "--print-path", url, sha256]);
+    non_interactive(&mut cmd);
+    debug!("$ {:?}", cmd);
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(FetchError::ExecutionFailed(output));
+    }
+    // prints the hash, then the path
+    let path = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .nth(1)
+        .map(PathBuf::from);
+    match path {
+        Some(path) => Ok(StorePath(path)),
+        None => Err(FetchError::ExecutionFailed(output)),
+    }
+}
+
 /// Opaque type to keep a temporary GC root directory alive.
 /// Once it is dropped, the GC root is removed.
 pub struct GcRootTempDir(tempfile::TempDir);
@@ -198,6 +627,7 @@ impl CallOpts {
     {
         let mut cmd = Command::new("nix-instantiate");
         cmd.args(&["--eval", "--json", "--strict"]);
+        non_interactive(&mut cmd);
 
         cmd.args(self.command_arguments());
 
@@ -210,6 +640,43 @@ impl CallOpts {
         }
     }
 
+    /// Instantiate the expression, without building it, and return
+    /// the paths to the resulting derivations (`.drv` files).
+    ///
+    /// This parses and evaluates the expression, so it is a cheap way
+    /// to find out whether it is broken.
+    ///
+    /// ```rust
+    /// extern crate lorri;
+    /// use lorri::nix;
+    /// # use std::env;
+    /// # env::set_var("NIX_PATH", "nixpkgs=./nix/bogus-nixpkgs/");
+    ///
+    /// let drvs = nix::CallOpts::expression(r#"
+    ///             import <nixpkgs> {}
+    /// "#)
+    ///         .attribute("hello")
+    ///         .instantiate()
+    ///         .unwrap();
+    /// assert!(drvs[0].as_path().to_string_lossy().ends_with(".drv"));
+    /// ```
+    pub fn instantiate(&self) -> Result<Vec<StorePath>, InstantiateError> {
+        let mut cmd = Command::new("nix-instantiate");
+        cmd.args(self.command_arguments());
+        non_interactive(&mut cmd);
+
+        let output = cmd.output()?;
+
+        if output.status.success() {
+            let stdout: &[u8] = &output.stdout;
+            Ok(osstrlines::Lines::from(stdout)
+                .map(|line| line.map(StorePath::from))
+                .collect::<Result<Vec<StorePath>, _>>()?)
+        } else {
+            Err(output.into())
+        }
+    }
+
     /// Build the expression and return a path to the build result:
     ///
     /// ```rust
@@ -314,6 +781,7 @@ impl CallOpts {
         cmd.args(self.command_arguments());
 
         cmd.stderr(Stdio::inherit());
+        non_interactive(&mut cmd);
         let output = cmd.output()?;
 
         if output.status.success() {
@@ -332,6 +800,15 @@ impl CallOpts {
         }
     }
 
+    /// The arguments `paths` passes to `nix-build` after its
+    /// `--out-link`, to show what would be built.
+    pub fn build_arguments(&self) -> Vec<OsString> {
+        self.command_arguments()
+            .into_iter()
+            .map(OsStr::to_owned)
+            .collect()
+    }
+
     /// Fetch common arguments passed to Nix's CLI, specifically
     /// the --expr expression, -A attribute, and --argstr values.
     fn command_arguments(&self) -> Vec<&OsStr> {
@@ -402,6 +879,64 @@ impl From<std::process::Output> for EvaluationError {
     }
 }
 
+/// Possible error conditions encountered when instantiating Nix expressions.
+#[derive(Debug)]
+pub enum InstantiateError {
+    /// A system-level IO error occured while executing Nix.
+    Io(std::io::Error),
+
+    /// Nix execution failed.
+    ExecutionFailed(std::process::Output),
+}
+
+impl From<std::io::Error> for InstantiateError {
+    fn from(e: std::io::Error) -> InstantiateError {
+        InstantiateError::Io(e)
+    }
+}
+
+impl From<std::process::Output> for InstantiateError {
+    fn from(output: std::process::Output) -> InstantiateError {
+        if output.status.success() {
+            panic!(
+                "Output is successful, but we're in error handling: {:#?}",
+                output
+            );
+        }
+
+        InstantiateError::ExecutionFailed(output)
+    }
+}
+
+/// Fetching a tarball into the store failed.
+#[derive(Debug)]
+pub enum FetchError {
+    /// A system-level IO error occured while executing Nix.
+    Io(std::io::Error),
+
+    /// Nix execution failed (or printed something unexpected).
+    ExecutionFailed(std::process::Output),
+}
+
+impl From<std::io::Error> for FetchError {
+    fn from(e: std::io::Error) -> FetchError {
+        FetchError::Io(e)
+    }
+}
+
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FetchError::Io(e) => write!(f, "could not run nix-prefetch-url: {}", e),
+            FetchError::ExecutionFailed(output) => write!(
+                f,
+                "nix-prefetch-url failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            ),
+        }
+    }
+}
+
 /// Possible error conditions encountered when executing Nix build commands.
 #[derive(Debug)]
 pub enum BuildError {
@@ -457,9 +992,9 @@ impl From<BuildError> for OnePathError {
 
 #[cfg(test)]
 mod tests {
-    use super::CallOpts;
-    use std::ffi::OsStr;
-    use std::path::PathBuf;
+    use super::{attribute_path, with_batch_mode, CallOpts, Options, Store};
+    use std::ffi::{OsStr, OsString};
+    use std::path::{Path, PathBuf};
 
     #[test]
     fn cmd_arguments_expression() {
@@ -501,4 +1036,116 @@ mod tests {
         .collect();
         assert_eq!(exp2, nix2.command_arguments());
     }
+
+    #[test]
+    fn store_uris() {
+        assert!(Store::Default.args().is_empty());
+        assert_eq!(Store::Default.chroot(), None);
+        assert!(Store::Default.is_default_layout());
+
+        let daemon = Store::Uri(String::from("daemon"));
+        assert_eq!(
+            daemon.args(),
+            vec![OsStr::new("--store"), OsStr::new("daemon")]
+        );
+        assert_eq!(daemon.chroot(), None);
+        assert!(daemon.is_default_layout());
+        assert!(Store::Uri(String::from("local")).is_default_layout());
+
+        let chroot = Store::Uri(String::from("local?root=/home/user/nix"));
+        assert_eq!(chroot.chroot(), Some(PathBuf::from("/home/user/nix")));
+        assert_eq!(
+            chroot.state_dir(),
+            PathBuf::from("/home/user/nix/nix/var/nix")
+        );
+
+        let plain = Store::Uri(String::from("/home/user/nix"));
+        assert_eq!(plain.chroot(), Some(PathBuf::from("/home/user/nix")));
+        assert!(!chroot.is_default_layout());
+        assert!(!plain.is_default_layout());
+
+        let state = Store::Uri(String::from("local?root=/r&state=/s"));
+        assert_eq!(state.state_dir(), PathBuf::from("/s"));
+
+        let store = Store::Uri(String::from("local?store=/opt/nix/store"));
+        assert_eq!(store.store_dir(), PathBuf::from("/opt/nix/store"));
+    }
+
+    #[test]
+    fn option_arguments() {
+        let mut options = Options::new();
+        options
+            .set("extra-substituters", "https://a.cachix.org")
+            .set("extra-trusted-public-keys", "a.cachix.org-1:abc=")
+            .include("nixpkgs", Path::new("/nix/store/abc-source"))
+            .argstr("shellHookMode", "skip");
+        options.attribute(&[String::from("shells"), String::from("dev")]);
+        let mut cli = Options::new();
+        cli.attribute(&attribute_path("devShells.backend").unwrap())
+            .shell_arg("withDocs", "true")
+            .shell_argstr("greeting", "say \"${hi}\"")
+            .show_trace();
+        options.extend(&cli);
+        assert_eq!(
+            options.args(),
+            [
+                "--option",
+                "extra-substituters",
+                "https://a.cachix.org",
+                "--option",
+                "extra-trusted-public-keys",
+                "a.cachix.org-1:abc=",
+                "-I",
+                "nixpkgs=/nix/store/abc-source",
+                "--argstr",
+                "shellHookMode",
+                "skip",
+                "--argstr",
+                "attribute",
+                r#"["devShells","backend"]"#,
+                "--arg",
+                "shellArgs",
+                r#"{ "withDocs" = (true); "greeting" = ("say \"\${hi}\""); }"#,
+                "--show-trace",
+            ]
+            .iter()
+            .map(OsString::from)
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn attribute_paths() {
+        let names = |names: &[&str]| Ok(names.iter().map(|name| name.to_string()).collect());
+        assert_eq!(attribute_path("dev"), names(&["dev"]));
+        assert_eq!(
+            attribute_path("devShells.backend"),
+            names(&["devShells", "backend"])
+        );
+        assert_eq!(
+            attribute_path(r#"shells."python3.11".default"#),
+            names(&["shells", "python3.11", "default"])
+        );
+        assert_eq!(attribute_path(r#""a\"b\\c""#), names(&[r#"a"b\c"#]));
+        assert_eq!(attribute_path(r#"shells."""#), names(&["shells", ""]));
+        assert!(attribute_path("").is_err());
+        assert!(attribute_path("shells.").is_err());
+        assert!(attribute_path("shells..dev").is_err());
+        assert!(attribute_path(r#"shells."dev"#).is_err());
+        assert!(attribute_path(r#"shells."dev"x"#).is_err());
+        assert!(attribute_path(r#"sh"ells""#).is_err());
+    }
+
+    #[test]
+    fn ssh_batch_mode() {
+        assert_eq!(with_batch_mode(None), "-o BatchMode=yes");
+        assert_eq!(
+            with_batch_mode(Some(String::from("-p 2222"))),
+            "-p 2222 -o BatchMode=yes"
+        );
+        assert_eq!(
+            with_batch_mode(Some(String::from("-o BatchMode=no"))),
+            "-o BatchMode=no"
+        );
+    }
 }
@@ -0,0 +1,233 @@
+//! Desktop notifications about builds (see `config::NotifyConfig`):
+//! when a build fails, and when a long build completes, so that
+//! nobody has to watch the daemon’s output while waiting.
+//!
+//! They are shown with `notify-send` (freedesktop) or, on macOS,
+//! with `osascript`. Like hooks, notifications are best-effort:
+//! failing to show one is logged, and never stops the build loop.
+
+use crate::build_loop::{BuildId, Event};
+use crate::config::NotifyConfig;
+use crate::fds;
+use crate::NixSource;
+use std::io;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
+
+/// How many lines of a failed build’s log a notification shows.
+const FAILURE_LINES: usize = 3;
+
+/// A notification to show.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    /// The title.
+    pub summary: String,
+    /// The text below it.
+    pub body: String,
+    /// Whether it needs attention, like a failure does.
+    pub urgent: bool,
+}
+
+impl Notification {
+    /// Show the notification, without waiting for it to go away.
+    pub fn show(&self) -> io::Result<()> {
+        let mut cmd = if cfg!(target_os = "macos") {
+            let mut cmd = Command::new("osascript");
+            cmd.arg("-e").arg(format!(
+                "display notification {} with title {}",
+                applescript_string(&self.body),
+                applescript_string(&self.summary)
+            ));
+            cmd
+        } else {
+            let mut cmd = Command::new("notify-send");
+            cmd.args(&["--app-name", "lorri", "--urgency"])
+                .arg(if self.urgent { "critical" } else { "normal" })
+                .arg(&self.summary)
+                .arg(&self.body);
+            cmd
+        };
+        debug!("$ {:?}", cmd);
+        let mut child = fds::restore_soft_limit(&mut cmd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        std::thread::spawn(move || child.wait());
+        Ok(())
+    }
+}
+
+/// A string literal of AppleScript with the text `s`.
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Decides which events of one build loop are worth a notification.
+pub struct Notifier {
+    /// Completed builds which took at least this long are notified.
+    long_build: Duration,
+    /// The running build, and when it started.
+    started: Option<(BuildId, SystemTime)>,
+    /// Whether showing a notification failed before, so that a
+    /// missing `notify-send` is only reported once.
+    failed: bool,
+}
+
+impl Notifier {
+    /// A notifier for the builds of one project.
+    pub fn new(config: &NotifyConfig) -> Notifier {
+        Notifier {
+            long_build: Duration::from_secs(config.long_build_secs),
+            started: None,
+            failed: false,
+        }
+    }
+
+    /// The notification for `event` of the build loop of `source`,
+    /// if it is worth one.
+    pub fn notification(&mut self, source: &NixSource, event: &Event) -> Option<Notification> {
+        match event {
+            Event::Started(build, time, _) => {
+                self.started = Some((*build, *time));
+                None
+            }
+            Event::Completed(build, time, _) => {
+                let took = self.took(*build, *time)?;
+                if took < self.long_build {
+                    return None;
+                }
+                Some(Notification {
+                    summary: String::from("lorri: build completed"),
+                    body: format!("{} built in {}", source, human_duration(took)),
+                    urgent: false,
+                })
+            }
+            Event::Failure(build, time, failure) => {
+                self.took(*build, *time);
+                let skip = failure.log_lines.len().saturating_sub(FAILURE_LINES);
+                let mut body = format!("{} failed to build", source);
+                for line in &failure.log_lines[skip..] {
+                    body.push('\n');
+                    body.push_str(&line.to_string_lossy());
+                }
+                Some(Notification {
+                    summary: String::from("lorri: build failed"),
+                    body,
+                    urgent: true,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Show the notification for `event` of the build loop of
+    /// `source`, if it is worth one.
+    pub fn observe(&mut self, source: &NixSource, event: &Event) {
+        let notification = match self.notification(source, event) {
+            Some(notification) => notification,
+            None => return,
+        };
+        match notification.show() {
+            Err(ref e) if !self.failed => {
+                self.failed = true;
+                warn!("could not show a desktop notification: {}", e)
+            }
+            _ => (),
+        }
+    }
+
+    /// How long `build`, which ended at `ended`, took (if we saw it
+    /// start).
+    fn took(&mut self, build: BuildId, ended: SystemTime) -> Option<Duration> {
+        match self.started.take() {
+            Some((started_build, started)) if started_build == build => {
+                ended.duration_since(started).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `duration` like `1m 20s`.
+fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m {}s", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Notification, Notifier};
+    use build_loop::{BuildExitFailure, BuildId, BuildResults, Event, Rebuild};
+    use builder::{CacheStats, OutputPaths, Timings};
+    use config::NotifyConfig;
+    use project::roots::RootPath;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+    use {NixFile, NixSource};
+
+    fn completed(build: BuildId, secs: u64) -> Event {
+        Event::Completed(
+            build,
+            UNIX_EPOCH + Duration::from_secs(secs),
+            BuildResults {
+                output_paths: OutputPaths {
+                    shell_gc_root: RootPath::from(PathBuf::from("/gc/shell_gc_root")),
+                    shells: Default::default(),
+                },
+                cache_stats: CacheStats::default(),
+                drv_path: None,
+                timings: Timings::default(),
+                rebuild: Rebuild::Full,
+                env_hash: None,
+                watched_files: 0,
+            },
+        )
+    }
+
+    #[test]
+    fn long_builds_and_failures() {
+        let source: NixSource = NixFile::from(PathBuf::from("/project/shell.nix")).into();
+        let mut notifier = Notifier::new(&NotifyConfig {
+            enabled: true,
+            long_build_secs: 30,
+        });
+        let (quick, slow, failing) = (BuildId::from(1), BuildId::from(2), BuildId::from(3));
+
+        assert_eq!(
+            notifier.notification(&source, &Event::Started(quick, UNIX_EPOCH, None)),
+            None
+        );
+        assert_eq!(notifier.notification(&source, &completed(quick, 10)), None);
+
+        notifier.notification(&source, &Event::Started(slow, UNIX_EPOCH, None));
+        assert_eq!(
+            notifier.notification(&source, &completed(slow, 80)),
+            Some(Notification {
+                summary: String::from("lorri: build completed"),
+                body: String::from("/project/shell.nix built in 1m 20s"),
+                urgent: false,
+            })
+        );
+
+        notifier.notification(&source, &Event::Started(failing, UNIX_EPOCH, None));
+        let failure = Event::Failure(
+            failing,
+            UNIX_EPOCH,
+            BuildExitFailure {
+                log_lines: vec!["building".into(), "error: undefined variable 'pkgs'".into()],
+                artifacts: None,
+            },
+        );
+        let notification = notifier.notification(&source, &failure).unwrap();
+        assert!(notification.urgent);
+        assert_eq!(
+            notification.body,
+            "/project/shell.nix failed to build\nbuilding\nerror: undefined variable 'pkgs'"
+        );
+    }
+}
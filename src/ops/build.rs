@@ -0,0 +1,167 @@
+//! Build a project once, without the daemon, and summarize the
+//! result for CI pipelines.
+
+use crate::build_loop::{BuildError, BuildExitFailure, BuildLoop, BuildResults};
+use crate::config::Config;
+use crate::ops::{ok, porcelain, print_record, ExitError, OpResult};
+use crate::project::Project;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// See the documentation for lorri::cli::Command::Build for more
+/// details.
+pub fn main(project: Project, json: bool) -> OpResult {
+    let config = Config::load(::ops::get_paths()?.config_file())
+        .map_err(|e| ExitError::errmsg(e.to_string()))?;
+    let mut build_loop = BuildLoop::new(&project);
+    build_loop.configure(&config);
+
+    let started = Instant::now();
+    let result = build_loop.once();
+    let mut summary = Summary {
+        nix_file: project.source.to_string(),
+        success: result.is_ok(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        shell_gc_root: None,
+        shells: BTreeMap::new(),
+        drv_path: None,
+        env_hash: None,
+        inputs: to_strings(build_loop.input_paths()),
+        log_lines: vec![],
+    };
+    let error = match result {
+        Ok(results) => {
+            summary.completed(&results);
+            None
+        }
+        Err(BuildError::Unrecoverable(err)) => Some(ExitError::err(100, format!("{:?}", err))),
+        Err(BuildError::Cancelled) => Some(ExitError::errmsg("The build was cancelled")),
+        Err(BuildError::Recoverable(failure))
+        | Err(BuildError::Network(failure))
+        | Err(BuildError::Interactive(failure)) => {
+            summary.failed(&failure);
+            Some(ExitError::errmsg(format!(
+                "The build of {} failed",
+                summary.nix_file
+            )))
+        }
+    };
+
+    let record = serde_json::to_value(&summary).expect("the summary is valid JSON");
+    if json && !porcelain() {
+        println!("{}", record);
+    } else {
+        print_record(&summary.text(), record);
+    }
+    match error {
+        Some(error) => Err(error),
+        None => ok(),
+    }
+}
+
+/// The outcome of a build, as printed by `lorri build`.
+#[derive(Serialize, Debug)]
+struct Summary {
+    nix_file: String,
+    success: bool,
+    duration_ms: u64,
+    /// The GC root of the (default) shell, if the build succeeded
+    shell_gc_root: Option<String>,
+    /// The GC roots of the named shells, if any
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    shells: BTreeMap<String, String>,
+    drv_path: Option<String>,
+    env_hash: Option<String>,
+    /// The files and directories the build read, a change of which
+    /// would change it (see `BuildLoop::input_paths`)
+    inputs: Vec<String>,
+    /// The log of a failed build
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    log_lines: Vec<String>,
+}
+
+impl Summary {
+    fn completed(&mut self, results: &BuildResults) {
+        self.shell_gc_root = Some(results.output_paths.shell_gc_root.to_string());
+        self.shells = results
+            .output_paths
+            .shells
+            .iter()
+            .map(|(name, root)| (name.clone(), root.to_string()))
+            .collect();
+        self.drv_path = results
+            .drv_path
+            .as_ref()
+            .map(|drv| drv.display().to_string());
+        self.env_hash = results.env_hash.clone();
+    }
+
+    fn failed(&mut self, failure: &BuildExitFailure) {
+        self.log_lines = failure
+            .log_lines
+            .iter()
+            .map(|line| line.to_string_lossy().into_owned())
+            .collect();
+    }
+
+    /// A human-readable version of the summary.
+    fn text(&self) -> String {
+        let mut lines = vec![
+            self.nix_file.clone(),
+            format!(
+                "  result:        {}, took {:.1}s",
+                if self.success { "success" } else { "failure" },
+                self.duration_ms as f64 / 1000.0
+            ),
+        ];
+        if let Some(ref root) = self.shell_gc_root {
+            lines.push(format!("  gc root:       {}", root));
+        }
+        for (name, root) in &self.shells {
+            lines.push(format!("  gc root ({}): {}", name, root));
+        }
+        lines.push(format!("  inputs:        {}", self.inputs.len()));
+        lines.extend(self.log_lines.iter().map(|line| format!("  | {}", line)));
+        lines.join("\n")
+    }
+}
+
+fn to_strings(paths: &[PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Summary;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn summary_of_a_failed_build() {
+        let summary = Summary {
+            nix_file: String::from("/project/shell.nix"),
+            success: false,
+            duration_ms: 1_300,
+            shell_gc_root: None,
+            shells: BTreeMap::new(),
+            drv_path: None,
+            env_hash: None,
+            inputs: vec![String::from("/project/shell.nix")],
+            log_lines: vec![String::from("error: undefined variable 'pkgs'")],
+        };
+        assert_eq!(
+            serde_json::to_string(&summary).unwrap(),
+            r#"{"nix_file":"/project/shell.nix","success":false,"duration_ms":1300,"shell_gc_root":null,"drv_path":null,"env_hash":null,"inputs":["/project/shell.nix"],"log_lines":["error: undefined variable 'pkgs'"]}"#
+        );
+        assert_eq!(
+            summary.text(),
+            "/project/shell.nix\n  \
+             result:        failure, took 1.3s\n  \
+             inputs:        1\n  \
+             | error: undefined variable 'pkgs'"
+        );
+    }
+}
@@ -0,0 +1,33 @@
+//! Cancel the running build of a project in the daemon.
+
+use crate::ops::{ok_msg, ExitError, OpResult};
+use crate::socket::communicate::{client, CancelBuild, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::NixFile;
+
+/// See the documentation for lorri::cli::Internal_::Cancel for more
+/// details.
+pub fn main(nix_file: NixFile) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let result = client::cancel_build(DEFAULT_READ_TIMEOUT)
+        .connect(&SocketPath::from(paths.daemon_socket_file()))
+        .map_err(|e| {
+            ExitError::errmsg(format!(
+                "Could not connect to the lorri daemon, is it running? ({:?})",
+                e
+            ))
+        })?
+        .request(&CancelBuild {
+            nix_file: nix_file.clone(),
+        })
+        .map_err(|e| ExitError::errmsg(format!("The daemon did not answer: {:?}", e)))?;
+
+    if result.cancelled {
+        ok_msg(format!("Cancelled the build of {}", nix_file))
+    } else {
+        Err(ExitError::errmsg(format!(
+            "No build of {} is running",
+            nix_file
+        )))
+    }
+}
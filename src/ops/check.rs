@@ -0,0 +1,162 @@
+//! Check that a shell file evaluates, without building it.
+
+use crate::nix::{CallOpts, InstantiateError};
+use crate::ops::{ok_msg, print_record, ExitError, OpResult};
+use crate::NixFile;
+use regex::Regex;
+use std::fmt;
+use std::path::PathBuf;
+
+/// See the documentation for lorri::cli::Internal_::Check for more
+/// details.
+pub fn main(nix_file: NixFile) -> OpResult {
+    match CallOpts::file(PathBuf::from(nix_file.as_os_str())).instantiate() {
+        Ok(_drvs) => ok_msg(format!("{}: ok", nix_file)),
+        Err(InstantiateError::Io(e)) => Err(ExitError::errmsg(format!(
+            "Could not run nix-instantiate: {}",
+            e
+        ))),
+        Err(InstantiateError::ExecutionFailed(output)) => {
+            for error in parse_errors(&String::from_utf8_lossy(&output.stderr)) {
+                let (file, line, column) = match error.position {
+                    Some((ref file, line, column)) => (Some(file), Some(line), Some(column)),
+                    None => (None, None, None),
+                };
+                print_record(
+                    &error.to_string(),
+                    serde_json::json!({
+                        "error": error.message,
+                        "file": file,
+                        "line": line,
+                        "column": column,
+                    }),
+                );
+            }
+            Err(ExitError::errmsg(format!(
+                "{}: evaluation failed",
+                nix_file
+            )))
+        }
+    }
+}
+
+/// An error reported by nix, with the position it points to, if any.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NixError {
+    /// The error message, without the `error: ` prefix
+    pub message: String,
+    /// The file, line and column the error occurred at
+    pub position: Option<(String, u32, u32)>,
+}
+
+impl fmt::Display for NixError {
+    /// Formats like compilers do, so editors can jump to the position.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some((ref file, line, column)) => {
+                write!(f, "{}:{}:{}: error: {}", file, line, column, self.message)
+            }
+            None => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+/// Extract the errors from the stderr of a failed nix command.
+///
+/// nix < 2.4 puts the position on the same line, like
+/// `error: undefined variable 'foo' at /shell.nix:3:5`,
+/// later versions put it on a separate `at /shell.nix:3:5:` line.
+fn parse_errors(stderr: &str) -> Vec<NixError> {
+    lazy_static! {
+        static ref INLINE: Regex =
+            Regex::new(r"^error: (?P<msg>.*?),? at (?P<pos>/\S+:\d+:\d+)$").unwrap();
+        static ref SEPARATE: Regex = Regex::new(r"^\s+at (?P<pos>/\S+:\d+:\d+):$").unwrap();
+    }
+
+    let position = |pos: &str| {
+        let mut parts = pos.rsplitn(3, ':');
+        let column = parts.next()?.parse().ok()?;
+        let line = parts.next()?.parse().ok()?;
+        Some((parts.next()?.to_string(), line, column))
+    };
+
+    let mut errors: Vec<NixError> = vec![];
+    for line in stderr.lines() {
+        if let Some(caps) = INLINE.captures(line) {
+            errors.push(NixError {
+                message: caps["msg"].to_string(),
+                position: position(&caps["pos"]),
+            });
+        } else if line.starts_with("error: ") {
+            errors.push(NixError {
+                message: line["error: ".len()..].to_string(),
+                position: None,
+            });
+        } else if let Some(caps) = SEPARATE.captures(line) {
+            if let Some(error) = errors.last_mut() {
+                if error.position.is_none() {
+                    error.position = position(&caps["pos"]);
+                }
+            }
+        }
+    }
+
+    if errors.is_empty() && !stderr.trim().is_empty() {
+        errors.push(NixError {
+            message: stderr.trim().to_string(),
+            position: None,
+        });
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_errors, NixError};
+
+    #[test]
+    fn inline_positions() {
+        assert_eq!(
+            parse_errors(
+                "error: syntax error, unexpected ')', at /home/user/shell.nix:3:5\n\
+                 (use '--show-trace' to show detailed location information)\n"
+            ),
+            vec![NixError {
+                message: String::from("syntax error, unexpected ')'"),
+                position: Some((String::from("/home/user/shell.nix"), 3, 5)),
+            }]
+        );
+    }
+
+    #[test]
+    fn separate_positions() {
+        let errors = parse_errors(
+            "error: undefined variable 'foo'\n\
+             \n       at /home/user/shell.nix:2:9:\n\
+             \n            1| { pkgs ? import <nixpkgs> {} }:\n\
+             \n            2| pkgs.mkShell { buildInputs = [ foo ]; }\n",
+        );
+        assert_eq!(
+            errors,
+            vec![NixError {
+                message: String::from("undefined variable 'foo'"),
+                position: Some((String::from("/home/user/shell.nix"), 2, 9)),
+            }]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "/home/user/shell.nix:2:9: error: undefined variable 'foo'"
+        );
+    }
+
+    #[test]
+    fn unrecognized_output() {
+        assert_eq!(
+            parse_errors("nix-instantiate: command not understood\n"),
+            vec![NixError {
+                message: String::from("nix-instantiate: command not understood"),
+                position: None,
+            }]
+        );
+    }
+}
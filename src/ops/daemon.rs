@@ -1,79 +1,135 @@
 //! Run a BuildLoop for `shell.nix`, watching for input file changes.
 //! Can be used together with `direnv`.
-use crate::daemon::Daemon;
-use crate::ops::{ok, ExitError, OpResult};
-use crate::socket::communicate::listener;
-use crate::socket::communicate::CommunicationType;
-use crate::socket::ReadWriter;
-use crate::thread::Pool;
-use std::sync::mpsc;
+
+extern crate nix;
+
+use self::nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use self::nix::libc::c_int;
+use self::nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use self::nix::unistd::{pipe, read, write};
+use crate::build_loop::Event;
+use crate::cli::DaemonOptions;
+use crate::config::Config;
+use crate::daemon::{Daemon, ShutdownHandle, StartError};
+use crate::fds;
+use crate::ops::{ok, print_record, ExitError, OpResult};
+use crate::socket::path::BindError;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
 
 /// See the documentation for lorri::cli::Command::Shell for more
 /// details.
-pub fn main() -> OpResult {
+pub fn main(opts: DaemonOptions) -> OpResult {
     let paths = ::ops::get_paths()?;
     let daemon_socket_file = paths.daemon_socket_file().to_owned();
-    let socket_path = ::socket::path::SocketPath::from(&daemon_socket_file);
-    // TODO: move listener into Daemon struct?
-    let listener = listener::Listener::new(&socket_path).map_err(|e| match e {
-        ::socket::path::BindError::OtherProcessListening => ExitError::errmsg(format!(
-            "Another daemon is already listening on the socket at {}. \
-             We are currently only allowing one daemon to be running at the same time.",
-            socket_path.display()
-        )),
-        e => panic!("{:?}", e),
-    })?;
+    let mut config =
+        Config::load(paths.config_file()).map_err(|e| ExitError::errmsg(e.to_string()))?;
+    // flags take precedence over the configuration
+    if let Some(event_buffer) = opts.event_buffer {
+        config.daemon.event_buffer = event_buffer;
+    }
+    if let Some(slow_listeners) = opts.slow_listeners {
+        config.daemon.slow_listeners = slow_listeners;
+    }
+    if opts.cancel_on_change || opts.no_cancel_on_change {
+        config.build.cancel_on_change = opts.cancel_on_change;
+    }
+    if let Some(debounce_ms) = opts.debounce_ms {
+        config.watch.debounce_ms = debounce_ms;
+    }
+    if let Some(backend) = opts.watch_backend {
+        config.watch.backend = backend;
+    }
+    config.notify.enabled |= opts.notify;
+    if let Some(long_build_secs) = opts.notify_long_build_secs {
+        config.notify.long_build_secs = long_build_secs;
+    }
 
-    let (mut daemon, build_messages_rx) = Daemon::new();
+    // every watched project takes file descriptors
+    match fds::raise_soft_limit() {
+        Ok(fds::Limits {
+            soft: Some(soft), ..
+        }) => info!("up to {} file descriptors can be open", soft),
+        Ok(_) => {}
+        Err(e) => warn!("could not raise the file descriptor limit: {}", e),
+    }
 
-    // messages sent from accept handlers
-    let (accept_messages_tx, accept_messages_rx) = mpsc::channel();
-
-    let handlers = daemon.handlers();
+    let (mut daemon, build_messages_rx) = Daemon::new();
+    daemon.set_config(config);
+    daemon.set_shells_dir(paths.shells_dir().to_owned());
+    shut_down_on_signals(daemon.shutdown_handle())
+        .map_err(|e| ExitError::errmsg(format!("Cannot handle signals: {}", e)))?;
+    daemon
+        .start(
+            &daemon_socket_file,
+            paths.gc_root_dir(),
+            paths.cas_store().clone(),
+        )
+        .map_err(|e| match e {
+            StartError::Bind(BindError::OtherProcessListening) => ExitError::errmsg(format!(
+                "Another daemon is already listening on the socket at {}. \
+                 We are currently only allowing one daemon to be running at the same time.",
+                daemon_socket_file.display()
+            )),
+            e => panic!("{:?}", e),
+        })?;
 
-    let mut pool = Pool::new();
-    pool.spawn("accept-loop", move || loop {
-        let accept_messages_tx = accept_messages_tx.clone();
-        // has to clone handlers once per accept loop,
-        // because accept spawns a thread each time.
-        let handlers = handlers.clone();
-        let _handle = listener
-            .accept(move |unix_stream, comm_type| match comm_type {
-                CommunicationType::Ping => {
-                    handlers.ping(ReadWriter::new(&unix_stream), accept_messages_tx)
-                }
-            })
-            // TODO
-            .unwrap();
-    })
-    .expect("Failed to spawn accept-loop");
+    print_record("lorri: ready", serde_json::json!({"event": "ready"}));
 
-    pool.spawn("build-loop", || {
+    std::thread::spawn(move || {
         for msg in build_messages_rx {
-            println!("{:#?}", msg);
+            match msg {
+                // with `--porcelain`, `lorri internal stream-events` has them;
+                // the lines nix prints are in the build logs
+                Event::LogLine(..) => {}
+                // a build reports progress many times a second
+                Event::Progress(..) => debug!("{:?}", msg),
+                _ if ::ops::porcelain() => {}
+                _ => println!("{:#?}", msg),
+            }
         }
-    })
-    .expect("Failed to spawn build-loop");
+    });
+    daemon.run_until_shutdown();
 
-    println!("lorri: ready");
+    ok()
+}
 
-    pool.spawn("build-instruction-handler", move || {
-        // For each build instruction, add the corresponding file
-        // to the watch list.
-        for start_build in accept_messages_rx {
-            let project = ::project::Project::new(
-                start_build.nix_file,
-                paths.gc_root_dir(),
-                paths.cas_store().clone(),
-            )
-            // TODO: the project needs to create its gc root dir
-            .unwrap();
-            daemon.add(project)
-        }
-    })
-    .expect("failed to spawn build-instruction-handler");
+/// The write end of the pipe the signal handler writes to.
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
 
-    pool.join_all_or_panic();
+/// Ask the daemon to shut down on SIGINT and SIGTERM; the second
+/// signal cancels the running builds (see `Daemon::run_until_shutdown`).
+///
+/// The signal handler only writes the signal to a pipe, a thread
+/// reads it and does the rest.
+fn shut_down_on_signals(shutdown: ShutdownHandle) -> Result<(), nix::Error> {
+    extern "C" fn on_signal(signal: c_int) {
+        // write(2) is async-signal-safe; if the pipe is full,
+        // enough signals are on their way already
+        let _ = write(SIGNAL_PIPE.load(Ordering::SeqCst), &[signal as u8]);
+    }
 
-    ok()
+    let (read_end, write_end): (RawFd, RawFd) = pipe()?;
+    // the processes we start don’t need it
+    for fd in &[read_end, write_end] {
+        fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    SIGNAL_PIPE.store(write_end, Ordering::SeqCst);
+    std::thread::spawn(move || {
+        let mut signal = [0u8];
+        while let Ok(1) = read(read_end, &mut signal) {
+            info!("received signal {}, shutting down", signal[0]);
+            shutdown.request(false);
+        }
+    });
+    let action = SigAction::new(
+        SigHandler::Handler(on_signal),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    for signal in &[Signal::SIGINT, Signal::SIGTERM] {
+        // the handler only touches the pipe, set up above
+        unsafe { sigaction(*signal, &action) }?;
+    }
+    Ok(())
 }
@@ -162,6 +162,12 @@ function declare() {
 
 export IN_NIX_SHELL=impure
 
+# lorri switches the root to a new environment once it is built;
+# resolve it once, so all files are read from the same environment
+if [ -L "$EVALUATION_ROOT" ]; then
+    EVALUATION_ROOT="$(readlink "$EVALUATION_ROOT")"
+fi
+
 if [ -f "$EVALUATION_ROOT/bash-export" ]; then
     # shellcheck disable=SC1090
     . "$EVALUATION_ROOT/bash-export"
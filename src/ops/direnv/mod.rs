@@ -2,88 +2,519 @@
 
 mod version;
 
+pub use self::version::DirenvFeatures;
 use self::version::{DirenvVersion, MIN_DIRENV_VERSION};
-use crate::ops::{ok, ok_msg, ExitError, OpResult};
-use crate::project::roots::Roots;
+use crate::bash;
+use crate::builder::{Progress, ProgressKind};
+use crate::flake::FLAKE_LOCK_FILE_NAME;
+use crate::ops::ping;
+use crate::ops::{ok_msg, ExitError, OpResult};
+use crate::project::config::CONFIG_FILE_NAME;
+use crate::project::env;
+use crate::project::roots::{RootPath, Roots};
 use crate::project::Project;
 use crate::socket::communicate::client;
-use crate::socket::communicate::{Ping, DEFAULT_READ_TIMEOUT};
-use std::process::Command;
+use crate::socket::communicate::{
+    EventMessage, Monitor, PingAttribute, PingResult, RegistrationError, WaitIdle, WaitIdleFor,
+    DEFAULT_READ_TIMEOUT,
+};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use crate::socket::{ReadError, ReadWriteError};
+use crate::NixFile;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How long to wait for the daemon’s kept events of a first build.
+const PROGRESS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How much longer than the requested wait to wait for the daemon’s
+/// answer to `WaitIdleFor`.
+const WAIT_IDLE_MARGIN: Duration = Duration::from_secs(1);
 
 /// See the documentation for lorri::cli::Command::Direnv for more
-/// details.
-pub fn main(project: Project) -> OpResult {
-    check_direnv_version()?;
+/// details. With `json`, print the changes to the environment and
+/// the files to watch as JSON instead of the snippet for direnv
+/// (see `json_delta`). With `max_wait`, wait that long for a build
+/// the daemon is running (see `wait_for_build`).
+pub fn main(
+    project: Project,
+    shell: Option<&str>,
+    json: bool,
+    max_wait: Option<Duration>,
+) -> OpResult {
+    let features = if json {
+        // evaluated by our own bash, direnv might not even be installed
+        DirenvFeatures {
+            strict_env: false,
+            watch_dir: true,
+        }
+    } else {
+        check_direnv_version()?
+    };
 
     let socket_path = ::ops::get_paths()?.daemon_socket_file().to_owned();
 
     let root_paths = Roots::from_project(&project).paths();
-    let paths_are_cached: bool = root_paths.all_exist();
+    let shell_root = match shell {
+        None => &root_paths.shell_gc_root,
+        Some(name) => root_paths.shells.get(name).ok_or_else(|| {
+            ExitError::errmsg(format!(
+                "There is no shell named `{}` in {} (named shells: {})",
+                name,
+                CONFIG_FILE_NAME,
+                root_paths
+                    .shells
+                    .keys()
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        })?,
+    };
+    // the daemon only builds nix files and local flakes
+    let ping = match project.source.nix_file() {
+        Some(nix_file) => ping_daemon(
+            &SocketPath::from(&socket_path),
+            nix_file,
+            project.attribute(),
+        ),
+        None => Pinged::NotRunning,
+    };
 
-    let ping_sent: bool = if let Ok(client) = client::ping(DEFAULT_READ_TIMEOUT).connect(
-        &::socket::path::SocketPath::from(::ops::get_paths()?.daemon_socket_file()),
-    ) {
-        client
-            .write(&Ping {
-                nix_file: project.nix_file.clone(),
-            })
-            .unwrap();
-        true
-    } else {
-        false
+    let stale = match (&ping, max_wait, project.source.nix_file()) {
+        (Pinged::Watched, Some(max_wait), Some(nix_file)) => {
+            !wait_for_build(&SocketPath::from(&socket_path), nix_file, max_wait)
+        }
+        _ => false,
     };
+    // after waiting, the build might have created them
+    let paths_are_cached: bool = root_paths.all_exist();
 
-    match (ping_sent, paths_are_cached) {
-        (true, true) => {}
+    match (ping, paths_are_cached) {
+        (Pinged::Watched, true) => {
+            if stale {
+                eprintln!(
+                    "Info: lorri is still building this project. Loading the previous environment,"
+                );
+                eprintln!("      direnv reloads it once the build completes.");
+            }
+        }
 
         // Ping sent & paths aren't cached: once the environment is created
         // the direnv environment will be updated automatically.
-        (true, false) => {
+        (Pinged::Watched, false) => {
             eprintln!("Notice: lorri has not completed an evaluation for this project yet.");
             eprintln!("        lorri should be evaluating the environment now.");
+            if let Some(progress) = project
+                .source
+                .nix_file()
+                .and_then(|nix_file| build_progress(&SocketPath::from(&socket_path), nix_file))
+            {
+                eprintln!("        building dev env: {}", progress);
+            }
+        }
+
+        // The daemon can't build the project: say why, and load
+        // whatever environment there is.
+        (Pinged::Refused(e), cached) => {
+            eprintln!("Error: the lorri daemon cannot watch this project: {}.", e);
+            if cached {
+                eprintln!("       Loading a cached environment.");
+            }
         }
 
         // Ping not sent and paths are cached: we can load a stale environment
         // When the daemon is started, we'll send a fresh ping.
-        (false, true) => {
+        (Pinged::NotRunning, true) => {
             eprintln!("Info: the lorri daemon is not running. Loading a cached environment.");
         }
 
         // Ping not sent and paths are not cached: we can't load anything,
         // but when the daemon in started we'll send a ping and eventually
         // load a fresh environment.
-        (false, false) => {
+        (Pinged::NotRunning, false) => {
             eprintln!("Error: the lorri daemon is not running and this project has not yet been evaluated.");
             eprintln!("       Please run `lorri daemon`.");
         }
     }
 
-    if std::env::var("DIRENV_IN_ENVRC") != Ok(String::from("1")) {
+    if !json && std::env::var("DIRENV_IN_ENVRC") != Ok(String::from("1")) {
         eprintln!(
             "Warning: 'lorri direnv' should be executed by direnv from within an `.envrc` file."
         )
     }
 
-    ok_msg(format!(
+    let mut snippet = envrc_snippet(shell_root, &socket_path, &watch_files(&project), features);
+    if stale && paths_are_cached {
+        snippet.push_str("export LORRI_STALE=1\n");
+    }
+    // the hash is of the default shell’s environment
+    if let (None, Some(hash)) = (shell, project.env_hash()) {
+        snippet.push_str(&format!("export LORRI_ENV_HASH={}\n", bash::quote(&hash)));
+    }
+    if json {
+        ok_msg(json_delta(&snippet)?)
+    } else {
+        ok_msg(snippet)
+    }
+}
+
+/// What came of pinging the daemon.
+enum Pinged {
+    /// The daemon watches the project.
+    Watched,
+    /// The daemon can’t watch the project.
+    Refused(RegistrationError),
+    /// No daemon is running (or the project is no nix file).
+    NotRunning,
+}
+
+/// Ping the daemon at `socket_path` with `nix_file`, or with the
+/// `attribute` of it (see `PingAttribute`).
+fn ping_daemon(socket_path: &SocketPath, nix_file: NixFile, attribute: Option<&str>) -> Pinged {
+    let answer = match attribute {
+        None => client::ping(DEFAULT_READ_TIMEOUT)
+            .connect(socket_path)
+            .map(|client| ping::send(client, nix_file)),
+        Some(attribute) => client::ping_attribute(DEFAULT_READ_TIMEOUT)
+            .connect(socket_path)
+            .map(|client| {
+                client.request(&PingAttribute {
+                    nix_file,
+                    attribute: attribute.to_string(),
+                })
+            }),
+    };
+    match answer {
+        Ok(Ok(PingResult::Registered)) => Pinged::Watched,
+        Ok(Ok(PingResult::Refused(e))) => Pinged::Refused(e),
+        Ok(Err(e)) => {
+            eprintln!("Warning: could not ping the lorri daemon: {:?}", e);
+            Pinged::Watched
+        }
+        Err(_) => Pinged::NotRunning,
+    }
+}
+
+/// Wait up to `max_wait` until the daemon has no pending or running
+/// build of `nix_file`. Whether it finished in time; `false` if it
+/// is still building. Other errors are reported, but not waited for.
+fn wait_for_build(socket_path: &SocketPath, nix_file: NixFile, max_wait: Duration) -> bool {
+    let client =
+        match client::wait_idle_for(millis(max_wait + WAIT_IDLE_MARGIN)).connect(socket_path) {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Warning: could not wait for the lorri daemon: {:?}", e);
+                return true;
+            }
+        };
+    match client.request(&WaitIdleFor {
+        nix_file: Some(nix_file.clone()),
+        max_wait_ms: max_wait.as_millis() as u64,
+    }) {
+        Ok(result) => result.idle,
+        // daemons before protocol version 19 don’t know `WaitIdleFor`
+        Err(ref e) if e.is_hang_up() => wait_for_build_unbounded(socket_path, nix_file, max_wait),
+        Err(client::Error::Message(ReadWriteError::R(ReadError::Timeout))) => false,
+        Err(e) => {
+            eprintln!("Warning: could not wait for the lorri daemon: {:?}", e);
+            true
+        }
+    }
+}
+
+/// Like `wait_for_build`, for daemons which keep waiting after we
+/// gave up.
+fn wait_for_build_unbounded(
+    socket_path: &SocketPath,
+    nix_file: NixFile,
+    max_wait: Duration,
+) -> bool {
+    let client = match client::wait_idle(millis(max_wait)).connect(socket_path) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Warning: could not wait for the lorri daemon: {:?}", e);
+            return true;
+        }
+    };
+    match client.request(&WaitIdle {
+        nix_file: Some(nix_file),
+    }) {
+        Ok(_) => true,
+        Err(client::Error::Message(ReadWriteError::R(ReadError::Timeout))) => false,
+        Err(e) => {
+            eprintln!("Warning: could not wait for the lorri daemon: {:?}", e);
+            true
+        }
+    }
+}
+
+/// `duration` as a socket timeout, which is at most `u16::MAX`
+/// milliseconds.
+fn millis(duration: Duration) -> Timeout {
+    Timeout::from_millis(duration.as_millis().min(u128::from(std::u16::MAX)) as u16)
+}
+
+/// Parse the `--max-wait` of `lorri direnv`: a number of
+/// milliseconds or seconds, like `200ms` or `2s`, of at most a
+/// minute.
+pub fn parse_max_wait(max_wait: &str) -> Result<Duration, String> {
+    let error = || {
+        format!(
+            "invalid duration `{}`, expected milliseconds or seconds of at most a minute (like `200ms` or `2s`)",
+            max_wait
+        )
+    };
+    let (number, unit) = if max_wait.ends_with("ms") {
+        (&max_wait[..max_wait.len() - 2], 1)
+    } else if max_wait.ends_with('s') {
+        (&max_wait[..max_wait.len() - 1], 1000)
+    } else {
+        return Err(error());
+    };
+    let millis = number
+        .parse::<u64>()
+        .map_err(|_| error())?
+        .checked_mul(unit)
+        .ok_or_else(error)?;
+    if millis > 60_000 {
+        return Err(error());
+    }
+    Ok(Duration::from_millis(millis))
+}
+
+/// How far the daemon got with the running build of `nix_file`, like
+/// `34/120 derivations, 1.5/12.0 MiB downloaded`, from the events it
+/// still keeps; `None` if it reported no progress (yet).
+fn build_progress(socket_path: &SocketPath, nix_file: NixFile) -> Option<String> {
+    let answers = client::monitor(Timeout::from_millis(100))
+        .connect(socket_path)
+        .ok()?
+        .request_stream(&Monitor {
+            nix_file: Some(nix_file),
+            since: Some(0),
+        })
+        .ok()?;
+    let deadline = Instant::now() + PROGRESS_TIMEOUT;
+    let mut lines = vec![];
+    // the kept events come right away, then reading times out
+    for answer in answers {
+        match answer {
+            Ok(EventMessage::Event(line)) => lines.push(line),
+            Ok(EventMessage::Gap { .. }) => (),
+            Err(_) => break,
+        }
+        if Instant::now() > deadline {
+            break;
+        }
+    }
+    progress_summary(&lines)
+}
+
+/// The latest progress of each kind in the event `lines` of a
+/// project, since its last build started.
+fn progress_summary(lines: &[String]) -> Option<String> {
+    let mut latest: Vec<Progress> = vec![];
+    for line in lines {
+        let event: serde_json::Value = match serde_json::from_str(line) {
+            Ok(event) => event,
+            Err(_) => continue,
+        };
+        match event["event"].as_str() {
+            Some("started") | Some("completed") | Some("failure") | Some("cancelled") => {
+                latest.clear()
+            }
+            Some("progress") => {
+                if let Ok(progress) = serde_json::from_value::<Progress>(event) {
+                    latest.retain(|p| p.kind != progress.kind);
+                    latest.push(progress);
+                }
+            }
+            _ => (),
+        }
+    }
+    let summary = [
+        ProgressKind::Builds,
+        ProgressKind::Downloads,
+        ProgressKind::Bytes,
+    ]
+    .iter()
+    .filter_map(|kind| latest.iter().find(|p| p.kind == *kind))
+    .map(Progress::to_string)
+    .collect::<Vec<_>>();
+    if summary.is_empty() {
+        None
+    } else {
+        Some(summary.join(", "))
+    }
+}
+
+/// Evaluate the direnv `snippet` in bash on top of the current
+/// environment, and describe what it does as JSON, for tools which
+/// apply the environment themselves:
+///
+/// ```json
+/// {
+///   "set": {"PATH": "/nix/store/…-hello/bin:/usr/bin", "name": "project"},
+///   "unset": [],
+///   "watch_files": ["/run/user/1000/lorri/daemon.socket", "…/shell.nix"],
+///   "watch_dirs": ["/home/user/.cache/lorri/gc_roots/…/gc_root"]
+/// }
+/// ```
+///
+/// `set` has the variables the snippet sets or changes, `unset`
+/// the ones it removes (session variables like `HOME` aside).
+pub fn json_delta(snippet: &str) -> Result<String, ExitError> {
+    let output = Command::new("bash")
+        .args(&[
+            "-c",
+            r#"exec 3>&1
+watch_file() { for file in "$@"; do printf 'file\0%s\0' "$file" >&3; done; }
+watch_dir() { printf 'dir\0%s\0' "$1" >&3; }
+eval "$1" >&2
+for name in $(compgen -e); do printf 'var\0%s\0%s\0' "$name" "${!name}"; done"#,
+            "--",
+            snippet,
+        ])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| ExitError::errmsg(format!("Could not run bash: {}", e)))?;
+    if !output.status.success() {
+        return Err(ExitError::errmsg("Could not evaluate the environment"));
+    }
+    let before: BTreeMap<String, String> = std::env::vars_os()
+        .map(|(name, value)| {
+            (
+                name.to_string_lossy().into_owned(),
+                value.to_string_lossy().into_owned(),
+            )
+        })
+        .collect();
+    Ok(delta(&before, &String::from_utf8_lossy(&output.stdout)).to_string())
+}
+
+/// The changes from the variables `before` to the `file`, `dir` and
+/// `var` records in `output` (see `json_delta`).
+fn delta(before: &BTreeMap<String, String>, output: &str) -> serde_json::Value {
+    let mut fields = output.split('\0');
+    let (mut watch_files, mut watch_dirs) = (vec![], vec![]);
+    let mut after = BTreeMap::new();
+    while let Some(tag) = fields.next() {
+        match (tag, fields.next()) {
+            ("file", Some(file)) => watch_files.push(file),
+            ("dir", Some(dir)) => watch_dirs.push(dir),
+            ("var", Some(name)) => {
+                after.insert(name, fields.next().unwrap_or_default());
+            }
+            _ => break,
+        }
+    }
+    let set: BTreeMap<&str, &str> = after
+        .iter()
+        .filter(|(name, value)| {
+            !env::is_session_variable(name)
+                && before.get(**name).map(String::as_str) != Some(**value)
+        })
+        .map(|(name, value)| (*name, *value))
+        .collect();
+    let unset: Vec<&str> = before
+        .keys()
+        .map(String::as_str)
+        .filter(|name| !env::is_session_variable(name) && !after.contains_key(name))
+        .collect();
+    serde_json::json!({
+        "set": set,
+        "unset": unset,
+        "watch_files": watch_files,
+        "watch_dirs": watch_dirs,
+    })
+}
+
+/// Files which change the environment lorri builds for `project`,
+/// besides the sources the daemon watches. direnv watches them, too,
+/// so that it reloads right away when the project is reconfigured.
+/// (Files which don’t exist yet are fine, direnv reloads once they
+/// are created.)
+pub fn watch_files(project: &Project) -> Vec<PathBuf> {
+    let mut files = vec![];
+    if let Some(nix_file) = project.source.nix_file() {
+        files.push(PathBuf::from(nix_file.as_os_str()));
+    }
+    files.push(project.config_root().join(CONFIG_FILE_NAME));
+    files.push(project.project_dir().join(FLAKE_LOCK_FILE_NAME));
+    files
+}
+
+/// The shell snippet `lorri direnv` hands to direnv for evaluation,
+/// loading the environment from `shell_root` and watching
+/// `watch_files` in addition to the environment. Uses what direnv
+/// offers according to `features`.
+pub fn envrc_snippet(
+    shell_root: &RootPath,
+    socket_path: &Path,
+    watch_files: &[PathBuf],
+    features: DirenvFeatures,
+) -> String {
+    // lorri replaces the roots by renaming new ones into their
+    // directory, which `watch_dir` notices even if the root’s own
+    // modification time doesn’t change
+    let watch_roots = match Path::new(shell_root.as_os_str()).parent() {
+        Some(dir) if features.watch_dir => {
+            format!("watch_dir {}\n", bash::quote(&dir.to_string_lossy()))
+        }
+        _ => String::new(),
+    };
+    let (lift_strict_mode, restore_strict_mode) = if features.strict_env {
+        (
+            r#"# the environment doesn’t load in strict mode (see `strict_env`)
+LORRI_STRICT_OPTIONS=""
+for option in errexit nounset pipefail; do
+    if shopt -qo "$option"; then LORRI_STRICT_OPTIONS="$LORRI_STRICT_OPTIONS -o $option"; fi
+done
+set +euo pipefail
+"#,
+            r#"if [ -n "$LORRI_STRICT_OPTIONS" ]; then set $LORRI_STRICT_OPTIONS; fi
+unset LORRI_STRICT_OPTIONS option
+"#,
+        )
+    } else {
+        ("", "")
+    };
+    format!(
         r#"
 EVALUATION_ROOT="{}"
 
 watch_file "{}"
 watch_file "$EVALUATION_ROOT"
-
-{}
-"#,
-        root_paths.shell_gc_root,
+{}{}
+{}{}
+{}"#,
+        shell_root,
         socket_path
-            .into_os_string()
-            .into_string()
+            .to_str()
             .expect("Socket path is not UTF-8 clean!"),
-        include_str!("envrc.bash")
-    ))
+        watch_roots,
+        watch_file_lines(watch_files),
+        lift_strict_mode,
+        include_str!("envrc.bash"),
+        restore_strict_mode,
+    )
+}
+
+/// A `watch_file` line for each of `files`.
+fn watch_file_lines(files: &[PathBuf]) -> String {
+    files
+        .iter()
+        .map(|file| format!("watch_file {}\n", bash::quote(&file.to_string_lossy())))
+        .collect()
 }
 
-/// Checks `direnv version` against the minimal version lorri requires.
-fn check_direnv_version() -> OpResult {
+/// Checks `direnv version` against the minimal version lorri requires,
+/// and returns the features of the version.
+pub fn check_direnv_version() -> Result<DirenvFeatures, ExitError> {
     let out = with_command("direnv", |mut cmd| cmd.arg("version").output())?;
     let version = std::str::from_utf8(&out.stdout)
         .map_err(|_| ())
@@ -98,14 +529,14 @@ fn check_direnv_version() -> OpResult {
             version, MIN_DIRENV_VERSION
         )))
     } else {
-        ok()
+        Ok(DirenvFeatures::of(&version))
     }
 }
 
 /// constructs a `Command` out of `executable`
 /// Recognizes the case in which the executable is missing,
 /// and converts it to a corresponding `ExitError`.
-fn with_command<T, F>(executable: &str, cmd: F) -> Result<T, ExitError>
+pub fn with_command<T, F>(executable: &str, cmd: F) -> Result<T, ExitError>
 where
     F: FnOnce(Command) -> std::io::Result<T>,
 {
@@ -119,3 +550,148 @@ where
         },
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        delta, envrc_snippet, json_delta, parse_max_wait, progress_summary, watch_file_lines,
+        DirenvFeatures,
+    };
+    use project::roots::RootPath;
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::time::Duration;
+
+    #[test]
+    fn max_wait() {
+        assert_eq!(parse_max_wait("200ms"), Ok(Duration::from_millis(200)));
+        assert_eq!(parse_max_wait("2s"), Ok(Duration::from_secs(2)));
+        assert!(parse_max_wait("200").is_err());
+        assert!(parse_max_wait("2m").is_err());
+        assert!(parse_max_wait("61s").is_err());
+    }
+
+    #[test]
+    fn progress_of_the_running_build() {
+        let lines = |events: &[&str]| events.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let build = r#"{"event":"progress","kind":"Builds","done":3,"expected":120}"#;
+        assert_eq!(
+            progress_summary(&lines(&[
+                r#"{"event":"started"}"#,
+                build,
+                r#"{"event":"progress","kind":"Bytes","done":1048576,"expected":4194304}"#,
+                r#"{"event":"progress","kind":"Builds","done":34,"expected":120}"#,
+            ])),
+            Some(String::from("34/120 derivations, 1.0/4.0 MiB downloaded"))
+        );
+        assert_eq!(
+            progress_summary(&lines(&[build, r#"{"event":"failure"}"#])),
+            None
+        );
+    }
+
+    #[test]
+    fn quoted_watch_files() {
+        assert_eq!(
+            watch_file_lines(&[
+                PathBuf::from("/project/shell.nix"),
+                PathBuf::from("/it's/.lorri.toml")
+            ]),
+            "watch_file '/project/shell.nix'\nwatch_file '/it'\\''s/.lorri.toml'\n"
+        );
+    }
+
+    fn snippet(gc_root_dir: &Path, features: DirenvFeatures) -> String {
+        let root = RootPath::from(gc_root_dir.join("shell_gc_root"));
+        envrc_snippet(&root, Path::new("/run/lorri/daemon.socket"), &[], features)
+    }
+
+    /// The snippet only uses what the direnv version offers.
+    #[test]
+    fn snippet_per_direnv_version() {
+        let dir = Path::new("/cache/lorri/gc_roots/abc/gc_root");
+        for version in &["2.19.2", "2.21.0", "2.28.0"] {
+            let features = DirenvFeatures::of(&version.parse().unwrap());
+            let snippet = snippet(dir, features);
+            assert_eq!(
+                snippet.contains("watch_dir '/cache/lorri/gc_roots/abc/gc_root'\n"),
+                features.watch_dir,
+                "direnv {}",
+                version
+            );
+            assert_eq!(
+                snippet.contains("set +euo pipefail"),
+                features.strict_env,
+                "direnv {}",
+                version
+            );
+        }
+    }
+
+    /// With `strict_env`, an environment which uses unset variables
+    /// still loads, and strict mode is restored afterwards.
+    #[test]
+    fn snippet_in_strict_mode() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        std::fs::write(
+            tmp.path().join("shell_gc_root"),
+            "declare -x LORRI_TEST_VAR=\"${UNSET_VAR}set\"\n",
+        )?;
+        let features = DirenvFeatures {
+            strict_env: true,
+            watch_dir: true,
+        };
+        let out = Command::new("bash")
+            .args(&["-euo", "pipefail", "-c"])
+            .arg(
+                r#"
+watch_file() { :; }
+watch_dir() { :; }
+eval "$1"
+printf '%s' "$LORRI_TEST_VAR"
+shopt -qo errexit && shopt -qo nounset && shopt -qo pipefail && printf ' strict'"#,
+            )
+            .arg("bash")
+            .arg(snippet(tmp.path(), features))
+            .env_remove("UNSET_VAR")
+            .output()?;
+        assert!(out.status.success(), "{:?}", out);
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "set strict");
+        Ok(())
+    }
+
+    #[test]
+    fn environment_delta() {
+        let before: BTreeMap<String, String> = [("PATH", "/bin"), ("OLD", "1"), ("SAME", "same")]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let output = "file\0/p/shell.nix\0dir\0/root\0var\0PATH\0/nix/bin:/bin\0\
+                      var\0SAME\0same\0var\0NEW\0a b\0var\0HOME\0/home\0";
+        assert_eq!(
+            delta(&before, output),
+            serde_json::json!({
+                "set": {"NEW": "a b", "PATH": "/nix/bin:/bin"},
+                "unset": ["OLD"],
+                "watch_files": ["/p/shell.nix"],
+                "watch_dirs": ["/root"],
+            })
+        );
+    }
+
+    #[test]
+    fn json_from_snippet() {
+        let json = json_delta(
+            "watch_file /p/shell.nix /p/.lorri.toml; watch_dir /root; export LORRI_JSON_TEST='x y'",
+        )
+        .unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(json["set"], serde_json::json!({"LORRI_JSON_TEST": "x y"}));
+        assert_eq!(
+            json["watch_files"],
+            serde_json::json!(["/p/shell.nix", "/p/.lorri.toml"])
+        );
+        assert_eq!(json["watch_dirs"], serde_json::json!(["/root"]));
+    }
+}
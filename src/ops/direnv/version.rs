@@ -5,6 +5,33 @@ pub struct DirenvVersion(usize, usize, usize);
 
 pub const MIN_DIRENV_VERSION: DirenvVersion = DirenvVersion(2, 19, 2);
 
+/// direnv added `strict_env` (and `unstrict_env`) in this version.
+const STRICT_ENV_SINCE: DirenvVersion = DirenvVersion(2, 21, 0);
+
+/// direnv added `watch_dir` in this version.
+const WATCH_DIR_SINCE: DirenvVersion = DirenvVersion(2, 28, 0);
+
+/// The features of a direnv version the `lorri direnv` snippet
+/// adapts to.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct DirenvFeatures {
+    /// The `.envrc` might run in strict mode (`set -euo pipefail`),
+    /// so loading the environment has to lift it.
+    pub strict_env: bool,
+    /// `watch_dir` watches all files in a directory.
+    pub watch_dir: bool,
+}
+
+impl DirenvFeatures {
+    /// The features of direnv `version`.
+    pub fn of(version: &DirenvVersion) -> DirenvFeatures {
+        DirenvFeatures {
+            strict_env: *version >= STRICT_ENV_SINCE,
+            watch_dir: *version >= WATCH_DIR_SINCE,
+        }
+    }
+}
+
 /// `"a.b.c"`, e.g. `"2.19.2"`.
 impl FromStr for DirenvVersion {
     type Err = ();
@@ -62,6 +89,23 @@ mod tests {
         eq((5, 0, 1), (1, 0, 0), Ordering::Greater);
     }
 
+    /// The features of the direnv versions lorri supports
+    #[test]
+    fn features_matrix() {
+        let features = |version: &str| DirenvFeatures::of(&version.parse().unwrap());
+        let feature_set = |strict_env, watch_dir| DirenvFeatures {
+            strict_env,
+            watch_dir,
+        };
+        assert_eq!(features("2.19.2"), feature_set(false, false));
+        assert_eq!(features("2.20.1"), feature_set(false, false));
+        assert_eq!(features("2.21.0"), feature_set(true, false));
+        assert_eq!(features("2.27.0"), feature_set(true, false));
+        assert_eq!(features("2.28.0"), feature_set(true, true));
+        assert_eq!(features("2.32.3"), feature_set(true, true));
+        assert_eq!(features("3.0.0"), feature_set(true, true));
+    }
+
     proptest! {
         /// Parsing roundtrip
         #[test]
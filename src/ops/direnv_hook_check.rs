@@ -0,0 +1,252 @@
+//! Check the direnv hook chain of a project, link by link.
+//!
+//! Most reports of “lorri doesn’t work” turn out to be a broken
+//! direnv setup, not a broken lorri. This walks the chain in the
+//! order direnv does and stops at the first broken link.
+
+use crate::ops::direnv::{check_direnv_version, envrc_snippet, watch_files, with_command};
+use crate::ops::{ok_msg, print_record, ExitError, OpResult};
+use crate::project::roots::Roots;
+use crate::project::Project;
+use std::path::Path;
+
+/// See the documentation for lorri::cli::Internal_::DirenvHookCheck
+/// for more details.
+pub fn main(project: Project) -> OpResult {
+    let project_dir = std::env::current_dir()
+        .map_err(|e| ExitError::errmsg(format!("Cannot access the current directory: {}", e)))?;
+    let envrc = project_dir.join(".envrc");
+    let paths = ::ops::get_paths()?;
+    let root_paths = Roots::from_project(&project).paths();
+
+    let features = link(
+        "direnv is installed and recent enough",
+        check_direnv_version().map_err(|e| e.message().to_string()),
+    )?;
+    link(".envrc calls `lorri direnv`", check_envrc(&envrc))?;
+    link(".envrc is allowed by direnv", check_allowed(&project_dir))?;
+    link(
+        "the project has been evaluated by lorri",
+        if root_paths.all_exist() {
+            Ok(())
+        } else {
+            Err(String::from(
+                "No environment has been built for this project yet.\n\
+                 Start `lorri daemon` and enter the directory, or run `lorri watch --once`.",
+            ))
+        },
+    )?;
+    let exported_path = link(
+        "the exported snippet evaluates in bash",
+        eval_snippet(&envrc_snippet(
+            &root_paths.shell_gc_root,
+            paths.daemon_socket_file(),
+            &watch_files(&project),
+            features,
+        )),
+    )?;
+    let env_path = link(
+        "the environment's PATH can be read",
+        eval_env_path(&root_paths.bash_export()),
+    )?;
+    link(
+        "PATH contains the environment's bin directories",
+        match missing_entries(&env_path, &exported_path).first() {
+            None => Ok(()),
+            Some(dir) => Err(format!(
+                "`{}` is in the environment's PATH, but not in the exported PATH:\n{}",
+                dir, exported_path
+            )),
+        },
+    )?;
+
+    ok_msg("\nThe direnv hook chain looks fine.")
+}
+
+/// Print the outcome of checking one link of the chain.
+/// The first broken link is turned into the exit error.
+fn link<T>(description: &str, result: Result<T, String>) -> Result<T, ExitError> {
+    match result {
+        Ok(t) => {
+            print_record(
+                &format!("ok:     {}", description),
+                serde_json::json!({"link": description, "ok": true}),
+            );
+            Ok(t)
+        }
+        Err(explanation) => {
+            print_record(
+                &format!("FAILED: {}", description),
+                serde_json::json!({"link": description, "ok": false}),
+            );
+            Err(ExitError::errmsg(format!(
+                "\nThe first broken link is: {}\n{}",
+                description, explanation
+            )))
+        }
+    }
+}
+
+/// The `.envrc` must exist and hand over to lorri.
+fn check_envrc(envrc: &Path) -> Result<(), String> {
+    match std::fs::read_to_string(envrc) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Err(format!(
+            "There is no {}. Run `lorri init` to create one.",
+            envrc.display()
+        )),
+        Err(e) => Err(format!("Cannot read {}: {}", envrc.display(), e)),
+        Ok(contents) => {
+            if contents.lines().any(evals_lorri_direnv) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{} does not call lorri. Add the line\n\n    eval \"$(lorri direnv)\"\n\nto it.",
+                    envrc.display()
+                ))
+            }
+        }
+    }
+}
+
+/// Whether a line of `.envrc` evaluates `lorri direnv` like the
+/// one `lorri init` writes does: `eval "$(lorri direnv)"`, maybe
+/// with options (but not commented out).
+fn evals_lorri_direnv(line: &str) -> bool {
+    let line = line.trim();
+    let (prefix, suffix) = ("eval \"$(lorri direnv", ")\"");
+    if !line.starts_with(prefix)
+        || !line.ends_with(suffix)
+        || line.len() < prefix.len() + suffix.len()
+    {
+        return false;
+    }
+    let options = &line[prefix.len()..line.len() - suffix.len()];
+    options.is_empty() || options.starts_with(' ')
+}
+
+/// `direnv status` must find the `.envrc` and consider it allowed.
+fn check_allowed(project_dir: &Path) -> Result<(), String> {
+    let out = with_command("direnv", |mut cmd| {
+        cmd.arg("status").current_dir(project_dir).output()
+    })
+    .map_err(|e| e.message().to_string())?;
+    match rc_allowed(&String::from_utf8_lossy(&out.stdout)) {
+        None => Err(String::from(
+            "direnv did not find the .envrc. Is direnv hooked into your shell?\n\
+             See https://direnv.net/docs/hook.html",
+        )),
+        Some(false) => Err(String::from(
+            "The .envrc is not allowed. Run `direnv allow` after reviewing it.",
+        )),
+        Some(true) => Ok(()),
+    }
+}
+
+/// Parse the “allowed” line of `direnv status`.
+///
+/// Older direnv versions print `true`/`false`, newer ones print
+/// the numeric allow status, where `0` means allowed.
+fn rc_allowed(status: &str) -> Option<bool> {
+    status
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("Found RC allowed "))
+        .map(|line| ["true", "0"].contains(&line["Found RC allowed ".len()..].trim()))
+}
+
+/// Evaluate the `lorri direnv` snippet in a scratch bash and
+/// return the resulting `PATH`.
+fn eval_snippet(snippet: &str) -> Result<String, String> {
+    bash_output(
+        r#"
+watch_file() { :; }
+watch_dir() { :; }
+eval "$1"
+printf '%s' "$PATH"
+"#,
+        snippet,
+    )
+}
+
+/// Source the environment built by nix and return its `PATH`.
+fn eval_env_path(bash_export: &Path) -> Result<String, String> {
+    bash_output(
+        r#". "$1" && printf '%s' "$PATH""#,
+        bash_export
+            .to_str()
+            .ok_or_else(|| format!("{} is not UTF-8 clean", bash_export.display()))?,
+    )
+}
+
+/// Run `script` in bash with `arg` as `$1` and return its stdout.
+fn bash_output(script: &str, arg: &str) -> Result<String, String> {
+    let out = with_command("bash", |mut cmd| {
+        cmd.args(&["-c", script, "--", arg]).output()
+    })
+    .map_err(|e| e.message().to_string())?;
+    if out.status.success() {
+        Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+    } else {
+        Err(format!(
+            "bash exited with {}:\n{}",
+            out.status,
+            String::from_utf8_lossy(&out.stderr)
+        ))
+    }
+}
+
+/// Entries of the `expected` PATH which are missing from `actual`.
+fn missing_entries<'a>(expected: &'a str, actual: &str) -> Vec<&'a str> {
+    expected
+        .split(':')
+        .filter(|dir| !dir.is_empty() && !actual.split(':').any(|a| a == *dir))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{evals_lorri_direnv, missing_entries, rc_allowed};
+
+    #[test]
+    fn envrc_lines() {
+        assert!(evals_lorri_direnv("eval \"$(lorri direnv)\""));
+        assert!(evals_lorri_direnv(
+            "  eval \"$(lorri direnv --shell-file dev.nix)\"\n"
+        ));
+        // lorri ships no direnv function
+        assert!(!evals_lorri_direnv("use lorri"));
+        assert!(!evals_lorri_direnv("# eval \"$(lorri direnv)\""));
+        assert!(!evals_lorri_direnv("eval \"$(lorri direnvx)\""));
+        assert!(!evals_lorri_direnv("echo lorri direnv"));
+    }
+
+    #[test]
+    fn parse_direnv_status() {
+        let status = |allowed: &str| {
+            format!(
+                "direnv exec path /usr/bin/direnv\n\
+                 Found RC path /home/user/project/.envrc\n\
+                 Found RC allowed {}\n\
+                 Found RC allowPath /home/user/.local/share/direnv/allow/abc\n",
+                allowed
+            )
+        };
+        assert_eq!(rc_allowed(&status("true")), Some(true));
+        assert_eq!(rc_allowed(&status("false")), Some(false));
+        assert_eq!(rc_allowed(&status("0")), Some(true));
+        assert_eq!(rc_allowed(&status("2")), Some(false));
+        assert_eq!(rc_allowed("No .envrc or .env loaded\n"), None);
+    }
+
+    #[test]
+    fn path_entries() {
+        assert_eq!(
+            missing_entries(
+                "/nix/store/a/bin:/nix/store/b/bin",
+                "/nix/store/a/bin:/usr/bin"
+            ),
+            vec!["/nix/store/b/bin"]
+        );
+        assert!(missing_entries("/a::/b", "/b:/a").is_empty());
+    }
+}
@@ -0,0 +1,255 @@
+//! Remove the GC roots of projects which no longer exist, so that
+//! the nix store can garbage collect their environments.
+
+use crate::nix::Store;
+use crate::ops::{ok_msg, print_record, ExitError, OpResult};
+use crate::project;
+use crate::project::config::utc_timestamp;
+use crate::project::roots::Roots;
+use crate::NixFile;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// See the documentation for lorri::cli::Command::Gc for more
+/// details.
+pub fn main(dry_run: bool, older_than: Option<Duration>) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let io_error = |e: std::io::Error| {
+        ExitError::errmsg(format!(
+            "Cannot read {}: {}",
+            paths.gc_root_dir().display(),
+            e
+        ))
+    };
+    let mut root_dirs = std::fs::read_dir(paths.gc_root_dir())
+        .map_err(io_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io_error)?;
+    root_dirs.sort();
+
+    let store = Store::from_env();
+    let now = SystemTime::now();
+    let (mut projects, mut removed, mut failed) = (0, 0, 0);
+    for root_dir in root_dirs.iter().filter(|dir| dir.is_dir()) {
+        projects += 1;
+        let stale = match stale(root_dir, now, older_than) {
+            Some(stale) => stale,
+            None => continue,
+        };
+        let record = |action: &str, error: Option<String>| {
+            serde_json::json!({
+                "action": action,
+                "root_dir": root_dir.display().to_string(),
+                "nix_file": stale.nix_file.as_ref().map(|nix_file| nix_file.to_string()),
+                "last_built": stale.last_built.map(utc_timestamp),
+                "error": error,
+            })
+        };
+        let description = format!(
+            "{} ({}, last built {})",
+            root_dir.display(),
+            stale
+                .nix_file
+                .as_ref()
+                .map(|nix_file| nix_file.to_string())
+                .unwrap_or_else(|| String::from("unknown project")),
+            stale
+                .last_built
+                .map(|time| utc_timestamp(time) + " UTC")
+                .unwrap_or_else(|| String::from("never"))
+        );
+        if dry_run {
+            removed += 1;
+            print_record(
+                &format!("would remove: {}", description),
+                record("would-remove", None),
+            );
+            continue;
+        }
+        match Roots::in_dir(root_dir, store.clone()).remove() {
+            Ok(()) => {
+                removed += 1;
+                print_record(
+                    &format!("removed: {}", description),
+                    record("removed", None),
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                print_record(
+                    &format!("could not remove {}: {}", description, e),
+                    record("failed", Some(e.to_string())),
+                );
+            }
+        }
+    }
+
+    let summary = format!(
+        "{} the roots of {} of {} projects{}",
+        if dry_run { "would remove" } else { "removed" },
+        removed,
+        projects,
+        if removed > 0 && !dry_run {
+            "; run `nix-collect-garbage` to free the space of their environments"
+        } else {
+            ""
+        }
+    );
+    if failed == 0 {
+        ok_msg(summary)
+    } else {
+        Err(ExitError::errmsg(format!(
+            "{}, {} could not be removed",
+            summary, failed
+        )))
+    }
+}
+
+/// The roots of a project which no longer exists.
+#[derive(Debug, PartialEq)]
+struct Stale {
+    /// The nix file lorri recorded for the project, if it did.
+    nix_file: Option<NixFile>,
+    /// When the project’s shell was last rooted, if it ever was.
+    last_built: Option<SystemTime>,
+}
+
+/// Whether the roots in `root_dir` (a directory in
+/// `Paths.gc_root_dir()`) can go: lorri recorded the nix file of
+/// their project, and its directory no longer exists. With
+/// `older_than`, the project also has to be built that long before
+/// `now`.
+///
+/// Older lorri versions recorded neither the nix file nor the source
+/// of a project (see `project::source_in`); their roots only go with
+/// `older_than`, by age alone. Roots of other sources are kept.
+fn stale(root_dir: &Path, now: SystemTime, older_than: Option<Duration>) -> Option<Stale> {
+    let nix_file = match project::nix_file_in(root_dir) {
+        Some(nix_file) => {
+            if Path::new(nix_file.as_os_str()).parent()?.exists() {
+                return None;
+            }
+            Some(nix_file)
+        }
+        None if older_than.is_some() && project::source_in(root_dir).is_none() => None,
+        None => return None,
+    };
+    // roots are replaced with every successful build
+    let shell_gc_root = PathBuf::from(
+        Roots::in_dir(root_dir, Store::Default)
+            .paths()
+            .shell_gc_root
+            .as_os_str(),
+    );
+    let last_built = std::fs::symlink_metadata(shell_gc_root)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    if let Some(older_than) = older_than {
+        let last_used = last_built.or_else(|| {
+            std::fs::metadata(root_dir)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })?;
+        match now.duration_since(last_used) {
+            Ok(age) if age >= older_than => {}
+            _ => return None,
+        }
+    }
+    Some(Stale {
+        nix_file,
+        last_built,
+    })
+}
+
+/// Parse an age like `30d`: a number followed by `s`, `m`, `h`, `d`
+/// or `w` (seconds, minutes, hours, days or weeks).
+pub fn parse_age(age: &str) -> Result<Duration, String> {
+    let error = || {
+        format!(
+            "invalid age `{}`, expected a number followed by s, m, h, d or w (like `30d`)",
+            age
+        )
+    };
+    let split = age.len().saturating_sub(1);
+    if !age.is_char_boundary(split) {
+        return Err(error());
+    }
+    let (number, unit) = age.split_at(split);
+    let number: u64 = number.parse().map_err(|_| error())?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(error()),
+    };
+    number
+        .checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_age, stale};
+    use std::os::unix::fs::symlink;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn ages() {
+        assert_eq!(parse_age("30d"), Ok(Duration::from_secs(30 * 24 * 60 * 60)));
+        assert_eq!(parse_age("2w"), Ok(Duration::from_secs(14 * 24 * 60 * 60)));
+        assert_eq!(parse_age("90s"), Ok(Duration::from_secs(90)));
+        assert!(parse_age("30").is_err());
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("-1d").is_err());
+        assert!(parse_age("1y").is_err());
+        assert!(parse_age("").is_err());
+    }
+
+    #[test]
+    fn stale_roots() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        // the project still exists
+        let project = tmp.path().join("project");
+        std::fs::create_dir(&project)?;
+        let existing = tmp.path().join("existing");
+        std::fs::create_dir_all(existing.join("gc_root"))?;
+        symlink(project.join("shell.nix"), existing.join("nix_file"))?;
+        assert_eq!(stale(&existing, now, None), None);
+
+        // roots of an older lorri, which didn’t record the project
+        let unknown = tmp.path().join("unknown");
+        std::fs::create_dir_all(unknown.join("gc_root"))?;
+        symlink(tmp.path(), unknown.join("gc_root/shell_gc_root"))?;
+        assert_eq!(stale(&unknown, now, None), None);
+        assert_eq!(stale(&unknown, now, Some(30 * day)), None);
+        let old = stale(&unknown, now + 31 * day, Some(30 * day)).expect("built long ago");
+        assert_eq!(old.nix_file, None);
+        assert!(old.last_built.is_some());
+
+        // projects without a nix file are kept
+        let expression = tmp.path().join("expression");
+        std::fs::create_dir_all(expression.join("gc_root"))?;
+        std::fs::write(
+            expression.join("source.json"),
+            r#"{"Expression":{"expression":"{}","dir":"/deleted"}}"#,
+        )?;
+        assert_eq!(stale(&expression, now + 31 * day, Some(30 * day)), None);
+
+        let gone = tmp.path().join("gone");
+        std::fs::create_dir_all(gone.join("gc_root"))?;
+        symlink(tmp.path().join("deleted/shell.nix"), gone.join("nix_file"))?;
+        symlink(tmp.path(), gone.join("gc_root/shell_gc_root"))?;
+        let stale_roots = stale(&gone, now, None).expect("the project is gone");
+        assert!(stale_roots.last_built.is_some());
+        assert!(stale(&gone, now, Some(30 * day)).is_none());
+        assert!(stale(&gone, now + 31 * day, Some(30 * day)).is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,20 @@
+//! Print a typed client for the events of the daemon.
+
+use crate::client_gen::{self, Lang};
+use crate::ops::{ok, porcelain, print_record, OpResult};
+
+/// See the documentation for lorri::cli::Internal_::GenClient for
+/// more details.
+pub fn main(lang: Lang) -> OpResult {
+    let source = client_gen::generate(lang);
+    if porcelain() {
+        let lang = match lang {
+            Lang::Python => "python",
+            Lang::Typescript => "typescript",
+        };
+        print_record("", serde_json::json!({"lang": lang, "source": source}));
+    } else {
+        print!("{}", source);
+    }
+    ok()
+}
@@ -0,0 +1,35 @@
+//! Write the environment of a project into IDE configuration files.
+
+use crate::ops::{ok_msg, ExitError, OpResult};
+use crate::project::config::CONFIG_FILE_NAME;
+use crate::project::ide_env::{self, IdeFormat};
+use crate::project::roots::Roots;
+use crate::project::Project;
+
+/// See the documentation for lorri::cli::Internal_::IdeEnv for more
+/// details.
+pub fn main(project: Project, format: IdeFormat) -> OpResult {
+    let root_paths = Roots::from_project(&project).paths();
+    if !root_paths.all_exist() {
+        return Err(ExitError::errmsg(
+            "No environment has been built for this project yet.\n\
+             Run `lorri watch --once` first.",
+        ));
+    }
+
+    let path = ide_env::write(project.config_root(), &root_paths, format)
+        .map_err(|e| ExitError::errmsg(format!("Could not write the environment: {}", e)))?;
+
+    ok_msg(format!(
+        "Wrote {}.\n\
+         To refresh it after every build, add this to {}:\n\n\
+         [ide-env]\n\
+         formats = [\"{}\"]",
+        path.display(),
+        CONFIG_FILE_NAME,
+        match format {
+            IdeFormat::Vscode => "vscode",
+            IdeFormat::Idea => "idea",
+        }
+    ))
+}
@@ -1,17 +1,51 @@
 //! The info callable is for printing
 
-use crate::ops::{ok, OpResult};
+use crate::config::Config;
+use crate::ops::{ok, print_record, ExitError, OpResult};
 use crate::project;
+use crate::watch::WatchBackend;
 use crate::VERSION_BUILD_REV;
 
 /// See the documentation for lorri::cli::Command::Info for more
 /// details.
-pub fn main(project: project::Project) -> OpResult {
-    println!("lorri version: {}", VERSION_BUILD_REV);
-    println!("Lorri Project Configuration");
-    println!();
-
-    println!("expression: {}", project.nix_file);
+pub fn main(project: project::Project, watch_backend: Option<WatchBackend>) -> OpResult {
+    let config = Config::load(::ops::get_paths()?.config_file())
+        .map_err(|e| ExitError::errmsg(e.to_string()))?;
+    let backend = watch_backend.unwrap_or(config.watch.backend);
+    // what the daemon uses for the project directory
+    let resolved = backend.resolve(project.project_dir());
+    let env_hash = project.env_hash();
+    print_record(
+        &format!(
+            "lorri version: {}\n\
+             Lorri Project Configuration\n\n\
+             expression: {}\n\
+             bin dir: {}\n\
+             environment hash: {}\n\
+             watch backend: {}{}",
+            VERSION_BUILD_REV,
+            project.source,
+            project.bin_dir().display(),
+            env_hash
+                .as_ref()
+                .map(String::as_str)
+                .unwrap_or("none (not built yet)"),
+            backend.as_str(),
+            if resolved == backend {
+                String::new()
+            } else {
+                format!(" ({} for the project directory)", resolved.as_str())
+            }
+        ),
+        serde_json::json!({
+            "version": VERSION_BUILD_REV,
+            "expression": project.source.to_string(),
+            "bin_dir": project.bin_dir().display().to_string(),
+            "env_hash": env_hash,
+            "watch_backend": backend,
+            "project_watch_backend": resolved,
+        }),
+    );
 
     ok()
 }
@@ -1,19 +1,111 @@
 //! Bootstrap a new lorri project
+//!
+//! The files are generated from templates, which can contain
+//! placeholders like `{{project}}`, see `Placeholders`.
 
-use crate::ops::{ok, ok_msg, ExitError, OpResult};
+use crate::ops::{ok, ok_msg, print_record, ExitError, OpResult};
+use regex::{Captures, Regex};
 use std::fs::File;
 use std::io;
 use std::io::Write;
 use std::path::Path;
 
+/// Name of the `shell.nix` template in a template directory.
+const SHELL_TEMPLATE: &str = "shell.nix";
+/// Name of the `.envrc` template in a template directory.
+const ENVRC_TEMPLATE: &str = "envrc";
+
+/// The values of the placeholders in templates:
+///
+/// - `{{project}}`: the name of the project directory
+/// - `{{channel}}`: the nixpkgs channel of the user (like
+///   `nixos-19.09`), `nixpkgs-unstable` if none is subscribed
+/// - `{{user}}`: the user name
+/// - `{{env.NAME}}`: the environment variable `NAME`
+pub struct Placeholders {
+    project: String,
+    channel: String,
+    user: String,
+}
+
+impl Placeholders {
+    /// Detect the values for a project in `project_dir`.
+    pub fn detect(project_dir: &Path) -> Placeholders {
+        Placeholders {
+            project: project_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            channel: std::env::var_os("HOME")
+                .and_then(|home| {
+                    std::fs::read_to_string(Path::new(&home).join(".nix-channels")).ok()
+                })
+                .and_then(|channels| nixpkgs_channel(&channels))
+                .unwrap_or_else(|| String::from("nixpkgs-unstable")),
+            user: std::env::var("USER").unwrap_or_default(),
+        }
+    }
+
+    /// Replace the placeholders in `template`.
+    /// Fails for unknown placeholders and unset variables.
+    pub fn render(&self, template: &str) -> Result<String, String> {
+        lazy_static! {
+            static ref PLACEHOLDER: Regex =
+                Regex::new(r"\{\{\s*([A-Za-z0-9_.]+)\s*\}\}").expect("invalid regex!");
+        }
+        let mut error = None;
+        let rendered = PLACEHOLDER.replace_all(template, |caps: &Captures| {
+            let value = match &caps[1] {
+                "project" => Ok(self.project.clone()),
+                "channel" => Ok(self.channel.clone()),
+                "user" => Ok(self.user.clone()),
+                name if name.starts_with("env.") => std::env::var(&name[4..])
+                    .map_err(|_| format!("environment variable {} is not set", &name[4..])),
+                name => Err(format!("unknown placeholder {{{{{}}}}}", name)),
+            };
+            value.unwrap_or_else(|e| {
+                error.get_or_insert(e);
+                String::new()
+            })
+        });
+        match error {
+            Some(e) => Err(e),
+            None => Ok(rendered.into_owned()),
+        }
+    }
+}
+
+/// The name of the `nixpkgs` channel in the contents of a
+/// `~/.nix-channels` file (lines of `<url> <name>`), like
+/// `nixos-19.09` for `https://nixos.org/channels/nixos-19.09 nixpkgs`.
+fn nixpkgs_channel(channels: &str) -> Option<String> {
+    channels.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some(url), Some("nixpkgs")) => url
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .map(String::from),
+            _ => None,
+        }
+    })
+}
+
 fn create_if_missing(path: &Path, contents: &str, msg: &str) -> Result<(), io::Error> {
     if path.exists() {
-        println!("- {} {}", msg, path.display());
+        print_record(
+            &format!("- {} {}", msg, path.display()),
+            serde_json::json!({"path": path.display().to_string(), "written": false}),
+        );
         Ok(())
     } else {
         let mut f = File::create(path)?;
         f.write_all(contents.as_bytes())?;
-        println!("- Writing {}", path.display());
+        print_record(
+            &format!("- Writing {}", path.display()),
+            serde_json::json!({"path": path.display().to_string(), "written": true}),
+        );
         Ok(())
     }
 }
@@ -25,20 +117,101 @@ fn to_op(e: Result<(), io::Error>) -> OpResult {
     }
 }
 
+/// The template `name` from `template_dir`, or `default` if there
+/// is no such template, rendered with `placeholders`.
+fn template(
+    template_dir: Option<&Path>,
+    name: &str,
+    default: &str,
+    placeholders: &Placeholders,
+) -> Result<String, ExitError> {
+    let template = match template_dir.map(|dir| dir.join(name)) {
+        Some(ref path) if path.exists() => std::fs::read_to_string(path).map_err(|e| {
+            ExitError::errmsg(format!("Cannot read template {}: {}", path.display(), e))
+        })?,
+        _ => String::from(default),
+    };
+    placeholders
+        .render(&template)
+        .map_err(|e| ExitError::errmsg(format!("Cannot fill in template {}: {}", name, e)))
+}
+
 /// See the documentation for lorri::cli::Command::Init for
 /// more details
-pub fn main(default_shell: &str, default_envrc: &str) -> OpResult {
+pub fn main(default_shell: &str, default_envrc: &str, template_dir: Option<&Path>) -> OpResult {
+    if let Some(dir) = template_dir {
+        if !dir.is_dir() {
+            return Err(ExitError::errmsg(format!(
+                "The template directory {} does not exist",
+                dir.display()
+            )));
+        }
+    }
+    let cwd = std::env::current_dir().map_err(|e| ExitError::errmsg(format!("{}", e)))?;
+    let placeholders = Placeholders::detect(&cwd);
+
     to_op(create_if_missing(
         Path::new("./shell.nix"),
-        default_shell,
+        &template(template_dir, SHELL_TEMPLATE, default_shell, &placeholders)?,
         "shell.nix exists, skipping. Make sure it is of a form that works with nix-shell.",
     ))?;
 
     to_op(create_if_missing(
         Path::new("./.envrc"),
-        default_envrc,
+        &template(template_dir, ENVRC_TEMPLATE, default_envrc, &placeholders)?,
         ".envrc exists, skipping. Please add 'eval \"$(lorri direnv)\" to it to set up lorri support.",
     ))?;
 
     ok_msg(String::from("\nSetup done."))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{nixpkgs_channel, Placeholders};
+
+    fn placeholders() -> Placeholders {
+        Placeholders {
+            project: String::from("myproject"),
+            channel: String::from("nixos-19.09"),
+            user: String::from("alice"),
+        }
+    }
+
+    #[test]
+    fn render_placeholders() {
+        std::env::set_var("LORRI_INIT_TEST_VAR", "value");
+        assert_eq!(
+            placeholders().render(
+                "# {{project}} by {{ user }}\n\
+                 import <{{channel}}> { x = \"{{env.LORRI_INIT_TEST_VAR}}\"; }"
+            ),
+            Ok(String::from(
+                "# myproject by alice\nimport <nixos-19.09> { x = \"value\"; }"
+            ))
+        );
+        assert_eq!(
+            placeholders().render("{{nope}}"),
+            Err(String::from("unknown placeholder {{nope}}"))
+        );
+        assert!(placeholders()
+            .render("{{env.LORRI_INIT_TEST_UNSET}}")
+            .is_err());
+        // nix’s own `${}` and other braces are left alone
+        assert_eq!(
+            placeholders().render("{ a = \"${b}\"; }"),
+            Ok(String::from("{ a = \"${b}\"; }"))
+        );
+    }
+
+    #[test]
+    fn detect_nixpkgs_channel() {
+        assert_eq!(
+            nixpkgs_channel(
+                "https://nixos.org/channels/nixos-unstable nixos\n\
+                 https://nixos.org/channels/nixos-19.09 nixpkgs\n"
+            ),
+            Some(String::from("nixos-19.09"))
+        );
+        assert_eq!(nixpkgs_channel(""), None);
+    }
+}
@@ -0,0 +1,183 @@
+//! Install git hooks which keep a lorri project healthy.
+//!
+//! Each hook gets a block of lorri commands between two marker lines.
+//! Existing hooks are kept: the block is inserted right after the
+//! shebang (so an `exit` at the end of the hook doesn’t skip it),
+//! and re-running the installer replaces the block in place.
+
+use crate::bash;
+use crate::ops::{ok_msg, print_record, ExitError, OpResult};
+use crate::NixFile;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const BEGIN_MARKER: &str = "# BEGIN lorri (installed by `lorri install-git-hooks`)";
+const END_MARKER: &str = "# END lorri";
+
+/// The hooks we install, by name.
+const HOOKS: [(&str, &str); 2] = [
+    ("pre-commit", include_str!("./pre-commit.bash")),
+    ("post-checkout", include_str!("./post-checkout.bash")),
+];
+
+/// See the documentation for lorri::cli::Command::InstallGitHooks for
+/// more details.
+pub fn main(nix_file: NixFile) -> OpResult {
+    let hooks_dir = hooks_dir()?;
+    fs::create_dir_all(&hooks_dir)
+        .map_err(|e| ExitError::errmsg(format!("Cannot create {}: {}", hooks_dir.display(), e)))?;
+
+    let shell_file = bash::quote(&nix_file.to_string());
+    for (name, template) in HOOKS.iter() {
+        let path = hooks_dir.join(name);
+        install_hook(&path, &template.replace("@shell_file@", &shell_file))?;
+        print_record(
+            &format!("- Installed {}", path.display()),
+            serde_json::json!({ "installed": path.display().to_string() }),
+        );
+    }
+
+    ok_msg(String::from("\nGit hooks installed."))
+}
+
+/// Ask git where the hooks live; this respects `core.hooksPath`
+/// and works in linked worktrees.
+fn hooks_dir() -> Result<PathBuf, ExitError> {
+    let output = Command::new("git")
+        .args(&["rev-parse", "--git-path", "hooks"])
+        .output()
+        .map_err(|e| ExitError::errmsg(format!("Could not run git: {}", e)))?;
+    if !output.status.success() {
+        return Err(ExitError::errmsg(format!(
+            "Not in a git repository:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&output.stdout).trim_end(),
+    ))
+}
+
+/// Write the lorri block into the hook at `path` and make it executable.
+fn install_hook(path: &Path, block: &str) -> Result<(), ExitError> {
+    let existing = match fs::read_to_string(path) {
+        Ok(contents) => Some(contents),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(ExitError::errmsg(format!(
+                "Cannot read {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+    let contents = with_block(existing.as_ref().map(String::as_str), block)
+        .map_err(|e| ExitError::errmsg(format!("Cannot install {}: {}", path.display(), e)))?;
+
+    let write = || -> std::io::Result<()> {
+        fs::write(path, contents)?;
+        let mut permissions = fs::metadata(path)?.permissions();
+        let mode = permissions.mode();
+        permissions.set_mode(mode | 0o111);
+        fs::set_permissions(path, permissions)
+    };
+    write().map_err(|e| ExitError::errmsg(format!("Cannot write {}: {}", path.display(), e)))
+}
+
+/// Compose the lorri `block` with the `existing` hook script.
+fn with_block(existing: Option<&str>, block: &str) -> Result<String, String> {
+    let block = format!("{}\n{}{}\n", BEGIN_MARKER, block, END_MARKER);
+    let existing = match existing {
+        None => return Ok(format!("#!/usr/bin/env bash\n\n{}", block)),
+        Some(existing) => existing,
+    };
+
+    // replace a previously installed block
+    if let Some(begin) = existing.find(BEGIN_MARKER) {
+        let end = existing[begin..]
+            .find(END_MARKER)
+            .map(|end| begin + end + END_MARKER.len())
+            .ok_or_else(|| format!("found `{}` without `{}`", BEGIN_MARKER, END_MARKER))?;
+        let rest = &existing[end..];
+        let rest = if rest.starts_with('\n') {
+            &rest[1..]
+        } else {
+            rest
+        };
+        return Ok(format!("{}{}{}", &existing[..begin], block, rest));
+    }
+
+    let (shebang, rest) = match existing.find('\n') {
+        Some(newline) if existing.starts_with("#!") => {
+            (&existing[..newline], &existing[newline + 1..])
+        }
+        _ => ("", existing),
+    };
+    let interpreter = shebang
+        .split_whitespace()
+        .last()
+        .and_then(|word| word.rsplit('/').next());
+    if !shebang.is_empty()
+        && !["sh", "bash", "dash", "zsh", "ksh"].contains(&interpreter.unwrap_or(""))
+    {
+        return Err(format!(
+            "the existing hook is not a shell script ({}), please add the lorri commands manually",
+            shebang
+        ));
+    }
+    Ok(format!(
+        "{}\n{}\n{}",
+        if shebang.is_empty() {
+            "#!/bin/sh"
+        } else {
+            shebang
+        },
+        block,
+        rest
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::with_block;
+
+    #[test]
+    fn new_hook() {
+        assert_eq!(
+            with_block(None, "lorri foo\n").unwrap(),
+            "#!/usr/bin/env bash\n\n\
+             # BEGIN lorri (installed by `lorri install-git-hooks`)\n\
+             lorri foo\n\
+             # END lorri\n"
+        );
+    }
+
+    #[test]
+    fn compose_with_existing_hook() {
+        let existing = "#!/bin/bash\nmake lint\nexit 0\n";
+        let installed = with_block(Some(existing), "lorri foo\n").unwrap();
+        assert_eq!(
+            installed,
+            "#!/bin/bash\n\
+             # BEGIN lorri (installed by `lorri install-git-hooks`)\n\
+             lorri foo\n\
+             # END lorri\n\
+             \n\
+             make lint\n\
+             exit 0\n"
+        );
+
+        // re-installing replaces the block
+        assert_eq!(
+            with_block(Some(&installed), "lorri bar\n").unwrap(),
+            installed.replace("foo", "bar")
+        );
+    }
+
+    #[test]
+    fn refuse_foreign_hooks() {
+        assert!(with_block(Some("#!/usr/bin/env python\nprint()\n"), "lorri foo\n").is_err());
+    }
+}
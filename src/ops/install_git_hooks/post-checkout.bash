@@ -0,0 +1,3 @@
+# Tell the lorri daemon that the project changed under its feet.
+# Never fails the checkout, even if the daemon is not running.
+lorri ping_ @shell_file@ >/dev/null 2>&1 || true
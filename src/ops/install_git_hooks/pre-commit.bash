@@ -0,0 +1,5 @@
+# Evaluate the project's shell whenever a nix file is committed,
+# so broken shells never land.
+if git diff --cached --name-only --diff-filter=ACMR | grep -q '\.nix$'; then
+    lorri internal check @shell_file@ || exit 1
+fi
@@ -0,0 +1,190 @@
+//! Install the lorri daemon as a systemd user service.
+//!
+//! Two units are installed: `lorri.socket`, which makes systemd own
+//! the daemon socket, and `lorri.service`, which systemd starts on
+//! the first connection (or at login) and hands the socket to (see
+//! `socket::path::SocketPath::bind`). The service gets the `PATH`
+//! nix is usually found on, since user services don’t inherit the
+//! login shell’s environment, and resource limits, so that
+//! evaluating a big project doesn’t take over the machine.
+
+extern crate directories;
+
+use self::directories::BaseDirs;
+use crate::cli::InstallServiceOptions;
+use crate::ops::{child_stdout, ok_msg, print_record, ExitError, OpResult};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SERVICE: &str = include_str!("./lorri.service");
+const SOCKET: &str = include_str!("./lorri.socket");
+
+/// Where nix and the tools of a nix user usually are, after the
+/// directories given with `--discover` and the one lorri is in.
+/// `%h` and `%u` are the user’s home directory and name.
+const NIX_PATH_DIRS: [&str; 7] = [
+    "%h/.nix-profile/bin",
+    "/etc/profiles/per-user/%u/bin",
+    "/nix/var/nix/profiles/default/bin",
+    "/run/current-system/sw/bin",
+    "/usr/local/bin",
+    "/usr/bin",
+    "/bin",
+];
+
+/// See the documentation for lorri::cli::Command::InstallService for
+/// more details.
+pub fn main(opts: InstallServiceOptions) -> OpResult {
+    if !opts.systemd {
+        return Err(ExitError::errmsg(String::from(
+            "Only systemd user services are supported for now, please pass --systemd",
+        )));
+    }
+    let paths = ::ops::get_paths()?;
+    let lorri = std::env::current_exe()
+        .map_err(|e| ExitError::errmsg(format!("Cannot find the lorri executable: {}", e)))?;
+    let unit_dir = BaseDirs::new()
+        .ok_or_else(|| {
+            ExitError::errmsg("Cannot find the configuration directory, please set $HOME")
+        })?
+        .config_dir()
+        .join("systemd/user");
+    fs::create_dir_all(&unit_dir)
+        .map_err(|e| ExitError::errmsg(format!("Cannot create {}: {}", unit_dir.display(), e)))?;
+
+    let mut path_dirs = opts.discover.clone();
+    path_dirs.extend(lorri.parent().map(Path::to_path_buf));
+    let units = [
+        (
+            "lorri.service",
+            service_unit(
+                &lorri,
+                &path_dirs,
+                std::env::var("NIX_PATH").ok().as_ref().map(String::as_str),
+                &opts,
+            ),
+        ),
+        ("lorri.socket", socket_unit(paths.daemon_socket_file())),
+    ];
+    for (name, contents) in units.iter() {
+        let path = unit_dir.join(name);
+        fs::write(&path, contents)
+            .map_err(|e| ExitError::errmsg(format!("Cannot write {}: {}", path.display(), e)))?;
+        print_record(
+            &format!("- Installed {}", path.display()),
+            serde_json::json!({ "installed": path.display().to_string() }),
+        );
+    }
+
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", "lorri.service"])?;
+    systemctl(&["enable", "--now", "lorri.socket"])?;
+
+    ok_msg(String::from(
+        "\nThe lorri daemon starts on the first connection to its socket, and at login.\n\
+         See its logs with `journalctl --user -u lorri`.",
+    ))
+}
+
+/// Run `systemctl --user` with `args`.
+fn systemctl(args: &[&str]) -> Result<(), ExitError> {
+    let status = Command::new("systemctl")
+        .arg("--user")
+        .args(args)
+        .stdout(child_stdout())
+        .status()
+        .map_err(|e| ExitError::errmsg(format!("Could not run systemctl: {}", e)))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(ExitError::errmsg(format!(
+            "`systemctl --user {}` failed ({})",
+            args.join(" "),
+            status
+        )))
+    }
+}
+
+/// The `lorri.service` unit running `lorri` as the daemon, with
+/// `path_dirs` in front of the usual nix directories on its `PATH`.
+fn service_unit(
+    lorri: &Path,
+    path_dirs: &[PathBuf],
+    nix_path: Option<&str>,
+    opts: &InstallServiceOptions,
+) -> String {
+    let path = path_dirs
+        .iter()
+        .map(|dir| escape(&dir.to_string_lossy()))
+        .chain(NIX_PATH_DIRS.iter().map(|dir| dir.to_string()))
+        .collect::<Vec<_>>()
+        .join(":");
+    let nix_path = match nix_path {
+        Some(nix_path) => format!(
+            "# the NIX_PATH of `lorri install-service`\nEnvironment={}\n",
+            quote(&format!("NIX_PATH={}", escape(nix_path)))
+        ),
+        None => String::new(),
+    };
+    SERVICE
+        .replace("@lorri@", &quote(&escape(&lorri.to_string_lossy())))
+        .replace("@path@", &quote(&format!("PATH={}", path)))
+        .replace("@nix_path@", &nix_path)
+        .replace("@cpu_quota@", &opts.cpu_quota)
+        .replace("@memory_high@", &opts.memory_high)
+}
+
+/// The `lorri.socket` unit listening on `socket`.
+fn socket_unit(socket: &Path) -> String {
+    SOCKET.replace("@socket@", &escape(&socket.to_string_lossy()))
+}
+
+/// Escape `%` in `s`, so systemd doesn’t take it for a specifier.
+fn escape(s: &str) -> String {
+    s.replace('%', "%%")
+}
+
+/// Quote `s` as a single word of a systemd unit setting.
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{service_unit, socket_unit};
+    use crate::cli::InstallServiceOptions;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn units() {
+        let opts = InstallServiceOptions {
+            systemd: true,
+            discover: vec![PathBuf::from("/opt/100%/bin")],
+            cpu_quota: String::from("100%"),
+            memory_high: String::from("4G"),
+        };
+        let service = service_unit(
+            Path::new("/nix/store/abc-lorri/bin/lorri"),
+            &opts.discover,
+            Some("nixpkgs=/home/\"me\"/nixpkgs"),
+            &opts,
+        );
+        assert!(service.contains("ExecStart=\"/nix/store/abc-lorri/bin/lorri\" daemon\n"));
+        assert!(service.contains(
+            "Environment=\"PATH=/opt/100%%/bin:%h/.nix-profile/bin:/etc/profiles/per-user/%u/bin:"
+        ));
+        assert!(service.contains("Environment=\"NIX_PATH=nixpkgs=/home/\\\"me\\\"/nixpkgs\"\n"));
+        assert!(service.contains("CPUQuota=100%\nMemoryHigh=4G\n"));
+
+        let without_nix_path = service_unit(Path::new("/bin/lorri"), &[], None, &opts);
+        assert!(!without_nix_path.contains("NIX_PATH"));
+        for placeholder in ["@lorri@", "@path@", "@nix_path@", "@cpu_quota@"].iter() {
+            assert!(!service.contains(placeholder));
+            assert!(!without_nix_path.contains(placeholder));
+        }
+
+        assert!(socket_unit(Path::new("/run/user/1000/lorri/daemon.socket"))
+            .contains("ListenStream=/run/user/1000/lorri/daemon.socket\n"));
+    }
+}
@@ -0,0 +1,58 @@
+//! List the projects the daemon watches.
+
+use crate::ops::{ok, porcelain, print_record, ExitError, OpResult};
+use crate::project::config::utc_timestamp;
+use crate::socket::communicate::{client, BuildState, ListProjects, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// See the documentation for lorri::cli::Internal_::ListProjects for
+/// more details.
+pub fn main(json: bool) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let result = client::list_projects(DEFAULT_READ_TIMEOUT)
+        .connect(&SocketPath::from(paths.daemon_socket_file()))
+        .map_err(|e| {
+            ExitError::errmsg(format!(
+                "Could not connect to the lorri daemon, is it running? ({:?})",
+                e
+            ))
+        })?
+        .request(&ListProjects {})
+        .map_err(|e| ExitError::errmsg(format!("The daemon did not answer: {:?}", e)))?;
+
+    if porcelain() {
+        for project in &result.projects {
+            print_record(
+                "",
+                serde_json::to_value(project).expect("projects are valid JSON"),
+            );
+        }
+        return ok();
+    }
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&result.projects).expect("projects are valid JSON")
+        );
+        return ok();
+    }
+    for project in result.projects {
+        let state = match project.state {
+            BuildState::Pending => "pending",
+            BuildState::Building => "building",
+            BuildState::Success => "success",
+            BuildState::Failure => "failure",
+            BuildState::Cancelled => "cancelled",
+        };
+        let last_build = project
+            .last_build
+            .map(|secs| utc_timestamp(UNIX_EPOCH + Duration::from_secs(secs)) + " UTC")
+            .unwrap_or_else(|| String::from("never"));
+        println!("{}", project.nix_file);
+        println!("  state:      {}", state);
+        println!("  last build: {}", last_build);
+        println!("  gc root:    {}", project.gc_root.display());
+    }
+    ok()
+}
@@ -0,0 +1,43 @@
+//! Print the log of a project’s build in the daemon, like `tail -f`.
+
+use crate::ops::{ok, print_record, ExitError, OpResult};
+use crate::socket::communicate::{client, FollowLog, LogMessage};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use crate::NixFile;
+
+/// See the documentation for lorri::cli::Internal_::Logs for more
+/// details.
+pub fn main(nix_file: NixFile, follow: bool) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let answers = client::follow_log(Timeout::Infinite)
+        .connect(&SocketPath::from(paths.daemon_socket_file()))
+        .map_err(|e| {
+            ExitError::errmsg(format!(
+                "Could not connect to the lorri daemon, is it running? ({:?})",
+                e
+            ))
+        })?
+        .request_stream(&FollowLog {
+            nix_file: nix_file.clone(),
+            follow,
+        })
+        .map_err(|e| ExitError::errmsg(format!("Could not ask the daemon: {:?}", e)))?;
+
+    for answer in answers {
+        match answer
+            .map_err(|e| ExitError::errmsg(format!("The daemon did not answer: {:?}", e)))?
+        {
+            LogMessage::NotWatched => {
+                return Err(ExitError::errmsg(format!(
+                    "The daemon doesn’t watch {}",
+                    nix_file
+                )))
+            }
+            LogMessage::Started => eprintln!("lorri: build of {} started", nix_file),
+            LogMessage::Line(line) => print_record(&line, serde_json::json!({ "line": line })),
+            LogMessage::Finished => eprintln!("lorri: build of {} finished", nix_file),
+        }
+    }
+    ok()
+}
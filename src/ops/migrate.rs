@@ -0,0 +1,555 @@
+//! Move the projects lorri knows to another machine:
+//!
+//! ```sh
+//! lorri internal export-projects > projects.json
+//! # on the new machine, once the projects are checked out
+//! lorri internal import-projects projects.json
+//! ```
+//!
+//! The export lists the source of every project with GC roots (its
+//! nix file, expression or flake), and the contents of its
+//! `.lorri.toml` (which often isn’t checked in). Importing writes back
+//! the configuration files which don’t exist yet, records the
+//! projects like a build would (so that `lorri gc` and later exports
+//! know them), and registers the ones with a nix file with the
+//! daemon, if it runs.
+//!
+//! A configuration which changes nix settings or runs commands is
+//! only written once the user confirms it, since the export might
+//! come from anywhere.
+
+use crate::cas::ContentAddressable;
+use crate::ops::ping;
+use crate::ops::{ok, ok_msg, print_record, ExitError, OpResult};
+use crate::project::config::{self, ProjectConfig, CONFIG_FILE_NAME};
+use crate::project::{self, Project};
+use crate::socket::communicate::{client, PingResult, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::{NixFile, NixSource};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The version of the export format; imports reject newer ones.
+/// Version 1 only had projects with a nix file, and no `source` and
+/// `config_root`.
+pub const FORMAT_VERSION: u32 = 2;
+
+/// The projects written by `export-projects`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Export {
+    /// See `FORMAT_VERSION`.
+    pub version: u32,
+    /// The projects, sorted by nix file (see `NixSource::id_bytes`).
+    pub projects: Vec<ExportedProject>,
+}
+
+/// One project of an `Export`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedProject {
+    /// The absolute path of the project’s nix file, if it has one
+    /// (see `NixSource::nix_file`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nix_file: Option<PathBuf>,
+    /// What the project builds; the `nix_file` if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<NixSource>,
+    /// The directory of the project’s `.lorri.toml` (see
+    /// `config::config_root`); the project directory if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_root: Option<PathBuf>,
+    /// The contents of the project’s `.lorri.toml`, if it has one.
+    #[serde(default)]
+    pub config: Option<String>,
+}
+
+impl ExportedProject {
+    /// What the project builds, if the export says.
+    pub fn source(&self) -> Option<NixSource> {
+        self.source.clone().or_else(|| {
+            self.nix_file
+                .clone()
+                .map(|nix_file| NixSource::from_nix_file(NixFile::from(nix_file)))
+        })
+    }
+}
+
+/// What importing did with the configuration of a project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigImport {
+    /// The project has no configuration.
+    None,
+    /// The configuration file was written.
+    Written,
+    /// The project has the same configuration already.
+    Unchanged,
+    /// The project has a different configuration, which was kept.
+    Kept,
+    /// The configuration changes nix settings or runs commands, and
+    /// the user didn’t confirm writing it.
+    Unconfirmed,
+}
+
+impl ConfigImport {
+    fn as_str(self) -> &'static str {
+        match self {
+            ConfigImport::None => "none",
+            ConfigImport::Written => "written",
+            ConfigImport::Unchanged => "unchanged",
+            ConfigImport::Kept => "kept",
+            ConfigImport::Unconfirmed => "unconfirmed",
+        }
+    }
+}
+
+/// See the documentation for lorri::cli::Internal_::ExportProjects
+/// for more details.
+pub fn export_main() -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let export = export(paths.gc_root_dir()).map_err(|e| {
+        ExitError::errmsg(format!(
+            "Cannot read {}: {}",
+            paths.gc_root_dir().display(),
+            e
+        ))
+    })?;
+    let record = serde_json::to_value(&export).expect("the export is valid JSON");
+    print_record(
+        &serde_json::to_string_pretty(&record).expect("the export is valid JSON"),
+        record,
+    );
+    ok()
+}
+
+/// See the documentation for lorri::cli::Internal_::ImportProjects
+/// for more details. With `yes`, configurations which change nix
+/// settings or run commands are written without asking.
+pub fn import_main(file: &Path, yes: bool) -> OpResult {
+    let mut contents = String::new();
+    let read = if file == Path::new("-") {
+        io::stdin().read_to_string(&mut contents)
+    } else {
+        std::fs::File::open(file).and_then(|mut f| f.read_to_string(&mut contents))
+    };
+    read.map_err(|e| ExitError::errmsg(format!("Cannot read {}: {}", file.display(), e)))?;
+    let export: Export = serde_json::from_str(&contents)
+        .map_err(|e| ExitError::errmsg(format!("Invalid export {}: {}", file.display(), e)))?;
+    if export.version > FORMAT_VERSION {
+        return Err(ExitError::errmsg(format!(
+            "{} was exported by a newer lorri (format version {}), upgrade lorri first",
+            file.display(),
+            export.version
+        )));
+    }
+
+    let paths = ::ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let mut daemon_running = true;
+    let (mut imported, mut missing, mut failed, mut unconfirmed) = (0, 0, 0, 0);
+    let mut confirm = |config_file: &Path, sections: &[&str]| yes || ask(config_file, sections);
+    for exported in &export.projects {
+        let source = match exported.source() {
+            Some(source) => source,
+            None => {
+                failed += 1;
+                eprintln!("a project has neither a nix file nor a source");
+                continue;
+            }
+        };
+        let shown = source.to_string();
+        let outcome = match import(
+            exported,
+            paths.gc_root_dir(),
+            paths.cas_store(),
+            &mut confirm,
+        ) {
+            Ok(Some(outcome)) => outcome,
+            Ok(None) => {
+                missing += 1;
+                print_record(
+                    &format!("{}: skipped, it doesn’t exist here", shown),
+                    serde_json::json!({ "nix_file": shown, "action": "missing" }),
+                );
+                continue;
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", shown, e);
+                continue;
+            }
+        };
+        imported += 1;
+        if outcome == ConfigImport::Unconfirmed {
+            unconfirmed += 1;
+        }
+
+        // the daemon is told about projects by their nix file
+        let registered = daemon_running
+            && source.nix_file().map_or(false, |nix_file| {
+                match client::ping(DEFAULT_READ_TIMEOUT).connect(&socket_path) {
+                    Ok(client) => ping::send(client, nix_file)
+                        .map(|result| result == PingResult::Registered)
+                        .unwrap_or(false),
+                    Err(_) => {
+                        // don’t try again for every project
+                        daemon_running = false;
+                        false
+                    }
+                }
+            });
+        print_record(
+            &format!(
+                "{}: imported (configuration: {}{})",
+                shown,
+                outcome.as_str(),
+                if registered { ", registered" } else { "" }
+            ),
+            serde_json::json!({
+                "nix_file": shown,
+                "action": "imported",
+                "config": outcome.as_str(),
+                "registered": registered,
+            }),
+        );
+    }
+
+    let mut summary = format!("imported {} projects", imported);
+    if missing > 0 {
+        summary += &format!(", skipped {} which don’t exist", missing);
+    }
+    if unconfirmed > 0 {
+        summary += &format!(
+            "; did not write {} configurations which change nix settings or run commands, \
+             import again with --yes to write them",
+            unconfirmed
+        );
+    }
+    if !daemon_running {
+        summary += "; the daemon isn’t running, it builds them once you enter them";
+    }
+    if failed > 0 {
+        Err(ExitError::errmsg(format!(
+            "{}, {} could not be imported",
+            summary, failed
+        )))
+    } else {
+        ok_msg(summary)
+    }
+}
+
+/// The projects with roots in `gc_root_dir` whose source lorri
+/// recorded and which still exist.
+pub fn export(gc_root_dir: &Path) -> io::Result<Export> {
+    let mut sources: Vec<NixSource> = vec![];
+    let entries = std::fs::read_dir(gc_root_dir)?;
+    for entry in entries {
+        if let Some(source) = project::source_in(&entry?.path()) {
+            if exists(&source) && !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+    }
+    sources.sort_by_key(|source| source.id_bytes());
+    let mut projects = vec![];
+    for source in sources {
+        let nix_file = source
+            .nix_file()
+            .map(|nix_file| PathBuf::from(nix_file.as_os_str()));
+        let config_root =
+            config::config_root(source.dir(), nix_file.as_ref().map(PathBuf::as_path));
+        projects.push(ExportedProject {
+            config: read_optional(&config_root.join(CONFIG_FILE_NAME))?,
+            nix_file,
+            source: Some(source),
+            config_root: Some(config_root),
+        });
+    }
+    Ok(Export {
+        version: FORMAT_VERSION,
+        projects,
+    })
+}
+
+/// Import `exported`: write its configuration unless the project
+/// has one, and record the project in `gc_root_dir`. `None` if it
+/// doesn’t exist. A configuration which changes nix settings or
+/// runs commands is only written if `confirm` (which gets the file
+/// and those sections) agrees.
+pub fn import<F>(
+    exported: &ExportedProject,
+    gc_root_dir: &Path,
+    cas: &ContentAddressable,
+    confirm: &mut F,
+) -> io::Result<Option<ConfigImport>>
+where
+    F: FnMut(&Path, &[&str]) -> bool,
+{
+    let source = match exported.source() {
+        Some(ref source) if exists(source) => source.clone(),
+        _ => return Ok(None),
+    };
+    let config_root = match exported.config_root {
+        // the configuration is the project’s own, or above it
+        Some(ref root) if !source.dir().starts_with(root) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the configuration directory {} is not above the project",
+                    root.display()
+                ),
+            ))
+        }
+        Some(ref root) => root.clone(),
+        None => source.dir().to_owned(),
+    };
+    let config_file = config_root.join(CONFIG_FILE_NAME);
+    let outcome = match (&exported.config, read_optional(&config_file)?) {
+        (None, _) => ConfigImport::None,
+        (Some(config), None) => {
+            let sections = sensitive_sections(config);
+            if sections.is_empty() || confirm(&config_file, &sections) {
+                std::fs::write(&config_file, config)?;
+                ConfigImport::Written
+            } else {
+                ConfigImport::Unconfirmed
+            }
+        }
+        (Some(config), Some(ref existing)) if config == existing => ConfigImport::Unchanged,
+        (Some(_), Some(_)) => ConfigImport::Kept,
+    };
+    Project::from_source(source, gc_root_dir, cas.clone())?;
+    Ok(Some(outcome))
+}
+
+/// Whether the project of `source` exists on this machine.
+fn exists(source: &NixSource) -> bool {
+    match source.nix_file() {
+        Some(nix_file) => Path::new(nix_file.as_os_str()).is_file(),
+        None => source.dir().is_dir(),
+    }
+}
+
+/// The sections of the project configuration `contents` which
+/// change nix settings or run commands. All of them, if it can’t be
+/// read (it might be for a newer lorri).
+fn sensitive_sections(contents: &str) -> Vec<&'static str> {
+    let config: ProjectConfig = match toml::from_str(contents) {
+        Ok(config) => config,
+        Err(_) => {
+            return vec![
+                "[nix]",
+                "[nixpkgs]",
+                "[cachix]",
+                "[push]",
+                "[hooks]",
+                "[[event-sink]]",
+            ]
+        }
+    };
+    let default = ProjectConfig::default();
+    let mut sections = vec![];
+    if config.nix != default.nix {
+        sections.push("[nix]");
+    }
+    if config.nixpkgs.is_some() {
+        sections.push("[nixpkgs]");
+    }
+    if config.cachix != default.cachix {
+        sections.push("[cachix]");
+    }
+    if config.push != default.push {
+        sections.push("[push]");
+    }
+    if config.hooks != default.hooks {
+        sections.push("[hooks]");
+    }
+    if !config.event_sinks.is_empty() {
+        sections.push("[[event-sink]]");
+    }
+    sections
+}
+
+/// Ask on the terminal whether to write `config_file` with these
+/// `sections`. No if there is no terminal to ask on (the export
+/// might come from stdin), or with `--porcelain`.
+fn ask(config_file: &Path, sections: &[&str]) -> bool {
+    if ::ops::porcelain() {
+        return false;
+    }
+    let tty = match std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+    {
+        Ok(tty) => tty,
+        Err(_) => return false,
+    };
+    let prompt = format!(
+        "{} sets {}, which change nix settings or run commands. Write it? [y/N] ",
+        config_file.display(),
+        sections.join(", ")
+    );
+    let mut answer = String::new();
+    let asked = (&tty)
+        .write_all(prompt.as_bytes())
+        .and_then(|()| io::BufReader::new(&tty).read_line(&mut answer));
+    asked.is_ok() && ["y", "yes"].contains(&answer.trim().to_lowercase().as_str())
+}
+
+/// Read a file which might not exist.
+fn read_optional(path: &Path) -> io::Result<Option<String>> {
+    match std::fs::read_to_string(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+        Ok(contents) => Ok(Some(contents)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export, import, ConfigImport, ExportedProject};
+    use cas::ContentAddressable;
+    use std::fs;
+    use std::path::Path;
+    use {NixFile, NixSource};
+
+    fn never(_: &Path, _: &[&str]) -> bool {
+        panic!("asked to confirm")
+    }
+
+    #[test]
+    fn export_and_import() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().canonicalize()?;
+        let (old_roots, new_roots) = (dir.join("old-roots"), dir.join("new-roots"));
+        let cas = ContentAddressable::new(dir.join("cas"))?;
+        for project in &["a", "b", "c"] {
+            fs::create_dir(dir.join(project))?;
+            fs::write(dir.join(project).join("shell.nix"), "")?;
+        }
+        fs::write(dir.join("a/.lorri.toml"), "attribute = \"dev\"\n")?;
+        fs::write(dir.join("b/.lorri.toml"), "[watch]\n")?;
+        let exported = |project: &str, config: Option<&str>| {
+            let nix_file = dir.join(project).join("shell.nix");
+            ExportedProject {
+                nix_file: Some(nix_file.clone()),
+                source: Some(NixSource::File(NixFile::from(nix_file))),
+                config_root: Some(dir.join(project)),
+                config: config.map(String::from),
+            }
+        };
+
+        // the projects lorri built, whose nix files still exist
+        for project in &["a", "b", "gone"] {
+            import(&exported(project, None), &old_roots, &cas, &mut never)?;
+        }
+        fs::create_dir(old_roots.join("unknown"))?;
+        let exported_old = export(&old_roots)?;
+        assert_eq!(
+            exported_old.projects,
+            vec![
+                exported("a", Some("attribute = \"dev\"\n")),
+                exported("b", Some("[watch]\n")),
+            ]
+        );
+
+        // configuration files are only written where there are none
+        fs::remove_file(dir.join("a/.lorri.toml"))?;
+        let mut changed = exported("b", Some("[nix]\n"));
+        assert_eq!(
+            import(&exported_old.projects[0], &new_roots, &cas, &mut never)?,
+            Some(ConfigImport::Written)
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("a/.lorri.toml"))?,
+            "attribute = \"dev\"\n"
+        );
+        assert_eq!(
+            import(&exported_old.projects[1], &new_roots, &cas, &mut never)?,
+            Some(ConfigImport::Unchanged)
+        );
+        assert_eq!(
+            import(&changed, &new_roots, &cas, &mut never)?,
+            Some(ConfigImport::Kept)
+        );
+        changed.nix_file = Some(dir.join("gone/shell.nix"));
+        changed.source = None;
+        assert_eq!(import(&changed, &new_roots, &cas, &mut never)?, None);
+        assert_eq!(export(&new_roots)?, exported_old);
+        Ok(())
+    }
+
+    #[test]
+    fn shell_files_and_expressions() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().canonicalize()?;
+        let (old_roots, new_roots) = (dir.join("old-roots"), dir.join("new-roots"));
+        let cas = ContentAddressable::new(dir.join("cas"))?;
+        fs::create_dir_all(dir.join("p/nix"))?;
+        fs::write(dir.join("p/nix/dev.nix"), "")?;
+        let config = "shell-file = \"nix/dev.nix\"\n[nix]\noptions = { max-jobs = \"4\" }\n";
+        fs::write(dir.join("p/.lorri.toml"), config)?;
+        let shell_file = ExportedProject {
+            nix_file: Some(dir.join("p/nix/dev.nix")),
+            source: None,
+            config_root: None,
+            config: None,
+        };
+        let expression = ExportedProject {
+            nix_file: None,
+            source: Some(NixSource::Expression {
+                expression: String::from("with import <nixpkgs> {}; mkShell {}"),
+                dir: dir.join("p"),
+            }),
+            config_root: None,
+            config: None,
+        };
+        import(&shell_file, &old_roots, &cas, &mut never)?;
+        import(&expression, &old_roots, &cas, &mut never)?;
+
+        // the configuration of the shell file is the project’s
+        let exported = export(&old_roots)?;
+        assert_eq!(exported.projects.len(), 2);
+        // sorted by `id_bytes`: paths come first
+        let (exported_shell_file, exported_expression) =
+            (&exported.projects[0], &exported.projects[1]);
+        assert_eq!(exported_expression.source, expression.source);
+        assert_eq!(exported_shell_file.config_root, Some(dir.join("p")));
+        assert_eq!(
+            exported_shell_file.config.as_ref().map(String::as_str),
+            Some(config)
+        );
+
+        // it sets nix options, so it is only written when confirmed
+        fs::remove_file(dir.join("p/.lorri.toml"))?;
+        let mut asked = vec![];
+        assert_eq!(
+            import(
+                exported_shell_file,
+                &new_roots,
+                &cas,
+                &mut |file: &Path, sections: &[&str]| {
+                    asked.push((file.to_owned(), sections.join(" ")));
+                    false
+                }
+            )?,
+            Some(ConfigImport::Unconfirmed)
+        );
+        assert_eq!(
+            asked,
+            vec![(dir.join("p/.lorri.toml"), String::from("[nix]"))]
+        );
+        assert!(!dir.join("p/.lorri.toml").exists());
+        assert_eq!(
+            import(
+                exported_shell_file,
+                &new_roots,
+                &cas,
+                &mut |_: &Path, _: &[&str]| true
+            )?,
+            Some(ConfigImport::Written)
+        );
+        assert_eq!(fs::read_to_string(dir.join("p/.lorri.toml"))?, config);
+        assert!(!dir.join("p/nix/.lorri.toml").exists());
+        Ok(())
+    }
+}
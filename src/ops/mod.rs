@@ -1,13 +1,106 @@
 //! Ops are command-line callables.
 
+pub mod build;
+pub mod cancel;
+pub mod check;
 pub mod daemon;
 pub mod direnv;
+pub mod direnv_hook_check;
+pub mod gc;
+pub mod gen_client;
+pub mod ide_env;
 pub mod info;
 pub mod init;
+pub mod install_git_hooks;
+pub mod install_service;
+pub mod list_projects;
+pub mod logs;
+pub mod migrate;
 pub mod ping;
+pub mod refresh_shells;
+pub mod register;
+pub mod root_check;
+pub mod self_test;
+pub mod show_eval_expr;
+pub mod stats;
+pub mod status;
+pub mod stop_daemon;
+pub mod stream_events;
 pub mod upgrade;
+pub mod wait_idle;
 pub mod watch;
 
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether ops print JSON records instead of text, see
+/// `set_porcelain`.
+static PORCELAIN: AtomicBool = AtomicBool::new(false);
+
+/// Make ops print nothing but single-line JSON records to stdout,
+/// and their other messages to stderr (`lorri --porcelain`).
+pub fn set_porcelain(porcelain: bool) {
+    PORCELAIN.store(porcelain, Ordering::Relaxed);
+}
+
+/// Whether ops print JSON records, see `set_porcelain`.
+pub fn porcelain() -> bool {
+    PORCELAIN.load(Ordering::Relaxed)
+}
+
+/// Print output of an op to stdout: `text`, or `record` as a single
+/// line of JSON with `--porcelain`.
+pub fn print_record(text: &str, record: serde_json::Value) {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    // like `println!`, which panics if stdout is closed
+    let written = if porcelain() {
+        writeln!(out, "{}", record)
+    } else {
+        writeln!(out, "{}", text)
+    };
+    written
+        .and_then(|()| out.flush())
+        .expect("failed printing to stdout");
+}
+
+/// Print a message for the user: to stdout, or to stderr with
+/// `--porcelain`, where stdout only has records.
+pub fn print_note(text: &str) {
+    if porcelain() {
+        eprintln!("{}", text);
+    } else {
+        println!("{}", text);
+    }
+}
+
+/// Where the commands ops run print their output: to stdout, or to
+/// stderr with `--porcelain`, where stdout only has records.
+pub fn child_stdout() -> std::process::Stdio {
+    if porcelain() {
+        stderr::duplicate()
+    } else {
+        std::process::Stdio::inherit()
+    }
+}
+
+mod stderr {
+    extern crate nix;
+
+    use self::nix::unistd::dup;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::process::Stdio;
+
+    /// A copy of our stderr for a child, which closes it when done.
+    pub fn duplicate() -> Stdio {
+        match dup(std::io::stderr().as_raw_fd()) {
+            Ok(fd) => unsafe { Stdio::from_raw_fd(fd) },
+            // out of file descriptors: better silent than on stdout
+            Err(_) => Stdio::null(),
+        }
+    }
+}
+
 /// Set up necessary directories or fail.
 pub fn get_paths() -> Result<::constants::Paths, ExitError> {
     ::constants::Paths::initialize()
@@ -1,22 +1,261 @@
 //! Run a BuildLoop for `shell.nix`, watching for input file changes.
 //! Can be used together with `direnv`.
-use crate::ops::{ok, OpResult};
+use crate::ops::{ok, ok_msg, print_record, ExitError, OpResult};
 use crate::NixFile;
 
-use crate::socket::communicate::client;
-use crate::socket::communicate::{Ping, DEFAULT_READ_TIMEOUT};
+use crate::socket::communicate::client::{Client, InitError};
+use crate::socket::communicate::{
+    client, EventMessage, Monitor, Ping, PingResult, RequestBuild, RequestBuildResult,
+    DEFAULT_READ_TIMEOUT,
+};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often to try connecting while waiting for the daemon.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
 
 /// See the documentation for lorri::cli::Command::Shell for more
-/// details.
-pub fn main(nix_file: NixFile) -> OpResult {
+/// details. With `wait_for_daemon`, keep trying to connect for that
+/// long, in case the daemon is still starting.
+pub fn main(nix_file: NixFile, wait_for_daemon: Option<Duration>) -> OpResult {
     // TODO: set up socket path, make it settable by the user
-    client::ping(DEFAULT_READ_TIMEOUT)
-        // TODO
-        .connect(&::socket::path::SocketPath::from(
-            ::ops::get_paths()?.daemon_socket_file(),
+    let paths = ::ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let client = connect(&socket_path, wait_for_daemon.unwrap_or_default()).map_err(|e| {
+        ExitError::errmsg(match wait_for_daemon {
+            Some(timeout) => format!(
+                "The lorri daemon did not start listening on {} within {}s ({:?})",
+                socket_path.display(),
+                timeout.as_secs(),
+                e
+            ),
+            None => format!(
+                "Could not connect to the lorri daemon, is it running? ({:?})",
+                e
+            ),
+        })
+    })?;
+    match send(client, nix_file.clone())
+        .map_err(|e| ExitError::errmsg(format!("Could not ping the daemon: {:?}", e)))?
+    {
+        PingResult::Registered => ok(),
+        PingResult::Refused(e) => Err(ExitError::errmsg(format!(
+            "The lorri daemon cannot watch {}: {}",
+            nix_file, e
+        ))),
+    }
+}
+
+/// See the documentation for lorri::cli::Command::Ping for more
+/// details. Fails if the build fails, or doesn’t finish within
+/// `timeout`.
+pub fn build_main(nix_file: NixFile, timeout: Option<Duration>) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let connect_error = |e| {
+        ExitError::errmsg(format!(
+            "Could not connect to the lorri daemon, is it running? ({:?})",
+            e
         ))
-        .unwrap()
-        .write(&Ping { nix_file })
-        .unwrap();
-    ok()
+    };
+    // listen before asking, so that the build can’t end unseen; the
+    // events the daemon kept are replayed, but they are of other
+    // builds
+    let answers = client::monitor(Timeout::Infinite)
+        .connect(&socket_path)
+        .map_err(connect_error)?
+        .request_stream(&Monitor {
+            nix_file: Some(nix_file.clone()),
+            since: Some(0),
+        })
+        .map_err(|e| ExitError::errmsg(format!("Could not ask the daemon: {:?}", e)))?;
+    let result = client::request_build(DEFAULT_READ_TIMEOUT)
+        .connect(&socket_path)
+        .map_err(connect_error)?
+        .request(&RequestBuild {
+            nix_file: nix_file.clone(),
+        });
+    let build = match result {
+        Ok(RequestBuildResult::Requested { build_id }) => build_id,
+        Ok(RequestBuildResult::Refused(e)) => {
+            return Err(ExitError::errmsg(format!(
+                "The lorri daemon cannot watch {}: {}",
+                nix_file, e
+            )))
+        }
+        Err(ref e) if e.is_hang_up() => {
+            return Err(ExitError::errmsg(
+                "The lorri daemon cannot start builds on request, restart it with this version of lorri",
+            ))
+        }
+        Err(e) => {
+            return Err(ExitError::errmsg(format!(
+                "Could not ask the daemon for a build: {:?}",
+                e
+            )))
+        }
+    };
+
+    let timed_out = Arc::new(AtomicBool::new(false));
+    if let Some(timeout) = timeout {
+        let closer = answers
+            .closer()
+            .map_err(|e| ExitError::errmsg(format!("Could not set up the timeout: {}", e)))?;
+        let timed_out = timed_out.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(timeout);
+            timed_out.store(true, Ordering::SeqCst);
+            // ends the answers
+            let _ = closer.shutdown(Shutdown::Both);
+        });
+    }
+    for answer in answers {
+        let line = match answer {
+            Ok(EventMessage::Event(line)) => line,
+            Ok(EventMessage::Gap { .. }) => continue,
+            Err(_) => break,
+        };
+        if let Some((outcome, event)) = outcome(build, &line) {
+            return match outcome {
+                Outcome::Completed => {
+                    print_record(&format!("build {} completed", build), event);
+                    ok_msg(format!("{} is built", nix_file))
+                }
+                Outcome::Failed => {
+                    let log = event["log_lines"]
+                        .as_array()
+                        .map(|lines| {
+                            lines
+                                .iter()
+                                .filter_map(|line| line.as_str())
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                        })
+                        .unwrap_or_default();
+                    print_record(&format!("build {} failed:\n{}", build, log), event);
+                    Err(ExitError::errmsg(format!("Building {} failed", nix_file)))
+                }
+                Outcome::Cancelled => {
+                    print_record(&format!("build {} was cancelled", build), event);
+                    Err(ExitError::errmsg(format!(
+                        "The build of {} was cancelled",
+                        nix_file
+                    )))
+                }
+            };
+        }
+    }
+    Err(ExitError::errmsg(
+        match timeout.filter(|_| timed_out.load(Ordering::SeqCst)) {
+            Some(timeout) => format!(
+                "The build of {} did not finish within {}s",
+                nix_file,
+                timeout.as_secs()
+            ),
+            None => String::from("The daemon closed the event stream before the build finished"),
+        },
+    ))
+}
+
+/// How a build ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// The outcome of the build with the id `build`, and the event
+/// telling it, if `line` ends the build.
+fn outcome(build: u64, line: &str) -> Option<(Outcome, serde_json::Value)> {
+    let event: serde_json::Value = serde_json::from_str(line).ok()?;
+    if event["build_id"].as_u64() != Some(build) {
+        return None;
+    }
+    let outcome = match event["event"].as_str()? {
+        "completed" => Outcome::Completed,
+        "failure" => Outcome::Failed,
+        "cancelled" => Outcome::Cancelled,
+        _ => return None,
+    };
+    Some((outcome, event))
+}
+
+/// Ping the daemon with `nix_file` and read whether it watches it.
+/// Daemons which don’t answer pings yet are taken to watch it.
+pub fn send(
+    client: Client<PingResult, Ping>,
+    nix_file: NixFile,
+) -> Result<PingResult, client::Error> {
+    match client.request(&Ping { nix_file }) {
+        Err(ref e) if e.is_hang_up() => Ok(PingResult::Registered),
+        result => result,
+    }
+}
+
+/// Connect to the daemon at `socket_path`. While its socket doesn’t
+/// exist or nobody listens on it, retry until `timeout` passed.
+fn connect(
+    socket_path: &SocketPath,
+    timeout: Duration,
+) -> Result<Client<PingResult, Ping>, InitError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match client::ping(DEFAULT_READ_TIMEOUT).connect(socket_path) {
+            Err(InitError::SocketConnect(_)) if Instant::now() < deadline => {
+                std::thread::sleep(RETRY_INTERVAL)
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, outcome, Outcome};
+    use socket::communicate::listener::Listener;
+    use socket::path::SocketPath;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn wait_for_the_daemon() {
+        let tmp = tempfile::tempdir().unwrap();
+        let socket = tmp.path().join("daemon.socket");
+        assert!(connect(&SocketPath::from(&socket), Duration::from_millis(0)).is_err());
+
+        let daemon_socket = socket.clone();
+        let daemon = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            Listener::new(&SocketPath::from(&daemon_socket))
+                .unwrap()
+                .accept(|_, _| ())
+                .unwrap()
+                .join()
+                .unwrap();
+        });
+        assert!(connect(&SocketPath::from(&socket), Duration::from_secs(10)).is_ok());
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn watch_the_requested_build() {
+        let event = |event: &str, build: u64| {
+            format!(
+                "{{\"nix_file\":\"/home/user/project/shell.nix\",\"event\":\"{}\",\"build_id\":{}}}\n",
+                event, build
+            )
+        };
+        // a build which was running before the request
+        assert!(outcome(4, &event("completed", 3)).is_none());
+        assert!(outcome(4, &event("started", 4)).is_none());
+        assert!(outcome(4, "not json").is_none());
+        let (outcome, event) = outcome(4, &event("failure", 4)).unwrap();
+        assert_eq!(outcome, Outcome::Failed);
+        assert_eq!(event["build_id"], 4);
+    }
 }
@@ -0,0 +1,19 @@
+# Register this shell with lorri, so that `lorri internal
+# refresh-shells` can ask it to reload its direnv environment.
+# Add to ~/.bashrc, after the direnv hook:
+#   eval "$(lorri internal refresh-shells --hook bash)"
+_lorri_shells_dir=@shells_dir@
+_lorri_register() {
+  if [[ "$PWD" != "${_lorri_registered_dir-}" ]]; then
+    mkdir -p "$_lorri_shells_dir" &&
+      printf '%s\n' "$PWD" >"$_lorri_shells_dir/$$" &&
+      _lorri_registered_dir="$PWD"
+  fi
+}
+# bash runs the trap once the running command (or the line being
+# edited) is done
+trap 'eval "$(direnv export bash)"' USR1
+trap 'rm -f "$_lorri_shells_dir/$$"' EXIT
+if [[ ";${PROMPT_COMMAND[*]:-};" != *";_lorri_register;"* ]]; then
+  PROMPT_COMMAND="_lorri_register${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+fi
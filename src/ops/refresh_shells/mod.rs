@@ -0,0 +1,326 @@
+//! Ask running shells to reload their direnv environment after a
+//! rebuild, instead of waiting for their next prompt or `cd`.
+//!
+//! Shells register with a hook (see `--hook`): on every prompt in a
+//! new directory, they write it to `<shells dir>/<pid>`, and they
+//! reload the environment when they get `SIGUSR1`. Refreshing
+//! signals the registered shells in a project; the daemon does so
+//! after every build which changed the environment of a project.
+
+extern crate nix;
+
+#[cfg(target_os = "linux")]
+use self::nix::libc;
+use self::nix::sys::signal::{kill, Signal};
+use self::nix::unistd::Pid;
+use crate::bash;
+use crate::ops::{ok_msg, print_record, ExitError, OpResult};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The shells there is a hook for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookShell {
+    /// GNU bash.
+    Bash,
+    /// The Z shell.
+    Zsh,
+}
+
+impl FromStr for HookShell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HookShell, String> {
+        match s {
+            "bash" => Ok(HookShell::Bash),
+            "zsh" => Ok(HookShell::Zsh),
+            _ => Err(format!("unknown shell `{}`, use bash or zsh", s)),
+        }
+    }
+}
+
+impl HookShell {
+    /// The hook to evaluate in the shell’s rc file, registering it
+    /// in `shells_dir`.
+    pub fn hook(self, shells_dir: &Path) -> String {
+        let template = match self {
+            HookShell::Bash => include_str!("./hook.bash"),
+            HookShell::Zsh => include_str!("./hook.zsh"),
+        };
+        template.replace("@shells_dir@", &bash::quote(&shells_dir.to_string_lossy()))
+    }
+}
+
+/// A shell registered by the hook.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shell {
+    /// Its process id.
+    pub pid: i32,
+    /// The directory it was in at its last prompt.
+    pub dir: PathBuf,
+}
+
+/// See the documentation for lorri::cli::Internal_::RefreshShells
+/// for more details. Refreshes the shells in `project_dir` (a
+/// canonical path), or all of them.
+pub fn main(hook: Option<HookShell>, project_dir: Option<&Path>) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    if let Some(shell) = hook {
+        return ok_msg(shell.hook(paths.shells_dir()));
+    }
+
+    let refreshed = refresh(paths.shells_dir(), project_dir).map_err(|e| {
+        ExitError::errmsg(format!(
+            "Cannot read {}: {}",
+            paths.shells_dir().display(),
+            e
+        ))
+    })?;
+    for shell in &refreshed {
+        print_record(
+            &format!("refreshed shell {} in {}", shell.pid, shell.dir.display()),
+            serde_json::json!({
+                "pid": shell.pid,
+                "dir": shell.dir.display().to_string(),
+            }),
+        );
+    }
+    ok_msg(format!("refreshed {} shells", refreshed.len()))
+}
+
+/// Signal the shells registered in `shells_dir` which are in
+/// `project_dir` (a canonical path) or below it, or all of them, to
+/// reload their environment. Returns the signalled shells; failing
+/// to signal a shell is logged.
+pub fn refresh(shells_dir: &Path, project_dir: Option<&Path>) -> io::Result<Vec<Shell>> {
+    let mut refreshed = vec![];
+    let shells = registered(shells_dir)?;
+    for shell in shells {
+        let dir = shell
+            .dir
+            .canonicalize()
+            .unwrap_or_else(|_| shell.dir.clone());
+        if !project_dir.map_or(true, |project_dir| dir.starts_with(project_dir)) {
+            continue;
+        }
+        match kill(Pid::from_raw(shell.pid), Signal::SIGUSR1) {
+            Ok(()) => refreshed.push(shell),
+            Err(e) => warn!("could not signal shell {}: {}", shell.pid, e),
+        }
+    }
+    Ok(refreshed)
+}
+
+/// The shells registered in `shells_dir` which are still running
+/// (none if no shell registered yet). The registrations of shells
+/// which exited without unregistering (like when their terminal was
+/// killed) are removed.
+pub fn registered(shells_dir: &Path) -> io::Result<Vec<Shell>> {
+    let entries = match fs::read_dir(shells_dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut shells = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let pid = match path
+            .file_name()
+            .and_then(|name| name.to_str()?.parse().ok())
+        {
+            Some(pid) => pid,
+            None => continue,
+        };
+        let registration = fs::read_to_string(&path).and_then(|dir| {
+            let registered_at = fs::metadata(&path)?.modified()?;
+            Ok((PathBuf::from(dir.trim_end_matches('\n')), registered_at))
+        });
+        let (dir, registered_at) = match registration {
+            Ok(registration) => registration,
+            // unregistered in the meantime
+            Err(_) => continue,
+        };
+        let shell = Shell { pid, dir };
+        match is_registered(&shell, registered_at) {
+            Some(true) => shells.push(shell),
+            Some(false) => {
+                let _ = fs::remove_file(&path);
+            }
+            None => warn!(
+                "cannot tell whether process {} is the shell which registered, not refreshing it",
+                shell.pid
+            ),
+        }
+    }
+    shells.sort_by_key(|shell| shell.pid);
+    Ok(shells)
+}
+
+/// How much earlier than its start time a process may seem to have
+/// started, because the boot time in `/proc/stat` is in seconds.
+const START_TIME_PRECISION: Duration = Duration::from_secs(1);
+
+/// Whether `shell` is still the running shell which registered at
+/// `registered_at`, and not a process which got the pid of an
+/// exited shell (which might not survive `SIGUSR1`): that one
+/// started after the registration. `None` where we can’t tell.
+fn is_registered(shell: &Shell, registered_at: SystemTime) -> Option<bool> {
+    // (a process of another user, which we may not signal, is not
+    // our shell either)
+    if kill(Pid::from_raw(shell.pid), None).is_err() {
+        return Some(false);
+    }
+    // the shell keeps its registration when it moves out of the
+    // project, it might come back
+    let started = started(shell.pid)?;
+    Some(started <= registered_at + START_TIME_PRECISION)
+}
+
+/// When the process `pid` started, from `/proc/<pid>/stat`.
+#[cfg(target_os = "linux")]
+fn started(pid: i32) -> Option<SystemTime> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the fields after the command name (which may contain spaces
+    // and parentheses) start with the state, the start time (in
+    // clock ticks since boot) is the 20th
+    let fields = &stat[stat.rfind(')')? + 1..];
+    let ticks: u64 = fields.split_whitespace().nth(19)?.parse().ok()?;
+    let boot: u64 = fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find(|line| line.starts_with("btime "))?["btime ".len()..]
+        .trim()
+        .parse()
+        .ok()?;
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return None;
+    }
+    Some(
+        UNIX_EPOCH
+            + Duration::from_secs(boot)
+            + Duration::from_millis(ticks * 1000 / ticks_per_second as u64),
+    )
+}
+
+/// When the process `pid` started, from `proc_pidinfo`.
+#[cfg(target_os = "macos")]
+fn started(pid: i32) -> Option<SystemTime> {
+    use self::nix::libc::{c_int, c_void, proc_bsdinfo};
+    // not in our version of libc
+    const PROC_PIDTBSDINFO: c_int = 3;
+    extern "C" {
+        fn proc_pidinfo(
+            pid: c_int,
+            flavor: c_int,
+            arg: u64,
+            buffer: *mut c_void,
+            buffersize: c_int,
+        ) -> c_int;
+    }
+
+    let mut info: proc_bsdinfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<proc_bsdinfo>() as c_int;
+    let written = unsafe {
+        proc_pidinfo(
+            pid,
+            PROC_PIDTBSDINFO,
+            0,
+            &mut info as *mut proc_bsdinfo as *mut c_void,
+            size,
+        )
+    };
+    if written != size {
+        return None;
+    }
+    Some(
+        UNIX_EPOCH
+            + Duration::from_secs(info.pbi_start_tvsec)
+            + Duration::from_micros(info.pbi_start_tvusec),
+    )
+}
+
+/// Elsewhere, we don’t know.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn started(_pid: i32) -> Option<SystemTime> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{registered, HookShell, Shell};
+    use crate::bash::expect_bash;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn registered_shells() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let cwd = std::env::current_dir()?;
+        let me = std::process::id() as i32;
+        fs::write(
+            tmp.path().join(me.to_string()),
+            format!("{}\n", cwd.display()),
+        )?;
+        // long gone
+        fs::write(tmp.path().join("2147483646"), "/tmp\n")?;
+        fs::write(tmp.path().join("not-a-pid"), "/tmp\n")?;
+
+        assert_eq!(registered(tmp.path())?, vec![Shell { pid: me, dir: cwd }]);
+        assert!(!tmp.path().join("2147483646").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn no_shells_registered_yet() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        assert_eq!(registered(&tmp.path().join("shells"))?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn recycled_pids() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let me = std::process::id() as i32;
+        let registration = tmp.path().join(me.to_string());
+        // an exited shell registered before this process started
+        fs::write(&registration, "/tmp\n")?;
+        expect_bash(
+            r#"touch -d "2000-01-01T00:00:00" "$1""#,
+            &[registration.as_os_str()],
+        );
+
+        assert_eq!(registered(tmp.path())?, vec![]);
+        assert!(!registration.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn moved_shells_stay_registered() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let me = std::process::id() as i32;
+        // not where this process is
+        fs::write(tmp.path().join(me.to_string()), "/\n")?;
+
+        assert_eq!(
+            registered(tmp.path())?,
+            vec![Shell {
+                pid: me,
+                dir: PathBuf::from("/")
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hooks_name_the_shells_dir() {
+        for shell in &[HookShell::Bash, HookShell::Zsh] {
+            let hook = shell.hook(Path::new("/run/user/1000/lorri/it's"));
+            assert!(hook.contains("_lorri_shells_dir='/run/user/1000/lorri/it'\\''s'\n"));
+        }
+    }
+}
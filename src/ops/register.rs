@@ -0,0 +1,176 @@
+//! Tell the daemon to watch many projects at once, for example all
+//! the `shell.nix` files under a directory:
+//!
+//! ```sh
+//! fd shell.nix | lorri internal register -
+//! ```
+
+use crate::locate_file;
+use crate::ops::ping;
+use crate::ops::{ok_msg, print_record, ExitError, OpResult};
+use crate::socket::communicate::{client, PingResult, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::NixFile;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+/// See the documentation for lorri::cli::Internal_::Register for
+/// more details.
+pub fn main(paths: Vec<PathBuf>) -> OpResult {
+    let stdin = io::stdin();
+    let (nix_files, mut errors) = nix_files(&paths, stdin.lock())
+        .map_err(|e| ExitError::errmsg(format!("Could not read stdin: {}", e)))?;
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+
+    let socket_path = ::ops::get_paths()?.daemon_socket_file().to_owned();
+    let mut registered = 0;
+    for nix_file in &nix_files {
+        let client = client::ping(DEFAULT_READ_TIMEOUT)
+            .connect(&SocketPath::from(&socket_path))
+            .map_err(|e| {
+                ExitError::errmsg(format!(
+                    "Could not connect to the lorri daemon, is it running? ({:?})",
+                    e
+                ))
+            })?;
+        let result = ping::send(client, nix_file.clone()).map_err(|e| {
+            ExitError::errmsg(format!(
+                "Could not register {}: {:?}",
+                nix_file.as_os_str().to_string_lossy(),
+                e
+            ))
+        })?;
+        match result {
+            PingResult::Registered => {
+                registered += 1;
+                let nix_file = nix_file.as_os_str().to_string_lossy();
+                print_record(&nix_file, serde_json::json!({ "registered": nix_file }));
+            }
+            PingResult::Refused(e) => {
+                let error = format!(
+                    "{}: the daemon refused it, {}",
+                    nix_file.as_os_str().to_string_lossy(),
+                    e
+                );
+                eprintln!("{}", error);
+                errors.push(error);
+            }
+        }
+    }
+
+    let summary = format!("registered {} projects", registered);
+    if errors.is_empty() {
+        ok_msg(summary)
+    } else {
+        Err(ExitError::errmsg(format!(
+            "{}, {} paths could not be registered",
+            summary,
+            errors.len()
+        )))
+    }
+}
+
+/// The nix files named by `paths`, where `-` stands for the
+/// newline-separated paths read from `stdin` (blank lines are
+/// skipped). The paths are the ones `lorri direnv` pings the daemon
+/// with (see `locate_file::as_pinged`), so that the daemon doesn’t
+/// watch a project twice. Paths which don’t exist are reported as
+/// errors.
+fn nix_files<R: BufRead>(paths: &[PathBuf], stdin: R) -> io::Result<(Vec<NixFile>, Vec<String>)> {
+    let mut named: Vec<PathBuf> = vec![];
+    let mut stdin = Some(stdin);
+    for path in paths {
+        if path != Path::new("-") {
+            named.push(path.clone());
+            continue;
+        }
+        // stdin can only be read once
+        if let Some(stdin) = stdin.take() {
+            for line in stdin.lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    named.push(PathBuf::from(line));
+                }
+            }
+        }
+    }
+
+    let (mut nix_files, mut errors) = (vec![], vec![]);
+    for path in named {
+        match locate_file::as_pinged(&path) {
+            Ok(nix_file) => {
+                let nix_file = NixFile::from(nix_file);
+                if !nix_files.contains(&nix_file) {
+                    nix_files.push(nix_file);
+                }
+            }
+            Err(e) => errors.push(format!("cannot register {}: {}", path.display(), e)),
+        }
+    }
+    Ok((nix_files, errors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nix_files;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use NixFile;
+
+    #[test]
+    fn paths_from_arguments_and_stdin() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().canonicalize()?;
+        for project in &["a", "b", "c"] {
+            fs::create_dir(dir.join(project))?;
+            fs::write(dir.join(project).join("shell.nix"), "")?;
+        }
+        let stdin = format!(
+            "{}\n\n{}\n{}\n",
+            dir.join("b/shell.nix").display(),
+            dir.join("c/shell.nix").display(),
+            dir.join("missing/shell.nix").display()
+        );
+        let (registered, errors) = nix_files(
+            &[
+                dir.join("a/shell.nix"),
+                PathBuf::from("-"),
+                dir.join("b/shell.nix"),
+                PathBuf::from("-"),
+            ],
+            stdin.as_bytes(),
+        )?;
+        assert_eq!(
+            registered,
+            vec![
+                NixFile::from(dir.join("a/shell.nix")),
+                NixFile::from(dir.join("b/shell.nix")),
+                NixFile::from(dir.join("c/shell.nix")),
+            ]
+        );
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("missing/shell.nix"));
+        Ok(())
+    }
+
+    #[test]
+    fn symlinks_like_direnv() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path().canonicalize()?;
+        fs::create_dir(dir.join("project"))?;
+        fs::write(dir.join("project/default.nix"), "")?;
+        symlink("default.nix", dir.join("project/shell.nix"))?;
+        symlink("project", dir.join("link"))?;
+        let (registered, errors) = nix_files(&[dir.join("link/shell.nix")], &b""[..])?;
+        // direnv pings from the project directory, with shell.nix
+        assert_eq!(
+            registered,
+            vec![NixFile::from(dir.join("project/shell.nix"))]
+        );
+        assert!(errors.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Check that the GC roots of all projects still point into the
+//! nix store, for example after `nix-collect-garbage -d` removed
+//! environments while their roots were missing.
+
+use crate::nix::Store;
+use crate::ops::{ok_msg, print_note, print_record, ExitError, OpResult};
+use crate::project;
+use crate::project::roots::Roots;
+use crate::socket::communicate::{client, PingResult, Rebuild, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::NixFile;
+
+/// See the documentation for lorri::cli::Internal_::RootCheck for
+/// more details.
+pub fn main(repair: bool, rebuild: bool) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let io_error = |e: std::io::Error| {
+        ExitError::errmsg(format!(
+            "Cannot read {}: {}",
+            paths.gc_root_dir().display(),
+            e
+        ))
+    };
+    let mut root_dirs = std::fs::read_dir(paths.gc_root_dir())
+        .map_err(io_error)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io_error)?;
+    root_dirs.sort();
+
+    let store = Store::from_env();
+    let (mut projects, mut valid, mut dangling, mut removed) = (0, 0, 0, 0);
+    let mut to_rebuild: Vec<NixFile> = vec![];
+    for root_dir in root_dirs.iter().filter(|dir| dir.is_dir()) {
+        let checks = match Roots::in_dir(root_dir, store.clone()).check() {
+            Ok(checks) => checks,
+            // no roots were created for this project yet
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(io_error(e)),
+        };
+        projects += 1;
+        let mut project_dangling = false;
+        for check in checks {
+            if check.valid {
+                valid += 1;
+                continue;
+            }
+            dangling += 1;
+            project_dangling = true;
+            let dangling = format!(
+                "dangling: {} -> {}",
+                check.root.display(),
+                check.target.as_path().display()
+            );
+            let record = |removed: bool, error: Option<String>| {
+                serde_json::json!({
+                    "dangling": check.root.display().to_string(),
+                    "target": check.target.as_path().display().to_string(),
+                    "removed": removed,
+                    "error": error,
+                })
+            };
+            if repair {
+                match std::fs::remove_file(&check.root) {
+                    Ok(()) => {
+                        removed += 1;
+                        print_record(&format!("{} (removed)", dangling), record(true, None));
+                    }
+                    Err(e) => print_record(
+                        &format!("{} (could not remove it: {})", dangling, e),
+                        record(false, Some(e.to_string())),
+                    ),
+                }
+            } else {
+                print_record(&dangling, record(false, None));
+            }
+        }
+        if project_dangling && rebuild {
+            match project::nix_file_in(root_dir) {
+                Some(nix_file) => to_rebuild.push(nix_file),
+                None => print_note(&format!(
+                    "cannot rebuild {}: lorri doesn't know its nix file",
+                    root_dir.display()
+                )),
+            }
+        }
+    }
+
+    let mut summary = format!(
+        "checked the roots of {} projects: {} valid, {} dangling",
+        projects, valid, dangling
+    );
+    if repair {
+        summary.push_str(&format!(", {} removed", removed));
+    }
+    if !to_rebuild.is_empty() {
+        request_rebuilds(&SocketPath::from(paths.daemon_socket_file()), &to_rebuild)?;
+        summary.push_str(&format!(", rebuilding {} projects", to_rebuild.len()));
+    }
+
+    if dangling > removed {
+        Err(ExitError::errmsg(format!(
+            "{}\nrun with --repair to remove the dangling roots",
+            summary
+        )))
+    } else {
+        ok_msg(summary)
+    }
+}
+
+/// Ask the daemon to build `nix_files` now, also the ones it
+/// watches already.
+fn request_rebuilds(socket_path: &SocketPath, nix_files: &[NixFile]) -> Result<(), ExitError> {
+    for nix_file in nix_files {
+        let result = client::rebuild(DEFAULT_READ_TIMEOUT)
+            .connect(socket_path)
+            .map_err(|e| {
+                ExitError::errmsg(format!(
+                    "Could not connect to the lorri daemon to rebuild, is it running? ({:?})",
+                    e
+                ))
+            })?
+            .request(&Rebuild {
+                nix_file: nix_file.clone(),
+            });
+        match result {
+            Ok(PingResult::Registered) => (),
+            Ok(PingResult::Refused(e)) => {
+                print_note(&format!("the daemon cannot rebuild {}: {}", nix_file, e))
+            }
+            Err(ref e) if e.is_hang_up() => {
+                return Err(ExitError::errmsg(
+                    "The lorri daemon cannot start builds on request, restart it with this version of lorri",
+                ))
+            }
+            Err(e) => {
+                return Err(ExitError::errmsg(format!(
+                    "Could not ask the daemon to rebuild: {:?}",
+                    e
+                )))
+            }
+        }
+    }
+    Ok(())
+}
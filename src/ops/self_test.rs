@@ -0,0 +1,149 @@
+//! Smoke test the whole pipeline: let a daemon build a trivial
+//! project and check that its environment ends up rooted.
+
+use crate::build_loop::Event;
+use crate::cas::ContentAddressable;
+use crate::daemon::Daemon;
+use crate::ops::{ok_msg, print_note, ExitError, OpResult};
+use crate::project::roots::Roots;
+use crate::project::Project;
+use crate::socket::communicate::{client, Ping, WaitIdle, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use crate::NixFile;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// A project without dependencies, so the test needs neither
+/// nixpkgs nor the network (lorri replaces the builder anyway).
+const SELF_TEST_SHELL_SRC: &str = r#"derivation {
+  name = "lorri-self-test";
+  system = builtins.currentSystem;
+  builder = "/bin/sh";
+  LORRI_SELF_TEST = "1";
+}
+"#;
+
+/// How often to ask the daemon whether it watches the project yet.
+const WATCH_ATTEMPTS: u32 = 50;
+
+/// See the documentation for lorri::cli::Internal_::SelfTest for more
+/// details.
+pub fn main(ephemeral: bool) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let tempdir = tempfile::tempdir().map_err(io_error)?;
+    let project_dir = tempdir.path().join("project");
+    fs::create_dir(&project_dir).map_err(io_error)?;
+    let shell_nix = project_dir.join("shell.nix");
+    fs::write(&shell_nix, SELF_TEST_SHELL_SRC).map_err(io_error)?;
+    let nix_file = NixFile::from(shell_nix);
+
+    let mut socket_file = paths.daemon_socket_file().to_owned();
+    let mut gc_root_dir = paths.gc_root_dir().to_owned();
+    let mut cas = paths.cas_store().clone();
+    let mut ephemeral_daemon = None;
+    if !ephemeral && ping(&socket_file, &nix_file).is_ok() {
+        print_note("lorri self-test: using the running daemon");
+    } else {
+        print_note("lorri self-test: starting an ephemeral daemon");
+        socket_file = tempdir.path().join("daemon.socket");
+        gc_root_dir = tempdir.path().join("gc_roots");
+        cas = ContentAddressable::new(tempdir.path().join("cas")).map_err(io_error)?;
+        let (mut daemon, events) = Daemon::new();
+        daemon
+            .start(&socket_file, &gc_root_dir, cas.clone())
+            .map_err(|e| ExitError::errmsg(format!("Could not start a daemon: {:?}", e)))?;
+        ephemeral_daemon = Some((daemon, events));
+        ping(&socket_file, &nix_file)?;
+    }
+
+    print_note(&format!("lorri self-test: building {}", nix_file));
+    let waited = wait_idle(&socket_file, &nix_file);
+
+    let project = Project::new(nix_file, &gc_root_dir, cas).map_err(io_error)?;
+    let roots = Roots::from_project(&project).paths();
+    let environment = fs::read_to_string(roots.bash_export()).unwrap_or_default();
+    let mut result = waited.and_then(|()| {
+        if roots.all_exist() && environment.contains("LORRI_SELF_TEST") {
+            ok_msg("lorri self-test: ok")
+        } else {
+            Err(ExitError::errmsg(
+                "lorri self-test: the build didn’t produce a rooted environment",
+            ))
+        }
+    });
+
+    // clean up
+    match ephemeral_daemon {
+        Some((mut daemon, events)) => {
+            daemon.stop();
+            if result.is_err() {
+                result = result.map_err(|e| with_failures(e, &events));
+            }
+        }
+        // the running daemon keeps watching the deleted project,
+        // but there is nothing left to root
+        None => {
+            let root_dir = gc_root_dir.join(project.root_name());
+            if let Err(e) = fs::remove_dir_all(&root_dir) {
+                warn!("could not remove {}: {}", root_dir.display(), e);
+            }
+        }
+    }
+    drop(tempdir);
+    result
+}
+
+/// Ask the daemon listening on `socket_file` to build `nix_file`.
+fn ping(socket_file: &Path, nix_file: &NixFile) -> Result<(), ExitError> {
+    client::ping(DEFAULT_READ_TIMEOUT)
+        .connect(&SocketPath::from(socket_file))
+        .map_err(|e| ExitError::errmsg(format!("Could not connect to the daemon: {:?}", e)))?
+        .write(&Ping {
+            nix_file: nix_file.clone(),
+        })
+        .map_err(|e| ExitError::errmsg(format!("Could not ping the daemon: {:?}", e)))
+}
+
+/// Wait until the daemon listening on `socket_file` is done
+/// building `nix_file`. The daemon handles the ping in the
+/// background, so it might not know about `nix_file` right away.
+fn wait_idle(socket_file: &Path, nix_file: &NixFile) -> Result<(), ExitError> {
+    for _ in 0..WATCH_ATTEMPTS {
+        let result = client::wait_idle(Timeout::Infinite)
+            .connect(&SocketPath::from(socket_file))
+            .map_err(|e| ExitError::errmsg(format!("Could not connect to the daemon: {:?}", e)))?
+            .request(&WaitIdle {
+                nix_file: Some(nix_file.clone()),
+            })
+            .map_err(|e| ExitError::errmsg(format!("The daemon did not answer: {:?}", e)))?;
+        if result.watched {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(ExitError::errmsg(format!(
+        "The daemon didn’t start watching {}",
+        nix_file
+    )))
+}
+
+/// Add the log of the failed builds in `events` to `error`.
+fn with_failures(error: ExitError, events: &mpsc::Receiver<Event>) -> ExitError {
+    let mut message = error.message().to_string();
+    for event in events.try_iter() {
+        if let Event::Failure(_, _, failure) = event {
+            message.push_str("\nThe build failed:");
+            for line in failure.log_lines {
+                message.push_str(&format!("\n  {}", line.to_string_lossy()));
+            }
+        }
+    }
+    ExitError::errmsg(message)
+}
+
+fn io_error(e: std::io::Error) -> ExitError {
+    ExitError::errmsg(format!("lorri self-test: {}", e))
+}
@@ -0,0 +1,44 @@
+//! Print what lorri evaluates for a shell file, to debug
+//! differences between `nix-shell` and lorri.
+
+use crate::bash;
+use crate::builder::{self, LOGGED_EVALUATION_NIX};
+use crate::ops::{ok, ok_msg, porcelain, print_record, ExitError, OpResult};
+use crate::project::Project;
+
+/// See the documentation for lorri::cli::Internal_::ShowEvalExpr for
+/// more details.
+pub fn main(project: Project) -> OpResult {
+    let options = project
+        .config()
+        .map_err(|e| e.to_string())
+        .and_then(|config| project.nix_options(&config))
+        .map_err(ExitError::errmsg)?;
+    let args = builder::nix_build_args(&project.source, &project.cas, &project.store, &options)
+        .map_err(|e| {
+            ExitError::errmsg(format!("Could not write the evaluated expression: {}", e))
+        })?;
+
+    let command: Vec<String> = std::iter::once(String::from("nix-build"))
+        .chain(args.iter().map(|arg| bash::quote(&arg.to_string_lossy())))
+        .collect();
+
+    if porcelain() {
+        print_record(
+            "",
+            serde_json::json!({
+                "source": project.source.to_string(),
+                "expression": LOGGED_EVALUATION_NIX,
+                "command": command,
+            }),
+        );
+        return ok();
+    }
+    println!(
+        "# lorri evaluates this expression, with `src` set to (a file with) {}:\n",
+        project.source
+    );
+    println!("{}", LOGGED_EVALUATION_NIX.trim_end());
+    println!("\n# by running:\n");
+    ok_msg(command.join(" "))
+}
@@ -0,0 +1,86 @@
+//! Report the resources the daemon uses.
+
+use crate::fds::Usage;
+use crate::ops::{ok, porcelain, print_note, print_record, ExitError, OpResult};
+use crate::socket::communicate::{client, Latency, LatencyResult, Stats, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+
+/// See the documentation for lorri::cli::Internal_::Stats for more
+/// details.
+pub fn main(json: bool) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let stats = client::stats(DEFAULT_READ_TIMEOUT)
+        .connect(&socket_path)
+        .map_err(|e| {
+            ExitError::errmsg(format!(
+                "Could not connect to the lorri daemon, is it running? ({:?})",
+                e
+            ))
+        })?
+        .request(&Stats {})
+        .map_err(|e| ExitError::errmsg(format!("The daemon did not answer: {:?}", e)))?;
+    // daemons before protocol version 13 don’t measure latency
+    let latency = client::latency(DEFAULT_READ_TIMEOUT)
+        .connect(&socket_path)
+        .ok()
+        .and_then(|client| client.request(&Latency {}).ok());
+
+    if json || porcelain() {
+        print_record(
+            "",
+            serde_json::json!({
+                "projects": stats.projects,
+                "open_fds": stats.open_fds,
+                "peak_open_fds": stats.peak_open_fds,
+                "fd_soft_limit": stats.fd_soft_limit,
+                "fd_hard_limit": stats.fd_hard_limit,
+                "build_latency": latency.as_ref().map(|latency| serde_json::json!({
+                    "builds": latency.builds,
+                    "last_ms": latency.last_ms,
+                    "mean_ms": latency.mean_ms(),
+                    "max_ms": latency.max_ms,
+                })),
+            }),
+        );
+        return ok();
+    }
+    let limit = |limit: Option<u64>| {
+        limit
+            .map(|limit| limit.to_string())
+            .unwrap_or_else(|| String::from("unlimited"))
+    };
+    println!("projects:              {}", stats.projects);
+    println!("open file descriptors: {}", stats.open_fds);
+    println!("peak:                  {}", stats.peak_open_fds);
+    println!(
+        "limit:                 {} (hard: {})",
+        limit(stats.fd_soft_limit),
+        limit(stats.fd_hard_limit)
+    );
+    if let Some(latency) = latency {
+        println!("build latency:         {}", describe(&latency));
+    }
+    let peak = Usage {
+        open: stats.peak_open_fds,
+        limit: stats.fd_soft_limit,
+    };
+    if peak.near_limit() {
+        print_note(
+            "the daemon came close to its file descriptor limit; raise it \
+             (`ulimit -n`, or `LimitNOFILE=` for systemd services) or watch fewer projects",
+        );
+    }
+    ok()
+}
+
+/// How long builds took to start after a change, for humans.
+fn describe(latency: &LatencyResult) -> String {
+    match latency.mean_ms() {
+        None => String::from("no builds started by a change yet"),
+        Some(mean) => format!(
+            "{}ms last, {}ms mean, {}ms max ({} builds)",
+            latency.last_ms, mean, latency.max_ms, latency.builds
+        ),
+    }
+}
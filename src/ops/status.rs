@@ -0,0 +1,141 @@
+//! Summarize the last build of a project in the daemon.
+
+use crate::ops::{ok_msg, ExitError, OpResult};
+use crate::project::config::utc_timestamp;
+use crate::socket::communicate::{client, BuildState, ProjectStatus, Status, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::NixFile;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// See the documentation for lorri::cli::Command::Status for more
+/// details.
+pub fn main(nix_file: NixFile) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let result = client::status(DEFAULT_READ_TIMEOUT)
+        .connect(&SocketPath::from(paths.daemon_socket_file()))
+        .map_err(|e| {
+            ExitError::errmsg(format!(
+                "Could not connect to the lorri daemon, is it running? ({:?})",
+                e
+            ))
+        })?
+        .request(&Status {
+            nix_file: nix_file.clone(),
+        })
+        .map_err(|e| ExitError::errmsg(format!("The daemon did not answer: {:?}", e)))?;
+
+    match result.project {
+        Some(status) => {
+            let modified = std::fs::metadata(nix_file.as_os_str())
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            ok_msg(summary(&status, modified))
+        }
+        None => Err(ExitError::errmsg(format!(
+            "The lorri daemon does not watch {} yet, run `lorri direnv` or \
+             `lorri internal ping` in the project first",
+            nix_file
+        ))),
+    }
+}
+
+/// A human-readable summary of `status`, where the nix file was last
+/// modified at `nix_file_modified`.
+fn summary(status: &ProjectStatus, nix_file_modified: Option<SystemTime>) -> String {
+    let project = &status.project;
+    let state = match project.state {
+        BuildState::Pending => "pending",
+        BuildState::Building => "building",
+        BuildState::Success => "success",
+        BuildState::Failure => "failure",
+        BuildState::Cancelled => "cancelled",
+    };
+    let last_build = match (project.last_build, status.build_duration) {
+        (Some(secs), Some(millis)) => format!(
+            "{}, {} UTC, took {:.1}s",
+            state,
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(secs)),
+            millis as f64 / 1000.0
+        ),
+        (Some(secs), None) => format!(
+            "{}, {} UTC",
+            state,
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(secs))
+        ),
+        (None, _) => String::from(state),
+    };
+    let started = status
+        .build_started
+        .map(|millis| UNIX_EPOCH + Duration::from_millis(millis));
+    // the nix file is read when the build starts
+    let environment = match (project.state, started, nix_file_modified) {
+        (BuildState::Pending, _, _) | (_, None, _) => "not built yet",
+        (BuildState::Building, _, _) => "building",
+        (_, Some(started), Some(modified)) if modified > started => {
+            "stale, the nix file changed after the last build started"
+        }
+        (BuildState::Success, _, _) => "up to date",
+        (_, _, _) => "stale, the last build did not succeed",
+    };
+
+    let mut lines = vec![
+        project.nix_file.to_string(),
+        format!("  last build:    {}", last_build),
+        format!("  environment:   {}", environment),
+    ];
+    let mut output_paths = status.output_paths.iter();
+    match output_paths.next() {
+        Some(first) => {
+            lines.push(format!("  output paths:  {}", first.display()));
+            for path in output_paths {
+                lines.push(format!("                 {}", path.display()));
+            }
+        }
+        None => lines.push(String::from("  output paths:  none yet")),
+    }
+    lines.push(format!("  watched files: {}", status.watched_files));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::summary;
+    use socket::communicate::{BuildState, ProjectStatus, WatchedProject};
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+    use NixFile;
+
+    #[test]
+    fn summary_of_a_project() {
+        let mut status = ProjectStatus {
+            project: WatchedProject {
+                nix_file: NixFile::from(PathBuf::from("/project/shell.nix")),
+                state: BuildState::Success,
+                last_build: Some(1_577_836_812),
+                gc_root: PathBuf::from("/gc_roots/0123/gc_root/shell_gc_root"),
+            },
+            build_started: Some(1_577_836_800_000),
+            build_duration: Some(12_345),
+            output_paths: vec![PathBuf::from("/gc_roots/0123/gc_root/shell_gc_root")],
+            watched_files: 42,
+        };
+        let built = UNIX_EPOCH + Duration::from_secs(1_577_836_800);
+        assert_eq!(
+            summary(&status, Some(built - Duration::from_secs(60))),
+            "/project/shell.nix\n  \
+             last build:    success, 2020-01-01 00:00:12 UTC, took 12.3s\n  \
+             environment:   up to date\n  \
+             output paths:  /gc_roots/0123/gc_root/shell_gc_root\n  \
+             watched files: 42"
+        );
+        assert!(summary(&status, Some(built + Duration::from_secs(1)))
+            .contains("environment:   stale, the nix file changed after the last build started"));
+
+        status.project.state = BuildState::Failure;
+        assert!(summary(&status, Some(built - Duration::from_secs(60)))
+            .contains("environment:   stale, the last build did not succeed"));
+
+        status.project.state = BuildState::Building;
+        assert!(summary(&status, None).contains("environment:   building"));
+    }
+}
@@ -0,0 +1,34 @@
+//! Ask the lorri daemon to shut down.
+
+use crate::ops::{ok_msg, ExitError, OpResult};
+use crate::socket::communicate::{client, Shutdown, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+
+/// See the documentation for lorri::cli::Internal_::StopDaemon for
+/// more details.
+pub fn main(cancel_builds: bool) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let result = client::shutdown(DEFAULT_READ_TIMEOUT)
+        .connect(&SocketPath::from(paths.daemon_socket_file()))
+        .map_err(|e| {
+            ExitError::errmsg(format!(
+                "Could not connect to the lorri daemon, is it running? ({:?})",
+                e
+            ))
+        })?
+        .request(&Shutdown { cancel_builds })
+        .map_err(|e| ExitError::errmsg(format!("The daemon did not answer: {:?}", e)))?;
+
+    ok_msg(match (result.running_builds, cancel_builds) {
+        (0, _) => String::from("The lorri daemon is stopping."),
+        (n, false) => format!(
+            "The lorri daemon stops once its {} running builds are done.\n\
+             Run `lorri internal stop-daemon --cancel-builds` to cancel them.",
+            n
+        ),
+        (n, true) => format!(
+            "The lorri daemon is stopping, its {} running builds are cancelled.",
+            n
+        ),
+    })
+}
@@ -0,0 +1,338 @@
+//! Print the events of the daemon’s build loops as JSON lines.
+//!
+//! With `--follow`, a lost connection (like when the daemon restarts
+//! for an upgrade) is re-established, waiting longer after every
+//! failed attempt (see `build_loop::retry_delay`), and resumed after
+//! the last event printed.
+
+use crate::build_loop::retry_delay;
+use crate::event_sink::EventKind;
+use crate::event_stream::Since;
+use crate::ops::{ExitError, OpResult};
+use crate::socket::communicate::client::Answers;
+use crate::socket::communicate::{client, EventMessage, Monitor, Resume, StreamEvents, Subscribe};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use crate::NixFile;
+use std::io::Write;
+use std::time::Duration;
+
+/// The line printed after reconnecting with `--follow`, whether the
+/// daemon restarted or disconnected this listener for reading too
+/// slowly.
+const RECONNECTED: &str = "{\"event\":\"reconnected\"}\n";
+
+/// Wait this long before the first attempt to reconnect.
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+
+/// Never wait longer than this before an attempt to reconnect.
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// See the documentation for lorri::cli::Internal_::StreamEvents for
+/// more details.
+/// With `only`, just the events of these kinds are printed. With
+/// `follow`, reconnect to the daemon instead of exiting when the
+/// connection is lost. With `log_lines`, the lines nix prints while
+/// building are printed as well.
+pub fn main(
+    nix_file: Option<NixFile>,
+    since: Option<Since>,
+    only: &[EventKind],
+    follow: bool,
+    log_lines: bool,
+) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    stream(
+        &socket_path,
+        nix_file,
+        since,
+        only,
+        follow,
+        log_lines,
+        &mut out,
+    )
+}
+
+/// Print the events of the daemon at `socket_path` to `out`, see
+/// `main`.
+fn stream(
+    socket_path: &SocketPath,
+    nix_file: Option<NixFile>,
+    since: Option<Since>,
+    only: &[EventKind],
+    follow: bool,
+    log_lines: bool,
+    out: &mut impl Write,
+) -> OpResult {
+    let mut since = since;
+    let mut connected = false;
+    let mut attempts = 0;
+    loop {
+        let lost = match subscribe(socket_path, nix_file.clone(), since, log_lines) {
+            Ok(answers) => {
+                if connected {
+                    print(out, RECONNECTED)?;
+                }
+                connected = true;
+                attempts = 0;
+                forward(answers, only, out, &mut since)?
+            }
+            Err(e) => e,
+        };
+        // a restarted daemon numbers its events anew, so only resume
+        // after an event whose epoch tells it whether it did
+        if since.map(|since| since.epoch.is_none()).unwrap_or(false) {
+            since = None;
+        }
+        if !follow {
+            return Err(ExitError::errmsg(lost));
+        }
+        if attempts == 0 {
+            warn!("{}; reconnecting", lost);
+        } else {
+            debug!("{}; reconnecting", lost);
+        }
+        attempts += 1;
+        std::thread::sleep(retry_delay(RECONNECT_DELAY, RECONNECT_MAX_DELAY, attempts));
+    }
+}
+
+/// Ask the daemon for its events (after `since`, if given), and
+/// for the log lines with `log_lines`.
+fn subscribe(
+    socket_path: &SocketPath,
+    nix_file: Option<NixFile>,
+    since: Option<Since>,
+    log_lines: bool,
+) -> Result<Answers<EventMessage>, String> {
+    let connect_error = |e| {
+        format!(
+            "Could not connect to the lorri daemon, is it running? ({:?})",
+            e
+        )
+    };
+    let request_error = |e| format!("Could not ask the daemon: {:?}", e);
+    // daemons which can’t resume still understand `StreamEvents`,
+    // only the newest understand `Subscribe` and `Resume`
+    match since {
+        Some(Since {
+            sequence,
+            epoch: Some(epoch),
+        }) => client::resume(Timeout::Infinite)
+            .connect(socket_path)
+            .map_err(connect_error)?
+            .request_stream(&Resume {
+                nix_file,
+                since: Some(sequence),
+                epoch: Some(epoch),
+                log_lines,
+            })
+            .map_err(request_error),
+        _ if log_lines => client::subscribe(Timeout::Infinite)
+            .connect(socket_path)
+            .map_err(connect_error)?
+            .request_stream(&Subscribe {
+                nix_file,
+                since: since.map(|since| since.sequence),
+                log_lines,
+            })
+            .map_err(request_error),
+        None => client::stream_events(Timeout::Infinite)
+            .connect(socket_path)
+            .map_err(connect_error)?
+            .request_stream(&StreamEvents { nix_file })
+            .map_err(request_error),
+        Some(since) => client::monitor(Timeout::Infinite)
+            .connect(socket_path)
+            .map_err(connect_error)?
+            .request_stream(&Monitor {
+                nix_file,
+                since: Some(since.sequence),
+            })
+            .map_err(request_error),
+    }
+}
+
+/// Print the events of `answers` to `out` until the daemon stops
+/// sending them, and return why it did. `since` is set to every
+/// event with a sequence number. Fails if they can’t be printed.
+fn forward(
+    answers: Answers<EventMessage>,
+    only: &[EventKind],
+    out: &mut impl Write,
+    since: &mut Option<Since>,
+) -> Result<String, ExitError> {
+    for answer in answers {
+        let line = match answer {
+            Err(e) => return Ok(format!("The daemon did not answer: {:?}", e)),
+            Ok(EventMessage::Event(line)) => {
+                let event = serde_json::from_str::<serde_json::Value>(&line).ok();
+                if let Some(sequence) = event.as_ref().and_then(|event| event["sequence"].as_u64())
+                {
+                    *since = Some(Since {
+                        sequence,
+                        epoch: event.as_ref().and_then(|event| event["epoch"].as_u64()),
+                    });
+                }
+                if !wanted(event.as_ref(), only) {
+                    continue;
+                }
+                line
+            }
+            Ok(EventMessage::Gap { dropped }) => {
+                format!("{{\"event\":\"gap\",\"dropped\":{}}}\n", dropped)
+            }
+        };
+        print(out, &line)?;
+    }
+    Ok(String::from(
+        "The daemon closed the event stream (it stopped, or this listener read too slowly)",
+    ))
+}
+
+/// Print `line` to `out` right away, since consumers read the events
+/// as they come.
+fn print(out: &mut impl Write, line: &str) -> Result<(), ExitError> {
+    out.write_all(line.as_bytes())
+        .and_then(|()| out.flush())
+        .map_err(|e| ExitError::errmsg(format!("Could not print an event: {}", e)))
+}
+
+/// Whether the JSON `event` (None if it isn’t valid JSON) is of one
+/// of the kinds in `only` (all are, if it is empty).
+fn wanted(event: Option<&serde_json::Value>, only: &[EventKind]) -> bool {
+    if only.is_empty() {
+        return true;
+    }
+    match event {
+        Some(event) => only.iter().any(|kind| event["event"] == kind.event_name()),
+        // don’t hide what we don’t understand
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stream, wanted};
+    use event_sink::EventKind;
+    use socket::communicate::{listener, CommunicationType, EventMessage, Resume, StreamEvents};
+    use socket::path::SocketPath;
+    use socket::{ReadWriter, Timeout};
+    use std::io::{self, Write};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn only_the_wanted_kinds() {
+        let event = |line: &str| serde_json::from_str::<serde_json::Value>(line).ok();
+        let started = event(r#"{"nix_file":"/p/shell.nix","event":"started","build_id":1}"#);
+        let failure = event(r#"{"nix_file":"/p/shell.nix","event":"failure","build_id":1}"#);
+        let progress = event(r#"{"nix_file":"/p/shell.nix","event":"progress","build_id":1}"#);
+        let only = [EventKind::Failures, EventKind::Completions];
+        assert!(!wanted(started.as_ref(), &only));
+        assert!(wanted(failure.as_ref(), &only));
+        assert!(!wanted(progress.as_ref(), &only));
+        assert!(wanted(progress.as_ref(), &[]));
+        assert!(wanted(None, &only));
+    }
+
+    /// Sends every line written to it, and fails once nobody
+    /// receives them anymore.
+    struct Lines(mpsc::Sender<String>);
+
+    impl Write for Lines {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .send(String::from_utf8_lossy(buf).into_owned())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn event(sequence: u64) -> EventMessage {
+        EventMessage::Event(format!(
+            "{{\"event\":\"started\",\"sequence\":{},\"epoch\":7}}\n",
+            sequence
+        ))
+    }
+
+    /// With `--follow`, a dropped connection is re-established and
+    /// resumed after the last event.
+    #[test]
+    fn follow() -> io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let socket = tmp.path().join("socket");
+        let listener = listener::Listener::new(&SocketPath::from(&socket)).unwrap();
+        let (resumed_tx, resumed_rx) = mpsc::channel();
+        let (proceed_tx, proceed_rx) = mpsc::channel::<()>();
+        let daemon = std::thread::spawn(move || {
+            // the first listener is disconnected after two events
+            listener
+                .accept(|socket, comm_type| {
+                    assert_eq!(comm_type, CommunicationType::StreamEvents);
+                    let mut rw: ReadWriter<StreamEvents, EventMessage> = ReadWriter::new(&socket);
+                    rw.read(&Timeout::Infinite).unwrap();
+                    rw.write(&Timeout::Infinite, &event(1)).unwrap();
+                    rw.write(&Timeout::Infinite, &event(2)).unwrap();
+                })
+                .unwrap()
+                .join()
+                .unwrap();
+            listener
+                .accept(move |socket, comm_type| {
+                    assert_eq!(comm_type, CommunicationType::Resume);
+                    let mut rw: ReadWriter<Resume, EventMessage> = ReadWriter::new(&socket);
+                    let resume = rw.read(&Timeout::Infinite).unwrap();
+                    resumed_tx.send((resume.since, resume.epoch)).unwrap();
+                    rw.write(&Timeout::Infinite, &event(3)).unwrap();
+                    // once the client stopped reading
+                    proceed_rx.recv().unwrap();
+                    rw.write(&Timeout::Infinite, &event(4)).unwrap();
+                })
+                .unwrap()
+                .join()
+                .unwrap();
+        });
+
+        let (lines_tx, lines_rx) = mpsc::channel();
+        let client = std::thread::spawn(move || {
+            stream(
+                &SocketPath::from(&socket),
+                None,
+                None,
+                &[],
+                true,
+                false,
+                &mut Lines(lines_tx),
+            )
+        });
+        let timeout = Duration::from_secs(10);
+        let lines: Vec<String> = (0..4)
+            .map(|_| lines_rx.recv_timeout(timeout).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "{\"event\":\"started\",\"sequence\":1,\"epoch\":7}\n",
+                "{\"event\":\"started\",\"sequence\":2,\"epoch\":7}\n",
+                "{\"event\":\"reconnected\"}\n",
+                "{\"event\":\"started\",\"sequence\":3,\"epoch\":7}\n",
+            ]
+        );
+        assert_eq!(resumed_rx.recv_timeout(timeout), Ok((Some(2), Some(7))));
+
+        // printing the next event fails
+        drop(lines_rx);
+        proceed_tx.send(()).unwrap();
+        assert!(client.join().unwrap().is_err());
+        daemon.join().unwrap();
+        Ok(())
+    }
+}
@@ -4,14 +4,20 @@
 //! However, while this repo is closed source, it uses a
 //! rolling-release branch.
 
+use crate::bash;
 use crate::changelog;
 use crate::cli;
 use crate::nix;
-use crate::ops::{ExitError, OpResult};
+use crate::ops::{child_stdout, ok, print_note, print_record, ExitError, OpResult};
 use crate::VERSION_BUILD_REV;
 use cas::ContentAddressable;
+use std::ffi::OsString;
+use std::path::Path;
 use std::process::Command;
 
+/// The repository `upgrade.nix` fetches branches from.
+const LORRI_REPOSITORY: &str = "https://github.com/target/lorri.git";
+
 impl From<cli::UpgradeTo> for String {
     fn from(desc: cli::UpgradeTo) -> Self {
         match desc.source.unwrap_or(cli::UpgradeSource::RollingRelease) {
@@ -36,9 +42,9 @@ pub fn main(upgrade_target: cli::UpgradeTo, cas: &ContentAddressable) -> OpResul
      */
     let upgrade_expr = include_str!("./upgrade.nix");
 
+    let dry_run = upgrade_target.dry_run;
+    let src = String::from(upgrade_target);
     let expr = {
-        let src = String::from(upgrade_target);
-        println!("Upgrading from source: {}", src);
         let mut expr = nix::CallOpts::file(
             cas.file_from_string(upgrade_expr)
                 .expect("could not write to CAS"),
@@ -50,25 +56,33 @@ pub fn main(upgrade_target: cli::UpgradeTo, cas: &ContentAddressable) -> OpResul
         expr
     };
 
+    if dry_run {
+        return dry_run_plan(&src, &expr);
+    }
+    print_note(&format!("Upgrading from source: {}", src));
+
     let changelog: changelog::Log = expr.clone().attribute("changelog").value().unwrap();
 
-    println!("Changelog when upgrading from {}:", VERSION_BUILD_REV);
+    print_note(&format!(
+        "Changelog when upgrading from {}:",
+        VERSION_BUILD_REV
+    ));
     for entry in changelog.entries {
         if VERSION_BUILD_REV < entry.version {
-            println!();
-            println!("{}:", entry.version);
+            print_note(&format!("\n{}:", entry.version));
             for line in entry.changes.lines() {
-                println!("    {}", line);
+                print_note(&format!("    {}", line));
             }
         }
     }
 
-    println!("Building ...");
+    print_note("Building ...");
     match expr.clone().attribute("package").path() {
         Ok((build_result, gc_root)) => {
             let status = Command::new("nix-env")
                 .arg("--install")
                 .arg(build_result.as_path())
+                .stdout(child_stdout())
                 .status()
                 .expect("Error: failed to execute nix-env --install");
             // we can drop the temporary gc root
@@ -89,3 +103,106 @@ pub fn main(upgrade_target: cli::UpgradeTo, cas: &ContentAddressable) -> OpResul
         ))),
     }
 }
+
+/// Print what `main` would do for `src`, without doing it.
+fn dry_run_plan(src: &str, expr: &nix::CallOpts) -> OpResult {
+    let (source, revision) = describe_source(src);
+    let build = build_command_line(expr.clone().attribute("package").build_arguments());
+    let profile = std::env::var_os("HOME").map(|home| Path::new(&home).join(".nix-profile"));
+    let profile_target = profile
+        .as_ref()
+        .and_then(|profile| std::fs::read_link(profile).ok());
+
+    let mut text = format!("source: {}", source);
+    if let Some(ref revision) = revision {
+        text.push_str(&format!("\nrevision: {}", revision));
+    }
+    text.push_str(&format!(
+        "\nbuild: {}\ninstall: nix-env --install <the built package>",
+        build
+    ));
+    match (&profile, &profile_target) {
+        (Some(profile), Some(target)) => text.push_str(&format!(
+            "\nprofile: {} -> {}",
+            profile.display(),
+            target.display()
+        )),
+        (Some(profile), None) => text.push_str(&format!("\nprofile: {}", profile.display())),
+        (None, _) => text.push_str("\nprofile: the default nix-env profile"),
+    }
+    print_record(
+        &text,
+        serde_json::json!({
+            "source": source,
+            "revision": revision,
+            "build": build,
+            "install": "nix-env --install",
+            "profile": profile.as_ref().map(|p| p.display().to_string()),
+            "profile_target": profile_target.as_ref().map(|p| p.display().to_string()),
+        }),
+    );
+    ok()
+}
+
+/// Where `upgrade.nix` fetches `src` from, and the revision of a
+/// local checkout (branches are only resolved when fetching).
+fn describe_source(src: &str) -> (String, Option<String>) {
+    match src {
+        "master" | "rolling-release" => (format!("branch {} of {}", src, LORRI_REPOSITORY), None),
+        path => {
+            let revision = Command::new("git")
+                .args(&["-C", path, "rev-parse", "HEAD"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_owned());
+            (format!("local checkout {}", path), revision)
+        }
+    }
+}
+
+/// The `nix-build` command line with `arguments`, as it would be
+/// typed into a shell.
+fn build_command_line(arguments: Vec<OsString>) -> String {
+    let mut words: Vec<String> = ["nix-build", "--out-link", "<temporary GC root>"]
+        .iter()
+        .map(|word| String::from(*word))
+        .collect();
+    words.extend(arguments.iter().map(|argument| {
+        let argument = argument.to_string_lossy();
+        let plain = argument
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:+@,".contains(c));
+        if plain {
+            argument.into_owned()
+        } else {
+            bash::quote(&argument)
+        }
+    }));
+    words.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_command_line, describe_source};
+    use nix::CallOpts;
+    use std::path::PathBuf;
+
+    #[test]
+    fn dry_run_plan() {
+        let mut expr = CallOpts::file(PathBuf::from("/cas/upgrade.nix"));
+        expr.argstr("src", "/home/user/my lorri");
+        assert_eq!(
+            build_command_line(expr.attribute("package").build_arguments()),
+            "nix-build --out-link <temporary GC root> -A package \
+             --argstr src '/home/user/my lorri' -- /cas/upgrade.nix"
+        );
+        assert_eq!(
+            describe_source("rolling-release"),
+            (
+                String::from("branch rolling-release of https://github.com/target/lorri.git"),
+                None
+            )
+        );
+    }
+}
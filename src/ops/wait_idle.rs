@@ -0,0 +1,34 @@
+//! Wait until the daemon has no pending or running builds.
+
+use crate::ops::{ok, ExitError, OpResult};
+use crate::socket::communicate::{client, WaitIdle};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use crate::NixFile;
+
+/// See the documentation for lorri::cli::Internal_::WaitIdle for more
+/// details.
+pub fn main(nix_file: Option<NixFile>) -> OpResult {
+    let paths = ::ops::get_paths()?;
+    // builds take as long as they take
+    let result = client::wait_idle(Timeout::Infinite)
+        .connect(&SocketPath::from(paths.daemon_socket_file()))
+        .map_err(|e| {
+            ExitError::errmsg(format!(
+                "Could not connect to the lorri daemon, is it running? ({:?})",
+                e
+            ))
+        })?
+        .request(&WaitIdle {
+            nix_file: nix_file.clone(),
+        })
+        .map_err(|e| ExitError::errmsg(format!("The daemon did not answer: {:?}", e)))?;
+
+    match nix_file {
+        Some(ref nix_file) if !result.watched => Err(ExitError::errmsg(format!(
+            "{} is not watched by the daemon",
+            nix_file
+        ))),
+        _ => ok(),
+    }
+}
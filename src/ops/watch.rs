@@ -1,54 +1,120 @@
-//! Run a BuildLoop for `shell.nix`, watching for input file changes.
+//! Run a BuildLoop for `shell.nix` (or an expression, see
+//! `WatchOptions::expr`), watching for input file changes.
 //! Can be used together with `direnv`.
-use crate::build_loop::{BuildError, BuildLoop};
+use crate::build_loop::{BuildError, BuildId, BuildLoop, Event};
 use crate::cli::WatchOptions;
-use crate::ops::{ok, ExitError, OpResult};
+use crate::config::Config;
+use crate::event_sink::to_json_line;
+use crate::hooks;
+use crate::notification::Notifier;
+use crate::ops::{ok, porcelain, ExitError, OpResult};
+use crate::project::config::{HooksConfig, ProjectConfig};
 use crate::project::Project;
+use crate::NixSource;
 use std::fmt::Debug;
 use std::io::Write;
 use std::sync::mpsc::channel;
 use std::thread;
+use std::time::SystemTime;
 
 /// See the documentation for lorri::cli::Command::Shell for more
 /// details.
 pub fn main(project: Project, opts: WatchOptions) -> OpResult {
+    let mut config = Config::load(::ops::get_paths()?.config_file())
+        .map_err(|e| ExitError::errmsg(e.to_string()))?;
+    // flags take precedence over the configuration
+    if opts.cancel_on_change || opts.no_cancel_on_change {
+        config.build.cancel_on_change = opts.cancel_on_change;
+    }
+    if let Some(debounce_ms) = opts.debounce_ms {
+        config.watch.debounce_ms = debounce_ms;
+    }
+    if let Some(backend) = opts.watch_backend {
+        config.watch.backend = backend;
+    }
+    config.notify.enabled |= opts.notify;
+    if let Some(long_build_secs) = opts.notify_long_build_secs {
+        config.notify.long_build_secs = long_build_secs;
+    }
     if opts.once {
-        main_run_once(project)
+        main_run_once(project, &config)
     } else {
-        main_run_forever(project)
+        main_run_forever(project, config)
     }
 }
 
-fn main_run_once(project: Project) -> OpResult {
+fn main_run_once(project: Project, config: &Config) -> OpResult {
     let mut build_loop = BuildLoop::new(&project);
+    build_loop.configure(config);
     match build_loop.once() {
-        Ok(msg) => {
-            print_build_message(msg);
+        Ok(results) => {
+            if porcelain() {
+                let event = Event::Completed(BuildId::next(), SystemTime::now(), results);
+                print_event(&project.source, &event);
+            } else {
+                print_build_message(results);
+            }
             ok()
         }
         Err(BuildError::Unrecoverable(err)) => Err(ExitError::err(100, format!("{:?}", err))),
-        Err(BuildError::Recoverable(exit_failure)) => {
+        Err(BuildError::Cancelled) => Err(ExitError::errmsg("The build was cancelled")),
+        Err(BuildError::Recoverable(exit_failure))
+        | Err(BuildError::Network(exit_failure))
+        | Err(BuildError::Interactive(exit_failure)) => {
             Err(ExitError::errmsg(format!("{:#?}", exit_failure)))
         }
     }
 }
 
-fn main_run_forever(project: Project) -> OpResult {
+fn main_run_forever(project: Project, config: Config) -> OpResult {
+    let source = project.source.clone();
+    let config_root = project.config_root().to_owned();
+    let global_hooks = config.hooks.clone();
+    let mut notifier = if config.notify.enabled {
+        Some(Notifier::new(&config.notify))
+    } else {
+        None
+    };
     let (tx, rx) = channel();
     let build_thread = {
         thread::spawn(move || {
             let mut build_loop = BuildLoop::new(&project);
-            build_loop.forever(tx);
+            build_loop.configure(&config);
+            build_loop.forever(tx)
         })
     };
 
+    // reloaded with every build, like in the daemon
+    let mut hooks = HooksConfig::default();
     for msg in rx {
-        print_build_message(msg);
+        if let Event::Started(..) = msg {
+            match ProjectConfig::load(&config_root) {
+                Ok(config) => hooks = config.hooks,
+                // the build reports the error, too
+                Err(e) => warn!("keeping the previous hooks: {}", e),
+            }
+        }
+        hooks::run(&hooks, &global_hooks, &config_root, &source, &msg);
+        if let Some(ref mut notifier) = notifier {
+            notifier.observe(&source, &msg);
+        }
+        if porcelain() {
+            print_event(&source, &msg);
+        } else {
+            print_build_message(msg);
+        }
     }
 
-    build_thread.join().unwrap();
+    match build_thread.join().unwrap() {
+        Ok(()) => ok(),
+        Err(err) => Err(ExitError::err(100, format!("{:?}", err))),
+    }
+}
 
-    ok()
+/// Print `event` as a JSON line (see `event_sink`) and flush.
+fn print_event(source: &NixSource, event: &Event) {
+    print!("{}", to_json_line(source, event));
+    let _ = std::io::stdout().flush();
 }
 
 /// Print a build message to stdout and flush.
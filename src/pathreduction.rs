@@ -1,13 +1,65 @@
 //! Given a list of paths, reduce them to a minimum set of paths
 //! which should be watched for changes.
 
+extern crate nix;
+
+use self::nix::libc;
 use std::collections::HashSet;
+use std::fmt;
+use std::io;
 use std::path::{Path, PathBuf};
 
+/// Paths with more components than this are left out of the
+/// reduction, since they are most likely the result of a loop in the
+/// filesystem (a bind mount of a directory below itself, say).
+pub const MAX_DEPTH: usize = 256;
+
+/// The paths to watch, and the ones which had to be left out.
+#[derive(Debug, Default)]
+pub struct Reduced {
+    /// The minimum set of paths to watch.
+    pub paths: HashSet<PathBuf>,
+    /// Paths which cannot be watched, and why.
+    pub skipped: Vec<Skipped>,
+}
+
+/// A path `reduce_paths` left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Skipped {
+    /// The path as it was passed to `reduce_paths`.
+    pub path: PathBuf,
+    /// Why it was left out.
+    pub reason: SkipReason,
+}
+
+/// Why `reduce_paths` left a path out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SkipReason {
+    /// Resolving the path runs into a symlink cycle.
+    SymlinkCycle,
+    /// The path has more than `MAX_DEPTH` components.
+    TooDeep,
+}
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.reason {
+            SkipReason::SymlinkCycle => write!(f, "{} (symlink cycle)", self.path.display()),
+            SkipReason::TooDeep => write!(
+                f,
+                "{} (more than {} components deep)",
+                self.path.display(),
+                MAX_DEPTH
+            ),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug)]
 enum PathReduction {
     Reduced(PathBuf),
     Remove,
+    Skip(SkipReason),
 }
 
 #[derive(Debug)]
@@ -26,11 +78,19 @@ impl PathReduction {
 }
 
 /// Reduce one list of paths to another list of paths.
-pub fn reduce_paths(paths: &[PathBuf]) -> HashSet<PathBuf> {
+///
+/// Paths in symlink cycles or nested absurdly deep are skipped rather
+/// than followed, and reported in `Reduced::skipped`.
+pub fn reduce_paths(paths: &[PathBuf]) -> Reduced {
+    let mut skipped = vec![];
     let mut reduced = paths
         .iter()
         .map(|path| {
-            let reducers = &[reduce_channel_path, reduce_nix_store_path];
+            let reducers = &[
+                reduce_pathological_path,
+                reduce_channel_path,
+                reduce_nix_store_path,
+            ];
 
             for reducer in reducers {
                 match reducer(path) {
@@ -46,7 +106,18 @@ pub fn reduce_paths(paths: &[PathBuf]) -> HashSet<PathBuf> {
             // Default: return a noop reduction
             PathReduction::Reduced(path.clone())
         })
-        .filter(|reduction| reduction != &PathReduction::Remove)
+        .zip(paths)
+        .filter_map(|(reduction, path)| match reduction {
+            PathReduction::Skip(reason) => {
+                skipped.push(Skipped {
+                    path: path.clone(),
+                    reason,
+                });
+                None
+            }
+            PathReduction::Remove => None,
+            reduced => Some(reduced),
+        })
         .map(|reduction| reduction.unwrap("previous filter got them"))
         .collect::<Vec<PathBuf>>();
 
@@ -54,14 +125,37 @@ pub fn reduce_paths(paths: &[PathBuf]) -> HashSet<PathBuf> {
     // possible, in the next fold.
     reduced.sort();
     reduced.dedup();
-    reduced
-        .into_iter()
-        .fold::<HashSet<PathBuf>, _>(HashSet::new(), |mut set, new_path| {
-            if !set.iter().any(|path| new_path.starts_with(path)) {
-                set.insert(new_path);
-            }
-            set
-        })
+    let paths =
+        reduced
+            .into_iter()
+            .fold::<HashSet<PathBuf>, _>(HashSet::new(), |mut set, new_path| {
+                if !set.iter().any(|path| new_path.starts_with(path)) {
+                    set.insert(new_path);
+                }
+                set
+            });
+    Reduced { paths, skipped }
+}
+
+/// Whether resolving a path failed because of a symlink cycle.
+fn is_symlink_cycle(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::ELOOP)
+}
+
+/// Skip paths which are too deep to be anything but a loop in the
+/// filesystem, or which cannot be resolved because of a symlink
+/// cycle. Watching those would at best fail, and at worst never
+/// finish.
+fn reduce_pathological_path(path: &Path) -> ReductionOp {
+    if path.components().count() > MAX_DEPTH {
+        return ReductionOp::Reduction(PathReduction::Skip(SkipReason::TooDeep));
+    }
+    match path.canonicalize() {
+        Err(ref e) if is_symlink_cycle(e) => {
+            ReductionOp::Reduction(PathReduction::Skip(SkipReason::SymlinkCycle))
+        }
+        _ => ReductionOp::NoOpinion,
+    }
 }
 
 /// Reduce a path coming from a user's channel to the location where
@@ -105,7 +199,7 @@ pub fn reduce_paths(paths: &[PathBuf]) -> HashSet<PathBuf> {
 ///    (C) it never changes.
 ///
 /// (E) Sub-path to exactly what file was looked at.
-fn reduce_channel_path(path: &PathBuf) -> ReductionOp {
+fn reduce_channel_path(path: &Path) -> ReductionOp {
     let nix_profile = Path::new("/nix/var/nix/profiles/per-user");
 
     // example path: /nix/var/nix/profiles/per-user/root/channels/nixos/....
@@ -127,8 +221,12 @@ fn reduce_channel_path(path: &PathBuf) -> ReductionOp {
     // Check to see that the channel's root canonicalizes to the same
     // root the full path resolves to. If so, simplify to
     // the directory containing the swapped channel symlink.
-    let canonical_channel_location = channel_root_path.canonicalize().unwrap();
-    let canonical_path_location = path.canonicalize().unwrap();
+    let (canonical_channel_location, canonical_path_location) =
+        match (channel_root_path.canonicalize(), path.canonicalize()) {
+            (Ok(channel), Ok(path)) => (channel, path),
+            // e.g. a channel which was removed since nix evaluated it
+            _ => return ReductionOp::NoOpinion,
+        };
     if canonical_path_location.starts_with(&canonical_channel_location) {
         let reduce_to = channel_root_path
             .parent()
@@ -147,7 +245,7 @@ fn reduce_channel_path(path: &PathBuf) -> ReductionOp {
 ///
 /// Note that because store paths are immutable, these paths can
 /// be discarded.
-fn reduce_nix_store_path(path: &PathBuf) -> ReductionOp {
+fn reduce_nix_store_path(path: &Path) -> ReductionOp {
     let nix_store = Path::new("/nix/store");
 
     // This is only a valid reduction if the Nix store path
@@ -158,13 +256,60 @@ fn reduce_nix_store_path(path: &PathBuf) -> ReductionOp {
         return ReductionOp::NoOpinion;
     }
 
-    if let Ok(path) = path.canonicalize() {
+    match path.canonicalize() {
         // Verify the path still starts with /nix/store
         // (see the prior comment block)
-        if path.starts_with(nix_store) {
-            return ReductionOp::Reduction(PathReduction::Remove);
+        Ok(ref path) if path.starts_with(nix_store) => {
+            ReductionOp::Reduction(PathReduction::Remove)
+        }
+        // With a chroot store (see `nix::Store`), store paths
+        // only exist below the chroot, so there is nothing to watch.
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            ReductionOp::Reduction(PathReduction::Remove)
         }
+        _ => ReductionOp::NoOpinion,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reduce_paths, SkipReason, Skipped, MAX_DEPTH};
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn skip_symlink_cycles() {
+        let temp = tempdir().unwrap();
+        let a = temp.path().join("a");
+        let b = temp.path().join("b");
+        symlink(&b, &a).unwrap();
+        symlink(&a, &b).unwrap();
+        let file = temp.path().join("file");
+        std::fs::write(&file, "").unwrap();
+
+        let reduced = reduce_paths(&[a.join("default.nix"), file.clone()]);
+        assert_eq!(reduced.paths.into_iter().collect::<Vec<_>>(), vec![file]);
+        assert_eq!(
+            reduced.skipped,
+            vec![Skipped {
+                path: a.join("default.nix"),
+                reason: SkipReason::SymlinkCycle,
+            }]
+        );
     }
 
-    ReductionOp::NoOpinion
+    #[test]
+    fn skip_too_deep_paths() {
+        let deep = vec![(0..=MAX_DEPTH).map(|_| "a").collect::<PathBuf>()];
+        let reduced = reduce_paths(&deep);
+        assert!(reduced.paths.is_empty());
+        assert_eq!(
+            reduced.skipped,
+            vec![Skipped {
+                path: deep[0].clone(),
+                reason: SkipReason::TooDeep,
+            }]
+        );
+    }
 }
@@ -1,54 +1,319 @@
-//! Wrap a nix file and manage corresponding state.
+//! Wrap a nix file (or another `NixSource`) and manage
+//! corresponding state.
 
+pub mod bin_dir;
+pub mod config;
+pub mod env;
+pub mod failures;
+pub mod ide_env;
 pub mod roots;
 
+use self::config::{config_root, ConfigError, ProjectConfig};
 use cas::ContentAddressable;
-use std::os::unix::ffi::OsStrExt;
+use nix::{self, Options, Store};
 use std::path::{Path, PathBuf};
-use NixFile;
+use {NixFile, NixSource};
+
+/// Name of the link to the nix file in a project’s root directory,
+/// for ops which only see the directories (see `nix_file_in`).
+const NIX_FILE_LINK: &str = "nix_file";
+
+/// Name of the file with the source of a project without a nix file
+/// (see `source_in`), in its root directory.
+const SOURCE_FILE: &str = "source.json";
 
 /// A “project” knows how to handle the lorri state
 /// for a given nix file.
 #[derive(Clone)]
 pub struct Project {
-    /// Absolute path to this project’s nix file.
-    pub nix_file: NixFile,
+    /// What this project builds; for a nix file, its absolute path.
+    pub source: NixSource,
 
     /// Directory in which this project’s
     /// garbage collection roots are stored.
     gc_root_path: PathBuf,
 
-    /// Hash of the nix file’s absolute path.
+    /// The directory of the project’s configuration (see
+    /// `config::config_root`).
+    config_root: PathBuf,
+
+    /// Hash of the source (for nix files, of the absolute path),
+    /// see `NixSource::id_bytes`.
     hash: String,
 
+    /// Name of the directory of the project’s roots
+    /// (see `GcRootsConfig::readable_names`).
+    root_name: String,
+
     /// Content-addressable store to save static files in
     pub cas: ContentAddressable,
+
+    /// The nix store this project is built into.
+    pub store: Store,
+
+    /// Nix arguments and settings from the command line, which take
+    /// precedence over the ones of the project’s configuration.
+    pub nix_args: Options,
+
+    /// The attribute path of the nix file this project builds,
+    /// instead of the one of the project’s configuration (see
+    /// `with_attribute`).
+    attribute: Option<String>,
 }
 
 impl Project {
     /// Construct a `Project` from nix file path
     /// and the base GC root directory
     /// (as returned by `Paths.gc_root_dir()`),
+    /// building into the nix store selected by `NIX_REMOTE`.
+    ///
+    /// The roots are named after the project when its configuration
+    /// asks for it (see `GcRootsConfig::readable_names`).
+    ///
+    /// A `flake.nix` is built as a flake (see
+    /// `NixSource::from_nix_file`).
     pub fn new(
         nix_file: NixFile,
         gc_root_dir: &Path,
         cas: ContentAddressable,
     ) -> std::io::Result<Project> {
-        let hash = format!("{:x}", md5::compute(nix_file.as_os_str().as_bytes()));
-        let project_gc_root = gc_root_dir.join(&hash).join("gc_root").to_path_buf();
+        Project::from_source(NixSource::from_nix_file(nix_file), gc_root_dir, cas)
+    }
+
+    /// Like `new`, for any `NixSource`.
+    pub fn from_source(
+        source: NixSource,
+        gc_root_dir: &Path,
+        cas: ContentAddressable,
+    ) -> std::io::Result<Project> {
+        Project::with_attribute(source, None, gc_root_dir, cas)
+    }
+
+    /// Like `from_source`, building the attribute path `attribute`
+    /// (see `nix::attribute_path`) of the nix file. Every attribute
+    /// is a project of its own, with its own roots.
+    pub fn with_attribute(
+        source: NixSource,
+        attribute: Option<String>,
+        gc_root_dir: &Path,
+        cas: ContentAddressable,
+    ) -> std::io::Result<Project> {
+        let project = Project::unset(source, attribute, gc_root_dir, cas);
+        project.set_up()?;
+        Ok(project)
+    }
+
+    /// Create the project’s root directory, recording its source.
+    fn set_up(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.gc_root_path)?;
+        let nix_file_link = self.gc_root_path.with_file_name(NIX_FILE_LINK);
+        if let Some(nix_file) = self.source.nix_file() {
+            if nix_file_link.symlink_metadata().is_err() {
+                match std::os::unix::fs::symlink(nix_file.as_os_str(), &nix_file_link) {
+                    // created by a concurrent lorri
+                    Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => (),
+                    result => result?,
+                }
+            }
+        } else {
+            let source_file = self.gc_root_path.with_file_name(SOURCE_FILE);
+            if !source_file.exists() {
+                let json = serde_json::to_string(&self.source).expect("sources are valid JSON");
+                std::fs::write(source_file, json)?;
+            }
+        }
+        Ok(())
+    }
 
-        std::fs::create_dir_all(&project_gc_root)?;
+    /// Like `new`, without creating the project’s root directory,
+    /// for looking at the state of a project lorri may have built
+    /// before (which must leave no traces if it didn’t).
+    pub fn lookup(nix_file: NixFile, gc_root_dir: &Path, cas: ContentAddressable) -> Project {
+        Project::unset(NixSource::from_nix_file(nix_file), None, gc_root_dir, cas)
+    }
 
-        Ok(Project {
-            nix_file,
+    /// The project, with its root directory not set up yet.
+    fn unset(
+        source: NixSource,
+        attribute: Option<String>,
+        gc_root_dir: &Path,
+        cas: ContentAddressable,
+    ) -> Project {
+        let mut id = source.id_bytes();
+        if let Some(ref attribute) = attribute {
+            id.push(0);
+            id.extend(attribute.as_bytes());
+        }
+        let hash = format!("{:x}", md5::compute(id));
+        let config_root = config_root(
+            source.dir(),
+            source.nix_file().as_ref().map(|n| Path::new(n.as_os_str())),
+        );
+        let readable_names = ProjectConfig::load(&config_root)
+            .map(|config| config.gc_roots.readable_names)
+            .unwrap_or(false);
+        let root_name = if readable_names {
+            readable_root_name(&config_root, &hash)
+        } else {
+            hash.clone()
+        };
+        let project_gc_root = gc_root_dir.join(&root_name).join("gc_root").to_path_buf();
+
+        Project {
+            source,
             gc_root_path: project_gc_root,
+            config_root,
             hash,
+            root_name,
             cas,
-        })
+            store: Store::from_env(),
+            nix_args: Options::new(),
+            attribute,
+        }
+    }
+
+    /// The directory containing the project’s nix file
+    /// (see `NixSource::dir`).
+    pub fn project_dir(&self) -> &Path {
+        self.source.dir()
+    }
+
+    /// The directory of the project’s `.lorri.toml`, which its
+    /// relative settings (and its `.gitignore`) are relative to: the
+    /// `project_dir`, or the directory above it which names the nix
+    /// file as its `shell-file`.
+    pub fn config_root(&self) -> &Path {
+        &self.config_root
+    }
+
+    /// Directory of links to the executables of the project’s
+    /// environment (see `bin_dir`).
+    pub fn bin_dir(&self) -> PathBuf {
+        self.gc_root_path.with_file_name("bin")
+    }
+
+    /// File with the hash of the environment of the last successful
+    /// build (see `env::hash`).
+    pub fn env_hash_file(&self) -> PathBuf {
+        self.gc_root_path.with_file_name("env-hash")
+    }
+
+    /// The hash of the environment of the last successful build,
+    /// if there was one.
+    pub fn env_hash(&self) -> Option<String> {
+        std::fs::read_to_string(self.env_hash_file())
+            .ok()
+            .map(|hash| hash.trim().to_string())
+    }
+
+    /// Directory of snapshots of failed builds (see `failures`).
+    pub fn failures_dir(&self) -> PathBuf {
+        self.gc_root_path.with_file_name("failures")
+    }
+
+    /// Directories lorri itself reads and writes for this project,
+    /// whose files are no inputs of its builds.
+    pub fn state_dirs(&self) -> Vec<PathBuf> {
+        let gc_root_dir = self.gc_root_path.parent().unwrap_or(&self.gc_root_path);
+        vec![self.cas.dir().to_owned(), gc_root_dir.to_owned()]
+    }
+
+    /// Read the project’s configuration (see `config`).
+    pub fn config(&self) -> Result<ProjectConfig, ConfigError> {
+        ProjectConfig::load(self.config_root())
+    }
+
+    /// The options for builds of this project with `config` (see
+    /// `ProjectConfig::build_options`) and `nix_args`,
+    /// fetching its pinned nixpkgs (if any) into the store.
+    pub fn nix_options(&self, config: &ProjectConfig) -> Result<Options, String> {
+        let mut options = config.build_options()?;
+        if let Some(ref pin) = config.nixpkgs {
+            let path = nix::fetch_tarball(&self.store, &pin.url, &pin.sha256)
+                .map_err(|e| format!("could not fetch the pinned nixpkgs {}: {}", pin.url, e))?;
+            options.include("nixpkgs", path.as_path());
+        }
+        options.extend(&self.nix_args);
+        if let Some(ref attribute) = self.attribute {
+            options.attribute(&nix::attribute_path(attribute)?);
+        }
+        Ok(options)
+    }
+
+    /// The attribute path this project builds, if it was created
+    /// `with_attribute`.
+    pub fn attribute(&self) -> Option<&str> {
+        self.attribute.as_ref().map(String::as_str)
     }
 
     /// Generate a "unique" ID for this project based on its absolute path.
     pub fn hash(&self) -> &str {
         &self.hash
     }
+
+    /// The name of the project’s GC root directory, also used to
+    /// name its roots in the nix store’s `gcroots`. This is the
+    /// `hash`, optionally prefixed with the project name.
+    pub fn root_name(&self) -> &str {
+        &self.root_name
+    }
+}
+
+/// The nix file of the project whose roots are in `root_dir` (a
+/// directory in `Paths.gc_root_dir()`), if lorri recorded it.
+pub fn nix_file_in(root_dir: &Path) -> Option<NixFile> {
+    std::fs::read_link(root_dir.join(NIX_FILE_LINK))
+        .ok()
+        .map(NixFile::from)
+}
+
+/// The source of the project whose roots are in `root_dir` (see
+/// `nix_file_in`), if lorri recorded it. Projects without a nix file
+/// (expressions and remote flakes) have it recorded, too.
+pub fn source_in(root_dir: &Path) -> Option<NixSource> {
+    match nix_file_in(root_dir) {
+        Some(nix_file) => Some(NixSource::from_nix_file(nix_file)),
+        None => std::fs::read_to_string(root_dir.join(SOURCE_FILE))
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok()),
+    }
+}
+
+/// `<project>-<hash>`, where `<project>` is the name of
+/// `project_dir` restricted to characters which are safe in file
+/// names and nix’s root listings.
+fn readable_root_name(project_dir: &Path, hash: &str) -> String {
+    let name: String = project_dir
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => hash.to_string(),
+        name => format!("{}-{}", name, hash),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::readable_root_name;
+    use std::path::Path;
+
+    #[test]
+    fn readable_root_names() {
+        assert_eq!(
+            readable_root_name(Path::new("/home/user/my project"), "1a2b3c"),
+            "my_project-1a2b3c"
+        );
+        assert_eq!(
+            readable_root_name(Path::new("/home/user/.dotfiles"), "1a2b3c"),
+            "dotfiles-1a2b3c"
+        );
+        assert_eq!(readable_root_name(Path::new("/"), "1a2b3c"), "1a2b3c");
+    }
 }
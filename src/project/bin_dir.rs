@@ -0,0 +1,198 @@
+//! A per-project directory of symlinks to the executables of the
+//! built environment.
+//!
+//! Tools which can’t use direnv (like IDEs launched from the desktop)
+//! only need to prepend this one directory to their `PATH`.
+//! It is regenerated after every successful build, and its
+//! path stays the same: it is a symlink to a directory of links,
+//! switched to a new one at once.
+
+use crate::builder::OutputPaths;
+use crate::project::env;
+use crate::project::roots::RootPath;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Replace the contents of `bin_dir` with links to the executables
+/// on the `PATH` of the environment in `root_paths` which are in
+/// `store_dir` (see `Store::store_dir`).
+pub fn update(
+    bin_dir: &Path,
+    store_dir: &Path,
+    root_paths: &OutputPaths<RootPath>,
+) -> io::Result<()> {
+    let env = env::read(&root_paths.bash_export())?;
+    let path = env.get("PATH").map(|p| p.as_str()).unwrap_or("");
+    let parent = bin_dir.parent().unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(parent)?;
+
+    // removed again unless the link points to it
+    let new_dir = tempfile::Builder::new()
+        .prefix(".bin-")
+        .tempdir_in(parent)?;
+    fs::set_permissions(new_dir.path(), fs::Permissions::from_mode(0o755))?;
+    link_executables(new_dir.path(), &store_dirs(path, store_dir))?;
+    switch(bin_dir, new_dir.path())?;
+    new_dir.into_path();
+    Ok(())
+}
+
+/// Point the symlink `bin_dir` to `new_dir` (in the same directory)
+/// by renaming a new link over it, so that it never is missing or
+/// half full, and remove the directory it pointed to before.
+fn switch(bin_dir: &Path, new_dir: &Path) -> io::Result<()> {
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(bin_dir.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp = bin_dir.with_file_name(tmp_name);
+    if let Err(e) = fs::remove_file(&tmp) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    symlink(new_dir.file_name().unwrap_or_default(), &tmp)?;
+
+    let old_dir = match fs::symlink_metadata(bin_dir) {
+        Ok(ref metadata) if metadata.file_type().is_symlink() => fs::read_link(bin_dir)
+            .ok()
+            .map(|old| bin_dir.with_file_name(old)),
+        // a directory of earlier versions can’t be renamed over
+        Ok(ref metadata) if metadata.is_dir() => {
+            fs::remove_dir_all(bin_dir)?;
+            None
+        }
+        _ => None,
+    };
+    fs::rename(&tmp, bin_dir)?;
+    if let Some(old_dir) = old_dir {
+        if old_dir != new_dir {
+            if let Err(e) = fs::remove_dir_all(&old_dir) {
+                debug!("could not remove {}: {}", old_dir.display(), e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The entries of `path` which are in `store_dir`; the others
+/// are left over from the build sandbox.
+fn store_dirs(path: &str, store_dir: &Path) -> Vec<PathBuf> {
+    path.split(':')
+        .map(PathBuf::from)
+        .filter(|dir| dir.starts_with(store_dir) && dir != store_dir)
+        .collect()
+}
+
+/// Link the executables in `dirs` into `bin_dir`. Like with `PATH`,
+/// the first directory containing an executable wins.
+fn link_executables(bin_dir: &Path, dirs: &[PathBuf]) -> io::Result<()> {
+    let mut seen = HashSet::new();
+    for dir in dirs {
+        let entries = match dir.read_dir() {
+            Ok(entries) => entries,
+            // like shells, ignore missing PATH entries
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let entry = entry?.path();
+            let is_executable = entry
+                .metadata()
+                .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false);
+            if let Some(name) = entry.file_name() {
+                if is_executable && seen.insert(name.to_owned()) {
+                    symlink(&entry, bin_dir.join(name))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{link_executables, store_dirs, switch};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+    use tempfile::tempdir;
+
+    fn executable(path: &Path) {
+        fs::write(path, "#!/bin/sh\n").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn only_store_dirs() {
+        assert_eq!(
+            store_dirs(
+                "/nix/store/abc-hello/bin:/path-not-set:/usr/bin:/nix/store",
+                Path::new("/nix/store")
+            ),
+            vec![PathBuf::from("/nix/store/abc-hello/bin")]
+        );
+        assert_eq!(
+            store_dirs(
+                "/nix/store/abc-hello/bin:/opt/nix/store/def-git/bin",
+                Path::new("/opt/nix/store")
+            ),
+            vec![PathBuf::from("/opt/nix/store/def-git/bin")]
+        );
+    }
+
+    #[test]
+    fn switch_directories() {
+        let tmp = tempdir().unwrap();
+        let bin = tmp.path().join("bin");
+        // the directory of earlier versions
+        fs::create_dir(&bin).unwrap();
+        fs::write(bin.join("old"), "").unwrap();
+
+        let first = tmp.path().join(".bin-first");
+        fs::create_dir(&first).unwrap();
+        switch(&bin, &first).unwrap();
+        assert_eq!(fs::read_link(&bin).unwrap(), Path::new(".bin-first"));
+        assert!(!bin.join("old").exists());
+
+        let second = tmp.path().join(".bin-second");
+        fs::create_dir(&second).unwrap();
+        fs::write(second.join("new"), "").unwrap();
+        switch(&bin, &second).unwrap();
+        assert!(bin.join("new").exists());
+        assert!(!first.exists());
+        assert!(!tmp.path().join(".bin.tmp").exists());
+    }
+
+    #[test]
+    fn first_executable_wins() {
+        let tmp = tempdir().unwrap();
+        let (first, second, bin) = (
+            tmp.path().join("first"),
+            tmp.path().join("second"),
+            tmp.path().join("bin"),
+        );
+        for dir in &[&first, &second, &bin] {
+            fs::create_dir(dir).unwrap();
+        }
+        executable(&first.join("hello"));
+        executable(&second.join("hello"));
+        executable(&second.join("git"));
+        fs::write(second.join("README"), "not executable").unwrap();
+
+        link_executables(
+            &bin,
+            &[first.clone(), tmp.path().join("missing"), second.clone()],
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_link(bin.join("hello")).unwrap(),
+            first.join("hello")
+        );
+        assert_eq!(fs::read_link(bin.join("git")).unwrap(), second.join("git"));
+        assert!(!bin.join("README").exists());
+    }
+}
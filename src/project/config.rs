@@ -0,0 +1,1286 @@
+//! Per-project configuration, read from a `.lorri.toml` next to
+//! the project’s nix file, or in a directory above it whose
+//! `shell-file` is the nix file (see `config_root`).
+//!
+//! ```toml
+//! # the nix file of the project, instead of `shell.nix`, for
+//! # commands run in the project directory without `--shell-file`
+//! shell-file = "nix/dev.nix"
+//! # build this attribute of the nix file, instead of all of it
+//! # (quote names with dots: `devShells."python3.11"`)
+//! attribute = "devShells.backend"
+//!
+//! [watch]
+//! # only watch files below the project directory and `extra-roots`
+//! scope = "project"
+//! extra-roots = ["../nix"]
+//! # don’t rebuild when these change (see `glob`), in addition to
+//! # the paths ignored by the project’s `.gitignore` and to
+//! # `.git`, `target/`, `node_modules/` and `result*`
+//! ignore = ["*.log", "docs/"]
+//! # don’t read the `.gitignore`
+//! gitignore = false
+//! # treat changes as one batch until none arrive for this long
+//! # (instead of the daemon’s `--debounce-ms`)
+//! debounce-ms = 200
+//!
+//! [watch.macos]
+//! # wait this long after a change for more changes (FSEvents only)
+//! latency-ms = 100
+//! # watch directories instead of single files (the default)
+//! directory-granularity = true
+//!
+//! [watch]
+//! # warn about files nix reads but lorri doesn’t watch (needs strace)
+//! strict = true
+//! # warn about inputs with mtimes this far in the future
+//! max-clock-skew-secs = 5
+//!
+//! [nix]
+//! # binary caches used for this project only
+//! substituters = ["https://example.cachix.org"]
+//! trusted-public-keys = ["example.cachix.org-1:AAAA…="]
+//! # other nix settings, passed with `--option` (only the ones in
+//! # `NIX_OPTIONS`, since the file is usually checked in)
+//! options = { max-jobs = "4", keep-going = "true" }
+//! # arguments of the function in the nix file, passed with
+//! # `--arg` (nix expressions) and `--argstr` (strings)
+//! args = { withDocs = "true" }
+//! argstrs = { python = "python311" }
+//! # print the stack trace of evaluation errors
+//! show-trace = true
+//! # fail evaluations which need to build something (import from
+//! # derivation), to keep them fast
+//! allow-ifd = false
+//!
+//! [nixpkgs]
+//! # `<nixpkgs>` in the project’s nix files
+//! url = "https://github.com/NixOS/nixpkgs/archive/<rev>.tar.gz"
+//! sha256 = "0000000000000000000000000000000000000000000000000000"
+//!
+//! [cachix]
+//! # defaults to the contents of a `.cachix` file
+//! name = "example"
+//! public-keys = ["example.cachix.org-1:AAAA…="]
+//! # push build results after successful builds
+//! push = true
+//! auth-token-env = "EXAMPLE_CACHIX_TOKEN"
+//!
+//! [push]
+//! # run after every successful build, with the store paths of the
+//! # environment appended, to push them to another binary cache
+//! command = ["nix", "copy", "--to", "s3://example-cache"]
+//!
+//! [hooks]
+//! # shell commands run when a build starts, completes or fails,
+//! # instead of the ones in lorri’s global configuration (see `hooks`)
+//! failure = 'notify-send -u critical lorri "$LORRI_PROJECT failed"'
+//!
+//! [shell-hook]
+//! # don’t run the shellHook when building the environment in the
+//! # daemon ("run", "skip", or "replace" with `replacement`)
+//! mode = "replace"
+//! replacement = "export DATABASE_URL=postgres://localhost/dev"
+//! # run it with a throwaway $HOME, and proxy variables which lead
+//! # nowhere (this doesn’t cut the network off)
+//! sandbox = true
+//!
+//! # several shells for the project: the nix file evaluates to an
+//! # attribute set, each shell is one of its attributes; the first
+//! # one is the default shell
+//! [[shell]]
+//! name = "dev"
+//! [[shell]]
+//! name = "docs"
+//! attribute = "shells.docs"
+//!
+//! [gc-roots]
+//! # name the root directory after the project (`myproject-<hash>`)
+//! readable-names = true
+//!
+//! [ide-env]
+//! # write the environment for these IDEs after every build
+//! formats = ["vscode"]
+//!
+//! [log]
+//! # append the nix output of builds to this file;
+//! # `{project}` and `{date}` (UTC) are replaced
+//! nix-output = "/var/log/lorri/{project}-{date}.log"
+//! # keep the first 50 and the last 200 lines of a failed build’s
+//! # log in its failure event (these are the defaults)
+//! failure-head-lines = 50
+//! failure-tail-lines = 200
+//!
+//! # mirror build events as JSON lines, see `event_sink`
+//! [[event-sink]]
+//! file = ".lorri/events.jsonl"
+//! [[event-sink]]
+//! command = ["notify-send", "lorri"]
+//! events = ["completed", "failure"]
+//! [[event-sink]]
+//! socket = "/run/user/1000/my-integration.sock"
+//! ```
+//!
+//! A missing file is the same as an empty one.
+
+use config_error::InvalidSetting;
+use event_sink::EVENT_NAMES;
+use glob::Rules;
+use nix::{self, Options};
+use project::ide_env::IdeFormat;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use toml;
+use watch::DEFAULT_IGNORES;
+
+/// Name of the configuration file in the project directory.
+pub const CONFIG_FILE_NAME: &str = ".lorri.toml";
+
+/// The nix settings a project may set in `[nix] options`: the ones
+/// which change how (fast) it is built, but not what the evaluation
+/// or builds may access.
+pub const NIX_OPTIONS: &[&str] = &[
+    "builders-use-substitutes",
+    "connect-timeout",
+    "cores",
+    "download-attempts",
+    "eval-cache",
+    "fallback",
+    "http-connections",
+    "keep-derivations",
+    "keep-failed",
+    "keep-going",
+    "keep-outputs",
+    "max-jobs",
+    "max-silent-time",
+    "narinfo-cache-negative-ttl",
+    "pure-eval",
+    "restrict-eval",
+    "stalled-download-timeout",
+    "substitute",
+    "tarball-ttl",
+    "timeout",
+    "warn-dirty",
+];
+
+/// The configuration of a project.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// The nix file of the project, relative to the project
+    /// directory, for commands which don’t get one (instead of
+    /// `shell.nix`).
+    #[serde(rename = "shell-file")]
+    pub shell_file: Option<PathBuf>,
+    /// The attribute path of the nix file to build, instead of all
+    /// of it (see `nix::attribute_path`).
+    pub attribute: Option<String>,
+    /// Which files are watched for changes.
+    pub watch: WatchConfig,
+    /// Settings for the nix builds of this project.
+    pub nix: NixConfig,
+    /// The pinned nixpkgs of this project.
+    pub nixpkgs: Option<NixpkgsPin>,
+    /// The cachix cache of this project.
+    pub cachix: CachixConfig,
+    /// How successful builds are pushed to other binary caches.
+    pub push: PushConfig,
+    /// Commands run when builds start, complete or fail.
+    pub hooks: HooksConfig,
+    /// IDE configuration refreshed after every build.
+    #[serde(rename = "ide-env")]
+    pub ide_env: IdeEnvConfig,
+    /// Where the output of this project’s builds goes.
+    pub log: LogConfig,
+    /// What happens to the `shellHook` when building the environment.
+    #[serde(rename = "shell-hook")]
+    pub shell_hook: ShellHookConfig,
+    /// Where the events of this project’s builds are mirrored to.
+    #[serde(rename = "event-sink")]
+    pub event_sinks: Vec<EventSinkConfig>,
+    /// How the project’s garbage collection roots are stored.
+    #[serde(rename = "gc-roots")]
+    pub gc_roots: GcRootsConfig,
+    /// The named shells of the project, if it has several.
+    #[serde(rename = "shell")]
+    pub shells: Vec<ShellConfig>,
+}
+
+/// One of several shells of a project. All shells are built by one
+/// evaluation of the project’s nix file, which has to evaluate to an
+/// attribute set; each shell gets its own GC root.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShellConfig {
+    /// The name of the shell, like `dev`
+    /// (letters, digits, `-` and `_`).
+    pub name: String,
+    /// The attribute path of the shell in the evaluated attribute
+    /// set, like `shells.dev` (see `nix::attribute_path`); the name
+    /// if unset.
+    pub attribute: Option<String>,
+}
+
+impl ShellConfig {
+    /// The attribute path of the shell.
+    pub fn attribute(&self) -> &str {
+        self.attribute.as_ref().unwrap_or(&self.name)
+    }
+
+    /// Pass the named `shells` to `logged-evaluation.nix`.
+    /// Their names end up in file names, and have to be unique.
+    fn apply(shells: &[ShellConfig], options: &mut Options) -> Result<(), String> {
+        if shells.is_empty() {
+            return Ok(());
+        }
+        let mut names: Vec<&str> = vec![];
+        for shell in shells {
+            let valid = !shell.name.is_empty()
+                && shell
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(format!("invalid shell name `{}`", shell.name));
+            }
+            if names.contains(&shell.name.as_str()) {
+                return Err(format!("the shell `{}` is defined twice", shell.name));
+            }
+            names.push(&shell.name);
+        }
+        let shells = shells
+            .iter()
+            .map(|shell| Ok((shell.name.clone(), nix::attribute_path(shell.attribute())?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        options.shells(&shells);
+        Ok(())
+    }
+}
+
+/// How the garbage collection roots of the project are stored.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GcRootsConfig {
+    /// Prefix the name of the project’s root directory (and of
+    /// the roots registered with nix) with the name of the project
+    /// directory, instead of using only a hash of the nix file path.
+    pub readable_names: bool,
+}
+
+/// What happens to the `shellHook` of the project’s shell when
+/// lorri builds its environment. Some hooks start servers or change
+/// global state, which shouldn’t happen in the daemon. The hook is
+/// kept in the environment (as `$shellHook`) in any case.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShellHookConfig {
+    /// Whether to run, skip or replace the hook.
+    pub mode: ShellHookMode,
+    /// The bash code run instead of the hook with `mode = "replace"`.
+    pub replacement: Option<String>,
+    /// Run the hook (or its replacement) with a throwaway `$HOME`
+    /// and XDG directories, and with proxies which lead nowhere, so
+    /// that it can’t write to the dotfiles of the user the daemon
+    /// runs as. The proxies only stop tools which honour them, the
+    /// network isn’t cut off; Nix’s build sandbox does that, if it
+    /// is enabled.
+    pub sandbox: bool,
+}
+
+/// See `ShellHookConfig`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ShellHookMode {
+    /// Run the `shellHook`, like `nix-shell` does.
+    Run,
+    /// Don’t run it.
+    Skip,
+    /// Run the `replacement` instead.
+    Replace,
+}
+
+impl Default for ShellHookMode {
+    fn default() -> ShellHookMode {
+        ShellHookMode::Run
+    }
+}
+
+impl ShellHookConfig {
+    /// Pass the configuration to `logged-evaluation.nix`.
+    fn apply(&self, options: &mut Options) -> Result<(), String> {
+        match (self.mode, &self.replacement) {
+            (ShellHookMode::Run, _) => {}
+            (ShellHookMode::Skip, _) => {
+                options.argstr("shellHookMode", "skip");
+            }
+            (ShellHookMode::Replace, Some(replacement)) => {
+                options
+                    .argstr("shellHookMode", "replace")
+                    .argstr("shellHookReplacement", replacement);
+            }
+            (ShellHookMode::Replace, None) => {
+                return Err(String::from(
+                    "`shell-hook.mode = \"replace\"` needs a `replacement`",
+                ))
+            }
+        }
+        if self.sandbox {
+            options.argstr("shellHookSandbox", "true");
+        }
+        Ok(())
+    }
+}
+
+/// A sink the build events of the project are mirrored to, as
+/// JSON lines (see `event_sink`). Exactly one of `file`, `command`
+/// and `socket` has to be set.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventSinkConfig {
+    /// Append the events to this file.
+    /// Relative paths are relative to the project directory.
+    pub file: Option<PathBuf>,
+    /// Run this command (with arguments) for every event,
+    /// with the event on stdin.
+    pub command: Option<Vec<String>>,
+    /// Send the events to the Unix socket at this path.
+    /// Relative paths are relative to the project directory.
+    pub socket: Option<PathBuf>,
+    /// Only mirror these events (see `event_sink::EVENT_NAMES`);
+    /// all but `log-line` and `progress` if empty.
+    #[serde(deserialize_with = "known_event_names")]
+    pub events: Vec<String>,
+}
+
+/// Logging of the project’s builds, independent of the
+/// logging of lorri itself.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LogConfig {
+    /// Template of the file the nix output of every build is
+    /// appended to. `{project}` is replaced by the name of the
+    /// project directory, `{date}` by the current date (UTC).
+    /// Relative paths are relative to the project directory.
+    pub nix_output: Option<String>,
+    /// Lines from the start of a failed build’s log kept in its
+    /// failure event (see `cap_failure_log`).
+    pub failure_head_lines: usize,
+    /// Lines from the end of a failed build’s log kept in its
+    /// failure event.
+    pub failure_tail_lines: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            nix_output: None,
+            failure_head_lines: 50,
+            failure_tail_lines: 200,
+        }
+    }
+}
+
+impl LogConfig {
+    /// The first `failure_head_lines` and the last
+    /// `failure_tail_lines` of the `log_lines` of a failed build,
+    /// with a line noting how many lines were elided between them.
+    /// The failure snapshot (see `project::failures`) keeps the
+    /// full log.
+    pub fn cap_failure_log(&self, mut log_lines: Vec<OsString>) -> Vec<OsString> {
+        let kept = self.failure_head_lines + self.failure_tail_lines;
+        if log_lines.len() <= kept {
+            return log_lines;
+        }
+        let elided = log_lines.len() - kept;
+        let tail_start = log_lines.len() - self.failure_tail_lines;
+        let tail = log_lines.split_off(tail_start);
+        log_lines.truncate(self.failure_head_lines);
+        log_lines.push(format!("lorri: … {} lines elided …", elided).into());
+        log_lines.extend(tail);
+        log_lines
+    }
+
+    /// The file to log the nix output of a build at `now` to, if any.
+    pub fn nix_output_path(&self, project_dir: &Path, now: SystemTime) -> Option<PathBuf> {
+        let template = self.nix_output.as_ref()?;
+        let project = project_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let path = template
+            .replace("{project}", &project)
+            .replace("{date}", &utc_date(now));
+        Some(project_dir.join(path))
+    }
+}
+
+/// Format `time` as `YYYY-MM-DD` in UTC.
+pub fn utc_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400)
+        .unwrap_or(0) as i64;
+    // civil date from days since 1970-01-01, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Format `time` as `YYYY-MM-DD HH:MM:SS` in UTC.
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        % 86_400;
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        utc_date(time),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Format `time` as an RFC 3339 timestamp in UTC, with milliseconds,
+/// like `2019-12-31T23:59:59.250Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_millis())
+        .unwrap_or(0);
+    format!(
+        "{}.{:03}Z",
+        utc_timestamp(time).replacen(' ', "T", 1),
+        millis
+    )
+}
+
+/// IDE configuration refreshed after every build
+/// (see `project::ide_env`).
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdeEnvConfig {
+    /// The formats to write.
+    pub formats: Vec<IdeFormat>,
+}
+
+/// Configuration of the file watcher.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct WatchConfig {
+    /// Which inputs are watched with filesystem notifications.
+    pub scope: WatchScope,
+    /// Directories outside the project which are watched
+    /// with `scope = "project"`. Relative paths are relative
+    /// to the project directory.
+    pub extra_roots: Vec<PathBuf>,
+    /// Settings which only apply on macOS.
+    pub macos: MacosWatchConfig,
+    /// Trace the files nix reads with `strace`, and warn about the
+    /// ones lorri doesn’t watch (see `read_trace`). Slow, so only
+    /// meant for debugging missed rebuilds.
+    pub strict: bool,
+    /// Send a `ClockSkew` event (see `build_loop::Event`) when an
+    /// input’s mtime is more than this many seconds in the future.
+    /// Inputs with future mtimes are tracked by content hash either
+    /// way (see `skew`).
+    pub max_clock_skew_secs: u64,
+    /// Glob patterns (see `glob`) of paths below the project
+    /// directory whose changes don’t cause rebuilds. `!` re-includes
+    /// paths ignored by the `.gitignore` or `watch::DEFAULT_IGNORES`.
+    pub ignore: Vec<String>,
+    /// Ignore the contents of directories matched by the
+    /// `.gitignore` in the project directory (files nix reads are
+    /// still watched).
+    pub gitignore: bool,
+    /// Treat changes as one batch until none arrive for this many
+    /// milliseconds, instead of the daemon’s `--debounce-ms`.
+    pub debounce_ms: Option<u64>,
+}
+
+impl Default for WatchConfig {
+    fn default() -> WatchConfig {
+        WatchConfig {
+            scope: WatchScope::default(),
+            extra_roots: vec![],
+            macos: MacosWatchConfig::default(),
+            strict: false,
+            max_clock_skew_secs: 5,
+            ignore: vec![],
+            gitignore: true,
+            debounce_ms: None,
+        }
+    }
+}
+
+/// Tuning of the FSEvents based watcher on macOS.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MacosWatchConfig {
+    /// Milliseconds to wait after a change for more changes, which
+    /// are then coalesced into a single rebuild.
+    pub latency_ms: u64,
+    /// Only register directories with FSEvents (every registration
+    /// restarts the event stream, which is slow for many files).
+    pub directory_granularity: bool,
+}
+
+impl Default for MacosWatchConfig {
+    fn default() -> MacosWatchConfig {
+        MacosWatchConfig {
+            latency_ms: 0,
+            directory_granularity: true,
+        }
+    }
+}
+
+/// Nix settings applied to the builds of one project,
+/// without touching the global `nix.conf`.
+///
+/// Note that nix only uses additional substituters if the user is
+/// trusted, or if they are listed in `trusted-substituters`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct NixConfig {
+    /// Binary caches to use in addition to the configured ones.
+    pub substituters: Vec<String>,
+    /// Public keys to trust in addition to the configured ones.
+    pub trusted_public_keys: Vec<String>,
+    /// Other nix settings, by name (see `NIX_OPTIONS`).
+    #[serde(deserialize_with = "allowed_nix_options")]
+    pub options: BTreeMap<String, String>,
+    /// Arguments of the function in the nix file, as nix expressions.
+    pub args: BTreeMap<String, String>,
+    /// Arguments of the function in the nix file, as strings.
+    pub argstrs: BTreeMap<String, String>,
+    /// Print the stack trace of evaluation errors.
+    pub show_trace: bool,
+    /// Whether evaluation may build derivations to import their
+    /// outputs (nix’s `allow-import-from-derivation`); nix’s
+    /// setting if unset.
+    pub allow_ifd: Option<bool>,
+}
+
+impl NixConfig {
+    /// The nix `--option`s and arguments for these settings.
+    pub fn options(&self) -> Options {
+        let mut options = Options::new();
+        if !self.substituters.is_empty() {
+            options.set("extra-substituters", &self.substituters.join(" "));
+        }
+        if !self.trusted_public_keys.is_empty() {
+            options.set(
+                "extra-trusted-public-keys",
+                &self.trusted_public_keys.join(" "),
+            );
+        }
+        if let Some(allow_ifd) = self.allow_ifd {
+            options.set("allow-import-from-derivation", &allow_ifd.to_string());
+        }
+        for (name, value) in &self.options {
+            options.set(name, value);
+        }
+        for (name, expr) in &self.args {
+            options.shell_arg(name, expr);
+        }
+        for (name, value) in &self.argstrs {
+            options.shell_argstr(name, value);
+        }
+        if self.show_trace {
+            options.show_trace();
+        }
+        options
+    }
+}
+
+/// Reject the `[nix] options` not in `NIX_OPTIONS`.
+fn allowed_nix_options<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    use serde::Deserialize;
+
+    let options = BTreeMap::<String, String>::deserialize(deserializer)?;
+    let unknown = options
+        .keys()
+        .find(|name| !NIX_OPTIONS.contains(&name.as_str()))
+        .cloned();
+    match unknown {
+        Some(name) => Err(D::Error::unknown_field(&name, NIX_OPTIONS)),
+        None => Ok(options),
+    }
+}
+
+/// Reject the event names not in `event_sink::EVENT_NAMES`.
+fn known_event_names<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+    use serde::Deserialize;
+
+    let names = Vec::<String>::deserialize(deserializer)?;
+    let unknown = names
+        .iter()
+        .find(|name| !EVENT_NAMES.contains(&name.as_str()))
+        .cloned();
+    match unknown {
+        Some(name) => Err(D::Error::unknown_variant(&name, EVENT_NAMES)),
+        None => Ok(names),
+    }
+}
+
+/// A nixpkgs tarball, which `<nixpkgs>` refers to in the builds
+/// of the project (instead of the one from `NIX_PATH`).
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NixpkgsPin {
+    /// URL of the tarball, e.g. a GitHub archive of a nixpkgs commit.
+    pub url: String,
+    /// Hash of the unpacked tarball, as printed by
+    /// `nix-prefetch-url --unpack <url>`.
+    pub sha256: String,
+}
+
+/// A cachix binary cache, used for builds and optionally
+/// pushed to after successful builds.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CachixConfig {
+    /// Name of the cache, served at `https://<name>.cachix.org`.
+    pub name: Option<String>,
+    /// Signing keys of the cache, as shown on its cachix page.
+    pub public_keys: Vec<String>,
+    /// Push the closure of the environment after successful builds.
+    pub push: bool,
+    /// Environment variable holding the auth token for pushing.
+    /// If unset, `cachix` uses `CACHIX_AUTH_TOKEN` or its own
+    /// configuration.
+    pub auth_token_env: Option<String>,
+}
+
+/// A command pushing the environment of successful builds to a
+/// binary cache (see `push`).
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PushConfig {
+    /// Run this command (with arguments) after every successful
+    /// build, with the store paths of the environment appended.
+    pub command: Option<Vec<String>>,
+}
+
+/// Shell commands run on build events (see `hooks`); empty ones
+/// are not run. Also a section of lorri’s global configuration,
+/// which the project’s hooks take precedence over, one by one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Run when a build starts.
+    pub started: String,
+    /// Run when a build completes successfully.
+    pub completed: String,
+    /// Run when a build fails.
+    pub failure: String,
+}
+
+/// File naming the project’s cachix cache, if not configured
+/// in `CONFIG_FILE_NAME`.
+pub const CACHIX_FILE_NAME: &str = ".cachix";
+
+impl CachixConfig {
+    /// The URL of the cache, if one is configured.
+    pub fn substituter(&self) -> Option<String> {
+        self.name
+            .as_ref()
+            .map(|name| format!("https://{}.cachix.org", name))
+    }
+}
+
+/// Which inputs of the evaluation are watched with filesystem
+/// notifications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatchScope {
+    /// Every input file (the default).
+    Everything,
+    /// Only inputs below the project directory and the configured
+    /// extra roots. All other inputs are checked by content hash
+    /// from time to time instead, which keeps the number of watches
+    /// low for projects importing large out-of-tree sources.
+    Project,
+}
+
+impl Default for WatchScope {
+    fn default() -> WatchScope {
+        WatchScope::Everything
+    }
+}
+
+/// Reading the configuration file failed.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read.
+    Io(io::Error),
+    /// The file is not valid TOML, or has unknown or invalid settings.
+    Parse(InvalidSetting),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "cannot read {}: {}", CONFIG_FILE_NAME, e),
+            ConfigError::Parse(invalid) => write!(f, "{}", invalid),
+        }
+    }
+}
+
+impl ProjectConfig {
+    /// Read the configuration from `project_dir`.
+    pub fn load(project_dir: &Path) -> Result<ProjectConfig, ConfigError> {
+        let file = project_dir.join(CONFIG_FILE_NAME);
+        let mut config: ProjectConfig = match read_optional(&file)? {
+            None => ProjectConfig::default(),
+            Some(contents) => toml::from_str(&contents)
+                .map_err(|e| ConfigError::Parse(InvalidSetting::new(&file, &contents, &e)))?,
+        };
+        if config.cachix.name.is_none() {
+            config.cachix.name = read_optional(&project_dir.join(CACHIX_FILE_NAME))?
+                .and_then(|contents| contents.split_whitespace().next().map(String::from));
+        }
+        Ok(config)
+    }
+
+    /// The nix `--option`s for builds of this project.
+    pub fn nix_options(&self) -> Options {
+        let mut nix = self.nix.clone();
+        if let Some(substituter) = self.cachix.substituter() {
+            nix.substituters.push(substituter);
+            nix.trusted_public_keys
+                .extend(self.cachix.public_keys.iter().cloned());
+        }
+        nix.options()
+    }
+
+    /// The options for builds of this project: `nix_options`, and
+    /// the arguments for lorri’s instrumentation.
+    pub fn build_options(&self) -> Result<Options, String> {
+        let mut options = self.nix_options();
+        if let Some(ref attribute) = self.attribute {
+            options.attribute(&nix::attribute_path(attribute)?);
+        }
+        self.shell_hook.apply(&mut options)?;
+        ShellConfig::apply(&self.shells, &mut options)?;
+        Ok(options)
+    }
+}
+
+/// The directory of the configuration of the project in `dir` (the
+/// directory of its nix file, see `NixSource::dir`): `dir` itself,
+/// unless it has no configuration file and the closest one above it
+/// names `nix_file` as its `shell-file`. Then that is the project’s
+/// root, as for commands run there, which build the `shell-file`.
+pub fn config_root(dir: &Path, nix_file: Option<&Path>) -> PathBuf {
+    /// Just the `shell-file`, so that other (even invalid) settings
+    /// don’t matter here.
+    #[derive(Deserialize)]
+    struct ShellFile {
+        #[serde(rename = "shell-file")]
+        shell_file: Option<PathBuf>,
+    }
+
+    let nix_file = match nix_file {
+        Some(nix_file) if !dir.join(CONFIG_FILE_NAME).exists() => normalize(nix_file),
+        _ => return dir.to_owned(),
+    };
+    for ancestor in dir.ancestors().skip(1) {
+        let contents = match read_optional(&ancestor.join(CONFIG_FILE_NAME)) {
+            Ok(None) => continue,
+            Ok(Some(contents)) => contents,
+            Err(_) => break,
+        };
+        let shell_file = toml::from_str::<ShellFile>(&contents)
+            .ok()
+            .and_then(|config| config.shell_file);
+        let builds_nix_file = shell_file.map_or(false, |shell_file| {
+            normalize(&ancestor.join(shell_file)) == nix_file
+        });
+        if builds_nix_file {
+            return ancestor.to_owned();
+        }
+        break;
+    }
+    dir.to_owned()
+}
+
+/// Read a file which might not exist.
+fn read_optional(path: &Path) -> Result<Option<String>, io::Error> {
+    match std::fs::read_to_string(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+        Ok(contents) => Ok(Some(contents)),
+    }
+}
+
+impl WatchConfig {
+    /// Split `paths` into the ones to watch with filesystem
+    /// notifications and the ones to track by content hash.
+    pub fn partition<I>(&self, project_dir: &Path, paths: I) -> (Vec<PathBuf>, Vec<PathBuf>)
+    where
+        I: IntoIterator<Item = PathBuf>,
+    {
+        match self.scope {
+            WatchScope::Everything => (paths.into_iter().collect(), vec![]),
+            WatchScope::Project => {
+                let roots: Vec<PathBuf> = std::iter::once(project_dir.to_path_buf())
+                    .chain(self.extra_roots.iter().map(|root| project_dir.join(root)))
+                    .map(|root| root.canonicalize().unwrap_or_else(|_| normalize(&root)))
+                    .collect();
+                paths
+                    .into_iter()
+                    .partition(|path| roots.iter().any(|root| path.starts_with(root)))
+            }
+        }
+    }
+
+    /// The ignore rules of the project in `project_dir`, see
+    /// `Watch::set_ignore`: `watch::DEFAULT_IGNORES`, the
+    /// `.gitignore` (if enabled) and then the `ignore` patterns.
+    pub fn ignore_rules(&self, project_dir: &Path) -> Result<Rules, String> {
+        let mut rules = Rules::default();
+        for rule in DEFAULT_IGNORES {
+            rules.add_for_contents(rule)?;
+        }
+        if self.gitignore {
+            match std::fs::read_to_string(project_dir.join(".gitignore")) {
+                Ok(contents) => rules.add_gitignore(&contents),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => warn!("cannot read .gitignore in {}: {}", project_dir.display(), e),
+            }
+        }
+        for rule in &self.ignore {
+            rules.add(rule)?;
+        }
+        Ok(rules)
+    }
+}
+
+/// Resolve `.` and `..` components without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        config_root, rfc3339, utc_date, ConfigError, EventSinkConfig, LogConfig, MacosWatchConfig,
+        NixConfig, NixpkgsPin, ProjectConfig, ShellConfig, WatchConfig, WatchScope,
+        CACHIX_FILE_NAME, CONFIG_FILE_NAME,
+    };
+    use nix::Options;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::tempdir;
+    use toml;
+
+    #[test]
+    fn parse_config() {
+        assert_eq!(
+            toml::from_str::<ProjectConfig>("").unwrap(),
+            ProjectConfig::default()
+        );
+        assert_eq!(
+            toml::from_str::<ProjectConfig>(
+                "[watch]\nscope = \"project\"\nextra-roots = [\"../nix\"]\n"
+            )
+            .unwrap()
+            .watch,
+            WatchConfig {
+                scope: WatchScope::Project,
+                extra_roots: vec![PathBuf::from("../nix")],
+                macos: MacosWatchConfig::default(),
+                strict: false,
+                max_clock_skew_secs: 5,
+                ignore: vec![],
+                gitignore: true,
+                debounce_ms: None,
+            }
+        );
+        assert_eq!(
+            toml::from_str::<ProjectConfig>("[watch.macos]\nlatency-ms = 100\n")
+                .unwrap()
+                .watch
+                .macos,
+            MacosWatchConfig {
+                latency_ms: 100,
+                directory_granularity: true,
+            }
+        );
+        assert_eq!(
+            toml::from_str::<ProjectConfig>(
+                "[nixpkgs]\nurl = \"https://example.com/nixpkgs.tar.gz\"\nsha256 = \"abc\"\n"
+            )
+            .unwrap()
+            .nixpkgs,
+            Some(NixpkgsPin {
+                url: String::from("https://example.com/nixpkgs.tar.gz"),
+                sha256: String::from("abc"),
+            })
+        );
+        assert!(
+            toml::from_str::<ProjectConfig>("[nixpkgs]\nurl = \"https://example.com\"\n").is_err()
+        );
+        assert_eq!(
+            toml::from_str::<ProjectConfig>(
+                "[[event-sink]]\nfile = \"events.jsonl\"\n\
+                 [[event-sink]]\ncommand = [\"notify-send\", \"lorri\"]\nevents = [\"failure\"]\n"
+            )
+            .unwrap()
+            .event_sinks,
+            vec![
+                EventSinkConfig {
+                    file: Some(PathBuf::from("events.jsonl")),
+                    ..EventSinkConfig::default()
+                },
+                EventSinkConfig {
+                    command: Some(vec![String::from("notify-send"), String::from("lorri")]),
+                    events: vec![String::from("failure")],
+                    ..EventSinkConfig::default()
+                },
+            ]
+        );
+        assert!(toml::from_str::<ProjectConfig>("[watch]\nscope = \"nothing\"\n").is_err());
+        assert!(toml::from_str::<ProjectConfig>("[wacth]\n").is_err());
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let project = tempdir().unwrap();
+        std::fs::write(
+            project.path().join(CONFIG_FILE_NAME),
+            "[watch]\nscope = \"projcet\"\n",
+        )
+        .unwrap();
+        match ProjectConfig::load(project.path()) {
+            Err(ConfigError::Parse(invalid)) => {
+                assert_eq!(invalid.file, project.path().join(CONFIG_FILE_NAME));
+                assert_eq!(invalid.line, Some(2));
+                assert_eq!(invalid.key, Some(String::from("watch.scope")));
+                assert_eq!(invalid.suggestion, Some(String::from("project")));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn nix_options() {
+        assert_eq!(NixConfig::default().options(), Options::new());
+
+        let config = toml::from_str::<ProjectConfig>(
+            "[nix]\n\
+             substituters = [\"https://a.cachix.org\", \"https://b.cachix.org\"]\n\
+             trusted-public-keys = [\"a.cachix.org-1:abc=\"]\n",
+        )
+        .unwrap();
+        let mut expected = Options::new();
+        expected
+            .set(
+                "extra-substituters",
+                "https://a.cachix.org https://b.cachix.org",
+            )
+            .set("extra-trusted-public-keys", "a.cachix.org-1:abc=");
+        assert_eq!(config.nix.options(), expected);
+    }
+
+    #[test]
+    fn only_allowed_nix_options() {
+        let invalid = "[nix]\noptions = { max-jobs = \"4\", allow-unsafe-native-code-during-evaluation = \"true\" }\n";
+        let project = tempdir().unwrap();
+        std::fs::write(project.path().join(CONFIG_FILE_NAME), invalid).unwrap();
+        match ProjectConfig::load(project.path()) {
+            Err(ConfigError::Parse(invalid)) => assert_eq!(
+                invalid.key,
+                Some(String::from(
+                    "nix.options.allow-unsafe-native-code-during-evaluation"
+                ))
+            ),
+            other => panic!("{:?}", other),
+        }
+        assert!(
+            toml::from_str::<ProjectConfig>("[nix]\noptions = { max-jbos = \"4\" }\n").is_err()
+        );
+    }
+
+    #[test]
+    fn only_known_event_names() {
+        let sink = |events| {
+            format!(
+                "[[event-sink]]\nfile = \"events.jsonl\"\nevents = {}\n",
+                events
+            )
+        };
+        let config = toml::from_str::<ProjectConfig>(&sink("[\"failure\", \"log-line\"]")).unwrap();
+        assert_eq!(config.event_sinks[0].events, vec!["failure", "log-line"]);
+        let error = toml::from_str::<ProjectConfig>(&sink("[\"failures\"]"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("failures"), "{}", error);
+    }
+
+    #[test]
+    fn config_root_of_a_shell_file() {
+        let root = tempdir().unwrap();
+        let nix_dir = root.path().join("nix");
+        std::fs::create_dir(&nix_dir).unwrap();
+        std::fs::write(
+            root.path().join(CONFIG_FILE_NAME),
+            "shell-file = \"./nix/dev.nix\"\n[watch]\ngitignore = false\n",
+        )
+        .unwrap();
+
+        let dev = nix_dir.join("dev.nix");
+        assert_eq!(config_root(&nix_dir, Some(&dev)), root.path());
+        let other = nix_dir.join("other.nix");
+        assert_eq!(config_root(&nix_dir, Some(&other)), nix_dir);
+        assert_eq!(config_root(&nix_dir, None), nix_dir);
+
+        // a configuration next to the nix file is its own
+        std::fs::write(nix_dir.join(CONFIG_FILE_NAME), "").unwrap();
+        assert_eq!(config_root(&nix_dir, Some(&dev)), nix_dir);
+    }
+
+    #[test]
+    fn project_settings() {
+        let config = toml::from_str::<ProjectConfig>(
+            "shell-file = \"nix/dev.nix\"\nattribute = \"shells.dev\"\n\
+             [watch]\nignore = [\"*.log\", \"docs/\"]\ndebounce-ms = 200\n\
+             [nix]\noptions = { max-jobs = \"4\" }\nargs = { withDocs = \"true\" }\n\
+             argstrs = { python = \"python311\" }\nshow-trace = true\nallow-ifd = false\n",
+        )
+        .unwrap();
+        assert_eq!(config.shell_file, Some(PathBuf::from("nix/dev.nix")));
+        assert_eq!(config.watch.debounce_ms, Some(200));
+        let mut expected = Options::new();
+        expected
+            .set("allow-import-from-derivation", "false")
+            .set("max-jobs", "4")
+            .shell_arg("withDocs", "true")
+            .shell_argstr("python", "python311")
+            .show_trace();
+        assert_eq!(config.nix.options(), expected);
+        expected.attribute(&[String::from("shells"), String::from("dev")]);
+        assert_eq!(config.build_options(), Ok(expected));
+    }
+
+    #[test]
+    fn ignore_rules() {
+        let project = tempdir().unwrap();
+        std::fs::write(project.path().join(".gitignore"), "/dist\n*.tmp\n").unwrap();
+        let config = WatchConfig {
+            ignore: vec![String::from("*.log"), String::from("!node_modules/my-lib")],
+            ..WatchConfig::default()
+        };
+        let ignored = |config: &WatchConfig, path: &str| {
+            config
+                .ignore_rules(project.path())
+                .unwrap()
+                .is_ignored(Path::new(path), true)
+        };
+        assert!(ignored(&config, ".git/index"));
+        assert!(ignored(&config, "result-dev/bin/x"));
+        assert!(ignored(&config, "dist/app.js"));
+        assert!(ignored(&config, "src/a.tmp"));
+        assert!(ignored(&config, "build.log"));
+        assert!(ignored(&config, "node_modules/left-pad/index.js"));
+        assert!(!ignored(&config, "node_modules/my-lib/index.js"));
+        assert!(!ignored(&config, "src/main.rs"));
+
+        let without_gitignore = WatchConfig {
+            gitignore: false,
+            ..config
+        };
+        assert!(!ignored(&without_gitignore, "dist/app.js"));
+        assert!(ignored(&without_gitignore, "target/debug/x"));
+
+        let invalid = WatchConfig {
+            ignore: vec![String::from("/")],
+            ..WatchConfig::default()
+        };
+        assert!(invalid.ignore_rules(project.path()).is_err());
+    }
+
+    #[test]
+    fn shell_hook() {
+        let options = |toml: &str| {
+            toml::from_str::<ProjectConfig>(toml)
+                .unwrap()
+                .build_options()
+        };
+        assert_eq!(options(""), Ok(Options::new()));
+
+        let mut skip = Options::new();
+        skip.argstr("shellHookMode", "skip");
+        assert_eq!(options("[shell-hook]\nmode = \"skip\"\n"), Ok(skip));
+
+        let mut replace = Options::new();
+        replace
+            .argstr("shellHookMode", "replace")
+            .argstr("shellHookReplacement", "echo hi");
+        assert_eq!(
+            options("[shell-hook]\nmode = \"replace\"\nreplacement = \"echo hi\"\n"),
+            Ok(replace)
+        );
+        assert!(options("[shell-hook]\nmode = \"replace\"\n").is_err());
+
+        let mut sandbox = Options::new();
+        sandbox.argstr("shellHookSandbox", "true");
+        assert_eq!(options("[shell-hook]\nsandbox = true\n"), Ok(sandbox));
+    }
+
+    #[test]
+    fn named_shells() {
+        let config = toml::from_str::<ProjectConfig>(
+            "[[shell]]\nname = \"dev\"\n\
+             [[shell]]\nname = \"docs\"\nattribute = \"shells.docs\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config.shells[1],
+            ShellConfig {
+                name: String::from("docs"),
+                attribute: Some(String::from("shells.docs")),
+            }
+        );
+        assert_eq!(config.shells[0].attribute(), "dev");
+
+        let mut expected = Options::new();
+        expected.shells(&[
+            (String::from("dev"), vec![String::from("dev")]),
+            (
+                String::from("docs"),
+                vec![String::from("shells"), String::from("docs")],
+            ),
+        ]);
+        assert_eq!(config.build_options(), Ok(expected.clone()));
+        assert_eq!(expected.shell_names(), &["dev", "docs"]);
+
+        let options = |toml: &str| {
+            toml::from_str::<ProjectConfig>(toml)
+                .unwrap()
+                .build_options()
+        };
+        assert!(options("[[shell]]\nname = \"../dev\"\n").is_err());
+        assert!(options("[[shell]]\nname = \"dev\"\n[[shell]]\nname = \"dev\"\n").is_err());
+        assert!(options("[[shell]]\nname = \"dev\"\nattribute = \"shells.\"\n").is_err());
+    }
+
+    #[test]
+    fn cachix() {
+        let project = tempdir().unwrap();
+        std::fs::write(project.path().join(CACHIX_FILE_NAME), "example\n").unwrap();
+        let config = ProjectConfig::load(project.path()).unwrap();
+        assert_eq!(config.cachix.name, Some(String::from("example")));
+
+        let mut expected = Options::new();
+        expected.set("extra-substituters", "https://example.cachix.org");
+        assert_eq!(config.nix_options(), expected);
+    }
+
+    #[test]
+    fn partition_by_scope() {
+        let paths = || {
+            vec![
+                PathBuf::from("/nonexistent/project/shell.nix"),
+                PathBuf::from("/nonexistent/nix/pkgs.nix"),
+                PathBuf::from("/nonexistent/elsewhere/default.nix"),
+            ]
+        };
+        let project = Path::new("/nonexistent/project");
+
+        let everything = WatchConfig::default();
+        assert_eq!(everything.partition(project, paths()), (paths(), vec![]));
+
+        let scoped = WatchConfig {
+            scope: WatchScope::Project,
+            extra_roots: vec![PathBuf::from("../nix")],
+            ..WatchConfig::default()
+        };
+        let (watched, hashed) = scoped.partition(project, paths());
+        assert_eq!(watched, paths()[..2].to_vec());
+        assert_eq!(
+            hashed,
+            vec![PathBuf::from("/nonexistent/elsewhere/default.nix")]
+        );
+    }
+
+    #[test]
+    fn nix_output_path() {
+        let day = |secs| utc_date(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(day(0), "1970-01-01");
+        assert_eq!(day(951_782_400), "2000-02-29");
+        assert_eq!(day(1_577_836_799), "2019-12-31");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_millis(1_577_836_799_250)),
+            "2019-12-31T23:59:59.250Z"
+        );
+
+        let project = Path::new("/home/user/project");
+        let now = UNIX_EPOCH + Duration::from_secs(1_577_836_800);
+        assert_eq!(LogConfig::default().nix_output_path(project, now), None);
+        let log = |template: &str| LogConfig {
+            nix_output: Some(String::from(template)),
+            ..LogConfig::default()
+        };
+        assert_eq!(
+            log("/var/log/lorri/{project}-{date}.log").nix_output_path(project, now),
+            Some(PathBuf::from("/var/log/lorri/project-2020-01-01.log"))
+        );
+        assert_eq!(
+            log("build.log").nix_output_path(project, now),
+            Some(PathBuf::from("/home/user/project/build.log"))
+        );
+    }
+
+    #[test]
+    fn cap_failure_log() {
+        let lines = |range: std::ops::Range<usize>| -> Vec<std::ffi::OsString> {
+            range.map(|i| i.to_string().into()).collect()
+        };
+        let log: LogConfig =
+            toml::from_str("failure-head-lines = 2\nfailure-tail-lines = 3").unwrap();
+        assert_eq!(log.cap_failure_log(lines(0..5)), lines(0..5));
+        let mut capped = lines(0..2);
+        capped.push("lorri: … 5 lines elided …".into());
+        capped.extend(lines(7..10));
+        assert_eq!(log.cap_failure_log(lines(0..10)), capped);
+        assert_eq!(LogConfig::default().failure_tail_lines, 200);
+    }
+}
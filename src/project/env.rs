@@ -0,0 +1,131 @@
+//! Read the environment variables a built environment exports.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+/// Variables which describe the build sandbox or the user’s
+/// session rather than the project, and which are not taken
+/// over from the environment (compare `envrc.bash`).
+const SESSION_VARIABLES: [&str; 19] = [
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "DISPLAY",
+    "TERM",
+    "IN_NIX_SHELL",
+    "TZ",
+    "PAGER",
+    "NIX_BUILD_SHELL",
+    "SHLVL",
+    "TEMPDIR",
+    "TMPDIR",
+    "TEMP",
+    "TMP",
+    "NIX_ENFORCE_PURITY",
+    "OLDPWD",
+    "PWD",
+    "SHELL",
+    "_",
+];
+
+/// Variables which differ between machines building the same
+/// environment, and which don’t count for its `hash`.
+const MACHINE_VARIABLES: [&str; 3] = ["NIX_BUILD_CORES", "NIX_BUILD_TOP", "NIX_LOG_FD"];
+
+/// Source `bash_export` in a clean bash and return the variables
+/// it exports, without the session variables.
+pub fn read(bash_export: &Path) -> io::Result<BTreeMap<String, String>> {
+    let output = Command::new("bash")
+        .args(&[
+            "-c",
+            r#". "$1" || exit 1
+for name in $(compgen -e); do printf '%s\0%s\0' "$name" "${!name}"; done"#,
+            "--",
+        ])
+        .arg(bash_export)
+        .env_clear()
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "could not read the environment from {}: {}",
+                bash_export.display(),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        ));
+    }
+    Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Whether `name` describes the build sandbox or the user’s session
+/// rather than the project.
+pub fn is_session_variable(name: &str) -> bool {
+    SESSION_VARIABLES.contains(&name)
+}
+
+/// A hash of the variables of `env` (as `read` returns them),
+/// except the ones which differ between machines. Environments
+/// built from the same derivations have the same hash, so it tells
+/// whether two people are in identical environments.
+pub fn hash(env: &BTreeMap<String, String>) -> String {
+    let mut context = md5::Context::new();
+    for (name, value) in env {
+        if !MACHINE_VARIABLES.contains(&name.as_str()) {
+            context.consume(name.as_bytes());
+            context.consume(b"\0");
+            context.consume(value.as_bytes());
+            context.consume(b"\0");
+        }
+    }
+    format!("{:x}", context.compute())
+}
+
+/// Parse `name\0value\0` pairs.
+fn parse(output: &str) -> BTreeMap<String, String> {
+    let mut fields = output.split('\0');
+    let mut env = BTreeMap::new();
+    while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+        if !is_session_variable(name) {
+            env.insert(name.to_string(), value.to_string());
+        }
+    }
+    env
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash, parse};
+
+    #[test]
+    fn parse_variables() {
+        let env = parse("PATH\0/nix/store/a/bin\0HOME\0/homeless-shelter\0multi\0a\nb\0");
+        assert_eq!(
+            env.into_iter().collect::<Vec<_>>(),
+            vec![
+                (String::from("PATH"), String::from("/nix/store/a/bin")),
+                (String::from("multi"), String::from("a\nb")),
+            ]
+        );
+    }
+
+    #[test]
+    fn environment_hash() {
+        let env = parse(concat!(
+            "PATH\0/nix/store/a/bin\0",
+            "NIX_BUILD_CORES\0",
+            "4\0"
+        ));
+        let same = parse(concat!(
+            "NIX_BUILD_CORES\0",
+            "16\0",
+            "PATH\0/nix/store/a/bin\0"
+        ));
+        assert_eq!(hash(&env), hash(&same));
+        assert_ne!(hash(&env), hash(&parse("PATH\0/nix/store/b/bin\0")));
+        // names and values can’t run into each other
+        assert_ne!(hash(&parse("ab\0c\0")), hash(&parse("a\0bc\0")));
+    }
+}
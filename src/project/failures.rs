@@ -0,0 +1,190 @@
+//! Snapshots of failed builds, for postmortems and bug reports.
+//!
+//! After a build fails, the project’s nix files, the log and the
+//! nix invocation are copied to a timestamped directory in the
+//! project’s `failures` directory (see `Project::failures_dir`):
+//!
+//! ```text
+//! failures/2020-01-01T123000Z/
+//!   command       the nix-build invocation
+//!   log           everything nix printed
+//!   inputs/       shell.nix and the local nix files it imports,
+//!                 relative to the project directory
+//! ```
+//!
+//! Only the latest `KEPT_SNAPSHOTS` snapshots are kept.
+
+use crate::project::config::{utc_date, CONFIG_FILE_NAME};
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many snapshots are kept per project.
+pub const KEPT_SNAPSHOTS: usize = 10;
+
+/// What is saved about a failed build.
+pub struct Snapshot<'a> {
+    /// The project directory; inputs outside of it are not saved.
+    pub project_dir: &'a Path,
+    /// Files the build read.
+    pub inputs: &'a [PathBuf],
+    /// The output of nix.
+    pub log_lines: &'a [OsString],
+    /// The nix command line.
+    pub command: &'a str,
+}
+
+impl<'a> Snapshot<'a> {
+    /// Save the snapshot to a new directory for time `now` in
+    /// `failures_dir`, and return its path. Older snapshots
+    /// are removed.
+    pub fn save(&self, failures_dir: &Path, now: SystemTime) -> io::Result<PathBuf> {
+        fs::create_dir_all(failures_dir)?;
+        let dir = new_snapshot_dir(failures_dir, &utc_timestamp(now))?;
+
+        fs::write(dir.join("command"), format!("{}\n", self.command))?;
+        let mut log = fs::File::create(dir.join("log"))?;
+        for line in self.log_lines {
+            log.write_all(line.as_bytes())?;
+            log.write_all(b"\n")?;
+        }
+
+        let config_file = self.project_dir.join(CONFIG_FILE_NAME);
+        let inputs = self
+            .inputs
+            .iter()
+            .filter(|path| path.extension() == Some("nix".as_ref()))
+            .chain(Some(&config_file));
+        for input in inputs {
+            let relative = match input.strip_prefix(self.project_dir) {
+                Ok(relative) if input.is_file() => relative,
+                _ => continue,
+            };
+            let target = dir.join("inputs").join(relative);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(input, target)?;
+        }
+
+        remove_old_snapshots(failures_dir)?;
+        Ok(dir)
+    }
+}
+
+/// Create a directory named `name` in `failures_dir`, or with a
+/// counter appended if it exists (for failures within a second).
+fn new_snapshot_dir(failures_dir: &Path, name: &str) -> io::Result<PathBuf> {
+    let mut dir = failures_dir.join(name);
+    let mut counter = 1;
+    loop {
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                dir = failures_dir.join(format!("{}-{}", name, counter));
+                counter += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Remove all but the newest `KEPT_SNAPSHOTS` snapshots. The
+/// timestamps in their names sort them from oldest to newest.
+fn remove_old_snapshots(failures_dir: &Path) -> io::Result<()> {
+    let mut snapshots = failures_dir
+        .read_dir()?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+    snapshots.sort();
+    let old = snapshots.len().saturating_sub(KEPT_SNAPSHOTS);
+    for snapshot in &snapshots[..old] {
+        fs::remove_dir_all(snapshot)?;
+    }
+    Ok(())
+}
+
+/// Format `time` as `YYYY-MM-DDTHHMMSSZ` in UTC
+/// (without colons, which some tools don’t like in file names).
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+        % 86_400;
+    format!(
+        "{}T{:02}{:02}{:02}Z",
+        utc_date(time),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{utc_timestamp, Snapshot, KEPT_SNAPSHOTS};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn save_snapshots() {
+        let tmp = tempfile::tempdir().unwrap();
+        let project_dir = tmp.path().join("project");
+        fs::create_dir_all(project_dir.join("nix")).unwrap();
+        fs::write(project_dir.join("shell.nix"), "import ./nix").unwrap();
+        fs::write(project_dir.join("nix/default.nix"), "{}").unwrap();
+        let inputs = vec![
+            project_dir.join("shell.nix"),
+            project_dir.join("nix/default.nix"),
+            PathBuf::from("/nix/store/abc-nixpkgs/default.nix"),
+        ];
+        let snapshot = Snapshot {
+            project_dir: &project_dir,
+            inputs: &inputs,
+            log_lines: &["error: oops".into()],
+            command: "\"nix-build\" \"shell.nix\"",
+        };
+
+        let failures_dir = tmp.path().join("failures");
+        let time = UNIX_EPOCH + Duration::from_secs(1_577_881_800);
+        let dir = snapshot.save(&failures_dir, time).unwrap();
+        assert_eq!(dir, failures_dir.join("2020-01-01T123000Z"));
+        assert_eq!(
+            fs::read_to_string(dir.join("log")).unwrap(),
+            "error: oops\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("inputs/nix/default.nix")).unwrap(),
+            "{}"
+        );
+        assert!(dir.join("inputs/shell.nix").is_file());
+        assert!(dir.join("command").is_file());
+
+        // failures within the same second get their own directory
+        assert_eq!(
+            snapshot.save(&failures_dir, time).unwrap(),
+            failures_dir.join("2020-01-01T123000Z-1")
+        );
+
+        for i in 0..KEPT_SNAPSHOTS as u64 {
+            snapshot
+                .save(&failures_dir, time + Duration::from_secs(i + 1))
+                .unwrap();
+        }
+        assert_eq!(failures_dir.read_dir().unwrap().count(), KEPT_SNAPSHOTS);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(
+            utc_timestamp(UNIX_EPOCH + Duration::from_secs(1_577_881_800)),
+            "2020-01-01T123000Z"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Write the environment into the configuration of IDEs, for
+//! IDE users who can’t use direnv.
+
+use crate::builder::OutputPaths;
+use crate::project::env;
+use crate::project::roots::RootPath;
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The configuration formats we can write the environment in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IdeFormat {
+    /// `terminal.integrated.env.*` in `.vscode/settings.json`
+    Vscode,
+    /// An env file for JetBrains IDEs at `.idea/lorri.env`,
+    /// to be loaded with the EnvFile plugin
+    Idea,
+}
+
+impl FromStr for IdeFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IdeFormat, String> {
+        match s {
+            "vscode" => Ok(IdeFormat::Vscode),
+            "idea" => Ok(IdeFormat::Idea),
+            _ => Err(format!("unknown format `{}`, use vscode or idea", s)),
+        }
+    }
+}
+
+/// Writing the IDE configuration failed.
+#[derive(Debug)]
+pub enum IdeEnvError {
+    /// Reading the environment or writing the file failed.
+    Io(io::Error),
+    /// The existing settings file is not valid JSON (note that
+    /// comments are not supported).
+    Json(PathBuf, serde_json::Error),
+}
+
+impl From<io::Error> for IdeEnvError {
+    fn from(e: io::Error) -> IdeEnvError {
+        IdeEnvError::Io(e)
+    }
+}
+
+impl std::fmt::Display for IdeEnvError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IdeEnvError::Io(e) => write!(f, "{}", e),
+            IdeEnvError::Json(path, e) => write!(
+                f,
+                "{} is not plain JSON, not touching it: {}",
+                path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Write the environment in `root_paths` into the IDE configuration
+/// in `project_dir`. Returns the path of the written file.
+pub fn write(
+    project_dir: &Path,
+    root_paths: &OutputPaths<RootPath>,
+    format: IdeFormat,
+) -> Result<PathBuf, IdeEnvError> {
+    let env = env::read(&root_paths.bash_export())?;
+    let (path, contents) = match format {
+        IdeFormat::Vscode => {
+            let path = project_dir.join(".vscode").join("settings.json");
+            let existing = match fs::read_to_string(&path) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+                Ok(contents) => Some(contents),
+            };
+            let contents = vscode_settings(existing.as_ref().map(String::as_str), &env)
+                .map_err(|e| IdeEnvError::Json(path.clone(), e))?;
+            (path, contents)
+        }
+        IdeFormat::Idea => (project_dir.join(".idea").join("lorri.env"), idea_env(&env)),
+    };
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, contents)?;
+    Ok(path)
+}
+
+/// The key of the terminal environment in VSCode’s settings.
+#[cfg(target_os = "macos")]
+const VSCODE_ENV_KEY: &str = "terminal.integrated.env.osx";
+#[cfg(not(target_os = "macos"))]
+const VSCODE_ENV_KEY: &str = "terminal.integrated.env.linux";
+
+/// Set the terminal environment in the VSCode `existing` settings,
+/// keeping all other settings.
+fn vscode_settings(
+    existing: Option<&str>,
+    env: &BTreeMap<String, String>,
+) -> Result<String, serde_json::Error> {
+    let mut settings = match existing {
+        Some(existing) if !existing.trim().is_empty() => serde_json::from_str(existing)?,
+        _ => serde_json::Value::Object(serde_json::Map::new()),
+    };
+    let env: serde_json::Map<String, serde_json::Value> = env
+        .iter()
+        .map(|(name, value)| {
+            let value = if name == "PATH" {
+                format!("{}:${{env:PATH}}", value)
+            } else {
+                value.clone()
+            };
+            (name.clone(), serde_json::Value::String(value))
+        })
+        .collect();
+    if let Some(settings) = settings.as_object_mut() {
+        settings.insert(VSCODE_ENV_KEY.to_string(), serde_json::Value::Object(env));
+    }
+    let mut contents = serde_json::to_string_pretty(&settings)?;
+    contents.push('\n');
+    Ok(contents)
+}
+
+/// The environment as an env file.
+fn idea_env(env: &BTreeMap<String, String>) -> String {
+    env.iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', r"\\")
+                .replace('"', "\\\"")
+                .replace('\n', r"\n");
+            if name == "PATH" {
+                format!("{}=\"{}:${{PATH}}\"\n", name, value)
+            } else {
+                format!("{}=\"{}\"\n", name, value)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{idea_env, vscode_settings, VSCODE_ENV_KEY};
+    use serde_json;
+    use std::collections::BTreeMap;
+
+    fn env() -> BTreeMap<String, String> {
+        vec![("PATH", "/nix/store/a/bin"), ("GREETING", "say \"hi\"")]
+            .into_iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn vscode_keeps_other_settings() {
+        let settings = vscode_settings(Some(r#"{ "editor.tabSize": 2 }"#), &env()).unwrap();
+        let settings: serde_json::Value = serde_json::from_str(&settings).unwrap();
+        assert_eq!(settings["editor.tabSize"], 2);
+        assert_eq!(
+            settings[VSCODE_ENV_KEY]["PATH"],
+            "/nix/store/a/bin:${env:PATH}"
+        );
+        assert_eq!(settings[VSCODE_ENV_KEY]["GREETING"], "say \"hi\"");
+
+        assert!(vscode_settings(Some("{ // comment\n}"), &env()).is_err());
+    }
+
+    #[test]
+    fn idea_env_file() {
+        assert_eq!(
+            idea_env(&env()),
+            "GREETING=\"say \\\"hi\\\"\"\nPATH=\"/nix/store/a/bin:${PATH}\"\n"
+        );
+    }
+}
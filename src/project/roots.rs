@@ -1,11 +1,18 @@
 //! Handling of nix GC roots
 //!
 //! TODO: inline this module into `::project`
+
+extern crate nix;
+
+use self::nix::libc;
 use crate::project::Project;
 use builder::OutputPaths;
-use nix::StorePath;
+use nix::{Store, StorePath};
+use std::collections::BTreeMap;
 use std::env;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Once;
 
 /// Roots manipulation
 #[derive(Clone)]
@@ -13,6 +20,22 @@ pub struct Roots {
     /// The GC root directory in the lorri user cache dir
     gc_root_path: PathBuf,
     id: String,
+    /// The nix store the roots point into
+    store: Store,
+    /// The names of the project’s named shells, which get a root
+    /// each (see `ShellConfig`)
+    shells: Vec<String>,
+}
+
+/// The outcome of checking one root, see `Roots::check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootCheck {
+    /// The root
+    pub root: PathBuf,
+    /// The store path the root points to
+    pub target: StorePath,
+    /// Whether `target` is still in the store
+    pub valid: bool,
 }
 
 /// A path to a gc root.
@@ -26,12 +49,21 @@ impl RootPath {
     }
 }
 
+impl From<PathBuf> for RootPath {
+    fn from(path: PathBuf) -> RootPath {
+        RootPath(path)
+    }
+}
+
 impl OutputPaths<RootPath> {
     /// Check whether all all GC roots exist.
     pub fn all_exist(&self) -> bool {
         match self {
             // Match here to ensure we cover every field
-            ::builder::OutputPaths { shell_gc_root } => shell_gc_root.0.exists(),
+            ::builder::OutputPaths {
+                shell_gc_root,
+                shells,
+            } => shell_gc_root.0.exists() && shells.values().all(|root| root.0.exists()),
         }
     }
 
@@ -42,6 +74,17 @@ impl OutputPaths<RootPath> {
             Ok(m) => m.is_dir(),
         }
     }
+
+    /// The bash script exporting the environment.
+    /// Older builds are the script itself, newer ones a directory
+    /// containing it.
+    pub fn bash_export(&self) -> PathBuf {
+        if self.shell_gc_root_is_dir() {
+            self.shell_gc_root.0.join("bash-export")
+        } else {
+            self.shell_gc_root.0.clone()
+        }
+    }
 }
 
 /// Proxy through the `Display` class for `PathBuf`.
@@ -54,21 +97,136 @@ impl std::fmt::Display for RootPath {
 impl Roots {
     // TODO: all use-cases are from_project; just save a reference to a project?
     /// Construct a Roots struct based on a project's GC root directory
-    /// and ID.
+    /// and ID, and the named shells in its current configuration.
     pub fn from_project(project: &Project) -> Roots {
         Roots {
             gc_root_path: project.gc_root_path.to_path_buf(),
-            id: project.hash().to_string(),
+            id: project.root_name().to_string(),
+            store: project.store.clone(),
+            shells: project
+                .config()
+                .map(|config| config.shells.into_iter().map(|shell| shell.name).collect())
+                .unwrap_or_default(),
         }
     }
 
+    /// The roots in the project root directory `root_dir` (a
+    /// directory in `Paths.gc_root_dir()`), pointing into `store`.
+    pub fn in_dir(root_dir: &Path, store: Store) -> Roots {
+        Roots {
+            gc_root_path: root_dir.join("gc_root"),
+            id: root_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            store,
+            shells: vec![],
+        }
+    }
+
+    /// Check all roots in the root directory, including the ones of
+    /// shells which are no longer configured, sorted by path.
+    pub fn check(&self) -> std::io::Result<Vec<RootCheck>> {
+        let mut roots = vec![];
+        let entries = std::fs::read_dir(&self.gc_root_path)?;
+        for entry in entries {
+            let root = entry?.path();
+            if let Ok(target) = std::fs::read_link(&root) {
+                roots.push((root, StorePath::from(target.into_os_string())));
+            }
+        }
+        let valid = self.validity(roots.iter().map(|(_, target)| target));
+        let mut checks: Vec<RootCheck> = roots
+            .into_iter()
+            .zip(valid)
+            .map(|((root, target), valid)| RootCheck {
+                root,
+                target,
+                valid,
+            })
+            .collect();
+        checks.sort_by(|a, b| a.root.cmp(&b.root));
+        Ok(checks)
+    }
+
     /// Return the filesystem paths for these roots.
     pub fn paths(&self) -> OutputPaths<RootPath> {
         OutputPaths {
             shell_gc_root: RootPath(self.gc_root_path.join("shell_gc_root")),
+            shells: self
+                .shells
+                .iter()
+                .map(|name| {
+                    let root = self.gc_root_path.join(shell_root_name(name));
+                    (name.clone(), RootPath(root))
+                })
+                .collect(),
         }
     }
 
+    /// Store paths of existing roots which are no longer valid,
+    /// for example because they were garbage collected while the
+    /// roots were missing, or deleted with `--ignore-liveness`.
+    pub fn lost(&self) -> Vec<StorePath> {
+        let OutputPaths {
+            shell_gc_root,
+            shells,
+        } = self.paths();
+        let targets: Vec<StorePath> = std::iter::once(&shell_gc_root)
+            .chain(shells.values())
+            // roots which were never created can’t be lost
+            .filter_map(|root| std::fs::read_link(&root.0).ok())
+            .map(|target| StorePath::from(target.into_os_string()))
+            .collect();
+        let valid = self.validity(targets.iter());
+        targets
+            .into_iter()
+            .zip(valid)
+            .filter(|(_, valid)| !valid)
+            .map(|(store_path, _)| store_path)
+            .collect()
+    }
+
+    /// Check whether each of `store_paths` is still in the store,
+    /// asking nix (if we have to) about all of them at once.
+    fn validity<'p, I>(&self, store_paths: I) -> Vec<bool>
+    where
+        I: Iterator<Item = &'p StorePath>,
+    {
+        let paths: Vec<&Path> = store_paths.map(StorePath::as_path).collect();
+        if let Some(chroot) = self.store.chroot() {
+            return paths
+                .iter()
+                .map(|path| chroot.join(path.strip_prefix("/").unwrap_or(path)).exists())
+                .collect();
+        }
+        if self.store.is_default_layout() {
+            return paths.iter().map(|path| path.exists()).collect();
+        }
+        if paths.is_empty() {
+            return vec![];
+        }
+        // for other stores, ask nix
+        let invalid: Vec<PathBuf> = match Command::new("nix-store")
+            .args(self.store.args())
+            .arg("--check-validity")
+            .arg("--print-invalid")
+            .args(&paths)
+            .output()
+        {
+            Ok(ref output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(PathBuf::from)
+                .collect(),
+            // we can’t tell, don’t cause a rebuild
+            _ => vec![],
+        };
+        paths
+            .iter()
+            .map(|path| !invalid.iter().any(|invalid| invalid == path))
+            .collect()
+    }
+
     /// Create roots to store paths.
     pub fn create_roots(
         &self,
@@ -78,37 +236,66 @@ impl Roots {
         paths: OutputPaths<StorePath>,
     ) -> Result<OutputPaths<RootPath>, AddRootError>
 where {
+        let mut shells = BTreeMap::new();
+        for (name, store_path) in &paths.shells {
+            shells.insert(name.clone(), self.add(&shell_root_name(name), store_path)?);
+        }
         Ok(OutputPaths {
             shell_gc_root: self.add("shell_gc_root", &paths.shell_gc_root)?,
+            shells,
+        })
+    }
+
+    /// The store paths the roots point to, if all of them exist.
+    pub fn current(&self) -> Option<OutputPaths<StorePath>> {
+        let target = |root: &RootPath| {
+            std::fs::read_link(&root.0)
+                .ok()
+                .map(|target| StorePath::from(target.into_os_string()))
+        };
+        let paths = self.paths();
+        Some(OutputPaths {
+            shell_gc_root: target(&paths.shell_gc_root)?,
+            shells: paths
+                .shells
+                .iter()
+                .map(|(name, root)| Some((name.clone(), target(root)?)))
+                .collect::<Option<_>>()?,
         })
     }
 
-    /// Store a new root under name
+    /// Store a new root under name. An existing root is replaced
+    /// atomically, so it always points to a complete environment.
     fn add(&self, name: &str, store_path: &StorePath) -> Result<RootPath, AddRootError> {
         // final path in the `self.gc_root_path` directory
         let mut path = self.gc_root_path.clone();
         path.push(name);
 
         debug!("Adding root from {:?} to {:?}", store_path.as_path(), path,);
-        std::fs::remove_file(&path).or_else(|e| AddRootError::remove(e, &path))?;
 
-        std::fs::remove_file(&path).or_else(|e| AddRootError::remove(e, &path))?;
+        if !self.store.is_default_layout() {
+            // nix replaces the root atomically itself
+            return self.add_indirect(path, store_path);
+        }
 
         // the forward GC root that points from the store path to our cache gc_roots dir
-        std::os::unix::fs::symlink(store_path.as_path(), &path)
-            .map_err(|e| AddRootError::symlink(e, store_path.as_path(), &path))?;
+        replace_symlink(store_path.as_path(), &path)?;
 
-        // the reverse GC root that points from nix to our cache gc_roots dir
-        let mut root = if let Ok(path) = env::var("NIX_STATE_DIR") {
-            PathBuf::from(path)
-        } else {
-            PathBuf::from("/nix/var/nix/")
-        };
-        root.push("gcroots");
-        root.push("per-user");
+        match self.add_per_user(name, &path) {
+            // TODO: don’t return the RootPath here
+            Ok(()) => Ok(RootPath(path)),
+            Err(ref e) if e.per_user_unavailable() => {
+                warn_rootless(e);
+                self.add_indirect(path, store_path)
+            }
+            Err(e) => Err(e),
+        }
+    }
 
-        // TODO: check on start of lorri
-        root.push(env::var("USER").expect("env var 'USER' must be set"));
+    /// Create the reverse GC root that points from nix’s per-user
+    /// roots to `path` in our cache gc_roots dir.
+    fn add_per_user(&self, name: &str, path: &Path) -> Result<(), AddRootError> {
+        let mut root = self.per_user_dir().map_err(|_| AddRootError::NoUser)?;
 
         // The user directory sometimes doesn’t exist,
         // but we can create it (it’s root but `rwxrwxrwx`)
@@ -119,24 +306,156 @@ where {
         root.push(format!("{}-{}", self.id, name));
 
         debug!("Connecting root from {:?} to {:?}", path, root,);
-        std::fs::remove_file(&root).or_else(|e| AddRootError::remove(e, &root))?;
+        replace_symlink(path, &root)
+    }
 
-        std::os::unix::fs::symlink(&path, &root)
-            .map_err(|e| AddRootError::symlink(e, &path, &root))?;
+    /// Nix’s directory of the roots of the current user.
+    fn per_user_dir(&self) -> Result<PathBuf, env::VarError> {
+        let mut dir = self.store.state_dir();
+        dir.push("gcroots");
+        dir.push("per-user");
+        dir.push(env::var("USER")?);
+        Ok(dir)
+    }
+
+    /// Remove the project root directory (see `in_dir`) with all
+    /// its roots, and the links to them in nix’s per-user roots.
+    /// The store paths can be garbage collected afterwards.
+    ///
+    /// The links are found by their target, or by the name older
+    /// lorri versions gave their only root (`<id>-shell_gc_root`),
+    /// in case the root directory has moved since.
+    pub fn remove(&self) -> std::io::Result<()> {
+        if let Ok(per_user_dir) = self.per_user_dir() {
+            let older_link = format!("{}-shell_gc_root", self.id);
+            // no per-user roots if nix registered them (see `add`)
+            if let Ok(entries) = std::fs::read_dir(per_user_dir) {
+                for entry in entries {
+                    let entry = entry?;
+                    let ours = match std::fs::read_link(entry.path()) {
+                        Ok(target) => target.starts_with(&self.gc_root_path),
+                        Err(_) => false,
+                    };
+                    if ours || entry.file_name() == older_link.as_str() {
+                        std::fs::remove_file(entry.path())?;
+                    }
+                }
+            }
+        }
+        let root_dir = self.gc_root_path.parent().unwrap_or(&self.gc_root_path);
+        std::fs::remove_dir_all(root_dir)
+    }
+
+    /// Let nix register `path` as an indirect root for `store_path`.
+    ///
+    /// Our own symlink scheme only works for the default store
+    /// layout; a non-standard store (like a chroot store) knows
+    /// best where its roots live.
+    fn add_indirect(
+        &self,
+        path: PathBuf,
+        store_path: &StorePath,
+    ) -> Result<RootPath, AddRootError> {
+        let mut cmd = Command::new("nix-store");
+        cmd.args(self.store.args())
+            .arg("--realise")
+            .arg("--add-root")
+            .arg(&path)
+            .arg("--indirect")
+            .arg(store_path.as_path());
+        debug!("$ {:?}", cmd);
 
-        // TODO: don’t return the RootPath here
-        Ok(RootPath(path))
+        let output = cmd
+            .output()
+            .map_err(|e| AddRootError::Io(e, String::from("Failed to execute nix-store")))?;
+        if output.status.success() {
+            Ok(RootPath(path))
+        } else {
+            Err(AddRootError::NixStore(output))
+        }
     }
 }
 
+/// Tell the user once that lorri runs rootless, because it can’t
+/// create its per-user GC roots (`error`).
+fn warn_rootless(error: &AddRootError) {
+    static WARNED: Once = Once::new();
+    WARNED.call_once(|| {
+        warn!(
+            "cannot create GC roots in nix’s per-user roots directory ({}); \
+             running rootless: nix registers the roots in the lorri cache \
+             directory as indirect roots instead",
+            error
+        )
+    });
+}
+
+/// Point the symlink `link` to `target`, replacing an existing
+/// `link` atomically (by renaming a new link over it).
+fn replace_symlink(target: &Path, link: &Path) -> Result<(), AddRootError> {
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(link.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp = link.with_file_name(tmp_name);
+    std::fs::remove_file(&tmp).or_else(|e| AddRootError::remove(e, &tmp))?;
+    std::os::unix::fs::symlink(target, &tmp).map_err(|e| AddRootError::symlink(e, target, &tmp))?;
+    std::fs::rename(&tmp, link).map_err(|e| {
+        AddRootError::Io(
+            e,
+            format!("Failed to move {} to {}", tmp.display(), link.display()),
+        )
+    })
+}
+
+/// The name of the root of the named shell `name`.
+fn shell_root_name(name: &str) -> String {
+    format!("shell_gc_root-{}", name)
+}
+
 /// Error conditions encountered when adding roots
 #[derive(Debug)]
 pub enum AddRootError {
     /// IO-related errors
     Io(std::io::Error, String),
+    /// `nix-store --add-root` failed (for non-default stores)
+    NixStore(std::process::Output),
+    /// `$USER` is not set, so the per-user roots directory is unknown
+    NoUser,
+}
+
+impl std::fmt::Display for AddRootError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AddRootError::Io(e, context) => write!(f, "{}: {}", context, e),
+            AddRootError::NixStore(output) => write!(
+                f,
+                "nix-store --add-root failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            AddRootError::NoUser => write!(
+                f,
+                "Cannot tell the per-user GC root directory: USER is not set"
+            ),
+        }
+    }
 }
 
 impl AddRootError {
+    /// Whether nix’s per-user roots can’t be used, so that lorri
+    /// runs rootless: it isn’t allowed to create the root there (like
+    /// in locked-down setups without write access to `/nix/var`, or
+    /// with a read-only `/nix/var`), or doesn’t know the user.
+    fn per_user_unavailable(&self) -> bool {
+        match self {
+            AddRootError::Io(e, _) => {
+                e.kind() == std::io::ErrorKind::PermissionDenied
+                    || e.raw_os_error() == Some(libc::EROFS)
+            }
+            AddRootError::NixStore(_) => false,
+            AddRootError::NoUser => true,
+        }
+    }
+
     /// Create a contextualized error around failing to create a directory
     fn create_dir_all(err: std::io::Error, path: &Path) -> AddRootError {
         AddRootError::Io(
@@ -166,3 +485,225 @@ impl AddRootError {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lost_roots() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let roots = Roots {
+            gc_root_path: tmp.path().to_path_buf(),
+            id: String::from("lost-roots-test"),
+            store: Store::Default,
+            shells: vec![String::from("docs")],
+        };
+        // no roots yet
+        assert_eq!(roots.lost(), vec![]);
+
+        let target = tmp.path().join("store-path");
+        std::fs::write(&target, "")?;
+        std::os::unix::fs::symlink(&target, tmp.path().join("shell_gc_root"))?;
+        let docs_target = tmp.path().join("docs-store-path");
+        std::fs::write(&docs_target, "")?;
+        std::os::unix::fs::symlink(&docs_target, tmp.path().join("shell_gc_root-docs"))?;
+        assert_eq!(roots.lost(), vec![]);
+
+        std::fs::remove_file(&target)?;
+        std::fs::remove_file(&docs_target)?;
+        assert_eq!(
+            roots.lost(),
+            vec![
+                StorePath::from(target.into_os_string()),
+                StorePath::from(docs_target.into_os_string())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replace_roots() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let roots = Roots {
+            gc_root_path: tmp.path().to_path_buf(),
+            id: String::from("replace-roots-test"),
+            store: Store::Default,
+            shells: vec![],
+        };
+        assert_eq!(roots.current(), None);
+
+        let link = tmp.path().join("shell_gc_root");
+        for target in &["old-store-path", "new-store-path"] {
+            replace_symlink(&tmp.path().join(target), &link).unwrap();
+        }
+        assert_eq!(
+            roots.current(),
+            Some(OutputPaths {
+                shell_gc_root: StorePath::from(tmp.path().join("new-store-path").into_os_string()),
+                shells: BTreeMap::new(),
+            })
+        );
+        // no temporary links are left behind
+        assert_eq!(std::fs::read_dir(tmp.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn check_roots() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let roots = Roots::in_dir(tmp.path(), Store::Default);
+        std::fs::create_dir(tmp.path().join("gc_root"))?;
+        let valid = tmp.path().join("valid-store-path");
+        std::fs::write(&valid, "")?;
+        let dangling = tmp.path().join("collected-store-path");
+        std::os::unix::fs::symlink(&valid, tmp.path().join("gc_root/shell_gc_root"))?;
+        std::os::unix::fs::symlink(&dangling, tmp.path().join("gc_root/shell_gc_root-old"))?;
+
+        assert_eq!(
+            roots.check()?,
+            vec![
+                RootCheck {
+                    root: tmp.path().join("gc_root/shell_gc_root"),
+                    target: StorePath::from(valid.into_os_string()),
+                    valid: true,
+                },
+                RootCheck {
+                    root: tmp.path().join("gc_root/shell_gc_root-old"),
+                    target: StorePath::from(dangling.into_os_string()),
+                    valid: false,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    /// `remove` takes the project’s links in the per-user roots with
+    /// it, including the one of the older layout, and leaves the
+    /// links of other projects.
+    #[test]
+    fn remove_roots() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let state = tmp.path().join("state");
+        let root_dir = tmp.path().join("0123");
+        let roots = Roots::in_dir(
+            &root_dir,
+            Store::Uri(format!("local?state={}", state.display())),
+        );
+        let per_user_dir = match roots.per_user_dir() {
+            Ok(dir) => dir,
+            Err(_) => {
+                eprintln!("skipping remove_roots: USER is not set");
+                return Ok(());
+            }
+        };
+        std::fs::create_dir_all(&per_user_dir)?;
+        std::fs::create_dir_all(root_dir.join("gc_root"))?;
+        let link =
+            |name: &str, target: &Path| std::os::unix::fs::symlink(target, per_user_dir.join(name));
+        link(
+            "0123-shell_gc_root-docs",
+            &root_dir.join("gc_root/shell_gc_root-docs"),
+        )?;
+        link(
+            "0123-shell_gc_root",
+            Path::new("/old/cache/lorri/gc_roots/0123/gc_root/shell_gc_root"),
+        )?;
+        link(
+            "4567-shell_gc_root",
+            &tmp.path().join("4567/gc_root/shell_gc_root"),
+        )?;
+
+        roots.remove()?;
+        assert!(!root_dir.exists());
+        let left: Vec<_> = std::fs::read_dir(&per_user_dir)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(left, vec![std::ffi::OsString::from("4567-shell_gc_root")]);
+        Ok(())
+    }
+
+    /// Without write access to nix’s per-user roots, lorri falls
+    /// back to indirect roots.
+    #[test]
+    fn per_user_roots_not_writable() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let state = tmp.path().join("state");
+        std::fs::create_dir(&state)?;
+        let roots = Roots {
+            gc_root_path: tmp.path().join("gc_root"),
+            id: String::from("rootless-test"),
+            store: Store::Uri(format!("local?state={}", state.display())),
+            shells: vec![],
+        };
+        let mut permissions = std::fs::metadata(&state)?.permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&state, permissions)?;
+
+        if std::fs::create_dir(state.join("gcroots")).is_ok() {
+            eprintln!("skipping per_user_roots_not_writable: root can write anyway");
+            return Ok(());
+        }
+
+        let error = roots
+            .add_per_user("shell_gc_root", &tmp.path().join("gc_root/shell_gc_root"))
+            .unwrap_err();
+        assert!(error.per_user_unavailable(), "{}", error);
+        Ok(())
+    }
+
+    #[test]
+    fn rootless_errors() {
+        let io = |errno| AddRootError::Io(std::io::Error::from_raw_os_error(errno), String::new());
+        assert!(io(libc::EACCES).per_user_unavailable());
+        assert!(io(libc::EPERM).per_user_unavailable());
+        assert!(io(libc::EROFS).per_user_unavailable());
+        assert!(!io(libc::ENOSPC).per_user_unavailable());
+        assert!(AddRootError::NoUser.per_user_unavailable());
+    }
+
+    /// Roots into a chroot store are registered in that store’s
+    /// state directory, and not in the host’s `/nix/var/nix`.
+    #[test]
+    fn indirect_root_in_chroot_store() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let store = Store::Uri(format!("local?root={}", tmp.path().join("store").display()));
+        let gc_root_path = tmp.path().join("gc_root");
+        std::fs::create_dir_all(&gc_root_path)?;
+
+        let file = tmp.path().join("content");
+        std::fs::write(&file, "some content")?;
+        let added = Command::new("nix-store")
+            .args(store.args())
+            .arg("--add")
+            .arg(&file)
+            .output()?;
+        assert!(added.status.success(), "{:?}", added);
+        let store_path = StorePath::from(std::ffi::OsStr::new(
+            String::from_utf8_lossy(&added.stdout).trim(),
+        ));
+
+        let roots = Roots {
+            gc_root_path: gc_root_path.clone(),
+            id: String::from("test"),
+            store: store.clone(),
+            shells: vec![],
+        };
+        let root = roots.add("shell_gc_root", &store_path).unwrap();
+        assert_eq!(
+            std::fs::read_link(root.as_os_str())?,
+            store_path.as_path().to_path_buf()
+        );
+
+        let auto_roots = store.state_dir().join("gcroots/auto");
+        let registered = std::fs::read_dir(&auto_roots)?
+            .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+            .any(|target| target == gc_root_path.join("shell_gc_root"));
+        assert!(
+            registered,
+            "root not registered in {}",
+            auto_roots.display()
+        );
+        Ok(())
+    }
+}
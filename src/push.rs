@@ -0,0 +1,173 @@
+//! Push build results to a binary cache with a command of the
+//! user’s choosing, like `nix copy --to s3://…` or `attic push`
+//! (see `project::config::PushConfig`). Pushing to cachix is
+//! configured on its own, see `cachix`.
+
+use crate::nix;
+use crate::nix::StorePath;
+use crate::project::config::PushConfig;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+/// The outcome of running the push command after a build.
+#[derive(Clone, Debug)]
+pub struct PushOutcome {
+    /// The command, with its arguments (but without `paths`).
+    pub command: Vec<String>,
+    /// The store paths which were pushed.
+    pub paths: Vec<StorePath>,
+    /// `Err` with an explanation if the command failed.
+    pub result: Result<(), String>,
+}
+
+/// Run the push command configured in `config`, with `paths`
+/// appended to its arguments.
+///
+/// Returns `None` if no push command is configured.
+pub fn push(config: &PushConfig, paths: Vec<StorePath>) -> Option<PushOutcome> {
+    let command = match config.command {
+        Some(ref command) if !command.is_empty() => command.clone(),
+        _ => return None,
+    };
+
+    let mut cmd = Command::new(&command[0]);
+    cmd.args(&command[1..])
+        .args(paths.iter().map(|path| path.as_path()))
+        .stdout(Stdio::null());
+    nix::non_interactive(&mut cmd);
+
+    debug!("$ {:?}", cmd);
+    let result = match cmd.output() {
+        Err(e) => Err(format!("could not run {}: {}", command[0], e)),
+        Ok(ref output) if !output.status.success() => Err(format!(
+            "{} exited with {}:\n{}",
+            command[0],
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )),
+        Ok(_) => Ok(()),
+    };
+    Some(PushOutcome {
+        command,
+        paths,
+        result,
+    })
+}
+
+/// Runs the pushes of a project one at a time, in the background.
+/// While a push runs, only the latest one requested since waits:
+/// the ones before it would push environments a newer build
+/// replaced.
+#[derive(Clone, Default)]
+pub struct Queue {
+    state: Arc<Mutex<QueueState>>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    /// Whether a push is running.
+    running: bool,
+    /// The push to run after it.
+    next: Option<(PushConfig, Vec<StorePath>)>,
+}
+
+impl Queue {
+    /// Run `push` with `config` and `paths` once the running push
+    /// (if any) is done, and call `done` with the outcome.
+    pub fn push<F>(&self, config: PushConfig, paths: Vec<StorePath>, done: F)
+    where
+        F: Fn(PushOutcome) + Send + 'static,
+    {
+        {
+            let mut state = self.state.lock().expect("push queue lock poisoned");
+            if state.running {
+                if state.next.replace((config, paths)).is_some() {
+                    debug!("skipping a push, a newer build replaced its environment");
+                }
+                return;
+            }
+            state.running = true;
+        }
+        let state = self.state.clone();
+        std::thread::spawn(move || {
+            let mut next = Some((config, paths));
+            while let Some((config, paths)) = next {
+                if let Some(outcome) = push(&config, paths) {
+                    done(outcome);
+                }
+                let mut state = state.lock().expect("push queue lock poisoned");
+                next = state.next.take();
+                state.running = next.is_some();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{push, Queue};
+    use nix::StorePath;
+    use project::config::PushConfig;
+    use std::ffi::OsStr;
+
+    fn config(command: &[&str]) -> PushConfig {
+        PushConfig {
+            command: Some(command.iter().map(|arg| arg.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn not_configured() {
+        assert!(push(&PushConfig::default(), vec![]).is_none());
+        assert!(push(&config(&[]), vec![]).is_none());
+    }
+
+    #[test]
+    fn paths_are_appended() {
+        let paths = vec![StorePath::from(OsStr::new("/nix/store/abc-env"))];
+        let outcome = push(
+            &config(&["sh", "-c", "test \"$1\" = /nix/store/abc-env", "sh"]),
+            paths,
+        )
+        .unwrap();
+        assert_eq!(outcome.result, Ok(()));
+
+        let outcome = push(&config(&["sh", "-c", "echo nope >&2; exit 3"]), vec![]).unwrap();
+        let error = outcome.result.unwrap_err();
+        assert!(error.starts_with("sh exited with"), "{}", error);
+        assert!(error.ends_with("nope\n"), "{}", error);
+    }
+
+    #[test]
+    fn one_push_at_a_time() {
+        let temp = tempfile::tempdir().unwrap();
+        let log = temp.path().join("log");
+        // fails if another push is running
+        let script = format!(
+            "mkdir \"{0}.lock\" && sleep 0.2 && echo \"$1\" >> \"{0}\" && rmdir \"{0}.lock\"",
+            log.display()
+        );
+        let queue = Queue::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+        for build in 1..=4 {
+            let tx = tx.clone();
+            queue.push(
+                config(&["sh", "-c", &script, "sh"]),
+                vec![StorePath::from(OsStr::new(&format!(
+                    "/nix/store/{}-env",
+                    build
+                )))],
+                move |outcome| tx.send(outcome).unwrap(),
+            );
+        }
+        drop(tx);
+        let outcomes: Vec<_> = rx.iter().collect();
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(|outcome| outcome.result.is_ok()));
+        // the pushes of builds 2 and 3 were skipped
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "/nix/store/1-env\n/nix/store/4-env\n"
+        );
+    }
+}
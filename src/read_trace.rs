@@ -0,0 +1,160 @@
+//! Strict input tracking: trace the files nix reads during a build
+//! with `strace`, and find the reads which lorri doesn’t watch.
+//!
+//! lorri learns about the inputs of a build from nix’s log output,
+//! which misses some kinds of reads (for example by builtins which
+//! don’t log). Every untracked read is a change that won’t trigger
+//! a rebuild. Tracing is slow, so it is opt-in, see
+//! `project::config::WatchConfig::strict`.
+
+use regex::Regex;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The system calls which read (or look for) files.
+const TRACED_CALLS: &str = "trace=open,openat,stat,lstat,newfstatat,statx,readlink,access";
+
+/// Prefixes of paths nix reads that are not inputs of the build.
+/// (`/build` is where local builds run in the nix sandbox.)
+const IGNORED_PREFIXES: &[&str] = &[
+    "/nix/", "/proc/", "/dev/", "/sys/", "/etc/", "/tmp/", "/build/",
+];
+
+lazy_static! {
+    /// Whether `strace` can be run.
+    static ref STRACE_AVAILABLE: bool = Command::new("strace").arg("-V").output().is_ok();
+}
+
+/// A command which runs `program` under `strace`, writing the
+/// trace to `trace_file`. `None` if `strace` is not installed.
+pub fn command(program: &str, trace_file: &Path) -> Option<Command> {
+    if !*STRACE_AVAILABLE {
+        return None;
+    }
+    let mut cmd = Command::new("strace");
+    cmd.args(&["-f", "-qq", "-e", TRACED_CALLS, "-o"])
+        .arg(trace_file)
+        .arg("--")
+        .arg(program);
+    Some(cmd)
+}
+
+/// The absolute paths successfully accessed according to the
+/// trace in `trace_file`.
+pub fn read(trace_file: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(parse(&fs::read_to_string(trace_file)?))
+}
+
+/// Parse `strace` output like
+/// `1234  openat(AT_FDCWD, "/home/user/shell.nix", O_RDONLY) = 3`
+/// into the paths of the successful calls.
+fn parse(trace: &str) -> Vec<PathBuf> {
+    lazy_static! {
+        static ref CALL: Regex =
+            Regex::new(r#"^(?:\d+\s+)?\w+\((?:AT_FDCWD, )?"((?:[^"\\]|\\.)*)".*\) = (-?\d+)"#)
+                .expect("invalid regex!");
+    }
+    let mut paths: Vec<PathBuf> = trace
+        .lines()
+        .filter_map(|line| CALL.captures(line))
+        .filter(|call| !call[2].starts_with('-'))
+        .map(|call| PathBuf::from(call[1].replace("\\\"", "\"").replace("\\\\", "\\")))
+        .filter(|path| path.is_absolute())
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// The user’s directories nix reads for itself, not for the build:
+/// its configuration and cache (`~/.config/nix` and `~/.cache/nix`,
+/// or below `$XDG_CONFIG_HOME` and `$XDG_CACHE_HOME`).
+fn nix_user_dirs() -> Vec<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    let xdg = |var: &str, default: &str| {
+        std::env::var_os(var)
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| home.as_ref().map(|home| home.join(default)))
+            .map(|dir| dir.join("nix"))
+    };
+    xdg("XDG_CONFIG_HOME", ".config")
+        .into_iter()
+        .chain(xdg("XDG_CACHE_HOME", ".cache"))
+        .collect()
+}
+
+/// The paths in `reads` which are neither `watched` (or inside a
+/// watched directory), nor ignored as not being build inputs (like
+/// nix’s own configuration and cache, see `nix_user_dirs`).
+/// `ignored` are additional directories to ignore, like lorri’s
+/// own state.
+pub fn untracked(reads: &[PathBuf], watched: &[PathBuf], ignored: &[PathBuf]) -> Vec<PathBuf> {
+    let nix_user_dirs = nix_user_dirs();
+    reads
+        .iter()
+        .filter(|read| {
+            !IGNORED_PREFIXES
+                .iter()
+                .any(|prefix| read.starts_with(prefix))
+                && !nix_user_dirs.iter().any(|dir| read.starts_with(dir))
+                && !ignored.iter().any(|dir| read.starts_with(dir))
+                && !watched.iter().any(|path| read.starts_with(path))
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nix_user_dirs, parse, untracked};
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_trace() {
+        let trace =
+            r#"4242  openat(AT_FDCWD, "/home/user/project/shell.nix", O_RDONLY|O_CLOEXEC) = 3
+4242  stat("/home/user/project/missing.nix", 0x7ffd) = -1 ENOENT (No such file or directory)
+4243  lstat("/home/user/project/src", {st_mode=S_IFDIR|0755, st_size=4096, ...}) = 0
+4243  openat(AT_FDCWD, "relative.nix", O_RDONLY) = 4
+4243  openat(AT_FDCWD, "/home/user/project/shell.nix", O_RDONLY|O_CLOEXEC) = 5
+4243  +++ exited with 0 +++"#;
+        assert_eq!(
+            parse(trace),
+            vec![
+                PathBuf::from("/home/user/project/shell.nix"),
+                PathBuf::from("/home/user/project/src"),
+            ]
+        );
+    }
+
+    #[test]
+    fn untracked_reads() {
+        let mut reads = vec![
+            PathBuf::from("/etc/nix/nix.conf"),
+            PathBuf::from("/home/user/.cache/lorri/cas/abc"),
+            PathBuf::from("/home/user/project/shell.nix"),
+            PathBuf::from("/home/user/project/src/main.rs"),
+            PathBuf::from("/home/user/secrets.json"),
+            PathBuf::from("/nix/store/abc-nixpkgs/default.nix"),
+        ];
+        // nix reads its own configuration and cache
+        for dir in nix_user_dirs() {
+            reads.push(dir.join("nix.conf"));
+        }
+        assert!(!nix_user_dirs().is_empty());
+        assert_eq!(
+            untracked(
+                &reads,
+                &[
+                    PathBuf::from("/home/user/project/shell.nix"),
+                    PathBuf::from("/home/user/project/src"),
+                ],
+                &[PathBuf::from("/home/user/.cache/lorri")]
+            ),
+            vec![PathBuf::from("/home/user/secrets.json")]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Detect inputs whose modification time lies in the future.
+//!
+//! Network filesystems whose server clock runs ahead of ours (and
+//! tools which set mtimes carelessly) leave files with mtimes in the
+//! future. Anything comparing mtimes to decide whether a file is
+//! stale gets confused by them, and on such filesystems filesystem
+//! notifications are unreliable, too. lorri tracks these inputs by
+//! their content hash instead (see `watch::Watch::extend_hashed`),
+//! and reports how far ahead they are.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Mtimes this far in the future are still fine; filesystems round
+/// timestamps, and a file can be written while we look at it.
+pub const TOLERANCE: Duration = Duration::from_secs(1);
+
+/// An input whose mtime is in the future.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skewed {
+    /// The input.
+    pub path: PathBuf,
+    /// How far its mtime is ahead of `now`.
+    pub ahead: Duration,
+}
+
+/// The `paths` whose mtime is more than `TOLERANCE` ahead of `now`,
+/// each logged. Paths which can’t be read are skipped.
+pub fn ahead_of(paths: &[PathBuf], now: SystemTime) -> Vec<Skewed> {
+    paths
+        .iter()
+        .filter_map(|path| {
+            let ahead = mtime(path)?.duration_since(now).ok()?;
+            if ahead <= TOLERANCE {
+                return None;
+            }
+            info!(
+                "the mtime of {} is {}s in the future, tracking it by content hash",
+                path.display(),
+                ahead.as_secs()
+            );
+            Some(Skewed {
+                path: path.clone(),
+                ahead,
+            })
+        })
+        .collect()
+}
+
+/// The largest skew of `skewed`, if any.
+pub fn max_ahead(skewed: &[Skewed]) -> Option<Duration> {
+    skewed.iter().map(|skewed| skewed.ahead).max()
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate nix;
+
+    use self::nix::sys::stat::utimes;
+    use self::nix::sys::time::{TimeVal, TimeValLike};
+    use super::{ahead_of, max_ahead};
+    use std::fs;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn future_mtimes() -> std::io::Result<()> {
+        let tmp = tempfile::tempdir()?;
+        // whole seconds, which `utimes` sets exactly
+        let now = UNIX_EPOCH
+            + Duration::from_secs(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            );
+        let path = |name: &str| tmp.path().join(name);
+        for name in ["past", "now", "future"].iter() {
+            fs::write(path(name), "")?;
+        }
+        let set_mtime = |name: &str, time: SystemTime| {
+            let time = TimeVal::seconds(time.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64);
+            utimes(&path(name), &time, &time)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        };
+        set_mtime("past", now - Duration::from_secs(3600))?;
+        set_mtime("now", now)?;
+        set_mtime("future", now + Duration::from_secs(120))?;
+
+        let paths = vec![path("past"), path("now"), path("future"), path("missing")];
+        let skewed = ahead_of(&paths, now);
+        assert_eq!(skewed.len(), 1);
+        assert_eq!(skewed[0].path, path("future"));
+        assert_eq!(max_ahead(&skewed), Some(Duration::from_secs(120)));
+        assert_eq!(max_ahead(&[]), None);
+        Ok(())
+    }
+}
@@ -8,11 +8,16 @@
 //!
 //! `client` implements a set of clients specialized to the communications
 //! we support.
+//!
+//! Clients and daemons of different versions talk to each other, so
+//! messages only ever get new variants and fields; the encoding of
+//! every released version is kept in `tests/compat/golden`.
 
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 
 use crate::socket::path::{BindError, BindLock, SocketPath};
-use crate::socket::{ReadWriteError, ReadWriter, Timeout};
+use crate::socket::{ReadError, ReadWriteError, ReadWriter, Timeout};
 use crate::NixFile;
 
 /// We declare 1s as the time readers should wait
@@ -20,12 +25,178 @@ use crate::NixFile;
 pub const DEFAULT_READ_TIMEOUT: Timeout = Timeout::from_millis(1000);
 
 /// Enum of all communication modes the lorri daemon supports.
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum CommunicationType {
     /// Ping the daemon from a project to tell it to watch & evaluate
     // TODO: rename to IndicateActivity (along with all other `ping` things)
     // issue: https://github.com/target/lorri/issues/101
     Ping,
+    /// Cancel the running build of a project
+    CancelBuild,
+    /// Wait until no builds are pending or running
+    WaitIdle,
+    /// Read the log of a project’s builds as it is written
+    FollowLog,
+    /// Listen to the events of all build loops
+    StreamEvents,
+    /// Like `StreamEvents`, resuming after the last event a client
+    /// saw before it reconnected
+    Monitor,
+    /// List the projects the daemon watches
+    ListProjects,
+    /// Stop the daemon once its running builds are done
+    Shutdown,
+    /// Summarize the last build of a project
+    Status,
+    /// Report the resources the daemon uses
+    Stats,
+    /// Build a project now, even if none of its inputs changed
+    Rebuild,
+    /// Report how long builds took to start after a change
+    Latency,
+    /// Like `Monitor`, optionally with the lines nix prints while
+    /// building
+    Subscribe,
+    /// Like `Subscribe`, resuming after an event of the daemon’s
+    /// epoch only (see `event_stream`)
+    Resume,
+    /// Like `Rebuild`, answered with the id of the build
+    RequestBuild,
+    /// Like `Ping`, for one attribute of the nix file
+    PingAttribute,
+    /// Like `WaitIdle`, giving up after a while
+    WaitIdleFor,
+    /// A communication type added in a newer version of lorri.
+    /// Never sent, and rejected by `Listener::accept`.
+    // variants are encoded by their position, so this stays last
+    #[serde(skip_serializing)]
+    Unknown,
+}
+
+/// Names of the `CommunicationType`s we know, by variant index.
+/// New variants have to be added here and in `deserialize` below.
+const COMMUNICATION_TYPES: &[&str] = &[
+    "Ping",
+    "CancelBuild",
+    "WaitIdle",
+    "FollowLog",
+    "StreamEvents",
+    "Monitor",
+    "ListProjects",
+    "Shutdown",
+    "Status",
+    "Stats",
+    "Rebuild",
+    "Latency",
+    "Subscribe",
+    "Resume",
+    "RequestBuild",
+    "PingAttribute",
+    "WaitIdleFor",
+];
+
+/// Like the derived implementation, but decodes variants
+/// it doesn’t know as `CommunicationType::Unknown`.
+/// (`#[serde(other)]` only works for self-describing formats).
+impl<'de> serde::Deserialize<'de> for CommunicationType {
+    fn deserialize<D>(deserializer: D) -> Result<CommunicationType, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::{EnumAccess, Error, VariantAccess, Visitor};
+
+        struct Variant(CommunicationType);
+
+        impl<'de> serde::Deserialize<'de> for Variant {
+            fn deserialize<D>(deserializer: D) -> Result<Variant, D::Error>
+            where
+                D: serde::Deserializer<'de>,
+            {
+                deserializer.deserialize_identifier(VariantVisitor)
+            }
+        }
+
+        struct VariantVisitor;
+
+        impl<'de> Visitor<'de> for VariantVisitor {
+            type Value = Variant;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a communication type")
+            }
+
+            fn visit_u64<E: Error>(self, index: u64) -> Result<Variant, E> {
+                Ok(Variant(match index {
+                    0 => CommunicationType::Ping,
+                    1 => CommunicationType::CancelBuild,
+                    2 => CommunicationType::WaitIdle,
+                    3 => CommunicationType::FollowLog,
+                    4 => CommunicationType::StreamEvents,
+                    5 => CommunicationType::Monitor,
+                    6 => CommunicationType::ListProjects,
+                    7 => CommunicationType::Shutdown,
+                    8 => CommunicationType::Status,
+                    9 => CommunicationType::Stats,
+                    10 => CommunicationType::Rebuild,
+                    11 => CommunicationType::Latency,
+                    12 => CommunicationType::Subscribe,
+                    13 => CommunicationType::Resume,
+                    14 => CommunicationType::RequestBuild,
+                    15 => CommunicationType::PingAttribute,
+                    16 => CommunicationType::WaitIdleFor,
+                    _ => CommunicationType::Unknown,
+                }))
+            }
+
+            fn visit_str<E: Error>(self, name: &str) -> Result<Variant, E> {
+                Ok(Variant(match name {
+                    "Ping" => CommunicationType::Ping,
+                    "CancelBuild" => CommunicationType::CancelBuild,
+                    "WaitIdle" => CommunicationType::WaitIdle,
+                    "FollowLog" => CommunicationType::FollowLog,
+                    "StreamEvents" => CommunicationType::StreamEvents,
+                    "Monitor" => CommunicationType::Monitor,
+                    "ListProjects" => CommunicationType::ListProjects,
+                    "Shutdown" => CommunicationType::Shutdown,
+                    "Status" => CommunicationType::Status,
+                    "Stats" => CommunicationType::Stats,
+                    "Rebuild" => CommunicationType::Rebuild,
+                    "Latency" => CommunicationType::Latency,
+                    "Subscribe" => CommunicationType::Subscribe,
+                    "Resume" => CommunicationType::Resume,
+                    "RequestBuild" => CommunicationType::RequestBuild,
+                    "PingAttribute" => CommunicationType::PingAttribute,
+                    "WaitIdleFor" => CommunicationType::WaitIdleFor,
+                    _ => CommunicationType::Unknown,
+                }))
+            }
+        }
+
+        struct CommunicationTypeVisitor;
+
+        impl<'de> Visitor<'de> for CommunicationTypeVisitor {
+            type Value = CommunicationType;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "enum CommunicationType")
+            }
+
+            fn visit_enum<A>(self, data: A) -> Result<CommunicationType, A::Error>
+            where
+                A: EnumAccess<'de>,
+            {
+                let (Variant(comm_type), variant) = data.variant()?;
+                variant.unit_variant()?;
+                Ok(comm_type)
+            }
+        }
+
+        deserializer.deserialize_enum(
+            "CommunicationType",
+            COMMUNICATION_TYPES,
+            CommunicationTypeVisitor,
+        )
+    }
 }
 
 /// Message sent by the client to ask the server to start
@@ -36,6 +207,388 @@ pub struct Ping {
     pub nix_file: NixFile,
 }
 
+/// Message sent by the client to ask the server to start watching
+/// the attribute path `attribute` of `nix_file`, like `Ping`. Every
+/// attribute of a nix file is a project of its own.
+/// See `CommunicationType::PingAttribute`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PingAttribute {
+    /// The nix file to watch and build on changes.
+    pub nix_file: NixFile,
+    /// The attribute path of the nix file to build (see
+    /// `nix::attribute_path`).
+    pub attribute: String,
+}
+
+/// The daemon’s answer to `Ping` and `PingAttribute`. Daemons
+/// before protocol version 10 don’t answer `Ping`, they close the
+/// connection (see `client::Error::is_hang_up`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PingResult {
+    /// The daemon watches the nix file.
+    Registered,
+    /// The daemon can’t watch the nix file.
+    Refused(RegistrationError),
+}
+
+/// Why the daemon refused to watch a nix file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RegistrationError {
+    /// The path of the nix file is not absolute.
+    RelativePath,
+    /// The nix file doesn’t exist.
+    NotFound,
+    /// The daemon may not read the nix file.
+    PermissionDenied,
+    /// The path is not a regular file, but a directory for example.
+    NotAFile,
+    /// Reading the nix file failed otherwise.
+    Io(String),
+    /// The attribute path of a `PingAttribute` is invalid.
+    InvalidAttribute(String),
+    /// The daemon could not set up the project (its GC root
+    /// directory, for example).
+    Setup(String),
+}
+
+impl std::fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RegistrationError::RelativePath => write!(f, "the path of the nix file is relative"),
+            RegistrationError::NotFound => write!(f, "the nix file does not exist"),
+            RegistrationError::PermissionDenied => {
+                write!(f, "the daemon is not allowed to read the nix file")
+            }
+            RegistrationError::NotAFile => write!(f, "the nix file is not a regular file"),
+            RegistrationError::Io(e) => write!(f, "the nix file cannot be read: {}", e),
+            RegistrationError::InvalidAttribute(e) => write!(f, "{}", e),
+            RegistrationError::Setup(e) => write!(f, "the project cannot be set up: {}", e),
+        }
+    }
+}
+
+/// Message sent by the client to cancel the running build of
+/// `nix_file`. See `CommunicationType::CancelBuild`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelBuild {
+    /// The nix file whose build to cancel.
+    pub nix_file: NixFile,
+}
+
+/// The daemon’s answer to `CancelBuild`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CancelBuildResult {
+    /// Whether a build was running (and is now cancelled).
+    pub cancelled: bool,
+}
+
+/// Message sent by the client to wait until the daemon has no
+/// pending or running builds. See `CommunicationType::WaitIdle`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaitIdle {
+    /// Only wait for the builds of this nix file.
+    pub nix_file: Option<NixFile>,
+}
+
+/// The daemon’s answer to `WaitIdle`, sent once it is idle.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WaitIdleResult {
+    /// Whether the daemon watches the nix file of the request
+    /// (always true if no nix file was given).
+    pub watched: bool,
+}
+
+/// Message sent by the client to wait until the daemon has no
+/// pending or running builds, for at most `max_wait_ms`
+/// milliseconds. See `CommunicationType::WaitIdleFor`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaitIdleFor {
+    /// Only wait for the builds of this nix file.
+    pub nix_file: Option<NixFile>,
+    /// How long to wait at most.
+    pub max_wait_ms: u64,
+}
+
+/// The daemon’s answer to `WaitIdleFor`, sent once it is idle or
+/// `max_wait_ms` passed.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WaitIdleForResult {
+    /// Whether the daemon watches the nix file of the request
+    /// (always true if no nix file was given).
+    pub watched: bool,
+    /// Whether the builds finished within `max_wait_ms`.
+    pub idle: bool,
+}
+
+/// Message sent by the client to read the log of the current (or
+/// most recent) build of `nix_file`. See `CommunicationType::FollowLog`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FollowLog {
+    /// The nix file whose builds to follow.
+    pub nix_file: NixFile,
+    /// Keep following the logs of the next builds, instead of
+    /// stopping after the current one.
+    pub follow: bool,
+}
+
+/// The daemon answers `FollowLog` with a stream of these, until the
+/// (last) build finished or the client hangs up.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LogMessage {
+    /// The daemon doesn’t watch the nix file; nothing follows.
+    NotWatched,
+    /// A build started.
+    Started,
+    /// A line the build printed.
+    Line(String),
+    /// The build finished.
+    Finished,
+}
+
+/// Message sent by the client to listen to build events.
+/// See `CommunicationType::StreamEvents`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamEvents {
+    /// Only the events of this nix file.
+    pub nix_file: Option<NixFile>,
+}
+
+/// Message sent by the client to listen to build events, after the
+/// event with sequence number `since` (every event line has its
+/// `sequence`, see `event_stream`).
+/// See `CommunicationType::Monitor`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Monitor {
+    /// Only the events of this nix file.
+    pub nix_file: Option<NixFile>,
+    /// Resume after this event; only new events if not set.
+    pub since: Option<u64>,
+}
+
+/// Message sent by the client to listen to build events, like
+/// `Monitor`. See `CommunicationType::Subscribe`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Subscribe {
+    /// Only the events of this nix file.
+    pub nix_file: Option<NixFile>,
+    /// Resume after this event; only new events if not set.
+    pub since: Option<u64>,
+    /// Also send the `log-line` events, which `StreamEvents` and
+    /// `Monitor` never send. They have no sequence number, and are
+    /// not replayed.
+    pub log_lines: bool,
+}
+
+/// Message sent by the client to listen to build events, like
+/// `Subscribe`, after the event with sequence number `since` of
+/// `epoch` (every event line has its `epoch`). If the daemon
+/// restarted since, it replays the events it keeps after a `Gap`.
+/// See `CommunicationType::Resume`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Resume {
+    /// Only the events of this nix file.
+    pub nix_file: Option<NixFile>,
+    /// Resume after this event; only new events if not set.
+    pub since: Option<u64>,
+    /// The epoch of the event `since`.
+    pub epoch: Option<u64>,
+    /// Also send the `log-line` events, see `Subscribe`.
+    pub log_lines: bool,
+}
+
+/// The daemon answers `StreamEvents`, `Monitor`, `Subscribe` and
+/// `Resume` with a stream of these, until the client hangs up. A
+/// client which reads too slowly misses events, or is disconnected
+/// (see `event_stream::SlowListeners`).
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventMessage {
+    /// An event, as a JSON line (see `event_sink::to_json_line`).
+    Event(String),
+    /// The client missed this many events because it read too slowly.
+    Gap {
+        /// The number of missed events.
+        dropped: u64,
+    },
+}
+
+/// Message sent by the client to list the projects the daemon
+/// watches. See `CommunicationType::ListProjects`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListProjects {}
+
+/// The daemon’s answer to `ListProjects`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListProjectsResult {
+    /// The watched projects, sorted by nix file.
+    pub projects: Vec<WatchedProject>,
+}
+
+/// Message sent by the client to stop the daemon: it stops
+/// listening, waits for its running builds and removes its socket.
+/// See `CommunicationType::Shutdown`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Shutdown {
+    /// Cancel the running builds instead of waiting for them.
+    pub cancel_builds: bool,
+}
+
+/// The daemon’s answer to `Shutdown`, sent before it stops.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShutdownResult {
+    /// The number of builds pending or running; the daemon stops
+    /// once they are done (or cancelled).
+    pub running_builds: u64,
+}
+
+/// Message sent by the client to ask for the state of a project.
+/// See `CommunicationType::Status`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+}
+
+/// The daemon’s answer to `Status`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusResult {
+    /// The project, if the daemon watches it.
+    pub project: Option<ProjectStatus>,
+}
+
+/// Message sent by the client to ask for the resources the daemon
+/// uses. See `CommunicationType::Stats`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {}
+
+/// The daemon’s answer to `Stats`.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsResult {
+    /// The number of projects the daemon watches.
+    pub projects: u64,
+    /// The file descriptors the daemon has open.
+    pub open_fds: u64,
+    /// The most file descriptors the daemon had open at once.
+    pub peak_open_fds: u64,
+    /// The soft `RLIMIT_NOFILE` of the daemon, if it has one.
+    pub fd_soft_limit: Option<u64>,
+    /// The hard `RLIMIT_NOFILE` of the daemon, if it has one.
+    pub fd_hard_limit: Option<u64>,
+}
+
+/// Message sent by the client to build a project now. The daemon
+/// answers like it does a `Ping`; projects it doesn’t watch yet
+/// are registered, which builds them.
+/// See `CommunicationType::Rebuild`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rebuild {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+}
+
+/// Message sent by the client to build a project now, like
+/// `Rebuild`. See `CommunicationType::RequestBuild`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestBuild {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+}
+
+/// The daemon’s answer to `RequestBuild`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RequestBuildResult {
+    /// The build with this id (see `build_loop::BuildId`) starts
+    /// once the running build is done; its events have it as their
+    /// `build_id`.
+    Requested {
+        /// The id of the build.
+        build_id: u64,
+    },
+    /// The daemon can’t build the nix file.
+    Refused(RegistrationError),
+}
+
+/// Message sent by the client to ask how long builds took to start
+/// after a change of their inputs (see `build_loop::Event::Started`).
+/// See `CommunicationType::Latency`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Latency {}
+
+/// The daemon’s answer to `Latency`, over the builds of all projects
+/// since the daemon started.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyResult {
+    /// The number of builds a change started.
+    pub builds: u64,
+    /// The latency of the last of them, in milliseconds.
+    pub last_ms: u64,
+    /// The sum of their latencies, in milliseconds.
+    pub total_ms: u64,
+    /// The highest latency of them, in milliseconds.
+    pub max_ms: u64,
+}
+
+impl LatencyResult {
+    /// Count a build which started `latency` after a change.
+    pub fn record(&mut self, latency: std::time::Duration) {
+        let ms = latency.as_millis() as u64;
+        self.builds += 1;
+        self.last_ms = ms;
+        self.total_ms += ms;
+        self.max_ms = std::cmp::max(self.max_ms, ms);
+    }
+
+    /// The mean latency in milliseconds, if any build was counted.
+    pub fn mean_ms(&self) -> Option<u64> {
+        self.total_ms.checked_div(self.builds)
+    }
+}
+
+/// The state of a watched project, with details of its last build.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProjectStatus {
+    /// See `ListProjects`.
+    pub project: WatchedProject,
+    /// When the last build started, in milliseconds since the Unix
+    /// epoch.
+    pub build_started: Option<u64>,
+    /// How long the last finished build took, in milliseconds.
+    pub build_duration: Option<u64>,
+    /// The GC roots of the shells of the last successful build.
+    pub output_paths: Vec<PathBuf>,
+    /// How many files the last successful build is watched for
+    /// (including those tracked by content hash).
+    pub watched_files: u64,
+}
+
+/// A project the daemon watches.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchedProject {
+    /// The nix file the project was pinged with.
+    pub nix_file: NixFile,
+    /// The outcome of the project’s last build event.
+    pub state: BuildState,
+    /// When the last build finished, in seconds since the Unix epoch.
+    pub last_build: Option<u64>,
+    /// The GC root of the project’s shell.
+    pub gc_root: PathBuf,
+}
+
+/// The state of a watched project, after its last build event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildState {
+    /// Nothing was built yet.
+    Pending,
+    /// A build is running.
+    Building,
+    /// The last build succeeded.
+    Success,
+    /// The last build failed.
+    Failure,
+    /// The last build was cancelled.
+    Cancelled,
+}
+
 /// No message can be sent through this socket end (empty type).
 pub enum NoMessage {}
 
@@ -75,6 +628,9 @@ pub mod listener {
         Accept(std::io::Error),
         /// The client’s message could not be decoded.
         Message(ReadWriteError),
+        /// The client asked for a communication type this
+        /// daemon doesn’t know (it is newer than we are).
+        UnknownCommunicationType,
     }
 
     impl Listener {
@@ -105,16 +661,25 @@ pub mod listener {
         {
             // - socket accept
             let (unix_stream, _) = self.listener.accept().map_err(AcceptError::Accept)?;
-            // - read first message as a `CommunicationType`
-            let comm_type: CommunicationType =
-                ReadWriter::<CommunicationType, ConnectionAccepted>::new(&unix_stream)
-                    .react(self.accept_timeout.clone(), |_| ConnectionAccepted())
-                    .map_err(AcceptError::Message)?;
+            let comm_type = {
+                let mut rw = ReadWriter::<CommunicationType, ConnectionAccepted>::new(&unix_stream);
+                // - read first message as a `CommunicationType`
+                let comm_type = rw
+                    .read(&self.accept_timeout)
+                    .map_err(|e| AcceptError::Message(ReadWriteError::R(e)))?;
+                // - acknowledge only what we can handle, a newer
+                //   client sees us hang up instead
+                if comm_type == CommunicationType::Unknown {
+                    return Err(AcceptError::UnknownCommunicationType);
+                }
+                rw.write(&self.accept_timeout, &ConnectionAccepted())
+                    .map_err(|e| AcceptError::Message(ReadWriteError::W(e)))?;
+                comm_type
+            };
             // spawn a thread with the accept handler
             Ok(std::thread::spawn(move || handler(unix_stream, comm_type)))
         }
     }
-
 }
 
 /// Clients that can talk to a `Listener`.
@@ -149,6 +714,17 @@ pub mod client {
         Message(ReadWriteError),
     }
 
+    impl Error {
+        /// Whether the `Listener` closed the connection instead of
+        /// answering, like daemons which don’t answer a message yet.
+        pub fn is_hang_up(&self) -> bool {
+            match self {
+                Error::Message(ReadWriteError::R(ReadError::Deserialize(e))) => is_eof(e),
+                _ => false,
+            }
+        }
+    }
+
     /// Error when initializing connection with the `Listener`.
     #[derive(Debug)]
     pub enum InitError {
@@ -206,6 +782,36 @@ pub mod client {
                 .map_err(|e| Error::Message(ReadWriteError::R(e)))
         }
 
+        /// Write a message to the connected `Listener` and
+        /// read its answer.
+        pub fn request(self, mes: &W) -> Result<R, Error>
+        where
+            R: serde::de::DeserializeOwned,
+            W: serde::Serialize,
+        {
+            let sock = &self.socket.ok_or(Error::NotConnected)?;
+            let mut rw: ReadWriter<R, W> = ReadWriter::new(sock);
+            rw.communicate(self.timeout, mes).map_err(Error::Message)
+        }
+
+        /// Write a message to the connected `Listener` and return
+        /// its answers, until it closes the connection.
+        /// Reading each answer is bounded by the timeout.
+        pub fn request_stream(self, mes: &W) -> Result<Answers<R>, Error>
+        where
+            W: serde::Serialize,
+        {
+            let sock = self.socket.ok_or(Error::NotConnected)?;
+            ReadWriter::<R, W>::new(&sock)
+                .write(&self.timeout, mes)
+                .map_err(|e| Error::Message(ReadWriteError::W(e)))?;
+            Ok(Answers {
+                socket: sock,
+                timeout: self.timeout,
+                read_type: PhantomData,
+            })
+        }
+
         /// Write a message to the connected `Listener`.
         pub fn write(self, mes: &W) -> Result<(), Error>
         where
@@ -218,10 +824,147 @@ pub mod client {
         }
     }
 
+    /// The answers of a `Listener` to a request, see
+    /// `Client::request_stream`.
+    pub struct Answers<R> {
+        socket: UnixStream,
+        timeout: Timeout,
+        read_type: PhantomData<R>,
+    }
+
+    impl<R> Answers<R> {
+        /// A handle on the connection, to close it from another
+        /// thread with `UnixStream::shutdown`, which ends the answers.
+        pub fn closer(&self) -> std::io::Result<UnixStream> {
+            self.socket.try_clone()
+        }
+    }
+
+    impl<R> Iterator for Answers<R>
+    where
+        R: serde::de::DeserializeOwned,
+    {
+        type Item = Result<R, Error>;
+
+        fn next(&mut self) -> Option<Result<R, Error>> {
+            let rw: ReadWriter<R, NoMessage> = ReadWriter::new(&self.socket);
+            match rw.read(&self.timeout) {
+                Ok(answer) => Some(Ok(answer)),
+                Err(ReadError::Deserialize(ref e)) if is_eof(e) => None,
+                Err(e) => Some(Err(Error::Message(ReadWriteError::R(e)))),
+            }
+        }
+    }
+
+    /// Whether reading failed because the other side closed the connection.
+    fn is_eof(e: &bincode::Error) -> bool {
+        match **e {
+            bincode::ErrorKind::Io(ref io) => io.kind() == std::io::ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+
     /// Client for the `Ping` communication type.
     /// Reading and writing messages is bounded by `timeout`.
-    pub fn ping(timeout: Timeout) -> Client<NoMessage, Ping> {
+    /// `request` reads whether the daemon watches the nix file,
+    /// `write` only sends the ping.
+    pub fn ping(timeout: Timeout) -> Client<PingResult, Ping> {
         Client::bake(timeout, CommunicationType::Ping)
     }
 
+    /// Client for the `PingAttribute` communication type, like
+    /// `ping`.
+    pub fn ping_attribute(timeout: Timeout) -> Client<PingResult, PingAttribute> {
+        Client::bake(timeout, CommunicationType::PingAttribute)
+    }
+
+    /// Client for the `CancelBuild` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn cancel_build(timeout: Timeout) -> Client<CancelBuildResult, CancelBuild> {
+        Client::bake(timeout, CommunicationType::CancelBuild)
+    }
+
+    /// Client for the `WaitIdle` communication type.
+    /// Reading and writing messages is bounded by `timeout`,
+    /// so it has to allow for the builds to finish.
+    pub fn wait_idle(timeout: Timeout) -> Client<WaitIdleResult, WaitIdle> {
+        Client::bake(timeout, CommunicationType::WaitIdle)
+    }
+
+    /// Client for the `WaitIdleFor` communication type. Reading
+    /// and writing messages is bounded by `timeout`, so it has to
+    /// allow for the requested wait.
+    pub fn wait_idle_for(timeout: Timeout) -> Client<WaitIdleForResult, WaitIdleFor> {
+        Client::bake(timeout, CommunicationType::WaitIdleFor)
+    }
+
+    /// Client for the `FollowLog` communication type.
+    /// Reading each log message is bounded by `timeout`, so it
+    /// has to allow for builds not printing anything for a while.
+    pub fn follow_log(timeout: Timeout) -> Client<LogMessage, FollowLog> {
+        Client::bake(timeout, CommunicationType::FollowLog)
+    }
+
+    /// Client for the `StreamEvents` communication type.
+    /// Reading each event is bounded by `timeout`, so it
+    /// has to allow for no builds happening for a while.
+    pub fn stream_events(timeout: Timeout) -> Client<EventMessage, StreamEvents> {
+        Client::bake(timeout, CommunicationType::StreamEvents)
+    }
+
+    /// Client for the `Monitor` communication type, see `stream_events`.
+    pub fn monitor(timeout: Timeout) -> Client<EventMessage, Monitor> {
+        Client::bake(timeout, CommunicationType::Monitor)
+    }
+
+    /// Client for the `Subscribe` communication type, see `stream_events`.
+    pub fn subscribe(timeout: Timeout) -> Client<EventMessage, Subscribe> {
+        Client::bake(timeout, CommunicationType::Subscribe)
+    }
+
+    /// Client for the `Resume` communication type, see `stream_events`.
+    pub fn resume(timeout: Timeout) -> Client<EventMessage, Resume> {
+        Client::bake(timeout, CommunicationType::Resume)
+    }
+
+    /// Client for the `ListProjects` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn list_projects(timeout: Timeout) -> Client<ListProjectsResult, ListProjects> {
+        Client::bake(timeout, CommunicationType::ListProjects)
+    }
+
+    /// Client for the `Shutdown` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn shutdown(timeout: Timeout) -> Client<ShutdownResult, Shutdown> {
+        Client::bake(timeout, CommunicationType::Shutdown)
+    }
+
+    /// Client for the `Status` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn status(timeout: Timeout) -> Client<StatusResult, Status> {
+        Client::bake(timeout, CommunicationType::Status)
+    }
+
+    /// Client for the `Stats` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn stats(timeout: Timeout) -> Client<StatsResult, Stats> {
+        Client::bake(timeout, CommunicationType::Stats)
+    }
+
+    /// Client for the `RequestBuild` communication type.
+    pub fn request_build(timeout: Timeout) -> Client<RequestBuildResult, RequestBuild> {
+        Client::bake(timeout, CommunicationType::RequestBuild)
+    }
+
+    /// Client for the `Rebuild` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn rebuild(timeout: Timeout) -> Client<PingResult, Rebuild> {
+        Client::bake(timeout, CommunicationType::Rebuild)
+    }
+
+    /// Client for the `Latency` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn latency(timeout: Timeout) -> Client<LatencyResult, Latency> {
+        Client::bake(timeout, CommunicationType::Latency)
+    }
 }
@@ -3,9 +3,12 @@
 pub mod communicate;
 pub mod path;
 
+extern crate nix;
+
 use std::convert::TryFrom;
 use std::io::Write;
 use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
 use std::time::{Duration, Instant};
 
@@ -212,6 +215,23 @@ impl<'a, R, W> ReadWriter<'a, R, W> {
         })
     }
 
+    /// Whether the other side closed the connection.
+    /// Only meaningful if it isn’t expected to send anything.
+    pub fn hung_up(&self) -> bool {
+        use self::nix::sys::socket::{recv, MsgFlags};
+        let mut buf = [0u8; 1];
+        match recv(
+            self.socket.as_raw_fd(),
+            &mut buf,
+            MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT,
+        ) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(self::nix::Error::Sys(self::nix::errno::Errno::EAGAIN)) => false,
+            Err(_) => true,
+        }
+    }
+
     /// Send a message to the other side.
     pub fn write(&mut self, timeout: &Timeout, mes: &W) -> Result<(), WriteError>
     where
@@ -2,7 +2,9 @@
 
 extern crate nix;
 
-use std::os::unix::io::AsRawFd;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 
@@ -26,8 +28,26 @@ impl From<std::io::Error> for BindError {
     }
 }
 
-/// Locks the socket the server is bound to. Drop to release.
-pub struct BindLock(std::fs::File);
+/// Locks the socket the server is bound to. Drop to release; this
+/// also removes the socket file we bound, so that it doesn’t linger
+/// once nobody listens on it.
+pub struct BindLock {
+    /// The locked lock file, only kept open.
+    _file: std::fs::File,
+    /// The socket file to remove, unless systemd owns it.
+    socket: Option<PathBuf>,
+}
+
+impl Drop for BindLock {
+    fn drop(&mut self) {
+        // still locked, so no new daemon bound it yet
+        if let Some(ref socket) = self.socket {
+            if let Err(e) = remove_if_exists(socket) {
+                warn!("could not remove the socket {}: {}", socket.display(), e);
+            }
+        }
+    }
+}
 
 impl<'a> SocketPath<'a> {
     /// Create from the path of the socket.
@@ -55,19 +75,68 @@ impl<'a> SocketPath<'a> {
     }
 
     /// Try to lock the lock file to find outswhether another process is listening.
+    ///
+    /// The lock file contains the pid of the process holding the lock.
+    /// If that process is dead, but the lock is still held (for example
+    /// by a child process which inherited the lock after a crash), the
+    /// lock file and socket are stale: they are removed and locking is
+    /// retried once.
     fn try_locking(&self) -> Result<BindLock, BindError> {
-        let h = std::fs::OpenOptions::new()
+        if let Some(lock) = self.lock()? {
+            return Ok(lock);
+        }
+        match self.lock_owner() {
+            Some(pid) if !is_alive(pid) => {
+                warn!(
+                    "the daemon (pid {}) which listened on {} is dead, removing its stale socket",
+                    pid,
+                    self.display()
+                );
+                remove_if_exists(&self.lockfile())?;
+                remove_if_exists(self.0)?;
+                self.lock()?.ok_or(BindError::OtherProcessListening)
+            }
+            _ => Err(BindError::OtherProcessListening),
+        }
+    }
+
+    /// Lock the lock file and write our pid to it.
+    /// `None` if another process holds the lock.
+    fn lock(&self) -> Result<Option<BindLock>, BindError> {
+        let lockfile = self.lockfile();
+        let mut h = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(self.lockfile())?;
+            .open(&lockfile)?;
         // we try to get an exclusive lock, nonblocking
         match nix::fcntl::flock(h.as_raw_fd(), nix::fcntl::FlockArg::LockExclusiveNonblock) {
             // if the lock would block, another process is listening
-            Err(nix::Error::Sys(nix::errno::EWOULDBLOCK)) => Err(BindError::OtherProcessListening),
-            other => other.map_err(BindError::Unix),
-        }?;
-        Ok(BindLock(h))
+            Err(nix::Error::Sys(nix::errno::EWOULDBLOCK)) => return Ok(None),
+            other => other.map_err(BindError::Unix)?,
+        };
+        // another process might have replaced a stale lock file
+        // after we opened it, then we locked the wrong file
+        match std::fs::metadata(&lockfile) {
+            Ok(ref m) if m.ino() == h.metadata()?.ino() => {}
+            _ => return Ok(None),
+        }
+        h.set_len(0)?;
+        write!(h, "{}", std::process::id())?;
+        Ok(Some(BindLock {
+            _file: h,
+            socket: None,
+        }))
+    }
+
+    /// The pid written to the lock file by the process holding it.
+    fn lock_owner(&self) -> Option<i32> {
+        let mut contents = String::new();
+        std::fs::File::open(self.lockfile())
+            .ok()?
+            .read_to_string(&mut contents)
+            .ok()?;
+        contents.trim().parse().ok().filter(|pid| *pid > 0)
     }
 
     /// `bind(2)` to this socket path.
@@ -75,23 +144,25 @@ impl<'a> SocketPath<'a> {
     /// Uses a lock file to guarantee no other process is listening to the same socket.
     /// The lock file is the socket file with a `.lock` file ending appended.
     ///
-    /// The lock file is released automatically when the returned `BindLock` is dropped.
+    /// The lock file is released automatically when the returned `BindLock` is dropped,
+    /// and the socket file removed.
     ///
     /// Uses the `flock(2)` trick decribed in
     /// https://gavv.github.io/articles/unix-socket-reuse/
+    ///
+    /// If systemd started us with the socket already bound (socket
+    /// activation, see `lorri install-service`), that socket is used.
     pub fn bind(&self) -> Result<(UnixListener, BindLock), BindError> {
         // - try to lock lockfile (open and flock exclusive nonblocking)
-        let lock = self.try_locking()?;
+        let mut lock = self.try_locking()?;
+        if let Some(l) = activated_listener() {
+            return Ok((l, lock));
+        }
         // - remove socket file if it exists
-        std::fs::remove_file(self.0).or_else(|e| {
-            if e.kind() == std::io::ErrorKind::NotFound {
-                Ok(())
-            } else {
-                Err(e)
-            }
-        })?;
+        remove_if_exists(self.0)?;
         // - bind to socket
         let l = UnixListener::bind(self.0)?;
+        lock.socket = Some(self.0.to_owned());
         Ok((l, lock))
     }
 
@@ -100,3 +171,52 @@ impl<'a> SocketPath<'a> {
         UnixStream::connect(self.0)
     }
 }
+
+/// The first socket passed by systemd’s socket activation
+/// (`sd_listen_fds(3)`), if we were started that way.
+fn activated_listener() -> Option<UnixListener> {
+    /// The first file descriptor passed by systemd.
+    const SD_LISTEN_FDS_START: i32 = 3;
+    let var = |name| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok());
+    let ours = var("LISTEN_PID") == Some(std::process::id());
+    let fds = var("LISTEN_FDS").unwrap_or(0);
+    // the sockets are for us, not for the processes we start
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if !ours || fds < 1 {
+        return None;
+    }
+    // and not to the processes we start, either
+    if let Err(e) = nix::fcntl::fcntl(
+        SD_LISTEN_FDS_START,
+        nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+    ) {
+        warn!(
+            "could not close the systemd socket for child processes: {}",
+            e
+        );
+    }
+    // systemd passes the descriptors to us only, and only once
+    Some(unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+/// Remove the file at `path`, unless it doesn’t exist.
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    std::fs::remove_file(path).or_else(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })
+}
+
+/// Whether the process `pid` exists.
+fn is_alive(pid: i32) -> bool {
+    match nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), None) {
+        Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => false,
+        // EPERM: it exists, but belongs to another user
+        _ => true,
+    }
+}
@@ -0,0 +1,163 @@
+//! A throwaway nix store with a fake nixpkgs, so that tests of
+//! builds, roots and the daemon depend neither on the host’s
+//! channels and store nor on the network.
+//!
+//! The first call to `fixtures` creates a temporary directory with
+//! a chroot store, a `nix.conf` without substituters and a copy of
+//! `nix/bogus-nixpkgs`, and points `NIX_REMOTE`, `NIX_CONF_DIR` and
+//! `NIX_PATH` (`<nixpkgs>`) of the test process at them, so that
+//! everything lorri runs uses them. All tests of a process share
+//! the fixtures; each gets its own project with `Fixtures::project`.
+//!
+//! Available to integration tests with the `test-fixtures` feature;
+//! they still need `nix-build` (but no `/nix`) on the `PATH`:
+//!
+//! ```sh
+//! cargo test --features test-fixtures --test fixtures
+//! ```
+
+use cas::ContentAddressable;
+use nix::Store;
+use project::Project;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use NixFile;
+
+/// The files of the fake nixpkgs, relative to its directory, and
+/// whether they are executable.
+const BOGUS_NIXPKGS: &[(&str, &str, bool)] = &[
+    (
+        "default.nix",
+        include_str!("../nix/bogus-nixpkgs/default.nix"),
+        false,
+    ),
+    (
+        "builder.sh",
+        include_str!("../nix/bogus-nixpkgs/builder.sh"),
+        true,
+    ),
+    (
+        "shell-builder.sh",
+        include_str!("../nix/bogus-nixpkgs/shell-builder.sh"),
+        false,
+    ),
+    (
+        "stdenv/setup",
+        include_str!("../nix/bogus-nixpkgs/stdenv/setup"),
+        false,
+    ),
+];
+
+/// The nix configuration of the fixtures: nothing is substituted,
+/// and builds don’t need a sandbox or build users.
+const NIX_CONF: &str = "substituters =\nsandbox = false\nbuild-users-group =\n";
+
+/// The store and nixpkgs shared by the tests of a process.
+pub struct Fixtures {
+    dir: PathBuf,
+}
+
+lazy_static! {
+    static ref FIXTURES: Result<Fixtures, String> =
+        Fixtures::create().map_err(|e| format!("could not set up the nix fixtures: {}", e));
+}
+
+/// The fixtures of this process, set up on the first call.
+///
+/// # Panics
+///
+/// If they can’t be set up.
+pub fn fixtures() -> &'static Fixtures {
+    match *FIXTURES {
+        Ok(ref fixtures) => fixtures,
+        Err(ref e) => panic!("{}", e),
+    }
+}
+
+impl Fixtures {
+    /// Set up the fixtures in a new temporary directory, which is
+    /// kept until the process exits.
+    fn create() -> io::Result<Fixtures> {
+        let dir = tempfile::Builder::new()
+            .prefix("lorri-fixtures")
+            .tempdir()?
+            .into_path();
+        let fixtures = Fixtures { dir };
+
+        for (file, contents, executable) in BOGUS_NIXPKGS {
+            let path = fixtures.nixpkgs().join(file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, contents)?;
+            if *executable {
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            }
+        }
+
+        let conf_dir = fixtures.dir.join("etc");
+        fs::create_dir_all(&conf_dir)?;
+        fs::write(conf_dir.join("nix.conf"), NIX_CONF)?;
+
+        std::env::set_var(
+            "NIX_REMOTE",
+            format!("local?root={}", fixtures.store_root().display()),
+        );
+        std::env::set_var("NIX_CONF_DIR", &conf_dir);
+        std::env::set_var(
+            "NIX_PATH",
+            format!("nixpkgs={}", fixtures.nixpkgs().display()),
+        );
+        Ok(fixtures)
+    }
+
+    /// The chroot store everything is built into.
+    pub fn store(&self) -> Store {
+        Store::from_env()
+    }
+
+    /// The directory the store lives in; a store path `/nix/store/…`
+    /// is at `<store_root>/nix/store/…` on disk.
+    pub fn store_root(&self) -> PathBuf {
+        self.dir.join("store")
+    }
+
+    /// The fake nixpkgs (see `nix/bogus-nixpkgs`), also available
+    /// as `<nixpkgs>`.
+    pub fn nixpkgs(&self) -> PathBuf {
+        self.dir.join("nixpkgs")
+    }
+
+    /// A new project whose `shell.nix` is `shell_nix`, with its own
+    /// GC roots and content-addressable store.
+    pub fn project(&self, shell_nix: &str) -> io::Result<TestProject> {
+        let dir = tempfile::Builder::new()
+            .prefix("project")
+            .tempdir_in(&self.dir)?;
+        let nix_file = dir.path().join("shell.nix");
+        fs::write(&nix_file, shell_nix)?;
+        let project = Project::new(
+            NixFile::from(nix_file),
+            &dir.path().join("gc_roots"),
+            ContentAddressable::new(dir.path().join("cas"))?,
+        )?;
+        Ok(TestProject { project, dir })
+    }
+}
+
+/// A project created by `Fixtures::project`, removed when dropped.
+pub struct TestProject {
+    /// The project, built into the fixtures’ store.
+    pub project: Project,
+    dir: TempDir,
+}
+
+impl TestProject {
+    /// The project directory, containing its `shell.nix`.
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+}
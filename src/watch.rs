@@ -1,71 +1,610 @@
 //! Recursively watch paths for changes, in an extensible and
 //! cross-platform way.
 
+extern crate nix;
+
+#[cfg(any(
+    test,
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "ios",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+mod kqueue;
+
+use self::nix::libc;
+use crate::clock::{Clock, SystemClock};
+use crate::glob::Rules;
 use crate::mpsc::FilterTimeoutIterator;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
-use std::collections::HashSet;
+use crate::nix::Store;
+use crate::pathreduction::MAX_DEPTH;
+use notify::{PollWatcher, RawEvent, RecursiveMode, Watcher};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, RecvError};
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, RecvError, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How paths are watched for changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatchBackend {
+    /// The platform’s notifications, but poll paths on network
+    /// filesystems, and all paths registered after the platform ran
+    /// out of watches (inotify’s `max_user_watches`).
+    Auto,
+    /// Only the platform’s notifications: inotify (FSEvents on macOS,
+    /// kqueue on the BSDs; see `NATIVE_NOTIFICATIONS`).
+    Inotify,
+    /// Poll every path, every `POLL_WATCHER_DELAY`.
+    Poll,
+}
+
+impl Default for WatchBackend {
+    fn default() -> WatchBackend {
+        WatchBackend::Auto
+    }
+}
+
+impl WatchBackend {
+    /// The name of the backend, as in `--watch-backend`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WatchBackend::Auto => "auto",
+            WatchBackend::Inotify => "inotify",
+            WatchBackend::Poll => "poll",
+        }
+    }
+
+    /// The backend `Auto` picks for `path` (as long as the platform
+    /// has watches left). Without `NATIVE_NOTIFICATIONS`, every
+    /// backend polls.
+    pub fn resolve(self, path: &Path) -> WatchBackend {
+        self.resolve_with(NATIVE_NOTIFICATIONS, || is_network_fs(path))
+    }
+
+    fn resolve_with<F>(self, native: bool, network_fs: F) -> WatchBackend
+    where
+        F: FnOnce() -> bool,
+    {
+        match self {
+            _ if !native => WatchBackend::Poll,
+            WatchBackend::Auto if network_fs() => WatchBackend::Poll,
+            WatchBackend::Auto => WatchBackend::Inotify,
+            backend => backend,
+        }
+    }
+}
+
+/// Whether the platform’s watcher gets notifications from the
+/// kernel: inotify on Linux, FSEvents on macOS, and kqueue on FreeBSD
+/// and the other BSDs (see `kqueue`, since `notify` has no kqueue
+/// watcher). Elsewhere, lorri polls every `POLL_WATCHER_DELAY`.
+pub const NATIVE_NOTIFICATIONS: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+));
+
+/// The platform’s watcher (see `NATIVE_NOTIFICATIONS`).
+#[cfg(any(
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+type NativeWatcher = kqueue::Watcher;
+#[cfg(not(any(
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+type NativeWatcher = notify::RecommendedWatcher;
+
+/// How the platform limits its watcher, for when it runs out.
+#[cfg(target_os = "linux")]
+const WATCH_LIMITS: &str = "inotify watches or instances (see `sysctl fs.inotify`)";
+#[cfg(not(target_os = "linux"))]
+const WATCH_LIMITS: &str = "open files (see `ulimit -n`)";
+
+impl FromStr for WatchBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WatchBackend, String> {
+        match s {
+            "auto" => Ok(WatchBackend::Auto),
+            "inotify" => Ok(WatchBackend::Inotify),
+            "poll" => Ok(WatchBackend::Poll),
+            _ => Err(format!(
+                "unknown watch backend `{}`, use auto, inotify or poll",
+                s
+            )),
+        }
+    }
+}
+
+/// How often the polling backend checks the paths it watches.
+pub const POLL_WATCHER_DELAY: Duration = Duration::from_secs(2);
 
 /// A dynamic list of paths to watch for changes, and
 /// react to changes when they occur.
 pub struct Watch {
-    notify: RecommendedWatcher,
+    /// The platform’s watcher, created when the first path is
+    /// registered with it.
+    notify: Option<NativeWatcher>,
+    /// The polling watcher, created when the first path is polled.
+    poll: Option<PollWatcher>,
+    /// Sends the events of both watchers to `rx`.
+    tx: Sender<RawEvent>,
     rx: std::sync::mpsc::Receiver<notify::RawEvent>,
-    watches: HashSet<PathBuf>,
+    /// Which watcher paths are registered with.
+    backend: WatchBackend,
+    /// Whether the platform’s watcher ran out of watches (or could
+    /// not be created at all), so that `WatchBackend::Auto` polls.
+    notify_exhausted: bool,
+    /// Watched paths, and whether they are on a case-insensitive
+    /// filesystem (where events might name them in a different case).
+    watches: HashMap<PathBuf, bool>,
+    /// Paths registered with `notify` or `poll`, which can be fewer
+    /// than `watches` (see `set_directory_granularity`).
+    registered: HashSet<PathBuf>,
+    /// The registered paths which are polled.
+    polled: HashSet<PathBuf>,
+    /// Register directories instead of files with `notify`.
+    directory_granularity: bool,
+    /// How long to wait for more events after the first one.
+    latency: Duration,
+    /// How long no events have to arrive before a batch of changes
+    /// is complete.
+    debounce: Duration,
+    /// Time, as far as waiting for `latency` is concerned.
+    clock: Arc<dyn Clock>,
+    /// Paths which are not watched, but checked by content hash.
+    hashed: HashMap<PathBuf, Hashed>,
+    /// The last seen contents of files we got events for (or watch
+    /// directly), to ignore events which don’t change the content,
+    /// like `touch` or checking out identical files.
+    contents: RefCell<HashMap<PathBuf, Content>>,
+    /// Paths below the first directory which the rules ignore are
+    /// neither watched nor cause changes.
+    ignore: (PathBuf, Rules),
+    /// How often inputs tracked by content hash are checked.
+    poll_interval: Duration,
+    /// Directories too large to watch, so that neither they nor the
+    /// directories containing them are watched recursively (see
+    /// `extend`).
+    too_broad: Vec<PathBuf>,
+    /// The directory of the nix store, whose paths never change
+    /// (see `set_store_dir`).
+    store_dir: PathBuf,
+    /// The directory of the project, which is watched even if it is
+    /// one of the `too_broad` directories (see `set_project_dir`).
+    project_dir: Option<PathBuf>,
+    /// When the first change since the last `take_changed_at` was
+    /// noticed.
+    changed_at: Cell<Option<Instant>>,
+    /// Events taken off the channel by `note_arrivals`, and when the
+    /// first of them arrived.
+    arrived: RefCell<Option<(Instant, Vec<RawEvent>)>>,
 }
 
+/// How often inputs tracked by content hash (and other conditions,
+/// see `wait_for_change_or`) are checked for changes, unless
+/// configured otherwise (see `Watch::set_poll_interval`).
+pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many `debounce` windows (see `Watch::set_debounce`) a burst
+/// of events may last.
+const DEBOUNCE_MAX_WINDOWS: u32 = 10;
+
+/// How many files below watched directories `Watch` remembers the
+/// content of (see `contents_changed`); once there are more, it
+/// forgets them, and their next event counts as a change.
+const CONTENTS_MAX: usize = 10_000;
+
+/// Directories whose contents are never worth a rebuild: version
+/// control, and the build output of common tools (see `set_ignore`).
+pub const DEFAULT_IGNORES: &[&str] = &[".git", "target/", "node_modules/", "result*"];
+
 impl Watch {
     /// Instantiate a new Watch.
     pub fn init() -> Result<Watch, notify::Error> {
         let (tx, rx) = channel();
+        let store_dir = canonical(Store::from_env().store_dir());
 
         Ok(Watch {
-            notify: Watcher::new_raw(tx)?,
-            watches: HashSet::new(),
+            notify: None,
+            poll: None,
+            tx,
+            backend: WatchBackend::default(),
+            notify_exhausted: false,
+            watches: HashMap::new(),
+            registered: HashSet::new(),
+            polled: HashSet::new(),
+            directory_granularity: cfg!(target_os = "macos"),
+            latency: Duration::from_millis(0),
+            debounce: Duration::from_millis(0),
+            clock: Arc::new(SystemClock),
+            hashed: HashMap::new(),
+            contents: RefCell::new(HashMap::new()),
+            ignore: (PathBuf::new(), Rules::default()),
+            poll_interval: POLL_INTERVAL,
+            too_broad: too_broad_dirs(&store_dir),
+            store_dir,
+            project_dir: None,
+            changed_at: Cell::new(None),
+            arrived: RefCell::new(None),
             rx,
         })
     }
 
+    /// Only register directories with the platform’s watcher, and
+    /// filter the events of watched files out of their directory’s
+    /// events. This is the default on macOS, where every new
+    /// registration restarts the FSEvents stream.
+    pub fn set_directory_granularity(&mut self, directory_granularity: bool) {
+        self.directory_granularity = directory_granularity;
+    }
+
+    /// After the first event, wait `latency` for more events to
+    /// arrive, so that they are coalesced into one batch of changes.
+    pub fn set_latency(&mut self, latency: Duration) {
+        self.latency = latency;
+    }
+
+    /// After the first event, collect events until none arrive for
+    /// `debounce` (or for `latency`, if longer, after the first), so
+    /// that a burst of writes (like an editor saving many files) is
+    /// one batch of changes. A burst is cut off after
+    /// `DEBOUNCE_MAX_WINDOWS` times `debounce`, so that a file which
+    /// is written continuously doesn’t hold back rebuilds forever.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    /// Ignore the paths below `root` which `rules` ignore (relative
+    /// to `root`): they are not watched, and their events don’t count
+    /// as changes. Rules added for contents (like `DEFAULT_IGNORES`
+    /// or a `.gitignore`) don’t apply to the paths passed to
+    /// `extend`, since nix did read those.
+    pub fn set_ignore(&mut self, root: &Path, rules: Rules) {
+        self.ignore = (root.to_path_buf(), rules);
+    }
+
+    /// Whether `path` is ignored, see `set_ignore`. `in_directory`
+    /// is false for paths passed to `extend`.
+    fn is_ignored(&self, path: &Path, in_directory: bool) -> bool {
+        let (root, rules) = &self.ignore;
+        match path.strip_prefix(root) {
+            Ok(relative) => rules.is_ignored(relative, in_directory),
+            Err(_) => false,
+        }
+    }
+
+    /// Watch `dir`, the directory of the project, recursively even if
+    /// it is the user’s home directory, which is too large to watch
+    /// otherwise (see `extend`).
+    pub fn set_project_dir(&mut self, dir: &Path) {
+        self.project_dir = Some(dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf()));
+    }
+
+    /// Never watch the paths in `dir`, the directory of the nix
+    /// store the project is built into (see `Store::store_dir`).
+    pub fn set_store_dir(&mut self, dir: &Path) {
+        self.store_dir = canonical(dir.to_path_buf());
+        self.too_broad = too_broad_dirs(&self.store_dir);
+    }
+
+    /// Register the paths watched from now on with `backend`.
+    pub fn set_backend(&mut self, backend: WatchBackend) {
+        if backend == WatchBackend::Inotify && !NATIVE_NOTIFICATIONS {
+            warn!("this platform has no file change notifications lorri can use, polling instead");
+        }
+        self.backend = backend;
+    }
+
+    /// How many of the registered paths are polled (see
+    /// `set_backend`), out of how many.
+    pub fn polled_paths(&self) -> (usize, usize) {
+        (self.polled.len(), self.registered.len())
+    }
+
+    /// Check inputs tracked by content hash every `poll_interval`.
+    pub fn set_poll_interval(&mut self, poll_interval: Duration) {
+        self.poll_interval = poll_interval;
+    }
+
+    /// Wait for `latency` on `clock` instead of the system clock.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
     /// Extend the watch list with an additional list of paths.
     /// Note: Watch maintains a list of already watched paths, and
     /// will not add duplicates.
-    pub fn extend(&mut self, paths: &[PathBuf]) -> Result<(), notify::Error> {
+    ///
+    /// Directories which contain the nix store or the user’s home
+    /// directory (or are one of them, unless it is the project’s, see
+    /// `set_project_dir`) are refused with `ExtendError::TooBroad`,
+    /// since watching them would use up all watches.
+    pub fn extend(&mut self, paths: &[PathBuf]) -> Result<(), ExtendError> {
         for path in paths {
-            self.add_path(&path)?;
+            if self.is_ignored(path, false) {
+                debug!("ignoring {:?}", path);
+                continue;
+            }
             if path.is_dir() {
-                self.add_path_recursively(&path)?;
+                self.refuse_too_broad(path)?;
+            }
+            self.add_path(path)?;
+            if path.is_dir() {
+                self.add_path_recursively(path, 0, &mut HashSet::new())?;
+            } else {
+                // the baseline for later events; a path watched
+                // already keeps its baseline, so that a change
+                // during a build isn’t mistaken for the baseline
+                let mut contents = self.contents.borrow_mut();
+                if !contents.contains_key(path) {
+                    if let Some(content) = Content::read(path) {
+                        contents.insert(path.clone(), content);
+                    }
+                }
             }
         }
+        debug!(
+            "watching {} paths with {} registrations",
+            self.watches.len(),
+            self.registered.len()
+        );
 
         Ok(())
     }
 
+    /// Track an additional list of paths by their content hash,
+    /// instead of watching them with filesystem notifications.
+    /// The current content is the baseline for later changes
+    /// (paths tracked already keep their baseline, like in `extend`).
+    pub fn extend_hashed(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            self.hashed
+                .entry(path.clone())
+                .or_insert_with(|| Hashed::of(path));
+        }
+    }
+
+    /// The number of paths watched or tracked by content hash.
+    pub fn watched_files(&self) -> usize {
+        self.watches.len() + self.hashed.len()
+    }
+
     /// Wait for a batch of changes to arrive, returning when they do.
+    /// Inputs tracked by hash are checked every `poll_interval`
+    /// (see `set_poll_interval`).
     pub fn wait_for_change(&mut self) -> Result<(), ()> {
-        self.block()
+        if self.hashed.is_empty() {
+            return self.block();
+        }
+        self.wait_for_change_or(|| false);
+        Ok(())
     }
 
-    /// Block until we have at least one event
-    pub fn block(&mut self) -> Result<(), ()> {
-        if self.blocking_iter().next().is_none() {
-            debug!("No event received!");
-            return Err(());
+    /// Like `wait_for_change`, but also return once `poll` returns
+    /// true. `poll` is called every `poll_interval`.
+    pub fn wait_for_change_or<F>(&mut self, mut poll: F)
+    where
+        F: FnMut() -> bool,
+    {
+        loop {
+            if self.block_timeout(self.poll_interval).is_ok()
+                || self.hashed_inputs_changed()
+                || poll()
+            {
+                return;
+            }
+        }
+    }
+
+    /// Return whether a watched path changed since the last call,
+    /// without waiting for changes. Inputs tracked by hash are not
+    /// checked, since hashing them on every call would be expensive.
+    pub fn poll_change(&self) -> bool {
+        self.block_timeout(Duration::from_millis(0)).is_ok()
+    }
+
+    /// Re-hash the inputs tracked by hash whose names, sizes or
+    /// modification times changed, and return whether the content
+    /// of any of them changed since they were last hashed.
+    fn hashed_inputs_changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, hashed) in self.hashed.iter_mut() {
+            let stamp = stamp_path(path).ok();
+            if hashed.stamp == stamp {
+                continue;
+            }
+            let new = Hashed::of(path);
+            if hashed.hash != new.hash {
+                info!("hashed input changed: {:?}", path);
+                changed = true;
+            }
+            *hashed = new;
+        }
+        if changed {
+            self.noticed_change(self.clock.now());
+        }
+        changed
+    }
+
+    /// Take the events which arrived so far off the channel, without
+    /// waiting or looking at them, to remember when they arrived:
+    /// the next `block` or `block_timeout` handles them, and their
+    /// change counts as noticed then. Called while a build runs, so
+    /// that the time a change waits for it counts.
+    pub fn note_arrivals(&self) {
+        let events: Vec<RawEvent> = self.try_iter().collect();
+        if events.is_empty() {
+            return;
         }
+        let now = self.clock.now();
+        self.arrived
+            .borrow_mut()
+            .get_or_insert_with(|| (now, vec![]))
+            .1
+            .extend(events);
+    }
 
-        self.process_ready()
+    /// When the first change since the last call was noticed, that
+    /// is when the first event of its batch arrived (before waiting
+    /// for `latency` and for the events to settle, or for a running
+    /// build, see `note_arrivals`), or when a change of an input
+    /// tracked by hash was found.
+    pub fn take_changed_at(&self) -> Option<Instant> {
+        self.changed_at.take()
     }
 
-    /// Block until we have at least one event
+    fn noticed_change(&self, at: Instant) {
+        if self.changed_at.get().is_none() {
+            self.changed_at.set(Some(at));
+        }
+    }
+
+    /// Block until we have at least one event which changes the
+    /// content of a watched path
+    pub fn block(&mut self) -> Result<(), ()> {
+        loop {
+            let (noticed, mut events) = match self.arrived.borrow_mut().take() {
+                Some(arrived) => arrived,
+                None => match self.blocking_iter().next() {
+                    Some(event) => (self.clock.now(), vec![event]),
+                    None => {
+                        debug!("No event received!");
+                        return Err(());
+                    }
+                },
+            };
+
+            self.settle(&mut events);
+            if self.contents_changed(&events) {
+                self.noticed_change(noticed);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Block until we have at least one event, return whether it
+    /// (or an event right after it) changes the content of a
+    /// watched path
     pub fn block_timeout(&self, timeout: Duration) -> Result<(), ()> {
-        if let Some(Ok(_)) = self.timeout_iter(timeout).next() {
-            self.process_ready()
-        } else {
-            Err(())
+        let arrived =
+            self.arrived
+                .borrow_mut()
+                .take()
+                .or_else(|| match self.timeout_iter(timeout).next() {
+                    Some(Ok(first)) => Some((self.clock.now(), vec![first])),
+                    _ => None,
+                });
+        if let Some((noticed, mut events)) = arrived {
+            self.settle(&mut events);
+            if self.contents_changed(&events) {
+                self.noticed_change(noticed);
+                return Ok(());
+            }
+        }
+        Err(())
+    }
+
+    /// Add the events arriving within `latency` to `events`, and
+    /// then more until none arrive for `debounce` (see
+    /// `set_latency` and `set_debounce`).
+    fn settle(&self, events: &mut Vec<notify::RawEvent>) {
+        let deadline = self.clock.now() + self.debounce * DEBOUNCE_MAX_WINDOWS;
+        // the latency is the first debounce window
+        let mut window = self.latency.max(self.debounce);
+        loop {
+            self.clock.sleep(window);
+            let ready = self.process_ready();
+            let settled = ready.is_empty() || self.debounce == Duration::from_millis(0);
+            events.extend(ready);
+            if settled {
+                return;
+            }
+            if self.clock.now() >= deadline {
+                debug!("events are still arriving, not waiting for them to settle");
+                return;
+            }
+            window = self.debounce;
         }
     }
 
+    /// Return whether the events changed the content of any of their
+    /// paths since it was last seen. Files whose size and
+    /// modification time stayed the same are unchanged; files are
+    /// only hashed if their size stayed the same. Directories, files
+    /// renamed into place, removed files and files we haven’t seen
+    /// before always count as changed.
+    fn contents_changed(&self, events: &[notify::RawEvent]) -> bool {
+        let mut contents = self.contents.borrow_mut();
+        let mut changed = false;
+        let mut renamed: HashMap<&PathBuf, bool> = HashMap::new();
+        for event in events {
+            if let Some(path) = &event.path {
+                let rename = match &event.op {
+                    Ok(op) => op.contains(notify::op::RENAME),
+                    Err(_) => false,
+                };
+                *renamed.entry(path).or_insert(false) |= rename;
+            }
+        }
+        for (path, renamed) in renamed {
+            let metadata = match path.metadata() {
+                Ok(ref metadata) if metadata.is_dir() => {
+                    changed = true;
+                    continue;
+                }
+                Ok(metadata) => metadata,
+                Err(_) => {
+                    contents.remove(path);
+                    changed = true;
+                    continue;
+                }
+            };
+            let stamp = Stamp::of(&metadata);
+            let (old_stamp, old_hash) = match contents.get(path) {
+                Some(old) => (Some(old.stamp), old.hash),
+                None => (None, None),
+            };
+            let same_len = old_stamp.map(|old| old.len) == Some(stamp.len);
+            if !renamed && old_stamp == Some(stamp) {
+                debug!("{:?} is unchanged", path);
+                continue;
+            }
+            let hash = if same_len { hash_path(path).ok() } else { None };
+            if !renamed && hash.is_some() && old_hash == hash {
+                debug!("content of {:?} is unchanged", path)
+            } else {
+                changed = true;
+            }
+            if !contents.contains_key(path) && contents.len() >= CONTENTS_MAX {
+                debug!("forgetting the contents of files below watched directories");
+                let watches = &self.watches;
+                contents.retain(|path, _| watches.contains_key(path));
+            }
+            contents.insert(path.clone(), Content { stamp, hash });
+        }
+        if !changed {
+            info!("ignoring events which didn’t change any content");
+        }
+        changed
+    }
+
     fn blocking_iter<'a>(&'a self) -> impl 'a + Iterator<Item = notify::RawEvent> {
         self.rx
             .iter()
@@ -96,22 +635,13 @@ impl Watch {
 
     /// Non-blocking, read all the events already received -- draining
     /// the event queue.
-    fn process_ready(&self) -> Result<(), ()> {
-        let mut events = 0;
-        let mut iter = self.try_iter();
-
-        loop {
-            match iter.next() {
-                Some(event) => {
-                    debug!("Received event: {:#?}", event);
-                    events += 1;
-                }
-                None => {
-                    info!("Found {} events", events);
-                    return Ok(());
-                }
-            }
-        }
+    fn process_ready(&self) -> Vec<notify::RawEvent> {
+        let events: Vec<notify::RawEvent> = self
+            .try_iter()
+            .inspect(|event| debug!("Received event: {:#?}", event))
+            .collect();
+        info!("Found {} events", events.len());
+        events
     }
 
     fn handle_event(&self, event: &notify::RawEvent) {
@@ -126,17 +656,62 @@ impl Watch {
         }
     }
 
-    fn add_path_recursively(&mut self, path: &PathBuf) -> Result<(), notify::Error> {
-        if path.canonicalize()?.starts_with(Path::new("/nix/store")) {
+    /// Fail if watching the directory `path` recursively would watch
+    /// one of the `too_broad` directories, other than the project’s.
+    fn refuse_too_broad(&self, path: &Path) -> Result<(), ExtendError> {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        match self.too_broad.iter().find(|dir| {
+            dir.starts_with(&canonical) && self.project_dir.as_ref() != Some(&canonical)
+        }) {
+            None => Ok(()),
+            Some(dir) => Err(ExtendError::TooBroad {
+                path: path.to_owned(),
+                dir: dir.clone(),
+            }),
+        }
+    }
+
+    /// Watch the subdirectories of `path`, which is `depth` levels
+    /// below the watched directory. Directories in `visited`
+    /// (canonicalized) are not descended into again, so symlink
+    /// cycles and bind mount loops are skipped instead of followed
+    /// forever; so is anything deeper than `pathreduction::MAX_DEPTH`.
+    fn add_path_recursively(
+        &mut self,
+        path: &PathBuf,
+        depth: usize,
+        visited: &mut HashSet<PathBuf>,
+    ) -> Result<(), notify::Error> {
+        let canonical = match path.canonicalize() {
+            Ok(canonical) => canonical,
+            Err(ref e) if e.raw_os_error() == Some(libc::ELOOP) => {
+                warn!("not watching {}: symlink cycle", path.display());
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if canonical.starts_with(&self.store_dir) {
+            return Ok(());
+        }
+        if !visited.insert(canonical) {
+            debug!("not watching {:?} again: directory loop", path);
+            return Ok(());
+        }
+        if depth >= MAX_DEPTH {
+            warn!(
+                "not watching below {}: more than {} directories deep",
+                path.display(),
+                MAX_DEPTH
+            );
             return Ok(());
         }
 
         for entry in path.read_dir()? {
             let subpath = entry?.path();
 
-            if subpath.is_dir() {
+            if subpath.is_dir() && !self.is_ignored(&subpath, true) {
                 self.add_path(&subpath)?;
-                self.add_path_recursively(&subpath)?;
+                self.add_path_recursively(&subpath, depth + 1, visited)?;
             }
 
             // Skip adding files, watching in the dir will handle it.
@@ -145,34 +720,370 @@ impl Watch {
     }
 
     fn add_path(&mut self, path: &PathBuf) -> Result<(), notify::Error> {
-        if !self.watches.contains(path) {
+        if !self.watches.contains_key(path) {
             debug!("Watching path {:?}", path);
 
-            self.notify.watch(path, RecursiveMode::NonRecursive)?;
-            self.watches.insert(path.clone());
+            if !self.directory_granularity || path.is_dir() {
+                self.register(path)?;
+            }
+            self.watches.insert(path.clone(), is_case_insensitive(path));
         }
 
         if let Some(parent) = path.parent() {
-            if !self.watches.contains(parent) {
-                debug!("Watching parent path {:?}", parent);
+            debug!("Watching parent path {:?}", parent);
+            self.register(parent)?;
+        }
+
+        Ok(())
+    }
 
-                self.notify.watch(&parent, RecursiveMode::NonRecursive)?;
+    /// Register `path` with `notify` or `poll` (see `set_backend`),
+    /// unless it already is.
+    fn register(&mut self, path: &Path) -> Result<(), notify::Error> {
+        if self.registered.contains(path) {
+            return Ok(());
+        }
+        let poll = match self.backend {
+            WatchBackend::Auto if self.notify_exhausted => true,
+            backend => backend.resolve(path) == WatchBackend::Poll,
+        };
+        if poll {
+            if self.poll.is_none() {
+                self.poll = Some(PollWatcher::with_delay_ms(
+                    self.tx.clone(),
+                    POLL_WATCHER_DELAY.as_millis() as u32,
+                )?);
+            }
+            if let Some(poll) = self.poll.as_mut() {
+                poll.watch(path, RecursiveMode::NonRecursive)?;
+            }
+            self.polled.insert(path.to_path_buf());
+        } else {
+            let registered = match self.notify {
+                Some(ref mut notify) => notify.watch(path, RecursiveMode::NonRecursive),
+                None => NativeWatcher::new_raw(self.tx.clone()).and_then(|mut notify| {
+                    let registered = notify.watch(path, RecursiveMode::NonRecursive);
+                    self.notify = Some(notify);
+                    registered
+                }),
+            };
+            match registered {
+                Ok(()) => {}
+                Err(ref e) if self.backend == WatchBackend::Auto && out_of_watches(e) => {
+                    warn!(
+                        "the system ran out of {}, polling paths for changes instead",
+                        WATCH_LIMITS
+                    );
+                    self.notify_exhausted = true;
+                    return self.register(path);
+                }
+                Err(e) => return Err(e),
             }
         }
-
+        self.registered.insert(path.to_path_buf());
         Ok(())
     }
 
     fn event_is_interesting(&self, event: &notify::RawEvent) -> bool {
         match event.path {
-            Some(ref path) => path_match(&self.watches, path),
+            Some(ref path) => {
+                path_match(&self.watches, path)
+                    && !self.is_ignored(path, !self.watches.contains_key(path))
+            }
+            None => false,
+        }
+    }
+}
+
+/// The size and modification time of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl Stamp {
+    fn of(metadata: &std::fs::Metadata) -> Stamp {
+        Stamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+/// The last seen content of a file (see `Watch::contents_changed`).
+struct Content {
+    stamp: Stamp,
+    /// The content hash, if it was hashed and could be read.
+    hash: Option<md5::Digest>,
+}
+
+impl Content {
+    /// The content of the file `path`, hashed; `None` if it can’t
+    /// be read.
+    fn read(path: &Path) -> Option<Content> {
+        let metadata = path.metadata().ok()?;
+        Some(Content {
+            stamp: Stamp::of(&metadata),
+            hash: Some(hash_path(path).ok()?),
+        })
+    }
+}
+
+/// An input tracked by content hash (see `Watch::extend_hashed`).
+/// Its content is only hashed again once its stamp changes.
+struct Hashed {
+    /// The names, sizes and modification times below the path
+    /// (`None` if they could not be read).
+    stamp: Option<md5::Digest>,
+    /// The content hash (`None` if it could not be read).
+    hash: Option<md5::Digest>,
+}
+
+impl Hashed {
+    fn of(path: &Path) -> Hashed {
+        Hashed {
+            stamp: stamp_path(path).ok(),
+            hash: hash_path(path).ok(),
+        }
+    }
+}
+
+/// Hash the contents of `path`; directories are hashed recursively,
+/// including the names of their entries. Directories reached again
+/// through a symlink are only hashed the first time.
+fn hash_path(path: &Path) -> io::Result<md5::Digest> {
+    walk_path(path, &mut |context, path| {
+        context.consume(std::fs::read(path)?);
+        Ok(())
+    })
+}
+
+/// Hash the sizes and modification times of `path`, recursively
+/// like `hash_path`, without reading any file: if the stamp stays
+/// the same, the contents are assumed to be unchanged.
+fn stamp_path(path: &Path) -> io::Result<md5::Digest> {
+    walk_path(path, &mut |context, path| {
+        let metadata = path.metadata()?;
+        let modified = metadata
+            .modified()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        context.consume(metadata.len().to_le_bytes());
+        context.consume(modified.as_secs().to_le_bytes());
+        context.consume(modified.subsec_nanos().to_le_bytes());
+        Ok(())
+    })
+}
+
+/// Hash `path` by walking directories recursively (hashing the
+/// names of their entries) and feeding each file with `file`.
+fn walk_path<F>(path: &Path, file: &mut F) -> io::Result<md5::Digest>
+where
+    F: FnMut(&mut md5::Context, &Path) -> io::Result<()>,
+{
+    fn feed<F>(
+        context: &mut md5::Context,
+        path: &Path,
+        file: &mut F,
+        visited: &mut HashSet<PathBuf>,
+    ) -> io::Result<()>
+    where
+        F: FnMut(&mut md5::Context, &Path) -> io::Result<()>,
+    {
+        if path.is_dir() {
+            if !visited.insert(path.canonicalize()?) {
+                return Ok(());
+            }
+            let mut entries = path
+                .read_dir()?
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<io::Result<Vec<PathBuf>>>()?;
+            entries.sort();
+            for entry in entries {
+                if let Some(name) = entry.file_name() {
+                    context.consume(name.to_string_lossy().as_bytes());
+                }
+                feed(context, &entry, file, visited)?;
+            }
+        } else {
+            file(context, path)?;
+        }
+        Ok(())
+    }
+
+    let mut context = md5::Context::new();
+    feed(&mut context, path, file, &mut HashSet::new())?;
+    Ok(context.compute())
+}
+
+/// Why `Watch::extend` failed.
+#[derive(Debug)]
+pub enum ExtendError {
+    /// The platform’s watcher failed.
+    Notify(notify::Error),
+    /// Watching the directory `path` recursively would watch `dir`,
+    /// which is too large to watch.
+    TooBroad {
+        /// The directory `extend` was passed.
+        path: PathBuf,
+        /// The directory too large to watch in it.
+        dir: PathBuf,
+    },
+}
+
+impl std::fmt::Display for ExtendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ExtendError::Notify(e) => write!(f, "{}", e),
+            ExtendError::TooBroad { path, dir } => write!(
+                f,
+                "refusing to watch {}, since it contains {}, which is too large to watch; \
+                 if the nix file refers to it (through NIX_PATH, for example), \
+                 set `scope = \"project\"` in the `[watch]` section of the project’s \
+                 .lorri.toml to only watch the files of the project",
+                path.display(),
+                dir.display()
+            ),
+        }
+    }
+}
+
+impl From<notify::Error> for ExtendError {
+    fn from(e: notify::Error) -> ExtendError {
+        ExtendError::Notify(e)
+    }
+}
+
+/// The directories which `Watch::extend` refuses to watch
+/// recursively: the nix store in `store_dir` (canonicalized) and
+/// the user’s home directory.
+fn too_broad_dirs(store_dir: &Path) -> Vec<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    std::iter::once(store_dir.to_path_buf())
+        .chain(home.filter(|home| home.is_absolute()).map(canonical))
+        .collect()
+}
+
+/// `dir` canonicalized, if it exists.
+fn canonical(dir: PathBuf) -> PathBuf {
+    dir.canonicalize().unwrap_or(dir)
+}
+
+/// Whether registering a path failed because the platform’s watcher
+/// ran out of watches (`ENOSPC`) or instances (`EMFILE`, which is
+/// also what kqueue runs out of: every watched file is open).
+fn out_of_watches(error: &notify::Error) -> bool {
+    match error {
+        notify::Error::Io(e) => {
+            let code = e.raw_os_error();
+            code == Some(libc::ENOSPC) || code == Some(libc::EMFILE)
+        }
+        _ => false,
+    }
+}
+
+/// Whether `path` is on a network filesystem, where changes made by
+/// other machines cause no notifications.
+#[cfg(target_os = "linux")]
+pub fn is_network_fs(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    const NETWORK_FS_MAGICS: &[u32] = &[
+        0x6969,      // NFS
+        0x517b,      // SMB
+        0xff53_4d42, // CIFS
+        0xfe53_4d42, // SMB2
+        0x0102_1997, // 9P
+        0x5346_414f, // AFS
+        0x7375_7245, // CODA
+        0x00c3_6400, // CEPH
+    ];
+    let path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // only writes to `stat`
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+    // `f_type` is signed on some platforms, the magics are 32 bit
+    NETWORK_FS_MAGICS.contains(&(stat.f_type as u32))
+}
+
+/// Whether `path` is on a network filesystem, where changes made by
+/// other machines cause no notifications.
+#[cfg(target_os = "macos")]
+pub fn is_network_fs(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = match std::ffi::CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    // only writes to `stat`
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return false;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    ["nfs", "smbfs", "afpfs", "webdav"]
+        .iter()
+        .any(|fs| name.to_bytes() == fs.as_bytes())
+}
+
+/// Whether `path` is on a network filesystem, where changes made by
+/// other machines cause no notifications.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn is_network_fs(_path: &Path) -> bool {
+    false
+}
+
+/// Whether `path` is on a case-insensitive filesystem (like the
+/// default APFS on macOS), found by looking up the path with the
+/// case of its last component flipped.
+fn is_case_insensitive(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    let flipped: String = name
+        .chars()
+        .map(|c| {
+            if c.is_lowercase() {
+                c.to_uppercase().next().unwrap_or(c)
+            } else {
+                c.to_lowercase().next().unwrap_or(c)
+            }
+        })
+        .collect();
+    if flipped == name {
+        // no letters to flip, so ask the parent instead
+        return match path.parent() {
+            Some(parent) => is_case_insensitive(parent),
             None => false,
+        };
+    }
+    match (path.metadata(), path.with_file_name(flipped).metadata()) {
+        (Ok(original), Ok(flipped)) => {
+            original.dev() == flipped.dev() && original.ino() == flipped.ino()
         }
+        _ => false,
     }
 }
 
+/// Compare two paths, ignoring case if `fold_case` is set.
+fn same_path(a: &Path, b: &Path, fold_case: bool) -> bool {
+    a == b
+        || (fold_case && a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase())
+}
+
 /// Determine if the event path is covered by our list of watched
-/// paths.
+/// paths. Paths on case-insensitive filesystems are compared
+/// ignoring case.
 ///
 /// Returns true if:
 ///   - the event's path directly names a path in our
@@ -182,11 +1093,11 @@ impl Watch {
 ///     list
 ///   - the event's path's parent names a canonicalized path in our
 ///     watch list
-fn path_match(watched_paths: &HashSet<PathBuf>, event_path: &Path) -> bool {
+fn path_match(watched_paths: &HashMap<PathBuf, bool>, event_path: &Path) -> bool {
     let event_parent = event_path.parent();
 
-    let matches = |watched: &Path| {
-        if event_path == watched {
+    let matches = |watched: &Path, fold_case: bool| {
+        if same_path(event_path, watched, fold_case) {
             debug!(
                 "Event path ({:?}) directly matches watched path",
                 event_path
@@ -196,7 +1107,7 @@ fn path_match(watched_paths: &HashSet<PathBuf>, event_path: &Path) -> bool {
         }
 
         if let Some(parent) = event_parent {
-            if parent == watched {
+            if same_path(parent, watched, fold_case) {
                 debug!(
                     "Event path ({:?}) parent ({:?}) matches watched path",
                     event_path, parent
@@ -208,13 +1119,13 @@ fn path_match(watched_paths: &HashSet<PathBuf>, event_path: &Path) -> bool {
         false
     };
 
-    watched_paths.iter().any(|watched| {
-        if matches(watched) {
+    watched_paths.iter().any(|(watched, &fold_case)| {
+        if matches(watched, fold_case) {
             return true;
         }
 
         if let Ok(canonicalized_watch) = watched.canonicalize() {
-            if matches(&canonicalized_watch) {
+            if matches(&canonicalized_watch, fold_case) {
                 return true;
             }
         }
@@ -225,9 +1136,17 @@ fn path_match(watched_paths: &HashSet<PathBuf>, event_path: &Path) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use super::Watch;
+    use super::{
+        is_case_insensitive, path_match, Content, ExtendError, Watch, WatchBackend, CONTENTS_MAX,
+        DEBOUNCE_MAX_WINDOWS, NATIVE_NOTIFICATIONS, POLL_WATCHER_DELAY,
+    };
     use crate::bash::expect_bash;
-    use std::time::Duration;
+    use crate::clock::{Clock, FakeClock};
+    use crate::glob::Rules;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::{mpsc, Arc};
+    use std::time::{Duration, Instant};
     use tempfile::tempdir;
 
     /// upper bound of watcher (if it’s hit, something is broken)
@@ -288,6 +1207,160 @@ mod tests {
         assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
     }
 
+    /// Write `writes` times to a watched file while another thread
+    /// blocks on it, a debounce window apart on a fake clock.
+    /// Returns the directory of the file, the clock, the result of
+    /// `block` once it arrives and the blocking thread.
+    fn debounce_writes(
+        writes: u32,
+    ) -> (
+        tempfile::TempDir,
+        FakeClock,
+        mpsc::Receiver<Result<(), ()>>,
+        std::thread::JoinHandle<Watch>,
+    ) {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let clock = FakeClock::new();
+        watcher.set_clock(Arc::new(clock.clone()));
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        let file = temp.path().join("foo");
+        watcher.extend(std::slice::from_ref(&file)).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+        watcher.set_debounce(Duration::from_millis(300));
+
+        let (tx, rx) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            tx.send(watcher.block()).unwrap();
+            watcher
+        });
+        for i in 0..writes {
+            std::fs::write(&file, i.to_string()).unwrap();
+            clock.wait_for_sleepers(1);
+            if i > 0 {
+                // the write arrives within the window
+                std::thread::sleep(Duration::from_millis(100));
+                clock.advance(Duration::from_millis(300));
+            }
+        }
+        (temp, clock, rx, handle)
+    }
+
+    #[test]
+    fn debounce_bursts() {
+        let (_temp, clock, rx, handle) = debounce_writes(3);
+        clock.wait_for_sleepers(1);
+        assert!(rx.try_recv().is_err());
+
+        // no more writes within a window
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(rx.recv_timeout(upper_watcher_timeout()), Ok(Ok(())));
+        let watcher = handle.join().unwrap();
+        // all writes were one batch of changes
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_err());
+    }
+
+    #[test]
+    fn debounce_cuts_off_long_bursts() {
+        let (_temp, _clock, rx, handle) = debounce_writes(DEBOUNCE_MAX_WINDOWS + 1);
+        // the writes kept coming, but the burst lasted long enough
+        assert_eq!(rx.recv_timeout(upper_watcher_timeout()), Ok(Ok(())));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn poll_change_without_waiting() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+        assert!(!watcher.poll_change());
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(watcher.poll_change());
+        // the change is only reported once
+        assert!(!watcher.poll_change());
+    }
+
+    #[test]
+    fn remember_when_a_change_was_noticed() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+        watcher.take_changed_at();
+
+        let before = Instant::now();
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 2 > "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
+        let changed_at = watcher.take_changed_at().expect("the change was noticed");
+        assert!(before <= changed_at && changed_at <= Instant::now());
+        assert_eq!(watcher.take_changed_at(), None);
+    }
+
+    #[test]
+    fn notice_changes_during_a_build() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let clock = FakeClock::new();
+        watcher.set_clock(Arc::new(clock.clone()));
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+        watcher.take_changed_at();
+
+        // the change arrives while a build runs, which polls
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        std::thread::sleep(Duration::from_millis(100));
+        let arrived = clock.now();
+        watcher.note_arrivals();
+        // the build takes a while
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(watcher.take_changed_at(), None);
+
+        // and afterwards, the change counts from when it arrived
+        assert!(watcher.block_timeout(Duration::from_millis(0)).is_ok());
+        assert_eq!(watcher.take_changed_at(), Some(arrived));
+        assert!(watcher.block_timeout(Duration::from_millis(0)).is_err());
+    }
+
+    #[test]
+    fn latency_waits_on_the_clock() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let clock = FakeClock::new();
+        watcher.set_clock(Arc::new(clock.clone()));
+        watcher.set_latency(Duration::from_secs(10));
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        let (tx, rx) = mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            tx.send(watcher.block()).unwrap();
+        });
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+
+        // the change arrived, but more could follow within the latency
+        clock.wait_for_sleepers(1);
+        clock.advance(Duration::from_secs(9));
+        clock.wait_for_sleepers(1);
+        assert!(rx.try_recv().is_err());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(rx.recv_timeout(upper_watcher_timeout()), Ok(Ok(())));
+        handle.join().unwrap();
+    }
+
     #[test]
     fn rename_over_vim() {
         // Vim renames files in to place for atomic writes
@@ -315,4 +1388,295 @@ mod tests {
         expect_bash(r#"mv "$1/bar" "$1/foo""#, &[temp.path().as_os_str()]);
         assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
     }
+
+    #[test]
+    fn polling_backend() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+        watcher.set_backend(WatchBackend::Poll);
+        watcher.extend(&[temp.path().to_path_buf()]).unwrap();
+        assert_eq!(watcher.polled_paths(), (2, 2));
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher
+            .block_timeout(POLL_WATCHER_DELAY + upper_watcher_timeout())
+            .is_ok());
+
+        assert_eq!("poll".parse(), Ok(WatchBackend::Poll));
+        assert!("fanotify".parse::<WatchBackend>().is_err());
+        // a local directory
+        assert_eq!(
+            WatchBackend::Auto.resolve(temp.path()),
+            if NATIVE_NOTIFICATIONS {
+                WatchBackend::Inotify
+            } else {
+                WatchBackend::Poll
+            }
+        );
+    }
+
+    #[test]
+    fn poll_without_native_notifications() {
+        for backend in &[
+            WatchBackend::Auto,
+            WatchBackend::Inotify,
+            WatchBackend::Poll,
+        ] {
+            assert_eq!(backend.resolve_with(false, || false), WatchBackend::Poll);
+        }
+        assert_eq!(
+            WatchBackend::Auto.resolve_with(true, || true),
+            WatchBackend::Poll
+        );
+        assert_eq!(
+            WatchBackend::Inotify.resolve_with(true, || true),
+            WatchBackend::Inotify
+        );
+    }
+
+    #[test]
+    fn refuse_too_broad() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+        let home = temp.path().canonicalize().unwrap().join("home");
+        let project = home.join("project");
+        std::fs::create_dir_all(&project).unwrap();
+        watcher.too_broad = vec![home.clone()];
+
+        // the directory itself and the ones containing it
+        let err = watcher.extend(std::slice::from_ref(&home)).unwrap_err();
+        assert!(err.to_string().contains("scope = \"project\""), "{}", err);
+        match watcher.extend(&[temp.path().to_path_buf()]) {
+            Err(ExtendError::TooBroad { path, dir }) => {
+                assert_eq!(path, temp.path());
+                assert_eq!(dir, home);
+            }
+            otherwise => panic!("{:?}", otherwise),
+        }
+        assert!(watcher.extend(&[project.join("..")]).is_err());
+        assert_eq!(watcher.watched_files(), 0);
+
+        // but not the directories in it
+        watcher.extend(&[project]).unwrap();
+
+        // nor the directory itself, if it is the project’s
+        watcher.set_project_dir(&home);
+        watcher.extend(std::slice::from_ref(&home)).unwrap();
+        assert!(watcher.extend(&[temp.path().to_path_buf()]).is_err());
+    }
+
+    #[test]
+    fn refuse_store_dir() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+        let store = temp.path().canonicalize().unwrap().join("store");
+        std::fs::create_dir_all(store.join("abc-hello/bin")).unwrap();
+        watcher.set_store_dir(&store);
+
+        match watcher.extend(&[temp.path().to_path_buf()]) {
+            Err(ExtendError::TooBroad { dir, .. }) => assert_eq!(dir, store),
+            otherwise => panic!("{:?}", otherwise),
+        }
+        // store paths are not descended into
+        watcher.extend(&[store.join("abc-hello")]).unwrap();
+        assert_eq!(watcher.watched_files(), 1);
+    }
+
+    #[test]
+    fn ignore_unchanged_content() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        // only the modification time changes
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_err());
+
+        // the same content is written again
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_err());
+
+        expect_bash(r#"echo 2 > "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
+    }
+
+    #[test]
+    fn symlink_loops() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        // every level links back to the top twice, so following the
+        // links would take 2^40 steps before the kernel gives up
+        expect_bash(
+            r#"mkdir -p "$1/dir"; ln -s .. "$1/dir/a"; ln -s .. "$1/dir/b"; ln -s loop "$1/loop""#,
+            &[temp.path().as_os_str()],
+        );
+        watcher.extend(&[temp.path().to_path_buf()]).unwrap();
+        assert!(watcher.watched_files() < 10);
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"echo 1 > "$1/dir/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
+    }
+
+    #[test]
+    fn ignored_paths() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(
+            r#"mkdir -p "$1/logs" "$1/target"; echo 1 > "$1/target/input""#,
+            &[temp.path().as_os_str()],
+        );
+        let mut rules = Rules::default();
+        rules.add_gitignore("target/\n");
+        rules.add("*.log").unwrap();
+        rules.add("logs/").unwrap();
+        watcher.set_ignore(temp.path(), rules);
+        watcher
+            .extend(&[temp.path().to_path_buf(), temp.path().join("target/input")])
+            .unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"echo 1 > "$1/build.log""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 1 > "$1/logs/today""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 1 > "$1/target/output""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_err());
+
+        // an input nix read is watched even if it is gitignored
+        expect_bash(r#"echo 2 > "$1/target/input""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
+    }
+
+    /// A change between watching a path and watching it again (like
+    /// during a build) is still noticed.
+    #[test]
+    fn keep_baseline() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"echo 2 > "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
+    }
+
+    #[test]
+    fn forget_contents_beyond_the_limit() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().to_path_buf()]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        // files below the directory seen earlier
+        let content = || Content::read(&temp.path().join("foo")).unwrap();
+        for i in 0..CONTENTS_MAX {
+            watcher
+                .contents
+                .borrow_mut()
+                .insert(temp.path().join(format!("seen-{}", i)), content());
+        }
+
+        expect_bash(r#"echo 1 > "$1/bar""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
+        let contents = watcher.contents.borrow();
+        assert!(contents.len() < CONTENTS_MAX);
+        assert!(contents.contains_key(&temp.path().join("bar")));
+    }
+
+    #[test]
+    fn hashed_inputs() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1/dir""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"echo 1 > "$1/dir/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend_hashed(&[temp.path().join("dir")]);
+        assert!(!watcher.hashed_inputs_changed());
+
+        // a change in content is detected, but only once
+        expect_bash(r#"echo 2 > "$1/dir/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.hashed_inputs_changed());
+        assert!(!watcher.hashed_inputs_changed());
+
+        // so is a new file
+        expect_bash(r#"touch "$1/dir/bar""#, &[temp.path().as_os_str()]);
+        assert!(watcher.hashed_inputs_changed());
+
+        // the content is not hashed again while the sizes and
+        // modification times stay the same
+        expect_bash(
+            r#"touch -r "$1/dir/foo" "$1/ref"
+               echo 3 > "$1/dir/foo"
+               touch -r "$1/ref" "$1/dir/foo""#,
+            &[temp.path().as_os_str()],
+        );
+        assert!(!watcher.hashed_inputs_changed());
+        // but once they change, it is
+        expect_bash(r#"touch "$1/dir/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.hashed_inputs_changed());
+        // and touching without changing the content is no change
+        expect_bash(r#"touch "$1/dir/foo""#, &[temp.path().as_os_str()]);
+        assert!(!watcher.hashed_inputs_changed());
+
+        // hashed inputs are not watched
+        assert!(watcher.block_timeout(Duration::from_millis(250)).is_err());
+    }
+
+    #[test]
+    fn case_folding() {
+        let watches = |fold_case| {
+            let mut watches = HashMap::new();
+            watches.insert(PathBuf::from("/nonexistent/Project/shell.nix"), fold_case);
+            watches
+        };
+        let event = Path::new("/nonexistent/project/Shell.nix");
+        assert!(!path_match(&watches(false), event));
+        assert!(path_match(&watches(true), event));
+        // changes in the watched directory match, too
+        assert!(path_match(
+            &watches(true),
+            Path::new("/nonexistent/project/shell.nix/foo")
+        ));
+    }
+
+    #[test]
+    fn detect_case_insensitivity() {
+        let temp = tempdir().unwrap();
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        // on case-insensitive filesystems, `FOO` names the same file
+        let insensitive = temp.path().join("FOO").exists();
+        assert_eq!(is_case_insensitive(&temp.path().join("foo")), insensitive);
+    }
+
+    #[test]
+    fn directory_granularity() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        watcher.set_directory_granularity(true);
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        // only the directory is registered
+        assert_eq!(watcher.registered.len(), 1);
+        macos_eat_late_notifications(&mut watcher);
+
+        // events of other files in the directory are filtered out
+        expect_bash(r#"echo 1 > "$1/bar""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_err());
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(upper_watcher_timeout()).is_ok());
+    }
 }
@@ -0,0 +1,753 @@
+//! Watch paths with kqueue, on FreeBSD and the other BSDs, where
+//! `notify` only polls (it has no kqueue watcher).
+//!
+//! kqueue watches open files, not paths, and says what happened to
+//! them but not to which names: a directory only reports that its
+//! entries changed, and a file that was renamed or removed doesn’t
+//! say where to. This watcher turns that into the events `notify`’s
+//! inotify watcher sends (see `normalize` and `changes`):
+//!
+//! - The files in a watched directory are opened as well, so that
+//!   writing to them is reported, like inotify does.
+//! - When a directory’s entries change, they are read again and
+//!   compared with the last ones. An entry which left the directory
+//!   while another one with the same inode arrived was renamed, and
+//!   is reported as a pair of `RENAME` events with a cookie.
+//! - A watched file which was removed or renamed is opened again if
+//!   its path exists afterwards, so that editors which save by
+//!   renaming a new file over the old one don’t end its watch.
+
+use notify::{op, Op, RawEvent};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// The `EVFILT_VNODE` notes kqueue reported for a file or directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Notes {
+    /// `NOTE_DELETE`: it was unlinked.
+    pub delete: bool,
+    /// `NOTE_WRITE`: it (or, for a directory, its entries) changed.
+    pub write: bool,
+    /// `NOTE_EXTEND`: it grew.
+    pub extend: bool,
+    /// `NOTE_ATTRIB`: its attributes changed.
+    pub attrib: bool,
+    /// `NOTE_LINK`: its link count changed.
+    pub link: bool,
+    /// `NOTE_RENAME`: it was renamed.
+    pub rename: bool,
+    /// `NOTE_REVOKE`: access to it was revoked (its filesystem was
+    /// unmounted, for example).
+    pub revoke: bool,
+}
+
+impl Notes {
+    /// Whether the path no longer refers to the watched file.
+    pub fn gone(self) -> bool {
+        self.delete || self.rename || self.revoke
+    }
+
+    /// Whether the entries of a directory may have changed. Which
+    /// notes report that differs between FreeBSD, macOS and the other
+    /// BSDs (and between filesystems), so any of them counts.
+    pub fn entries_changed(self) -> bool {
+        self.write || self.extend || self.link
+    }
+}
+
+/// What a watched path is to the watcher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// A file (or a directory) whose parent isn’t watched.
+    Watched { is_dir: bool },
+    /// A file in a watched directory, which reports its removal and
+    /// renames (see `changes`).
+    Entry,
+}
+
+/// The events of `notes` for `path`. Apart from attributes, a
+/// directory’s own notes cause no events: its entries are compared
+/// instead (see `changes`).
+pub fn normalize(path: &Path, notes: Notes, kind: Kind) -> Vec<RawEvent> {
+    let mut ops = Vec::new();
+    if kind != (Kind::Watched { is_dir: true }) && (notes.write || notes.extend) {
+        ops.push(op::WRITE);
+    }
+    if notes.attrib || (notes.link && kind != (Kind::Watched { is_dir: true })) {
+        ops.push(op::CHMOD);
+    }
+    if let Kind::Watched { .. } = kind {
+        if notes.delete || notes.revoke {
+            ops.push(op::REMOVE);
+        } else if notes.rename {
+            ops.push(op::RENAME);
+        }
+    }
+    ops.into_iter()
+        .map(|op| raw_event(path, op, None))
+        .collect()
+}
+
+/// The entries of a directory, by name, with their inode.
+pub type Entries = BTreeMap<OsString, u64>;
+
+/// Read the entries of the directory `dir`.
+pub fn read_entries(dir: &Path) -> io::Result<Entries> {
+    let mut entries = Entries::new();
+    let read_dir = dir.read_dir()?;
+    for entry in read_dir {
+        let entry = entry?;
+        match entry.metadata() {
+            Ok(metadata) => {
+                entries.insert(entry.file_name(), metadata.ino());
+            }
+            // removed since it was listed
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(entries)
+}
+
+/// The events which turned the entries `old` of the directory `dir`
+/// into `new`. An entry whose inode left under another name was
+/// renamed (as was one replaced by a rename); the pair of `RENAME`
+/// events shares a cookie, taken from `cookie`.
+pub fn changes(dir: &Path, old: &Entries, new: &Entries, cookie: &mut u32) -> Vec<RawEvent> {
+    let mut left: BTreeMap<u64, &OsString> = old
+        .iter()
+        .filter(|(name, _)| !new.contains_key(*name))
+        .map(|(name, &ino)| (ino, name))
+        .collect();
+    let mut events = Vec::new();
+    for (name, ino) in new {
+        if old.get(name) == Some(ino) {
+            continue;
+        }
+        match left.remove(ino) {
+            Some(from) => {
+                *cookie = cookie.wrapping_add(1);
+                events.push(raw_event(&dir.join(from), op::RENAME, Some(*cookie)));
+                events.push(raw_event(&dir.join(name), op::RENAME, Some(*cookie)));
+            }
+            None => events.push(raw_event(&dir.join(name), op::CREATE, None)),
+        }
+    }
+    events.extend(
+        left.values()
+            .map(|name| raw_event(&dir.join(name), op::REMOVE, None)),
+    );
+    events
+}
+
+fn raw_event(path: &Path, op: Op, cookie: Option<u32>) -> RawEvent {
+    RawEvent {
+        path: Some(path.to_path_buf()),
+        op: Ok(op),
+        cookie,
+    }
+}
+
+#[cfg(any(
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "ios",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+pub use self::watcher::Watcher;
+
+#[cfg(any(
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "ios",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+mod watcher {
+    extern crate nix;
+
+    use self::nix::errno::Errno;
+    use self::nix::libc;
+    use self::nix::sys::event::{self, EventFilter, EventFlag, FilterFlag, KEvent};
+    use self::nix::unistd;
+    use super::{changes, normalize, raw_event, read_entries, Entries, Kind, Notes};
+    use notify::{op, RawEvent, RecursiveMode};
+    use std::collections::HashMap;
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc::Sender;
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+
+    /// How long the watcher thread waits for events before checking
+    /// whether the watcher was dropped.
+    const WAKE_UP_MS: usize = 100;
+
+    /// Watches paths with kqueue, and sends their events like
+    /// `notify`’s watchers do.
+    pub struct Watcher {
+        kq: RawFd,
+        state: Arc<Mutex<State>>,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    /// An open file or directory.
+    struct Vnode {
+        path: PathBuf,
+        file: File,
+        /// Registered with `watch`, rather than opened as the entry
+        /// of a watched directory.
+        watched: bool,
+        /// The last seen entries, for directories.
+        entries: Option<Entries>,
+    }
+
+    struct State {
+        kq: RawFd,
+        /// The open vnodes by id, which kqueue returns with their
+        /// events (file descriptors are reused, ids are not).
+        vnodes: HashMap<usize, Vnode>,
+        ids: HashMap<PathBuf, usize>,
+        next_id: usize,
+        cookie: u32,
+    }
+
+    impl Watcher {
+        /// Create a watcher which sends the events of watched paths
+        /// to `tx`.
+        pub fn new_raw(tx: Sender<RawEvent>) -> Result<Watcher, notify::Error> {
+            let kq = event::kqueue().map_err(to_io)?;
+            let state = Arc::new(Mutex::new(State {
+                kq,
+                vnodes: HashMap::new(),
+                ids: HashMap::new(),
+                next_id: 0,
+                cookie: 0,
+            }));
+            let stop = Arc::new(AtomicBool::new(false));
+            let thread = {
+                let state = state.clone();
+                let stop = stop.clone();
+                std::thread::spawn(move || run(kq, &state, &tx, &stop))
+            };
+            Ok(Watcher {
+                kq,
+                state,
+                stop,
+                thread: Some(thread),
+            })
+        }
+
+        /// Watch `path`, and the files in it if it is a directory
+        /// (only non-recursive watches are supported).
+        pub fn watch<P: AsRef<Path>>(
+            &mut self,
+            path: P,
+            recursive_mode: RecursiveMode,
+        ) -> Result<(), notify::Error> {
+            if let RecursiveMode::Recursive = recursive_mode {
+                return Err(notify::Error::Generic(
+                    "the kqueue watcher only watches paths non-recursively".to_string(),
+                ));
+            }
+            let path = std::env::current_dir()?.join(path);
+            let mut state = self.state.lock().expect("kqueue state lock poisoned");
+            match state.ids.get(&path).cloned() {
+                Some(id) => {
+                    if let Some(vnode) = state.vnodes.get_mut(&id) {
+                        vnode.watched = true;
+                    }
+                    Ok(())
+                }
+                None => state.open(&path, true).map_err(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => notify::Error::PathNotFound,
+                    _ => notify::Error::Io(e),
+                }),
+            }
+        }
+    }
+
+    impl Drop for Watcher {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+            let _ = unistd::close(self.kq);
+        }
+    }
+
+    /// Send the events of the vnodes in `state` to `tx`, until `stop`
+    /// is set or nobody listens anymore.
+    fn run(kq: RawFd, state: &Mutex<State>, tx: &Sender<RawEvent>, stop: &AtomicBool) {
+        let empty = KEvent::new(
+            0,
+            EventFilter::EVFILT_VNODE,
+            EventFlag::empty(),
+            FilterFlag::empty(),
+            0,
+            0,
+        );
+        let mut ready = vec![empty; 64];
+        while !stop.load(Ordering::SeqCst) {
+            let n = match event::kevent(kq, &[], &mut ready, WAKE_UP_MS) {
+                Ok(n) => n,
+                Err(nix::Error::Sys(Errno::EINTR)) => continue,
+                Err(e) => {
+                    let _ = tx.send(RawEvent {
+                        path: None,
+                        op: Err(notify::Error::Io(to_io(e))),
+                        cookie: None,
+                    });
+                    return;
+                }
+            };
+            let events = state
+                .lock()
+                .expect("kqueue state lock poisoned")
+                .handle(ready[..n].iter().map(|ev| (ev.udata() as usize, notes(ev))));
+            for event in events {
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn notes(ev: &KEvent) -> Notes {
+        let fflags = ev.fflags();
+        Notes {
+            delete: fflags.contains(FilterFlag::NOTE_DELETE),
+            write: fflags.contains(FilterFlag::NOTE_WRITE),
+            extend: fflags.contains(FilterFlag::NOTE_EXTEND),
+            attrib: fflags.contains(FilterFlag::NOTE_ATTRIB),
+            link: fflags.contains(FilterFlag::NOTE_LINK),
+            rename: fflags.contains(FilterFlag::NOTE_RENAME),
+            revoke: fflags.contains(FilterFlag::NOTE_REVOKE),
+        }
+    }
+
+    impl State {
+        /// The events for the notes of the vnodes with the given ids.
+        fn handle<I>(&mut self, ready: I) -> Vec<RawEvent>
+        where
+            I: Iterator<Item = (usize, Notes)>,
+        {
+            let mut events = Vec::new();
+            let mut gone = Vec::new();
+            for (id, notes) in ready {
+                let (path, is_dir, watched) = match self.vnodes.get(&id) {
+                    Some(vnode) => (vnode.path.clone(), vnode.entries.is_some(), vnode.watched),
+                    // replaced earlier in this batch
+                    None => continue,
+                };
+                let kind = if self.in_watched_dir(&path) {
+                    Kind::Entry
+                } else {
+                    Kind::Watched { is_dir }
+                };
+                events.extend(normalize(&path, notes, kind));
+                if is_dir && notes.entries_changed() && !notes.gone() {
+                    events.extend(self.rescan(id));
+                }
+                if notes.gone() {
+                    gone.push((id, path, watched, kind));
+                }
+            }
+            for (id, path, watched, kind) in gone {
+                if !self.vnodes.contains_key(&id) {
+                    continue;
+                }
+                self.close(id);
+                // entries come back with their directory’s rescan
+                if watched && path.exists() {
+                    match self.open(&path, true) {
+                        Ok(()) if kind != Kind::Entry => {
+                            events.push(raw_event(&path, op::CREATE, None))
+                        }
+                        Ok(()) => {}
+                        Err(e) => warn!("could not watch {} again: {}", path.display(), e),
+                    }
+                }
+            }
+            events
+        }
+
+        fn in_watched_dir(&self, path: &Path) -> bool {
+            path.parent()
+                .and_then(|parent| self.ids.get(parent))
+                .and_then(|id| self.vnodes.get(id))
+                .map(|parent| parent.watched && parent.entries.is_some())
+                .unwrap_or(false)
+        }
+
+        /// Compare the entries of the directory `id` with the last
+        /// ones, and open the new files among them.
+        fn rescan(&mut self, id: usize) -> Vec<RawEvent> {
+            let (dir, new) = match self.vnodes.get(&id) {
+                Some(vnode) => match read_entries(&vnode.path) {
+                    Ok(new) => (vnode.path.clone(), new),
+                    Err(e) => {
+                        debug!("could not read {}: {}", vnode.path.display(), e);
+                        return Vec::new();
+                    }
+                },
+                None => return Vec::new(),
+            };
+            let old = match self.vnodes.get_mut(&id) {
+                Some(vnode) => vnode.entries.replace(new.clone()).unwrap_or_default(),
+                None => Entries::new(),
+            };
+            let events = changes(&dir, &old, &new, &mut self.cookie);
+            for (name, ino) in &old {
+                if new.get(name) != Some(ino) {
+                    self.close_entry(&dir.join(name));
+                }
+            }
+            for (name, ino) in &new {
+                if old.get(name) != Some(ino) {
+                    self.open_entry(&dir.join(name));
+                }
+            }
+            events
+        }
+
+        /// Open `path`, the entry of a watched directory, unless it
+        /// is a directory or open already.
+        fn open_entry(&mut self, path: &Path) {
+            if path.is_dir() || self.ids.contains_key(path) {
+                return;
+            }
+            if let Err(e) = self.open(path, false) {
+                debug!("could not watch {}: {}", path.display(), e);
+            }
+        }
+
+        /// Close `path`, an entry of a watched directory which left
+        /// it, or reopen it if it was also watched itself.
+        fn close_entry(&mut self, path: &Path) {
+            if let Some(id) = self.ids.get(path).cloned() {
+                let watched = self.vnodes.get(&id).map(|vnode| vnode.watched);
+                self.close(id);
+                if watched == Some(true) && path.exists() {
+                    if let Err(e) = self.open(path, true) {
+                        warn!("could not watch {} again: {}", path.display(), e);
+                    }
+                }
+            }
+        }
+
+        /// Open `path` and register it with kqueue; a directory’s
+        /// files are opened as well.
+        fn open(&mut self, path: &Path, watched: bool) -> std::io::Result<()> {
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(path)?;
+            let entries = if file.metadata()?.is_dir() {
+                Some(read_entries(path)?)
+            } else {
+                None
+            };
+            let id = self.next_id;
+            self.next_id += 1;
+            let change = KEvent::new(
+                file.as_raw_fd() as libc::uintptr_t,
+                EventFilter::EVFILT_VNODE,
+                EventFlag::EV_ADD | EventFlag::EV_ENABLE | EventFlag::EV_CLEAR,
+                FilterFlag::NOTE_DELETE
+                    | FilterFlag::NOTE_WRITE
+                    | FilterFlag::NOTE_EXTEND
+                    | FilterFlag::NOTE_ATTRIB
+                    | FilterFlag::NOTE_LINK
+                    | FilterFlag::NOTE_RENAME
+                    | FilterFlag::NOTE_REVOKE,
+                0,
+                id as libc::intptr_t,
+            );
+            event::kevent(self.kq, &[change], &mut [], 0).map_err(to_io)?;
+            let names: Vec<_> = entries
+                .iter()
+                .flat_map(|entries| entries.keys())
+                .map(|name| path.join(name))
+                .collect();
+            self.ids.insert(path.to_path_buf(), id);
+            self.vnodes.insert(
+                id,
+                Vnode {
+                    path: path.to_path_buf(),
+                    file,
+                    watched,
+                    entries,
+                },
+            );
+            if watched {
+                for name in names {
+                    self.open_entry(&name);
+                }
+            }
+            Ok(())
+        }
+
+        /// Close the vnode `id` (which unregisters it from kqueue),
+        /// and the entries opened for it.
+        fn close(&mut self, id: usize) {
+            if let Some(vnode) = self.vnodes.remove(&id) {
+                if self.ids.get(&vnode.path) == Some(&id) {
+                    self.ids.remove(&vnode.path);
+                }
+                for name in vnode.entries.iter().flat_map(|entries| entries.keys()) {
+                    let entry = vnode.path.join(name);
+                    let unwatched = self
+                        .ids
+                        .get(&entry)
+                        .and_then(|id| self.vnodes.get(id).map(|entry| (*id, entry.watched)));
+                    if let Some((id, false)) = unwatched {
+                        self.close(id);
+                    }
+                }
+                drop(vnode.file);
+            }
+        }
+    }
+
+    fn to_io(e: nix::Error) -> std::io::Error {
+        match e.as_errno() {
+            Some(errno) => std::io::Error::from_raw_os_error(errno as i32),
+            None => std::io::Error::new(std::io::ErrorKind::Other, e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changes, normalize, read_entries, Entries, Kind, Notes};
+    use notify::{op, Op, RawEvent};
+    use std::ffi::OsString;
+    use std::path::{Path, PathBuf};
+
+    fn ops(events: &[RawEvent]) -> Vec<(PathBuf, Op, Option<u32>)> {
+        events
+            .iter()
+            .map(|event| {
+                (
+                    event.path.clone().unwrap(),
+                    *event.op.as_ref().unwrap(),
+                    event.cookie,
+                )
+            })
+            .collect()
+    }
+
+    fn entries(names: &[(&str, u64)]) -> Entries {
+        names
+            .iter()
+            .map(|&(name, ino)| (OsString::from(name), ino))
+            .collect()
+    }
+
+    #[test]
+    fn files() {
+        let path = Path::new("/project/shell.nix");
+        let file = Kind::Watched { is_dir: false };
+        let written = Notes {
+            write: true,
+            extend: true,
+            ..Notes::default()
+        };
+        assert_eq!(
+            ops(&normalize(path, written, file)),
+            vec![(path.to_path_buf(), op::WRITE, None)]
+        );
+        let truncated = Notes {
+            attrib: true,
+            ..Notes::default()
+        };
+        assert_eq!(
+            ops(&normalize(path, truncated, file)),
+            vec![(path.to_path_buf(), op::CHMOD, None)]
+        );
+        let replaced = Notes {
+            delete: true,
+            link: true,
+            ..Notes::default()
+        };
+        assert_eq!(
+            ops(&normalize(path, replaced, file)),
+            vec![
+                (path.to_path_buf(), op::CHMOD, None),
+                (path.to_path_buf(), op::REMOVE, None)
+            ]
+        );
+        let renamed = Notes {
+            rename: true,
+            ..Notes::default()
+        };
+        assert_eq!(
+            ops(&normalize(path, renamed, file)),
+            vec![(path.to_path_buf(), op::RENAME, None)]
+        );
+        assert!(replaced.gone() && renamed.gone() && !written.gone());
+        // the directory reports those
+        assert!(normalize(path, renamed, Kind::Entry).is_empty());
+        assert!(normalize(path, replaced, Kind::Entry)
+            .iter()
+            .all(|event| event.op.as_ref().unwrap() == &op::CHMOD));
+    }
+
+    #[test]
+    fn directories() {
+        let path = Path::new("/project");
+        let dir = Kind::Watched { is_dir: true };
+        // FreeBSD reports new subdirectories as links
+        for notes in &[
+            Notes {
+                write: true,
+                ..Notes::default()
+            },
+            Notes {
+                extend: true,
+                ..Notes::default()
+            },
+            Notes {
+                write: true,
+                link: true,
+                ..Notes::default()
+            },
+        ] {
+            assert!(notes.entries_changed());
+            assert!(normalize(path, *notes, dir).is_empty());
+        }
+        let removed = Notes {
+            delete: true,
+            ..Notes::default()
+        };
+        assert!(!removed.entries_changed());
+        assert_eq!(
+            ops(&normalize(path, removed, dir)),
+            vec![(path.to_path_buf(), op::REMOVE, None)]
+        );
+    }
+
+    #[test]
+    fn entry_changes() {
+        let dir = Path::new("/project");
+        let mut cookie = 0;
+        let old = entries(&[("default.nix", 1), ("shell.nix", 2), (".shell.nix.swp", 3)]);
+
+        // `rm shell.nix`, `touch new.nix`
+        let new = entries(&[("default.nix", 1), ("new.nix", 4), (".shell.nix.swp", 3)]);
+        assert_eq!(
+            ops(&changes(dir, &old, &new, &mut cookie)),
+            vec![
+                (dir.join("new.nix"), op::CREATE, None),
+                (dir.join("shell.nix"), op::REMOVE, None),
+            ]
+        );
+
+        // `mv shell.nix default.nix`, as editors save
+        let new = entries(&[("default.nix", 2), (".shell.nix.swp", 3)]);
+        assert_eq!(
+            ops(&changes(dir, &old, &new, &mut cookie)),
+            vec![
+                (dir.join("shell.nix"), op::RENAME, Some(1)),
+                (dir.join("default.nix"), op::RENAME, Some(1)),
+            ]
+        );
+
+        // `mv .shell.nix.swp swap`, `mv default.nix old.nix`
+        let new = entries(&[("old.nix", 1), ("shell.nix", 2), ("swap", 3)]);
+        assert_eq!(
+            ops(&changes(dir, &old, &new, &mut cookie)),
+            vec![
+                (dir.join("default.nix"), op::RENAME, Some(2)),
+                (dir.join("old.nix"), op::RENAME, Some(2)),
+                (dir.join(".shell.nix.swp"), op::RENAME, Some(3)),
+                (dir.join("swap"), op::RENAME, Some(3)),
+            ]
+        );
+
+        assert!(changes(dir, &old, &old, &mut cookie).is_empty());
+    }
+
+    /// A watched file keeps being watched after a new file was
+    /// renamed over it, and the rename is reported with both names.
+    #[cfg(any(
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    #[test]
+    fn renames_over_watched_files() {
+        use super::Watcher;
+        use notify::RecursiveMode;
+        use std::fs;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let temp = tempfile::tempdir().unwrap();
+        let shell = temp.path().join("shell.nix");
+        fs::write(&shell, "1").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = Watcher::new_raw(tx).unwrap();
+        watcher
+            .watch(temp.path(), RecursiveMode::NonRecursive)
+            .unwrap();
+        watcher.watch(&shell, RecursiveMode::NonRecursive).unwrap();
+        let settle = || {
+            let mut events = Vec::new();
+            while let Ok(event) = rx.recv_timeout(Duration::from_millis(500)) {
+                events.push((event.path.unwrap(), event.op.unwrap()));
+            }
+            events
+        };
+
+        let new = temp.path().join("shell.nix.new");
+        fs::write(&new, "2").unwrap();
+        assert!(settle().contains(&(new.clone(), op::CREATE)));
+        fs::rename(&new, &shell).unwrap();
+        let events = settle();
+        assert!(events.contains(&(new, op::RENAME)), "{:?}", events);
+        assert!(
+            events.contains(&(shell.clone(), op::RENAME)),
+            "{:?}",
+            events
+        );
+
+        fs::write(&shell, "3").unwrap();
+        let events = settle();
+        assert!(events.contains(&(shell, op::WRITE)), "{:?}", events);
+    }
+
+    #[test]
+    fn read_directory_entries() {
+        use std::os::unix::fs::MetadataExt;
+        let temp = tempfile::tempdir().unwrap();
+        std::fs::write(temp.path().join("shell.nix"), "").unwrap();
+        std::fs::create_dir(temp.path().join("nix")).unwrap();
+        let read = read_entries(temp.path()).unwrap();
+        assert_eq!(
+            read,
+            entries(&[
+                ("nix", temp.path().join("nix").metadata().unwrap().ino()),
+                (
+                    "shell.nix",
+                    temp.path().join("shell.nix").metadata().unwrap().ino()
+                ),
+            ])
+        );
+    }
+}
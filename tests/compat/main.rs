@@ -0,0 +1,471 @@
+//! Wire compatibility between lorri versions.
+//!
+//! `golden/v<N>` holds the messages as encoded by protocol version N.
+//! Every version must keep decoding (and encoding to) the messages
+//! of all versions before it; when a message changes, add a new
+//! version directory instead of touching the old ones.
+
+extern crate bincode;
+extern crate lorri;
+
+use lorri::socket::communicate::listener::ConnectionAccepted;
+use lorri::socket::communicate::{
+    BuildState, CancelBuild, CancelBuildResult, CommunicationType, EventMessage, FollowLog,
+    Latency, LatencyResult, ListProjects, ListProjectsResult, LogMessage, Monitor, Ping,
+    PingAttribute, PingResult, ProjectStatus, Rebuild, RegistrationError, RequestBuild,
+    RequestBuildResult, Resume, Shutdown, ShutdownResult, Stats, StatsResult, Status, StatusResult,
+    StreamEvents, Subscribe, WaitIdle, WaitIdleFor, WaitIdleForResult, WaitIdleResult,
+    WatchedProject,
+};
+use lorri::NixFile;
+use std::path::PathBuf;
+
+/// Decode `golden`, check it with `expect` and make sure
+/// encoding the value again produces the same bytes.
+fn round_trip<T, F>(golden: &[u8], expect: F)
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    F: FnOnce(&T),
+{
+    let value: T = bincode::deserialize(golden).expect("golden file does not decode");
+    expect(&value);
+    assert_eq!(
+        bincode::serialize(&value).unwrap(),
+        golden,
+        "encoding changed"
+    );
+}
+
+#[test]
+fn v1_messages() {
+    round_trip(
+        include_bytes!("golden/v1/communication_type_ping.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::Ping),
+    );
+    round_trip(
+        include_bytes!("golden/v1/connection_accepted.bin"),
+        |_: &ConnectionAccepted| (),
+    );
+    round_trip(include_bytes!("golden/v1/ping.bin"), |p: &Ping| {
+        assert_eq!(
+            p.nix_file,
+            NixFile::from(PathBuf::from("/home/user/project/shell.nix"))
+        )
+    });
+}
+
+#[test]
+fn v2_messages() {
+    round_trip(
+        include_bytes!("golden/v2/communication_type_cancel_build.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::CancelBuild),
+    );
+    round_trip(
+        include_bytes!("golden/v2/cancel_build.bin"),
+        |c: &CancelBuild| {
+            assert_eq!(
+                c.nix_file,
+                NixFile::from(PathBuf::from("/home/user/project/shell.nix"))
+            )
+        },
+    );
+    round_trip(
+        include_bytes!("golden/v2/cancel_build_result.bin"),
+        |r: &CancelBuildResult| assert_eq!(*r, CancelBuildResult { cancelled: true }),
+    );
+}
+
+#[test]
+fn v3_messages() {
+    round_trip(
+        include_bytes!("golden/v3/communication_type_wait_idle.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::WaitIdle),
+    );
+    round_trip(include_bytes!("golden/v3/wait_idle.bin"), |w: &WaitIdle| {
+        assert_eq!(
+            w.nix_file,
+            Some(NixFile::from(PathBuf::from("/home/user/project/shell.nix")))
+        )
+    });
+    round_trip(
+        include_bytes!("golden/v3/wait_idle_result.bin"),
+        |r: &WaitIdleResult| assert_eq!(*r, WaitIdleResult { watched: true }),
+    );
+}
+
+#[test]
+fn v4_messages() {
+    round_trip(
+        include_bytes!("golden/v4/communication_type_follow_log.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::FollowLog),
+    );
+    round_trip(
+        include_bytes!("golden/v4/follow_log.bin"),
+        |f: &FollowLog| {
+            assert_eq!(
+                f.nix_file,
+                NixFile::from(PathBuf::from("/home/user/project/shell.nix"))
+            );
+            assert!(f.follow);
+        },
+    );
+    round_trip(
+        include_bytes!("golden/v4/log_message_line.bin"),
+        |m: &LogMessage| {
+            assert_eq!(
+                *m,
+                LogMessage::Line(String::from("building '/nix/store/abc-shell.drv'..."))
+            )
+        },
+    );
+}
+
+#[test]
+fn v5_messages() {
+    round_trip(
+        include_bytes!("golden/v5/communication_type_stream_events.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::StreamEvents),
+    );
+    round_trip(
+        include_bytes!("golden/v5/stream_events.bin"),
+        |s: &StreamEvents| {
+            assert_eq!(
+                s.nix_file,
+                Some(NixFile::from(PathBuf::from("/home/user/project/shell.nix")))
+            )
+        },
+    );
+    round_trip(
+        include_bytes!("golden/v5/event_message_gap.bin"),
+        |m: &EventMessage| assert_eq!(*m, EventMessage::Gap { dropped: 3 }),
+    );
+}
+
+#[test]
+fn v6_messages() {
+    round_trip(
+        include_bytes!("golden/v6/communication_type_monitor.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::Monitor),
+    );
+    round_trip(include_bytes!("golden/v6/monitor.bin"), |m: &Monitor| {
+        assert_eq!(
+            m.nix_file,
+            Some(NixFile::from(PathBuf::from("/home/user/project/shell.nix")))
+        );
+        assert_eq!(m.since, Some(42));
+    });
+}
+
+#[test]
+fn v7_messages() {
+    round_trip(
+        include_bytes!("golden/v7/communication_type_list_projects.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::ListProjects),
+    );
+    round_trip(
+        include_bytes!("golden/v7/list_projects.bin"),
+        |_: &ListProjects| (),
+    );
+    round_trip(
+        include_bytes!("golden/v7/list_projects_result.bin"),
+        |r: &ListProjectsResult| {
+            assert_eq!(
+                r.projects,
+                vec![WatchedProject {
+                    nix_file: NixFile::from(PathBuf::from("/home/user/project/shell.nix")),
+                    state: BuildState::Success,
+                    last_build: Some(1_700_000_000),
+                    gc_root: PathBuf::from(
+                        "/home/user/.cache/lorri/gc_roots/0123/gc_root/shell_gc_root"
+                    ),
+                }]
+            )
+        },
+    );
+}
+
+#[test]
+fn v8_messages() {
+    round_trip(
+        include_bytes!("golden/v8/communication_type_shutdown.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::Shutdown),
+    );
+    round_trip(include_bytes!("golden/v8/shutdown.bin"), |s: &Shutdown| {
+        assert!(s.cancel_builds)
+    });
+    round_trip(
+        include_bytes!("golden/v8/shutdown_result.bin"),
+        |r: &ShutdownResult| assert_eq!(r.running_builds, 2),
+    );
+}
+
+#[test]
+fn v9_messages() {
+    round_trip(
+        include_bytes!("golden/v9/communication_type_status.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::Status),
+    );
+    round_trip(include_bytes!("golden/v9/status.bin"), |s: &Status| {
+        assert_eq!(
+            s.nix_file,
+            NixFile::from(PathBuf::from("/home/user/project/shell.nix"))
+        )
+    });
+    round_trip(
+        include_bytes!("golden/v9/status_result.bin"),
+        |r: &StatusResult| {
+            let gc_root =
+                PathBuf::from("/home/user/.cache/lorri/gc_roots/0123/gc_root/shell_gc_root");
+            assert_eq!(
+                r.project,
+                Some(ProjectStatus {
+                    project: WatchedProject {
+                        nix_file: NixFile::from(PathBuf::from("/home/user/project/shell.nix")),
+                        state: BuildState::Success,
+                        last_build: Some(1_700_000_000),
+                        gc_root: gc_root.clone(),
+                    },
+                    build_started: Some(1_699_999_988_000),
+                    build_duration: Some(12_000),
+                    output_paths: vec![gc_root],
+                    watched_files: 42,
+                })
+            )
+        },
+    );
+}
+
+#[test]
+fn v10_messages() {
+    round_trip(
+        include_bytes!("golden/v10/ping_result_registered.bin"),
+        |r: &PingResult| assert_eq!(*r, PingResult::Registered),
+    );
+    round_trip(
+        include_bytes!("golden/v10/ping_result_refused.bin"),
+        |r: &PingResult| assert_eq!(*r, PingResult::Refused(RegistrationError::PermissionDenied)),
+    );
+    round_trip(
+        include_bytes!("golden/v10/ping_result_refused_io.bin"),
+        |r: &PingResult| {
+            assert_eq!(
+                *r,
+                PingResult::Refused(RegistrationError::Io(String::from(
+                    "Input/output error (os error 5)"
+                )))
+            )
+        },
+    );
+}
+
+#[test]
+fn v11_messages() {
+    round_trip(
+        include_bytes!("golden/v11/communication_type_stats.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::Stats),
+    );
+    round_trip(include_bytes!("golden/v11/stats.bin"), |_: &Stats| ());
+    round_trip(
+        include_bytes!("golden/v11/stats_result.bin"),
+        |r: &StatsResult| {
+            assert_eq!(
+                *r,
+                StatsResult {
+                    projects: 3,
+                    open_fds: 57,
+                    peak_open_fds: 112,
+                    fd_soft_limit: Some(1024),
+                    fd_hard_limit: None,
+                }
+            )
+        },
+    );
+}
+
+#[test]
+fn v12_messages() {
+    round_trip(
+        include_bytes!("golden/v12/communication_type_rebuild.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::Rebuild),
+    );
+    round_trip(include_bytes!("golden/v12/rebuild.bin"), |r: &Rebuild| {
+        assert_eq!(
+            r.nix_file,
+            NixFile::from(PathBuf::from("/home/user/project/shell.nix"))
+        )
+    });
+}
+
+#[test]
+fn v13_messages() {
+    round_trip(
+        include_bytes!("golden/v13/communication_type_latency.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::Latency),
+    );
+    round_trip(include_bytes!("golden/v13/latency.bin"), |_: &Latency| ());
+    round_trip(
+        include_bytes!("golden/v13/latency_result.bin"),
+        |r: &LatencyResult| {
+            assert_eq!(
+                *r,
+                LatencyResult {
+                    builds: 4,
+                    last_ms: 120,
+                    total_ms: 900,
+                    max_ms: 450,
+                }
+            );
+            assert_eq!(r.mean_ms(), Some(225));
+        },
+    );
+}
+
+#[test]
+fn v14_messages() {
+    round_trip(
+        include_bytes!("golden/v14/communication_type_subscribe.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::Subscribe),
+    );
+    round_trip(
+        include_bytes!("golden/v14/subscribe.bin"),
+        |s: &Subscribe| {
+            assert_eq!(
+                s.nix_file,
+                Some(NixFile::from(PathBuf::from("/home/user/project/shell.nix")))
+            );
+            assert_eq!(s.since, Some(42));
+            assert!(s.log_lines);
+        },
+    );
+}
+
+#[test]
+fn v15_messages() {
+    round_trip(
+        include_bytes!("golden/v15/communication_type_resume.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::Resume),
+    );
+    round_trip(include_bytes!("golden/v15/resume.bin"), |r: &Resume| {
+        assert_eq!(
+            r.nix_file,
+            Some(NixFile::from(PathBuf::from("/home/user/project/shell.nix")))
+        );
+        assert_eq!(r.since, Some(42));
+        assert_eq!(r.epoch, Some(1_600_000_000_000_000_000));
+        assert!(r.log_lines);
+    });
+}
+
+#[test]
+fn v16_messages() {
+    round_trip(
+        include_bytes!("golden/v16/communication_type_request_build.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::RequestBuild),
+    );
+    round_trip(
+        include_bytes!("golden/v16/request_build.bin"),
+        |r: &RequestBuild| {
+            assert_eq!(
+                r.nix_file,
+                NixFile::from(PathBuf::from("/home/user/project/shell.nix"))
+            )
+        },
+    );
+    round_trip(
+        include_bytes!("golden/v16/request_build_result_requested.bin"),
+        |r: &RequestBuildResult| assert_eq!(*r, RequestBuildResult::Requested { build_id: 42 }),
+    );
+    round_trip(
+        include_bytes!("golden/v16/request_build_result_refused.bin"),
+        |r: &RequestBuildResult| {
+            assert_eq!(
+                *r,
+                RequestBuildResult::Refused(RegistrationError::PermissionDenied)
+            )
+        },
+    );
+}
+
+#[test]
+fn v17_messages() {
+    round_trip(
+        include_bytes!("golden/v17/communication_type_ping_attribute.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::PingAttribute),
+    );
+    round_trip(
+        include_bytes!("golden/v17/ping_attribute.bin"),
+        |p: &PingAttribute| {
+            assert_eq!(
+                p.nix_file,
+                NixFile::from(PathBuf::from("/home/user/project/default.nix"))
+            );
+            assert_eq!(p.attribute, "devShells.\"python3.11\"");
+        },
+    );
+    round_trip(
+        include_bytes!("golden/v17/ping_result_refused_invalid_attribute.bin"),
+        |r: &PingResult| {
+            assert_eq!(
+                *r,
+                PingResult::Refused(RegistrationError::InvalidAttribute(String::from(
+                    "invalid attribute path `shells.`: empty attribute name"
+                )))
+            )
+        },
+    );
+}
+
+#[test]
+fn v18_messages() {
+    round_trip(
+        include_bytes!("golden/v18/ping_result_refused_setup.bin"),
+        |r: &PingResult| {
+            assert_eq!(
+                *r,
+                PingResult::Refused(RegistrationError::Setup(String::from(
+                    "could not create /var/lib/lorri/gc_roots/0123/gc_root: \
+                     Permission denied (os error 13)"
+                )))
+            )
+        },
+    );
+}
+
+#[test]
+fn v19_messages() {
+    round_trip(
+        include_bytes!("golden/v19/communication_type_wait_idle_for.bin"),
+        |t: &CommunicationType| assert_eq!(*t, CommunicationType::WaitIdleFor),
+    );
+    round_trip(
+        include_bytes!("golden/v19/wait_idle_for.bin"),
+        |w: &WaitIdleFor| {
+            assert_eq!(
+                w.nix_file,
+                Some(NixFile::from(PathBuf::from("/home/user/project/shell.nix")))
+            );
+            assert_eq!(w.max_wait_ms, 2000);
+        },
+    );
+    round_trip(
+        include_bytes!("golden/v19/wait_idle_for_result.bin"),
+        |r: &WaitIdleForResult| {
+            assert_eq!(
+                *r,
+                WaitIdleForResult {
+                    watched: true,
+                    idle: false
+                }
+            )
+        },
+    );
+}
+
+/// A daemon has to understand that a newer client asks for something
+/// it doesn’t support, instead of failing to decode the request.
+#[test]
+fn unknown_communication_type() {
+    let from_the_future = 42u32.to_le_bytes();
+    let t: CommunicationType = bincode::deserialize(&from_the_future).unwrap();
+    assert_eq!(t, CommunicationType::Unknown);
+}
@@ -1,20 +1,33 @@
 extern crate lorri;
+extern crate nix;
 extern crate tempfile;
 
 use lorri::build_loop;
 use lorri::cas::ContentAddressable;
 use lorri::project::Project;
 use lorri::socket::communicate::{client, listener};
-use lorri::socket::communicate::{CommunicationType, Ping};
+use lorri::socket::communicate::{
+    BuildState, CommunicationType, ListProjects, Ping, PingAttribute, PingResult,
+    RegistrationError, RequestBuild, RequestBuildResult, Shutdown, Status,
+};
 use lorri::socket::path::SocketPath;
 use lorri::socket::{ReadWriter, Timeout};
 use lorri::NixFile;
-use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use nix::fcntl::{flock, FlockArg};
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 
+/// A nix file in `dir` the daemon accepts; building it fails.
+fn shell_nix(dir: &Path) -> std::io::Result<NixFile> {
+    let path = dir.join("shell.nix");
+    std::fs::write(&path, "{}")?;
+    Ok(NixFile::from(path))
+}
+
 /// This tests the basic working of the client/daemon setup.
 ///
 /// The daemon starts listening, the client sends a message
@@ -48,6 +61,7 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
                 CommunicationType::Ping => {
                     handlers.ping(ReadWriter::new(&unix_stream), accept_messages_tx)
                 }
+                other => panic!("unexpected communication type {:?}", other),
             })
             .unwrap()
     });
@@ -56,7 +70,7 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
         .connect(&socket_path)
         .unwrap()
         .write(&Ping {
-            nix_file: NixFile::from(PathBuf::from("/who/cares")),
+            nix_file: shell_nix(tempdir.path())?,
         })
         .unwrap();
 
@@ -69,13 +83,14 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
     let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
     let project = Project::new(start_build.nix_file, &tempdir.path().join("gc_root"), cas).unwrap();
     daemon.add(project);
+    start_build.registered.send(Ok(())).unwrap();
 
     // Read the first build event, which should be a `Started` message
     match build_events_rx
         .recv_timeout(Duration::from_millis(100))
         .unwrap()
     {
-        build_loop::Event::Started => Ok(()),
+        build_loop::Event::Started(..) => Ok(()),
         ev => Err(Error::new(
             ErrorKind::Other,
             format!("didn’t expect event {:?}", ev),
@@ -87,6 +102,64 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
     Ok(())
 }
 
+/// A requested build starts with the id the daemon answered with,
+/// for a project it didn’t watch yet, too.
+#[test]
+pub fn request_build_of_a_new_project() -> std::io::Result<()> {
+    let (accept_messages_tx, accept_messages_rx) = mpsc::channel();
+    let tempdir = tempfile::tempdir()?;
+    let socket = tempdir.path().join("socket");
+    let socket_path = SocketPath::from(&socket);
+    let listener = listener::Listener::new(&socket_path).unwrap();
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new();
+
+    let handlers = daemon.handlers();
+    let accept_handle = thread::spawn(move || {
+        listener
+            .accept(move |unix_stream, comm_type| match comm_type {
+                CommunicationType::RequestBuild => {
+                    handlers.request_build(ReadWriter::new(&unix_stream), accept_messages_tx)
+                }
+                other => panic!("unexpected communication type {:?}", other),
+            })
+            .unwrap()
+    });
+    // the daemon answers once the project is set up
+    let gc_root_dir = tempdir.path().join("gc_root");
+    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
+    let setup = thread::spawn(move || {
+        let start_build = accept_messages_rx
+            .recv_timeout(Duration::from_millis(1000))
+            .unwrap();
+        let project = Project::new(start_build.nix_file, &gc_root_dir, cas).unwrap();
+        start_build.registered.send(Ok(())).unwrap();
+        project
+    });
+    let answer = client::request_build(Timeout::from_millis(1000))
+        .connect(&socket_path)
+        .unwrap()
+        .request(&RequestBuild {
+            nix_file: shell_nix(tempdir.path())?,
+        })
+        .unwrap();
+    let build_id = match answer {
+        RequestBuildResult::Requested { build_id } => build_id,
+        refused => panic!("the build was refused: {:?}", refused),
+    };
+    accept_handle.join().unwrap().join().unwrap();
+    daemon.add(setup.join().unwrap());
+
+    match build_events_rx
+        .recv_timeout(Duration::from_millis(100))
+        .unwrap()
+    {
+        build_loop::Event::Started(build, ..) => assert_eq!(build.as_u64(), build_id),
+        ev => panic!("didn’t expect event {:?}", ev),
+    }
+    daemon.stop();
+    Ok(())
+}
+
 #[test]
 pub fn start_two_listeners_on_same_socket() -> std::io::Result<()> {
     let tempdir = tempfile::tempdir()?;
@@ -107,3 +180,348 @@ pub fn start_two_listeners_on_same_socket() -> std::io::Result<()> {
     drop(listener);
     Ok(())
 }
+
+/// A pinged project is listed with its GC root, and with the state
+/// of its build.
+#[test]
+pub fn list_watched_projects() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let gc_root_dir = tempdir.path().join("gc_root");
+    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
+
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new();
+    daemon.start(p, &gc_root_dir, cas).unwrap();
+    let list = || {
+        client::list_projects(Timeout::from_millis(1000))
+            .connect(&SocketPath::from(p))
+            .unwrap()
+            .request(&ListProjects {})
+            .unwrap()
+            .projects
+    };
+    assert_eq!(list(), vec![]);
+
+    let nix_file = shell_nix(tempdir.path())?;
+    client::ping(Timeout::from_millis(100))
+        .connect(&SocketPath::from(p))
+        .unwrap()
+        .write(&Ping {
+            nix_file: nix_file.clone(),
+        })
+        .unwrap();
+    match build_events_rx
+        .recv_timeout(Duration::from_secs(1))
+        .unwrap()
+    {
+        build_loop::Event::Started(..) => (),
+        ev => panic!("didn’t expect event {:?}", ev),
+    }
+
+    let projects = list();
+    assert_eq!(projects.len(), 1);
+    assert_eq!(projects[0].nix_file, nix_file);
+    assert_ne!(projects[0].state, BuildState::Pending);
+    assert!(projects[0].gc_root.starts_with(&gc_root_dir));
+
+    daemon.stop();
+    Ok(())
+}
+
+/// Every attribute of a nix file is a project of its own, with its
+/// own build loop and GC roots.
+#[test]
+pub fn watch_attributes_of_a_nix_file() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
+
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new();
+    daemon
+        .start(p, &tempdir.path().join("gc_root"), cas)
+        .unwrap();
+    let nix_file = shell_nix(tempdir.path())?;
+    let ping = |attribute: &str| {
+        client::ping_attribute(Timeout::from_millis(1000))
+            .connect(&SocketPath::from(p))
+            .unwrap()
+            .request(&PingAttribute {
+                nix_file: nix_file.clone(),
+                attribute: attribute.to_string(),
+            })
+            .unwrap()
+    };
+    match ping("shells.") {
+        PingResult::Refused(RegistrationError::InvalidAttribute(_)) => (),
+        result => panic!("didn’t expect {:?}", result),
+    }
+    assert_eq!(ping("shells.backend"), PingResult::Registered);
+    assert_eq!(ping(r#"shells."frontend.v2""#), PingResult::Registered);
+    // pinging an attribute again doesn’t start another loop
+    assert_eq!(ping("shells.backend"), PingResult::Registered);
+    for _ in 0..2 {
+        match build_events_rx
+            .recv_timeout(Duration::from_secs(1))
+            .unwrap()
+        {
+            build_loop::Event::Started(..) => (),
+            ev => panic!("didn’t expect event {:?}", ev),
+        }
+    }
+
+    let projects = client::list_projects(Timeout::from_millis(1000))
+        .connect(&SocketPath::from(p))
+        .unwrap()
+        .request(&ListProjects {})
+        .unwrap()
+        .projects;
+    assert_eq!(projects.len(), 2);
+    assert!(projects.iter().all(|project| project.nix_file == nix_file));
+    assert_ne!(projects[0].gc_root, projects[1].gc_root);
+
+    daemon.stop();
+    Ok(())
+}
+
+/// The daemon answers a ping with why it can’t watch the nix file.
+#[test]
+pub fn refuse_missing_nix_file() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
+
+    let (mut daemon, _build_events_rx) = ::lorri::daemon::Daemon::new();
+    daemon
+        .start(p, &tempdir.path().join("gc_root"), cas)
+        .unwrap();
+    let ping = |nix_file: PathBuf| {
+        client::ping(Timeout::from_millis(1000))
+            .connect(&SocketPath::from(p))
+            .unwrap()
+            .request(&Ping {
+                nix_file: NixFile::from(nix_file),
+            })
+            .unwrap()
+    };
+    assert_eq!(
+        ping(tempdir.path().join("missing.nix")),
+        PingResult::Refused(RegistrationError::NotFound)
+    );
+    assert_eq!(
+        ping(tempdir.path().to_owned()),
+        PingResult::Refused(RegistrationError::NotAFile)
+    );
+    assert_eq!(
+        ping(PathBuf::from("shell.nix")),
+        PingResult::Refused(RegistrationError::RelativePath)
+    );
+    let list = client::list_projects(Timeout::from_millis(1000))
+        .connect(&SocketPath::from(p))
+        .unwrap()
+        .request(&ListProjects {})
+        .unwrap();
+    assert_eq!(list.projects, vec![]);
+
+    let shell_nix = shell_nix(tempdir.path())?;
+    assert_eq!(
+        ping(PathBuf::from(shell_nix.as_os_str())),
+        PingResult::Registered
+    );
+
+    daemon.stop();
+    Ok(())
+}
+
+/// The status of a project records when its last build started;
+/// unknown projects have no status.
+#[test]
+pub fn project_status() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
+
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new();
+    daemon
+        .start(p, &tempdir.path().join("gc_root"), cas)
+        .unwrap();
+    let status = |nix_file: &NixFile| {
+        client::status(Timeout::from_millis(1000))
+            .connect(&SocketPath::from(p))
+            .unwrap()
+            .request(&Status {
+                nix_file: nix_file.clone(),
+            })
+            .unwrap()
+            .project
+    };
+
+    let nix_file = shell_nix(tempdir.path())?;
+    assert_eq!(status(&nix_file), None);
+    client::ping(Timeout::from_millis(100))
+        .connect(&SocketPath::from(p))
+        .unwrap()
+        .write(&Ping {
+            nix_file: nix_file.clone(),
+        })
+        .unwrap();
+    match build_events_rx
+        .recv_timeout(Duration::from_secs(1))
+        .unwrap()
+    {
+        build_loop::Event::Started(..) => (),
+        ev => panic!("didn’t expect event {:?}", ev),
+    }
+
+    // the state is recorded before the event is passed on
+    let project = status(&nix_file).expect("the project is watched");
+    assert_eq!(project.project.nix_file, nix_file);
+    assert_eq!(project.project.state, BuildState::Building);
+    assert!(project.build_started.is_some());
+    assert_eq!(project.build_duration, None);
+
+    daemon.stop();
+    Ok(())
+}
+
+/// The same as `start_job_with_ping`, but with the daemon handling
+/// the socket itself. Once stopped, the socket is free again.
+#[test]
+pub fn start_and_stop_daemon() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let gc_root_dir = tempdir.path().join("gc_root");
+    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
+
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new();
+    daemon.start(p, &gc_root_dir, cas.clone()).unwrap();
+
+    client::ping(Timeout::from_millis(100))
+        .connect(&SocketPath::from(p))
+        .unwrap()
+        .write(&Ping {
+            nix_file: shell_nix(tempdir.path())?,
+        })
+        .unwrap();
+
+    match build_events_rx
+        .recv_timeout(Duration::from_secs(1))
+        .unwrap()
+    {
+        build_loop::Event::Started(..) => (),
+        ev => panic!("didn’t expect event {:?}", ev),
+    }
+
+    daemon.stop();
+    // the socket lock was released
+    let (mut other_daemon, _) = ::lorri::daemon::Daemon::new();
+    other_daemon.start(p, &gc_root_dir, cas).unwrap();
+    other_daemon.stop();
+    Ok(())
+}
+
+/// A stopped daemon which is started again starts new build loops
+/// for the projects it built before.
+#[test]
+pub fn restart_daemon() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let gc_root_dir = tempdir.path().join("gc_root");
+    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
+    let nix_file = shell_nix(tempdir.path())?;
+
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new();
+    for _ in 0..2 {
+        daemon.start(p, &gc_root_dir, cas.clone()).unwrap();
+        client::ping(Timeout::from_millis(100))
+            .connect(&SocketPath::from(p))
+            .unwrap()
+            .write(&Ping {
+                nix_file: nix_file.clone(),
+            })
+            .unwrap();
+        // skip what the stopped build loop sent last
+        loop {
+            match build_events_rx.recv_timeout(Duration::from_secs(1)) {
+                Ok(build_loop::Event::Started(..)) => break,
+                Ok(_) => continue,
+                Err(e) => panic!("no build started: {}", e),
+            }
+        }
+        daemon.stop();
+    }
+    Ok(())
+}
+
+/// Asked to shut down, the daemon cancels its builds (as asked),
+/// stops and removes its socket.
+#[test]
+pub fn shut_down_with_message() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let gc_root_dir = tempdir.path().join("gc_root");
+    let cas = ContentAddressable::new(tempdir.path().join("cas")).unwrap();
+
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new();
+    daemon.start(p, &gc_root_dir, cas).unwrap();
+    client::ping(Timeout::from_millis(100))
+        .connect(&SocketPath::from(p))
+        .unwrap()
+        .write(&Ping {
+            nix_file: shell_nix(tempdir.path())?,
+        })
+        .unwrap();
+    match build_events_rx
+        .recv_timeout(Duration::from_secs(1))
+        .unwrap()
+    {
+        build_loop::Event::Started(..) => (),
+        ev => panic!("didn’t expect event {:?}", ev),
+    }
+
+    let result = client::shutdown(Timeout::from_millis(1000))
+        .connect(&SocketPath::from(p))
+        .unwrap()
+        .request(&Shutdown {
+            cancel_builds: true,
+        })
+        .unwrap();
+    assert!(result.running_builds <= 1);
+    daemon.run_until_shutdown();
+    assert!(!p.exists(), "the socket was not removed");
+    Ok(())
+}
+
+#[test]
+pub fn recover_stale_socket() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let socket_path = SocketPath::from(p);
+
+    // a lock held on behalf of a process which is gone
+    let mut dead = std::process::Command::new("true").spawn()?;
+    let dead_pid = dead.id();
+    dead.wait()?;
+    let stale_lock = lock(&socket_path.lockfile(), dead_pid)?;
+    let listener = listener::Listener::new(&socket_path).unwrap();
+    drop(listener);
+    drop(stale_lock);
+
+    // a lock held by a live process
+    let live_lock = lock(&socket_path.lockfile(), std::process::id())?;
+    match listener::Listener::new(&socket_path) {
+        Err(lorri::socket::path::BindError::OtherProcessListening) => (),
+        Ok(_) => panic!("other process should be listening"),
+        Err(e) => panic!("{:?}", e),
+    }
+    drop(live_lock);
+    Ok(())
+}
+
+/// Lock `lockfile` like a daemon with pid `pid` would.
+fn lock(lockfile: &Path, pid: u32) -> std::io::Result<std::fs::File> {
+    let file = std::fs::File::create(lockfile)?;
+    flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock)
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    write!(&file, "{}", pid)?;
+    Ok(file)
+}
@@ -0,0 +1,55 @@
+//! Builds into the hermetic nix store of `lorri::test_fixtures`.
+//! Run with `cargo test --features test-fixtures --test fixtures`.
+
+extern crate lorri;
+
+use lorri::build_loop::BuildLoop;
+use lorri::test_fixtures::fixtures;
+use std::path::{Path, PathBuf};
+
+/// Where the store path `path` is on disk.
+fn in_store(path: &Path) -> PathBuf {
+    fixtures()
+        .store_root()
+        .join(path.strip_prefix("/").expect("store paths are absolute"))
+}
+
+#[test]
+fn build_into_fixture_store() -> std::io::Result<()> {
+    let test =
+        fixtures().project("with import <nixpkgs> {}; mkShell { buildInputs = [ hello ]; }")?;
+    let results = BuildLoop::new(&test.project)
+        .once()
+        .expect("the build failed");
+
+    let root = Path::new(results.output_paths.shell_gc_root.as_os_str());
+    let target = std::fs::read_link(root)?;
+    assert!(target.starts_with("/nix/store"), "{}", target.display());
+    assert!(
+        in_store(&target).exists(),
+        "{} is not in the fixture store",
+        target.display()
+    );
+    Ok(())
+}
+
+#[test]
+fn roots_registered_in_fixture_store() -> std::io::Result<()> {
+    let test = fixtures().project("with import <nixpkgs> {}; mkShell {}")?;
+    let results = BuildLoop::new(&test.project)
+        .once()
+        .expect("the build failed");
+
+    let root = PathBuf::from(results.output_paths.shell_gc_root.as_os_str());
+    let auto_roots = fixtures().store().state_dir().join("gcroots/auto");
+    let registered = std::fs::read_dir(&auto_roots)?
+        .filter_map(|entry| std::fs::read_link(entry.ok()?.path()).ok())
+        .any(|target| target == root);
+    assert!(
+        registered,
+        "{} not in {}",
+        root.display(),
+        auto_roots.display()
+    );
+    Ok(())
+}
@@ -57,7 +57,7 @@ impl DirenvTestCase {
     /// Run `direnv allow` and then `direnv export json`, and return
     /// the environment DirEnv would produce.
     pub fn get_direnv_variables(&self) -> DirenvEnv {
-        let shell = direnv::main(self.project.clone())
+        let shell = direnv::main(self.project.clone(), None, false, None)
             .unwrap()
             .expect("direnv::main should return a string of shell");
 
@@ -0,0 +1,112 @@
+extern crate serde_json;
+extern crate tempfile;
+
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The lorri binary cargo builds for the integration tests, next to
+/// the `deps` directory of the test binaries.
+fn lorri() -> PathBuf {
+    let mut dir = std::env::current_exe().expect("the test binary has a path");
+    dir.pop();
+    if dir.ends_with("deps") {
+        dir.pop();
+    }
+    dir.join("lorri")
+}
+
+/// Run `lorri --porcelain` with `args` in the project `dir`, with
+/// the lorri directories below `home` and `path` as `PATH`, and
+/// check that it printed nothing but single-line JSON records (the
+/// last one with the result). Returns the records.
+fn porcelain(home: &Path, dir: &Path, path: &OsString, args: &[&str]) -> Vec<serde_json::Value> {
+    let output = Command::new(lorri())
+        .arg("--porcelain")
+        .args(args)
+        .current_dir(dir)
+        .env("HOME", home)
+        .env("XDG_CACHE_HOME", home.join(".cache"))
+        .env("XDG_CONFIG_HOME", home.join(".config"))
+        .env("XDG_RUNTIME_DIR", home.join("run"))
+        .env("PATH", path)
+        .output()
+        .expect("lorri should start");
+    let stdout = String::from_utf8(output.stdout).expect("stdout is UTF-8");
+    let records: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| match serde_json::from_str(line) {
+            Ok(serde_json::Value::Object(record)) => serde_json::Value::Object(record),
+            _ => panic!(
+                "`lorri {}` printed {:?}, not a record",
+                args.join(" "),
+                line
+            ),
+        })
+        .collect();
+    let result = records
+        .last()
+        .and_then(|last| last.get("result"))
+        .and_then(|result| result.as_str())
+        .map(String::from);
+    let expected = if output.status.success() {
+        "ok"
+    } else {
+        "error"
+    };
+    assert_eq!(
+        result.as_ref().map(String::as_str),
+        Some(expected),
+        "`lorri {}` printed {:?}",
+        args.join(" "),
+        stdout
+    );
+    records
+}
+
+#[test]
+fn ops_print_only_records() {
+    let home = tempfile::tempdir().unwrap();
+    let project = home.path().join("project");
+    fs::create_dir(&project).unwrap();
+    let path = std::env::var_os("PATH").unwrap_or_default();
+
+    let ops: &[&[&str]] = &[
+        &["init"],
+        &["info"],
+        // without a daemon
+        &["status"],
+        &["ping"],
+        &["internal", "stats"],
+        &["internal", "show-eval-expr"],
+        &["internal", "register", "shell.nix"],
+        &["internal", "root-check"],
+        &["gc", "--dry-run"],
+    ];
+    for args in ops {
+        porcelain(home.path(), &project, &path, args);
+    }
+}
+
+#[test]
+fn commands_print_to_stderr() {
+    let home = tempfile::tempdir().unwrap();
+    let bin = home.path().join("bin");
+    fs::create_dir(&bin).unwrap();
+    let systemctl = bin.join("systemctl");
+    fs::write(&systemctl, "#!/bin/sh\necho \"systemctl $*\"\n").unwrap();
+    fs::set_permissions(&systemctl, fs::Permissions::from_mode(0o755)).unwrap();
+    let mut path = OsString::from(&bin);
+    path.push(":");
+    path.push(std::env::var_os("PATH").unwrap_or_default());
+
+    let records = porcelain(
+        home.path(),
+        home.path(),
+        &path,
+        &["install-service", "--systemd"],
+    );
+    assert_eq!(records.len(), 3);
+}